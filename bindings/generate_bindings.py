@@ -7,8 +7,8 @@ def uniffi_gen(executable: str, *args):
     print(f'Running {executable} {" ".join(map(str, args))}')
     subprocess.run([executable, *map(str, args)], check=True)
 
-def uniffi_gen_go(udl: Path, output_dir: Path):
-    uniffi_gen("uniffi-bindgen-go", udl, "-o", output_dir)
+def uniffi_gen_go(udl: Path, output_dir: Path, config_file: Path):
+    uniffi_gen("uniffi-bindgen-go", udl, "-o", output_dir, "-c", config_file)
 
 
 def uniffi_gen_cs(udl: Path, output_dir: Path, config_file: Path):
@@ -47,7 +47,7 @@ if __name__ == '__main__':
 
     if args.all:
         config_file = Path(args.config)
-        uniffi_gen_go(udl_file, output_dir.joinpath(f"go"))
+        uniffi_gen_go(udl_file, output_dir.joinpath(f"go"), config_file)
         uniffi_gen_cs(udl_file, output_dir.joinpath(f"csharp"), config_file)
         uniffi_gen_kotlin(udl_file, output_dir.joinpath(f"kotlin"))
         uniffi_gen_swift(udl_file, output_dir.joinpath(f"swift"))
@@ -55,7 +55,8 @@ if __name__ == '__main__':
         uniffi_gen_cpp(udl_file, output_dir.joinpath(f"cpp"))
     else:
         if args.go:
-            uniffi_gen_go(udl_file, output_dir.joinpath(f"go"))
+            config_file = Path(args.config)
+            uniffi_gen_go(udl_file, output_dir.joinpath(f"go"), config_file)
         if args.csharp:
             config_file = Path(args.config)
             uniffi_gen_cs(udl_file, output_dir.joinpath(f"csharp"), config_file)
@@ -0,0 +1,3 @@
+module $GO_MODULE_PATH
+
+go 1.21
@@ -0,0 +1,66 @@
+// Hand written helpers living next to the bindings generated with
+// uniffi-bindgen-go into this directory.
+package norddrop
+
+// EventChannel implements EventCallback by delivering the events to a
+// channel. The library waits while the channel is full, so it should be
+// drained continuously.
+type EventChannel struct {
+	events chan Event
+}
+
+// NewEventChannel creates the callback with the channel buffering up to
+// capacity events.
+func NewEventChannel(capacity int) *EventChannel {
+	return &EventChannel{events: make(chan Event, capacity)}
+}
+
+// OnEvent is called by the library for each event.
+func (c *EventChannel) OnEvent(event Event) {
+	c.events <- event
+}
+
+// Events returns the channel receiving the events.
+func (c *EventChannel) Events() <-chan Event {
+	return c.events
+}
+
+// Close closes the events channel, ending the loops ranging over it. It must
+// be called only once the instance is stopped and destroyed, no events are
+// delivered afterwards.
+func (c *EventChannel) Close() {
+	close(c.events)
+}
+
+// LoggerFunc implements Logger with a function, logging the messages up to
+// MaxLevel.
+type LoggerFunc struct {
+	MaxLevel LogLevel
+	Log      func(level LogLevel, msg string)
+}
+
+// OnLog is called by the library for each log message. The messages are
+// dropped when Log is not set.
+func (l LoggerFunc) OnLog(level LogLevel, msg string) {
+	if l.Log != nil {
+		l.Log(level, msg)
+	}
+}
+
+// Level returns the maximum log level.
+func (l LoggerFunc) Level() LogLevel {
+	return l.MaxLevel
+}
+
+// NewWithChannels creates the instance delivering its events to the channel
+// of the returned EventChannel.
+func NewWithChannels(keyStore KeyStore, logger Logger, capacity int) (*NordDrop, *EventChannel, error) {
+	events := NewEventChannel(capacity)
+
+	drop, err := NewNordDrop(events, keyStore, logger)
+	if err != nil {
+		return nil, nil, err
+	}
+
+	return drop, events, nil
+}
@@ -0,0 +1,200 @@
+//! TLS certificates derived from the X25519 identity keys. The X25519 secret
+//! is used as the Ed25519 signing key in the XEdDSA way, so the peer can
+//! verify the certificate knowing only the X25519 public key.
+
+use curve25519_dalek::{montgomery::MontgomeryPoint, EdwardsPoint, Scalar};
+use ed25519_dalek::{
+    hazmat::{self, ExpandedSecretKey},
+    VerifyingKey,
+};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, RemoteKeyPair, SerialNumber};
+use sha2::{Digest, Sha512};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use crate::{PublicKey, SecretKey};
+
+const NONCE_DOMAIN: &[u8] = b"libdrop-tls-nonce";
+const COMMON_NAME: &str = "libdrop";
+
+/// Ed25519 signing key derived from the X25519 secret
+pub struct CertKey {
+    expanded: ExpandedSecretKey,
+    public: VerifyingKey,
+}
+
+impl CertKey {
+    pub fn new(secret: &SecretKey) -> Self {
+        let mut bytes = secret.to_bytes();
+        bytes[0] &= 248;
+        bytes[31] &= 127;
+        bytes[31] |= 64;
+
+        let mut scalar = Scalar::from_bytes_mod_order(bytes);
+
+        // The Montgomery form does not carry the sign, the peer assumes the
+        // positive one
+        if EdwardsPoint::mul_base(&scalar).compress().as_bytes()[31] & 0x80 != 0 {
+            scalar = -scalar;
+        }
+
+        let mut hash_prefix = [0u8; 32];
+        hash_prefix.copy_from_slice(
+            &Sha512::new()
+                .chain_update(NONCE_DOMAIN)
+                .chain_update(bytes)
+                .finalize()[..32],
+        );
+
+        let expanded = ExpandedSecretKey {
+            scalar,
+            hash_prefix,
+        };
+        let public = VerifyingKey::from(&expanded);
+
+        Self { expanded, public }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Creates the Ed25519 signature of the message
+    pub fn sign(&self, msg: &[u8]) -> [u8; 64] {
+        hazmat::raw_sign::<Sha512>(&self.expanded, msg, &self.public).to_bytes()
+    }
+
+    /// Creates the self-signed X.509 certificate in the DER format
+    pub fn certificate(&self) -> Vec<u8> {
+        let mut params = CertificateParams::default();
+        params.serial_number = Some(SerialNumber::from(1));
+        params.not_before = rcgen::date_time_ymd(1970, 1, 1);
+        params.not_after = rcgen::date_time_ymd(9999, 12, 31);
+
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, COMMON_NAME);
+        params.distinguished_name = name;
+
+        let key = KeyPair::from_remote(Box::new(RemoteKey(CertKey {
+            expanded: ExpandedSecretKey {
+                scalar: self.expanded.scalar,
+                hash_prefix: self.expanded.hash_prefix,
+            },
+            public: self.public,
+        })))
+        .expect("Remote key pairs are always accepted");
+
+        params
+            .self_signed(&key)
+            .expect("The certificate parameters are valid")
+            .der()
+            .to_vec()
+    }
+}
+
+/// Signs the certificate with the derived key
+struct RemoteKey(CertKey);
+
+impl RemoteKeyPair for RemoteKey {
+    fn public_key(&self) -> &[u8] {
+        self.0.public.as_bytes()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+        Ok(self.0.sign(msg).to_vec())
+    }
+
+    fn algorithm(&self) -> &'static rcgen::SignatureAlgorithm {
+        &rcgen::PKCS_ED25519
+    }
+}
+
+/// Returns the certificate key expected from the peer with the given X25519
+/// public key
+pub fn peer_cert_key(pubkey: &PublicKey) -> Option<[u8; 32]> {
+    let point = MontgomeryPoint(pubkey.to_bytes()).to_edwards(0)?;
+    Some(point.compress().to_bytes())
+}
+
+/// Extracts the Ed25519 public key from the DER encoded X.509 certificate
+pub fn certificate_key(cert: &[u8]) -> Option<[u8; 32]> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let spki = cert.public_key();
+
+    if spki.algorithm.algorithm != x509_parser::oid_registry::OID_SIG_ED25519
+        || spki.algorithm.parameters.is_some()
+    {
+        return None;
+    }
+
+    spki.subject_public_key.data.as_ref().try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Signature;
+
+    use super::*;
+
+    #[test]
+    fn certificate_matches_identity() {
+        for seed in [[1u8; 32], [42; 32], [0xfe; 32]] {
+            let secret = SecretKey::from(seed);
+            let key = CertKey::new(&secret);
+
+            assert_eq!(
+                peer_cert_key(&PublicKey::from(&secret)),
+                Some(key.public_key())
+            );
+
+            let cert = key.certificate();
+            assert_eq!(certificate_key(&cert), Some(key.public_key()));
+
+            let verifier = VerifyingKey::from_bytes(&key.public_key()).unwrap();
+            let msg = b"message";
+            verifier
+                .verify_strict(msg, &Signature::from_bytes(&key.sign(msg)))
+                .unwrap();
+
+            // The certificate is signed with the key it carries
+            let (_, parsed) = X509Certificate::from_der(&cert).unwrap();
+            let sig: [u8; 64] = parsed.signature_value.data.as_ref().try_into().unwrap();
+            verifier
+                .verify_strict(
+                    parsed.tbs_certificate.as_ref(),
+                    &Signature::from_bytes(&sig),
+                )
+                .unwrap();
+        }
+
+        let other = CertKey::new(&SecretKey::from([7; 32]));
+        assert_ne!(
+            peer_cert_key(&PublicKey::from(&SecretKey::from([1; 32]))),
+            Some(other.public_key())
+        );
+
+        assert_eq!(certificate_key(b"garbage"), None);
+    }
+
+    #[test]
+    fn rfc8032_key_conversion() {
+        // RFC 8032, section 7.1, test 1. The X25519 secret is the first half
+        // of the SHA-512 hash of the Ed25519 seed
+        let seed = hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let expected = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&Sha512::digest(seed)[..32]);
+        let secret = SecretKey::from(secret);
+
+        assert_eq!(CertKey::new(&secret).public_key(), expected);
+        assert_eq!(peer_cert_key(&PublicKey::from(&secret)), Some(expected));
+    }
+
+    fn hex(s: &str) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        out
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cert;
 pub mod http;
 
 use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
@@ -17,6 +18,8 @@ pub struct Nonce(pub [u8; NONCE_LEN]);
 pub use x25519_dalek::{PublicKey, StaticSecret as SecretKey};
 
 const DOMAIN_STRING: &str = "libdrop-auth";
+const CHUNK_KEY_DOMAIN: &[u8] = b"libdrop-chunk";
+const CAPABILITIES_KEY_DOMAIN: &[u8] = b"libdrop-capabilities";
 
 impl Nonce {
     pub fn generate_as_client() -> Self {
@@ -104,6 +107,122 @@ pub fn create_ticket_as_server(
     Some(http::Authorization { ticket, nonce })
 }
 
+/// Derives the symmetric key used to encrypt the file chunks exchanged with
+/// the peer. The `context` binds the key to e.g. the transfer
+pub fn create_chunk_key(secret: &SecretKey, peer_pubkey: &PublicKey, context: &[u8]) -> [u8; 32] {
+    let shared_secret = secret.diffie_hellman(peer_pubkey);
+
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(Some(context), shared_secret.as_bytes())
+        .expand(CHUNK_KEY_DOMAIN, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+/// Signs the transfer report with the Ed25519 key derived from the device's
+/// secret key, the same one the TLS certificate is signed with. Anyone knowing
+/// the device's X25519 public key can verify it with
+/// [`verify_report_signature()`]
+pub fn create_report_signature(secret: &SecretKey, report: &[u8]) -> String {
+    BASE64.encode(cert::CertKey::new(secret).sign(report))
+}
+
+/// Checks the base64 encoded report signature against the X25519 public key of
+/// the device which created it
+pub fn verify_report_signature(pubkey: &PublicKey, report: &[u8], signature: &str) -> bool {
+    let Some(key) = cert::peer_cert_key(pubkey)
+        .and_then(|key| ed25519_dalek::VerifyingKey::from_bytes(&key).ok())
+    else {
+        return false;
+    };
+    let Some(signature) = BASE64
+        .decode(signature)
+        .ok()
+        .and_then(|sig| ed25519_dalek::Signature::from_slice(&sig).ok())
+    else {
+        return false;
+    };
+
+    key.verify_strict(report, &signature).is_ok()
+}
+
+/// Authenticates the event payload delivered to the services outside of the
+/// device with the key shared with them. The result is the base64 encoded,
+/// unpadded HMAC-SHA256 of the payload
+pub fn create_payload_signature(key: &[u8], payload: &[u8]) -> String {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    hmac.update(payload);
+    BASE64.encode(hmac.finalize().into_bytes())
+}
+
+/// Authenticates the capabilities the peer sends in the connection handshake.
+/// The `binding` ties the MAC to the handshake, e.g. the `authorization`
+/// header value, and the `label` to the side sending it
+pub fn create_capabilities_mac(
+    secret: &SecretKey,
+    peer_pubkey: &PublicKey,
+    label: &[u8],
+    binding: &[u8],
+    capabilities: &[u8],
+) -> String {
+    use hmac::Mac;
+
+    BASE64.encode(
+        capabilities_hmac(secret, peer_pubkey, label, binding, capabilities)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Checks the MAC created by the peer with [`create_capabilities_mac()`]
+pub fn verify_capabilities_mac(
+    secret: &SecretKey,
+    peer_pubkey: &PublicKey,
+    label: &[u8],
+    binding: &[u8],
+    capabilities: &[u8],
+    mac: &str,
+) -> bool {
+    use hmac::Mac;
+
+    let Ok(mac) = BASE64.decode(mac) else {
+        return false;
+    };
+
+    capabilities_hmac(secret, peer_pubkey, label, binding, capabilities)
+        .verify_slice(&mac)
+        .is_ok()
+}
+
+fn capabilities_hmac(
+    secret: &SecretKey,
+    peer_pubkey: &PublicKey,
+    label: &[u8],
+    binding: &[u8],
+    capabilities: &[u8],
+) -> hmac::Hmac<sha2::Sha256> {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let shared_secret = secret.diffie_hellman(peer_pubkey);
+
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+        .expand(CAPABILITIES_KEY_DOMAIN, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    // The lengths keep the fields apart
+    for field in [label, binding, capabilities] {
+        hmac.update(&(field.len() as u64).to_be_bytes());
+        hmac.update(field);
+    }
+    hmac
+}
+
 fn create_tag(secret: &SecretKey, pubkey: &PublicKey, nonce: Nonce) -> Option<Vec<u8>> {
     use hmac::{Hmac, Mac};
     use sha2::Sha256;
@@ -185,4 +304,125 @@ mod tests {
             create_tag(&bob_secret, &alice_public, nonce)
         );
     }
+
+    #[test]
+    fn chunk_key_derivation() {
+        let alice_public = PublicKey::from(ALICE_PUB_KEY);
+        let alice_secret = SecretKey::from(ALICE_PRIV_KEY);
+
+        let bob_public = PublicKey::from(BOB_PUB_KEY);
+        let bob_secret = SecretKey::from(BOB_PRIV_KEY);
+
+        assert_eq!(
+            create_chunk_key(&alice_secret, &bob_public, b"transfer"),
+            create_chunk_key(&bob_secret, &alice_public, b"transfer")
+        );
+        assert_ne!(
+            create_chunk_key(&alice_secret, &bob_public, b"transfer"),
+            create_chunk_key(&alice_secret, &bob_public, b"other transfer")
+        );
+
+        let charlie_secret = SecretKey::from(CHARLIE_PRIV_KEY);
+        assert_ne!(
+            create_chunk_key(&alice_secret, &bob_public, b"transfer"),
+            create_chunk_key(&charlie_secret, &alice_public, b"transfer")
+        );
+    }
+
+    #[test]
+    fn capabilities_mac() {
+        let alice_public = PublicKey::from(ALICE_PUB_KEY);
+        let alice_secret = SecretKey::from(ALICE_PRIV_KEY);
+
+        let bob_public = PublicKey::from(BOB_PUB_KEY);
+        let bob_secret = SecretKey::from(BOB_PRIV_KEY);
+
+        let mac = create_capabilities_mac(
+            &alice_secret,
+            &bob_public,
+            b"client",
+            b"drop ticket",
+            b"chunk-crc32",
+        );
+
+        let verify = |secret, label: &[u8], binding: &[u8], caps: &[u8]| {
+            verify_capabilities_mac(secret, &alice_public, label, binding, caps, &mac)
+        };
+
+        assert!(verify(
+            &bob_secret,
+            b"client",
+            b"drop ticket",
+            b"chunk-crc32"
+        ));
+        // Stripped, replayed in another handshake or reflected back
+        assert!(!verify(&bob_secret, b"client", b"drop ticket", b""));
+        assert!(!verify(
+            &bob_secret,
+            b"client",
+            b"drop other",
+            b"chunk-crc32"
+        ));
+        assert!(!verify(
+            &bob_secret,
+            b"server",
+            b"drop ticket",
+            b"chunk-crc32"
+        ));
+
+        let charlie_secret = SecretKey::from(CHARLIE_PRIV_KEY);
+        assert!(!verify(
+            &charlie_secret,
+            b"client",
+            b"drop ticket",
+            b"chunk-crc32"
+        ));
+    }
+
+    #[test]
+    fn report_signature() {
+        let alice_secret = SecretKey::from(ALICE_PRIV_KEY);
+        let bob_secret = SecretKey::from(BOB_PRIV_KEY);
+
+        let alice_pubkey = PublicKey::from(&alice_secret);
+
+        let signature = create_report_signature(&alice_secret, b"report");
+        assert_eq!(signature, create_report_signature(&alice_secret, b"report"));
+
+        // Verifiable with the public key only
+        assert!(verify_report_signature(
+            &alice_pubkey,
+            b"report",
+            &signature
+        ));
+        assert!(!verify_report_signature(
+            &alice_pubkey,
+            b"other report",
+            &signature
+        ));
+        assert!(!verify_report_signature(
+            &PublicKey::from(&bob_secret),
+            b"report",
+            &signature
+        ));
+        assert!(!verify_report_signature(
+            &alice_pubkey,
+            b"report",
+            &create_report_signature(&bob_secret, b"report")
+        ));
+        assert!(!verify_report_signature(
+            &alice_pubkey,
+            b"report",
+            "garbage"
+        ));
+    }
+
+    #[test]
+    fn payload_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            create_payload_signature(b"Jefe", b"what do ya want for nothing?"),
+            "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM"
+        );
+    }
 }
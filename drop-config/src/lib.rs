@@ -16,8 +16,160 @@ pub struct DropConfig {
     // If set the checksum events will be emited for every checksum_events_granularity bytes
     // Default value is 256KB.
     pub checksum_events_granularity: u64,
+    // The maximum number of timed connection retries, the later ones happen
+    // only on the network refresh
     pub connection_retries: u32,
+    // The delays between the connection retries
+    pub retry_policy: RetryPolicy,
     pub auto_retry_interval: Option<Duration>,
+    // When the peer resolves to both IPv4 and IPv6 addresses, use the IPv6 one
+    pub prefer_ipv6: bool,
+    // The port the server listens on and the one dialed when the peer address
+    // does not specify it
+    pub port: u16,
+    // Start in the low-power mode. It can be changed later at runtime
+    pub low_power_mode: bool,
+    // Relay used when the peer cannot be reached directly
+    pub relay: Option<RelayConfig>,
+    // Serve and dial the peers over TLS with certificates derived from the
+    // identity keys. Both peers need to have it enabled
+    pub tls: bool,
+    // Encrypt the file chunks with keys derived from the identity keys. Used
+    // only when both peers have it enabled
+    pub chunk_encryption: bool,
+    // A transfer with the same peer and files as the one created within this
+    // window is not created again, the existing one is returned instead.
+    // Zero disables the check
+    pub duplicate_transfer_window: Duration,
+    // Apply the modification time and the permissions sent by the peer to the
+    // received files
+    pub preserve_metadata: bool,
+    // Send the empty directories of the directory transfers and recreate the
+    // received ones
+    pub transfer_empty_dirs: bool,
+    // Send the symlinks pointing inside of the transferred directories and
+    // recreate the received ones
+    pub transfer_symlinks: bool,
+    // Report the paths the received files were saved at back to the sender
+    pub report_final_paths: bool,
+    // Skip the hidden and system files when walking the directories
+    pub skip_hidden_files: bool,
+    // Transfers with the aggregate size of the files above this many bytes
+    // are refused, both the created and the received ones
+    pub transfer_size_limit_bytes: Option<u64>,
+    // Flush the received files to the disk and read them back at the
+    // destination to verify the checksum before reporting the completion. The
+    // cached pages are dropped first where the OS allows it, so that the data
+    // is read from the storage medium
+    pub verify_writes: bool,
+    // Add CRC to the file chunks and retransmit the corrupted ones. Used only
+    // when both peers have it enabled
+    pub chunk_crc: bool,
+    // Serve the metrics in the Prometheus text format at `http://<addr>/metrics`.
+    // The endpoint is not authenticated, the address must be a loopback one.
+    // Requires the library built with the `metrics` feature
+    pub metrics_addr: Option<String>,
+    // Start in the read-only mode. It can be changed later at runtime
+    pub read_only: bool,
+    // Cache the peer public keys returned by the callback for this long.
+    // `None` disables the cache
+    pub peer_key_ttl: Option<Duration>,
+    // Create all of the directories of the received root directory when its
+    // first file is downloaded instead of one by one as the files arrive
+    pub create_dir_skeleton: bool,
+    // The directory the partially downloaded files are written to before
+    // being renamed to the destination, or copied there when it is on another
+    // filesystem. `None` writes them next to the destination
+    pub partial_dir: Option<String>,
+    // The extension of the partially downloaded files
+    pub partial_file_extension: String,
+    // Enables the relay role. The transfers asking to be forwarded are
+    // stored in this directory and sent on to their final peer
+    pub forward_dir: Option<String>,
+    // The IP addresses of the peers allowed to forward the transfers through
+    // this relay
+    pub forward_peers: Vec<String>,
+    // The partial files older than this and not belonging to any unfinished
+    // transfer are removed on startup. `None` disables the scan
+    pub partial_gc_age: Option<Duration>,
+    // The allow-list of the directories the files can be downloaded into,
+    // including their subdirectories. Empty allows any destination
+    pub download_roots: Vec<String>,
+    // Enables the inbox mode. Every incoming transfer is downloaded
+    // automatically into this directory, where it waits to be claimed
+    pub inbox_dir: Option<String>,
+    // The transfers not fitting into the inbox are rejected. `None` means
+    // unlimited
+    pub inbox_quota_bytes: Option<u64>,
+    // Measure the time spent in each stage of the chunk pipeline, reported in
+    // the diagnostics snapshot
+    pub pipeline_timing: bool,
+    // Requesting the same transfer again while the previous one is still
+    // pending returns the previous one, regardless of the duplicate window
+    pub idempotent_transfers: bool,
+    // Assign the short human-friendly codes to the transfers, accepted in
+    // place of the transfer IDs
+    pub short_transfer_ids: bool,
+    // Reject the incoming files whose checksum matches the file downloaded
+    // before, which is still on the disk
+    pub skip_duplicates: bool,
+    // Check all the source files are readable before sending the transfer
+    // request, failing the unreadable ones right away
+    pub transfer_preflight: bool,
+    // The `RequestReceived` and `RequestQueued` events of the transfers with
+    // more files carry no file list, which is fetched on demand instead
+    pub event_files_limit: Option<usize>,
+    // The number of threads hashing the partially transferred files to check
+    // if they can be resumed. When set, the peers negotiate BLAKE3 in place of
+    // SHA-256 for these checks
+    pub checksum_threads: Option<usize>,
+    // The bytes each peer may send within `peer_quota_window`, the requests
+    // exceeding it are rejected. `None` means unlimited
+    pub peer_quota_bytes: Option<u64>,
+    pub peer_quota_window: Duration,
+    // The limits of the simultaneous incoming connections, each carrying one
+    // transfer. The peers over the limits are told to retry later. `None`
+    // means unlimited
+    pub max_peers: Option<usize>,
+    pub max_transfers_per_peer: Option<usize>,
+    // The size of the file chunks sent to the peer
+    pub chunk_size: usize,
+    // Adjust the chunk size to the measured throughput and round-trip time,
+    // starting from `chunk_size`
+    pub adaptive_chunk_size: bool,
+    // The bytes of the chunks queued for sending, shared by the uploads over
+    // the same connection. The uploads wait for the queued chunks to be
+    // written to the socket once it's used up
+    pub upload_buffer_bytes: usize,
+    // The number of the ranges of a big file uploaded at the same time, when
+    // the peer supports it. `1` uploads the files sequentially
+    pub parallel_ranges: usize,
+    // Share one connection between the transfers in both directions with the
+    // same peer. Used only when both peers have it enabled
+    pub shared_connections: bool,
+    // Keep the shared connection without any transfers for this long, so
+    // that the next transfers with the peer reuse it. Used only when both
+    // peers have `shared_connections` enabled. `None` closes it right away
+    pub connection_keep_alive: Option<Duration>,
+    // The name and the platform of this device declared to the peers in the
+    // handshake, shown by the peers in place of the address
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    // Restrict the instance to one direction, e.g. for the kiosk or drop-box
+    // style deployments
+    pub transfer_mode: TransferMode,
+    // The key the event payloads are signed with, so that the services the
+    // app forwards the events to can authenticate them. FFI only
+    pub event_signing_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    // The relay address in the `host:port` form
+    pub addr: String,
+    pub token: String,
+    // Number of failed direct connection attempts after which the relay is used
+    pub after_retries: u32,
 }
 
 impl Default for DropConfig {
@@ -29,7 +181,125 @@ impl Default for DropConfig {
             checksum_events_size_threshold: None,
             checksum_events_granularity: 256 * 1024,
             connection_retries: 5,
+            retry_policy: RetryPolicy::default(),
             auto_retry_interval: None,
+            prefer_ipv6: false,
+            port: DEFAULT_PORT,
+            low_power_mode: false,
+            relay: None,
+            tls: false,
+            chunk_encryption: false,
+            duplicate_transfer_window: Duration::from_secs(2),
+            preserve_metadata: false,
+            transfer_empty_dirs: false,
+            transfer_symlinks: false,
+            report_final_paths: false,
+            skip_hidden_files: false,
+            transfer_size_limit_bytes: None,
+            verify_writes: false,
+            chunk_crc: false,
+            metrics_addr: None,
+            read_only: false,
+            peer_key_ttl: None,
+            create_dir_skeleton: false,
+            partial_dir: None,
+            partial_file_extension: DEFAULT_PARTIAL_FILE_EXTENSION.to_string(),
+            forward_dir: None,
+            forward_peers: Vec::new(),
+            partial_gc_age: None,
+            download_roots: Vec::new(),
+            inbox_dir: None,
+            inbox_quota_bytes: None,
+            pipeline_timing: false,
+            idempotent_transfers: false,
+            short_transfer_ids: false,
+            skip_duplicates: false,
+            transfer_preflight: false,
+            event_files_limit: None,
+            checksum_threads: None,
+            peer_quota_bytes: None,
+            peer_quota_window: DEFAULT_PEER_QUOTA_WINDOW,
+            max_peers: None,
+            max_transfers_per_peer: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            adaptive_chunk_size: false,
+            upload_buffer_bytes: DEFAULT_UPLOAD_BUFFER_BYTES,
+            parallel_ranges: 1,
+            shared_connections: false,
+            connection_keep_alive: None,
+            device_name: None,
+            platform: None,
+            transfer_mode: TransferMode::Both,
+            event_signing_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferMode {
+    // Both send and receive the transfers
+    Both,
+    // Refuse the outgoing transfers, text messages and uploads
+    ReceiveOnly,
+    // Reject the incoming transfer requests and text messages, refuse the
+    // downloads
+    SendOnly,
+}
+
+impl TransferMode {
+    pub fn can_send(self) -> bool {
+        self != Self::ReceiveOnly
+    }
+
+    pub fn can_receive(self) -> bool {
+        self != Self::SendOnly
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryBackoff {
+    // The delay doubles with each retry
+    Exponential,
+    // The delay grows by the base delay with each retry
+    Linear,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // The delay of the first retry
+    pub base_delay: Duration,
+    // The delays are capped at this value
+    pub max_delay: Duration,
+    pub backoff: RetryBackoff,
+    // Randomize each delay between the half and the full value so that the
+    // peers do not retry in lockstep
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// The delay before the retry number `retry`, counted from zero, without
+    /// the jitter
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = match self.backoff {
+            RetryBackoff::Exponential => 1u32
+                .checked_shl(retry)
+                .and_then(|factor| self.base_delay.checked_mul(factor)),
+            RetryBackoff::Linear => retry
+                .checked_add(1)
+                .and_then(|factor| self.base_delay.checked_mul(factor)),
+        };
+
+        delay.unwrap_or(Duration::MAX).min(self.max_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: FIRST_RETRY_AFTER,
+            max_delay: MAX_RETRY_DELAY,
+            backoff: RetryBackoff::Exponential,
+            jitter: false,
         }
     }
 }
@@ -40,10 +310,58 @@ pub struct MooseConfig {
     pub prod: bool,
 }
 
-pub const PORT: u16 = 49111;
+pub const DEFAULT_PORT: u16 = 49111;
 pub const TRANFER_IDLE_LIFETIME: Duration = Duration::new(60, 0);
 pub const PING_INTERVAL: Duration = Duration::new(30, 0);
 pub const MAX_UPLOADS_IN_FLIGHT: usize = 4;
+pub const LOW_POWER_PING_INTERVAL: Duration = Duration::new(120, 0);
+pub const LOW_POWER_UPLOADS_IN_FLIGHT: usize = 1;
 pub const MAX_REQUESTS_PER_SEC: u32 = 50;
 pub const WS_SEND_TIMEOUT: Duration = Duration::new(20, 0);
 pub const FIRST_RETRY_AFTER: Duration = Duration::new(1, 0);
+pub const MAX_RETRY_DELAY: Duration = Duration::new(300, 0);
+// Keeps the partial files of the previous versions resumable
+pub const DEFAULT_PARTIAL_FILE_EXTENSION: &str = "dropdl-part";
+pub const DEFAULT_PEER_QUOTA_WINDOW: Duration = Duration::new(24 * 60 * 60, 0);
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+// Well below the WebSocket frame limit of the receivers
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+pub const DEFAULT_UPLOAD_BUFFER_BYTES: usize = 16 * 1024 * 1024;
+pub const MAX_PARALLEL_RANGES: usize = 8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delays() {
+        let mut policy = RetryPolicy {
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(10),
+            backoff: RetryBackoff::Exponential,
+            jitter: false,
+        };
+
+        let delays: Vec<_> = (0..5).map(|retry| policy.delay(retry).as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 10, 10]);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+
+        policy.backoff = RetryBackoff::Linear;
+        let delays: Vec<_> = (0..5).map(|retry| policy.delay(retry).as_secs()).collect();
+        assert_eq!(delays, [2, 4, 6, 8, 10]);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn transfer_mode_directions() {
+        assert!(TransferMode::Both.can_send());
+        assert!(TransferMode::Both.can_receive());
+
+        assert!(!TransferMode::ReceiveOnly.can_send());
+        assert!(TransferMode::ReceiveOnly.can_receive());
+
+        assert!(TransferMode::SendOnly.can_send());
+        assert!(!TransferMode::SendOnly.can_receive());
+    }
+}
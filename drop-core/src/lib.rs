@@ -1,3 +1,3 @@
 mod status;
 
-pub use status::Status;
+pub use status::{ErrorCategory, Status};
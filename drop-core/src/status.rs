@@ -25,6 +25,13 @@ pub enum Status {
     ConnectionClosedByPeer = 38,
     TooManyRequests = 39,
     PermissionDenied = 40,
+    ConnectionDenied = 41,
+    Interrupted = 42,
+    PeerKeyUnavailable = 43,
+    QuotaExceeded = 44,
+    PeerBusy = 45,
+    Expired = 46,
+    ReceiveDisabled = 47,
 }
 
 impl serde::Serialize for Status {
@@ -36,6 +43,15 @@ impl serde::Serialize for Status {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for Status {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        u32::deserialize(deserializer).map(Self::from)
+    }
+}
+
 impl From<u32> for Status {
     fn from(value: u32) -> Self {
         use Status::*;
@@ -65,8 +81,88 @@ impl From<u32> for Status {
             38 => ConnectionClosedByPeer,
             39 => TooManyRequests,
             40 => PermissionDenied,
+            41 => ConnectionDenied,
+            42 => Interrupted,
+            43 => PeerKeyUnavailable,
+            44 => QuotaExceeded,
+            45 => PeerBusy,
+            46 => Expired,
+            47 => ReceiveDisabled,
             _unknown => IoError, /* Use IO error because we have no clue what it is. This
                                   * shouldn't happen */
         }
     }
 }
+
+/// The broad class of the failure, for the apps to pick the message and the
+/// recovery action without matching on every status code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    #[default]
+    Unknown,
+    /// Reading or writing the local files failed
+    Filesystem,
+    /// The connection failed or was lost
+    Network,
+    /// The peer refused or could not serve the request
+    Peer,
+    /// The peer could not be authenticated
+    Auth,
+    /// The request exceeds the configured limits
+    Policy,
+    /// The local database failed
+    Storage,
+    /// The app passed the invalid arguments
+    InvalidRequest,
+    /// The operation does not apply to the transfer or file in its state
+    State,
+    /// The transferred data does not match the source
+    Integrity,
+}
+
+impl Status {
+    pub fn category(self) -> ErrorCategory {
+        use Status::*;
+
+        match self {
+            Finalized => ErrorCategory::Unknown,
+            BadPath | BadFile | FilenameTooLong | IoError | PermissionDenied | FileModified => {
+                ErrorCategory::Filesystem
+            }
+            BadTransfer | BadFileId | InvalidArgument | EmptyTransfer => {
+                ErrorCategory::InvalidRequest
+            }
+            BadTransferState | FileRejected | FileFailed | FileFinished => ErrorCategory::State,
+            TransferLimitsExceeded | QuotaExceeded | Expired | ReceiveDisabled => {
+                ErrorCategory::Policy
+            }
+            MismatchedSize | FileChecksumMismatch => ErrorCategory::Integrity,
+            AddrInUse | ConnectionClosedByPeer | Interrupted => ErrorCategory::Network,
+            AuthenticationFailed | PeerKeyUnavailable => ErrorCategory::Auth,
+            StorageError | DbLost => ErrorCategory::Storage,
+            TooManyRequests | ConnectionDenied | PeerBusy => ErrorCategory::Peer,
+        }
+    }
+
+    /// Whether retrying the same operation later can succeed without the user
+    /// changing anything
+    pub fn is_retryable(self) -> bool {
+        use Status::*;
+
+        matches!(
+            self,
+            IoError
+                | MismatchedSize
+                | AddrInUse
+                | FileModified
+                | FileChecksumMismatch
+                | ConnectionClosedByPeer
+                | TooManyRequests
+                | Interrupted
+                | PeerKeyUnavailable
+                | QuotaExceeded
+                | PeerBusy
+        )
+    }
+}
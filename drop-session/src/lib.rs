@@ -0,0 +1,508 @@
+//! The per transfer state machine driven by the events of the service. It
+//! keeps the current state of the transfers and their files so the frontends
+//! can query it instead of reimplementing the transitions themselves.
+//!
+//! Only the transfers announced with the request event since the session was
+//! created are tracked, the events of other transfers are ignored.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use drop_transfer::{Event, File, FileId, Transfer};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// Waiting for any of the files to start
+    Pending,
+    /// At least one of the files has started
+    Active,
+    /// All of the files reached the terminal state
+    Finished,
+    /// Canceled or withdrawn by either side
+    Canceled {
+        by_peer: bool,
+    },
+    Failed,
+}
+
+impl TransferState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Finished | Self::Canceled { .. } | Self::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    Pending,
+    /// Queued for the download, waiting for the slot
+    Queued,
+    InProgress,
+    Paused,
+    Completed,
+    Failed,
+    Rejected {
+        by_peer: bool,
+    },
+    /// Withdrawn by the sender before it was started
+    Withdrawn {
+        by_peer: bool,
+    },
+}
+
+impl FileState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Self::Completed | Self::Failed | Self::Rejected { .. } | Self::Withdrawn { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSession {
+    pub id: FileId,
+    pub size: u64,
+    pub transferred: u64,
+    pub state: FileState,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferSession {
+    pub id: Uuid,
+    pub peer: IpAddr,
+    pub direction: Direction,
+    pub state: TransferState,
+    pub files: HashMap<FileId, FileSession>,
+}
+
+impl FileSession {
+    fn new(file: &impl File) -> Self {
+        Self {
+            id: file.id().clone(),
+            size: file.size(),
+            transferred: 0,
+            state: FileState::Pending,
+        }
+    }
+}
+
+impl TransferSession {
+    fn new<T: Transfer>(xfer: &T, direction: Direction) -> Self {
+        let files = xfer
+            .files()
+            .values()
+            .map(|file| (file.id().clone(), FileSession::new(file)))
+            .collect();
+
+        Self {
+            id: xfer.id(),
+            peer: xfer.peer(),
+            direction,
+            state: TransferState::Pending,
+            files,
+        }
+    }
+
+    pub fn transferred(&self) -> u64 {
+        self.files.values().map(|file| file.transferred).sum()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.values().map(|file| file.size).sum()
+    }
+
+    fn update_file(&mut self, file_id: &FileId, update: impl FnOnce(&mut FileSession)) {
+        if self.state.is_terminal() {
+            return;
+        }
+
+        let Some(file) = self.files.get_mut(file_id) else {
+            return;
+        };
+        // The late events, e.g. the progress racing with the failure, must
+        // not revive the file
+        if file.state.is_terminal() {
+            return;
+        }
+
+        update(file);
+
+        if file.state == FileState::InProgress && self.state == TransferState::Pending {
+            self.state = TransferState::Active;
+        }
+        if self.files.values().all(|file| file.state.is_terminal()) {
+            self.state = TransferState::Finished;
+        }
+    }
+
+    /// Tracks the files added to the transfer after the request
+    fn add_files<T: Transfer>(&mut self, xfer: &T, file_ids: &[FileId]) {
+        if self.state.is_terminal() {
+            return;
+        }
+
+        for file_id in file_ids {
+            if let Some(file) = xfer.files().get(file_id) {
+                self.files
+                    .entry(file_id.clone())
+                    .or_insert_with(|| FileSession::new(file));
+            }
+        }
+    }
+
+    fn finish(&mut self, state: TransferState) {
+        if !self.state.is_terminal() {
+            self.state = state;
+        }
+    }
+}
+
+/// The state of all of the transfers seen in the events
+#[derive(Debug, Default)]
+pub struct Session {
+    transfers: HashMap<Uuid, TransferSession>,
+}
+
+impl Session {
+    pub fn transfer(&self, transfer_id: Uuid) -> Option<&TransferSession> {
+        self.transfers.get(&transfer_id)
+    }
+
+    pub fn transfers(&self) -> impl Iterator<Item = &TransferSession> {
+        self.transfers.values()
+    }
+
+    /// Forgets the transfers which reached the terminal state
+    pub fn clear_finished(&mut self) {
+        self.transfers.retain(|_, xfer| !xfer.state.is_terminal());
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            Event::RequestReceived(xfer) => {
+                self.transfers.insert(
+                    xfer.id(),
+                    TransferSession::new(xfer.as_ref(), Direction::Incoming),
+                );
+            }
+            Event::RequestQueued(xfer) => {
+                self.transfers.insert(
+                    xfer.id(),
+                    TransferSession::new(xfer.as_ref(), Direction::Outgoing),
+                );
+            }
+
+            Event::IncomingFilesAdded(xfer, file_ids) => {
+                if let Some(session) = self.transfers.get_mut(&xfer.id()) {
+                    session.add_files(xfer.as_ref(), file_ids);
+                }
+            }
+            Event::OutgoingFilesAdded(xfer, file_ids) => {
+                if let Some(session) = self.transfers.get_mut(&xfer.id()) {
+                    session.add_files(xfer.as_ref(), file_ids);
+                }
+            }
+
+            Event::FileUploadStarted(xfer, file_id, offset) => {
+                self.file_started(xfer.id(), file_id, *offset)
+            }
+            Event::FileDownloadStarted(xfer, file_id, _, offset) => {
+                self.file_started(xfer.id(), file_id, *offset)
+            }
+            Event::FileDownloadPending {
+                transfer_id,
+                file_id,
+                ..
+            } => self.update_file(*transfer_id, file_id, |file| {
+                file.state = FileState::Queued;
+            }),
+
+            Event::FileUploadProgress(xfer, file_id, transferred) => {
+                self.file_progress(xfer.id(), file_id, *transferred)
+            }
+            Event::FileDownloadProgress(xfer, file_id, transferred) => {
+                self.file_progress(xfer.id(), file_id, *transferred)
+            }
+            Event::FileUploadThrottled {
+                transfer_id,
+                file_id,
+                transferred,
+            } => self.file_progress(*transfer_id, file_id, *transferred),
+
+            Event::FileUploadSuccess(xfer, file_id, _) => self.file_completed(xfer.id(), file_id),
+            Event::FileDownloadSuccess(xfer, success) => {
+                self.file_completed(xfer.id(), &success.id)
+            }
+
+            Event::FileUploadFailed(xfer, file_id, _) => {
+                self.update_file(xfer.id(), file_id, |file| file.state = FileState::Failed)
+            }
+            Event::FileDownloadFailed(xfer, file_id, _, _) => {
+                self.update_file(xfer.id(), file_id, |file| file.state = FileState::Failed)
+            }
+
+            Event::FileUploadPaused {
+                transfer_id,
+                file_id,
+            }
+            | Event::FileDownloadPaused {
+                transfer_id,
+                file_id,
+            } => self.update_file(*transfer_id, file_id, |file| {
+                file.state = FileState::Paused;
+            }),
+
+            Event::FileUploadRejected {
+                transfer_id,
+                file_id,
+                by_peer,
+            }
+            | Event::FileDownloadRejected {
+                transfer_id,
+                file_id,
+                by_peer,
+            } => self.update_file(*transfer_id, file_id, |file| {
+                file.state = FileState::Rejected { by_peer: *by_peer };
+            }),
+            Event::FileUploadWithdrawn {
+                transfer_id,
+                file_id,
+            } => self.update_file(*transfer_id, file_id, |file| {
+                file.state = FileState::Withdrawn { by_peer: false };
+            }),
+            Event::FileDownloadWithdrawn {
+                transfer_id,
+                file_id,
+            } => self.update_file(*transfer_id, file_id, |file| {
+                file.state = FileState::Withdrawn { by_peer: true };
+            }),
+
+            Event::IncomingTransferCanceled(xfer, by_peer)
+            | Event::IncomingTransferWithdrawn(xfer, by_peer) => {
+                self.finish(xfer.id(), TransferState::Canceled { by_peer: *by_peer })
+            }
+            Event::OutgoingTransferCanceled(xfer, by_peer)
+            | Event::OutgoingTransferWithdrawn(xfer, by_peer) => {
+                self.finish(xfer.id(), TransferState::Canceled { by_peer: *by_peer })
+            }
+            Event::OutgoingTransferFailed(xfer, _, _) => {
+                self.finish(xfer.id(), TransferState::Failed)
+            }
+
+            _ => (),
+        }
+    }
+
+    fn update_file(
+        &mut self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+        update: impl FnOnce(&mut FileSession),
+    ) {
+        if let Some(xfer) = self.transfers.get_mut(&transfer_id) {
+            xfer.update_file(file_id, update);
+        }
+    }
+
+    fn file_started(&mut self, transfer_id: Uuid, file_id: &FileId, offset: u64) {
+        self.update_file(transfer_id, file_id, |file| {
+            file.state = FileState::InProgress;
+            file.transferred = offset;
+        });
+    }
+
+    fn file_progress(&mut self, transfer_id: Uuid, file_id: &FileId, transferred: u64) {
+        self.update_file(transfer_id, file_id, |file| {
+            file.state = FileState::InProgress;
+            file.transferred = transferred;
+        });
+    }
+
+    fn file_completed(&mut self, transfer_id: Uuid, file_id: &FileId) {
+        self.update_file(transfer_id, file_id, |file| {
+            file.state = FileState::Completed;
+            file.transferred = file.size;
+        });
+    }
+
+    fn finish(&mut self, transfer_id: Uuid, state: TransferState) {
+        if let Some(xfer) = self.transfers.get_mut(&transfer_id) {
+            xfer.finish(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use drop_transfer::{Error, FileToRecv, IncomingTransfer};
+
+    use super::*;
+
+    fn incoming() -> Arc<IncomingTransfer> {
+        let files = vec![
+            FileToRecv::new("a".into(), String::from("dir/a").into(), 10),
+            FileToRecv::new("b".into(), String::from("dir/b").into(), 20),
+        ];
+
+        Arc::new(
+            IncomingTransfer::new(
+                "127.0.0.1".parse().unwrap(),
+                files,
+                &drop_config::DropConfig::default(),
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn transitions() {
+        let xfer = incoming();
+        let (a, b) = (FileId::from("a"), FileId::from("b"));
+
+        let mut session = Session::default();
+        session.handle_event(&Event::RequestReceived(xfer.clone()));
+
+        let state = session.transfer(xfer.id()).unwrap();
+        assert_eq!(state.direction, Direction::Incoming);
+        assert_eq!(state.state, TransferState::Pending);
+        assert_eq!(state.total_size(), 30);
+
+        session.handle_event(&Event::FileDownloadStarted(
+            xfer.clone(),
+            a.clone(),
+            "/tmp".into(),
+            2,
+        ));
+        session.handle_event(&Event::FileDownloadProgress(xfer.clone(), a.clone(), 5));
+
+        let state = session.transfer(xfer.id()).unwrap();
+        assert_eq!(state.state, TransferState::Active);
+        assert_eq!(state.files[&a].state, FileState::InProgress);
+        assert_eq!(state.transferred(), 5);
+
+        // The late progress must not revive the failed file
+        session.handle_event(&Event::FileDownloadFailed(
+            xfer.clone(),
+            a.clone(),
+            Error::BadFile,
+            None,
+        ));
+        session.handle_event(&Event::FileDownloadProgress(xfer.clone(), a.clone(), 7));
+        assert_eq!(
+            session.transfer(xfer.id()).unwrap().files[&a].state,
+            FileState::Failed
+        );
+
+        session.handle_event(&Event::FileDownloadRejected {
+            transfer_id: xfer.id(),
+            file_id: b.clone(),
+            by_peer: true,
+        });
+
+        let state = session.transfer(xfer.id()).unwrap();
+        assert_eq!(state.state, TransferState::Finished);
+        assert_eq!(state.files[&b].state, FileState::Rejected { by_peer: true });
+
+        // The terminal state is kept
+        session.handle_event(&Event::IncomingTransferCanceled(xfer.clone(), false));
+        assert_eq!(
+            session.transfer(xfer.id()).unwrap().state,
+            TransferState::Finished
+        );
+
+        session.clear_finished();
+        assert!(session.transfer(xfer.id()).is_none());
+    }
+
+    #[test]
+    fn cancel() {
+        let xfer = incoming();
+
+        let mut session = Session::default();
+        session.handle_event(&Event::FileDownloadPaused {
+            transfer_id: xfer.id(),
+            file_id: "a".into(),
+        });
+        assert!(session.transfer(xfer.id()).is_none());
+
+        session.handle_event(&Event::RequestReceived(xfer.clone()));
+        session.handle_event(&Event::IncomingTransferCanceled(xfer.clone(), true));
+
+        let state = session.transfer(xfer.id()).unwrap();
+        assert_eq!(state.state, TransferState::Canceled { by_peer: true });
+        assert_eq!(state.files[&FileId::from("a")].state, FileState::Pending);
+    }
+
+    #[test]
+    fn withdrawn() {
+        let xfer = incoming();
+        let (a, b) = (FileId::from("a"), FileId::from("b"));
+
+        let mut session = Session::default();
+        session.handle_event(&Event::RequestReceived(xfer.clone()));
+        session.handle_event(&Event::FileDownloadWithdrawn {
+            transfer_id: xfer.id(),
+            file_id: a.clone(),
+        });
+
+        let state = session.transfer(xfer.id()).unwrap();
+        assert_eq!(
+            state.files[&a].state,
+            FileState::Withdrawn { by_peer: true }
+        );
+        assert_eq!(state.files[&b].state, FileState::Pending);
+        assert_eq!(state.state, TransferState::Pending);
+    }
+
+    #[test]
+    fn files_added() {
+        let xfer = incoming();
+        let (a, b) = (FileId::from("a"), FileId::from("b"));
+
+        let mut session = Session::default();
+        session.handle_event(&Event::RequestReceived(xfer.clone()));
+
+        // The transfer is announced with the first file only
+        session
+            .transfers
+            .get_mut(&xfer.id())
+            .unwrap()
+            .files
+            .remove(&b);
+        session.handle_event(&Event::IncomingFilesAdded(xfer.clone(), vec![b.clone()]));
+
+        let state = session.transfer(xfer.id()).unwrap();
+        assert_eq!(state.files[&b].state, FileState::Pending);
+        assert_eq!(state.total_size(), 30);
+
+        // The added file keeps the transfer from finishing
+        session.handle_event(&Event::FileDownloadStarted(
+            xfer.clone(),
+            a.clone(),
+            "/tmp".into(),
+            0,
+        ));
+        session.handle_event(&Event::FileDownloadSuccess(
+            xfer.clone(),
+            drop_transfer::event::DownloadSuccess {
+                id: a,
+                final_path: drop_transfer::utils::Hidden(
+                    std::path::PathBuf::from("/tmp/a").into_boxed_path(),
+                ),
+                path_sanitized: false,
+            },
+        ));
+        assert_eq!(
+            session.transfer(xfer.id()).unwrap().state,
+            TransferState::Active
+        );
+    }
+}
@@ -0,0 +1,7 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS event_journal (
+  seq INTEGER PRIMARY KEY AUTOINCREMENT,
+  timestamp INTEGER NOT NULL,
+  payload TEXT NOT NULL
+);
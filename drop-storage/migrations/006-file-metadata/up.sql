@@ -0,0 +1,17 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS incoming_path_metadata (
+  path_id INTEGER PRIMARY KEY,
+  mime_type TEXT DEFAULT NULL,
+  mtime INTEGER DEFAULT NULL,
+  permissions INTEGER DEFAULT NULL,
+  FOREIGN KEY(path_id) REFERENCES incoming_paths(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS outgoing_path_metadata (
+  path_id INTEGER PRIMARY KEY,
+  mime_type TEXT DEFAULT NULL,
+  mtime INTEGER DEFAULT NULL,
+  permissions INTEGER DEFAULT NULL,
+  FOREIGN KEY(path_id) REFERENCES outgoing_paths(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE outgoing_path_completed_states ADD COLUMN final_path TEXT DEFAULT NULL;
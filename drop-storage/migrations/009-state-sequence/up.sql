@@ -0,0 +1,121 @@
+-- Add migration script here
+
+-- The local order of the states. Unlike the timestamps it is not affected by
+-- the wall-clock changes. The states recorded before have it set to 0
+CREATE TABLE IF NOT EXISTS state_sequence (
+  value INTEGER NOT NULL
+);
+
+INSERT INTO state_sequence (value) VALUES (0);
+
+ALTER TABLE transfer_cancel_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS transfer_cancel_states_seq AFTER INSERT ON transfer_cancel_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE transfer_cancel_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE transfer_failed_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS transfer_failed_states_seq AFTER INSERT ON transfer_failed_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE transfer_failed_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE outgoing_path_started_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS outgoing_path_started_states_seq AFTER INSERT ON outgoing_path_started_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE outgoing_path_started_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE outgoing_path_failed_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS outgoing_path_failed_states_seq AFTER INSERT ON outgoing_path_failed_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE outgoing_path_failed_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE outgoing_path_completed_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS outgoing_path_completed_states_seq AFTER INSERT ON outgoing_path_completed_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE outgoing_path_completed_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE outgoing_path_reject_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS outgoing_path_reject_states_seq AFTER INSERT ON outgoing_path_reject_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE outgoing_path_reject_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE outgoing_path_paused_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS outgoing_path_paused_states_seq AFTER INSERT ON outgoing_path_paused_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE outgoing_path_paused_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE incoming_path_pending_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS incoming_path_pending_states_seq AFTER INSERT ON incoming_path_pending_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE incoming_path_pending_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE incoming_path_started_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS incoming_path_started_states_seq AFTER INSERT ON incoming_path_started_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE incoming_path_started_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE incoming_path_failed_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS incoming_path_failed_states_seq AFTER INSERT ON incoming_path_failed_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE incoming_path_failed_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE incoming_path_completed_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS incoming_path_completed_states_seq AFTER INSERT ON incoming_path_completed_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE incoming_path_completed_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE incoming_path_reject_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS incoming_path_reject_states_seq AFTER INSERT ON incoming_path_reject_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE incoming_path_reject_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+ALTER TABLE incoming_path_paused_states ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER IF NOT EXISTS incoming_path_paused_states_seq AFTER INSERT ON incoming_path_paused_states
+BEGIN
+  UPDATE state_sequence SET value = value + 1;
+  UPDATE incoming_path_paused_states SET seq = (SELECT value FROM state_sequence) WHERE rowid = NEW.rowid;
+END;
+
+-- The difference between the peer's and the local wall clock in
+-- milliseconds, estimated when connecting
+CREATE TABLE IF NOT EXISTS transfer_clock_skews (
+  transfer_id TEXT PRIMARY KEY,
+  skew_ms INTEGER NOT NULL,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+
+-- The annotation set by the app on the transfer
+CREATE TABLE IF NOT EXISTS transfer_notes (
+  transfer_id TEXT PRIMARY KEY,
+  note TEXT NOT NULL,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- The store-and-forward routing of the transfers passing through the relay
+-- instance. The origin is the transfer ID given by the original sender
+CREATE TABLE IF NOT EXISTS transfer_forwarding (
+  transfer_id TEXT PRIMARY KEY,
+  origin_id TEXT NOT NULL,
+  forward_to TEXT DEFAULT NULL,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+
+-- The short human-friendly code of the transfer, usable in place of its ID
+CREATE TABLE IF NOT EXISTS transfer_aliases (
+  transfer_id TEXT PRIMARY KEY,
+  alias TEXT NOT NULL UNIQUE,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
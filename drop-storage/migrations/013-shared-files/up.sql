@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- The cursor of the directory shared with the peer, the version of every file
+-- already sent
+CREATE TABLE IF NOT EXISTS shared_files (
+  dir TEXT NOT NULL,
+  peer TEXT NOT NULL,
+  subpath TEXT NOT NULL,
+  size INTEGER NOT NULL,
+  mtime INTEGER NOT NULL,
+  PRIMARY KEY(dir, peer, subpath)
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+
+-- The bytes received from each peer, counted into its quota. Kept apart from
+-- the transfers so that purging the history does not reset the quota
+CREATE TABLE IF NOT EXISTS peer_usage (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  peer TEXT NOT NULL,
+  bytes INTEGER NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+  CHECK(bytes >= 0)
+);
@@ -0,0 +1,13 @@
+-- Add migration script here
+
+-- The SHA-256 of the whole files sent before, valid as long as the size and
+-- the modification time of the file stay the same. One row per path, replaced
+-- once the file changes. The least recently used rows are evicted
+CREATE TABLE IF NOT EXISTS checksum_cache (
+  path TEXT PRIMARY KEY NOT NULL,
+  size INTEGER NOT NULL,
+  mtime INTEGER NOT NULL,
+  checksum BLOB NOT NULL,
+  -- Grows with each use, the rows with the lowest are evicted first
+  last_use INTEGER NOT NULL DEFAULT 0
+);
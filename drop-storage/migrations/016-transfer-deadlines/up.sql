@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- The deadline, in milliseconds since the Unix epoch, by which the receiver
+-- has to start the files of the outgoing transfer. The files not started by
+-- then expire
+CREATE TABLE IF NOT EXISTS transfer_deadlines (
+  transfer_id TEXT PRIMARY KEY,
+  expires_at INTEGER NOT NULL,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- The opaque label the app attaches to the transfer when creating it, e.g. to
+-- group the transfers of a single chat or session. It travels to the receiver
+-- with the request
+CREATE TABLE IF NOT EXISTS transfer_labels (
+  transfer_id TEXT PRIMARY KEY,
+  label TEXT NOT NULL,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,19 @@
+-- Add migration script here
+
+-- The key-value pairs the sending app attached to the file, carried in the
+-- transfer manifest
+CREATE TABLE IF NOT EXISTS incoming_path_app_metadata (
+  path_id INTEGER NOT NULL,
+  key TEXT NOT NULL,
+  value TEXT NOT NULL,
+  PRIMARY KEY(path_id, key),
+  FOREIGN KEY(path_id) REFERENCES incoming_paths(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS outgoing_path_app_metadata (
+  path_id INTEGER NOT NULL,
+  key TEXT NOT NULL,
+  value TEXT NOT NULL,
+  PRIMARY KEY(path_id, key),
+  FOREIGN KEY(path_id) REFERENCES outgoing_paths(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- The files withdrawn from the transfer by the sender before they were
+-- started. These are terminal like the rejected ones, but reported apart
+ALTER TABLE outgoing_path_reject_states ADD COLUMN withdrawn BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE incoming_path_reject_states ADD COLUMN withdrawn BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- The name and the platform each peer declared about itself in the last
+-- connection handshake
+CREATE TABLE IF NOT EXISTS peers (
+  peer TEXT PRIMARY KEY NOT NULL,
+  device_name TEXT,
+  platform TEXT,
+  updated_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+
+-- The public keys of the peers which authenticated their capabilities in the
+-- connection handshake. The unauthenticated capabilities of those are refused
+CREATE TABLE IF NOT EXISTS capability_pins (
+  public_key TEXT PRIMARY KEY NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW'))
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- The digest of the peer and the file set the outgoing transfer was requested
+-- with. The same request made again while the transfer is still pending
+-- returns that transfer instead of creating a new one
+CREATE TABLE IF NOT EXISTS transfer_request_keys (
+  transfer_id TEXT PRIMARY KEY,
+  request_key TEXT NOT NULL,
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- The port each peer listens on, as it declared in the last connection
+-- handshake. The receiver dials it to check whether the transfer is still alive
+ALTER TABLE peers ADD COLUMN listen_port INTEGER;
@@ -0,0 +1,84 @@
+//! Write-behind batching of the event journal. The frequent events, like the
+//! progress ones, are queued in memory and written in a single transaction
+//! once [`JOURNAL_BATCH_EVENTS`] of them pile up, any other event is written
+//! or the timer run by [`crate::Storage::flush_journal_periodically`] fires
+//! [`JOURNAL_FLUSH_INTERVAL`] after the first of them was queued. The reads of
+//! the journal flush the queue first, so they never miss an event. Up to a
+//! batch of the queued events is lost when the process dies, which is fine
+//! for the events the next ones supersede.
+
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+
+use crate::{types::JournalEvent, Result};
+
+pub const JOURNAL_BATCH_EVENTS: usize = 64;
+pub const JOURNAL_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+pub(crate) struct JournalBatch {
+    // Assigned here rather than by the database so the queued events get
+    // their sequence numbers at once. Loaded on the first use
+    next_seq: Option<i64>,
+    pending: Vec<JournalEvent>,
+}
+
+impl JournalBatch {
+    /// Queues the event, returns its sequence number
+    pub(crate) fn push(&mut self, conn: &Connection, timestamp: i64, payload: &str) -> Result<i64> {
+        let seq = match self.next_seq {
+            Some(seq) => seq,
+            None => last_seq(conn)? + 1,
+        };
+        self.next_seq = Some(seq + 1);
+
+        self.pending.push(JournalEvent {
+            seq,
+            timestamp,
+            payload: payload.to_string(),
+        });
+
+        Ok(seq)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub(crate) fn is_due(&self) -> bool {
+        self.pending.len() >= JOURNAL_BATCH_EVENTS
+    }
+
+    /// Writes all of the pending events. They are dropped on failure, so that
+    /// the broken database does not make the queue grow
+    pub(crate) fn flush(&mut self, conn: &mut Connection) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        let conn = conn.transaction()?;
+        for event in &pending {
+            conn.execute(
+                "INSERT INTO event_journal (seq, timestamp, payload) VALUES (?1, ?2, ?3)",
+                params![event.seq, event.timestamp, event.payload],
+            )?;
+        }
+        conn.commit()?;
+
+        Ok(())
+    }
+}
+
+// The highest sequence number ever assigned, the purged events included
+fn last_seq(conn: &Connection) -> Result<i64> {
+    let seq = conn.query_row(
+        "SELECT MAX(COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'event_journal'), 0), \
+         COALESCE((SELECT MAX(seq) FROM event_journal), 0))",
+        [],
+        |r| r.get(0),
+    )?;
+
+    Ok(seq)
+}
@@ -1,11 +1,13 @@
 pub mod error;
+mod journal;
+pub mod path;
 pub mod sync;
 pub mod types;
 
 use std::{
     collections::{
         hash_map::Entry::{Occupied, Vacant},
-        HashMap,
+        BTreeMap, HashMap,
     },
     io,
     path::Path,
@@ -13,20 +15,27 @@ use std::{
 };
 
 use include_dir::{include_dir, Dir};
-use rusqlite::{params, Connection, OpenFlags, Transaction};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
 use rusqlite_migration::Migrations;
 use slog::{debug, error, trace, warn, Logger};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 use types::{
-    DbTransferType, FileSyncState, IncomingFileToRetry, IncomingPath, IncomingPathStateEvent,
-    IncomingPathStateEventData, IncomingTransferToRetry, OutgoingFileToRetry, OutgoingPath,
-    OutgoingPathStateEvent, OutgoingPathStateEventData, TempFileLocation, Transfer, TransferFiles,
-    TransferIncomingPath, TransferOutgoingPath, TransferStateEvent, TransferType,
+    DbTransferType, DirEntry, FileMetadata, FileSyncState, IncomingFileToRetry, IncomingPath,
+    IncomingPathStateEvent, IncomingPathStateEventData, IncomingTransferToRetry,
+    OutgoingFileToRetry, OutgoingPath, OutgoingPathStateEvent, OutgoingPathStateEventData,
+    TempFileLocation, Transfer, TransferFiles, TransferIncomingPath, TransferOutgoingPath,
+    TransferStateEvent, TransferType,
 };
 use uuid::Uuid;
 
-use crate::error::Error;
-pub use crate::types::{FileChecksum, FinishedIncomingFile, OutgoingTransferToRetry, TransferInfo};
+use crate::{error::Error, journal::JournalBatch};
+pub use crate::{
+    journal::{JOURNAL_BATCH_EVENTS, JOURNAL_FLUSH_INTERVAL},
+    types::{
+        FileChecksum, FinishedIncomingFile, JournalEvent, OutgoingTransferToRetry, PeerDevice,
+        SharedFile, TransferForwarding, TransferInfo,
+    },
+};
 
 type Result<T> = std::result::Result<T, Error>;
 type QueryResult<T> = std::result::Result<T, rusqlite::Error>;
@@ -34,11 +43,19 @@ type QueryResult<T> = std::result::Result<T, rusqlite::Error>;
 // SQLite storage wrapper
 pub struct Storage {
     conn: Mutex<Connection>,
+    // Locked before the connection
+    journal: Mutex<JournalBatch>,
+    // Notified when the first event of a batch is queued
+    journal_queued: Notify,
     logger: Logger,
 }
 
 const MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
 
+// The checksums of this many files are kept, the least recently used ones are
+// evicted
+const MAX_CACHED_CHECKSUMS: usize = 10_000;
+
 #[cfg(unix)]
 fn prepare_sqlite_file(path: &str) -> io::Result<OpenFlags> {
     use std::os::unix::prelude::{OpenOptionsExt, PermissionsExt};
@@ -79,6 +96,14 @@ fn prepare_sqlite_file(_: &str) -> io::Result<OpenFlags> {
     Ok(OpenFlags::default())
 }
 
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Err(e) = self.journal.get_mut().flush(self.conn.get_mut()) {
+            error!(self.logger, "Failed to flush journal events"; "error" => %e);
+        }
+    }
+}
+
 impl Storage {
     pub fn new(logger: Logger, path: &str) -> Result<Self> {
         let flags = prepare_sqlite_file(path)?;
@@ -94,6 +119,8 @@ impl Storage {
         Ok(Self {
             logger,
             conn: Mutex::new(conn),
+            journal: Mutex::new(JournalBatch::default()),
+            journal_queued: Notify::new(),
         })
     }
 
@@ -171,6 +198,40 @@ impl Storage {
         }
     }
 
+    /// Adds the files to the transfer already inserted, the files already in
+    /// it are skipped
+    pub async fn insert_transfer_files(&self, transfer_id: Uuid, files: &TransferFiles) {
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            let is_incoming = match files {
+                TransferFiles::Incoming(files) => {
+                    for file in files {
+                        Self::insert_incoming_path(&self.logger, &conn, transfer_id, file);
+                    }
+                    true
+                }
+                TransferFiles::Outgoing(files) => {
+                    for file in files {
+                        Self::insert_outgoing_path(&self.logger, &conn, transfer_id, file);
+                    }
+                    false
+                }
+            };
+
+            sync::insert_files(&conn, transfer_id, is_incoming)?;
+
+            conn.commit()?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert transfer files"; "error" => %e);
+        }
+    }
+
     pub async fn update_transfer_sync_states(&self, transfer_id: Uuid, local: sync::TransferState) {
         let task = async {
             let conn = self.conn.lock().await;
@@ -243,6 +304,14 @@ impl Storage {
                         WHERE op.transfer_id = ?1 AND op.path_hash = ?2
                         LIMIT 1
                     ) as is_failed,
+                    (
+                        SELECT opfs.status_code
+                        FROM outgoing_path_failed_states opfs
+                        INNER JOIN outgoing_paths op ON op.id = opfs.path_id
+                        WHERE op.transfer_id = ?1 AND op.path_hash = ?2
+                        ORDER BY opfs.id DESC
+                        LIMIT 1
+                    ) as failed_status,
                     EXISTS (
                         SELECT 1
                         FROM outgoing_path_completed_states opcs
@@ -256,19 +325,39 @@ impl Storage {
                         INNER JOIN outgoing_paths op ON op.id = oprs.path_id
                         WHERE op.transfer_id = ?1 AND op.path_hash = ?2
                         LIMIT 1
-                    ) as is_rejected
+                    ) as is_rejected,
+                    EXISTS (
+                        SELECT 1
+                        FROM outgoing_path_reject_states oprs
+                        INNER JOIN outgoing_paths op ON op.id = oprs.path_id
+                        WHERE op.transfer_id = ?1 AND op.path_hash = ?2 AND oprs.withdrawn
+                        LIMIT 1
+                    ) as is_withdrawn,
+                    EXISTS (
+                        SELECT 1
+                        FROM outgoing_path_started_states opss
+                        INNER JOIN outgoing_paths op ON op.id = opss.path_id
+                        WHERE op.transfer_id = ?1 AND op.path_hash = ?2
+                        LIMIT 1
+                    ) as is_started
                 "#,
                 params![tid, file_id],
                 |r| {
                     let is_failed = r.get("is_failed")?;
+                    let failed_status = r.get("failed_status")?;
                     let is_success = r.get("is_completed")?;
                     let is_rejected = r.get("is_rejected")?;
+                    let is_withdrawn = r.get("is_withdrawn")?;
+                    let is_started = r.get("is_started")?;
 
                     Ok(FileSyncState {
                         sync,
                         is_rejected,
                         is_success,
                         is_failed,
+                        failed_status,
+                        is_withdrawn,
+                        is_started,
                     })
                 },
             )?;
@@ -333,6 +422,14 @@ impl Storage {
                         WHERE ip.transfer_id = ?1 AND ip.path_hash = ?2
                         LIMIT 1
                     ) as is_failed,
+                    (
+                        SELECT ipfs.status_code
+                        FROM incoming_path_failed_states ipfs
+                        INNER JOIN incoming_paths ip ON ip.id = ipfs.path_id
+                        WHERE ip.transfer_id = ?1 AND ip.path_hash = ?2
+                        ORDER BY ipfs.id DESC
+                        LIMIT 1
+                    ) as failed_status,
                     EXISTS (
                         SELECT 1
                         FROM incoming_path_completed_states ipcs
@@ -346,19 +443,39 @@ impl Storage {
                         INNER JOIN incoming_paths ip ON ip.id = iprs.path_id
                         WHERE ip.transfer_id = ?1 AND ip.path_hash = ?2
                         LIMIT 1
-                    ) as is_rejected
+                    ) as is_rejected,
+                    EXISTS (
+                        SELECT 1
+                        FROM incoming_path_reject_states iprs
+                        INNER JOIN incoming_paths ip ON ip.id = iprs.path_id
+                        WHERE ip.transfer_id = ?1 AND ip.path_hash = ?2 AND iprs.withdrawn
+                        LIMIT 1
+                    ) as is_withdrawn,
+                    EXISTS (
+                        SELECT 1
+                        FROM incoming_path_started_states ipss
+                        INNER JOIN incoming_paths ip ON ip.id = ipss.path_id
+                        WHERE ip.transfer_id = ?1 AND ip.path_hash = ?2
+                        LIMIT 1
+                    ) as is_started
                 "#,
                 params![tid, file_id],
                 |r| {
                     let is_failed = r.get("is_failed")?;
+                    let failed_status = r.get("failed_status")?;
                     let is_success = r.get("is_completed")?;
                     let is_rejected = r.get("is_rejected")?;
+                    let is_withdrawn = r.get("is_withdrawn")?;
+                    let is_started = r.get("is_started")?;
 
                     Ok(FileSyncState {
                         sync,
                         is_rejected,
                         is_success,
                         is_failed,
+                        failed_status,
+                        is_withdrawn,
+                        is_started,
                     })
                 },
             )?;
@@ -429,6 +546,16 @@ impl Storage {
                 params![tid, path.relative_path, path.file_id, path.size],
             )?;
 
+            Self::insert_path_metadata(
+                conn,
+                "incoming_path_metadata",
+                "incoming_path_app_metadata",
+                "incoming_paths",
+                &tid,
+                &path.file_id,
+                &path.metadata,
+            )?;
+
             Ok::<(), Error>(())
         };
 
@@ -455,6 +582,16 @@ impl Storage {
                 params![tid, path.relative_path, path.file_id, path.size, uri,],
             )?;
 
+            Self::insert_path_metadata(
+                conn,
+                "outgoing_path_metadata",
+                "outgoing_path_app_metadata",
+                "outgoing_paths",
+                &tid,
+                &path.file_id,
+                &path.metadata,
+            )?;
+
             Ok::<(), Error>(())
         };
 
@@ -463,6 +600,207 @@ impl Storage {
         }
     }
 
+    fn insert_path_metadata(
+        conn: &Transaction<'_>,
+        table: &str,
+        app_table: &str,
+        paths_table: &str,
+        tid: &str,
+        file_id: &str,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        if metadata.mime_type.is_some()
+            || metadata.mtime.is_some()
+            || metadata.permissions.is_some()
+        {
+            conn.execute(
+                &format!(
+                    "INSERT INTO {table} (path_id, mime_type, mtime, permissions)
+            SELECT id, ?3, ?4, ?5 FROM {paths_table} WHERE transfer_id = ?1 AND path_hash = ?2
+            ON CONFLICT DO NOTHING"
+                ),
+                params![
+                    tid,
+                    file_id,
+                    metadata.mime_type,
+                    metadata.mtime,
+                    metadata.permissions
+                ],
+            )?;
+        }
+
+        for (key, value) in &metadata.app_metadata {
+            conn.execute(
+                &format!(
+                    "INSERT INTO {app_table} (path_id, key, value)
+            SELECT id, ?3, ?4 FROM {paths_table} WHERE transfer_id = ?1 AND path_hash = ?2
+            ON CONFLICT DO NOTHING"
+                ),
+                params![tid, file_id, key, value],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn path_metadata(
+        conn: &Transaction<'_>,
+        table: &str,
+        app_table: &str,
+    ) -> Result<HashMap<i64, FileMetadata>> {
+        let mut metadata: HashMap<i64, FileMetadata> = conn
+            .prepare(&format!(
+                "SELECT path_id, mime_type, mtime, permissions FROM {table}"
+            ))?
+            .query_map([], |r| {
+                Ok((
+                    r.get("path_id")?,
+                    FileMetadata {
+                        mime_type: r.get("mime_type")?,
+                        mtime: r.get("mtime")?,
+                        permissions: r.get("permissions")?,
+                        app_metadata: BTreeMap::new(),
+                    },
+                ))
+            })?
+            .collect::<QueryResult<_>>()?;
+
+        let mut stmt = conn.prepare(&format!("SELECT path_id, key, value FROM {app_table}"))?;
+        let mut rows = stmt.query([])?;
+        while let Some(r) = rows.next()? {
+            metadata
+                .entry(r.get("path_id")?)
+                .or_default()
+                .app_metadata
+                .insert(r.get("key")?, r.get("value")?);
+        }
+
+        Ok(metadata)
+    }
+
+    // The app metadata of all the files of the transfer, by the path ID
+    fn transfer_app_metadata(
+        conn: &Transaction<'_>,
+        table: &str,
+        paths_table: &str,
+        tid: &str,
+    ) -> QueryResult<HashMap<i64, BTreeMap<String, String>>> {
+        let mut metadata: HashMap<i64, BTreeMap<_, _>> = HashMap::new();
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT m.path_id, m.key, m.value FROM {table} m
+            INNER JOIN {paths_table} p ON p.id = m.path_id
+            WHERE p.transfer_id = ?1"
+        ))?;
+        let mut rows = stmt.query(params![tid])?;
+        while let Some(r) = rows.next()? {
+            metadata
+                .entry(r.get("path_id")?)
+                .or_default()
+                .insert(r.get("key")?, r.get("value")?);
+        }
+
+        Ok(metadata)
+    }
+
+    fn clock_skews(conn: &Transaction<'_>) -> Result<HashMap<Uuid, i64>> {
+        Self::transfer_values(conn, "transfer_clock_skews", "skew_ms")
+    }
+
+    fn transfer_notes(conn: &Transaction<'_>) -> Result<HashMap<Uuid, String>> {
+        Self::transfer_values(conn, "transfer_notes", "note")
+    }
+
+    fn transfer_aliases(conn: &Transaction<'_>) -> Result<HashMap<Uuid, String>> {
+        Self::transfer_values(conn, "transfer_aliases", "alias")
+    }
+
+    fn transfer_labels(conn: &Transaction<'_>) -> Result<HashMap<Uuid, String>> {
+        Self::transfer_values(conn, "transfer_labels", "label")
+    }
+
+    fn transfer_forwardings(conn: &Transaction<'_>) -> Result<HashMap<Uuid, TransferForwarding>> {
+        let values = conn
+            .prepare("SELECT transfer_id, origin_id, forward_to FROM transfer_forwarding")?
+            .query_map([], |r| {
+                let parse = |column| {
+                    Uuid::parse_str(r.get::<_, String>(column)?.as_str())
+                        .map_err(|_| rusqlite::Error::InvalidQuery)
+                };
+
+                let forwarding = TransferForwarding {
+                    origin_id: parse("origin_id")?,
+                    forward_to: r.get("forward_to")?,
+                };
+                Ok((parse("transfer_id")?, forwarding))
+            })?
+            .collect::<QueryResult<_>>()?;
+
+        Ok(values)
+    }
+
+    fn transfer_values<T: rusqlite::types::FromSql>(
+        conn: &Transaction<'_>,
+        table: &str,
+        column: &str,
+    ) -> Result<HashMap<Uuid, T>> {
+        let values = conn
+            .prepare(&format!("SELECT transfer_id, {column} FROM {table}"))?
+            .query_map([], |r| {
+                let id = Uuid::parse_str(r.get::<_, String>("transfer_id")?.as_str())
+                    .map_err(|_| rusqlite::Error::InvalidQuery)?;
+                Ok((id, r.get(column)?))
+            })?
+            .collect::<QueryResult<_>>()?;
+
+        Ok(values)
+    }
+
+    pub async fn insert_incoming_dir_entries(&self, transfer_id: Uuid, entries: &[DirEntry]) {
+        if entries.is_empty() {
+            return;
+        }
+
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            for entry in entries {
+                conn.execute(
+                    "INSERT INTO incoming_dir_entries (transfer_id, relative_path, \
+                     symlink_target) VALUES (?1, ?2, ?3)",
+                    params![tid, entry.relative_path, entry.symlink_target],
+                )?;
+            }
+
+            conn.commit()?;
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert incoming dir entries"; "error" => %e);
+        }
+    }
+
+    fn dir_entries(conn: &Transaction<'_>, tid: &str) -> Result<Vec<DirEntry>> {
+        let entries = conn
+            .prepare(
+                "SELECT relative_path, symlink_target FROM incoming_dir_entries WHERE transfer_id \
+                 = ?1 ORDER BY id",
+            )?
+            .query_map(params![tid], |r| {
+                Ok(DirEntry {
+                    relative_path: r.get("relative_path")?,
+                    symlink_target: r.get("symlink_target")?,
+                })
+            })?
+            .collect::<QueryResult<_>>()?;
+
+        Ok(entries)
+    }
+
     pub async fn save_checksum(&self, transfer_id: Uuid, file_id: &str, checksum: &[u8]) {
         let tid = transfer_id.to_string();
 
@@ -488,6 +826,43 @@ impl Storage {
         }
     }
 
+    /// The final paths of the files of the other transfers downloaded with the
+    /// same checksum and size, the most recent first
+    pub async fn completed_paths_with_checksum(
+        &self,
+        checksum: &[u8],
+        size: u64,
+        exclude_transfer_id: Uuid,
+    ) -> Vec<String> {
+        let tid = exclude_transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let out = conn
+                .prepare(
+                    r#"
+                    SELECT c.final_path FROM incoming_paths p
+                    INNER JOIN incoming_path_completed_states c ON c.path_id = p.id
+                    WHERE p.checksum = ?1 AND p.bytes = ?2 AND p.transfer_id != ?3
+                        AND NOT p.is_deleted
+                    ORDER BY c.id DESC
+                    "#,
+                )?
+                .query_map(params![checksum, size as i64, tid], |row| row.get(0))?
+                .collect::<QueryResult<Vec<_>>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(out) => out,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch paths by checksum"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+
     pub async fn fetch_checksums(&self, transfer_id: Uuid) -> Vec<FileChecksum> {
         let tid = transfer_id.to_string();
         trace!(
@@ -546,6 +921,33 @@ impl Storage {
         }
     }
 
+    /// Records the difference between the peer's and the local wall clock
+    /// estimated when connecting. Only the latest estimate is kept
+    pub async fn update_peer_clock_skew(&self, transfer_id: Uuid, skew_ms: i64) {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Updating peer clock skew";
+            "transfer_id" => &tid,
+            "skew_ms" => skew_ms);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR REPLACE INTO transfer_clock_skews (transfer_id, skew_ms) VALUES (?1, \
+                 ?2)",
+                params![tid, skew_ms],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to update peer clock skew"; "error" => %e);
+        }
+    }
+
     pub async fn insert_transfer_cancel_state(&self, transfer_id: Uuid, by_peer: bool) {
         let tid = transfer_id.to_string();
 
@@ -728,7 +1130,12 @@ impl Storage {
         }
     }
 
-    pub async fn insert_outgoing_path_completed_state(&self, transfer_id: Uuid, path_id: &str) {
+    pub async fn insert_outgoing_path_completed_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        final_path: Option<&str>,
+    ) {
         let tid = transfer_id.to_string();
         trace!(
             self.logger,
@@ -740,11 +1147,11 @@ impl Storage {
             let conn = self.conn.lock().await;
             conn.execute(
                 r#"
-                INSERT INTO outgoing_path_completed_states (path_id)
-                SELECT id
+                INSERT INTO outgoing_path_completed_states (path_id, final_path)
+                SELECT id, ?3
                 FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
                 "#,
-                params![tid, path_id],
+                params![tid, path_id, final_path],
             )?;
 
             Ok::<(), Error>(())
@@ -844,6 +1251,52 @@ impl Storage {
         }
     }
 
+    /// Marks the file withdrawn by the sender before it was started. It is
+    /// terminal like the rejected file
+    pub async fn insert_outgoing_path_withdrawn_state(&self, transfer_id: Uuid, path_id: &str) {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                INSERT INTO outgoing_path_reject_states (path_id, by_peer, bytes_sent, withdrawn)
+                SELECT id, FALSE, 0, TRUE
+                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert outgoing path withdrawn state"; "error" => %e);
+        }
+    }
+
+    pub async fn insert_incoming_path_withdrawn_state(&self, transfer_id: Uuid, path_id: &str) {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                INSERT INTO incoming_path_reject_states (path_id, by_peer, bytes_received, withdrawn)
+                SELECT id, TRUE, 0, TRUE
+                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert incoming path withdrawn state"; "error" => %e);
+        }
+    }
+
     pub async fn insert_outgoing_path_paused_state(
         &self,
         transfer_id: Uuid,
@@ -918,6 +1371,21 @@ impl Storage {
                 params![until_timestamp],
             )?;
 
+            // The journal timestamps are kept in milliseconds
+            conn.execute(
+                "DELETE FROM event_journal WHERE timestamp < ?1",
+                params![until_timestamp.saturating_mul(1000)],
+            )?;
+
+            conn.execute(
+                r#"
+                DELETE FROM peers
+                WHERE updated_at < datetime(?1, 'unixepoch')
+                    AND peer NOT IN(SELECT peer FROM transfers WHERE NOT is_deleted)
+                "#,
+                params![until_timestamp],
+            )?;
+
             Ok::<(), Error>(())
         };
 
@@ -956,6 +1424,13 @@ impl Storage {
                 }
             }
 
+            // The devices of the peers without any transfer left are forgotten
+            conn.execute(
+                "DELETE FROM peers WHERE peer NOT IN(SELECT peer FROM transfers WHERE NOT \
+                 is_deleted)",
+                params![],
+            )?;
+
             Ok::<(), Error>(())
         };
 
@@ -973,10 +1448,17 @@ impl Storage {
 
             let mut out = Vec::with_capacity(rec_transfers.len());
             for rec_transfer in rec_transfers {
+                let mut app_metadata = Self::transfer_app_metadata(
+                    &conn,
+                    "outgoing_path_app_metadata",
+                    "outgoing_paths",
+                    &rec_transfer.tid,
+                )?;
+
                 let files = conn
                     .prepare(
                         r#"
-                    SELECT relative_path, uri, path_hash, bytes 
+                    SELECT id, relative_path, uri, path_hash, bytes 
                     FROM outgoing_paths 
                     WHERE transfer_id = ?1
                     "#,
@@ -987,15 +1469,19 @@ impl Storage {
                             r.get::<_, String>("uri")?,
                             r.get("relative_path")?,
                             r.get("bytes")?,
+                            app_metadata
+                                .remove(&r.get::<_, i64>("id")?)
+                                .unwrap_or_default(),
                         ))
                     })?
                     .map(|row| {
-                        let (file_id, uri, subpath, size) = row?;
+                        let (file_id, uri, subpath, size, app_metadata) = row?;
                         Ok(OutgoingFileToRetry {
                             file_id,
                             uri: uri.parse()?,
                             subpath,
                             size,
+                            app_metadata,
                         })
                     })
                     .collect::<Result<_>>()?;
@@ -1032,11 +1518,19 @@ impl Storage {
 
             let mut out = Vec::with_capacity(rec_transfers.len());
             for rec_transfer in rec_transfers {
+                let mut app_metadata = Self::transfer_app_metadata(
+                    &conn,
+                    "incoming_path_app_metadata",
+                    "incoming_paths",
+                    &rec_transfer.tid,
+                )?;
+
                 let files = conn
                     .prepare(
                         r#"
-                    SELECT relative_path, path_hash, bytes 
-                    FROM incoming_paths 
+                    SELECT ip.id, relative_path, path_hash, bytes, m.mime_type, m.mtime, m.permissions
+                    FROM incoming_paths ip
+                    LEFT JOIN incoming_path_metadata m ON m.path_id = ip.id
                     WHERE transfer_id = ?1
                     "#,
                     )?
@@ -1045,16 +1539,27 @@ impl Storage {
                             file_id: r.get("path_hash")?,
                             subpath: r.get("relative_path")?,
                             size: r.get("bytes")?,
+                            metadata: FileMetadata {
+                                mime_type: r.get("mime_type")?,
+                                mtime: r.get("mtime")?,
+                                permissions: r.get("permissions")?,
+                                app_metadata: app_metadata
+                                    .remove(&r.get::<_, i64>("id")?)
+                                    .unwrap_or_default(),
+                            },
                         })
                     })?
                     .collect::<QueryResult<_>>()?;
 
+                let dir_entries = Self::dir_entries(&conn, &rec_transfer.tid)?;
+
                 out.push(IncomingTransferToRetry {
                     uuid: rec_transfer.tid.parse().map_err(|err| {
                         crate::Error::InternalError(format!("Failed to parse UUID: {err}"))
                     })?,
                     peer: rec_transfer.peer,
                     files,
+                    dir_entries,
                 });
             }
 
@@ -1120,7 +1625,47 @@ impl Storage {
     }
 
     pub async fn transfers_since(&self, since_timestamp: i64) -> Vec<Transfer> {
-        // Collect transfers since a given timestamp.
+        trace!(
+        self.logger,
+        "Fetching transfers since timestamp";
+        "since_timestamp" => since_timestamp);
+
+        self.fetch_transfers(Some(since_timestamp), None, None)
+            .await
+    }
+
+    /// The transfers since the timestamp carrying the label
+    pub async fn transfers_with_label(&self, since_timestamp: i64, label: &str) -> Vec<Transfer> {
+        trace!(
+        self.logger,
+        "Fetching labeled transfers since timestamp";
+        "since_timestamp" => since_timestamp,
+        "label" => label);
+
+        self.fetch_transfers(Some(since_timestamp), None, Some(label))
+            .await
+    }
+
+    /// The single transfer with its files and their states, `None` if there is
+    /// no such transfer
+    pub async fn transfer(&self, transfer_id: Uuid) -> Option<Transfer> {
+        trace!(
+        self.logger,
+        "Fetching transfer";
+        "transfer_id" => %transfer_id);
+
+        self.fetch_transfers(None, Some(transfer_id), None)
+            .await
+            .pop()
+    }
+
+    async fn fetch_transfers(
+        &self,
+        since_timestamp: Option<i64>,
+        transfer_id: Option<Uuid>,
+        label: Option<&str>,
+    ) -> Vec<Transfer> {
+        // Collect transfers since a given timestamp or the single transfer.
         // This performs 3 queries, fetching by insertion order:
         // 1. transfers with their states.
         // 2. outgoing paths with their states
@@ -1132,10 +1677,11 @@ impl Storage {
         // For transfers, their rowid is selected as well and used to sort the
         // transfers. Because its not part of `Transfer` structure, a tuple is
         // used as hashmap value.
-        trace!(
-        self.logger,
-        "Fetching transfers since timestamp";
-        "since_timestamp" => since_timestamp);
+        // The states are ordered by their local sequence number rather than
+        // the timestamps, so the wall-clock changes do not reorder them. The
+        // states recorded before the sequence was introduced have it set to 0
+        // and fall back to the timestamp order.
+        let transfer_id = transfer_id.map(|id| id.to_string());
 
         let task = async {
             let mut conn = self.conn.lock().await;
@@ -1149,19 +1695,23 @@ impl Storage {
                 .prepare(
                     r#"
                 WITH ts AS  (
-                    select 1, id, transfer_id, by_peer, created_at from transfer_cancel_states
+                    select 1, id, transfer_id, by_peer, created_at, seq from transfer_cancel_states
                     union all
-                    select 2, id, transfer_id, status_code, created_at from transfer_failed_states
+                    select 2, id, transfer_id, status_code, created_at, seq from transfer_failed_states
                 )
                 select t.*, ts.*, t.rowid from transfers t
                     left join ts on ts.transfer_id = t.id
-                    where not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
+                    where not t.is_deleted
+                        and (?1 is null or t.created_at >= datetime(?1, 'unixepoch'))
+                        and (?2 is null or t.id = ?2)
+                        and (?3 is null or t.id in (select transfer_id from transfer_labels where label = ?3))
+                    order by ts.seq, ts.created_at
                 "#,
                 )?
-                .query_map(params![since_timestamp], |row| {
+                .query_map(params![since_timestamp, transfer_id, label], |row| {
                     let id = Uuid::parse_str(row.get::<_, String>(0)?.as_str())
                         .map_err(|_| rusqlite::Error::InvalidQuery)?;
-                    let rowid: u64 = row.get(10)?;
+                    let rowid: u64 = row.get(11)?;
                     let transfer: &mut Transfer = &mut match transfers_map.entry(id) {
                         Occupied(e) => e.into_mut(),
                         Vacant(k) => {
@@ -1176,6 +1726,13 @@ impl Storage {
                                 transfer_type,
                                 created_at: row.get(3)?,
                                 states: vec![],
+                                totals: Default::default(),
+                                peer_clock_skew_ms: None,
+                                note: None,
+                                origin_id: None,
+                                forward_to: None,
+                                alias: None,
+                                label: None,
                             };
                             k.insert((rowid, t))
                         }
@@ -1211,24 +1768,28 @@ impl Storage {
 
             let mut outgoing_paths: HashMap<i64, OutgoingPath> = HashMap::new();
             // Here is the same situation as before - because the columns after created_at
-            // are all integers, they can be shared.
+            // are all integers, they can be shared. The only text column is the final path
+            // reported by the receiver.
             let _ = tx.prepare(r#"
             WITH ops AS (
-                select 1, path_id, created_at, bytes_sent, null from outgoing_path_started_states
+                select 1, path_id, created_at, bytes_sent, null, null, seq from outgoing_path_started_states
                 union all
-                select 2, path_id, created_at, status_code, bytes_sent from outgoing_path_failed_states
+                select 2, path_id, created_at, status_code, bytes_sent, null, seq from outgoing_path_failed_states
                 union all
-                select 3, path_id, created_at, null, null from outgoing_path_completed_states
+                select 3, path_id, created_at, null, null, final_path, seq from outgoing_path_completed_states
                 union all
-                select 4, path_id, created_at, by_peer, bytes_sent from outgoing_path_reject_states
+                select 4, path_id, created_at, by_peer, bytes_sent, null, seq from outgoing_path_reject_states where not withdrawn
                 union all
-                select 5, path_id, created_at, bytes_sent, null from outgoing_path_paused_states
+                select 5, path_id, created_at, bytes_sent, null, null, seq from outgoing_path_paused_states
+                union all
+                select 6, path_id, created_at, by_peer, null, null, seq from outgoing_path_reject_states where withdrawn
             )
             SELECT op.*, ops.*, op.rowid from outgoing_paths op
                 left join ops on ops.path_id = op.id
                 left join transfers t on t.id = op.transfer_id and not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
-                where not op.is_deleted
-            "#)?.query_map(params![since_timestamp], |row| {
+                where not op.is_deleted and (?2 is null or op.transfer_id = ?2)
+                order by ops.seq, ops.created_at
+            "#)?.query_map(params![since_timestamp, transfer_id], |row| {
                 let path_id: i64 = row.get(0)?;
                 let path = match outgoing_paths.entry(path_id) {
                     Occupied(p) => p.into_mut(),
@@ -1244,6 +1805,7 @@ impl Storage {
                             bytes: row.get(5)?,
                             bytes_sent: 0,
                             created_at: row.get(6)?,
+                            metadata: FileMetadata::default(),
                             states: vec![],
                         };
                         let uri_str: String = row.get(3)?;
@@ -1296,7 +1858,9 @@ impl Storage {
                         3 => path.states.push(OutgoingPathStateEvent {
                             path_id,
                             created_at,
-                            data: OutgoingPathStateEventData::Completed,
+                            data: OutgoingPathStateEventData::Completed {
+                                final_path: row.get(13)?,
+                            },
                         }),
                         4 => path.states.push(OutgoingPathStateEvent {
                             path_id,
@@ -1313,6 +1877,13 @@ impl Storage {
                                 bytes_sent: row.get(11)?
                             },
                         }),
+                        6 => path.states.push(OutgoingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: OutgoingPathStateEventData::Withdrawn {
+                                by_peer: row.get(11)?,
+                            },
+                        }),
                         other => warn!(
                                         self.logger,
                                         "Unexpected union member identifier for outgoing path status";
@@ -1323,15 +1894,18 @@ impl Storage {
                 Ok(())
             })?.count();
 
-            for (_, mut path) in outgoing_paths {
-                path.states.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+            let mut outgoing_metadata =
+                Self::path_metadata(&tx, "outgoing_path_metadata", "outgoing_path_app_metadata")?;
+            for (path_id, mut path) in outgoing_paths {
+                path.metadata = outgoing_metadata.remove(&path_id).unwrap_or_default();
 
                 path.bytes_sent = path.states.last().map_or(0, |state| match state.data {
                     OutgoingPathStateEventData::Started { bytes_sent } => bytes_sent,
                     OutgoingPathStateEventData::Failed { bytes_sent, .. } => bytes_sent,
-                    OutgoingPathStateEventData::Completed => path.bytes,
+                    OutgoingPathStateEventData::Completed { .. } => path.bytes,
                     OutgoingPathStateEventData::Rejected { bytes_sent, .. } => bytes_sent,
                     OutgoingPathStateEventData::Paused { bytes_sent } => bytes_sent,
+                    OutgoingPathStateEventData::Withdrawn { .. } => 0,
                 });
                 if let Some((_, t)) = transfers_map.get_mut(&path.transfer_id) {
                     if let DbTransferType::Outgoing(pp) = &mut t.transfer_type {
@@ -1345,24 +1919,26 @@ impl Storage {
             // these fields a separate column will be used.
             let _ = tx.prepare(r#"
             WITH ips AS (
-                select 1, path_id, created_at, null, null, base_dir from incoming_path_pending_states
+                select 1, path_id, created_at, null, null, base_dir, seq from incoming_path_pending_states
+                union all
+                select 2, path_id, created_at, bytes_received, null, null, seq from incoming_path_started_states
                 union all
-                select 2, path_id, created_at, bytes_received, null, null from incoming_path_started_states
+                select 3, path_id, created_at, status_code, bytes_received, null, seq from incoming_path_failed_states
                 union all
-                select 3, path_id, created_at, status_code, bytes_received, null from incoming_path_failed_states
+                select 4, path_id, created_at, null, null, final_path, seq from incoming_path_completed_states
                 union all
-                select 4, path_id, created_at, null, null, final_path from incoming_path_completed_states
+                select 5, path_id, created_at, by_peer, bytes_received, null, seq from incoming_path_reject_states where not withdrawn
                 union all
-                select 5, path_id, created_at, by_peer, bytes_received, null from incoming_path_reject_states
+                select 6, path_id, created_at, bytes_received, null, null, seq from incoming_path_paused_states
                 union all
-                select 6, path_id, created_at, bytes_received, null, null from incoming_path_paused_states
+                select 7, path_id, created_at, by_peer, null, null, seq from incoming_path_reject_states where withdrawn
             )
             SELECT ip.*, ips.* from incoming_paths ip
                 left join ips on ips.path_id = ip.id
                 left join transfers t on t.id = ip.transfer_id and not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
-                where not ip.is_deleted
-                order by ip.rowid
-            "#)?.query_map(params![since_timestamp], |row| {
+                where not ip.is_deleted and (?2 is null or ip.transfer_id = ?2)
+                order by ip.rowid, ips.seq, ips.created_at
+            "#)?.query_map(params![since_timestamp, transfer_id], |row| {
                 let path_id: i64 = row.get(0)?;
                 let path = match incoming_paths.entry(path_id) {
                     Occupied(p) => p.into_mut(),
@@ -1376,6 +1952,7 @@ impl Storage {
                             bytes: row.get(4)?,
                             bytes_received: 0,
                             created_at: row.get(5)?,
+                            metadata: FileMetadata::default(),
                             states: vec![],
                         };
                         e.insert(res)
@@ -1430,6 +2007,13 @@ impl Storage {
                                 bytes_received: row.get(11)?
                             },
                         }),
+                        7 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Withdrawn {
+                                by_peer: row.get(11)?,
+                            },
+                        }),
                         _ => {}
                     }
                 }
@@ -1437,8 +2021,10 @@ impl Storage {
                 Ok(())
             })?.count();
 
-            for (_, mut path) in incoming_paths {
-                path.states.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+            let mut incoming_metadata =
+                Self::path_metadata(&tx, "incoming_path_metadata", "incoming_path_app_metadata")?;
+            for (path_id, mut path) in incoming_paths {
+                path.metadata = incoming_metadata.remove(&path_id).unwrap_or_default();
 
                 path.bytes_received = path
                     .states
@@ -1459,6 +2045,7 @@ impl Storage {
                         IncomingPathStateEventData::Paused { bytes_received } => {
                             Some(bytes_received)
                         }
+                        IncomingPathStateEventData::Withdrawn { .. } => Some(0),
                     })
                     .unwrap_or(0);
 
@@ -1468,19 +2055,44 @@ impl Storage {
                     }
                 }
             }
+            for (id, skew) in Self::clock_skews(&tx)? {
+                if let Some((_, t)) = transfers_map.get_mut(&id) {
+                    t.peer_clock_skew_ms = Some(skew);
+                }
+            }
+            for (id, note) in Self::transfer_notes(&tx)? {
+                if let Some((_, t)) = transfers_map.get_mut(&id) {
+                    t.note = Some(note);
+                }
+            }
+            for (id, forwarding) in Self::transfer_forwardings(&tx)? {
+                if let Some((_, t)) = transfers_map.get_mut(&id) {
+                    t.origin_id = Some(forwarding.origin_id);
+                    t.forward_to = forwarding.forward_to;
+                }
+            }
+            for (id, alias) in Self::transfer_aliases(&tx)? {
+                if let Some((_, t)) = transfers_map.get_mut(&id) {
+                    t.alias = Some(alias);
+                }
+            }
+            for (id, label) in Self::transfer_labels(&tx)? {
+                if let Some((_, t)) = transfers_map.get_mut(&id) {
+                    t.label = Some(label);
+                }
+            }
+
             drop(tx);
             drop(conn);
             let mut transfers: Vec<(u64, Transfer)> = transfers_map.into_values().collect();
             transfers.sort_by_key(|rt| rt.0);
             let mut transfers: Vec<Transfer> = transfers.into_iter().map(|rt| rt.1).collect();
             for transfer in &mut transfers {
-                transfer
-                    .states
-                    .sort_by(|a, b| a.created_at.cmp(&b.created_at));
                 match transfer.transfer_type {
                     DbTransferType::Incoming(ref mut p) => p.sort_by_key(|ip| ip.id),
                     DbTransferType::Outgoing(ref mut p) => p.sort_by_key(|op| op.id),
                 };
+                transfer.update_totals();
             }
             Ok::<Vec<_>, Error>(transfers)
         };
@@ -1488,749 +2100,2779 @@ impl Storage {
         match task.await {
             Ok(transfers) => transfers,
             Err(e) => {
-                error!(self.logger, "Failed to get transfers since timestamp"; "error" => %e);
+                error!(self.logger, "Failed to get transfers"; "error" => %e);
                 vec![]
             }
         }
     }
 
-    pub async fn remove_transfer_file(&self, transfer_id: Uuid, file_id: &str) -> Option<()> {
+    /// Sets the note of the transfer or removes it when `None`. Returns `None`
+    /// if there is no such transfer
+    pub async fn set_transfer_note(&self, transfer_id: Uuid, note: Option<&str>) -> Option<()> {
         let tid = transfer_id.to_string();
 
         trace!(
             self.logger,
-            "Removing transfer file";
+            "Setting transfer note";
             "transfer_id" => &tid,
-            "file_id" => file_id,
+            "note" => note,
         );
 
         let task = async {
-            let conn = self.conn.lock().await;
-
-            let mut count = 0;
-            count += conn
-                .prepare(
-                    r#"
-                UPDATE outgoing_paths
-                SET is_deleted = TRUE
-                WHERE transfer_id = ?1
-                    AND path_hash = ?2
-                    AND (
-                        id IN(SELECT path_id FROM outgoing_path_reject_states) OR
-                        id IN(SELECT path_id FROM outgoing_path_failed_states) OR
-                        id IN(SELECT path_id FROM outgoing_path_completed_states)
-                    )
-            "#,
-                )?
-                .execute(params![tid, file_id])?;
-            count += conn
-                .prepare(
-                    r#"
-                UPDATE incoming_paths
-                SET is_deleted = TRUE
-                WHERE transfer_id = ?1
-                    AND path_hash = ?2
-                    AND (
-                        id IN(SELECT path_id FROM incoming_path_reject_states) OR
-                        id IN(SELECT path_id FROM incoming_path_failed_states) OR
-                        id IN(SELECT path_id FROM incoming_path_completed_states)
-                    )
-            "#,
-                )?
-                .execute(params![tid, file_id])?;
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction()?;
 
-            match count {
-                0 => Ok::<Option<()>, Error>(None),
-                1 => Ok(Some(())),
-                _ => {
-                    warn!(
-                        self.logger,
-                        "Deleted a file from both outgoing and incoming paths"
-                    );
-                    Ok(Some(()))
-                }
+            let exists: bool = tx.query_row(
+                "SELECT EXISTS(SELECT 1 FROM transfers WHERE id = ?1 AND NOT is_deleted)",
+                params![tid],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                return Ok::<_, Error>(None);
             }
+
+            match note {
+                Some(note) => tx.execute(
+                    "INSERT OR REPLACE INTO transfer_notes (transfer_id, note) VALUES (?1, ?2)",
+                    params![tid, note],
+                )?,
+                None => tx.execute(
+                    "DELETE FROM transfer_notes WHERE transfer_id = ?1",
+                    params![tid],
+                )?,
+            };
+
+            tx.commit()?;
+            Ok(Some(()))
         };
 
         match task.await {
             Ok(res) => res,
             Err(e) => {
-                error!(self.logger, "Failed to remove transfer file"; "error" => %e);
+                error!(self.logger, "Failed to set transfer note"; "error" => %e);
                 None
             }
         }
     }
 
-    pub async fn fetch_temp_locations(&self, transfer_id: Uuid) -> Vec<TempFileLocation> {
+    /// Records the store-and-forward routing of the transfer
+    pub async fn insert_transfer_forwarding(
+        &self,
+        transfer_id: Uuid,
+        forwarding: &TransferForwarding,
+    ) {
         let tid = transfer_id.to_string();
 
         trace!(
             self.logger,
-            "Fetching temporary file locations";
-            "transfer_id" => &tid
+            "Inserting transfer forwarding";
+            "transfer_id" => &tid,
+            "origin_id" => %forwarding.origin_id,
         );
 
         let task = async {
             let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR REPLACE INTO transfer_forwarding (transfer_id, origin_id, forward_to) \
+                 VALUES (?1, ?2, ?3)",
+                params![tid, forwarding.origin_id.to_string(), forwarding.forward_to],
+            )?;
 
-            let out = conn
-                .prepare(
-                    r#"
-                SELECT DISTINCT path_hash, base_dir
-                FROM incoming_paths ip
-                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
-                WHERE transfer_id = ?1
-                "#,
-                )?
-                .query_map(params![tid], |row| {
-                    Ok(TempFileLocation {
-                        file_id: row.get("path_hash")?,
-                        base_path: row.get("base_dir")?,
-                    })
-                })?
-                .collect::<QueryResult<_>>()?;
+            Ok::<(), Error>(())
+        };
 
-            Ok::<Vec<_>, Error>(out)
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert transfer forwarding"; "error" => %e);
+        }
+    }
+
+    pub async fn transfer_forwarding(&self, transfer_id: Uuid) -> Option<TransferForwarding> {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let forwarding = conn
+                .query_row(
+                    "SELECT origin_id, forward_to FROM transfer_forwarding WHERE transfer_id = ?1",
+                    params![tid],
+                    |r| Ok((r.get::<_, String>(0)?, r.get(1)?)),
+                )
+                .optional()?;
+
+            let forwarding = match forwarding {
+                Some((origin_id, forward_to)) => Some(TransferForwarding {
+                    origin_id: Uuid::parse_str(&origin_id)
+                        .map_err(|_| rusqlite::Error::InvalidQuery)?,
+                    forward_to,
+                }),
+                None => None,
+            };
+
+            Ok::<_, Error>(forwarding)
         };
 
         match task.await {
-            Ok(res) => res,
+            Ok(forwarding) => forwarding,
             Err(e) => {
-                error!(self.logger, "Failed to fetch temporary file locations"; "error" => %e);
-                vec![]
+                error!(self.logger, "Failed to get transfer forwarding"; "error" => %e);
+                None
             }
         }
     }
 
-    pub async fn fetch_base_dirs_for_file(&self, transfer_id: Uuid, file_id: &str) -> Vec<String> {
+    /// Records the deadline of the outgoing transfer, in milliseconds since
+    /// the Unix epoch
+    pub async fn insert_transfer_deadline(&self, transfer_id: Uuid, expires_at: i64) {
         let tid = transfer_id.to_string();
 
         trace!(
             self.logger,
-            "Fetching temporary file locations";
-            "transfer_id" => &tid
+            "Inserting transfer deadline";
+            "transfer_id" => &tid,
+            "expires_at" => expires_at,
         );
 
         let task = async {
             let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR REPLACE INTO transfer_deadlines (transfer_id, expires_at) VALUES (?1, \
+                 ?2)",
+                params![tid, expires_at],
+            )?;
 
-            let out = conn
-                .prepare(
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert transfer deadline"; "error" => %e);
+        }
+    }
+
+    pub async fn transfer_deadline(&self, transfer_id: Uuid) -> Option<i64> {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let expires_at = conn
+                .query_row(
                     r#"
-                SELECT DISTINCT base_dir
-                FROM incoming_paths ip
-                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
-                WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                )?
-                .query_map(params![tid, file_id], |row| row.get("base_dir"))?
-                .collect::<QueryResult<_>>()?;
+                    SELECT d.expires_at
+                    FROM transfer_deadlines d
+                    INNER JOIN transfers t ON t.id = d.transfer_id
+                    WHERE d.transfer_id = ?1 AND NOT t.is_deleted
+                    "#,
+                    params![tid],
+                    |r| r.get(0),
+                )
+                .optional()?;
 
-            Ok::<Vec<_>, Error>(out)
+            Ok::<_, Error>(expires_at)
         };
 
         match task.await {
-            Ok(res) => res,
+            Ok(expires_at) => expires_at,
             Err(e) => {
-                error!(self.logger, "Failed to fetch temporary file locations for {file_id}"; "error" => %e);
-                vec![]
+                error!(self.logger, "Failed to get transfer deadline"; "error" => %e);
+                None
             }
         }
     }
 
-    pub async fn cleanup_garbage_transfers(&self) -> usize {
-        trace!(self.logger, "Removing garbage transfers");
+    pub async fn insert_transfer_label(&self, transfer_id: Uuid, label: &str) {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Inserting transfer label";
+            "transfer_id" => &tid,
+            "label" => label,
+        );
 
         let task = async {
             let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR REPLACE INTO transfer_labels (transfer_id, label) VALUES (?1, ?2)",
+                params![tid, label],
+            )?;
 
-            let count = conn.execute(
-                r#"
-                DELETE FROM transfers WHERE id IN (
-                    SELECT t.id 
-                    FROM transfers t
-                    LEFT JOIN sync_transfer st ON t.id = st.transfer_id
-                    WHERE t.is_deleted AND st.sync_id IS NULL                    
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert transfer label"; "error" => %e);
+        }
+    }
+
+    pub async fn transfer_label(&self, transfer_id: Uuid) -> Option<String> {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let label = conn
+                .query_row(
+                    "SELECT label FROM transfer_labels WHERE transfer_id = ?1",
+                    params![tid],
+                    |r| r.get(0),
                 )
-                "#,
-                params![],
-            )?;
+                .optional()?;
 
-            debug!(self.logger, "Removed {count} garbage transfers");
-            Result::Ok(count)
+            Ok::<_, Error>(label)
         };
 
         match task.await {
-            Err(err) => {
-                error!(self.logger, "Failed to remove garbage transfers: {err}");
-                0
+            Ok(label) => label,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer label"; "error" => %e);
+                None
             }
-            Ok(count) => count,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub async fn insert_transfer_request_key(&self, transfer_id: Uuid, request_key: &str) {
+        let tid = transfer_id.to_string();
 
-    #[tokio::test]
-    async fn test_insert_transfer() {
-        let logger = slog::Logger::root(slog::Discard, slog::o!());
-        let storage = Storage::new(logger, ":memory:").unwrap();
+        trace!(
+            self.logger,
+            "Inserting transfer request key";
+            "transfer_id" => &tid,
+            "request_key" => request_key,
+        );
 
-        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
-        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR REPLACE INTO transfer_request_keys (transfer_id, request_key) VALUES \
+                 (?1, ?2)",
+                params![tid, request_key],
+            )?;
 
-        {
-            let transfer = TransferInfo {
-                id: transfer_id_1,
-                peer: "1.2.3.4".to_string(),
-                files: TransferFiles::Incoming(vec![
-                    TransferIncomingPath {
-                        file_id: "id1".to_string(),
-                        relative_path: "1".to_string(),
-                        size: 1024,
-                    },
-                    TransferIncomingPath {
-                        file_id: "id2".to_string(),
-                        relative_path: "2".to_string(),
-                        size: 2048,
-                    },
-                ]),
-            };
+            Ok::<(), Error>(())
+        };
 
-            storage.insert_transfer(&transfer).await;
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert transfer request key"; "error" => %e);
         }
+    }
 
-        {
-            let transfer = TransferInfo {
-                id: transfer_id_2,
-                peer: "5.6.7.8".to_string(),
-                files: TransferFiles::Outgoing(vec![
-                    TransferOutgoingPath {
-                        file_id: "id3".to_string(),
-                        size: 1024,
-                        uri: "file:///dir".parse().unwrap(),
-                        relative_path: "3".to_string(),
-                    },
-                    TransferOutgoingPath {
-                        file_id: "id4".to_string(),
-                        relative_path: "4".to_string(),
-                        uri: "file:///dir".parse().unwrap(),
-                        size: 2048,
+    /// Forgets the request key once the file set of the transfer changes
+    pub async fn remove_transfer_request_key(&self, transfer_id: Uuid) {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Removing transfer request key";
+            "transfer_id" => &tid,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "DELETE FROM transfer_request_keys WHERE transfer_id = ?1",
+                params![tid],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to remove transfer request key"; "error" => %e);
+        }
+    }
+
+    pub async fn transfer_request_key(&self, transfer_id: Uuid) -> Option<String> {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let request_key = conn
+                .query_row(
+                    "SELECT request_key FROM transfer_request_keys WHERE transfer_id = ?1",
+                    params![tid],
+                    |r| r.get(0),
+                )
+                .optional()?;
+
+            Ok::<_, Error>(request_key)
+        };
+
+        match task.await {
+            Ok(request_key) => request_key,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer request key"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Assigns the alias to the transfer. Returns `false` when the alias is
+    /// already taken by another transfer
+    pub async fn set_transfer_alias(&self, transfer_id: Uuid, alias: &str) -> bool {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Setting transfer alias";
+            "transfer_id" => &tid,
+            "alias" => alias,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT INTO transfer_aliases (transfer_id, alias) VALUES (?1, ?2) ON \
+                 CONFLICT(transfer_id) DO UPDATE SET alias = excluded.alias",
+                params![tid, alias],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        match task.await {
+            Ok(()) => true,
+            Err(Error::DBError(rusqlite::Error::SqliteFailure(err, _)))
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                false
+            }
+            Err(e) => {
+                error!(self.logger, "Failed to set transfer alias"; "error" => %e);
+                false
+            }
+        }
+    }
+
+    pub async fn transfer_by_alias(&self, alias: &str) -> Option<Uuid> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let id = conn
+                .query_row(
+                    "SELECT t.id FROM transfer_aliases a INNER JOIN transfers t ON t.id = \
+                     a.transfer_id WHERE a.alias = ?1 AND NOT t.is_deleted",
+                    params![alias],
+                    |r| r.get::<_, String>(0),
+                )
+                .optional()?;
+
+            let id = id
+                .map(|id| Uuid::parse_str(&id).map_err(|_| rusqlite::Error::InvalidQuery))
+                .transpose()?;
+
+            Ok::<_, Error>(id)
+        };
+
+        match task.await {
+            Ok(id) => id,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer by alias"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// The files of the directory already sent to the peer
+    pub async fn shared_files(&self, dir: &str, peer: &str) -> Vec<SharedFile> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let files = conn
+                .prepare(
+                    "SELECT subpath, size, mtime FROM shared_files WHERE dir = ?1 AND peer = ?2",
+                )?
+                .query_map(params![dir, peer], |r| {
+                    Ok(SharedFile {
+                        subpath: r.get("subpath")?,
+                        size: r.get("size")?,
+                        mtime: r.get("mtime")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<_, Error>(files)
+        };
+
+        match task.await {
+            Ok(files) => files,
+            Err(e) => {
+                error!(self.logger, "Failed to get shared files"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+
+    /// Advances the cursor of the directory shared with the peer
+    pub async fn save_shared_files(&self, dir: &str, peer: &str, files: &[SharedFile]) {
+        trace!(
+            self.logger,
+            "Saving shared files";
+            "dir" => dir,
+            "peer" => peer,
+            "count" => files.len(),
+        );
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            for file in files {
+                conn.execute(
+                    "INSERT INTO shared_files (dir, peer, subpath, size, mtime) VALUES (?1, ?2, \
+                     ?3, ?4, ?5) ON CONFLICT(dir, peer, subpath) DO UPDATE SET size = \
+                     excluded.size, mtime = excluded.mtime",
+                    params![dir, peer, file.subpath, file.size, file.mtime],
+                )?;
+            }
+
+            conn.commit()?;
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to save shared files"; "error" => %e);
+        }
+    }
+
+    /// Records the bytes received from the peer, counted into its quota
+    pub async fn insert_peer_usage(&self, peer: &str, bytes: i64) {
+        trace!(
+            self.logger,
+            "Inserting peer usage";
+            "peer" => peer,
+            "bytes" => bytes,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT INTO peer_usage (peer, bytes) VALUES (?1, ?2)",
+                params![peer, bytes],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert peer usage"; "error" => %e);
+        }
+    }
+
+    /// The bytes received from the peer since the timestamp. The records
+    /// older than the timestamp are dropped, as they no longer count
+    pub async fn peer_usage(&self, peer: &str, since_timestamp: i64) -> u64 {
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "DELETE FROM peer_usage WHERE created_at < datetime(?1, 'unixepoch')",
+                params![since_timestamp],
+            )?;
+
+            let bytes: i64 = conn.query_row(
+                "SELECT COALESCE(SUM(bytes), 0) FROM peer_usage WHERE peer = ?1",
+                params![peer],
+                |r| r.get(0),
+            )?;
+
+            Ok::<_, Error>(bytes as u64)
+        };
+
+        match task.await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(self.logger, "Failed to get peer usage"; "error" => %e);
+                0
+            }
+        }
+    }
+
+    /// Records the name and the platform the peer declared in the handshake,
+    /// replacing the previous ones
+    pub async fn save_peer_device(&self, peer: &str, device: &PeerDevice) {
+        trace!(
+            self.logger,
+            "Saving peer device";
+            "peer" => peer,
+            "name" => ?device.name,
+            "platform" => ?device.platform,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                INSERT INTO peers (peer, device_name, platform) VALUES (?1, ?2, ?3)
+                ON CONFLICT(peer) DO UPDATE SET
+                    device_name = excluded.device_name,
+                    platform = excluded.platform,
+                    updated_at = excluded.updated_at
+                "#,
+                params![peer, device.name, device.platform],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to save peer device"; "error" => %e);
+        }
+    }
+
+    /// The name and the platform the peer declared in the last handshake
+    pub async fn peer_device(&self, peer: &str) -> Option<PeerDevice> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let device = conn
+                .query_row(
+                    "SELECT device_name, platform FROM peers WHERE peer = ?1 AND (device_name IS \
+                     NOT NULL OR platform IS NOT NULL)",
+                    params![peer],
+                    |r| {
+                        Ok(PeerDevice {
+                            name: r.get("device_name")?,
+                            platform: r.get("platform")?,
+                        })
                     },
-                ]),
-            };
+                )
+                .optional()?;
+
+            Ok::<_, Error>(device)
+        };
+
+        match task.await {
+            Ok(device) => device,
+            Err(e) => {
+                error!(self.logger, "Failed to get peer device"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Records the port the peer declared to listen on in the handshake
+    pub async fn save_peer_listen_port(&self, peer: &str, port: u16) {
+        trace!(
+            self.logger,
+            "Saving peer listen port";
+            "peer" => peer,
+            "port" => port,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                INSERT INTO peers (peer, listen_port) VALUES (?1, ?2)
+                ON CONFLICT(peer) DO UPDATE SET
+                    listen_port = excluded.listen_port,
+                    updated_at = excluded.updated_at
+                "#,
+                params![peer, port],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to save peer listen port"; "error" => %e);
+        }
+    }
+
+    /// The port the peer declared to listen on in the last handshake
+    pub async fn peer_listen_port(&self, peer: &str) -> Option<u16> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let port = conn
+                .query_row(
+                    "SELECT listen_port FROM peers WHERE peer = ?1",
+                    params![peer],
+                    |r| r.get::<_, Option<u16>>(0),
+                )
+                .optional()?;
+
+            Ok::<_, Error>(port.flatten())
+        };
+
+        match task.await {
+            Ok(port) => port,
+            Err(e) => {
+                error!(self.logger, "Failed to get peer listen port"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Remembers that the peer authenticated its capabilities in the handshake
+    pub async fn pin_peer_capabilities(&self, public_key: &str) {
+        trace!(self.logger, "Pinning peer capabilities"; "public_key" => public_key);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR IGNORE INTO capability_pins (public_key) VALUES (?1)",
+                params![public_key],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to pin peer capabilities"; "error" => %e);
+        }
+    }
+
+    /// Whether the peer authenticated its capabilities in any handshake before
+    pub async fn are_peer_capabilities_pinned(&self, public_key: &str) -> bool {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let pinned = conn
+                .query_row(
+                    "SELECT 1 FROM capability_pins WHERE public_key = ?1",
+                    params![public_key],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+
+            Ok::<_, Error>(pinned)
+        };
+
+        match task.await {
+            Ok(pinned) => pinned,
+            Err(e) => {
+                error!(self.logger, "Failed to check peer capability pin"; "error" => %e);
+                // Refusing the peer is safer than letting the capabilities be stripped
+                true
+            }
+        }
+    }
+
+    /// The cached checksum of the file, `None` when the file was not hashed
+    /// yet or it changed since
+    pub async fn cached_checksum(&self, path: &str, size: i64, mtime: i64) -> Option<Vec<u8>> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let checksum: Option<Vec<u8>> = conn
+                .query_row(
+                    "SELECT checksum FROM checksum_cache WHERE path = ?1 AND size = ?2 AND mtime \
+                     = ?3",
+                    params![path, size, mtime],
+                    |r| r.get(0),
+                )
+                .optional()?;
+
+            if checksum.is_some() {
+                conn.execute(
+                    "UPDATE checksum_cache SET last_use = (SELECT MAX(last_use) + 1 FROM \
+                     checksum_cache) WHERE path = ?1",
+                    params![path],
+                )?;
+            }
+
+            Ok::<_, Error>(checksum)
+        };
+
+        match task.await {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                error!(self.logger, "Failed to get cached checksum"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Caches the checksum of the file of the given size and modification
+    /// time, evicting the least recently used ones above the limit
+    pub async fn cache_checksum(&self, path: &str, size: i64, mtime: i64, checksum: &[u8]) {
+        self.cache_checksum_within(path, size, mtime, checksum, MAX_CACHED_CHECKSUMS)
+            .await
+    }
+
+    async fn cache_checksum_within(
+        &self,
+        path: &str,
+        size: i64,
+        mtime: i64,
+        checksum: &[u8],
+        limit: usize,
+    ) {
+        trace!(
+            self.logger,
+            "Caching checksum";
+            "size" => size,
+            "mtime" => mtime,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT INTO checksum_cache (path, size, mtime, checksum, last_use) VALUES (?1, \
+                 ?2, ?3, ?4, (SELECT IFNULL(MAX(last_use), 0) + 1 FROM checksum_cache)) ON \
+                 CONFLICT(path) DO UPDATE SET size = excluded.size, mtime = excluded.mtime, \
+                 checksum = excluded.checksum, last_use = excluded.last_use",
+                params![path, size, mtime, checksum],
+            )?;
+            conn.execute(
+                "DELETE FROM checksum_cache WHERE path NOT IN (SELECT path FROM checksum_cache \
+                 ORDER BY last_use DESC LIMIT ?1)",
+                params![limit],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to cache checksum"; "error" => %e);
+        }
+    }
+
+    pub async fn remove_transfer_file(&self, transfer_id: Uuid, file_id: &str) -> Option<()> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Removing transfer file";
+            "transfer_id" => &tid,
+            "file_id" => file_id,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let mut count = 0;
+            count += conn
+                .prepare(
+                    r#"
+                UPDATE outgoing_paths
+                SET is_deleted = TRUE
+                WHERE transfer_id = ?1
+                    AND path_hash = ?2
+                    AND (
+                        id IN(SELECT path_id FROM outgoing_path_reject_states) OR
+                        id IN(SELECT path_id FROM outgoing_path_failed_states) OR
+                        id IN(SELECT path_id FROM outgoing_path_completed_states)
+                    )
+            "#,
+                )?
+                .execute(params![tid, file_id])?;
+            count += conn
+                .prepare(
+                    r#"
+                UPDATE incoming_paths
+                SET is_deleted = TRUE
+                WHERE transfer_id = ?1
+                    AND path_hash = ?2
+                    AND (
+                        id IN(SELECT path_id FROM incoming_path_reject_states) OR
+                        id IN(SELECT path_id FROM incoming_path_failed_states) OR
+                        id IN(SELECT path_id FROM incoming_path_completed_states)
+                    )
+            "#,
+                )?
+                .execute(params![tid, file_id])?;
+
+            match count {
+                0 => Ok::<Option<()>, Error>(None),
+                1 => Ok(Some(())),
+                _ => {
+                    warn!(
+                        self.logger,
+                        "Deleted a file from both outgoing and incoming paths"
+                    );
+                    Ok(Some(()))
+                }
+            }
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to remove transfer file"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_temp_locations(&self, transfer_id: Uuid) -> Vec<TempFileLocation> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Fetching temporary file locations";
+            "transfer_id" => &tid
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let out = conn
+                .prepare(
+                    r#"
+                SELECT DISTINCT path_hash, base_dir
+                FROM incoming_paths ip
+                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
+                WHERE transfer_id = ?1
+                "#,
+                )?
+                .query_map(params![tid], |row| {
+                    Ok(TempFileLocation {
+                        file_id: row.get("path_hash")?,
+                        base_path: row.get("base_dir")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch temporary file locations"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+
+    /// The download directories of all the incoming files, where the partial
+    /// files might be left
+    pub async fn incoming_base_dirs(&self) -> Vec<String> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let out = conn
+                .prepare("SELECT DISTINCT base_dir FROM incoming_path_pending_states")?
+                .query_map([], |row| row.get("base_dir"))?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch incoming base dirs"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+
+    /// Drops the in-flight state of the incoming file so that it is not
+    /// resumed from the partial file anymore
+    pub async fn clear_incoming_file_in_flight(&self, transfer_id: Uuid, file_id: &str) {
+        let conn = self.conn.lock().await;
+
+        if let Err(e) = sync::stop_incoming_file(&conn, transfer_id, file_id) {
+            error!(self.logger, "Failed to clear incoming file in-flight state"; "error" => %e);
+        }
+    }
+
+    pub async fn fetch_base_dirs_for_file(&self, transfer_id: Uuid, file_id: &str) -> Vec<String> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Fetching temporary file locations";
+            "transfer_id" => &tid
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let out = conn
+                .prepare(
+                    r#"
+                SELECT DISTINCT base_dir
+                FROM incoming_paths ip
+                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
+                WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                )?
+                .query_map(params![tid, file_id], |row| row.get("base_dir"))?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch temporary file locations for {file_id}"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn cleanup_garbage_transfers(&self) -> usize {
+        trace!(self.logger, "Removing garbage transfers");
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let count = conn.execute(
+                r#"
+                DELETE FROM transfers WHERE id IN (
+                    SELECT t.id 
+                    FROM transfers t
+                    LEFT JOIN sync_transfer st ON t.id = st.transfer_id
+                    WHERE t.is_deleted AND st.sync_id IS NULL                    
+                )
+                "#,
+                params![],
+            )?;
+
+            debug!(self.logger, "Removed {count} garbage transfers");
+            Result::Ok(count)
+        };
+
+        match task.await {
+            Err(err) => {
+                error!(self.logger, "Failed to remove garbage transfers: {err}");
+                0
+            }
+            Ok(count) => count,
+        }
+    }
+
+    /// Inserts the paused state for the transfer paths left in the started
+    /// state, e.g. by a crash. Returns the number of such paths
+    pub async fn pause_interrupted_paths(&self, transfer_id: Uuid) -> usize {
+        let tid = transfer_id.to_string();
+        trace!(
+            self.logger,
+            "Pausing interrupted paths";
+            "transfer_id" => &tid);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let mut count = 0;
+
+            for (dir, bytes) in [("incoming", "bytes_received"), ("outgoing", "bytes_sent")] {
+                count += conn.execute(
+                    &format!(
+                        r#"
+                        INSERT INTO {dir}_path_paused_states (path_id, {bytes})
+                        SELECT ss.path_id, ss.{bytes}
+                        FROM {dir}_path_started_states ss
+                        INNER JOIN {dir}_paths p ON p.id = ss.path_id
+                        WHERE p.transfer_id = ?1
+                            AND ss.rowid = (
+                                SELECT MAX(rowid) FROM {dir}_path_started_states
+                                WHERE path_id = ss.path_id
+                            )
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_paused_states
+                                WHERE path_id = ss.path_id AND created_at >= ss.created_at
+                            )
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_failed_states WHERE path_id = ss.path_id
+                            )
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_completed_states WHERE path_id = ss.path_id
+                            )
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_reject_states WHERE path_id = ss.path_id
+                            )
+                        "#
+                    ),
+                    params![tid],
+                )?;
+            }
+
+            Ok::<_, Error>(count)
+        };
+
+        match task.await {
+            Ok(count) => count,
+            Err(e) => {
+                error!(self.logger, "Failed to pause interrupted paths"; "error" => %e);
+                0
+            }
+        }
+    }
+
+    /// Inserts the failed state with the given status for the transfer paths
+    /// which are not in a terminal state. Returns the number of such paths
+    pub async fn fail_unfinished_paths(&self, transfer_id: Uuid, status: u32) -> usize {
+        let tid = transfer_id.to_string();
+        trace!(
+            self.logger,
+            "Failing unfinished paths";
+            "transfer_id" => &tid,
+            "status" => status);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let mut count = 0;
+
+            for (dir, bytes) in [("incoming", "bytes_received"), ("outgoing", "bytes_sent")] {
+                count += conn.execute(
+                    &format!(
+                        r#"
+                        INSERT INTO {dir}_path_failed_states (path_id, status_code, {bytes})
+                        SELECT p.id, ?2, COALESCE((
+                            SELECT MAX({bytes}) FROM {dir}_path_started_states
+                            WHERE path_id = p.id
+                        ), 0)
+                        FROM {dir}_paths p
+                        WHERE p.transfer_id = ?1
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_failed_states WHERE path_id = p.id
+                            )
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_completed_states WHERE path_id = p.id
+                            )
+                            AND NOT EXISTS (
+                                SELECT 1 FROM {dir}_path_reject_states WHERE path_id = p.id
+                            )
+                        "#
+                    ),
+                    params![tid, status],
+                )?;
+            }
+
+            Ok::<_, Error>(count)
+        };
+
+        match task.await {
+            Ok(count) => count,
+            Err(e) => {
+                error!(self.logger, "Failed to fail unfinished paths"; "error" => %e);
+                0
+            }
+        }
+    }
+
+    /// The size of the database in bytes
+    pub async fn db_size(&self) -> Option<u64> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            let size: i64 = conn.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                params![],
+                |row| row.get(0),
+            )?;
+
+            Ok::<_, Error>(size as u64)
+        };
+
+        match task.await {
+            Ok(size) => Some(size),
+            Err(e) => {
+                error!(self.logger, "Failed to query the database size"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Writes the event into the journal together with the queued ones,
+    /// returns its sequence number
+    pub async fn insert_journal_event(&self, timestamp: i64, payload: &str) -> Option<i64> {
+        self.journal_event(timestamp, payload, true).await
+    }
+
+    /// Queues the frequent event, like the progress one, to be written into
+    /// the journal with the next batch. Returns its sequence number
+    pub async fn queue_journal_event(&self, timestamp: i64, payload: &str) -> Option<i64> {
+        self.journal_event(timestamp, payload, false).await
+    }
+
+    /// Writes the queued journal events
+    pub async fn flush_journal(&self) {
+        let mut journal = self.journal.lock().await;
+        let mut conn = self.conn.lock().await;
+
+        if let Err(e) = journal.flush(&mut conn) {
+            error!(self.logger, "Failed to flush journal events"; "error" => %e);
+        }
+    }
+
+    /// Writes the queued journal events [`JOURNAL_FLUSH_INTERVAL`] after the
+    /// first of them is queued, so that they do not wait for the next written
+    /// event. Runs until dropped
+    pub async fn flush_journal_periodically(&self) {
+        loop {
+            self.journal_queued.notified().await;
+            tokio::time::sleep(JOURNAL_FLUSH_INTERVAL).await;
+            self.flush_journal().await;
+        }
+    }
+
+    async fn journal_event(&self, timestamp: i64, payload: &str, flush: bool) -> Option<i64> {
+        let mut journal = self.journal.lock().await;
+        let mut conn = self.conn.lock().await;
+
+        let mut task = || {
+            let seq = journal.push(&conn, timestamp, payload)?;
+            if flush || journal.is_due() {
+                journal.flush(&mut conn)?;
+            } else if journal.len() == 1 {
+                self.journal_queued.notify_one();
+            }
+
+            Ok::<_, Error>(seq)
+        };
+
+        match task() {
+            Ok(seq) => Some(seq),
+            Err(e) => {
+                error!(self.logger, "Failed to insert journal event"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    pub async fn journal_events_since(&self, seq: i64) -> Vec<JournalEvent> {
+        trace!(
+            self.logger,
+            "Fetching journal events since";
+            "seq" => seq);
+
+        self.flush_journal().await;
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let out = conn
+                .prepare(
+                    r#"
+                SELECT seq, timestamp, payload
+                FROM event_journal
+                WHERE seq > ?1
+                ORDER BY seq ASC
+                "#,
+                )?
+                .query_map(params![seq], |row| {
+                    Ok(JournalEvent {
+                        seq: row.get("seq")?,
+                        timestamp: row.get("timestamp")?,
+                        payload: row.get("payload")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(events) => events,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch journal events"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_insert_transfer() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        {
+            let transfer = TransferInfo {
+                id: transfer_id_1,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                        metadata: FileMetadata::default(),
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "2".to_string(),
+                        size: 2048,
+                        metadata: FileMetadata::default(),
+                    },
+                ]),
+            };
+
+            storage.insert_transfer(&transfer).await;
+        }
+
+        {
+            let transfer = TransferInfo {
+                id: transfer_id_2,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![
+                    TransferOutgoingPath {
+                        file_id: "id3".to_string(),
+                        size: 1024,
+                        uri: "file:///dir".parse().unwrap(),
+                        relative_path: "3".to_string(),
+                        metadata: FileMetadata::default(),
+                    },
+                    TransferOutgoingPath {
+                        file_id: "id4".to_string(),
+                        relative_path: "4".to_string(),
+                        uri: "file:///dir".parse().unwrap(),
+                        size: 2048,
+                        metadata: FileMetadata::default(),
+                    },
+                ]),
+            };
+
+            storage.insert_transfer(&transfer).await;
+        }
+
+        {
+            let transfers = storage.transfers_since(0).await;
+            assert_eq!(transfers.len(), 2);
+
+            let incoming_transfer = &transfers[0];
+            let outgoing_transfer = &transfers[1];
+
+            assert_eq!(incoming_transfer.id, transfer_id_1);
+            assert_eq!(outgoing_transfer.id, transfer_id_2);
+
+            assert_eq!(incoming_transfer.peer_id, "1.2.3.4".to_string());
+            assert_eq!(outgoing_transfer.peer_id, "5.6.7.8".to_string());
+
+            let transfer = storage.transfer(transfer_id_2).await.unwrap();
+            assert_eq!(transfer.id, transfer_id_2);
+            assert!(
+                matches!(transfer.transfer_type, DbTransferType::Outgoing(paths) if paths.len() == 2)
+            );
+            assert!(storage
+                .transfer("23e4900a-0521-11ee-be56-0242ac120002".parse().unwrap())
+                .await
+                .is_none());
+        }
+
+        storage
+            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
+            .await;
+
+        // Because the transfers haven't reached the terminal state
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 2);
+
+        storage
+            .insert_transfer_cancel_state(transfer_id_1, false)
+            .await;
+        storage
+            .insert_transfer_failed_state(transfer_id_2, 42)
+            .await;
+
+        storage
+            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn remove_outgoing_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Outgoing(vec![
+                TransferOutgoingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "1".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "2".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id3".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "3".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id4".to_string(),
+                    relative_path: "4".to_string(),
+                    uri: "file:///dir".parse().unwrap(),
+                    size: 2048,
+                    metadata: FileMetadata::default(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_outgoing_path_failed_state(transfer_id, "id1", 1, 123)
+            .await;
+        storage
+            .insert_outgoing_path_completed_state(transfer_id, "id2", None)
+            .await;
+        storage
+            .insert_outgoing_path_reject_state(transfer_id, "id3", false, 246)
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Outgoing(out) => out,
+            _ => panic!("Unexpected transfer type"),
+        };
+        assert_eq!(paths.len(), 4);
+
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id1")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id2")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id3")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id4")
+            .await
+            .is_none());
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Outgoing(out) => out,
+            _ => panic!("Unexpected transfer type"),
+        };
+        assert_eq!(paths.len(), 1); // 1 since we removed one of them
+        assert_eq!(paths[0].file_id, "id4");
+    }
+
+    #[tokio::test]
+    async fn withdrawn_outgoing_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Outgoing(vec![
+                TransferOutgoingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "1".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "2".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_outgoing_path_reject_state(transfer_id, "id1", true, 246)
+            .await;
+        storage
+            .insert_outgoing_path_withdrawn_state(transfer_id, "id2")
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Outgoing(out) => out,
+            _ => panic!("Unexpected transfer type"),
+        };
+
+        let state = |file_id| {
+            let path = paths.iter().find(|path| path.file_id == file_id).unwrap();
+            assert_eq!(path.states.len(), 1);
+            &path.states[0].data
+        };
+        assert!(matches!(
+            state("id1"),
+            OutgoingPathStateEventData::Rejected {
+                by_peer: true,
+                bytes_sent: 246
+            }
+        ));
+        assert!(matches!(
+            state("id2"),
+            OutgoingPathStateEventData::Withdrawn { by_peer: false }
+        ));
+
+        // The withdrawals are told apart from the rejections on restore
+        for (file_id, withdrawn) in [("id1", false), ("id2", true)] {
+            let state = storage
+                .outgoing_file_sync_state(transfer_id, file_id)
+                .await
+                .unwrap();
+            assert!(state.is_rejected);
+            assert_eq!(state.is_withdrawn, withdrawn);
+        }
+
+        // Terminal like the rejected file
+        let sync = storage
+            .outgoing_file_sync_state(transfer_id, "id2")
+            .await
+            .unwrap();
+        assert!(sync.is_rejected);
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id2")
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn remove_incoming_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    relative_path: "1".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    relative_path: "2".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "id3".to_string(),
+                    size: 1024,
+                    relative_path: "3".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "id4".to_string(),
+                    relative_path: "4".to_string(),
+                    size: 2048,
+                    metadata: FileMetadata::default(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_incoming_path_failed_state(transfer_id, "id1", 1, 123)
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/id2")
+            .await;
+        storage
+            .insert_incoming_path_reject_state(transfer_id, "id3", false, 246)
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Incoming(inc) => inc,
+            _ => panic!("Unexpected transfer type"),
+        };
+        assert_eq!(paths.len(), 4);
+
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id1")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id2")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id3")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id4")
+            .await
+            .is_none());
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Incoming(inc) => inc,
+            _ => panic!("Unexpected transfer type"),
+        };
+
+        assert_eq!(paths.len(), 1); // 1 since we removed one of them
+        assert_eq!(paths[0].file_id, "id4");
+    }
+
+    #[tokio::test]
+    async fn check_storage_api() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer1_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer1_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "idi1".to_string(),
+                    size: 1024,
+                    relative_path: "1".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "idi2".to_string(),
+                    size: 1024,
+                    relative_path: "2".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "idi3".to_string(),
+                    size: 1024,
+                    relative_path: "3".to_string(),
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "idi4".to_string(),
+                    relative_path: "4".to_string(),
+                    size: 2048,
+                    metadata: FileMetadata::default(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_incoming_path_failed_state(transfer1_id, "idi1", 1, 123)
+            .await;
+        storage
+            .start_incoming_file(transfer1_id, "idi2", "/recv/idi2")
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer1_id, "idi2", "/recv/idi2")
+            .await;
+        storage
+            .insert_incoming_path_reject_state(transfer1_id, "idi3", false, 234)
+            .await;
+        storage
+            .insert_incoming_path_started_state(transfer1_id, "idi4", 12345)
+            .await;
+
+        let transfer2_id: Uuid = "f333302e-584b-42f8-9f66-6a5ef400297d".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer2_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Outgoing(vec![
+                TransferOutgoingPath {
+                    file_id: "ido1".to_string(),
+                    relative_path: "1".to_string(),
+                    uri: "file:///dir/1".parse().unwrap(),
+                    size: 1024,
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "ido2".to_string(),
+                    relative_path: "2".to_string(),
+                    uri: "file:///dir/2".parse().unwrap(),
+                    size: 1024,
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "ido3".to_string(),
+                    relative_path: "3".to_string(),
+                    uri: "file:///dir/3".parse().unwrap(),
+                    size: 1024,
+                    metadata: FileMetadata::default(),
+                },
+                TransferOutgoingPath {
+                    file_id: "ido4".to_string(),
+                    relative_path: "4".to_string(),
+                    uri: "file:///dir/4".parse().unwrap(),
+                    size: 2048,
+                    metadata: FileMetadata::default(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_outgoing_path_failed_state(transfer2_id, "ido1", 1, 123)
+            .await;
+        storage
+            .insert_outgoing_path_completed_state(transfer2_id, "ido2", Some("/recv/2"))
+            .await;
+        storage
+            .insert_outgoing_path_reject_state(transfer2_id, "ido3", false, 234)
+            .await;
+        storage
+            .insert_outgoing_path_started_state(transfer2_id, "ido4", 12345)
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers.len(), 2);
+
+        assert_eq!(transfers[0].id, transfer1_id);
+        assert_eq!(transfers[0].peer_id, "5.6.7.8");
+        assert_eq!(transfers[0].states.len(), 0);
+
+        match &transfers[0].transfer_type {
+            DbTransferType::Incoming(inc) => {
+                assert_eq!(inc[0].transfer_id, transfer1_id);
+                assert_eq!(inc[0].relative_path, "1");
+                assert_eq!(inc[0].bytes, 1024);
+                assert_eq!(inc[0].bytes_received, 123);
+                assert_eq!(inc[0].file_id, "idi1");
+                assert_eq!(inc[0].states.len(), 1);
+
+                assert!(matches!(
+                    inc[0].states[0].data,
+                    IncomingPathStateEventData::Failed {
+                        status_code: 1,
+                        bytes_received: 123
+                    }
+                ));
+
+                assert_eq!(inc[1].transfer_id, transfer1_id);
+                assert_eq!(inc[1].relative_path, "2");
+                assert_eq!(inc[1].bytes, 1024);
+                assert_eq!(inc[1].bytes_received, 1024);
+                assert_eq!(inc[1].file_id, "idi2");
+                assert_eq!(inc[1].states.len(), 2);
+
+                assert!(matches!(
+                    &inc[1].states[0].data,
+                    IncomingPathStateEventData::Pending{
+                        base_dir,
+                    } if base_dir == "/recv/idi2",
+                ));
+                assert!(matches!(
+                    &inc[1].states[1].data,
+                    IncomingPathStateEventData::Completed {
+                        final_path
+                    } if final_path == "/recv/idi2"
+                ));
+
+                assert_eq!(inc[2].transfer_id, transfer1_id);
+                assert_eq!(inc[2].relative_path, "3");
+                assert_eq!(inc[2].bytes, 1024);
+                assert_eq!(inc[2].bytes_received, 234);
+                assert_eq!(inc[2].file_id, "idi3");
+                assert_eq!(inc[2].states.len(), 1);
+
+                assert!(matches!(
+                    inc[2].states[0].data,
+                    IncomingPathStateEventData::Rejected {
+                        by_peer: false,
+                        bytes_received: 234
+                    }
+                ));
+
+                assert_eq!(inc[3].transfer_id, transfer1_id);
+                assert_eq!(inc[3].relative_path, "4");
+                assert_eq!(inc[3].bytes, 2048);
+                assert_eq!(inc[3].bytes_received, 12345);
+                assert_eq!(inc[3].file_id, "idi4");
+                assert_eq!(inc[3].states.len(), 1);
+
+                assert!(matches!(
+                    &inc[3].states[0].data,
+                    IncomingPathStateEventData::Started {
+                        bytes_received: 12345
+                    }
+                ));
+            }
+            _ => panic!("Unexpected transfer type"),
+        };
+
+        assert_eq!(transfers[1].id, transfer2_id);
+        assert_eq!(transfers[1].peer_id, "1.2.3.4");
+        assert_eq!(transfers[1].states.len(), 0);
+
+        match &transfers[1].transfer_type {
+            DbTransferType::Outgoing(inc) => {
+                assert_eq!(inc[0].transfer_id, transfer2_id);
+                assert_eq!(inc[0].relative_path, "1");
+                assert_eq!(inc[0].bytes, 1024);
+                assert_eq!(inc[0].bytes_sent, 123);
+                assert_eq!(inc[0].file_id, "ido1");
+                assert_eq!(inc[0].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[0].content_uri.is_none());
+                assert_eq!(inc[0].states.len(), 1);
+
+                assert!(matches!(
+                    inc[0].states[0].data,
+                    OutgoingPathStateEventData::Failed {
+                        status_code: 1,
+                        bytes_sent: 123
+                    }
+                ));
 
-            storage.insert_transfer(&transfer).await;
-        }
+                assert_eq!(inc[1].transfer_id, transfer2_id);
+                assert_eq!(inc[1].relative_path, "2");
+                assert_eq!(inc[1].bytes, 1024);
+                assert_eq!(inc[1].bytes_sent, 1024);
+                assert_eq!(inc[1].file_id, "ido2");
+                assert_eq!(inc[1].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[1].content_uri.is_none());
+                assert_eq!(inc[1].states.len(), 1);
 
-        {
-            let transfers = storage.transfers_since(0).await;
-            assert_eq!(transfers.len(), 2);
+                assert!(matches!(
+                    &inc[1].states[0].data,
+                    OutgoingPathStateEventData::Completed { final_path: Some(path) } if path == "/recv/2"
+                ));
 
-            let incoming_transfer = &transfers[0];
-            let outgoing_transfer = &transfers[1];
+                assert_eq!(inc[2].transfer_id, transfer2_id);
+                assert_eq!(inc[2].relative_path, "3");
+                assert_eq!(inc[2].bytes, 1024);
+                assert_eq!(inc[2].bytes_sent, 234);
+                assert_eq!(inc[2].file_id, "ido3");
+                assert_eq!(inc[2].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[2].content_uri.is_none());
+                assert_eq!(inc[2].states.len(), 1);
 
-            assert_eq!(incoming_transfer.id, transfer_id_1);
-            assert_eq!(outgoing_transfer.id, transfer_id_2);
+                assert!(matches!(
+                    inc[2].states[0].data,
+                    OutgoingPathStateEventData::Rejected {
+                        by_peer: false,
+                        bytes_sent: 234
+                    }
+                ));
 
-            assert_eq!(incoming_transfer.peer_id, "1.2.3.4".to_string());
-            assert_eq!(outgoing_transfer.peer_id, "5.6.7.8".to_string());
-        }
+                assert_eq!(inc[3].transfer_id, transfer2_id);
+                assert_eq!(inc[3].relative_path, "4");
+                assert_eq!(inc[3].bytes, 2048);
+                assert_eq!(inc[3].bytes_sent, 12345);
+                assert_eq!(inc[3].file_id, "ido4");
+                assert_eq!(inc[3].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[3].content_uri.is_none());
+                assert_eq!(inc[3].states.len(), 1);
 
-        storage
-            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
-            .await;
+                assert!(matches!(
+                    inc[3].states[0].data,
+                    OutgoingPathStateEventData::Started { bytes_sent: 12345 }
+                ));
+            }
+            _ => panic!("Unexpected transfer type"),
+        };
+    }
 
-        // Because the transfers haven't reached the terminal state
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 2);
+    #[tokio::test]
+    async fn removing_garbage_transfers() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id_1,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![]),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        let transfer = TransferInfo {
+            id: transfer_id_2,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Outgoing(vec![]),
+        };
+        storage.insert_transfer(&transfer).await;
 
+        // Transfers need to be termiated before any purging is allowed
         storage
             .insert_transfer_cancel_state(transfer_id_1, false)
             .await;
         storage
-            .insert_transfer_failed_state(transfer_id_2, 42)
+            .insert_transfer_cancel_state(transfer_id_2, false)
             .await;
 
-        storage
-            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
-            .await;
+        // No garbage to collect
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 0);
+
+        storage.purge_transfers(&[transfer_id_1.to_string()]).await;
 
+        // Still the transfer was not synced
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 0);
+
+        let cleared = storage.transfer_sync_clear(transfer_id_1).await;
+        assert!(cleared.is_some());
+
+        // Now the transfer can be garbage collected
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 1);
+
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 0);
+
+        // Ensure we haven't deleted the second transfer
         let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 0);
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].id, transfer_id_2);
     }
 
     #[tokio::test]
-    async fn remove_outgoing_file() {
+    async fn journal_events_are_numbered() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let first = storage.insert_journal_event(1000, "first").await.unwrap();
+        let second = storage.insert_journal_event(2000, "second").await.unwrap();
+        assert!(second > first);
+
+        let events = storage.journal_events_since(0).await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].seq, first);
+        assert_eq!(events[0].payload, "first");
+        assert_eq!(events[1].seq, second);
+        assert_eq!(events[1].timestamp, 2000);
+
+        let events = storage.journal_events_since(first).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].payload, "second");
+
+        // Purging takes seconds while the journal is kept in milliseconds
+        storage.purge_transfers_until(2).await;
+        let events = storage.journal_events_since(0).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].seq, second);
+    }
+
+    #[tokio::test]
+    async fn db_size() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let empty = storage.db_size().await.unwrap();
+        assert!(empty > 0);
+
+        for i in 0..100 {
+            storage
+                .insert_journal_event(i, &"x".repeat(1024))
+                .await
+                .unwrap();
+        }
+        assert!(storage.db_size().await.unwrap() > empty);
+    }
+
+    #[tokio::test]
+    async fn file_metadata() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
         let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let metadata = FileMetadata {
+            mime_type: Some("image/png".to_string()),
+            mtime: Some(1700000000000),
+            permissions: Some(0o640),
+            app_metadata: [("message_id".to_string(), "42".to_string())].into(),
+        };
 
         let transfer = TransferInfo {
             id: transfer_id,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Outgoing(vec![
-                TransferOutgoingPath {
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
                     file_id: "id1".to_string(),
-                    size: 1024,
-                    uri: "file:///dir".parse().unwrap(),
                     relative_path: "1".to_string(),
+                    size: 1024,
+                    metadata: metadata.clone(),
                 },
-                TransferOutgoingPath {
+                TransferIncomingPath {
                     file_id: "id2".to_string(),
-                    size: 1024,
-                    uri: "file:///dir".parse().unwrap(),
                     relative_path: "2".to_string(),
+                    size: 2048,
+                    metadata: FileMetadata::default(),
                 },
-                TransferOutgoingPath {
-                    file_id: "id3".to_string(),
-                    size: 1024,
-                    uri: "file:///dir".parse().unwrap(),
-                    relative_path: "3".to_string(),
-                },
-                TransferOutgoingPath {
+            ]),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        let transfers = storage.incoming_transfers_to_resume().await;
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].files[0].metadata, metadata);
+        assert_eq!(transfers[0].files[1].metadata, FileMetadata::default());
+
+        let transfers = storage.transfers_since(0).await;
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Incoming(paths) => paths,
+            DbTransferType::Outgoing(_) => panic!("Expected incoming transfer"),
+        };
+        assert_eq!(paths[0].metadata, metadata);
+        assert_eq!(paths[1].metadata, FileMetadata::default());
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                file_id: "id3".to_string(),
+                relative_path: "3".to_string(),
+                uri: "file:///dir/3".parse().unwrap(),
+                size: 1024,
+                metadata: metadata.clone(),
+            }]),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        let transfers = storage.outgoing_transfers_to_resume().await;
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].files[0].app_metadata, metadata.app_metadata);
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120004".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
                     file_id: "id4".to_string(),
                     relative_path: "4".to_string(),
-                    uri: "file:///dir".parse().unwrap(),
+                    size: 1024,
+                    metadata: metadata.clone(),
+                },
+                TransferIncomingPath {
+                    file_id: "id5".to_string(),
+                    relative_path: "5".to_string(),
+                    size: 1024,
+                    metadata: FileMetadata::default(),
+                },
+            ]),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        let transfers = storage.incoming_transfers_to_resume().await;
+        let transfer = transfers
+            .iter()
+            .find(|transfer| transfer.uuid == transfer_id)
+            .unwrap();
+        let mut files = transfer.files.iter().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+        assert_eq!(files[0].metadata, metadata);
+        assert_eq!(files[1].metadata, FileMetadata::default());
+    }
+
+    #[tokio::test]
+    async fn transfer_totals() {
+        use crate::types::TransferTotals;
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    relative_path: "1".to_string(),
+                    size: 1024,
+                    metadata: FileMetadata::default(),
+                },
+                TransferIncomingPath {
+                    file_id: "id2".to_string(),
+                    relative_path: "2".to_string(),
                     size: 2048,
+                    metadata: FileMetadata::default(),
                 },
             ]),
         };
+        storage.insert_transfer(&transfer).await;
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(
+            transfers[0].totals,
+            TransferTotals {
+                total_bytes: 3072,
+                transferred_bytes: 0,
+                duration_ms: None,
+                average_bps: None,
+            }
+        );
+
+        storage
+            .insert_incoming_path_started_state(transfer_id, "id1", 0)
+            .await;
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/1")
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        let totals = &transfers[0].totals;
+        assert_eq!(totals.total_bytes, 3072);
+        assert_eq!(totals.transferred_bytes, 1024);
+
+        let duration = totals.duration_ms.expect("Missing duration");
+        assert!(duration >= 50);
+        assert_eq!(totals.average_bps, Some(1024 * 1000 / duration));
+
+        // Resumed from the half of the file, the other half was transferred
+        // within the duration
+        storage
+            .insert_incoming_path_started_state(transfer_id, "id2", 1024)
+            .await;
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/2")
+            .await;
+
+        let transfers = storage.transfers_since(0).await;
+        let totals = &transfers[0].totals;
+        assert_eq!(totals.transferred_bytes, 3072);
+
+        let duration = totals.duration_ms.expect("Missing duration");
+        assert!(duration >= 100);
+        assert_eq!(totals.average_bps, Some(2048 * 1000 / duration));
+    }
+
+    #[tokio::test]
+    async fn reconcile_interrupted_paths() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(
+                ["id1", "id2", "id3"]
+                    .into_iter()
+                    .map(|id| TransferIncomingPath {
+                        file_id: id.to_string(),
+                        relative_path: id.to_string(),
+                        size: 1024,
+                        metadata: FileMetadata::default(),
+                    })
+                    .collect(),
+            ),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        storage
+            .insert_incoming_path_started_state(transfer_id, "id1", 512)
+            .await;
+        storage
+            .insert_incoming_path_started_state(transfer_id, "id2", 0)
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/id2")
+            .await;
+
+        // Keep the states ordered by their timestamps
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(storage.pause_interrupted_paths(transfer_id).await, 1);
+        assert_eq!(storage.pause_interrupted_paths(transfer_id).await, 0);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(storage.fail_unfinished_paths(transfer_id, 42).await, 2);
+        assert_eq!(storage.fail_unfinished_paths(transfer_id, 42).await, 0);
+
+        let transfers = storage.transfers_since(0).await;
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Incoming(paths) => paths,
+            DbTransferType::Outgoing(_) => panic!("Expected incoming transfer"),
+        };
+
+        assert!(matches!(
+            paths[0].states[1].data,
+            IncomingPathStateEventData::Paused {
+                bytes_received: 512
+            }
+        ));
+        assert!(matches!(
+            paths[0].states[2].data,
+            IncomingPathStateEventData::Failed {
+                status_code: 42,
+                bytes_received: 512
+            }
+        ));
+        assert_eq!(paths[1].states.len(), 2);
+        assert!(matches!(
+            paths[2].states[0].data,
+            IncomingPathStateEventData::Failed {
+                status_code: 42,
+                bytes_received: 0
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn states_ordered_by_sequence() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                file_id: "id1".to_string(),
+                relative_path: "1".to_string(),
+                size: 1024,
+                metadata: FileMetadata::default(),
+            }]),
+        };
         storage.insert_transfer(&transfer).await;
+
         storage
-            .insert_outgoing_path_failed_state(transfer_id, "id1", 1, 123)
+            .insert_incoming_path_started_state(transfer_id, "id1", 0)
             .await;
         storage
-            .insert_outgoing_path_completed_state(transfer_id, "id2")
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/tmp/1")
             .await;
+
+        // The clock went backwards after the file was started
         storage
-            .insert_outgoing_path_reject_state(transfer_id, "id3", false, 246)
-            .await;
+            .conn
+            .lock()
+            .await
+            .execute(
+                "UPDATE incoming_path_completed_states SET created_at = '2000-01-01 00:00:00.000'",
+                params![],
+            )
+            .unwrap();
 
         let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
-
+        assert_eq!(transfers[0].peer_clock_skew_ms, None);
         let paths = match &transfers[0].transfer_type {
-            DbTransferType::Outgoing(out) => out,
-            _ => panic!("Unexpected transfer type"),
+            DbTransferType::Incoming(paths) => paths,
+            DbTransferType::Outgoing(_) => panic!("Expected incoming transfer"),
         };
-        assert_eq!(paths.len(), 4);
+        assert!(matches!(
+            paths[0].states[0].data,
+            IncomingPathStateEventData::Started { .. }
+        ));
+        assert!(matches!(
+            paths[0].states[1].data,
+            IncomingPathStateEventData::Completed { .. }
+        ));
+
+        storage.update_peer_clock_skew(transfer_id, -1500).await;
+        storage.update_peer_clock_skew(transfer_id, 2500).await;
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers[0].peer_clock_skew_ms, Some(2500));
+    }
+
+    #[tokio::test]
+    async fn transfer_notes() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![]),
+        };
+        storage.insert_transfer(&transfer).await;
 
+        let unknown: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
         assert!(storage
-            .remove_transfer_file(transfer_id, "id1")
+            .set_transfer_note(unknown, Some("note"))
             .await
-            .is_some());
+            .is_none());
+
         assert!(storage
-            .remove_transfer_file(transfer_id, "id2")
+            .set_transfer_note(transfer_id, Some("ticket #1"))
             .await
             .is_some());
         assert!(storage
-            .remove_transfer_file(transfer_id, "id3")
+            .set_transfer_note(transfer_id, Some("ticket #123"))
             .await
             .is_some());
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers[0].note.as_deref(), Some("ticket #123"));
+
+        assert!(storage.set_transfer_note(transfer_id, None).await.is_some());
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers[0].note, None);
+
+        storage
+            .insert_transfer_cancel_state(transfer_id, false)
+            .await;
+        storage.purge_transfers(&[transfer_id.to_string()]).await;
         assert!(storage
-            .remove_transfer_file(transfer_id, "id4")
+            .set_transfer_note(transfer_id, Some("note"))
             .await
             .is_none());
+    }
 
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
+    #[tokio::test]
+    async fn transfer_forwarding() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Outgoing(out) => out,
-            _ => panic!("Unexpected transfer type"),
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let origin_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![]),
         };
-        assert_eq!(paths.len(), 1); // 1 since we removed one of them
-        assert_eq!(paths[0].file_id, "id4");
+        storage.insert_transfer(&transfer).await;
+        assert_eq!(storage.transfer_forwarding(transfer_id).await, None);
+
+        let forwarding = TransferForwarding {
+            origin_id,
+            forward_to: Some("5.6.7.8".to_string()),
+        };
+        storage
+            .insert_transfer_forwarding(transfer_id, &forwarding)
+            .await;
+        assert_eq!(
+            storage.transfer_forwarding(transfer_id).await,
+            Some(forwarding)
+        );
+
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers[0].origin_id, Some(origin_id));
+        assert_eq!(transfers[0].forward_to.as_deref(), Some("5.6.7.8"));
     }
 
     #[tokio::test]
-    async fn remove_incoming_file() {
+    async fn transfer_deadline() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
         let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
-
         let transfer = TransferInfo {
             id: transfer_id,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Incoming(vec![
-                TransferIncomingPath {
-                    file_id: "id1".to_string(),
-                    size: 1024,
-                    relative_path: "1".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "id2".to_string(),
-                    size: 1024,
-                    relative_path: "2".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "id3".to_string(),
-                    size: 1024,
-                    relative_path: "3".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "id4".to_string(),
-                    relative_path: "4".to_string(),
-                    size: 2048,
-                },
-            ]),
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                file_id: "id1".to_string(),
+                relative_path: "1".to_string(),
+                uri: "file:///dir".parse().unwrap(),
+                size: 1024,
+                metadata: FileMetadata::default(),
+            }]),
         };
-
         storage.insert_transfer(&transfer).await;
+        assert_eq!(storage.transfer_deadline(transfer_id).await, None);
+
         storage
-            .insert_incoming_path_failed_state(transfer_id, "id1", 1, 123)
+            .insert_transfer_deadline(transfer_id, 1_700_000_000_000)
             .await;
+        assert_eq!(
+            storage.transfer_deadline(transfer_id).await,
+            Some(1_700_000_000_000)
+        );
+
+        // The expired file is told apart from the other failures on restore
+        let failed_status = || async {
+            storage
+                .outgoing_file_sync_state(transfer_id, "id1")
+                .await
+                .unwrap()
+                .failed_status
+        };
+        assert_eq!(failed_status().await, None);
         storage
-            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/id2")
+            .insert_outgoing_path_failed_state(transfer_id, "id1", 46, 0)
             .await;
+        assert_eq!(failed_status().await, Some(46));
+
         storage
-            .insert_incoming_path_reject_state(transfer_id, "id3", false, 246)
+            .insert_transfer_cancel_state(transfer_id, false)
             .await;
+        storage.purge_transfers(&[transfer_id.to_string()]).await;
+        assert_eq!(storage.transfer_deadline(transfer_id).await, None);
+    }
 
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
+    #[tokio::test]
+    async fn transfer_label() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Incoming(inc) => inc,
-            _ => panic!("Unexpected transfer type"),
+        let first: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let second: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        for id in [first, second] {
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Outgoing(vec![]),
+                })
+                .await;
+        }
+        assert_eq!(storage.transfer_label(first).await, None);
+
+        storage.insert_transfer_label(first, "chat-42").await;
+        assert_eq!(
+            storage.transfer_label(first).await.as_deref(),
+            Some("chat-42")
+        );
+
+        let labeled = storage.transfers_with_label(0, "chat-42").await;
+        assert_eq!(labeled.len(), 1);
+        assert_eq!(labeled[0].id, first);
+        assert_eq!(labeled[0].label.as_deref(), Some("chat-42"));
+
+        assert!(storage.transfers_with_label(0, "chat-43").await.is_empty());
+        assert_eq!(storage.transfers_since(0).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn transfer_request_key() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Outgoing(vec![]),
+            })
+            .await;
+        assert_eq!(storage.transfer_request_key(transfer_id).await, None);
+
+        storage
+            .insert_transfer_request_key(transfer_id, "7f3a")
+            .await;
+        assert_eq!(
+            storage.transfer_request_key(transfer_id).await.as_deref(),
+            Some("7f3a")
+        );
+
+        storage.remove_transfer_request_key(transfer_id).await;
+        assert_eq!(storage.transfer_request_key(transfer_id).await, None);
+    }
+
+    #[tokio::test]
+    async fn transfer_added_files() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let path = |file_id: &str| TransferOutgoingPath {
+            file_id: file_id.to_string(),
+            relative_path: file_id.to_string(),
+            uri: "file:///dir".parse().unwrap(),
+            size: 1024,
+            metadata: FileMetadata::default(),
         };
-        assert_eq!(paths.len(), 4);
 
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Outgoing(vec![path("id1")]),
+            })
+            .await;
         assert!(storage
-            .remove_transfer_file(transfer_id, "id1")
+            .outgoing_file_sync_state(transfer_id, "id2")
             .await
-            .is_some());
+            .is_none());
+
+        storage
+            .insert_transfer_files(transfer_id, &TransferFiles::Outgoing(vec![path("id2")]))
+            .await;
         assert!(storage
-            .remove_transfer_file(transfer_id, "id2")
+            .outgoing_file_sync_state(transfer_id, "id1")
             .await
             .is_some());
         assert!(storage
-            .remove_transfer_file(transfer_id, "id3")
+            .outgoing_file_sync_state(transfer_id, "id2")
             .await
             .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id4")
-            .await
-            .is_none());
-
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
 
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Incoming(inc) => inc,
-            _ => panic!("Unexpected transfer type"),
+        let transfer = storage.transfer(transfer_id).await.unwrap();
+        let paths = match &transfer.transfer_type {
+            DbTransferType::Outgoing(paths) => paths,
+            DbTransferType::Incoming(_) => panic!("Expected outgoing transfer"),
         };
-
-        assert_eq!(paths.len(), 1); // 1 since we removed one of them
-        assert_eq!(paths[0].file_id, "id4");
+        assert_eq!(paths.len(), 2);
     }
 
     #[tokio::test]
-    async fn check_storage_api() {
+    async fn transfer_alias() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let transfer1_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let first: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let second: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        for id in [first, second] {
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![]),
+                })
+                .await;
+        }
 
-        let transfer = TransferInfo {
-            id: transfer1_id,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Incoming(vec![
-                TransferIncomingPath {
-                    file_id: "idi1".to_string(),
-                    size: 1024,
-                    relative_path: "1".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "idi2".to_string(),
-                    size: 1024,
-                    relative_path: "2".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "idi3".to_string(),
-                    size: 1024,
-                    relative_path: "3".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "idi4".to_string(),
-                    relative_path: "4".to_string(),
-                    size: 2048,
-                },
-            ]),
-        };
+        assert!(storage.set_transfer_alias(first, "ABCD-2345").await);
+        assert!(!storage.set_transfer_alias(second, "ABCD-2345").await);
+        assert!(storage.set_transfer_alias(second, "ABCD-2346").await);
 
-        storage.insert_transfer(&transfer).await;
-        storage
-            .insert_incoming_path_failed_state(transfer1_id, "idi1", 1, 123)
-            .await;
-        storage
-            .start_incoming_file(transfer1_id, "idi2", "/recv/idi2")
-            .await;
-        storage
-            .insert_incoming_path_completed_state(transfer1_id, "idi2", "/recv/idi2")
-            .await;
-        storage
-            .insert_incoming_path_reject_state(transfer1_id, "idi3", false, 234)
-            .await;
-        storage
-            .insert_incoming_path_started_state(transfer1_id, "idi4", 12345)
-            .await;
+        assert_eq!(storage.transfer_by_alias("ABCD-2345").await, Some(first));
+        assert_eq!(storage.transfer_by_alias("ABCD-9999").await, None);
 
-        let transfer2_id: Uuid = "f333302e-584b-42f8-9f66-6a5ef400297d".parse().unwrap();
+        let transfers = storage.transfers_since(0).await;
+        assert_eq!(transfers[0].alias.as_deref(), Some("ABCD-2345"));
+        assert_eq!(transfers[1].alias.as_deref(), Some("ABCD-2346"));
+    }
 
-        let transfer = TransferInfo {
-            id: transfer2_id,
-            peer: "1.2.3.4".to_string(),
-            files: TransferFiles::Outgoing(vec![
-                TransferOutgoingPath {
-                    file_id: "ido1".to_string(),
-                    relative_path: "1".to_string(),
-                    uri: "file:///dir/1".parse().unwrap(),
-                    size: 1024,
-                },
-                TransferOutgoingPath {
-                    file_id: "ido2".to_string(),
-                    relative_path: "2".to_string(),
-                    uri: "file:///dir/2".parse().unwrap(),
-                    size: 1024,
-                },
-                TransferOutgoingPath {
-                    file_id: "ido3".to_string(),
-                    relative_path: "3".to_string(),
-                    uri: "file:///dir/3".parse().unwrap(),
-                    size: 1024,
-                },
-                TransferOutgoingPath {
-                    file_id: "ido4".to_string(),
-                    relative_path: "4".to_string(),
-                    uri: "file:///dir/4".parse().unwrap(),
-                    size: 2048,
-                },
-            ]),
-        };
+    #[tokio::test]
+    async fn shared_files_cursor() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let file = |subpath: &str, size, mtime| SharedFile {
+            subpath: subpath.to_string(),
+            size,
+            mtime,
+        };
 
-        storage.insert_transfer(&transfer).await;
-        storage
-            .insert_outgoing_path_failed_state(transfer2_id, "ido1", 1, 123)
-            .await;
         storage
-            .insert_outgoing_path_completed_state(transfer2_id, "ido2")
+            .save_shared_files("/dir", "1.2.3.4", &[file("a", 1, 10), file("b", 2, 20)])
             .await;
         storage
-            .insert_outgoing_path_reject_state(transfer2_id, "ido3", false, 234)
+            .save_shared_files("/dir", "1.2.3.4", &[file("a", 3, 30)])
             .await;
+
+        let mut files = storage.shared_files("/dir", "1.2.3.4").await;
+        files.sort_by(|a, b| a.subpath.cmp(&b.subpath));
+        assert_eq!(files, vec![file("a", 3, 30), file("b", 2, 20)]);
+
+        assert!(storage.shared_files("/dir", "5.6.7.8").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn paths_with_checksum() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let first: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let second: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        for id in [first, second] {
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "file".to_string(),
+                        size: 1024,
+                        metadata: FileMetadata::default(),
+                    }]),
+                })
+                .await;
+            storage.save_checksum(id, "id1", &[1; 32]).await;
+        }
+
         storage
-            .insert_outgoing_path_started_state(transfer2_id, "ido4", 12345)
+            .insert_incoming_path_completed_state(first, "id1", "/recv/file")
             .await;
 
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 2);
+        assert_eq!(
+            storage
+                .completed_paths_with_checksum(&[1; 32], 1024, second)
+                .await,
+            vec!["/recv/file".to_string()]
+        );
+        assert!(storage
+            .completed_paths_with_checksum(&[1; 32], 1024, first)
+            .await
+            .is_empty());
+        assert!(storage
+            .completed_paths_with_checksum(&[1; 32], 1000, second)
+            .await
+            .is_empty());
+        assert!(storage
+            .completed_paths_with_checksum(&[2; 32], 1024, second)
+            .await
+            .is_empty());
+    }
 
-        assert_eq!(transfers[0].id, transfer1_id);
-        assert_eq!(transfers[0].peer_id, "5.6.7.8");
-        assert_eq!(transfers[0].states.len(), 0);
+    #[tokio::test]
+    async fn incoming_dir_entries() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        match &transfers[0].transfer_type {
-            DbTransferType::Incoming(inc) => {
-                assert_eq!(inc[0].transfer_id, transfer1_id);
-                assert_eq!(inc[0].relative_path, "1");
-                assert_eq!(inc[0].bytes, 1024);
-                assert_eq!(inc[0].bytes_received, 123);
-                assert_eq!(inc[0].file_id, "idi1");
-                assert_eq!(inc[0].states.len(), 1);
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                file_id: "id1".to_string(),
+                relative_path: "dir/file".to_string(),
+                size: 1024,
+                metadata: FileMetadata::default(),
+            }]),
+        };
+        storage.insert_transfer(&transfer).await;
 
-                assert!(matches!(
-                    inc[0].states[0].data,
-                    IncomingPathStateEventData::Failed {
-                        status_code: 1,
-                        bytes_received: 123
-                    }
-                ));
+        storage
+            .insert_incoming_dir_entries(
+                transfer_id,
+                &[
+                    DirEntry {
+                        relative_path: "dir/empty".to_string(),
+                        symlink_target: None,
+                    },
+                    DirEntry {
+                        relative_path: "dir/link".to_string(),
+                        symlink_target: Some("../file".to_string()),
+                    },
+                ],
+            )
+            .await;
 
-                assert_eq!(inc[1].transfer_id, transfer1_id);
-                assert_eq!(inc[1].relative_path, "2");
-                assert_eq!(inc[1].bytes, 1024);
-                assert_eq!(inc[1].bytes_received, 1024);
-                assert_eq!(inc[1].file_id, "idi2");
-                assert_eq!(inc[1].states.len(), 2);
+        let transfers = storage.incoming_transfers_to_resume().await;
+        let entries = &transfers[0].dir_entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].relative_path, "dir/empty");
+        assert_eq!(entries[0].symlink_target, None);
+        assert_eq!(entries[1].symlink_target.as_deref(), Some("../file"));
+    }
 
-                assert!(matches!(
-                    &inc[1].states[0].data,
-                    IncomingPathStateEventData::Pending{
-                        base_dir,
-                    } if base_dir == "/recv/idi2",
-                ));
-                assert!(matches!(
-                    &inc[1].states[1].data,
-                    IncomingPathStateEventData::Completed {
-                        final_path
-                    } if final_path == "/recv/idi2"
-                ));
+    #[tokio::test]
+    async fn capability_pins() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-                assert_eq!(inc[2].transfer_id, transfer1_id);
-                assert_eq!(inc[2].relative_path, "3");
-                assert_eq!(inc[2].bytes, 1024);
-                assert_eq!(inc[2].bytes_received, 234);
-                assert_eq!(inc[2].file_id, "idi3");
-                assert_eq!(inc[2].states.len(), 1);
+        assert!(!storage.are_peer_capabilities_pinned("aabb").await);
 
-                assert!(matches!(
-                    inc[2].states[0].data,
-                    IncomingPathStateEventData::Rejected {
-                        by_peer: false,
-                        bytes_received: 234
-                    }
-                ));
+        storage.pin_peer_capabilities("aabb").await;
+        storage.pin_peer_capabilities("aabb").await;
 
-                assert_eq!(inc[3].transfer_id, transfer1_id);
-                assert_eq!(inc[3].relative_path, "4");
-                assert_eq!(inc[3].bytes, 2048);
-                assert_eq!(inc[3].bytes_received, 12345);
-                assert_eq!(inc[3].file_id, "idi4");
-                assert_eq!(inc[3].states.len(), 1);
+        assert!(storage.are_peer_capabilities_pinned("aabb").await);
+        assert!(!storage.are_peer_capabilities_pinned("ccdd").await);
+    }
 
-                assert!(matches!(
-                    &inc[3].states[0].data,
-                    IncomingPathStateEventData::Started {
-                        bytes_received: 12345
-                    }
-                ));
-            }
-            _ => panic!("Unexpected transfer type"),
-        };
+    #[tokio::test]
+    async fn peer_devices() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        assert_eq!(transfers[1].id, transfer2_id);
-        assert_eq!(transfers[1].peer_id, "1.2.3.4");
-        assert_eq!(transfers[1].states.len(), 0);
+        assert_eq!(storage.peer_device("1.2.3.4").await, None);
 
-        match &transfers[1].transfer_type {
-            DbTransferType::Outgoing(inc) => {
-                assert_eq!(inc[0].transfer_id, transfer2_id);
-                assert_eq!(inc[0].relative_path, "1");
-                assert_eq!(inc[0].bytes, 1024);
-                assert_eq!(inc[0].bytes_sent, 123);
-                assert_eq!(inc[0].file_id, "ido1");
-                assert_eq!(inc[0].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[0].content_uri.is_none());
-                assert_eq!(inc[0].states.len(), 1);
+        let device = |name: &str, platform: Option<&str>| PeerDevice {
+            name: Some(name.to_string()),
+            platform: platform.map(str::to_string),
+        };
+        storage
+            .save_peer_device("1.2.3.4", &device("Alice's laptop", Some("linux")))
+            .await;
+        storage
+            .save_peer_device("1.2.3.4", &device("Alice's phone", None))
+            .await;
 
-                assert!(matches!(
-                    inc[0].states[0].data,
-                    OutgoingPathStateEventData::Failed {
-                        status_code: 1,
-                        bytes_sent: 123
-                    }
-                ));
+        assert_eq!(
+            storage.peer_device("1.2.3.4").await,
+            Some(device("Alice's phone", None))
+        );
+        assert_eq!(storage.peer_device("5.6.7.8").await, None);
 
-                assert_eq!(inc[1].transfer_id, transfer2_id);
-                assert_eq!(inc[1].relative_path, "2");
-                assert_eq!(inc[1].bytes, 1024);
-                assert_eq!(inc[1].bytes_sent, 1024);
-                assert_eq!(inc[1].file_id, "ido2");
-                assert_eq!(inc[1].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[1].content_uri.is_none());
-                assert_eq!(inc[1].states.len(), 1);
+        // The device is forgotten together with the last transfer of the peer
+        storage
+            .save_peer_device("5.6.7.8", &device("Bob's tablet", None))
+            .await;
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![]),
+            })
+            .await;
 
-                assert!(matches!(
-                    inc[1].states[0].data,
-                    OutgoingPathStateEventData::Completed
-                ));
+        storage.purge_transfers(&[]).await;
+        assert_eq!(storage.peer_device("1.2.3.4").await, None);
+        assert!(storage.peer_device("5.6.7.8").await.is_some());
 
-                assert_eq!(inc[2].transfer_id, transfer2_id);
-                assert_eq!(inc[2].relative_path, "3");
-                assert_eq!(inc[2].bytes, 1024);
-                assert_eq!(inc[2].bytes_sent, 234);
-                assert_eq!(inc[2].file_id, "ido3");
-                assert_eq!(inc[2].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[2].content_uri.is_none());
-                assert_eq!(inc[2].states.len(), 1);
+        storage
+            .insert_transfer_cancel_state(transfer_id, false)
+            .await;
+        storage.purge_transfers(&[transfer_id.to_string()]).await;
+        assert_eq!(storage.peer_device("5.6.7.8").await, None);
+    }
 
-                assert!(matches!(
-                    inc[2].states[0].data,
-                    OutgoingPathStateEventData::Rejected {
-                        by_peer: false,
-                        bytes_sent: 234
-                    }
-                ));
+    #[tokio::test]
+    async fn peer_listen_ports() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-                assert_eq!(inc[3].transfer_id, transfer2_id);
-                assert_eq!(inc[3].relative_path, "4");
-                assert_eq!(inc[3].bytes, 2048);
-                assert_eq!(inc[3].bytes_sent, 12345);
-                assert_eq!(inc[3].file_id, "ido4");
-                assert_eq!(inc[3].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[3].content_uri.is_none());
-                assert_eq!(inc[3].states.len(), 1);
+        assert_eq!(storage.peer_listen_port("1.2.3.4").await, None);
 
-                assert!(matches!(
-                    inc[3].states[0].data,
-                    OutgoingPathStateEventData::Started { bytes_sent: 12345 }
-                ));
-            }
-            _ => panic!("Unexpected transfer type"),
+        storage.save_peer_listen_port("1.2.3.4", 49111).await;
+        assert_eq!(storage.peer_listen_port("1.2.3.4").await, Some(49111));
+        // No device declared yet
+        assert_eq!(storage.peer_device("1.2.3.4").await, None);
+
+        // The device and the port are kept side by side
+        let device = PeerDevice {
+            name: Some("Alice's laptop".to_string()),
+            platform: None,
         };
+        storage.save_peer_device("1.2.3.4", &device).await;
+        storage.save_peer_listen_port("1.2.3.4", 49112).await;
+        assert_eq!(storage.peer_device("1.2.3.4").await, Some(device));
+        assert_eq!(storage.peer_listen_port("1.2.3.4").await, Some(49112));
     }
 
     #[tokio::test]
-    async fn removing_garbage_transfers() {
+    async fn test_peer_usage() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
-        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage.insert_peer_usage("1.2.3.4", 100).await;
+        storage.insert_peer_usage("1.2.3.4", 50).await;
+        storage.insert_peer_usage("5.6.7.8", 10).await;
+
+        assert_eq!(storage.peer_usage("1.2.3.4", 0).await, 150);
+        assert_eq!(storage.peer_usage("5.6.7.8", 0).await, 10);
+        assert_eq!(storage.peer_usage("9.9.9.9", 0).await, 0);
+
+        // Outside of the window
+        let future = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            + 60;
+        assert_eq!(storage.peer_usage("1.2.3.4", future).await, 0);
+        assert_eq!(storage.peer_usage("1.2.3.4", 0).await, 0);
+    }
 
-        let transfer = TransferInfo {
-            id: transfer_id_1,
-            peer: "1.2.3.4".to_string(),
-            files: TransferFiles::Incoming(vec![]),
+    #[tokio::test]
+    async fn test_journal_batching() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        assert_eq!(storage.insert_journal_event(1, "a").await, Some(1));
+        assert_eq!(storage.queue_journal_event(2, "b").await, Some(2));
+        assert_eq!(storage.queue_journal_event(3, "c").await, Some(3));
+
+        let count = || async {
+            let conn = storage.conn.lock().await;
+            conn.query_row("SELECT COUNT(*) FROM event_journal", [], |r| {
+                r.get::<_, i64>(0)
+            })
+            .unwrap()
         };
-        storage.insert_transfer(&transfer).await;
+        assert_eq!(count().await, 1);
 
-        let transfer = TransferInfo {
-            id: transfer_id_2,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Outgoing(vec![]),
+        // The next written event takes the queued ones with it
+        assert_eq!(storage.insert_journal_event(4, "d").await, Some(4));
+        assert_eq!(count().await, 4);
+
+        for i in 0..JOURNAL_BATCH_EVENTS as i64 {
+            storage.queue_journal_event(5 + i, "e").await;
+        }
+        assert_eq!(count().await, 4 + JOURNAL_BATCH_EVENTS as i64);
+
+        storage.queue_journal_event(100, "f").await;
+        let events = storage.journal_events_since(0).await;
+        assert_eq!(events.len(), 5 + JOURNAL_BATCH_EVENTS);
+        assert!(events.windows(2).all(|w| w[0].seq + 1 == w[1].seq));
+        assert_eq!(events.last().unwrap().payload, "f");
+
+        // The numbering goes on after the purge
+        storage.purge_transfers_until(i64::MAX).await;
+        let seq = storage.insert_journal_event(101, "g").await.unwrap();
+        assert_eq!(seq, events.last().unwrap().seq + 1);
+    }
+
+    #[tokio::test]
+    async fn test_journal_flush_timer() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = std::sync::Arc::new(Storage::new(logger, ":memory:").unwrap());
+        let flush_task = tokio::spawn({
+            let storage = storage.clone();
+            async move { storage.flush_journal_periodically().await }
+        });
+
+        let count = || async {
+            let conn = storage.conn.lock().await;
+            conn.query_row("SELECT COUNT(*) FROM event_journal", [], |r| {
+                r.get::<_, i64>(0)
+            })
+            .unwrap()
         };
-        storage.insert_transfer(&transfer).await;
 
-        // Transfers need to be termiated before any purging is allowed
-        storage
-            .insert_transfer_cancel_state(transfer_id_1, false)
-            .await;
-        storage
-            .insert_transfer_cancel_state(transfer_id_2, false)
-            .await;
+        storage.queue_journal_event(1, "a").await;
+        storage.queue_journal_event(2, "b").await;
+        assert_eq!(count().await, 0);
 
-        // No garbage to collect
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 0);
+        // Written without any other event coming
+        tokio::time::sleep(JOURNAL_FLUSH_INTERVAL * 2).await;
+        assert_eq!(count().await, 2);
 
-        storage.purge_transfers(&[transfer_id_1.to_string()]).await;
+        storage.queue_journal_event(3, "c").await;
+        tokio::time::sleep(JOURNAL_FLUSH_INTERVAL * 2).await;
+        assert_eq!(count().await, 3);
 
-        // Still the transfer was not synced
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 0);
+        flush_task.abort();
+    }
 
-        let cleared = storage.transfer_sync_clear(transfer_id_1).await;
-        assert!(cleared.is_some());
+    #[tokio::test]
+    async fn test_checksum_cache() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        // Now the transfer can be garbage collected
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 1);
+        assert_eq!(storage.cached_checksum("/tmp/a", 10, 100).await, None);
 
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 0);
+        storage.cache_checksum("/tmp/a", 10, 100, &[1; 32]).await;
+        assert_eq!(
+            storage.cached_checksum("/tmp/a", 10, 100).await,
+            Some(vec![1; 32])
+        );
+        // The file changed
+        assert_eq!(storage.cached_checksum("/tmp/a", 10, 101).await, None);
+        assert_eq!(storage.cached_checksum("/tmp/a", 11, 100).await, None);
+
+        storage.cache_checksum("/tmp/a", 11, 101, &[2; 32]).await;
+        assert_eq!(storage.cached_checksum("/tmp/a", 10, 100).await, None);
+        assert_eq!(
+            storage.cached_checksum("/tmp/a", 11, 101).await,
+            Some(vec![2; 32])
+        );
+    }
 
-        // Ensure we haven't deleted the second transfer
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
-        assert_eq!(transfers[0].id, transfer_id_2);
+    #[tokio::test]
+    async fn test_checksum_cache_eviction() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        for path in ["/tmp/a", "/tmp/b", "/tmp/c"] {
+            storage
+                .cache_checksum_within(path, 10, 100, &[1; 32], 3)
+                .await;
+        }
+
+        // The hit makes the file the most recently used
+        assert!(storage.cached_checksum("/tmp/a", 10, 100).await.is_some());
+        storage
+            .cache_checksum_within("/tmp/d", 10, 100, &[1; 32], 3)
+            .await;
+
+        assert!(storage.cached_checksum("/tmp/a", 10, 100).await.is_some());
+        assert_eq!(storage.cached_checksum("/tmp/b", 10, 100).await, None);
+        assert!(storage.cached_checksum("/tmp/c", 10, 100).await.is_some());
+        assert!(storage.cached_checksum("/tmp/d", 10, 100).await.is_some());
     }
 }
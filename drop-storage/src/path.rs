@@ -0,0 +1,222 @@
+//! Resolution of the default database location following the platform
+//! conventions
+
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
+
+/// The `storage_path` value asking to store the database in the default
+/// location of the platform
+pub const AUTO: &str = "auto";
+
+const DIR_NAME: &str = "libdrop";
+const FILE_NAME: &str = "libdrop.sqlite";
+
+/// Returns the database path to open. The [`AUTO`] path is resolved to the
+/// default location of the platform, creating the missing directories
+/// accessible to the current user only. Other paths are returned as they are
+pub fn resolve(path: &str) -> io::Result<String> {
+    if path != AUTO {
+        return Ok(path.to_string());
+    }
+
+    let dir = app_dir(&default_dir()?, app_name().as_deref());
+    create_dir(&dir)?;
+
+    dir.join(FILE_NAME)
+        .into_os_string()
+        .into_string()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Non UTF-8 storage path"))
+}
+
+// The data directories of the desktops are shared by all the apps of the
+// user, each app using libdrop gets the subdirectory named after its
+// executable
+fn app_dir(data_dir: &Path, app: Option<&str>) -> PathBuf {
+    let dir = data_dir.join(DIR_NAME);
+    match app {
+        Some(app) => dir.join(app),
+        None => dir,
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "ios"))]
+fn app_name() -> Option<String> {
+    // The data directory belongs to the app already
+    None
+}
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+fn app_name() -> Option<String> {
+    let exe = env::current_exe().ok()?;
+    let name = exe.file_stem()?.to_str()?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+fn env_dir(var: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .filter(|val| !val.is_empty())
+        .map(PathBuf::from)
+}
+
+fn not_found(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Cannot determine the {what} directory"),
+    )
+}
+
+/// `$XDG_DATA_HOME`, falling back to `~/.local/share`
+#[cfg(all(unix, not(any(target_os = "android", target_vendor = "apple"))))]
+fn default_dir() -> io::Result<PathBuf> {
+    xdg_data_dir(env_dir("XDG_DATA_HOME"), env_dir("HOME"))
+}
+
+#[cfg(all(unix, not(any(target_os = "android", target_vendor = "apple"))))]
+fn xdg_data_dir(data_home: Option<PathBuf>, home: Option<PathBuf>) -> io::Result<PathBuf> {
+    // The relative paths are to be ignored according to the specification
+    if let Some(dir) = data_home.filter(|dir| dir.is_absolute()) {
+        return Ok(dir);
+    }
+
+    home.map(|home| home.join(".local").join("share"))
+        .ok_or_else(|| not_found("home"))
+}
+
+/// `~/Library/Application Support`, the home being the app container on iOS
+#[cfg(target_vendor = "apple")]
+fn default_dir() -> io::Result<PathBuf> {
+    env_dir("HOME")
+        .map(|home| home.join("Library").join("Application Support"))
+        .ok_or_else(|| not_found("home"))
+}
+
+/// The files directory of the app, `/data/user/<user>/<package>/files`
+#[cfg(target_os = "android")]
+fn default_dir() -> io::Result<PathBuf> {
+    // The process name is the package name, optionally followed by the
+    // `:<process>` suffix
+    let cmdline = std::fs::read("/proc/self/cmdline")?;
+    let name = cmdline.split(|&b| b == 0).next().unwrap_or_default();
+    let name = std::str::from_utf8(name).map_err(|_| not_found("app files"))?;
+
+    let uid = std::os::unix::fs::MetadataExt::uid(&std::fs::metadata("/proc/self")?);
+    android_files_dir(name, uid).ok_or_else(|| not_found("app files"))
+}
+
+// The `/data/data` directory is the one of the primary user only, the other
+// users and the work profiles have theirs under `/data/user/<user>`
+#[cfg(any(target_os = "android", test))]
+fn android_files_dir(process: &str, uid: u32) -> Option<PathBuf> {
+    const PER_USER_RANGE: u32 = 100_000;
+
+    let package = process.split(':').next().unwrap_or_default();
+    if package.is_empty() || package.contains('/') || package.starts_with('.') {
+        return None;
+    }
+
+    Some(
+        Path::new("/data/user")
+            .join((uid / PER_USER_RANGE).to_string())
+            .join(package)
+            .join("files"),
+    )
+}
+
+/// `%LOCALAPPDATA%`
+#[cfg(windows)]
+fn default_dir() -> io::Result<PathBuf> {
+    env_dir("LOCALAPPDATA").ok_or_else(|| not_found("local app data"))
+}
+
+#[cfg(unix)]
+fn create_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+
+    // Created by the earlier versions or by the user with the default mode
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+}
+
+#[cfg(not(unix))]
+fn create_dir(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_paths_are_kept() {
+        assert_eq!(resolve(":memory:").unwrap(), ":memory:");
+        assert_eq!(resolve("/tmp/db.sqlite").unwrap(), "/tmp/db.sqlite");
+    }
+
+    #[test]
+    fn apps_get_own_directories() {
+        let data = Path::new("/data");
+        assert_eq!(
+            app_dir(data, Some("daemon")),
+            Path::new("/data/libdrop/daemon")
+        );
+        assert_eq!(app_dir(data, None), Path::new("/data/libdrop"));
+    }
+
+    #[cfg(all(unix, not(any(target_os = "android", target_vendor = "apple"))))]
+    #[test]
+    fn xdg_data_home() {
+        let home = Some(PathBuf::from("/home/user"));
+
+        assert_eq!(
+            xdg_data_dir(Some("/xdg".into()), home.clone()).unwrap(),
+            Path::new("/xdg")
+        );
+        assert_eq!(
+            xdg_data_dir(Some("relative".into()), home.clone()).unwrap(),
+            Path::new("/home/user/.local/share")
+        );
+        assert_eq!(
+            xdg_data_dir(None, home).unwrap(),
+            Path::new("/home/user/.local/share")
+        );
+        assert!(xdg_data_dir(None, None).is_err());
+    }
+
+    #[test]
+    fn android_users() {
+        assert_eq!(
+            android_files_dir("com.example.app", 10_123),
+            Some(PathBuf::from("/data/user/0/com.example.app/files"))
+        );
+        assert_eq!(
+            android_files_dir("com.example.app:service", 1_010_123),
+            Some(PathBuf::from("/data/user/10/com.example.app/files"))
+        );
+        assert_eq!(android_files_dir("../etc", 10_123), None);
+        assert_eq!(android_files_dir("", 10_123), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = env::temp_dir().join(format!("drop-storage-path-{}", std::process::id()));
+        let dir = tmp.join("libdrop").join("app");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        create_dir(&dir).unwrap();
+
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        std::fs::remove_dir_all(tmp).unwrap();
+    }
+}
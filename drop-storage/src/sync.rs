@@ -104,6 +104,44 @@ pub(super) fn insert_transfer(
     Ok(())
 }
 
+/// Adds the sync states of the files added to the transfer after it was
+/// inserted
+pub(super) fn insert_files(
+    conn: &Connection,
+    transfer_id: Uuid,
+    is_incoming: bool,
+) -> super::Result<()> {
+    let tid = transfer_id.to_string();
+
+    if is_incoming {
+        conn.execute(
+            r#"
+                INSERT INTO sync_incoming_files (sync_id, path_id, local_state)
+                SELECT st.sync_id, ip.id, ?2
+                FROM sync_transfer st
+                INNER JOIN incoming_paths ip ON st.transfer_id = ip.transfer_id
+                WHERE st.transfer_id = ?1
+                    AND ip.id NOT IN (SELECT path_id FROM sync_incoming_files)
+                "#,
+            params![tid, FileState::Alive],
+        )?;
+    } else {
+        conn.execute(
+            r#"
+                INSERT INTO sync_outgoing_files (sync_id, path_id, local_state)
+                SELECT st.sync_id, op.id, ?2
+                FROM sync_transfer st
+                INNER JOIN outgoing_paths op ON st.transfer_id = op.transfer_id
+                WHERE st.transfer_id = ?1
+                    AND op.id NOT IN (SELECT path_id FROM sync_outgoing_files)
+                "#,
+            params![tid, FileState::Alive],
+        )?;
+    }
+
+    Ok(())
+}
+
 pub(super) fn transfer_state(
     conn: &Connection,
     transfer_id: Uuid,
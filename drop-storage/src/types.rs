@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 use chrono::NaiveDateTime;
 use serde::Serialize;
@@ -23,9 +23,15 @@ pub enum OutgoingPathStateEventData {
     #[serde(rename = "failed")]
     Failed { status_code: i64, bytes_sent: i64 },
     #[serde(rename = "completed")]
-    Completed,
+    Completed {
+        // Reported by the receiver, if enabled on its side
+        #[serde(skip_serializing_if = "Option::is_none")]
+        final_path: Option<String>,
+    },
     #[serde(rename = "rejected")]
     Rejected { by_peer: bool, bytes_sent: i64 },
+    #[serde(rename = "withdrawn")]
+    Withdrawn { by_peer: bool },
     #[serde(rename = "paused")]
     Paused { bytes_sent: i64 },
 }
@@ -46,6 +52,8 @@ pub enum IncomingPathStateEventData {
     Completed { final_path: String },
     #[serde(rename = "rejected")]
     Rejected { by_peer: bool, bytes_received: i64 },
+    #[serde(rename = "withdrawn")]
+    Withdrawn { by_peer: bool },
     #[serde(rename = "paused")]
     Paused { bytes_received: i64 },
 }
@@ -96,10 +104,25 @@ pub enum TransferType {
     Outgoing = 1,
 }
 
+/// Optional file attributes received in or sent with the transfer manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FileMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<u32>,
+    /// The key-value pairs the sending app attached to the file
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub app_metadata: BTreeMap<String, String>,
+}
+
 pub struct TransferIncomingPath {
     pub file_id: FileId,
     pub relative_path: String,
     pub size: i64,
+    pub metadata: FileMetadata,
 }
 
 pub struct TransferOutgoingPath {
@@ -107,6 +130,7 @@ pub struct TransferOutgoingPath {
     pub relative_path: String,
     pub uri: url::Url,
     pub size: i64,
+    pub metadata: FileMetadata,
 }
 
 pub enum TransferFiles {
@@ -120,6 +144,14 @@ pub struct TransferInfo {
     pub files: TransferFiles,
 }
 
+/// The store-and-forward routing of the transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferForwarding {
+    pub origin_id: TransferId,
+    // The final peer, set only on the hop to the relay
+    pub forward_to: Option<String>,
+}
+
 pub struct FileChecksum {
     pub file_id: FileId,
     pub checksum: Option<Vec<u8>>,
@@ -129,12 +161,37 @@ pub struct IncomingFileToRetry {
     pub file_id: String,
     pub subpath: String,
     pub size: u64,
+    pub metadata: FileMetadata,
+}
+
+/// The version of the file of the shared directory already sent to the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedFile {
+    pub subpath: String,
+    pub size: i64,
+    // Modification time in milliseconds since the UNIX epoch
+    pub mtime: i64,
+}
+
+/// The name and the platform the peer declared about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerDevice {
+    pub name: Option<String>,
+    pub platform: Option<String>,
+}
+
+/// The empty directory or the symlink of the incoming directory transfer
+pub struct DirEntry {
+    pub relative_path: String,
+    // `None` for the empty directory
+    pub symlink_target: Option<String>,
 }
 
 pub struct IncomingTransferToRetry {
     pub uuid: uuid::Uuid,
     pub peer: String,
     pub files: Vec<IncomingFileToRetry>,
+    pub dir_entries: Vec<DirEntry>,
 }
 
 pub struct FinishedIncomingFile {
@@ -147,6 +204,7 @@ pub struct OutgoingFileToRetry {
     pub subpath: String,
     pub uri: url::Url,
     pub size: i64,
+    pub app_metadata: BTreeMap<String, String>,
 }
 
 pub struct OutgoingTransferToRetry {
@@ -165,6 +223,12 @@ pub struct FileSyncState {
     pub is_rejected: bool,
     pub is_success: bool,
     pub is_failed: bool,
+    /// The status code of the last failure
+    pub failed_status: Option<i64>,
+    /// The rejection was the sender withdrawing the file
+    pub is_withdrawn: bool,
+    /// The file was started at least once
+    pub is_started: bool,
 }
 
 #[derive(Serialize)]
@@ -185,6 +249,148 @@ pub struct Transfer {
     pub states: Vec<TransferStateEvent>,
     #[serde(flatten)]
     pub transfer_type: DbTransferType,
+    #[serde(flatten)]
+    pub totals: TransferTotals,
+    /// The peer's wall clock minus the local one in milliseconds, estimated
+    /// when connecting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_clock_skew_ms: Option<i64>,
+    /// The annotation set by the app
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The transfer ID given by the original sender of the forwarded transfer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin_id: Option<TransferId>,
+    /// The final peer of the transfer sent to the relay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward_to: Option<String>,
+    /// The short human-friendly code of the transfer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// The opaque label attached to the transfer when creating it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// Totals computed from the path states
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct TransferTotals {
+    // The size of all the files
+    pub total_bytes: i64,
+    // Bytes sent or received so far
+    pub transferred_bytes: i64,
+    // Time from the first file started to the last file state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i64>,
+    // Average bytes per second over the `duration_ms`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_bps: Option<i64>,
+}
+
+impl TransferTotals {
+    fn new(
+        total_bytes: i64,
+        transferred_bytes: i64,
+        resumed_bytes: i64,
+        started_at: Option<NaiveDateTime>,
+        last_state_at: Option<NaiveDateTime>,
+    ) -> Self {
+        let duration_ms = started_at
+            .zip(last_state_at)
+            .map(|(start, end)| (end - start).num_milliseconds().max(0));
+
+        // The bytes the files were resumed from were not transferred within
+        // the duration
+        let average_bps = duration_ms
+            .filter(|&duration| duration > 0)
+            .map(|duration| (transferred_bytes - resumed_bytes).max(0) * 1000 / duration);
+
+        Self {
+            total_bytes,
+            transferred_bytes,
+            duration_ms,
+            average_bps,
+        }
+    }
+}
+
+impl Transfer {
+    /// Computes the totals, must be called once the paths are collected
+    pub(crate) fn update_totals(&mut self) {
+        // The state timestamps of each path with the offset of the started ones
+        let (total_bytes, transferred_bytes, states): (i64, i64, Vec<Vec<_>>) =
+            match &self.transfer_type {
+                DbTransferType::Incoming(paths) => (
+                    paths.iter().map(|path| path.bytes).sum(),
+                    paths.iter().map(|path| path.bytes_received).sum(),
+                    paths
+                        .iter()
+                        .map(|path| {
+                            path.states
+                                .iter()
+                                .map(|state| {
+                                    let offset = match state.data {
+                                        IncomingPathStateEventData::Started { bytes_received } => {
+                                            Some(bytes_received)
+                                        }
+                                        _ => None,
+                                    };
+                                    (offset, state.created_at)
+                                })
+                                .collect()
+                        })
+                        .collect(),
+                ),
+                DbTransferType::Outgoing(paths) => (
+                    paths.iter().map(|path| path.bytes).sum(),
+                    paths.iter().map(|path| path.bytes_sent).sum(),
+                    paths
+                        .iter()
+                        .map(|path| {
+                            path.states
+                                .iter()
+                                .map(|state| {
+                                    let offset = match state.data {
+                                        OutgoingPathStateEventData::Started { bytes_sent } => {
+                                            Some(bytes_sent)
+                                        }
+                                        _ => None,
+                                    };
+                                    (offset, state.created_at)
+                                })
+                                .collect()
+                        })
+                        .collect(),
+                ),
+            };
+
+        // The offset each file was first started from
+        let resumed_bytes = states
+            .iter()
+            .filter_map(|path| {
+                path.iter()
+                    .filter_map(|(offset, at)| Some((*at, (*offset)?)))
+                    .min()
+                    .map(|(_, offset)| offset)
+            })
+            .sum();
+
+        let states = states.iter().flatten();
+        let started_at = states
+            .clone()
+            .filter(|(offset, _)| offset.is_some())
+            .map(|(_, at)| *at)
+            .min();
+        let last_state_at = states.map(|(_, at)| *at).max();
+
+        self.totals = TransferTotals::new(
+            total_bytes,
+            transferred_bytes,
+            resumed_bytes,
+            started_at,
+            last_state_at,
+        );
+    }
 }
 
 #[derive(Serialize)]
@@ -202,6 +408,8 @@ pub struct OutgoingPath {
     pub file_id: String,
     pub bytes: i64,
     pub bytes_sent: i64,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
     pub states: Vec<OutgoingPathStateEvent>,
 }
 
@@ -216,5 +424,13 @@ pub struct IncomingPath {
     pub file_id: String,
     pub bytes: i64,
     pub bytes_received: i64,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
     pub states: Vec<IncomingPathStateEvent>,
 }
+
+pub struct JournalEvent {
+    pub seq: i64,
+    pub timestamp: i64,
+    pub payload: String,
+}
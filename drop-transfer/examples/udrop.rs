@@ -59,7 +59,7 @@ fn print_event(ev: &Event) {
                 xfid, info.id, info.final_path,
             );
         }
-        Event::FileUploadSuccess(xfer, path) => {
+        Event::FileUploadSuccess(xfer, path, _) => {
             info!("[EVENT] FileUploadSuccess {}: {:?}", xfer.id(), path,);
         }
         Event::RequestQueued(xfer) => {
@@ -88,12 +88,12 @@ fn print_event(ev: &Event) {
                 status
             );
         }
-        Event::FileDownloadFailed(xfer, file, status) => {
+        Event::FileDownloadFailed(xfer, file, status, partial_path) => {
             let xfid = xfer.id();
 
             info!(
-                "[EVENT] FileDownloadFailed {}: {:?}, {:?}",
-                xfid, file, status
+                "[EVENT] FileDownloadFailed {}: {:?}, {:?}, partial file: {:?}",
+                xfid, file, status, partial_path
             );
         }
         Event::IncomingTransferCanceled(xfer, by_peer) => {
@@ -110,6 +110,34 @@ fn print_event(ev: &Event) {
                 by_peer
             );
         }
+        Event::IncomingTransferWithdrawn(xfer, by_peer) => {
+            info!(
+                "[EVENT] IncomingTransferWithdrawn {}, by peer? {}",
+                xfer.id(),
+                by_peer
+            );
+        }
+        Event::OutgoingTransferWithdrawn(xfer, by_peer) => {
+            info!(
+                "[EVENT] OutgoingTransferWithdrawn {}, by peer? {}",
+                xfer.id(),
+                by_peer
+            );
+        }
+        Event::IncomingFilesAdded(xfer, files) => {
+            info!(
+                "[EVENT] IncomingFilesAdded {}: {} files",
+                xfer.id(),
+                files.len()
+            );
+        }
+        Event::OutgoingFilesAdded(xfer, files) => {
+            info!(
+                "[EVENT] OutgoingFilesAdded {}: {} files",
+                xfer.id(),
+                files.len()
+            );
+        }
         Event::OutgoingTransferFailed(xfer, err, by_peer) => {
             info!(
                 "[EVENT] OutgoingTransferFailed {}, status: {}, by peer? {}",
@@ -133,6 +161,14 @@ fn print_event(ev: &Event) {
         } => {
             info!("[EVENT] FileUploadRejected {transfer_id}: {file_id}, by_peer?: {by_peer}")
         }
+        Event::FileDownloadWithdrawn {
+            transfer_id,
+            file_id,
+        } => info!("[EVENT] FileDownloadWithdrawn {transfer_id}: {file_id}"),
+        Event::FileUploadWithdrawn {
+            transfer_id,
+            file_id,
+        } => info!("[EVENT] FileUploadWithdrawn {transfer_id}: {file_id}"),
         Event::FileUploadPaused {
             transfer_id,
             file_id,
@@ -181,10 +217,83 @@ fn print_event(ev: &Event) {
             file_id,
             progress,
         } => info!("[EVENT] VerifyChecksumProgress {transfer_id}: {file_id}, progress: {progress}"),
+        Event::StartupReconciled(summary) => info!("[EVENT] StartupReconciled: {summary:?}"),
+        Event::KeyRotationCompleted => info!("[EVENT] KeyRotationCompleted"),
+        Event::OperationRefused {
+            transfer_id,
+            file_id,
+            operation,
+        } => info!("[EVENT] OperationRefused {transfer_id}: {operation:?}, file: {file_id:?}"),
+        Event::IncomingDirectoryPrepared {
+            transfer_id,
+            root,
+            path,
+        } => info!("[EVENT] IncomingDirectoryPrepared {transfer_id}: {root} -> {path:?}"),
+        Event::IncomingDownloadsQueued {
+            transfer_id,
+            file_ids,
+        } => info!("[EVENT] IncomingDownloadsQueued {transfer_id}: {file_ids:?}"),
+        Event::IncomingFileDuplicate {
+            transfer_id,
+            file_id,
+            existing_path,
+        } => info!("[EVENT] IncomingFileDuplicate {transfer_id}: {file_id} -> {existing_path:?}"),
+        Event::OutgoingPreflightFinished {
+            transfer_id,
+            unreadable,
+        } => info!("[EVENT] OutgoingPreflightFinished {transfer_id}: unreadable: {unreadable:?}"),
+        Event::IncomingTransferRejected {
+            transfer_id,
+            peer,
+            error,
+        } => info!("[EVENT] IncomingTransferRejected {transfer_id} from {peer}: {error}"),
+        Event::IncomingTransferPaused {
+            transfer_id,
+            paused,
+        } => info!("[EVENT] IncomingTransferPaused {transfer_id}: paused: {paused}"),
+        Event::OutgoingTransferPaused {
+            transfer_id,
+            paused,
+        } => info!("[EVENT] OutgoingTransferPaused {transfer_id}: paused: {paused}"),
+        Event::IncomingText { id, peer, text } => {
+            info!("[EVENT] IncomingText {id} from {peer}: {text:?}")
+        }
+        Event::OutgoingTextSent { id } => info!("[EVENT] OutgoingTextSent {id}"),
+        Event::OutgoingTextFailed { id, error } => {
+            info!("[EVENT] OutgoingTextFailed {id}: {error}")
+        }
         Event::OutgoingTransferDeferred { transfer, error } => info!(
             "[EVENT] OutgoingTransferDeferred {}: error: {error}",
             transfer.id()
         ),
+        Event::OutgoingTransferPostponed(transfer) => {
+            info!("[EVENT] OutgoingTransferPostponed {}", transfer.id())
+        }
+        Event::OutgoingPeerKeyUnavailable(transfer) => info!(
+            "[EVENT] OutgoingPeerKeyUnavailable {}, peer: {}",
+            transfer.id(),
+            transfer.peer()
+        ),
+        Event::OutgoingConnectionAttemptFailed {
+            transfer,
+            attempt,
+            next_retry_in,
+        } => info!(
+            "[EVENT] OutgoingConnectionAttemptFailed {}: attempt: {attempt}, next retry in: \
+             {next_retry_in:?}",
+            transfer.id()
+        ),
+        Event::OutgoingConnectionDiagnostics {
+            transfer,
+            diagnostics,
+        } => info!(
+            "[EVENT] OutgoingConnectionDiagnostics {}: {diagnostics:?}",
+            transfer.id()
+        ),
+        Event::OutgoingTransferConnected { transfer, relayed } => info!(
+            "[EVENT] OutgoingTransferConnected {}: relayed: {relayed}",
+            transfer.id()
+        ),
         Event::FileDownloadPending {
             transfer_id,
             file_id,
@@ -327,7 +436,7 @@ async fn main() -> anyhow::Result<()> {
     let storage = Arc::new(Storage::new(logger.clone(), storage_file).unwrap());
 
     let mut service = Service::start(
-        addr,
+        vec![addr],
         storage.clone(),
         tx,
         logger,
@@ -335,6 +444,8 @@ async fn main() -> anyhow::Result<()> {
         drop_analytics::moose_mock(),
         Arc::new(auth),
         Instant::now(),
+        None,
+        None,
         #[cfg(unix)]
         None,
     )
@@ -343,7 +454,10 @@ async fn main() -> anyhow::Result<()> {
 
     if let Some(xfer) = xfer {
         info!("Transfer:\n{xfer:#?}");
-        service.send_request(xfer).await;
+        service
+            .send_request(xfer)
+            .await
+            .context("Failed to send the transfer")?;
     }
 
     info!("Listening...");
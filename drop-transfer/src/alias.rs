@@ -0,0 +1,92 @@
+//! Short human-friendly transfer codes, e.g. `K7QF-3MXD`, easy to read aloud.
+//! With the `short_transfer_ids` config option every new transfer gets such
+//! code stored next to it, which is accepted in place of the transfer ID
+
+use rand::Rng;
+use slog::{warn, Logger};
+use uuid::Uuid;
+
+use crate::service::State;
+
+// No `0`, `1`, `I`, `L` nor `O` which are easily confused
+const ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+const CODE_LEN: usize = 8;
+// The code is generated again when it collides with the existing one
+const MAX_ATTEMPTS: usize = 8;
+
+fn generate() -> String {
+    let mut rng = rand::thread_rng();
+
+    let mut code = String::with_capacity(CODE_LEN + 1);
+    for i in 0..CODE_LEN {
+        if i == CODE_LEN / 2 {
+            code.push('-');
+        }
+        code.push(ALPHABET[rng.gen_range(0..ALPHABET.len())] as char);
+    }
+    code
+}
+
+/// The canonical form of the code typed by the user, who might have skipped
+/// the dash or used the lowercase letters. `None` if it's not a valid code
+pub(crate) fn normalize(code: &str) -> Option<String> {
+    let chars: Vec<_> = code
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    if chars.len() != CODE_LEN
+        || !chars
+            .iter()
+            .all(|&c| c.is_ascii() && ALPHABET.contains(&(c as u8)))
+    {
+        return None;
+    }
+
+    let (head, tail) = chars.split_at(CODE_LEN / 2);
+    Some(format!(
+        "{}-{}",
+        head.iter().collect::<String>(),
+        tail.iter().collect::<String>()
+    ))
+}
+
+/// Assigns the unique code to the newly created transfer, when enabled
+pub(crate) async fn assign(state: &State, logger: &Logger, transfer_id: Uuid) {
+    if !state.config.short_transfer_ids {
+        return;
+    }
+
+    for _ in 0..MAX_ATTEMPTS {
+        if state
+            .storage
+            .set_transfer_alias(transfer_id, &generate())
+            .await
+        {
+            return;
+        }
+    }
+
+    warn!(
+        logger,
+        "Failed to assign the short ID to transfer {transfer_id}"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizing() {
+        let code = generate();
+        assert_eq!(normalize(&code), Some(code.clone()));
+        assert_eq!(normalize(&code.to_lowercase().replace('-', "")), Some(code));
+
+        assert_eq!(normalize("k7qf 3mxd").as_deref(), Some("K7QF-3MXD"));
+        assert_eq!(normalize("K7QF-3MX"), None);
+        assert_eq!(normalize("K7QF-3MXO"), None);
+        assert_eq!(normalize("23e488a4-0521-11ee-be56-0242ac120002"), None);
+    }
+}
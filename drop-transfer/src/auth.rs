@@ -1,11 +1,90 @@
-use std::net::IpAddr;
+//! Authentication of the peers in the connection handshake. Both sides
+//! challenge each other with a nonce in the `www-authenticate` header and
+//! answer the peer's challenge with the `authorization` header. The X25519
+//! scheme is always available, the integrators can add their own schemes with
+//! [`Context::with_authenticator()`] and make them mandatory with
+//! [`Context::without_default_scheme()`]. Each side offers all of its schemes
+//! and the answering side picks the one it prefers.
 
-use drop_auth::{PublicKey, SecretKey};
-use hyper::{http::HeaderValue, Response};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use drop_auth::{Nonce, PublicKey, SecretKey};
+use hyper::{http::HeaderValue, HeaderMap, Response};
+
+use crate::protocol::v6::ChunkCipher;
+
+/// The side of the connection answering the peer's challenge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// `check_nonce_prefix` tells whether the server's nonce must be marked
+    /// as generated by the server
+    Client {
+        check_nonce_prefix: bool,
+    },
+    Server,
+}
+
+/// The identity scheme used to authenticate the peers
+pub trait Authenticator: Send + Sync {
+    /// The name of the scheme, the first token of the `www-authenticate` and
+    /// `authorization` header values
+    fn scheme(&self) -> &str;
+
+    /// The `www-authenticate` header value challenging the peer with the nonce
+    fn challenge(&self, nonce: &Nonce) -> String;
+
+    /// Answers the peer's `www-authenticate` header value in this scheme with
+    /// the `authorization` header value proving the local identity
+    fn respond(&self, peer_ip: IpAddr, challenge: &str, role: Role) -> anyhow::Result<String>;
+
+    /// Checks the peer's `authorization` header value in this scheme against
+    /// the nonce it was challenged with
+    fn verify(&self, peer_ip: IpAddr, authorization: &str, nonce: &Nonce) -> bool;
+}
 
 pub struct Context {
+    keys: Arc<Keys>,
+    // The default X25519 scheme first, so that the older peers, reading only
+    // the first challenge, find it
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    // Whether the default scheme is offered and accepted
+    default_scheme: bool,
+}
+
+struct Keys {
     secret: Box<dyn Fn() -> Option<SecretKey> + Send + Sync>,
     public: Box<dyn Fn(IpAddr) -> Option<PublicKey> + Send + Sync>,
+    key_cache: Mutex<KeyCache>,
+    rotation: Mutex<Rotation>,
+}
+
+#[derive(Default)]
+struct Rotation {
+    // Replaces the key returned by the callback once the key is rotated
+    secret: Option<SecretKey>,
+    // Bumped by each rotation
+    generation: u64,
+    // The chunk ciphers derived from the current key, kept by the open
+    // connections until they close
+    ciphers: Vec<Weak<ChunkCipher>>,
+}
+
+/// The default scheme, proving the knowledge of the X25519 keys
+struct X25519 {
+    keys: Arc<Keys>,
+}
+
+#[derive(Default)]
+struct KeyCache {
+    // `None` disables the cache
+    ttl: Option<Duration>,
+    keys: HashMap<IpAddr, (Instant, PublicKey)>,
 }
 
 impl Context {
@@ -13,35 +92,225 @@ impl Context {
         secret: impl Fn() -> Option<SecretKey> + Send + Sync + 'static,
         public: impl Fn(IpAddr) -> Option<PublicKey> + Send + Sync + 'static,
     ) -> Self {
-        Self {
+        let keys = Arc::new(Keys {
             secret: Box::new(secret),
             public: Box::new(public),
+            key_cache: Mutex::default(),
+            rotation: Mutex::default(),
+        });
+
+        Self {
+            authenticators: vec![Arc::new(X25519 { keys: keys.clone() })],
+            default_scheme: true,
+            keys,
         }
     }
 
-    pub fn authorize(
+    /// Adds the alternative scheme of authenticating the peers. The added
+    /// schemes are preferred over the default X25519 one, in the order they
+    /// are added. The TLS certificates and the chunk encryption keep using the
+    /// X25519 keys
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticators.push(authenticator);
+        self
+    }
+
+    /// Neither offers nor accepts the default X25519 scheme, so that the peers
+    /// must authenticate with one of the added schemes. The TLS certificates
+    /// and the chunk encryption keep using the X25519 keys
+    pub fn without_default_scheme(mut self) -> Self {
+        self.default_scheme = false;
+        self
+    }
+
+    /// Keeps the public keys of the peers for the `ttl` instead of calling the
+    /// callback each time. `None` disables the cache. The cached keys are
+    /// dropped
+    pub fn set_key_cache_ttl(&self, ttl: Option<Duration>) {
+        let mut cache = self.keys.key_cache.lock().expect("Poisoned lock");
+        cache.ttl = ttl;
+        cache.keys.clear();
+    }
+
+    /// Drops the cached public key of the peer so that the next connection
+    /// calls the callback again. Returns whether the key was cached
+    pub fn invalidate_peer_key(&self, peer_ip: IpAddr) -> bool {
+        let mut cache = self.keys.key_cache.lock().expect("Poisoned lock");
+        cache.keys.remove(&peer_ip).is_some()
+    }
+
+    /// Replaces the local secret key returned by the callback, see
+    /// `rotate_secret_key()`
+    pub fn set_secret_key(&self, secret: SecretKey) {
+        self.rotate_secret_key(secret);
+    }
+
+    /// Replaces the local secret key. The new connections authenticate and
+    /// encrypt the chunks with the new key, while the open ones keep the
+    /// ciphers derived from the previous key until they close. The transfers
+    /// reconnecting meanwhile switch to the new key. Returns the ciphers still
+    /// in use
+    pub(crate) fn rotate_secret_key(&self, secret: SecretKey) -> Vec<Weak<ChunkCipher>> {
+        let mut rotation = self.keys.rotation.lock().expect("Poisoned lock");
+        rotation.secret = Some(secret);
+        rotation.generation += 1;
+
+        let mut ciphers = std::mem::take(&mut rotation.ciphers);
+        ciphers.retain(|cipher| cipher.strong_count() > 0);
+        ciphers
+    }
+
+    pub(crate) fn secret_key(&self) -> Option<SecretKey> {
+        self.keys.secret()
+    }
+
+    /// The local public key, `None` if the secret key is not available
+    pub fn public_key(&self) -> Option<PublicKey> {
+        let secret = self.keys.secret()?;
+        Some(PublicKey::from(&secret))
+    }
+
+    /// Signs the transfer report with the local secret key
+    pub fn sign_report(&self, report: &[u8]) -> Option<String> {
+        let secret = self.keys.secret()?;
+        Some(drop_auth::create_report_signature(&secret, report))
+    }
+
+    /// The public key of the peer, from the cache if it is still fresh. The
+    /// failed lookups are not cached
+    pub(crate) fn peer_public_key(&self, peer_ip: IpAddr) -> Option<PublicKey> {
+        self.keys.peer_public_key(peer_ip)
+    }
+
+    /// Derives the chunk encryption cipher for the transfer with the peer. The
+    /// connection keeps it until it closes, even if the key is rotated
+    /// meanwhile
+    pub(crate) fn chunk_cipher(
+        &self,
+        peer_ip: IpAddr,
+        transfer_id: uuid::Uuid,
+    ) -> Option<Arc<ChunkCipher>> {
+        loop {
+            let generation = self.keys.rotation.lock().expect("Poisoned lock").generation;
+            let cipher = Arc::new(ChunkCipher::new(&self.chunk_key(peer_ip, transfer_id)?));
+
+            let mut rotation = self.keys.rotation.lock().expect("Poisoned lock");
+            // Derived from the key rotated meanwhile
+            if rotation.generation != generation {
+                continue;
+            }
+
+            rotation.ciphers.retain(|cipher| cipher.strong_count() > 0);
+            rotation.ciphers.push(Arc::downgrade(&cipher));
+            return Some(cipher);
+        }
+    }
+
+    fn chunk_key(&self, peer_ip: IpAddr, transfer_id: uuid::Uuid) -> Option<[u8; 32]> {
+        tokio::task::block_in_place(|| {
+            let pubkey = self.keys.peer_public_key(peer_ip)?;
+            let secret = self.keys.secret()?;
+            Some(drop_auth::create_chunk_key(
+                &secret,
+                &pubkey,
+                transfer_id.as_bytes(),
+            ))
+        })
+    }
+
+    /// The MAC of the capabilities header value sent to the peer in the
+    /// handshake bound by the `authorization` header value, see
+    /// [`drop_auth::create_capabilities_mac()`]
+    pub(crate) fn capabilities_mac(
+        &self,
+        peer_ip: IpAddr,
+        from_client: bool,
+        authorization: &str,
+        caps: &str,
+    ) -> Option<String> {
+        tokio::task::block_in_place(|| {
+            let pubkey = self.keys.peer_public_key(peer_ip)?;
+            let secret = self.keys.secret()?;
+            Some(drop_auth::create_capabilities_mac(
+                &secret,
+                &pubkey,
+                capabilities_label(from_client),
+                authorization.as_bytes(),
+                caps.as_bytes(),
+            ))
+        })
+    }
+
+    /// Checks the MAC of the capabilities header value sent by the peer
+    pub(crate) fn verify_capabilities_mac(
         &self,
         peer_ip: IpAddr,
-        auth_header_value: &str,
-        nonce: &drop_auth::Nonce,
+        from_client: bool,
+        authorization: &str,
+        caps: &str,
+        mac: &str,
     ) -> bool {
         tokio::task::block_in_place(|| {
-            let auth_req = drop_auth::http::Authorization::parse(auth_header_value)?;
-            let pubkey = (self.public)(peer_ip)?;
-            let secret = (self.secret)()?;
-            drop_auth::authorize(nonce, &secret, &pubkey, &auth_req)
+            let (Some(pubkey), Some(secret)) =
+                (self.keys.peer_public_key(peer_ip), self.keys.secret())
+            else {
+                return false;
+            };
+
+            drop_auth::verify_capabilities_mac(
+                &secret,
+                &pubkey,
+                capabilities_label(from_client),
+                authorization.as_bytes(),
+                caps.as_bytes(),
+                mac,
+            )
         })
-        .is_some()
+    }
+
+    /// The enabled schemes in the order they are offered
+    fn offered(&self) -> impl Iterator<Item = &Arc<dyn Authenticator>> {
+        self.authenticators
+            .iter()
+            .skip(usize::from(!self.default_scheme))
+    }
+
+    /// The enabled schemes in the order of preference
+    fn preferred(&self) -> impl Iterator<Item = &Arc<dyn Authenticator>> {
+        let default = self.authenticators.first().filter(|_| self.default_scheme);
+        self.authenticators.iter().skip(1).chain(default)
+    }
+
+    /// Picks the preferred scheme out of the challenges offered by the peer
+    fn pick<'a>(
+        &self,
+        challenges: impl Iterator<Item = &'a str> + Clone,
+    ) -> Option<(&dyn Authenticator, &'a str)> {
+        self.preferred().find_map(|authenticator| {
+            let challenge = challenges
+                .clone()
+                .find(|value| has_scheme(value, authenticator.scheme()))?;
+            Some((authenticator.as_ref(), challenge))
+        })
+    }
+
+    pub fn authorize(&self, peer_ip: IpAddr, auth_header_value: &str, nonce: &Nonce) -> bool {
+        let Some(authenticator) = self
+            .offered()
+            .find(|authenticator| has_scheme(auth_header_value, authenticator.scheme()))
+        else {
+            return false;
+        };
+
+        tokio::task::block_in_place(|| authenticator.verify(peer_ip, auth_header_value, nonce))
     }
 
     pub fn authorize_server<T>(
         &self,
         response: &hyper::Response<T>,
         ip: IpAddr,
-        nonce: &drop_auth::Nonce,
+        nonce: &Nonce,
     ) -> anyhow::Result<()> {
-        use anyhow::Context;
-
         let ticket = response
             .headers()
             .get(drop_auth::http::Authorization::KEY)
@@ -62,59 +331,306 @@ impl Context {
         peer_ip: IpAddr,
         check_nonce_prefix: bool,
     ) -> anyhow::Result<(&'static str, HeaderValue)> {
-        use anyhow::Context;
-
-        tokio::task::block_in_place(|| {
-            let www_auth_header_value = response
-                .headers()
-                .get(drop_auth::http::WWWAuthenticate::KEY)
-                .context("Missing 'www-authenticate' header")?
-                .to_str()?;
-
-            let resp = drop_auth::http::WWWAuthenticate::parse(www_auth_header_value)
-                .context("Failed to parse 'www-authenticate' header")?;
-
-            let public = (self.public)(peer_ip).context("Failed to fetch peer's public key")?;
-            let secret = (self.secret)().context("Failed to fetch private key")?;
+        let challenges = www_authenticate_values(response.headers());
+        anyhow::ensure!(!challenges.is_empty(), "Missing 'www-authenticate' header");
 
-            let ticket =
-                drop_auth::create_ticket_as_client(&secret, &public, resp, check_nonce_prefix)
-                    .context("Failed to create auth ticket")?;
-
-            let value = HeaderValue::from_str(&ticket.to_string())?;
-            anyhow::Ok((drop_auth::http::Authorization::KEY, value))
-        })
+        self.create_auth_header(peer_ip, &challenges, Role::Client { check_nonce_prefix })
     }
 
     pub fn create_servers_auth_header(
         &self,
         peer_ip: IpAddr,
-        www_auth_value: &str,
+        www_auth_values: &[String],
     ) -> anyhow::Result<(&'static str, HeaderValue)> {
-        use anyhow::Context;
+        self.create_auth_header(peer_ip, www_auth_values, Role::Server)
+    }
 
-        tokio::task::block_in_place(|| {
-            let resp = drop_auth::http::WWWAuthenticate::parse(www_auth_value)
-                .context("Failed to parse 'www-authenticate' header")?;
+    fn create_auth_header(
+        &self,
+        peer_ip: IpAddr,
+        challenges: &[String],
+        role: Role,
+    ) -> anyhow::Result<(&'static str, HeaderValue)> {
+        let (authenticator, challenge) = self
+            .pick(challenges.iter().map(String::as_str))
+            .context("No supported authentication scheme offered by the peer")?;
 
-            let public = (self.public)(peer_ip).context("Failed to fetch peer's public key")?;
-            let secret = (self.secret)().context("Failed to fetch private key")?;
+        let value =
+            tokio::task::block_in_place(|| authenticator.respond(peer_ip, challenge, role))?;
+        let value = HeaderValue::from_str(&value)?;
+        Ok((drop_auth::http::Authorization::KEY, value))
+    }
 
-            let ticket = drop_auth::create_ticket_as_server(&secret, &public, resp)
-                .context("Failed to create auth ticket")?;
+    /// The `www-authenticate` headers challenging the peer with the nonce in
+    /// each of the schemes
+    pub fn create_www_authentication_headers(
+        &self,
+        nonce: &Nonce,
+    ) -> Vec<(&'static str, HeaderValue)> {
+        self.offered()
+            .filter_map(|authenticator| {
+                let value = HeaderValue::from_str(&authenticator.challenge(nonce)).ok()?;
+                Some((drop_auth::http::WWWAuthenticate::KEY, value))
+            })
+            .collect()
+    }
+}
 
-            let value = HeaderValue::from_str(&ticket.to_string())?;
-            anyhow::Ok((drop_auth::http::Authorization::KEY, value))
-        })
+impl Keys {
+    fn secret(&self) -> Option<SecretKey> {
+        let rotated = self.rotation.lock().expect("Poisoned lock").secret.clone();
+        rotated.or_else(|| (self.secret)())
+    }
+
+    fn peer_public_key(&self, peer_ip: IpAddr) -> Option<PublicKey> {
+        let ttl = {
+            let cache = self.key_cache.lock().expect("Poisoned lock");
+            if let Some(&(fetched, key)) = cache.keys.get(&peer_ip) {
+                if cache.ttl.is_some_and(|ttl| fetched.elapsed() < ttl) {
+                    return Some(key);
+                }
+            }
+            cache.ttl
+        };
+
+        // The callback is called without the lock as it might be slow
+        let key = (self.public)(peer_ip)?;
+
+        if ttl.is_some() {
+            let mut cache = self.key_cache.lock().expect("Poisoned lock");
+            cache.keys.insert(peer_ip, (Instant::now(), key));
+        }
+
+        Some(key)
     }
 }
 
-pub fn create_www_authentication_header(nonce: &drop_auth::Nonce) -> (&'static str, HeaderValue) {
-    let value = drop_auth::http::WWWAuthenticate::new(*nonce);
+impl Authenticator for X25519 {
+    fn scheme(&self) -> &str {
+        "drop"
+    }
+
+    fn challenge(&self, nonce: &Nonce) -> String {
+        drop_auth::http::WWWAuthenticate::new(*nonce).to_string()
+    }
+
+    fn respond(&self, peer_ip: IpAddr, challenge: &str, role: Role) -> anyhow::Result<String> {
+        let resp = drop_auth::http::WWWAuthenticate::parse(challenge)
+            .context("Failed to parse 'www-authenticate' header")?;
+
+        let public = self
+            .keys
+            .peer_public_key(peer_ip)
+            .context("Failed to fetch peer's public key")?;
+        let secret = self.keys.secret().context("Failed to fetch private key")?;
+
+        let ticket = match role {
+            Role::Client { check_nonce_prefix } => {
+                drop_auth::create_ticket_as_client(&secret, &public, resp, check_nonce_prefix)
+            }
+            Role::Server => drop_auth::create_ticket_as_server(&secret, &public, resp),
+        }
+        .context("Failed to create auth ticket")?;
+
+        Ok(ticket.to_string())
+    }
+
+    fn verify(&self, peer_ip: IpAddr, authorization: &str, nonce: &Nonce) -> bool {
+        let verify = || {
+            let auth_req = drop_auth::http::Authorization::parse(authorization)?;
+            let pubkey = self.keys.peer_public_key(peer_ip)?;
+            let secret = self.keys.secret()?;
+            drop_auth::authorize(nonce, &secret, &pubkey, &auth_req)
+        };
+        verify().is_some()
+    }
+}
+
+// Tells the MACs of the two sides apart, so that they cannot be reflected
+fn capabilities_label(from_client: bool) -> &'static [u8] {
+    if from_client {
+        b"client"
+    } else {
+        b"server"
+    }
+}
+
+/// All the `www-authenticate` header values, one per scheme
+pub(crate) fn www_authenticate_values(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(drop_auth::http::WWWAuthenticate::KEY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .collect()
+}
+
+// The scheme names are case insensitive
+fn has_scheme(header_value: &str, scheme: &str) -> bool {
+    header_value
+        .split_ascii_whitespace()
+        .next()
+        .is_some_and(|token| token.eq_ignore_ascii_case(scheme))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    struct Token;
+
+    impl Authenticator for Token {
+        fn scheme(&self) -> &str {
+            "token"
+        }
+
+        fn challenge(&self, nonce: &Nonce) -> String {
+            format!("token nonce=\"{}\"", nonce.0[0])
+        }
+
+        fn respond(&self, _: IpAddr, challenge: &str, _: Role) -> anyhow::Result<String> {
+            let nonce = challenge.trim_start_matches("token nonce=");
+            Ok(format!("token ticket={nonce}"))
+        }
+
+        fn verify(&self, _: IpAddr, authorization: &str, nonce: &Nonce) -> bool {
+            authorization == format!("token ticket=\"{}\"", nonce.0[0])
+        }
+    }
+
+    fn challenges(ctx: &Context, nonce: &Nonce) -> Vec<String> {
+        ctx.create_www_authentication_headers(nonce)
+            .into_iter()
+            .map(|(_, value)| value.to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn scheme_negotiation() {
+        let context = |secret: [u8; 32], peer: [u8; 32]| {
+            Context::new(
+                move || Some(SecretKey::from(secret)),
+                move |_| Some(PublicKey::from(&SecretKey::from(peer))),
+            )
+        };
+        let custom = context([1; 32], [2; 32]).with_authenticator(Arc::new(Token));
+        let peer: IpAddr = [10, 0, 0, 1].into();
+        let nonce = Nonce::generate_as_client();
+
+        // The default scheme is offered first
+        let offered = challenges(&custom, &nonce);
+        assert_eq!(offered.len(), 2);
+        assert!(offered[0].starts_with("drop "));
+
+        // The added scheme is preferred when both sides support it
+        let other = context([2; 32], [1; 32]).with_authenticator(Arc::new(Token));
+        let (_, value) = other.create_servers_auth_header(peer, &offered).unwrap();
+        let value = value.to_str().unwrap();
+        assert!(value.starts_with("token "));
+        assert!(custom.authorize(peer, value, &nonce));
 
-    (
-        drop_auth::http::WWWAuthenticate::KEY,
-        HeaderValue::from_str(&value.to_string())
-            .expect("The www-authenticate header value should be always valid"),
-    )
+        // Otherwise the default one is used
+        let default = context([2; 32], [1; 32]);
+        let (_, value) = default.create_servers_auth_header(peer, &offered).unwrap();
+        let value = value.to_str().unwrap();
+        assert!(value.starts_with("drop "));
+        assert!(custom.authorize(peer, value, &nonce));
+
+        // The schemes not supported locally are rejected
+        assert!(!default.authorize(peer, "token ticket=\"0\"", &nonce));
+
+        // Only the added scheme is offered and accepted once it's mandatory
+        let strict = context([1; 32], [2; 32])
+            .with_authenticator(Arc::new(Token))
+            .without_default_scheme();
+        let offered = challenges(&strict, &nonce);
+        assert_eq!(offered.len(), 1);
+        assert!(offered[0].starts_with("token "));
+        assert!(default.create_servers_auth_header(peer, &offered).is_err());
+
+        let (_, value) = default
+            .create_servers_auth_header(peer, &challenges(&custom, &nonce))
+            .unwrap();
+        assert!(!strict.authorize(peer, value.to_str().unwrap(), &nonce));
+
+        let (_, value) = other.create_servers_auth_header(peer, &offered).unwrap();
+        assert!(strict.authorize(peer, value.to_str().unwrap(), &nonce));
+    }
+
+    #[test]
+    fn key_rotation() {
+        let ctx = Context::new(
+            || Some(SecretKey::from([1; 32])),
+            |_| Some(PublicKey::from([2; 32])),
+        );
+        let peer: IpAddr = [10, 0, 0, 1].into();
+        let (open, closed) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+        let before = ctx.chunk_key(peer, open).unwrap();
+        let public = ctx.public_key().unwrap();
+        let cipher = ctx.chunk_cipher(peer, open).unwrap();
+        drop(ctx.chunk_cipher(peer, closed).unwrap());
+
+        // Only the cipher of the open connection is still in use
+        let in_use = ctx.rotate_secret_key(SecretKey::from([3; 32]));
+        assert_ne!(ctx.public_key().unwrap(), public);
+        assert_eq!(in_use.len(), 1);
+        assert!(Arc::ptr_eq(&in_use[0].upgrade().unwrap(), &cipher));
+
+        // The transfer reconnecting switches to the new key
+        let rotated = ctx.chunk_key(peer, open).unwrap();
+        assert_ne!(rotated, before);
+        assert_eq!(
+            rotated,
+            drop_auth::create_chunk_key(
+                &SecretKey::from([3; 32]),
+                &PublicKey::from([2; 32]),
+                open.as_bytes()
+            )
+        );
+
+        drop(cipher);
+        assert!(in_use.iter().all(|cipher| cipher.strong_count() == 0));
+    }
+
+    #[test]
+    fn peer_key_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let ctx = {
+            let calls = calls.clone();
+            Context::new(
+                || None,
+                move |_| {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Some(PublicKey::from([1; 32]))
+                },
+            )
+        };
+        let peer: IpAddr = [10, 0, 0, 1].into();
+
+        // Disabled by default
+        ctx.peer_public_key(peer).unwrap();
+        ctx.peer_public_key(peer).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        ctx.set_key_cache_ttl(Some(Duration::from_secs(60)));
+        ctx.peer_public_key(peer).unwrap();
+        ctx.peer_public_key(peer).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        assert!(ctx.invalidate_peer_key(peer));
+        assert!(!ctx.invalidate_peer_key(peer));
+        ctx.peer_public_key(peer).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        ctx.set_key_cache_ttl(Some(Duration::ZERO));
+        ctx.peer_public_key(peer).unwrap();
+        ctx.peer_public_key(peer).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 6);
+    }
 }
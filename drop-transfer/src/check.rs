@@ -1,17 +1,15 @@
-use std::{
-    net::{IpAddr, SocketAddr},
-    ops::ControlFlow,
-    sync::Arc,
-};
+use std::{net::SocketAddr, ops::ControlFlow, sync::Arc};
 
-use hyper::StatusCode;
+use hyper::{body::Bytes, Method, StatusCode};
 use slog::{debug, info, Logger};
 use tokio_util::sync::CancellationToken;
 
-use crate::{auth, protocol, service::State, tasks::AliveGuard, utils, IncomingTransfer, Transfer};
+use crate::{
+    forward, protocol, service::State, tasks::AliveGuard, tls, utils, IncomingTransfer, Transfer,
+};
 
 #[derive(thiserror::Error, Debug)]
-enum RequestError {
+pub(crate) enum RequestError {
     #[error("{0}")]
     General(#[from] anyhow::Error),
     #[error("Unexpected HTTP response: {0}")]
@@ -27,11 +25,16 @@ pub(crate) fn spawn(
     stop: CancellationToken,
 ) {
     let id = xfer.id();
+    let guard = guard.task("check", format!("Incoming transfer {id}"));
 
     tokio::spawn(async move {
         let _guard = guard;
-        let mut backoff =
-            utils::RetryTrigger::new(refresh_trigger, state.config.connection_retries);
+        let mut backoff = utils::RetryTrigger::new(
+            refresh_trigger,
+            state.low_power.subscribe(),
+            state.config.connection_retries,
+            &state.config.retry_policy,
+        );
 
         let task = async {
             while run(&state, &xfer, &logger).await.is_continue() {
@@ -63,6 +66,7 @@ async fn run(state: &State, xfer: &Arc<IncomingTransfer>, logger: &Logger) -> Co
         if let Some(state) = state.transfer_manager.incoming_remove(xfer.id()).await {
             state.xfer_events.cancel(true).await
         }
+        forward::ended(state, logger, xfer);
 
         return ControlFlow::Break(());
     }
@@ -71,20 +75,14 @@ async fn run(state: &State, xfer: &Arc<IncomingTransfer>, logger: &Logger) -> Co
 }
 
 async fn ask_server_if_alive(state: &State, xfer: &IncomingTransfer, logger: &Logger) -> bool {
-    let mut connector = hyper::client::HttpConnector::new();
-    connector.set_local_address(Some(state.addr));
-
-    let client = hyper::Client::builder().build::<_, hyper::Body>(connector);
-
     let versions_to_try = [protocol::Version::V6];
 
     for version in versions_to_try {
         match make_request(
-            &client,
-            xfer.peer(),
+            state,
+            SocketAddr::new(xfer.peer(), xfer.peer_port().unwrap_or(state.config.port)),
             version,
             xfer.id(),
-            &state.auth,
             logger,
         )
         .await
@@ -113,34 +111,70 @@ async fn ask_server_if_alive(state: &State, xfer: &IncomingTransfer, logger: &Lo
 
 // Returns whether the transfer is alive
 async fn make_request(
-    client: &hyper::Client<hyper::client::HttpConnector>,
-    ip: IpAddr,
+    state: &State,
+    addr: SocketAddr,
     version: protocol::Version,
     xfer_id: uuid::Uuid,
-    auth: &auth::Context,
     logger: &Logger,
 ) -> Result<bool, RequestError> {
+    let path = format!("/drop/{version}/check/{xfer_id}");
+
+    match request(state, addr, Method::GET, &path, Bytes::new(), logger).await? {
+        StatusCode::OK => Ok(true),
+        StatusCode::GONE => Ok(false),
+        status => Err(RequestError::UnexpectedResponse(status)),
+    }
+}
+
+/// Makes the HTTP request to the peer's server, authenticating both sides the
+/// same way the WebSocket connection does. Returns the response status
+pub(crate) async fn request(
+    state: &State,
+    addr: SocketAddr,
+    method: Method,
+    path: &str,
+    body: Bytes,
+    logger: &Logger,
+) -> Result<StatusCode, RequestError> {
     use anyhow::Context;
 
-    let addr = SocketAddr::new(ip, drop_config::PORT);
-    let url: hyper::Uri = format!("http://{addr}/drop/{version}/check/{xfer_id}")
-        .parse()
-        .expect("URL should be valid");
+    let auth = &state.auth;
+    let ip = addr.ip();
+
+    debug!(logger, "Making HTTP request: {method} {addr}{path}");
+
+    let local = SocketAddr::new(state.local_addr_for(ip), 0);
+    let stream = utils::connect(local, addr)
+        .await
+        .context("Failed to connect")?;
+    let stream = tls::connect(state.config.tls, auth, stream, ip)
+        .await
+        .context("Failed to establish TLS")?;
 
-    debug!(logger, "Making HTTP request: {url}");
+    let (mut client, conn) = hyper::client::conn::handshake(stream)
+        .await
+        .context("Failed to perform HTTP handshake")?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
 
-    let req = hyper::Request::get(url.clone());
+    let host = addr.to_string();
+    let mut req = hyper::Request::builder()
+        .method(method.clone())
+        .uri(path)
+        .header(hyper::header::HOST, host.as_str());
 
     let nonce = drop_auth::Nonce::generate_as_client();
-    let (key, value) = auth::create_www_authentication_header(&nonce);
+    for (key, value) in auth.create_www_authentication_headers(&nonce) {
+        req = req.header(key, value);
+    }
 
     let req = req
-        .header(key, value)
-        .body(hyper::Body::empty())
+        .body(hyper::Body::from(body.clone()))
         .expect("Creating request should not fail");
 
     let response = client
-        .request(req)
+        .send_request(req)
         .await
         .context("Failed to perform HTTP request")?;
 
@@ -151,14 +185,6 @@ async fn make_request(
     };
 
     match response.status() {
-        StatusCode::OK => {
-            authorize()?;
-            Ok(true)
-        }
-        StatusCode::GONE => {
-            authorize()?;
-            Ok(false)
-        }
         StatusCode::UNAUTHORIZED => {
             authorize()?;
 
@@ -166,22 +192,25 @@ async fn make_request(
             let (key, value) = auth.create_clients_auth_header(&response, ip, false)?;
 
             debug!(logger, "Building 'authorization' request");
-            let req = hyper::Request::get(url)
+            let req = hyper::Request::builder()
+                .method(method)
+                .uri(path)
+                .header(hyper::header::HOST, host)
                 .header(key, value)
-                .body(hyper::Body::empty())
+                .body(hyper::Body::from(body))
                 .expect("Creating request should not fail");
 
             let response = client
-                .request(req)
+                .send_request(req)
                 .await
                 .context("Failed to perform the second HTTP request")?;
 
-            match response.status() {
-                StatusCode::OK => Ok(true),
-                StatusCode::GONE => Ok(false),
-                status => Err(RequestError::UnexpectedResponse(status)),
-            }
+            Ok(response.status())
         }
-        status => Err(RequestError::UnexpectedResponse(status)),
+        status if status.is_success() || status == StatusCode::GONE => {
+            authorize()?;
+            Ok(status)
+        }
+        status => Ok(status),
     }
 }
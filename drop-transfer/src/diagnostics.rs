@@ -0,0 +1,173 @@
+//! Timing and the outcome of each phase of the outgoing connection handshake.
+//! The last handshake with each peer is kept, the failed ones are reported
+//! with the event too, so that the authentication failures can be told apart
+//! from the network ones.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+// The peers whose last handshake is kept, the oldest handshake is dropped
+// for the next peer
+const MAX_PEERS: usize = 256;
+
+/// The phase of the connection handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum HandshakePhase {
+    /// Connecting the socket, directly or through the relay, or opening the
+    /// stream over the connection shared with the peer
+    Connect,
+    /// The TLS handshake, only when TLS is enabled
+    Tls,
+    /// Requesting the protocol versions until the peer accepts one
+    Version,
+    /// Authenticating the peer and authorizing with it
+    Auth,
+    /// Switching the accepted connection over to the WebSocket
+    Upgrade,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseTiming {
+    pub phase: HandshakePhase,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakeDiagnostics {
+    pub peer: IpAddr,
+    pub started_at: SystemTime,
+    /// The phases in the order they were entered, the failed one last. The
+    /// phases can repeat when the connection is shared with the peer, as it
+    /// is established first
+    pub phases: Vec<PhaseTiming>,
+    /// The failed phase and the reason, `None` when the handshake succeeded
+    pub failure: Option<(HandshakePhase, String)>,
+}
+
+/// Measures the phases of one handshake
+pub(crate) struct Recorder {
+    peer: IpAddr,
+    started_at: SystemTime,
+    phase: HandshakePhase,
+    phase_started: Instant,
+    phases: Vec<PhaseTiming>,
+}
+
+impl Recorder {
+    pub(crate) fn new(peer: IpAddr) -> Self {
+        Self {
+            peer,
+            started_at: SystemTime::now(),
+            phase: HandshakePhase::Connect,
+            phase_started: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Finishes the current phase and enters the next one
+    pub(crate) fn enter(&mut self, phase: HandshakePhase) {
+        self.close_phase();
+        self.phase = phase;
+        self.phase_started = Instant::now();
+    }
+
+    /// Finishes the handshake, `error` is set when the current phase failed
+    pub(crate) fn finish(mut self, error: Option<String>) -> HandshakeDiagnostics {
+        self.close_phase();
+
+        HandshakeDiagnostics {
+            peer: self.peer,
+            started_at: self.started_at,
+            failure: error.map(|error| (self.phase, error)),
+            phases: self.phases,
+        }
+    }
+
+    fn close_phase(&mut self) {
+        self.phases.push(PhaseTiming {
+            phase: self.phase,
+            duration: self.phase_started.elapsed(),
+        });
+    }
+}
+
+/// The last handshake with each of up to `MAX_PEERS` peers
+#[derive(Default)]
+pub(crate) struct Log {
+    handshakes: Mutex<HashMap<IpAddr, HandshakeDiagnostics>>,
+}
+
+impl Log {
+    pub(crate) fn record(&self, diagnostics: HandshakeDiagnostics) {
+        let mut handshakes = self.handshakes.lock().expect("Poisoned lock");
+
+        if handshakes.len() >= MAX_PEERS && !handshakes.contains_key(&diagnostics.peer) {
+            let oldest = handshakes
+                .values()
+                .min_by_key(|handshake| handshake.started_at)
+                .map(|handshake| handshake.peer);
+            if let Some(oldest) = oldest {
+                handshakes.remove(&oldest);
+            }
+        }
+
+        handshakes.insert(diagnostics.peer, diagnostics);
+    }
+
+    pub(crate) fn get(&self, peer: IpAddr) -> Option<HandshakeDiagnostics> {
+        self.handshakes
+            .lock()
+            .expect("Poisoned lock")
+            .get(&peer)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_failed_phase() {
+        let mut recorder = Recorder::new("10.0.0.2".parse().unwrap());
+        recorder.enter(HandshakePhase::Version);
+        recorder.enter(HandshakePhase::Auth);
+
+        let diagnostics = recorder.finish(Some("Authentication failed".to_string()));
+        let phases: Vec<_> = diagnostics.phases.iter().map(|t| t.phase).collect();
+        assert_eq!(
+            phases,
+            [
+                HandshakePhase::Connect,
+                HandshakePhase::Version,
+                HandshakePhase::Auth
+            ]
+        );
+        assert_eq!(
+            diagnostics.failure,
+            Some((HandshakePhase::Auth, "Authentication failed".to_string()))
+        );
+
+        let log = Log::default();
+        log.record(diagnostics);
+        assert!(log.get("10.0.0.2".parse().unwrap()).is_some());
+        assert!(log.get("10.0.0.3".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn log_drops_oldest_peer() {
+        let log = Log::default();
+        for i in 0..=MAX_PEERS {
+            let peer = IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]);
+            log.record(Recorder::new(peer).finish(None));
+        }
+
+        assert!(log.get("10.0.0.0".parse().unwrap()).is_none());
+        assert!(log.get("10.0.0.1".parse().unwrap()).is_some());
+        assert!(log.get("10.0.1.0".parse().unwrap()).is_some());
+    }
+}
@@ -55,6 +55,24 @@ pub enum Error {
     ConnectionClosedByPeer,
     #[error("Peer responded with too many requests status")]
     TooManyRequests,
+    #[error("Connection denied by the connection policy")]
+    ConnectionDenied,
+    #[error("Failed to decrypt the file chunk")]
+    ChunkDecryption,
+    #[error("File chunks corrupted in transit")]
+    ChunkCorrupted,
+    #[error("The public key of the peer is not available")]
+    PeerKeyUnavailable,
+    #[error("Peer exceeded its quota")]
+    QuotaExceeded,
+    #[error("Peer is busy, retry later")]
+    PeerBusy,
+    #[error("The file was not started before the transfer deadline")]
+    Expired,
+    #[error("The peer does not receive transfers")]
+    ReceiveDisabled,
+    #[error("Operation refused by the read-only or receive-only mode")]
+    OperationRefused,
 }
 
 impl Error {
@@ -72,6 +90,26 @@ impl Error {
             _ => None,
         }
     }
+
+    pub fn category(&self) -> drop_core::ErrorCategory {
+        match self {
+            // Both are reported as the IO errors but come from the connection
+            Error::WsServer(_) | Error::WsClient(_) => drop_core::ErrorCategory::Network,
+            Error::ChunkDecryption | Error::ChunkCorrupted => drop_core::ErrorCategory::Integrity,
+            _ => drop_core::Status::from(self).category(),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Io(io) => !matches!(
+                io.kind(),
+                ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::InvalidInput
+            ),
+            Error::ChunkDecryption => false,
+            _ => drop_core::Status::from(self).is_retryable(),
+        }
+    }
 }
 
 impl From<&Error> for drop_core::Status {
@@ -108,6 +146,15 @@ impl From<&Error> for drop_core::Status {
             Error::EmptyTransfer => Status::EmptyTransfer,
             Error::ConnectionClosedByPeer => Status::ConnectionClosedByPeer,
             Error::TooManyRequests => Status::TooManyRequests,
+            Error::ConnectionDenied => Status::ConnectionDenied,
+            Error::ChunkDecryption => Status::BadTransferState,
+            Error::ChunkCorrupted => Status::FileChecksumMismatch,
+            Error::PeerKeyUnavailable => Status::PeerKeyUnavailable,
+            Error::QuotaExceeded => Status::QuotaExceeded,
+            Error::PeerBusy => Status::PeerBusy,
+            Error::Expired => Status::Expired,
+            Error::ReceiveDisabled => Status::ReceiveDisabled,
+            Error::OperationRefused => Status::BadTransferState,
         }
     }
 }
@@ -1,4 +1,9 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use uuid::Uuid;
 
@@ -6,13 +11,46 @@ use crate::{
     file::FileId,
     transfer::{IncomingTransfer, OutgoingTransfer},
     utils::Hidden,
-    Error,
+    Error, HandshakeDiagnostics,
 };
 
 #[derive(Debug)]
 pub struct DownloadSuccess {
     pub id: FileId,
     pub final_path: Hidden<Box<Path>>,
+    // Some of the path components sent by the peer could not be used on the
+    // local filesystem and were replaced
+    pub path_sanitized: bool,
+}
+
+/// The storage states left unfinished by the previous run, e.g. due to a crash,
+/// and reconciled on startup
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StartupReconciliation {
+    // Files left in progress, marked as paused
+    pub paused_files: usize,
+    // Transfers which could not be restored, marked as failed
+    pub failed_transfers: usize,
+    // Unfinished files of the failed transfers
+    pub failed_files: usize,
+}
+
+impl StartupReconciliation {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The operation refused in the read-only, the receive-only or the send-only
+/// mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum RefusedOperation {
+    /// Creating the outgoing transfer
+    NewTransfer,
+    /// Downloading the file of the incoming transfer
+    Download,
+    /// Uploading the file requested by the receiver
+    Upload,
 }
 
 #[derive(Debug)]
@@ -32,11 +70,13 @@ pub enum Event {
     FileUploadProgress(Arc<OutgoingTransfer>, FileId, u64),
     FileDownloadProgress(Arc<IncomingTransfer>, FileId, u64),
 
-    FileUploadSuccess(Arc<OutgoingTransfer>, FileId),
+    // The final path is reported by the receiver, if enabled on its side
+    FileUploadSuccess(Arc<OutgoingTransfer>, FileId, Option<String>),
     FileDownloadSuccess(Arc<IncomingTransfer>, DownloadSuccess),
 
     FileUploadFailed(Arc<OutgoingTransfer>, FileId, Error),
-    FileDownloadFailed(Arc<IncomingTransfer>, FileId, Error),
+    // The partially downloaded file left on the disk, if any
+    FileDownloadFailed(Arc<IncomingTransfer>, FileId, Error, Option<PathBuf>),
 
     FileUploadPaused {
         transfer_id: Uuid,
@@ -58,6 +98,16 @@ pub enum Event {
         by_peer: bool,
     },
 
+    // The sender withdrew the file before the receiver started it
+    FileUploadWithdrawn {
+        transfer_id: Uuid,
+        file_id: FileId,
+    },
+    FileDownloadWithdrawn {
+        transfer_id: Uuid,
+        file_id: FileId,
+    },
+
     FileUploadThrottled {
         transfer_id: Uuid,
         file_id: FileId,
@@ -67,12 +117,48 @@ pub enum Event {
     IncomingTransferCanceled(Arc<IncomingTransfer>, bool),
     OutgoingTransferCanceled(Arc<OutgoingTransfer>, bool),
 
+    // The sender added the files to the transfer before the receiver acted on
+    // it. Carries the transfer with all the files and the IDs of the added ones
+    IncomingFilesAdded(Arc<IncomingTransfer>, Vec<FileId>),
+    OutgoingFilesAdded(Arc<OutgoingTransfer>, Vec<FileId>),
+
+    // The sender withdrew the transfer before the receiver acted on it
+    IncomingTransferWithdrawn(Arc<IncomingTransfer>, bool),
+    OutgoingTransferWithdrawn(Arc<OutgoingTransfer>, bool),
+
+    // The receiver paused or resumed all the files of the transfer
+    IncomingTransferPaused {
+        transfer_id: Uuid,
+        paused: bool,
+    },
+    OutgoingTransferPaused {
+        transfer_id: Uuid,
+        paused: bool,
+    },
+
     OutgoingTransferFailed(Arc<OutgoingTransfer>, Error, bool),
 
     OutgoingTransferDeferred {
         transfer: Arc<OutgoingTransfer>,
         error: Error,
     },
+    OutgoingTransferPostponed(Arc<OutgoingTransfer>),
+    OutgoingPeerKeyUnavailable(Arc<OutgoingTransfer>),
+    // The `next_retry_in` is `None` when the retry happens only on the network
+    // refresh
+    OutgoingConnectionAttemptFailed {
+        transfer: Arc<OutgoingTransfer>,
+        attempt: u32,
+        next_retry_in: Option<Duration>,
+    },
+    OutgoingConnectionDiagnostics {
+        transfer: Arc<OutgoingTransfer>,
+        diagnostics: HandshakeDiagnostics,
+    },
+    OutgoingTransferConnected {
+        transfer: Arc<OutgoingTransfer>,
+        relayed: bool,
+    },
 
     FinalizeChecksumStarted {
         transfer_id: Uuid,
@@ -103,4 +189,115 @@ pub enum Event {
         file_id: FileId,
         progress: u64,
     },
+
+    StartupReconciled(StartupReconciliation),
+    // No transfer uses the secret key replaced with `set_private_key()`
+    KeyRotationCompleted,
+
+    OperationRefused {
+        transfer_id: Uuid,
+        file_id: Option<FileId>,
+        operation: RefusedOperation,
+    },
+    IncomingDirectoryPrepared {
+        transfer_id: Uuid,
+        root: String,
+        path: PathBuf,
+    },
+    IncomingDownloadsQueued {
+        transfer_id: Uuid,
+        file_ids: Vec<FileId>,
+    },
+    IncomingFileDuplicate {
+        transfer_id: Uuid,
+        file_id: FileId,
+        existing_path: PathBuf,
+    },
+    OutgoingPreflightFinished {
+        transfer_id: Uuid,
+        unreadable: Vec<FileId>,
+    },
+    // The transfer request rejected without asking the app, it is not stored
+    IncomingTransferRejected {
+        transfer_id: Uuid,
+        peer: IpAddr,
+        error: Error,
+    },
+
+    // The short text message, not related to any transfer
+    IncomingText {
+        id: Uuid,
+        peer: IpAddr,
+        text: String,
+    },
+    OutgoingTextSent {
+        id: Uuid,
+    },
+    OutgoingTextFailed {
+        id: Uuid,
+        error: Error,
+    },
+}
+
+impl Event {
+    /// The transfer the event relates to, `None` for the events not related
+    /// to any transfer
+    pub fn transfer_id(&self) -> Option<Uuid> {
+        use crate::Transfer as _;
+
+        let id = match self {
+            Event::RequestReceived(xfer)
+            | Event::FileDownloadStarted(xfer, ..)
+            | Event::FileDownloadProgress(xfer, ..)
+            | Event::FileDownloadSuccess(xfer, _)
+            | Event::FileDownloadFailed(xfer, ..)
+            | Event::IncomingTransferCanceled(xfer, _)
+            | Event::IncomingTransferWithdrawn(xfer, _)
+            | Event::IncomingFilesAdded(xfer, _) => xfer.id(),
+            Event::RequestQueued(xfer)
+            | Event::FileUploadStarted(xfer, ..)
+            | Event::FileUploadProgress(xfer, ..)
+            | Event::FileUploadSuccess(xfer, ..)
+            | Event::FileUploadFailed(xfer, ..)
+            | Event::OutgoingTransferCanceled(xfer, _)
+            | Event::OutgoingTransferWithdrawn(xfer, _)
+            | Event::OutgoingFilesAdded(xfer, _)
+            | Event::OutgoingTransferFailed(xfer, ..)
+            | Event::OutgoingTransferPostponed(xfer)
+            | Event::OutgoingPeerKeyUnavailable(xfer)
+            | Event::OutgoingTransferDeferred { transfer: xfer, .. }
+            | Event::OutgoingConnectionAttemptFailed { transfer: xfer, .. }
+            | Event::OutgoingConnectionDiagnostics { transfer: xfer, .. }
+            | Event::OutgoingTransferConnected { transfer: xfer, .. } => xfer.id(),
+            Event::FileDownloadPending { transfer_id, .. }
+            | Event::FileUploadPaused { transfer_id, .. }
+            | Event::FileDownloadPaused { transfer_id, .. }
+            | Event::FileUploadRejected { transfer_id, .. }
+            | Event::FileDownloadRejected { transfer_id, .. }
+            | Event::FileUploadWithdrawn { transfer_id, .. }
+            | Event::FileDownloadWithdrawn { transfer_id, .. }
+            | Event::FileUploadThrottled { transfer_id, .. }
+            | Event::IncomingTransferPaused { transfer_id, .. }
+            | Event::OutgoingTransferPaused { transfer_id, .. }
+            | Event::FinalizeChecksumStarted { transfer_id, .. }
+            | Event::FinalizeChecksumFinished { transfer_id, .. }
+            | Event::FinalizeChecksumProgress { transfer_id, .. }
+            | Event::VerifyChecksumStarted { transfer_id, .. }
+            | Event::VerifyChecksumFinished { transfer_id, .. }
+            | Event::VerifyChecksumProgress { transfer_id, .. }
+            | Event::OperationRefused { transfer_id, .. }
+            | Event::IncomingDirectoryPrepared { transfer_id, .. }
+            | Event::IncomingDownloadsQueued { transfer_id, .. }
+            | Event::IncomingFileDuplicate { transfer_id, .. }
+            | Event::OutgoingPreflightFinished { transfer_id, .. }
+            | Event::IncomingTransferRejected { transfer_id, .. } => *transfer_id,
+            Event::StartupReconciled(_)
+            | Event::KeyRotationCompleted
+            | Event::IncomingText { .. }
+            | Event::OutgoingTextSent { .. }
+            | Event::OutgoingTextFailed { .. } => return None,
+        };
+
+        Some(id)
+    }
 }
@@ -0,0 +1,65 @@
+//! The deadline of the outgoing transfers. Once it passes, the files the
+//! receiver did not start expire, they are failed on both peers with the
+//! `Expired` status. The files already started go on as usual
+
+use std::{sync::Arc, time::SystemTime};
+
+use slog::{debug, info, warn, Logger};
+use tokio_util::sync::CancellationToken;
+
+use crate::{service::State, tasks::AliveGuard, transfer::Transfer, ws, OutgoingTransfer};
+
+pub(crate) fn spawn(
+    state: Arc<State>,
+    xfer: Arc<OutgoingTransfer>,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) {
+    let Some(expires_at) = xfer.expires_at() else {
+        return;
+    };
+
+    let id = xfer.id();
+    let guard = guard.task("expiry", format!("Deadline of outgoing transfer {id}"));
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let Some(job_stop) = state.transfer_manager.outgoing_job_stop(id).await else {
+            return;
+        };
+
+        let left = expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+
+        tokio::select! {
+            biased;
+
+            _ = stop.cancelled() => {
+                debug!(logger, "stop expiry job for: {id}");
+                return;
+            },
+            _ = job_stop.cancelled() => return,
+            _ = tokio::time::sleep(left) => (),
+        }
+
+        match state.transfer_manager.outgoing_expire(id).await {
+            Ok(res) => {
+                if !res.file_events.is_empty() {
+                    info!(
+                        logger,
+                        "{} files of transfer {id} expired",
+                        res.file_events.len()
+                    );
+                }
+
+                for events in res.file_events {
+                    events.failed(crate::Error::Expired).await;
+                }
+                ws::client::handle_finish_xfer_state(res.xfer_state, false).await;
+            }
+            Err(err) => warn!(logger, "Failed to expire transfer {id}: {err}"),
+        }
+    });
+}
@@ -0,0 +1,334 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::FileSubPath;
+
+/// Directory tree entries other than the regular files. They are not
+/// transferred on their own, the receiver recreates them next to the files
+/// of the same root directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntries {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub empty_dirs: Vec<FileSubPath>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub symlinks: Vec<Symlink>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Symlink {
+    pub path: FileSubPath,
+    // Relative target with the `/` separators
+    pub target: String,
+}
+
+impl DirEntries {
+    pub fn is_empty(&self) -> bool {
+        self.empty_dirs.is_empty() && self.symlinks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.empty_dirs.len() + self.symlinks.len()
+    }
+
+    /// Drops the entries not belonging to any root directory or the symlinks
+    /// pointing outside of it
+    pub(crate) fn sanitize(&mut self) {
+        self.empty_dirs.retain(|dir| dir.len() > 1);
+        self.symlinks
+            .retain(|link| link.path.len() > 1 && symlink_stays_inside(&link.path, &link.target));
+    }
+
+    /// Entries placed under the given root directory
+    pub(crate) fn with_root(&self, root: &str) -> DirEntries {
+        DirEntries {
+            empty_dirs: self
+                .empty_dirs
+                .iter()
+                .filter(|dir| dir.root() == root)
+                .cloned()
+                .collect(),
+            symlinks: self
+                .symlinks
+                .iter()
+                .filter(|link| link.path.root() == root)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl From<&DirEntries> for Vec<drop_storage::types::DirEntry> {
+    fn from(value: &DirEntries) -> Self {
+        let dirs = value
+            .empty_dirs
+            .iter()
+            .map(|dir| drop_storage::types::DirEntry {
+                relative_path: dir.to_string(),
+                symlink_target: None,
+            });
+
+        let links = value
+            .symlinks
+            .iter()
+            .map(|link| drop_storage::types::DirEntry {
+                relative_path: link.path.to_string(),
+                symlink_target: Some(link.target.clone()),
+            });
+
+        dirs.chain(links).collect()
+    }
+}
+
+impl From<Vec<drop_storage::types::DirEntry>> for DirEntries {
+    fn from(value: Vec<drop_storage::types::DirEntry>) -> Self {
+        let mut entries = DirEntries::default();
+
+        for entry in value {
+            let path = FileSubPath::from(entry.relative_path);
+            match entry.symlink_target {
+                Some(target) => entries.symlinks.push(Symlink { path, target }),
+                None => entries.empty_dirs.push(path),
+            }
+        }
+
+        entries
+    }
+}
+
+impl Symlink {
+    /// Checks if the target resolves inside of the root directory of the
+    /// symlink
+    pub(crate) fn stays_inside(&self) -> bool {
+        symlink_stays_inside(&self.path, &self.target)
+    }
+
+    /// Reads the symlink, returns `None` if the target is not relative or
+    /// points outside of the transfer root directory
+    pub(crate) fn read(path: &Path, subpath: FileSubPath) -> io::Result<Option<Self>> {
+        let target = std::fs::read_link(path)?;
+
+        let mut parts = Vec::new();
+        for comp in target.components() {
+            match comp {
+                Component::Normal(name) => match name.to_str() {
+                    Some(name) => parts.push(name),
+                    None => return Ok(None),
+                },
+                Component::ParentDir => parts.push(".."),
+                Component::CurDir => (),
+                Component::RootDir | Component::Prefix(_) => return Ok(None),
+            }
+        }
+
+        let target = parts.join("/");
+        if !symlink_stays_inside(&subpath, &target) {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            path: subpath,
+            target,
+        }))
+    }
+
+    /// Creates the symlink at the given location inside of the `root_dir` the
+    /// symlink's root directory is downloaded into. The target is resolved
+    /// against the links already present on the disk, so that neither a chain
+    /// of links nor a link placed under a linked directory escapes the root
+    pub(crate) fn create(&self, root_dir: &Path, location: &Path) -> io::Result<()> {
+        let outside = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Symlink target points outside of the transfer",
+            )
+        };
+
+        if !symlink_stays_inside(&self.path, &self.target) {
+            return Err(outside());
+        }
+
+        let parent = location.parent().ok_or_else(outside)?;
+        if crosses_symlink(root_dir, parent)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Symlink is placed under another symlink",
+            ));
+        }
+
+        let root_dir = root_dir.canonicalize()?;
+        if !resolves_inside(&root_dir, &parent.canonicalize()?, &self.target) {
+            return Err(outside());
+        }
+
+        let target: PathBuf = self
+            .target
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .map(|part| match part {
+                ".." => part.to_string(),
+                _ => crate::utils::normalize_filename(part),
+            })
+            .collect();
+
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, location);
+
+        #[cfg(windows)]
+        return std::os::windows::fs::symlink_file(target, location);
+    }
+}
+
+/// Checks if any of the components of the `path` below the `root` directory
+/// is a symlink
+pub(crate) fn crosses_symlink(root: &Path, path: &Path) -> io::Result<bool> {
+    let Ok(below) = path.strip_prefix(root) else {
+        return Ok(true);
+    };
+
+    let mut current = root.to_path_buf();
+    for comp in below.components() {
+        current.push(comp);
+
+        match std::fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => return Ok(true),
+            Ok(_) => (),
+            // The rest of the path is yet to be created
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(false)
+}
+
+// Follows the relative `target` from the canonical `dir` through the links
+// existing on the disk, checking that each step stays inside of the canonical
+// `root`. Going up from a path not created yet is refused, as a link created
+// there later could move the target
+fn resolves_inside(root: &Path, dir: &Path, target: &str) -> bool {
+    let mut current = dir.to_path_buf();
+    let mut exists = true;
+
+    for part in target.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." if !exists => return false,
+            ".." => {
+                current.pop();
+            }
+            name => {
+                current.push(crate::utils::normalize_filename(name));
+                match current.canonicalize() {
+                    Ok(resolved) => current = resolved,
+                    Err(_) => exists = false,
+                }
+            }
+        }
+
+        if !current.starts_with(root) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Checks if the relative target of the symlink with the given subpath
+/// resolves inside of the subpath's root directory
+fn symlink_stays_inside(subpath: &FileSubPath, target: &str) -> bool {
+    // The depth of the directory containing the symlink, the root is 1
+    let mut depth = subpath.len() as isize - 1;
+
+    for part in target.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => depth -= 1,
+            part if part.contains('\\') || part.contains(':') => return false,
+            _ => depth += 1,
+        }
+
+        if depth < 1 {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlink_targets() {
+        let subpath = FileSubPath::from("root/dir/link");
+
+        assert!(symlink_stays_inside(&subpath, "file"));
+        assert!(symlink_stays_inside(&subpath, "../other/file"));
+        assert!(symlink_stays_inside(&subpath, "./../file"));
+        assert!(!symlink_stays_inside(&subpath, "../../file"));
+        assert!(!symlink_stays_inside(&subpath, "../../root/../../file"));
+        assert!(!symlink_stays_inside(&subpath, "..\\..\\file"));
+        assert!(!symlink_stays_inside(&subpath, "C:/file"));
+
+        let mut entries = DirEntries {
+            empty_dirs: vec![FileSubPath::from("root/empty"), FileSubPath::from("top")],
+            symlinks: vec![
+                Symlink {
+                    path: subpath.clone(),
+                    target: "file".into(),
+                },
+                Symlink {
+                    path: subpath,
+                    target: "../../../etc/passwd".into(),
+                },
+            ],
+        };
+        entries.sanitize();
+
+        assert_eq!(entries.empty_dirs, vec![FileSubPath::from("root/empty")]);
+        assert_eq!(entries.symlinks.len(), 1);
+        assert_eq!(entries.with_root("root").len(), 2);
+        assert!(entries.with_root("other").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chained_symlinks_escaping() {
+        let dest = tempfile::tempdir().unwrap();
+        let root_dir = dest.path().join("root");
+        std::fs::create_dir_all(root_dir.join("a/b")).unwrap();
+
+        let create = |path: &str, target: &str| {
+            let link = Symlink {
+                path: FileSubPath::from(path),
+                target: target.into(),
+            };
+            let location = dest.path().join(path);
+            link.create(&root_dir, &location)
+        };
+
+        // Points to the root, fine on its own
+        create("root/a/b/up", "../..").unwrap();
+        assert!(root_dir.join("a/b/up").is_dir());
+
+        // Two levels below the root by the path, but the first link makes it
+        // the root's parent
+        assert!(create("root/a/b/chain", "up/..").is_err());
+        assert!(create("root/a/b/chain", "up/../file").is_err());
+
+        // Placed under the linked directory, the subpath claims it to be
+        // inside
+        assert!(create("root/a/b/up/a/under", "../..").is_err());
+
+        // Going up from a path not created yet
+        assert!(create("root/a/b/later", "missing/../../..").is_err());
+
+        create("root/a/b/down", "up/a").unwrap();
+        assert!(std::fs::read_link(root_dir.join("a/b/down")).is_ok());
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::{Component, Path};
+
+use glob::{MatchOptions, Pattern};
+
+/// Glob patterns of the entries skipped when walking the directories. The
+/// patterns without `/` are matched against every component of the path
+/// relative to the directory (e.g. `node_modules`, `*.tmp`), the others
+/// against the whole relative path (e.g. `build/*.o`).
+#[derive(Debug, Default)]
+pub struct Exclude {
+    names: Vec<Pattern>,
+    paths: Vec<Pattern>,
+}
+
+impl Exclude {
+    pub fn new(patterns: &[impl AsRef<str>]) -> crate::Result<Self> {
+        let mut exclude = Self::default();
+
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+
+            let compiled = Pattern::new(pattern)
+                .map_err(|err| crate::Error::BadPath(format!("Invalid exclude pattern: {err}")))?;
+
+            if pattern.contains('/') {
+                exclude.paths.push(compiled);
+            } else {
+                exclude.names.push(compiled);
+            }
+        }
+
+        Ok(exclude)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    /// Checks the path relative to the walked directory
+    pub(crate) fn matches(&self, relpath: &Path) -> bool {
+        let mut parts = Vec::new();
+        for comp in relpath.components() {
+            if let Component::Normal(name) = comp {
+                let name = name.to_string_lossy();
+                if self.names.iter().any(|pattern| pattern.matches(&name)) {
+                    return true;
+                }
+                parts.push(name);
+            }
+        }
+
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        let relpath = parts.join("/");
+        self.paths
+            .iter()
+            .any(|pattern| pattern.matches_with(&relpath, options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclude_patterns() {
+        let exclude = Exclude::new(&["node_modules", "*.tmp", "/build/*.o"]).unwrap();
+
+        assert!(exclude.matches(Path::new("node_modules")));
+        assert!(exclude.matches(Path::new("app/node_modules/lib.js")));
+        assert!(exclude.matches(Path::new("a/b/file.tmp")));
+        assert!(exclude.matches(Path::new("build/main.o")));
+        assert!(!exclude.matches(Path::new("src/build/main.o")));
+        assert!(!exclude.matches(Path::new("build/sub/main.o")));
+        assert!(!exclude.matches(Path::new("src/main.rs")));
+        assert!(!exclude.matches(Path::new("file.tmp.txt")));
+
+        assert!(Exclude::new(&["[a"]).is_err());
+        assert!(Exclude::new(&["/"]).unwrap().is_empty());
+    }
+}
@@ -1,13 +1,14 @@
 #[cfg(unix)]
 use std::os::unix::prelude::*;
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs,
     path::{Path, PathBuf},
 };
 
 use drop_config::DropConfig;
 
+use super::{DirEntries, Exclude};
 use crate::FileToSend;
 
 pub enum GatherSrc {
@@ -25,7 +26,10 @@ pub struct GatherCtx<'a> {
     #[cfg(unix)]
     fdresolv: Option<&'a super::FdResolver>,
     files: Vec<FileToSend>,
+    entries: DirEntries,
+    excluded: usize,
     used_names: HashSet<PathBuf>,
+    app_metadata: BTreeMap<String, String>,
 }
 
 impl<'a> GatherCtx<'a> {
@@ -35,7 +39,10 @@ impl<'a> GatherCtx<'a> {
             #[cfg(unix)]
             fdresolv: None,
             files: Vec::new(),
+            entries: DirEntries::default(),
+            excluded: 0,
             used_names: HashSet::new(),
+            app_metadata: BTreeMap::new(),
         }
     }
 
@@ -45,11 +52,30 @@ impl<'a> GatherCtx<'a> {
         self
     }
 
+    /// Attaches the app metadata to the files gathered next, e.g. to all the
+    /// files of a directory
+    pub fn set_app_metadata(&mut self, app_metadata: BTreeMap<String, String>) -> &mut Self {
+        self.app_metadata = app_metadata;
+        self
+    }
+
     pub fn take(&mut self) -> Vec<FileToSend> {
         self.used_names.clear();
         std::mem::take(&mut self.files)
     }
 
+    /// Takes the empty directories and the symlinks gathered alongside the
+    /// files
+    pub fn take_entries(&mut self) -> DirEntries {
+        std::mem::take(&mut self.entries)
+    }
+
+    /// The number of the directory entries skipped by the exclude patterns or
+    /// as hidden
+    pub fn excluded(&self) -> usize {
+        self.excluded
+    }
+
     fn fetch_free_dir_name(&mut self, path: &Path) -> crate::Result<PathBuf> {
         let file_name = path
             .file_name()
@@ -64,6 +90,16 @@ impl<'a> GatherCtx<'a> {
     }
 
     pub fn gather_from_path(&mut self, path: impl AsRef<Path>) -> crate::Result<&mut Self> {
+        self.gather_from_path_excluding(path, &Exclude::default())
+    }
+
+    /// Gathers the files skipping the directory entries matching `exclude`.
+    /// The patterns are not applied to the path itself
+    pub fn gather_from_path_excluding(
+        &mut self,
+        path: impl AsRef<Path>,
+        exclude: &Exclude,
+    ) -> crate::Result<&mut Self> {
         let path = path.as_ref();
 
         let meta = fs::symlink_metadata(path)?;
@@ -71,10 +107,22 @@ impl<'a> GatherCtx<'a> {
         if meta.is_dir() {
             let name = self.fetch_free_dir_name(path)?;
 
-            let batch = super::FileToSend::walk(path, &name, self.config)?;
-            self.files.extend(batch);
+            let batch = super::FileToSend::walk(
+                path,
+                &name,
+                self.config,
+                &mut self.entries,
+                exclude,
+                &mut self.excluded,
+            )?;
+            self.files.extend(
+                batch
+                    .into_iter()
+                    .map(|file| file.with_app_metadata(self.app_metadata.clone())),
+            );
         } else {
-            let file = super::FileToSend::from_path(path, meta.len())?;
+            let file = super::FileToSend::from_path(path, meta.len())?
+                .with_app_metadata(self.app_metadata.clone());
             self.files.push(file);
         }
 
@@ -114,7 +162,8 @@ impl<'a> GatherCtx<'a> {
 
         // In case of FD, its allways a file
         let subpath = FileSubPath::from_file_name(path)?;
-        let file = FileToSend::from_fd(path, subpath, uri, fd, self.files.len())?;
+        let file = FileToSend::from_fd(path, subpath, uri, fd, self.files.len())?
+            .with_app_metadata(self.app_metadata.clone());
 
         self.files.push(file);
         Ok(self)
@@ -105,6 +105,11 @@ impl FileSubPath {
         self.0.first().expect("Missing first path component")
     }
 
+    /// Checks if any of the components below the root directory is a dotfile
+    pub(crate) fn is_hidden_below_root(&self) -> bool {
+        self.0.iter().skip(1).any(|part| part.starts_with('.'))
+    }
+
     pub fn from_path(path: impl AsRef<Path>) -> crate::Result<Self> {
         let vec = path
             .as_ref()
@@ -1,22 +1,31 @@
+mod entries;
+mod exclude;
 mod gather;
 mod id;
 mod reader;
 
+#[cfg(unix)]
+use std::os::unix::prelude::*;
 use std::{
+    collections::BTreeMap,
     fmt,
     future::Future,
-    io::{self, BufRead, Read, Write},
+    io::{self, BufRead, Read, Seek, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
-#[cfg(unix)]
-use std::{os::unix::prelude::*, sync::Arc};
 
 use drop_analytics::TransferDirection;
 use drop_config::DropConfig;
+use drop_storage::Storage;
+pub(crate) use entries::crosses_symlink;
+pub use entries::{DirEntries, Symlink};
+pub use exclude::Exclude;
 pub use gather::*;
 pub use id::{FileId, FileSubPath};
 use once_cell::sync::OnceCell;
-pub use reader::FileReader;
+pub use reader::{ChunkSize, FileReader, ReadAhead};
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use walkdir::WalkDir;
 
@@ -27,14 +36,149 @@ pub struct FileInfo {
     pub direction: TransferDirection,
 }
 
+/// Optional file attributes sent alongside the file in the transfer manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    // Modification time in milliseconds since the UNIX epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    // Unix permission bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<u32>,
+    // The key-value pairs the sending app attached to the file, at most
+    // `MAX_APP_METADATA_SIZE` bytes in total
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub app_metadata: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    /// Checks the app metadata fits in `MAX_APP_METADATA_SIZE`
+    pub fn check_app_metadata(app_metadata: &BTreeMap<String, String>) -> crate::Result<()> {
+        let size: usize = app_metadata
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+
+        if size > MAX_APP_METADATA_SIZE {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+impl From<drop_storage::types::FileMetadata> for FileMetadata {
+    fn from(value: drop_storage::types::FileMetadata) -> Self {
+        let drop_storage::types::FileMetadata {
+            mime_type,
+            mtime,
+            permissions,
+            app_metadata,
+        } = value;
+
+        Self {
+            mime_type,
+            mtime,
+            permissions,
+            app_metadata,
+        }
+    }
+}
+
+impl From<FileMetadata> for drop_storage::types::FileMetadata {
+    fn from(value: FileMetadata) -> Self {
+        let FileMetadata {
+            mime_type,
+            mtime,
+            permissions,
+            app_metadata,
+        } = value;
+
+        Self {
+            mime_type,
+            mtime,
+            permissions,
+            app_metadata,
+        }
+    }
+}
+
+/// The pre-opened destination the file is downloaded into in place, e.g. the
+/// descriptor of the content URI. It must be opened for reading and writing
+/// since the written data is read back for the checksum
+#[derive(Debug)]
+pub struct DestinationFd {
+    content_uri: url::Url,
+    file: std::fs::File,
+}
+
+impl DestinationFd {
+    /// Duplicates the descriptor, the caller keeps the ownership of the `fd`
+    #[cfg(unix)]
+    pub fn new(content_uri: url::Url, fd: RawFd) -> io::Result<Self> {
+        // SAFETY: the caller guarantees the descriptor is open for the call
+        let file = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+
+        Ok(Self {
+            content_uri,
+            file: file.into(),
+        })
+    }
+
+    pub fn content_uri(&self) -> &url::Url {
+        &self.content_uri
+    }
+
+    /// Opens the destination for writing from the start, dropping any of the
+    /// previous content
+    pub(crate) fn create(&self) -> io::Result<std::fs::File> {
+        let mut file = self.file.try_clone()?;
+        file.set_len(0)?;
+        file.rewind()?;
+        Ok(file)
+    }
+
+    /// Opens the destination for reading the written content
+    pub(crate) fn open(&self) -> io::Result<std::fs::File> {
+        let mut file = self.file.try_clone()?;
+        file.rewind()?;
+        Ok(file)
+    }
+}
+
 #[cfg(unix)]
 pub type FdResolver = dyn Fn(&str) -> Option<RawFd> + Send + Sync;
 
 const HEADER_SIZE: usize = 1024;
 const UNKNOWN_STR: &str = "unknown";
 
+/// The limit of the file preview size sent with the transfer request
+pub const MAX_PREVIEW_SIZE: usize = 64 * 1024; // 64 KiB
+
+/// The limit of all the previews of the transfer request together. The
+/// previews end up in the events and the event journal, so the request with
+/// many files carries only the first ones
+pub const MAX_TOTAL_PREVIEW_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// The limit of the app metadata of a single file, the keys and the values
+/// together
+pub const MAX_APP_METADATA_SIZE: usize = 4 * 1024; // 4 KiB
+
 const CHECKSUM_CHUNK_SIZE: usize = 256 * 1024; // 256 KiB
 
+// Bigger chunks let the thread pool split the work between more threads
+const PARALLEL_CHECKSUM_CHUNK_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// The hash function of the file checksums
+#[derive(Clone)]
+pub enum HashAlg {
+    Sha256,
+    /// BLAKE3 computed on the given thread pool, used only for the checks of
+    /// the partially transferred files when negotiated with the peer
+    Blake3(Arc<rayon::ThreadPool>),
+}
+
 pub trait File {
     fn id(&self) -> &FileId;
     fn subpath(&self) -> &FileSubPath;
@@ -51,13 +195,16 @@ pub trait File {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileToSend {
     file_id: FileId,
     subpath: FileSubPath,
     size: u64,
     pub(crate) source: FileSource,
     mime_type: OnceCell<Hidden<String>>,
+    metadata: OnceCell<FileMetadata>,
+    app_metadata: BTreeMap<String, String>,
+    preview: Option<Hidden<Vec<u8>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -65,13 +212,19 @@ pub struct FileToRecv {
     file_id: FileId,
     subpath: FileSubPath,
     size: u64,
+    metadata: FileMetadata,
+    preview: Option<Hidden<Vec<u8>>>,
+    path_sanitized: bool,
 }
 
+#[derive(Clone)]
 pub enum FileSource {
     Path(Hidden<PathBuf>),
     #[cfg(unix)]
     Fd {
-        fd: OnceCell<RawFd>,
+        // Shared with the clones of the file, so the descriptor is resolved
+        // once for all the edits of the transfer
+        fd: Arc<OnceCell<RawFd>>,
         resolver: Option<Arc<FdResolver>>,
         content_uri: url::Url,
     },
@@ -136,7 +289,7 @@ impl File for FileToRecv {
     }
 
     fn mime_type(&self) -> &str {
-        UNKNOWN_STR
+        self.metadata.mime_type.as_deref().unwrap_or(UNKNOWN_STR)
     }
 
     fn direction() -> TransferDirection {
@@ -150,11 +303,137 @@ impl FileToRecv {
             file_id,
             subpath,
             size,
+            metadata: FileMetadata::default(),
+            preview: None,
+            path_sanitized: false,
         }
     }
+
+    pub fn with_metadata(mut self, metadata: FileMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    pub(crate) fn with_preview(mut self, preview: Vec<u8>) -> Self {
+        self.preview = Some(Hidden(preview));
+        self
+    }
+
+    /// The thumbnail sent by the peer with the request. It is not persisted,
+    /// the transfers restored from the database come without it
+    pub fn preview(&self) -> Option<&[u8]> {
+        self.preview.as_deref().map(Vec::as_slice)
+    }
+
+    pub(crate) fn with_path_sanitized(mut self, sanitized: bool) -> Self {
+        self.path_sanitized = sanitized;
+        self
+    }
+
+    /// Whether any name of the path sent by the peer had to be changed to be
+    /// valid on this platform. It is not persisted, the transfers restored
+    /// from the database report `false`
+    pub fn is_path_sanitized(&self) -> bool {
+        self.path_sanitized
+    }
+
+    /// Applies the modification time and the permissions received from the
+    /// sender to the downloaded file
+    pub(crate) fn apply_metadata(&self, path: &Path) -> io::Result<()> {
+        if let Some(mtime) = self.metadata.mtime {
+            let mtime = std::time::UNIX_EPOCH
+                + std::time::Duration::from_millis(mtime.try_into().unwrap_or_default());
+            filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime))?;
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.metadata.permissions {
+            // Never apply the setuid, setgid and sticky bits coming from the peer
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        Ok(())
+    }
 }
 
 impl FileToSend {
+    /// Gathers the file metadata sent in the manifest. The file is read only
+    /// once, the attributes which cannot be read are left out
+    pub fn metadata(&self) -> &FileMetadata {
+        self.metadata.get_or_init(|| FileMetadata {
+            app_metadata: self.app_metadata.clone(),
+            ..self.read_metadata()
+        })
+    }
+
+    fn read_metadata(&self) -> FileMetadata {
+        let mime_type = Some(self.mime_type())
+            .filter(|mime| *mime != UNKNOWN_STR)
+            .map(String::from);
+
+        let meta = reader::open(&self.source).and_then(|mut reader| reader.meta());
+        let meta = if let Ok(meta) = meta {
+            meta
+        } else {
+            return FileMetadata {
+                mime_type,
+                ..Default::default()
+            };
+        };
+
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|time| time.as_millis() as _);
+
+        #[cfg(unix)]
+        let permissions = Some(meta.permissions().mode() & 0o777);
+        #[cfg(not(unix))]
+        let permissions = None;
+
+        FileMetadata {
+            mime_type,
+            mtime,
+            permissions,
+            ..Default::default()
+        }
+    }
+
+    /// Attaches the key-value pairs sent to the peer in the manifest, see
+    /// `FileMetadata::check_app_metadata()`
+    pub fn with_app_metadata(mut self, app_metadata: BTreeMap<String, String>) -> Self {
+        if let Some(metadata) = self.metadata.get_mut() {
+            metadata.app_metadata = app_metadata.clone();
+        }
+        self.app_metadata = app_metadata;
+        self
+    }
+
+    /// Attaches the thumbnail sent to the peer with the request. The previews
+    /// longer than `MAX_PREVIEW_SIZE` are left out of the request
+    pub fn with_preview(mut self, preview: Vec<u8>) -> Self {
+        self.preview = Some(Hidden(preview));
+        self
+    }
+
+    pub fn preview(&self) -> Option<&[u8]> {
+        self.preview.as_deref().map(Vec::as_slice)
+    }
+
+    /// The URI of the file, the `file://` URL of the path or the content URI
+    pub fn uri(&self) -> Option<url::Url> {
+        match &self.source {
+            FileSource::Path(path) => url::Url::from_file_path(&**path).ok(),
+            #[cfg(unix)]
+            FileSource::Fd { content_uri, .. } => Some(content_uri.clone()),
+        }
+    }
+
     pub fn base_dir(&self) -> Option<&str> {
         let fullpath = match &self.source {
             FileSource::Path(fullpath) => fullpath,
@@ -166,6 +445,25 @@ impl FileToSend {
         base_dir.to_str()
     }
 
+    /// The content URI of the file provided with the file descriptor
+    #[cfg(unix)]
+    pub fn content_uri(&self) -> Option<&url::Url> {
+        match &self.source {
+            FileSource::Path(_) => None,
+            FileSource::Fd { content_uri, .. } => Some(content_uri),
+        }
+    }
+
+    /// Whether the file descriptor was provided or resolved. The library
+    /// never closes it, the host app does once the file is no longer used
+    #[cfg(unix)]
+    pub fn fd_opened(&self) -> bool {
+        match &self.source {
+            FileSource::Path(_) => false,
+            FileSource::Fd { fd, .. } => fd.get().is_some(),
+        }
+    }
+
     fn from_path(path: impl AsRef<Path>, size: u64) -> crate::Result<Self> {
         let path = path.as_ref();
         let abspath = crate::utils::make_path_absolute(path)?;
@@ -188,6 +486,9 @@ impl FileToSend {
             size,
             source: FileSource::Path(Hidden(abspath)),
             mime_type: OnceCell::new(),
+            metadata: OnceCell::new(),
+            app_metadata: BTreeMap::new(),
+            preview: None,
         }
     }
 
@@ -219,10 +520,13 @@ impl FileToSend {
                 size: meta.len(),
                 source: FileSource::Fd {
                     resolver: None,
-                    fd: OnceCell::with_value(fd),
+                    fd: Arc::new(OnceCell::with_value(fd)),
                     content_uri,
                 },
                 mime_type: OnceCell::new(),
+                metadata: OnceCell::new(),
+                app_metadata: BTreeMap::new(),
+                preview: None,
             })
         };
         let result = create_file();
@@ -247,22 +551,58 @@ impl FileToSend {
             size,
             source: FileSource::Fd {
                 resolver: Some(resolver),
-                fd: OnceCell::new(),
+                fd: Arc::new(OnceCell::new()),
                 content_uri,
             },
             mime_type: OnceCell::new(),
+            metadata: OnceCell::new(),
+            app_metadata: BTreeMap::new(),
+            preview: None,
         }
     }
 
-    fn walk(path: &Path, subname: &Path, config: &DropConfig) -> Result<Vec<Self>, Error> {
+    /// Collects the files in the directory. The empty directories and the
+    /// symlinks are collected into `entries` if enabled in the config. The
+    /// entries matching `exclude`, and the hidden ones if configured so, are
+    /// skipped together with their contents and counted into `excluded`
+    fn walk(
+        path: &Path,
+        subname: &Path,
+        config: &DropConfig,
+        entries: &mut DirEntries,
+        exclude: &Exclude,
+        excluded: &mut usize,
+    ) -> Result<Vec<Self>, Error> {
         let mut files = Vec::new();
         let mut breadth = 0;
 
-        for entry in WalkDir::new(path).min_depth(1).into_iter() {
+        let walker = WalkDir::new(path)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|entry| {
+                let skip = entry.depth() > 0
+                    && ((config.skip_hidden_files && is_hidden(entry))
+                        || entry
+                            .path()
+                            .strip_prefix(path)
+                            .is_ok_and(|relpath| exclude.matches(relpath)));
+
+                if skip {
+                    *excluded += 1;
+                }
+                !skip
+            });
+
+        for entry in walker {
             let entry = entry?;
             let meta = entry.metadata()?;
 
-            if !meta.is_file() {
+            let is_empty_dir = config.transfer_empty_dirs
+                && meta.is_dir()
+                && std::fs::read_dir(entry.path())?.next().is_none();
+            let is_symlink = config.transfer_symlinks && meta.file_type().is_symlink();
+
+            if !meta.is_file() && !is_empty_dir && !is_symlink {
                 continue;
             }
 
@@ -284,6 +624,19 @@ impl FileToSend {
             let subpath = PathBuf::from_iter([subname, relpath]);
             let subpath = FileSubPath::from_path(subpath)?;
 
+            if is_empty_dir {
+                entries.empty_dirs.push(subpath);
+                continue;
+            }
+
+            if is_symlink {
+                // The symlinks pointing outside of the directory are skipped
+                if let Some(link) = Symlink::read(entry.path(), subpath)? {
+                    entries.symlinks.push(link);
+                }
+                continue;
+            }
+
             let path = entry.into_path();
             let abspath = crate::utils::make_path_absolute(&path)?;
             let file_id = file_id_from_path(&abspath)?;
@@ -305,26 +658,153 @@ impl FileToSend {
         FileReader::new(reader, meta)
     }
 
-    /// Calculate sha2 of a file. This is a blocking operation
-    pub(crate) async fn checksum<F, Fut>(
-        &self,
-        limit: u64,
-        progress_cb: Option<F>,
-        event_granularity: Option<u64>,
-    ) -> crate::Result<[u8; 32]>
-    where
-        F: FnMut(u64) -> Fut + Send + Sync,
-        Fut: Future<Output = ()>,
-    {
+    /// Checks the file can be opened and read. This is a blocking operation
+    pub(crate) fn check_readable(&self) -> crate::Result<()> {
+        let mut reader = reader::open(&self.source)?;
+        if self.size() > 0 {
+            reader.read_exact(&mut [0u8; 1])?;
+        }
+        Ok(())
+    }
+
+    /// Calculates the checksum of the first `limit` bytes of the file on the
+    /// blocking thread pool
+    pub(crate) async fn checksum(&self, hash: &HashAlg, limit: u64) -> crate::Result<[u8; 32]> {
         let reader = reader::open(&self.source)?.take(limit);
-        let csum = checksum(reader, progress_cb, event_granularity).await?;
+        let csum = checksum_blocking(hash.clone(), reader).await?;
         Ok(csum)
     }
+
+    /// The SHA-256 of the whole file. Cached in the storage under the path, the
+    /// size and the modification time of the file, so sending the same file
+    /// again does not hash it again
+    pub(crate) async fn full_checksum(&self, storage: &Storage) -> crate::Result<[u8; 32]> {
+        let key = self.checksum_key().await;
+
+        if let Some((path, size, mtime)) = &key {
+            let cached = storage.cached_checksum(path, *size, *mtime).await;
+            if let Some(csum) = cached.and_then(|csum| csum.try_into().ok()) {
+                return Ok(csum);
+            }
+        }
+
+        let csum = self.checksum(&HashAlg::Sha256, self.size()).await?;
+
+        // Not cached when the file changed while being hashed
+        if let Some((path, size, mtime)) = &key {
+            if self.checksum_key().await.as_ref() == key.as_ref() {
+                storage.cache_checksum(path, *size, *mtime, &csum).await;
+            }
+        }
+
+        Ok(csum)
+    }
+
+    // The path, the size and the modification time in nanoseconds the
+    // checksum is cached under. The files given by the descriptor are not
+    // cached
+    async fn checksum_key(&self) -> Option<(String, i64, i64)> {
+        let FileSource::Path(path) = &self.source else {
+            return None;
+        };
+
+        let meta = tokio::fs::metadata(&path.0).await.ok()?;
+        let mtime = meta
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+
+        Some((
+            path.0.to_str()?.to_string(),
+            meta.len() as _,
+            mtime.as_nanos() as _,
+        ))
+    }
+}
+
+/// Checks for the dotfiles and, on Windows, the files with the hidden or system
+/// attribute
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    if entry.file_name().to_string_lossy().starts_with('.') {
+        return true;
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+
+        if let Ok(meta) = entry.metadata() {
+            return meta.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0;
+        }
+    }
+
+    false
 }
 
 /// This function performs buffering internally. No need to use buffered
 /// readers.
 pub async fn checksum<F, Fut>(
+    reader: impl io::Read,
+    progress_cb: Option<F>,
+    event_granularity: Option<u64>,
+) -> io::Result<[u8; 32]>
+where
+    F: FnMut(u64) -> Fut + Send + Sync,
+    Fut: Future<Output = ()>,
+{
+    checksum_with(&HashAlg::Sha256, reader, progress_cb, event_granularity).await
+}
+
+/// The same as `checksum_with()` without the progress, computed on the
+/// blocking thread pool so that hashing the big files does not hold up the
+/// runtime
+pub async fn checksum_blocking(
+    hash: HashAlg,
+    reader: impl io::Read + Send + 'static,
+) -> io::Result<[u8; 32]> {
+    tokio::task::spawn_blocking(move || {
+        let capacity = match hash {
+            HashAlg::Sha256 => CHECKSUM_CHUNK_SIZE,
+            HashAlg::Blake3(_) => PARALLEL_CHECKSUM_CHUNK_SIZE,
+        };
+        let mut reader = io::BufReader::with_capacity(capacity, reader);
+
+        match hash {
+            HashAlg::Sha256 => {
+                let mut sha = sha2::Sha256::new();
+                io::copy(&mut reader, &mut sha)?;
+                Ok(sha.finalize().into())
+            }
+            HashAlg::Blake3(pool) => {
+                let mut blake = blake3::Hasher::new();
+                loop {
+                    let buf = reader.fill_buf()?;
+                    if buf.is_empty() {
+                        break;
+                    }
+
+                    pool.install(|| {
+                        blake.update_rayon(buf);
+                    });
+
+                    let n = buf.len();
+                    reader.consume(n);
+                }
+                Ok(blake.finalize().into())
+            }
+        }
+    })
+    .await
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+}
+
+/// The same as `checksum()` but with the given hash function
+pub async fn checksum_with<F, Fut>(
+    hash: &HashAlg,
     reader: impl io::Read,
     mut progress_cb: Option<F>,
     event_granularity: Option<u64>,
@@ -333,9 +813,14 @@ where
     F: FnMut(u64) -> Fut + Send + Sync,
     Fut: Future<Output = ()>,
 {
-    let mut csum = sha2::Sha256::new();
+    let mut sha = sha2::Sha256::new();
+    let mut blake = blake3::Hasher::new();
 
-    let mut reader = io::BufReader::with_capacity(CHECKSUM_CHUNK_SIZE, reader);
+    let capacity = match hash {
+        HashAlg::Sha256 => CHECKSUM_CHUNK_SIZE,
+        HashAlg::Blake3(_) => PARALLEL_CHECKSUM_CHUNK_SIZE,
+    };
+    let mut reader = io::BufReader::with_capacity(capacity, reader);
 
     let mut total_n: u64 = 0;
     let mut announced_bytes: u64 = 0;
@@ -354,7 +839,24 @@ where
             break;
         }
 
-        csum.write_all(buf)?;
+        match hash {
+            HashAlg::Sha256 => sha.write_all(buf)?,
+            // The calling thread waits for the pool, so not the runtime one
+            HashAlg::Blake3(pool) => {
+                let pool = pool.clone();
+                let data = buf.to_vec();
+                let mut hasher = std::mem::take(&mut blake);
+
+                blake = tokio::task::spawn_blocking(move || {
+                    pool.install(|| {
+                        hasher.update_rayon(&data);
+                    });
+                    hasher
+                })
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            }
+        }
 
         let n = buf.len();
         reader.consume(n);
@@ -373,7 +875,10 @@ where
         tokio::task::yield_now().await;
     }
 
-    Ok(csum.finalize().into())
+    Ok(match hash {
+        HashAlg::Sha256 => sha.finalize().into(),
+        HashAlg::Blake3(_) => blake.finalize().into(),
+    })
 }
 
 fn file_id_from_path(path: impl AsRef<Path>) -> crate::Result<FileId> {
@@ -420,14 +925,78 @@ mod tests {
 
             let size = TEST.len() as _;
             let file = super::FileToSend::from_path(tmp.path(), size).unwrap();
-            file.checksum(size, None::<fn(u64) -> futures::future::Ready<()>>, None)
-                .await
-                .unwrap()
+            file.checksum(&super::HashAlg::Sha256, size).await.unwrap()
         };
 
         assert_eq!(csum.as_slice(), EXPECTED);
     }
 
+    #[tokio::test]
+    async fn cached_checksum() {
+        use std::io::Write;
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = drop_storage::Storage::new(logger, ":memory:").unwrap();
+
+        let mut tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        tmp.write_all(TEST).unwrap();
+
+        let file = super::FileToSend::from_path(tmp.path(), TEST.len() as _).unwrap();
+        assert_eq!(file.full_checksum(&storage).await.unwrap(), EXPECTED);
+
+        let (path, size, mtime) = file.checksum_key().await.unwrap();
+        assert_eq!(
+            storage.cached_checksum(&path, size, mtime).await,
+            Some(EXPECTED.to_vec())
+        );
+
+        // Served from the cache
+        storage.cache_checksum(&path, size, mtime, &[7; 32]).await;
+        assert_eq!(file.full_checksum(&storage).await.unwrap(), [7; 32]);
+    }
+
+    #[tokio::test]
+    async fn parallel_checksum() {
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+
+        let csum = super::checksum_with(
+            &super::HashAlg::Blake3(std::sync::Arc::new(pool)),
+            &data[..],
+            None::<fn(u64) -> futures::future::Ready<()>>,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(csum, *blake3::hash(&data).as_bytes());
+    }
+
+    #[tokio::test]
+    async fn read_ahead() {
+        use std::io::Write;
+
+        let data: Vec<u8> = (0..2_500_000u32).map(|i| i as u8).collect();
+        let mut tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        tmp.write_all(&data).unwrap();
+
+        let file = super::FileToSend::from_path(tmp.path(), data.len() as _).unwrap();
+        let mut chunks = file
+            .open(1000)
+            .unwrap()
+            .read_ahead(super::ChunkSize::new(1024 * 1024), None);
+
+        let mut read = Vec::new();
+        while let Some(chunk) = chunks.next_chunk().await.unwrap() {
+            read.extend_from_slice(&chunk);
+        }
+
+        assert_eq!(read, &data[1000..]);
+    }
+
     #[test]
     fn checksum_yielding() {
         use std::{
@@ -461,4 +1030,147 @@ mod tests {
         assert!(future.as_mut().poll(&mut cx).is_pending());
         assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(_))));
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn metadata_roundtrip() {
+        use std::{io::Write, os::unix::fs::PermissionsExt};
+
+        use super::File as _;
+
+        let mut src = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        src.write_all(TEST).unwrap();
+        std::fs::set_permissions(src.path(), std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let file = super::FileToSend::from_path(src.path(), TEST.len() as _).unwrap();
+        let mut meta = file.metadata().clone();
+        assert_eq!(meta.permissions, Some(0o640));
+        assert!(meta.mtime.is_some());
+
+        meta.mtime = Some(1_000_000);
+        meta.permissions = Some(0o4755);
+
+        let dst = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        super::FileToRecv::new(file.id().clone(), file.subpath().clone(), TEST.len() as _)
+            .with_metadata(meta)
+            .apply_metadata(dst.path())
+            .unwrap();
+
+        let applied = std::fs::metadata(dst.path()).unwrap();
+        assert_eq!(applied.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(
+            applied.modified().unwrap(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_000_000)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn gather_dir_entries() {
+        let tmp = tempfile::tempdir().expect("Failed to create tmp dir");
+        let root = tmp.path().join("root");
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        std::fs::write(root.join("a.txt"), TEST).unwrap();
+        std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+        std::os::unix::fs::symlink("../../outside", root.join("escape")).unwrap();
+
+        let mut config = super::DropConfig::default();
+        let mut gather = super::GatherCtx::new(&config);
+        gather.gather_from_path(&root).unwrap();
+        assert_eq!(gather.take().len(), 1);
+        assert!(gather.take_entries().is_empty());
+
+        config.transfer_empty_dirs = true;
+        config.transfer_symlinks = true;
+        let mut gather = super::GatherCtx::new(&config);
+        gather.gather_from_path(&root).unwrap();
+        assert_eq!(gather.take().len(), 1);
+
+        let entries = gather.take_entries();
+        assert_eq!(
+            entries.empty_dirs,
+            vec![super::FileSubPath::from("root/empty")]
+        );
+        assert_eq!(
+            entries.symlinks,
+            vec![super::Symlink {
+                path: super::FileSubPath::from("root/link"),
+                target: "a.txt".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn gather_excluding() {
+        use super::File as _;
+
+        let tmp = tempfile::tempdir().expect("Failed to create tmp dir");
+        let root = tmp.path().join("root");
+        std::fs::create_dir_all(root.join("node_modules/lib")).unwrap();
+        std::fs::write(root.join("node_modules/lib/index.js"), TEST).unwrap();
+        std::fs::write(root.join("a.txt"), TEST).unwrap();
+        std::fs::write(root.join("b.tmp"), TEST).unwrap();
+
+        let config = super::DropConfig::default();
+        let exclude = super::Exclude::new(&["node_modules", "*.tmp"]).unwrap();
+        let mut gather = super::GatherCtx::new(&config);
+        gather.gather_from_path_excluding(&root, &exclude).unwrap();
+
+        let files = gather.take();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].subpath(), &super::FileSubPath::from("root/a.txt"));
+        assert_eq!(gather.excluded(), 2);
+    }
+
+    #[test]
+    fn gather_skipping_hidden() {
+        let tmp = tempfile::tempdir().expect("Failed to create tmp dir");
+        let root = tmp.path().join("root");
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join(".git/config"), TEST).unwrap();
+        std::fs::write(root.join(".env"), TEST).unwrap();
+        std::fs::write(root.join("a.txt"), TEST).unwrap();
+
+        let mut config = super::DropConfig::default();
+        let mut gather = super::GatherCtx::new(&config);
+        gather.gather_from_path(&root).unwrap();
+        assert_eq!(gather.take().len(), 3);
+
+        config.skip_hidden_files = true;
+        let mut gather = super::GatherCtx::new(&config);
+        gather.gather_from_path(&root).unwrap();
+        assert_eq!(gather.take().len(), 1);
+        assert_eq!(gather.excluded(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn destination_fd() {
+        use std::{
+            io::{Read, Write},
+            os::fd::AsRawFd,
+        };
+
+        let tmp = tempfile::tempdir().expect("Failed to create tmp dir");
+        let path = tmp.path().join("dest");
+        std::fs::write(&path, b"previous content").unwrap();
+
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let dest =
+            super::DestinationFd::new("content://test/dest".parse().unwrap(), file.as_raw_fd())
+                .unwrap();
+        // The descriptor is duplicated
+        drop(file);
+
+        dest.create().unwrap().write_all(TEST).unwrap();
+
+        let mut written = Vec::new();
+        dest.open().unwrap().read_to_end(&mut written).unwrap();
+        assert_eq!(written, TEST);
+        assert_eq!(std::fs::read(&path).unwrap(), TEST);
+    }
 }
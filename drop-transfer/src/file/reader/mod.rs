@@ -3,12 +3,30 @@ mod fd;
 
 mod path;
 
-use std::{fs, io};
-
-use crate::Error;
-
-/// Number of bytes read from files when uploading
-const CHUNK_SIZE: usize = 1024 * 1024;
+use std::{
+    fs, io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    timing::{self, Stage, StageTimer},
+    Error,
+};
+
+/// Number of bytes read from files when retransmitting
+const CHUNK_SIZE: usize = drop_config::DEFAULT_CHUNK_SIZE;
+/// Spare capacity of the uploaded chunk buffers so that appending the CRC
+/// trailer does not reallocate them
+const CHUNK_TRAILER_ROOM: usize = 64;
+/// Number of chunks read ahead of the consumer. Together with the chunk being
+/// sent it keeps the disk busy while the socket is written
+const READ_AHEAD_CHUNKS: usize = 2;
 
 pub struct FileReader {
     inner: Box<dyn Reader>,
@@ -16,6 +34,17 @@ pub struct FileReader {
     meta: fs::Metadata,
 }
 
+/// The size of the chunks read ahead, shared with the consumer which may
+/// change it while the file is being read
+#[derive(Clone)]
+pub struct ChunkSize(Arc<AtomicUsize>);
+
+/// The chunks of the file read on the blocking thread pool ahead of the
+/// consumer
+pub struct ReadAhead {
+    chunks: mpsc::Receiver<crate::Result<Vec<u8>>>,
+}
+
 pub(super) fn open(source: &super::FileSource) -> crate::Result<Box<dyn Reader>> {
     let reader: Box<dyn Reader> = match source {
         super::FileSource::Path(path) => Box::new(path::FileReader::new(path)?),
@@ -50,7 +79,31 @@ impl FileReader {
     }
 
     pub fn read_chunk(&mut self) -> crate::Result<Option<&[u8]>> {
-        let n = self.inner.read(&mut self.buffer)?;
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let n = self.read_into(&mut buffer);
+        self.buffer = buffer;
+
+        Ok(n?.map(|n| &self.buffer[..n]))
+    }
+
+    /// Reads the next chunk into a newly allocated buffer handed over to the
+    /// caller, so that it's passed down to the socket without copying. The
+    /// WebSocket framing, TLS and the chunk encryption rule out `sendfile()`,
+    /// hence the file data goes through the user space anyway
+    pub fn read_chunk_owned(&mut self, size: usize) -> crate::Result<Option<Vec<u8>>> {
+        let mut buffer = Vec::with_capacity(size + CHUNK_TRAILER_ROOM);
+        buffer.resize(size, 0);
+
+        let n = self.read_into(&mut buffer)?;
+        Ok(n.map(|n| {
+            buffer.truncate(n);
+            buffer
+        }))
+    }
+
+    // Returns the number of bytes read, `None` at the end of the file
+    fn read_into(&mut self, buffer: &mut [u8]) -> crate::Result<Option<usize>> {
+        let n = self.inner.read(buffer)?;
 
         if !self.is_mtime_ok().unwrap_or(true) {
             return Err(Error::FileModified);
@@ -72,8 +125,35 @@ impl FileReader {
             return Err(Error::MismatchedSize);
         }
 
-        let chunk = &self.buffer[..n];
-        Ok(Some(chunk))
+        Ok(Some(n))
+    }
+
+    /// Moves the reading to the blocking thread pool so that the disk reads
+    /// overlap with the consumer's writes. The reading stops at the end of the
+    /// file, on the first error or once the [`ReadAhead`] is dropped
+    pub(crate) fn read_ahead(
+        mut self,
+        chunk_size: ChunkSize,
+        timer: Option<Arc<StageTimer>>,
+    ) -> ReadAhead {
+        let (tx, rx) = mpsc::channel(READ_AHEAD_CHUNKS);
+
+        tokio::task::spawn_blocking(move || loop {
+            let started = Instant::now();
+            let chunk = match self.read_chunk_owned(chunk_size.get()) {
+                Ok(Some(chunk)) => Ok(chunk),
+                Ok(None) => break,
+                Err(err) => Err(err),
+            };
+            timing::record(timer.as_deref(), Stage::Read, started);
+
+            let failed = chunk.is_err();
+            if tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        });
+
+        ReadAhead { chunks: rx }
     }
 
     fn is_mtime_ok(&mut self) -> crate::Result<bool> {
@@ -84,6 +164,28 @@ impl FileReader {
     }
 }
 
+impl ChunkSize {
+    pub fn new(size: usize) -> Self {
+        Self(Arc::new(AtomicUsize::new(size)))
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Applies to the chunks read from now on
+    pub fn set(&self, size: usize) {
+        self.0.store(size, Ordering::Relaxed);
+    }
+}
+
+impl ReadAhead {
+    /// The next chunk of the file, `None` at the end of the file
+    pub async fn next_chunk(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        self.chunks.recv().await.transpose()
+    }
+}
+
 pub(super) trait Reader: io::Read + io::Seek + Send + Sync {
     fn bytes_read(&self) -> u64;
     fn meta(&mut self) -> crate::Result<fs::Metadata>;
@@ -0,0 +1,264 @@
+//! Store-and-forward relaying. The incoming transfers asking to be forwarded
+//! are downloaded into `<forward_dir>/<transfer_id>` and, once all the files
+//! are there, sent on to the final peer as a new outgoing transfer carrying
+//! the same origin ID. The stored files are removed once the forwarded
+//! transfer finishes or the incoming one ends before all the files are
+//! downloaded. The same coordinator stores the transfers of the inbox
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use drop_config::DropConfig;
+use slog::{debug, info, warn, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    file::GatherCtx,
+    inbox,
+    service::{self, State},
+    tasks::AliveGuard,
+    transfer::{Forwarding, IncomingTransfer, OutgoingTransfer, Transfer},
+    utils,
+};
+
+pub(crate) enum Request {
+    /// The transfer was just registered, its files are to be downloaded
+    Received(Arc<IncomingTransfer>),
+    /// All the files of the transfer reached the terminal state or the
+    /// transfer was cancelled
+    Downloaded(Arc<IncomingTransfer>),
+    /// The outgoing transfer is finished, the files it forwarded are no
+    /// longer needed
+    Finished(Arc<OutgoingTransfer>),
+}
+
+pub(crate) async fn spawn(
+    mut rx: mpsc::UnboundedReceiver<Request>,
+    refresh_trigger: watch::Receiver<()>,
+    state: Arc<State>,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) {
+    // The restored transfers might have files which were never started
+    let restored: Vec<_> = state
+        .transfer_manager
+        .incoming
+        .lock()
+        .await
+        .values()
+        .filter(|xstate| is_stored(&state.config, &xstate.xfer))
+        .map(|xstate| xstate.xfer.clone())
+        .collect();
+
+    for xfer in restored {
+        if xfer.forward_to().is_none() {
+            state.inbox_usage.restore(&xfer);
+        }
+        state.forward(&logger, Request::Received(xfer));
+    }
+
+    let task_guard = guard.task("forward", "Forwarding coordinator");
+    tokio::spawn(async move {
+        let _guard = task_guard;
+        let mut forwarded = HashSet::new();
+
+        let task = async {
+            while let Some(req) = rx.recv().await {
+                match req {
+                    Request::Received(xfer) => download_all(&state, &logger, &xfer).await,
+                    Request::Downloaded(xfer) => {
+                        if !forwarded.insert(xfer.id()) {
+                            continue;
+                        }
+
+                        let sent = match forward(&state, &logger, &xfer).await {
+                            Ok(forwarded) => service::send_request(
+                                &state,
+                                &logger,
+                                refresh_trigger.clone(),
+                                guard.clone(),
+                                stop.clone(),
+                                forwarded,
+                            )
+                            .await
+                            .map_err(anyhow::Error::from),
+                            Err(err) => Err(err),
+                        };
+
+                        if let Err(err) = sent {
+                            warn!(logger, "Not forwarding transfer {}: {err:?}", xfer.id());
+                            remove_stored(&state, &logger, xfer.id());
+                        }
+                    }
+                    Request::Finished(xfer) => remove_stored(&state, &logger, xfer.origin_id()),
+                }
+            }
+        };
+
+        tokio::select! {
+            biased;
+
+            _ = stop.cancelled() => {
+                debug!(logger, "Stopping forwarding coordinator");
+            },
+            _ = task => (),
+        }
+    });
+}
+
+/// Passes the ended transfer asking to be forwarded to the coordinator, which
+/// sends it on or cleans up after it. The inbox keeps only the room of the
+/// files the transfer stored
+pub(crate) fn ended(state: &State, logger: &Logger, xfer: &Arc<IncomingTransfer>) {
+    if xfer.forward_to().is_some() {
+        state.forward(logger, Request::Downloaded(xfer.clone()));
+    } else {
+        state.inbox_usage.settle(&state.config, xfer.id());
+    }
+}
+
+/// Lets the coordinator remove the files the finished outgoing transfer
+/// forwarded
+pub(crate) fn finished(state: &State, logger: &Logger, xfer: &Arc<OutgoingTransfer>) {
+    if xfer.forwarding().is_some() && xfer.forward_to().is_none() {
+        state.forward(logger, Request::Finished(xfer.clone()));
+    }
+}
+
+/// Checks whether the transfer is downloaded automatically, either to be
+/// forwarded or into the inbox
+pub(crate) fn is_stored(config: &DropConfig, xfer: &IncomingTransfer) -> bool {
+    store_dir(config, xfer).is_some()
+}
+
+fn store_dir(config: &DropConfig, xfer: &IncomingTransfer) -> Option<PathBuf> {
+    if xfer.forward_to().is_none() {
+        return inbox::spool_dir(config, xfer.id());
+    }
+
+    let forward_dir = config.forward_dir.as_ref()?;
+    Some(PathBuf::from(forward_dir).join(xfer.id().to_string()))
+}
+
+/// Builds the outgoing transfer of the files stored in the directory. The
+/// partial files left by the failed downloads are skipped
+pub(crate) fn stored_transfer(
+    config: &DropConfig,
+    dir: &Path,
+    peer: IpAddr,
+    origin_id: Uuid,
+) -> crate::Result<OutgoingTransfer> {
+    let suffix = format!(".{}", config.partial_file_extension);
+
+    let mut gather = GatherCtx::new(config);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().ends_with(&suffix) {
+            continue;
+        }
+
+        gather.gather_from_path(entry.path())?;
+    }
+
+    let xfer = OutgoingTransfer::new(peer, gather.take(), config)?
+        .with_dir_entries(gather.take_entries(), config)?
+        .with_hidden_files_skipped(config.skip_hidden_files)
+        .with_forwarding(Some(Forwarding {
+            origin_id,
+            forward_to: None,
+        }));
+
+    Ok(xfer)
+}
+
+async fn download_all(state: &State, logger: &Logger, xfer: &IncomingTransfer) {
+    let Some(dir) = store_dir(&state.config, xfer) else {
+        return;
+    };
+    let Some(dir) = dir.to_str() else {
+        warn!(logger, "Forwarding directory is not valid UTF-8");
+        return;
+    };
+
+    match xfer.forward_to() {
+        Some(forward_to) => info!(
+            logger,
+            "Storing transfer {} to be forwarded to {forward_to}",
+            xfer.id()
+        ),
+        None => info!(logger, "Storing transfer {} in the inbox", xfer.id()),
+    }
+
+    for file_id in xfer.files().keys() {
+        if let Err(err) =
+            service::start_download(state, logger, xfer.id(), file_id, dir, None).await
+        {
+            warn!(
+                logger,
+                "Failed to start download of {file_id} for forwarding: {err}"
+            );
+        }
+    }
+}
+
+/// Removes the files stored to be forwarded. The forwarded transfers carry the
+/// ID of the incoming one as the origin ID, see `ws::server::v6`
+fn remove_stored(state: &State, logger: &Logger, transfer_id: Uuid) {
+    let Some(forward_dir) = &state.config.forward_dir else {
+        return;
+    };
+
+    let dir = PathBuf::from(forward_dir).join(transfer_id.to_string());
+    match fs::remove_dir_all(dir) {
+        Ok(()) => debug!(logger, "Removed the forwarded files of {transfer_id}"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => warn!(
+            logger,
+            "Failed to remove the forwarded files of {transfer_id}: {err}"
+        ),
+    }
+}
+
+async fn forward(
+    state: &State,
+    logger: &Logger,
+    xfer: &IncomingTransfer,
+) -> anyhow::Result<OutgoingTransfer> {
+    let forward_to = xfer.forward_to().context("Missing final peer")?;
+    let dir = store_dir(&state.config, xfer).context("Forwarding is not enabled")?;
+
+    let finished = state.storage.finished_incoming_files(xfer.id()).await;
+    if finished.len() != xfer.files().len() {
+        anyhow::bail!(
+            "Only {} out of {} files were downloaded",
+            finished.len(),
+            xfer.files().len()
+        );
+    }
+
+    let (peer, peer_scope_id, peer_port) =
+        utils::parse_peer(forward_to).context("Failed to parse the final peer")?;
+
+    let outgoing = stored_transfer(&state.config, &dir, peer, xfer.origin_id())?
+        .with_peer_scope_id(peer_scope_id)
+        .with_peer_port(peer_port)
+        .with_label(xfer.label().map(ToString::to_string))?;
+
+    info!(
+        logger,
+        "Forwarding transfer {} as {} to {forward_to}",
+        xfer.id(),
+        outgoing.id(),
+    );
+
+    Ok(outgoing)
+}
@@ -0,0 +1,339 @@
+//! The inbox of an always-on receiver. Every incoming transfer is downloaded
+//! into the spool at `<inbox_dir>/<transfer_id>`, from where the app claims
+//! the files or forwards them to the other devices
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use drop_config::DropConfig;
+use slog::{info, warn, Logger};
+use uuid::Uuid;
+
+use crate::{
+    forward,
+    service::State,
+    transfer::{IncomingTransfer, OutgoingTransfer, Transfer},
+    utils::{self, Hidden},
+    validate, Error,
+};
+
+/// The transfer kept in the inbox spool
+#[derive(Debug, Clone)]
+pub struct InboxEntry {
+    pub transfer_id: Uuid,
+    /// The transfer ID given by the original sender
+    pub origin_id: Uuid,
+    /// The size of the files stored so far
+    pub size: u64,
+    /// The files are still being downloaded
+    pub in_progress: bool,
+}
+
+pub(crate) fn spool_dir(config: &DropConfig, transfer_id: Uuid) -> Option<PathBuf> {
+    let inbox_dir = config.inbox_dir.as_ref()?;
+    Some(PathBuf::from(inbox_dir).join(transfer_id.to_string()))
+}
+
+/// The room taken in the spool by each transfer, the size of the stored files
+/// or the size of the whole transfer while it is being downloaded. The spool
+/// is scanned once on startup, the quota checks use the running total
+#[derive(Default)]
+pub(crate) struct SpoolUsage(Mutex<Usage>);
+
+#[derive(Default)]
+struct Usage {
+    transfers: HashMap<Uuid, u64>,
+    total: u64,
+}
+
+impl Usage {
+    fn set(&mut self, transfer_id: Uuid, size: u64) {
+        let old = self.transfers.insert(transfer_id, size).unwrap_or(0);
+        self.total = self.total - old + size;
+    }
+
+    fn remove(&mut self, transfer_id: Uuid) {
+        if let Some(size) = self.transfers.remove(&transfer_id) {
+            self.total -= size;
+        }
+    }
+
+    fn get(&self, transfer_id: Uuid) -> u64 {
+        self.transfers.get(&transfer_id).copied().unwrap_or(0)
+    }
+}
+
+impl SpoolUsage {
+    pub(crate) fn load(config: &DropConfig) -> Self {
+        let mut usage = Usage::default();
+
+        if let Some(Ok(entries)) = config.inbox_dir.as_ref().map(fs::read_dir) {
+            for entry in entries.flatten() {
+                if let Some(transfer_id) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| Uuid::try_parse(name).ok())
+                {
+                    usage.set(transfer_id, dir_size(&entry.path()));
+                }
+            }
+        }
+
+        Self(Mutex::new(usage))
+    }
+
+    /// Reserves the room for the whole transfer when it fits into the spool
+    /// quota. Returns `false` otherwise
+    pub(crate) fn reserve(&self, config: &DropConfig, xfer: &IncomingTransfer) -> bool {
+        if config.inbox_dir.is_none() {
+            return true;
+        }
+
+        let mut usage = self.0.lock().expect("Poisoned lock");
+        let size = xfer.total_size().max(usage.get(xfer.id()));
+
+        let others = usage.total - usage.get(xfer.id());
+        if config
+            .inbox_quota_bytes
+            .is_some_and(|quota| others.saturating_add(size) > quota)
+        {
+            return false;
+        }
+
+        usage.set(xfer.id(), size);
+        true
+    }
+
+    /// Reserves the room for the restored transfer regardless of the quota,
+    /// it was admitted before the restart
+    pub(crate) fn restore(&self, xfer: &IncomingTransfer) {
+        let mut usage = self.0.lock().expect("Poisoned lock");
+        let size = xfer.total_size().max(usage.get(xfer.id()));
+        usage.set(xfer.id(), size);
+    }
+
+    /// Replaces the reservation of the ended transfer with the size of the
+    /// files it actually stored
+    pub(crate) fn settle(&self, config: &DropConfig, transfer_id: Uuid) {
+        let Some(dir) = spool_dir(config, transfer_id) else {
+            return;
+        };
+
+        let size = dir.is_dir().then(|| dir_size(&dir));
+        let mut usage = self.0.lock().expect("Poisoned lock");
+        match size {
+            Some(size) => usage.set(transfer_id, size),
+            None => usage.remove(transfer_id),
+        }
+    }
+
+    fn release(&self, transfer_id: Uuid) {
+        self.0.lock().expect("Poisoned lock").remove(transfer_id);
+    }
+}
+
+pub(crate) async fn list(state: &State) -> crate::Result<Vec<InboxEntry>> {
+    let inbox_dir = state
+        .config
+        .inbox_dir
+        .as_ref()
+        .ok_or(Error::InvalidArgument)?;
+
+    let entries = match fs::read_dir(inbox_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut out = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(transfer_id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Uuid::try_parse(name).ok())
+        else {
+            continue;
+        };
+
+        let origin_id = state
+            .storage
+            .transfer_forwarding(transfer_id)
+            .await
+            .map_or(transfer_id, |fwd| fwd.origin_id);
+
+        out.push(InboxEntry {
+            transfer_id,
+            origin_id,
+            size: dir_size(&entry.path()),
+            in_progress: state.transfer_manager.is_incoming_alive(transfer_id).await,
+        });
+    }
+
+    Ok(out)
+}
+
+/// Moves the stored files into the destination directory, renaming them on
+/// conflicts, and removes the transfer from the spool. Returns the new paths
+pub(crate) async fn claim(
+    state: &State,
+    logger: &Logger,
+    transfer_id: Uuid,
+    dest: &Path,
+) -> crate::Result<Vec<PathBuf>> {
+    let dir = stored_dir(state, transfer_id).await?;
+    let dest = validate::destination(&state.config, dest)?;
+
+    let suffix = format!(".{}", state.config.partial_file_extension);
+
+    let mut claimed = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().ends_with(&suffix) {
+            continue;
+        }
+
+        let target = utils::filepath_variants(&dest.join(entry.file_name()))?
+            .find(|path| !path.exists())
+            .expect("The variants are endless");
+
+        fs::rename(entry.path(), &target)?;
+        claimed.push(target);
+    }
+
+    if let Err(err) = fs::remove_dir_all(&dir) {
+        warn!(
+            logger,
+            "Failed to remove inbox directory {:?}: {err}",
+            Hidden(&dir)
+        );
+    }
+    state.inbox_usage.release(transfer_id);
+
+    info!(logger, "Claimed inbox transfer {transfer_id}");
+    Ok(claimed)
+}
+
+/// Creates the outgoing transfer of the stored files, keeping the origin ID
+pub(crate) async fn outgoing(
+    state: &State,
+    transfer_id: Uuid,
+    peer: IpAddr,
+    peer_scope_id: u32,
+    peer_port: Option<u16>,
+) -> crate::Result<OutgoingTransfer> {
+    let dir = stored_dir(state, transfer_id).await?;
+
+    let origin_id = state
+        .storage
+        .transfer_forwarding(transfer_id)
+        .await
+        .map_or(transfer_id, |fwd| fwd.origin_id);
+
+    forward::stored_transfer(&state.config, &dir, peer, origin_id).map(|xfer| {
+        xfer.with_peer_scope_id(peer_scope_id)
+            .with_peer_port(peer_port)
+    })
+}
+
+/// The spool directory of the transfer with all the files downloaded
+async fn stored_dir(state: &State, transfer_id: Uuid) -> crate::Result<PathBuf> {
+    let dir = spool_dir(&state.config, transfer_id).ok_or(Error::InvalidArgument)?;
+    if !dir.is_dir() {
+        return Err(Error::BadTransfer);
+    }
+
+    if state.transfer_manager.is_incoming_alive(transfer_id).await {
+        return Err(Error::BadTransferState(
+            "Transfer is still being downloaded".into(),
+        ));
+    }
+
+    Ok(dir)
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(ftype) if ftype.is_dir() => dir_size(&entry.path()),
+            Ok(ftype) if ftype.is_file() => entry.metadata().map_or(0, |meta| meta.len()),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileToRecv;
+
+    fn transfer(config: &DropConfig, size: u64) -> IncomingTransfer {
+        IncomingTransfer::new(
+            "127.0.0.1".parse().unwrap(),
+            vec![FileToRecv::new("id".into(), "b".into(), size)],
+            config,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = Uuid::new_v4();
+        fs::create_dir_all(dir.path().join(stored.to_string()).join("sub")).unwrap();
+        fs::write(dir.path().join(stored.to_string()).join("sub/a"), [0; 60]).unwrap();
+
+        let mut config = DropConfig {
+            inbox_dir: Some(dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let xfer = transfer(&config, 40);
+
+        assert!(SpoolUsage::load(&config).reserve(&config, &xfer));
+
+        config.inbox_quota_bytes = Some(100);
+        assert!(SpoolUsage::load(&config).reserve(&config, &xfer));
+
+        config.inbox_quota_bytes = Some(99);
+        assert!(!SpoolUsage::load(&config).reserve(&config, &xfer));
+    }
+
+    #[test]
+    fn reservations() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DropConfig {
+            inbox_dir: Some(dir.path().to_string_lossy().into_owned()),
+            inbox_quota_bytes: Some(100),
+            ..Default::default()
+        };
+        let usage = SpoolUsage::load(&config);
+
+        let first = transfer(&config, 60);
+        let second = transfer(&config, 60);
+        assert!(usage.reserve(&config, &first));
+        // Asking again for the same transfer does not take more room
+        assert!(usage.reserve(&config, &first));
+        assert!(!usage.reserve(&config, &second));
+
+        // Only the stored files count once the transfer ends
+        let spool = spool_dir(&config, first.id()).unwrap();
+        fs::create_dir(&spool).unwrap();
+        fs::write(spool.join("a"), [0; 30]).unwrap();
+        usage.settle(&config, first.id());
+        assert!(usage.reserve(&config, &second));
+        assert!(!usage.reserve(&config, &transfer(&config, 20)));
+
+        usage.release(first.id());
+        assert!(usage.reserve(&config, &transfer(&config, 40)));
+    }
+}
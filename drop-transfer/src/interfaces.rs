@@ -0,0 +1,147 @@
+//! Enumeration of the local addresses the instance can listen on. The
+//! loopback and the link-local addresses are left out, the rest is ordered by
+//! how likely the peers reach it: the mesh network first, then the private
+//! IPv4 networks, the other IPv4 and finally the IPv6 addresses
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// The address of the local network interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalAddr {
+    pub interface: String,
+    pub ip: IpAddr,
+    /// The address is in the range used by the mesh networks and VPNs
+    pub mesh: bool,
+}
+
+impl LocalAddr {
+    fn new(interface: String, ip: IpAddr) -> Self {
+        let ip = crate::utils::canonical_socket_addr((ip, 0).into()).ip();
+
+        Self {
+            interface,
+            ip,
+            mesh: is_mesh(ip),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self.ip {
+            _ if self.mesh => 0,
+            IpAddr::V4(ip) if ip.is_private() => 1,
+            IpAddr::V4(_) => 2,
+            IpAddr::V6(_) => 3,
+        }
+    }
+}
+
+/// The candidate listen addresses, the best one first. With `mesh_only` only
+/// the mesh network and VPN addresses are returned
+pub fn local_addrs(mesh_only: bool) -> io::Result<Vec<LocalAddr>> {
+    let addrs = if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|iface| {
+            let ip = iface.ip();
+            LocalAddr::new(iface.name, ip)
+        })
+        .collect();
+
+    Ok(filter_and_sort(addrs, mesh_only))
+}
+
+/// The address `Service::start()` should listen on, `None` when there is no
+/// suitable one
+pub fn pick_listen_addr(mesh_only: bool) -> io::Result<Option<IpAddr>> {
+    Ok(local_addrs(mesh_only)?.first().map(|addr| addr.ip))
+}
+
+fn filter_and_sort(mut addrs: Vec<LocalAddr>, mesh_only: bool) -> Vec<LocalAddr> {
+    addrs.retain(|addr| is_usable(addr.ip) && (!mesh_only || addr.mesh));
+    // Stable, so the order of the interfaces is kept within the same rank
+    addrs.sort_by_key(LocalAddr::rank);
+    addrs
+}
+
+fn is_usable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_link_local() && !ip.is_unspecified(),
+        // The link-local addresses need the zone index to be reached
+        IpAddr::V6(ip) => !ip.is_loopback() && !is_unicast_link_local(ip) && !ip.is_unspecified(),
+    }
+}
+
+/// The shared address space of RFC 6598 and the unique local IPv6 addresses,
+/// used by the mesh networks
+fn is_mesh(ip: IpAddr) -> bool {
+    const SHARED: (Ipv4Addr, u32) = (Ipv4Addr::new(100, 64, 0, 0), 10);
+
+    match ip {
+        IpAddr::V4(ip) => {
+            u32::from(ip) >> (32 - SHARED.1) == u32::from(SHARED.0) >> (32 - SHARED.1)
+        }
+        IpAddr::V6(ip) => is_unique_local(ip),
+    }
+}
+
+fn is_unique_local(ip: Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xfe00 == 0xfc00
+}
+
+fn is_unicast_link_local(ip: Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidate_order() {
+        let addr = |name: &str, ip: &str| LocalAddr::new(name.into(), ip.parse().unwrap());
+
+        let addrs = vec![
+            addr("lo", "127.0.0.1"),
+            addr("lo", "::1"),
+            addr("eth0", "fe80::1"),
+            addr("eth0", "2001:db8::1"),
+            addr("eth0", "169.254.0.1"),
+            addr("wan", "203.0.113.1"),
+            addr("eth0", "192.168.1.10"),
+            addr("nordlynx", "100.64.0.1"),
+            addr("nordlynx", "fd74:656c::1"),
+            addr("wlan0", "::ffff:10.0.0.5"),
+        ];
+
+        let names = |addrs: Vec<LocalAddr>| -> Vec<String> {
+            addrs.into_iter().map(|addr| addr.ip.to_string()).collect()
+        };
+
+        assert_eq!(
+            names(filter_and_sort(addrs.clone(), false)),
+            [
+                "100.64.0.1",
+                "fd74:656c::1",
+                "192.168.1.10",
+                "10.0.0.5",
+                "203.0.113.1",
+                "2001:db8::1"
+            ]
+        );
+        assert_eq!(
+            names(filter_and_sort(addrs, true)),
+            ["100.64.0.1", "fd74:656c::1"]
+        );
+    }
+
+    #[test]
+    fn mesh_range() {
+        assert!(is_mesh("100.64.0.0".parse().unwrap()));
+        assert!(is_mesh("100.127.255.255".parse().unwrap()));
+        assert!(!is_mesh("100.128.0.0".parse().unwrap()));
+        assert!(!is_mesh("100.63.255.255".parse().unwrap()));
+        assert!(!is_mesh("2001:db8::1".parse().unwrap()));
+    }
+}
@@ -1,27 +1,53 @@
+mod alias;
 pub mod auth;
 mod check;
+mod diagnostics;
 mod error;
 pub mod event;
+mod expiry;
 pub mod file;
+mod forward;
+mod inbox;
+pub mod interfaces;
 mod manager;
+mod preflight;
 mod protocol;
 mod quarantine;
 pub mod service;
+mod share;
+pub mod spans;
+mod stats;
 mod storage_dispatch;
 mod tasks;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod text;
+mod timing;
+mod tls;
 pub mod transfer;
 pub mod utils;
+mod validate;
 mod ws;
 
 #[cfg(unix)]
 pub use crate::file::FdResolver;
 pub(crate) use crate::manager::TransferManager;
 pub use crate::{
+    diagnostics::{HandshakeDiagnostics, HandshakePhase, PhaseTiming},
     error::Error,
-    event::Event,
-    file::{File, FileId, FileToRecv, FileToSend},
-    service::Service,
+    event::{Event, RefusedOperation},
+    file::{DestinationFd, File, FileId, FileToRecv, FileToSend},
+    inbox::InboxEntry,
+    manager::{FileLiveStatus, FileSnapshot, OutgoingRetryState, TransferSnapshot},
+    protocol::device::PeerDevice,
+    service::{
+        ConnectionDecision, ConnectionPolicy, IncomingDecision, IncomingFilter, InstanceSnapshot,
+        Service,
+    },
+    stats::{TransferCounters, TransferStats},
     storage_dispatch::StorageDispatch,
+    tasks::TaskInfo,
+    timing::StageTimings,
     transfer::{IncomingTransfer, OutgoingTransfer, Transfer, TransferData},
 };
 
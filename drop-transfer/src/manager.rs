@@ -1,24 +1,32 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    io,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    fs, io,
+    net::IpAddr,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::Context;
 use drop_config::DropConfig;
 use drop_storage::{sync, types::OutgoingFileToRetry, Storage};
 use slog::{debug, error, info, trace, warn, Logger};
-use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tokio::sync::{mpsc::UnboundedSender, Mutex, Notify};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
     check,
-    file::FileSubPath,
-    service::State,
+    event::StartupReconciliation,
+    expiry,
+    file::{DestinationFd, DirEntries, FileSubPath},
+    service::{ConnectionDecision, State},
+    spans,
+    stats::{StatsTracker, TransferCounters, TransferTotals},
     tasks::AliveGuard,
+    timing::StageTimings,
     transfer::{IncomingTransfer, OutgoingTransfer},
+    utils,
     ws::{
         self,
         client::ClientReq,
@@ -26,7 +34,7 @@ use crate::{
         EventTxFactory, FileEventTx, IncomingFileEventTx, IncomingTransferEventTx,
         OutgoingFileEventTx, OutgoingTransferEventTx, TransferEventTx,
     },
-    File, FileId, FileToRecv, FileToSend, Transfer,
+    File, FileId, FileToRecv, FileToSend, Transfer, TransferStats,
 };
 
 pub struct CloseResult<T: Transfer> {
@@ -39,6 +47,19 @@ pub struct FinishResult<T: Transfer> {
     pub file_events: Arc<FileEventTx<T>>,
 }
 
+pub struct ExpireResult<T: Transfer> {
+    pub xfer_state: FinishTransferState<T>,
+    pub file_events: Vec<Arc<FileEventTx<T>>>,
+}
+
+pub struct FilesAdded<T: Transfer> {
+    /// The transfer with all the files
+    pub xfer: Arc<T>,
+    /// The IDs of the added files, empty when all were already there
+    pub files: Vec<FileId>,
+    pub events: Arc<TransferEventTx<T>>,
+}
+
 pub enum FinishTransferState<T: Transfer> {
     Canceled { events: Arc<TransferEventTx<T>> },
     Alive,
@@ -48,7 +69,9 @@ pub enum OutgoingConnected {
     JustCancelled {
         events: Arc<OutgoingTransferEventTx>,
     },
-    Continue,
+    /// Carries the transfer with the files added since the connection job
+    /// started
+    Continue { xfer: Arc<OutgoingTransfer> },
 }
 
 pub enum IncomingRegistered {
@@ -56,6 +79,8 @@ pub enum IncomingRegistered {
         events: Arc<IncomingTransferEventTx>,
     },
     Continue,
+    /// The resumed request carries the files the sender added in the meantime
+    FilesAdded(FilesAdded<IncomingTransfer>),
     JustCancelled {
         events: Arc<IncomingTransferEventTx>,
     },
@@ -70,10 +95,73 @@ pub enum FileTerminalState {
 
 enum IncomingLocalFileState {
     Idle,
-    InFlight { path: PathBuf },
+    InFlight {
+        path: PathBuf,
+        dest: Option<Arc<DestinationFd>>,
+    },
     Terminal(FileTerminalState),
 }
 
+/// Connection retry state of the outgoing transfer
+#[derive(Debug, Clone)]
+pub struct OutgoingRetryState {
+    pub transfer_id: Uuid,
+    pub peer: IpAddr,
+    /// Number of failed connection attempts since the last successful
+    /// connection
+    pub attempts: u32,
+    /// The time of the next attempt. `None` means the next attempt happens
+    /// only after `network_refresh()`
+    pub next_attempt: Option<SystemTime>,
+    /// The last decision of the connection policy. `None` when there is no
+    /// policy
+    pub decision: Option<ConnectionDecision>,
+}
+
+/// Live state of the file of the active transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLiveStatus {
+    /// Nothing is being transferred
+    Idle,
+    /// Waiting for the upload slot or for the checks preceding the transfer
+    Pending,
+    /// The file data is being transferred
+    InFlight,
+    Completed,
+    Rejected,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSnapshot {
+    pub file_id: FileId,
+    pub status: FileLiveStatus,
+    /// Number of bytes transferred as reported by the last progress event
+    pub transferred: u64,
+    /// Present when the pipeline timing is enabled and any chunk was handled
+    pub timings: Option<StageTimings>,
+}
+
+/// Live state of the active transfer
+#[derive(Debug, Clone)]
+pub struct TransferSnapshot {
+    pub transfer_id: Uuid,
+    pub peer: IpAddr,
+    /// Whether the connection with the peer is established
+    pub connected: bool,
+    /// Number of failed connection attempts since the last successful
+    /// connection. Always `0` for the incoming transfers
+    pub retry_attempts: u32,
+    pub files: Vec<FileSnapshot>,
+}
+
+#[derive(Default)]
+struct RetryInfo {
+    attempts: u32,
+    next_attempt: Option<SystemTime>,
+    decision: Option<ConnectionDecision>,
+}
+
 enum OutgoingLocalFileState {
     Alive,
     Terminal(FileTerminalState),
@@ -87,15 +175,42 @@ pub struct IncomingState {
     file_sync: HashMap<FileId, IncomingLocalFileState>,
     file_events: HashMap<FileId, Arc<IncomingFileEventTx>>,
     pub xfer_events: Arc<IncomingTransferEventTx>,
+    stats: Arc<StatsTracker>,
+    span: tracing::Span,
+    /// All the files were paused by the local user
+    paused: bool,
+    /// The rates the files are paced at by the local user, `0` for the
+    /// paused ones
+    paced: HashMap<FileId, u64>,
 }
 
 pub struct OutgoingState {
     pub xfer: Arc<OutgoingTransfer>,
     conn: Option<UnboundedSender<ClientReq>>,
+    /// Stops the connection/retry job of the transfer
+    job_stop: CancellationToken,
+    /// Makes the connection job retry right away
+    retry_now: Arc<Notify>,
+    retry: RetryInfo,
     xfer_sync: sync::TransferState,
     file_sync: HashMap<FileId, OutgoingLocalFileState>,
     file_events: HashMap<FileId, Arc<OutgoingFileEventTx>>,
     pub xfer_events: Arc<OutgoingTransferEventTx>,
+    stats: Arc<StatsTracker>,
+    span: tracing::Span,
+    /// The receiver acted on some of the files, the transfer can no longer be
+    /// withdrawn
+    accepted: bool,
+    /// The files the receiver started, these do not expire
+    started: HashSet<FileId>,
+    /// The files expired by the transfer deadline
+    expired: HashSet<FileId>,
+    /// The files withdrawn before the receiver started them
+    withdrawn: HashSet<FileId>,
+    /// The receiver reached last understands the `AddFiles` message
+    can_add_files: bool,
+    /// The receiver paused all the files
+    peer_paused: bool,
 }
 
 /// Transfer manager is responsible for keeping track of all ongoing or pending
@@ -106,11 +221,18 @@ pub struct TransferManager {
     storage: Arc<Storage>,
     logger: Logger,
     event_factory: EventTxFactory,
+    totals: TransferTotals,
 }
 
 #[derive(Default)]
 pub struct DirMapping {
     mappings: HashMap<PathBuf, String>,
+    // Root directories in which the empty directories were already recreated
+    entries_created: HashSet<PathBuf>,
+    // Root directories in which the symlinks were already recreated
+    links_created: HashSet<PathBuf>,
+    // Root directories whose whole directory structure was already created
+    skeletons_created: HashSet<PathBuf>,
 }
 
 impl TransferManager {
@@ -121,14 +243,18 @@ impl TransferManager {
             storage,
             logger,
             event_factory,
+            totals: TransferTotals::default(),
         }
     }
 
-    /// Returns `Some()` if the transfer is new one
+    /// Returns `Some()` if the transfer is new one. The resumed request may
+    /// carry more files only when the sender negotiated adding them
     pub async fn register_incoming(
         &self,
         xfer: Arc<IncomingTransfer>,
         conn: UnboundedSender<ServerReq>,
+        can_add_files: bool,
+        config: &DropConfig,
     ) -> anyhow::Result<IncomingRegistered> {
         let mut lock = self.incoming.lock().await;
 
@@ -136,7 +262,7 @@ impl TransferManager {
             Entry::Occupied(mut occ) => {
                 let state = occ.get_mut();
 
-                ensure_resume_matches_existing_transfer(&*xfer, &*state.xfer)?;
+                ensure_resume_matches_existing_transfer(&*xfer, &*state.xfer, can_add_files)?;
 
                 info!(
                     self.logger,
@@ -163,6 +289,46 @@ impl TransferManager {
                         drop(conn)
                     }
                     _ => {
+                        let files: Vec<_> = xfer
+                            .files()
+                            .values()
+                            .filter(|file| !state.xfer.files().contains_key(file.id()))
+                            .cloned()
+                            .collect();
+
+                        let added = match state
+                            .add_sender_files(
+                                files.clone(),
+                                xfer.dir_entries().clone(),
+                                config,
+                                &self.storage,
+                                &self.event_factory,
+                            )
+                            .await
+                        {
+                            Ok(added) => added,
+                            Err(err) => {
+                                warn!(
+                                    self.logger,
+                                    "Failed to add the files to {}: {err}",
+                                    xfer.id()
+                                );
+
+                                // The sender already queued the files, they
+                                // fail there so that both sides agree on the
+                                // transfer
+                                for file in files {
+                                    if let Err(e) = conn.send(ServerReq::Fail {
+                                        file: file.id().clone(),
+                                        msg: err.to_string(),
+                                    }) {
+                                        warn!(self.logger, "Failed to send FAIL request: {e}");
+                                    }
+                                }
+                                Vec::new()
+                            }
+                        };
+
                         state.conn = Some(conn);
 
                         let was_cancelled = state
@@ -175,6 +341,14 @@ impl TransferManager {
                             }
                             FinishTransferState::Alive => (),
                         }
+
+                        if !added.is_empty() {
+                            return Ok(IncomingRegistered::FilesAdded(FilesAdded {
+                                xfer: state.xfer.clone(),
+                                files: added,
+                                events: state.xfer_events.clone(),
+                            }));
+                        }
                     }
                 }
 
@@ -194,10 +368,25 @@ impl TransferManager {
                     return Ok(IncomingRegistered::Continue);
                 }
 
+                self.storage
+                    .insert_incoming_dir_entries(xfer.id(), &Vec::from(xfer.dir_entries()))
+                    .await;
+                if let Some(forwarding) = xfer.forwarding() {
+                    self.storage
+                        .insert_transfer_forwarding(xfer.id(), &forwarding.into())
+                        .await;
+                }
+                if let Some(label) = xfer.label() {
+                    self.storage.insert_transfer_label(xfer.id(), label).await;
+                }
                 self.storage
                     .update_transfer_sync_states(xfer.id(), sync::TransferState::Active)
                     .await;
 
+                let stats = self.totals.incoming_tracker();
+                stats.connection_attempt();
+                let span = spans::transfer(xfer.id(), "incoming");
+
                 let state = vacc.insert(IncomingState {
                     xfer: xfer.clone(),
                     conn: Some(conn),
@@ -214,11 +403,19 @@ impl TransferManager {
                         .map(|file_id| {
                             (
                                 file_id.clone(),
-                                Arc::new(self.event_factory.file(xfer.clone(), file_id.clone())),
+                                Arc::new(self.event_factory.file(
+                                    xfer.clone(),
+                                    file_id.clone(),
+                                    stats.clone(),
+                                )),
                             )
                         })
                         .collect(),
                     xfer_events: Arc::new(self.event_factory.transfer(xfer, false)),
+                    stats,
+                    span,
+                    paused: false,
+                    paced: HashMap::new(),
                 });
 
                 Ok(IncomingRegistered::IsNew {
@@ -233,10 +430,20 @@ impl TransferManager {
         lock.get(&transfer_id).is_some()
     }
 
+    /// The outgoing transfer created from the same request and not yet acted
+    /// on by the receiver
+    pub async fn pending_outgoing(&self, request_key: &str) -> Option<Uuid> {
+        let lock = self.outgoing.lock().await;
+        lock.values()
+            .find(|state| !state.accepted && state.xfer.request_key() == Some(request_key))
+            .map(|state| state.xfer.id())
+    }
+
     pub async fn outgoing_connected(
         &self,
         transfer_id: Uuid,
         conn: UnboundedSender<ClientReq>,
+        can_add_files: bool,
     ) -> crate::Result<OutgoingConnected> {
         let mut lock = self.outgoing.lock().await;
         let state = lock
@@ -251,6 +458,10 @@ impl TransferManager {
             state.xfer_sync = sync::TransferState::Active;
         }
 
+        state.retry = RetryInfo::default();
+        state.stats.connection_attempt();
+        state.can_add_files = can_add_files;
+
         match state.xfer_sync {
             sync::TransferState::Canceled => {
                 debug!(self.logger, "Outgoing transfer is locally cancelled");
@@ -276,7 +487,9 @@ impl TransferManager {
             }
         }
 
-        Ok(OutgoingConnected::Continue)
+        Ok(OutgoingConnected::Continue {
+            xfer: state.xfer.clone(),
+        })
     }
 
     pub async fn insert_outgoing(
@@ -299,10 +512,34 @@ impl TransferManager {
             }
             Entry::Vacant(entry) => {
                 self.storage.insert_transfer(&xfer.storage_info()).await;
+                if let Some(forwarding) = xfer.forwarding() {
+                    self.storage
+                        .insert_transfer_forwarding(xfer.id(), &forwarding.into())
+                        .await;
+                }
+                if let Some(expires_at) = xfer.expires_at() {
+                    self.storage
+                        .insert_transfer_deadline(xfer.id(), utils::unix_millis(expires_at))
+                        .await;
+                }
+                if let Some(label) = xfer.label() {
+                    self.storage.insert_transfer_label(xfer.id(), label).await;
+                }
+                if let Some(request_key) = xfer.request_key() {
+                    self.storage
+                        .insert_transfer_request_key(xfer.id(), request_key)
+                        .await;
+                }
+
+                let stats = self.totals.outgoing_tracker();
+                let span = spans::transfer(xfer.id(), "outgoing");
 
                 entry.insert(OutgoingState {
                     xfer: xfer.clone(),
                     conn: None,
+                    job_stop: CancellationToken::new(),
+                    retry_now: Arc::new(Notify::new()),
+                    retry: RetryInfo::default(),
                     xfer_sync: sync::TransferState::New,
                     file_sync: xfer
                         .files()
@@ -315,11 +552,23 @@ impl TransferManager {
                         .map(|file_id| {
                             (
                                 file_id.clone(),
-                                Arc::new(self.event_factory.file(xfer.clone(), file_id.clone())),
+                                Arc::new(self.event_factory.file(
+                                    xfer.clone(),
+                                    file_id.clone(),
+                                    stats.clone(),
+                                )),
                             )
                         })
                         .collect(),
                     xfer_events: Arc::new(self.event_factory.transfer(xfer, false)),
+                    stats,
+                    span,
+                    accepted: false,
+                    started: HashSet::new(),
+                    expired: HashSet::new(),
+                    withdrawn: HashSet::new(),
+                    can_add_files: false,
+                    peer_paused: false,
                 })
             }
         };
@@ -405,6 +654,58 @@ impl TransferManager {
         })
     }
 
+    /// Withdraws the file the receiver did not start yet and tells the
+    /// receiver about it
+    pub async fn outgoing_withdraw_file(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+    ) -> crate::Result<FinishResult<OutgoingTransfer>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        state.ensure_not_cancelled()?;
+
+        if state.started.contains(file_id) {
+            return Err(crate::Error::BadTransferState(
+                "The file is already started by the peer".into(),
+            ));
+        }
+
+        state
+            .file_sync_mut(file_id)?
+            .try_terminate(FileTerminalState::Rejected)?;
+        state.withdrawn.insert(file_id.clone());
+
+        self.storage
+            .update_outgoing_file_sync_states(
+                state.xfer.id(),
+                file_id.as_ref(),
+                sync::FileState::Terminal,
+            )
+            .await;
+
+        if let Some(conn) = &state.conn {
+            debug!(self.logger, "Pushing file WITHDRAW message: {file_id}");
+
+            if let Err(e) = conn.send(ClientReq::WithdrawFile {
+                file: file_id.clone(),
+            }) {
+                warn!(self.logger, "Failed to send WITHDRAW message: {e}");
+            };
+        }
+
+        Ok(FinishResult {
+            xfer_state: state
+                .cancel_transfer_if_all_files_terminated(&self.logger, &self.storage)
+                .await,
+            file_events: state.file_events(file_id)?.clone(),
+        })
+    }
+
     pub async fn outgoing_terminal_recv(
         &self,
         transfer_id: Uuid,
@@ -486,6 +787,112 @@ impl TransferManager {
         })
     }
 
+    /// Asks the sender to pause or resume all the files of the transfer. The
+    /// state is sent again on every reconnection
+    pub async fn incoming_pause(
+        &self,
+        transfer_id: Uuid,
+        paused: bool,
+    ) -> crate::Result<Arc<IncomingTransferEventTx>> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+        state.ensure_not_cancelled()?;
+
+        if state.paused == paused {
+            return Err(crate::Error::BadTransferState(if paused {
+                "Transfer is already paused".into()
+            } else {
+                "Transfer is not paused".into()
+            }));
+        }
+        state.paused = paused;
+
+        if let Some(conn) = &state.conn {
+            let req = if paused {
+                ServerReq::Pause
+            } else {
+                ServerReq::Resume
+            };
+
+            if let Err(e) = conn.send(req) {
+                warn!(self.logger, "Failed to send pause request: {}", e);
+            }
+        }
+
+        Ok(state.xfer_events.clone())
+    }
+
+    /// Asks the sender to send the file at most at `rate` bytes per second,
+    /// `0` pauses the file and `None` lifts the limit. The request is
+    /// repeated on every reconnection
+    pub async fn incoming_pace(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+        rate: Option<u64>,
+    ) -> crate::Result<()> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+        state.ensure_not_cancelled()?;
+
+        if let IncomingLocalFileState::Terminal(_) = state.file_sync_mut(file_id)? {
+            return Err(crate::Error::BadTransferState(
+                "File is already finished".into(),
+            ));
+        }
+
+        match rate {
+            Some(rate) => state.paced.insert(file_id.clone(), rate),
+            None => state.paced.remove(file_id),
+        };
+
+        if let Some(conn) = &state.conn {
+            let req = ServerReq::Pace {
+                file: file_id.clone(),
+                rate,
+            };
+
+            if let Err(e) = conn.send(req) {
+                warn!(self.logger, "Failed to send pace request: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the incoming transfer withdrawn by the sender, unless some of
+    /// its files were already acted on
+    pub async fn incoming_withdraw(
+        &self,
+        transfer_id: Uuid,
+    ) -> crate::Result<Arc<IncomingTransferEventTx>> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock.get(&transfer_id).ok_or(crate::Error::BadTransfer)?;
+        state.ensure_not_cancelled()?;
+
+        let accepted = state
+            .file_sync
+            .values()
+            .any(|file| !matches!(file, IncomingLocalFileState::Idle));
+        if accepted {
+            return Err(crate::Error::BadTransferState(
+                "Some of the files are already accepted".into(),
+            ));
+        }
+
+        let state = lock.remove(&transfer_id).ok_or(crate::Error::BadTransfer)?;
+        self.storage.transfer_sync_clear(transfer_id).await;
+
+        Ok(state.xfer_events)
+    }
+
     pub async fn incoming_remove(&self, transfer_id: Uuid) -> Option<IncomingState> {
         debug!(self.logger, "Removing incoming transfer: {transfer_id}");
         let mut lock = self.incoming.lock().await;
@@ -504,11 +911,119 @@ impl TransferManager {
         }
     }
 
+    /// The live statistics of the active transfer in either direction
+    pub async fn transfer_stats(&self, transfer_id: Uuid) -> Option<TransferStats> {
+        if let Some(state) = self.incoming.lock().await.get(&transfer_id) {
+            return Some(state.stats.snapshot());
+        }
+
+        self.outgoing
+            .lock()
+            .await
+            .get(&transfer_id)
+            .map(|state| state.stats.snapshot())
+    }
+
+    /// The number of the active transfers and the bytes transferred since the
+    /// start
+    pub async fn counters(&self) -> TransferCounters {
+        TransferCounters {
+            active_incoming: self.incoming.lock().await.len(),
+            active_outgoing: self.outgoing.lock().await.len(),
+            bytes_sent: self.totals.sent(),
+            bytes_received: self.totals.received(),
+        }
+    }
+
+    /// The number of files being transferred right now, in both directions
+    pub(crate) async fn in_flight_files(&self) -> usize {
+        // The file state locks are taken without holding the manager locks
+        let incoming: Vec<_> = {
+            let lock = self.incoming.lock().await;
+            lock.values()
+                .flat_map(|state| state.file_events.values().cloned())
+                .collect()
+        };
+        let outgoing: Vec<_> = {
+            let lock = self.outgoing.lock().await;
+            lock.values()
+                .flat_map(|state| state.file_events.values().cloned())
+                .collect()
+        };
+
+        let mut count = 0;
+        for events in incoming {
+            count += events.is_in_flight().await as usize;
+        }
+        for events in outgoing {
+            count += events.is_in_flight().await as usize;
+        }
+        count
+    }
+
+    /// Checks whether the incoming file is being downloaded. `false` also
+    /// when the transfer is not active anymore
+    pub(crate) async fn is_incoming_file_in_flight(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+    ) -> bool {
+        let lock = self.incoming.lock().await;
+
+        lock.get(&transfer_id)
+            .and_then(|state| state.file_sync.get(file_id))
+            .is_some_and(|fsync| matches!(fsync, IncomingLocalFileState::InFlight { .. }))
+    }
+
+    pub(crate) async fn incoming_stats(&self, transfer_id: Uuid) -> Option<Arc<StatsTracker>> {
+        let lock = self.incoming.lock().await;
+        lock.get(&transfer_id).map(|state| state.stats.clone())
+    }
+
+    pub(crate) async fn outgoing_stats(&self, transfer_id: Uuid) -> Option<Arc<StatsTracker>> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id).map(|state| state.stats.clone())
+    }
+
+    /// The span of the transfer in either direction
+    pub(crate) async fn transfer_span(&self, transfer_id: Uuid) -> Option<tracing::Span> {
+        if let Some(state) = self.incoming.lock().await.get(&transfer_id) {
+            return Some(state.span.clone());
+        }
+
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id).map(|state| state.span.clone())
+    }
+
+    /// Whether the transfer is already known, including the cancelled ones
+    pub async fn is_incoming_known(&self, transfer_id: Uuid) -> bool {
+        self.incoming.lock().await.contains_key(&transfer_id)
+    }
+
+    /// The bytes of the files the peer may still send in the incoming
+    /// transfers already registered
+    pub async fn incoming_pending_bytes(&self, peer: IpAddr) -> u64 {
+        let lock = self.incoming.lock().await;
+
+        lock.values()
+            .filter(|state| state.xfer.peer() == peer)
+            .filter(|state| !matches!(state.xfer_sync, sync::TransferState::Canceled))
+            .flat_map(|state| {
+                state.xfer.files().values().filter(|file| {
+                    !matches!(
+                        state.file_sync.get(file.id()),
+                        Some(IncomingLocalFileState::Terminal(_))
+                    )
+                })
+            })
+            .fold(0, |total, file| total.saturating_add(file.size()))
+    }
+
     pub async fn incoming_finish_post(
         &self,
         transfer_id: Uuid,
         file_id: &FileId,
-        success: Result<(), String>,
+        success: Result<PathBuf, String>,
     ) -> crate::Result<FinishTransferState<IncomingTransfer>> {
         let mut lock = self.incoming.lock().await;
 
@@ -533,7 +1048,13 @@ impl TransferManager {
             let file = file_id.clone();
 
             let (name, serv_req) = match success {
-                Ok(()) => ("DONE", ServerReq::Done { file }),
+                Ok(final_path) => (
+                    "DONE",
+                    ServerReq::Done {
+                        file,
+                        final_path: Some(final_path),
+                    },
+                ),
                 Err(msg) => ("FAIL", ServerReq::Fail { file, msg }),
             };
 
@@ -623,97 +1144,489 @@ impl TransferManager {
             .cancel_transfer_if_all_files_terminated(&self.logger, &self.storage)
             .await;
 
-        Ok(FinishResult {
+        Ok(FinishResult {
+            xfer_state,
+            file_events: state.file_events(file_id)?.clone(),
+        })
+    }
+
+    pub async fn incoming_issue_close(
+        &self,
+        transfer_id: Uuid,
+    ) -> crate::Result<CloseResult<IncomingTransfer>> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        state.ensure_not_cancelled()?;
+        state.cancel_transfer(&self.logger, &self.storage).await;
+
+        for val in state.file_sync.values_mut() {
+            if let IncomingLocalFileState::InFlight { .. } = &*val {
+                *val = IncomingLocalFileState::Idle;
+            }
+        }
+
+        let res = CloseResult {
+            file_events: state.file_events.values().cloned().collect(),
+            xfer_events: state.xfer_events.clone(),
+        };
+
+        Ok(res)
+    }
+
+    pub async fn outgoing_issue_close(
+        &self,
+        transfer_id: Uuid,
+    ) -> crate::Result<CloseResult<OutgoingTransfer>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        match state.xfer_sync {
+            // If it's new, then suppress transfer synchronization by deleting the state
+            sync::TransferState::New => {
+                // The peer was never reached, stop connection retries right away so that
+                // the job does not produce any events after cancellation
+                state.job_stop.cancel();
+                self.storage.transfer_sync_clear(transfer_id).await;
+
+                let res = CloseResult {
+                    file_events: state.file_events.values().cloned().collect(),
+                    xfer_events: state.xfer_events.clone(),
+                };
+
+                lock.remove(&transfer_id);
+
+                Ok(res)
+            }
+            sync::TransferState::Active => {
+                state.cancel_transfer(&self.logger, &self.storage).await;
+
+                Ok(CloseResult {
+                    file_events: state.file_events.values().cloned().collect(),
+                    xfer_events: state.xfer_events.clone(),
+                })
+            }
+            sync::TransferState::Canceled => Err(crate::Error::BadTransfer),
+        }
+    }
+
+    /// Withdraws the outgoing transfer the receiver did not act on yet. When
+    /// the peer was never reached it is just dropped, like when cancelled
+    pub async fn outgoing_withdraw(
+        &self,
+        transfer_id: Uuid,
+    ) -> crate::Result<CloseResult<OutgoingTransfer>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        if state.accepted {
+            return Err(crate::Error::BadTransferState(
+                "The transfer is already accepted by the peer".into(),
+            ));
+        }
+
+        match state.xfer_sync {
+            sync::TransferState::New => {
+                state.job_stop.cancel();
+                self.storage.transfer_sync_clear(transfer_id).await;
+
+                let res = CloseResult {
+                    file_events: state.file_events.values().cloned().collect(),
+                    xfer_events: state.xfer_events.clone(),
+                };
+
+                lock.remove(&transfer_id);
+
+                Ok(res)
+            }
+            sync::TransferState::Active => {
+                state
+                    .finish_transfer(&self.logger, &self.storage, ClientReq::Withdraw)
+                    .await;
+
+                Ok(CloseResult {
+                    file_events: state.file_events.values().cloned().collect(),
+                    xfer_events: state.xfer_events.clone(),
+                })
+            }
+            sync::TransferState::Canceled => Err(crate::Error::BadTransfer),
+        }
+    }
+
+    /// Adds the files to the outgoing transfer the receiver did not act on
+    /// yet. The files already in the transfer are skipped. The receiver
+    /// connected learns about them right away, otherwise with the request sent
+    /// on the next connection
+    pub async fn outgoing_add_files(
+        &self,
+        transfer_id: Uuid,
+        files: Vec<FileToSend>,
+        entries: DirEntries,
+        config: &DropConfig,
+    ) -> crate::Result<FilesAdded<OutgoingTransfer>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        if state.accepted {
+            return Err(crate::Error::BadTransferState(
+                "The transfer is already accepted by the peer".into(),
+            ));
+        }
+
+        match state.xfer_sync {
+            sync::TransferState::New => (),
+            sync::TransferState::Active if state.can_add_files => (),
+            sync::TransferState::Active => {
+                return Err(crate::Error::BadTransferState(
+                    "The peer does not support adding files".into(),
+                ))
+            }
+            sync::TransferState::Canceled => return Err(crate::Error::BadTransfer),
+        }
+
+        let xfer = Arc::new(state.xfer.with_added_files(files, entries, config)?);
+        let added: Vec<_> = xfer
+            .files()
+            .keys()
+            .filter(|file_id| !state.xfer.files().contains_key(*file_id))
+            .cloned()
+            .collect();
+
+        if !added.is_empty() {
+            self.storage
+                .insert_transfer_files(
+                    transfer_id,
+                    &xfer.storage_files(|file_id| added.contains(file_id)),
+                )
+                .await;
+            self.storage.remove_transfer_request_key(transfer_id).await;
+
+            for file_id in &added {
+                state
+                    .file_sync
+                    .insert(file_id.clone(), OutgoingLocalFileState::Alive);
+                state.file_events.insert(
+                    file_id.clone(),
+                    Arc::new(self.event_factory.file(
+                        xfer.clone(),
+                        file_id.clone(),
+                        state.stats.clone(),
+                    )),
+                );
+            }
+
+            state.xfer = xfer;
+
+            if let Some(conn) = &state.conn {
+                debug!(self.logger, "Pushing ADD FILES message");
+                if let Err(e) = conn.send(ClientReq::AddFiles {
+                    xfer: state.xfer.clone(),
+                    files: added.clone(),
+                }) {
+                    warn!(self.logger, "Failed to send ADD FILES message: {e}");
+                }
+            }
+        }
+
+        Ok(FilesAdded {
+            xfer: state.xfer.clone(),
+            files: added,
+            events: state.xfer_events.clone(),
+        })
+    }
+
+    /// Adds the files the sender added to the incoming transfer. The files
+    /// already known are skipped
+    pub async fn incoming_add_files(
+        &self,
+        transfer_id: Uuid,
+        files: Vec<FileToRecv>,
+        entries: DirEntries,
+        config: &DropConfig,
+    ) -> crate::Result<FilesAdded<IncomingTransfer>> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        let added = state
+            .add_sender_files(files, entries, config, &self.storage, &self.event_factory)
+            .await?;
+
+        Ok(FilesAdded {
+            xfer: state.xfer.clone(),
+            files: added,
+            events: state.xfer_events.clone(),
+        })
+    }
+
+    /// Marks the outgoing transfer as accepted by the receiver
+    pub(crate) async fn outgoing_accepted(&self, transfer_id: Uuid) {
+        if let Some(state) = self.outgoing.lock().await.get_mut(&transfer_id) {
+            state.accepted = true;
+        }
+    }
+
+    pub async fn outgoing_ensure_file_not_terminated(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+    ) -> crate::Result<()> {
+        let lock = self.outgoing.lock().await;
+        let state = lock.get(&transfer_id).ok_or(crate::Error::BadTransfer)?;
+        let state = state
+            .file_sync
+            .get(file_id)
+            .ok_or(crate::Error::BadFileId)?;
+
+        state.ensure_not_terminated()
+    }
+
+    /// Marks the file as started by the receiver, so that it does not expire
+    pub async fn outgoing_file_started(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+    ) -> crate::Result<()> {
+        let mut lock = self.outgoing.lock().await;
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+        state.file_sync_mut(file_id)?.ensure_not_terminated()?;
+
+        state.started.insert(file_id.clone());
+        Ok(())
+    }
+
+    /// Fails the files the receiver did not start before the transfer
+    /// deadline and tells the receiver about them
+    pub async fn outgoing_expire(
+        &self,
+        transfer_id: Uuid,
+    ) -> crate::Result<ExpireResult<OutgoingTransfer>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        state.ensure_not_cancelled()?;
+
+        let mut file_events = Vec::new();
+        for (file_id, sync) in &mut state.file_sync {
+            if state.started.contains(file_id)
+                || sync.try_terminate(FileTerminalState::Failed).is_err()
+            {
+                continue;
+            }
+
+            self.storage
+                .update_outgoing_file_sync_states(
+                    transfer_id,
+                    file_id.as_ref(),
+                    sync::FileState::Terminal,
+                )
+                .await;
+
+            if let Some(conn) = &state.conn {
+                debug!(self.logger, "Pushing file EXPIRE message");
+                if let Err(e) = conn.send(ClientReq::Expire {
+                    file: file_id.clone(),
+                }) {
+                    warn!(self.logger, "Failed to send EXPIRE message: {e}");
+                }
+            }
+
+            state.expired.insert(file_id.clone());
+            if let Some(events) = state.file_events.get(file_id) {
+                file_events.push(events.clone());
+            }
+        }
+
+        let xfer_state = state
+            .cancel_transfer_if_all_files_terminated(&self.logger, &self.storage)
+            .await;
+
+        Ok(ExpireResult {
             xfer_state,
-            file_events: state.file_events(file_id)?.clone(),
+            file_events,
         })
     }
 
-    pub async fn incoming_issue_close(
+    pub async fn outgoing_remove(&self, transfer_id: Uuid) -> Option<OutgoingState> {
+        debug!(self.logger, "Removing outgoing transfer: {transfer_id}");
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock.remove(&transfer_id)?;
+        self.storage.transfer_sync_clear(transfer_id).await;
+        Some(state)
+    }
+
+    /// Number of failed connection attempts since the last successful
+    /// connection
+    pub async fn outgoing_retry_attempts(&self, transfer_id: Uuid) -> u32 {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id)
+            .map_or(0, |state| state.retry.attempts)
+    }
+
+    /// Records the failed connection attempt and the delay after which the
+    /// next one happens. Returns the number of the failed attempts so far
+    pub async fn outgoing_retry_scheduled(
         &self,
         transfer_id: Uuid,
-    ) -> crate::Result<CloseResult<IncomingTransfer>> {
-        let mut lock = self.incoming.lock().await;
+        delay: Duration,
+    ) -> Option<u32> {
+        let mut lock = self.outgoing.lock().await;
+        let state = lock.get_mut(&transfer_id)?;
 
-        let state = lock
-            .get_mut(&transfer_id)
-            .ok_or(crate::Error::BadTransfer)?;
+        state.retry.attempts += 1;
+        state.retry.next_attempt = SystemTime::now().checked_add(delay);
+        state.stats.connection_attempt();
 
-        state.ensure_not_cancelled()?;
-        state.cancel_transfer(&self.logger, &self.storage).await;
+        Some(state.retry.attempts)
+    }
 
-        for val in state.file_sync.values_mut() {
-            if let IncomingLocalFileState::InFlight { .. } = &*val {
-                *val = IncomingLocalFileState::Idle;
-            }
+    /// Records the attempt skipped by the connection policy. It is not
+    /// counted as a failed one
+    pub async fn outgoing_retry_postponed(&self, transfer_id: Uuid, delay: Duration) {
+        let mut lock = self.outgoing.lock().await;
+        if let Some(state) = lock.get_mut(&transfer_id) {
+            state.retry.next_attempt = SystemTime::now().checked_add(delay);
         }
-
-        let res = CloseResult {
-            file_events: state.file_events.values().cloned().collect(),
-            xfer_events: state.xfer_events.clone(),
-        };
-
-        Ok(res)
     }
 
-    pub async fn outgoing_issue_close(
+    pub async fn outgoing_connection_decision(
         &self,
         transfer_id: Uuid,
-    ) -> crate::Result<CloseResult<OutgoingTransfer>> {
+        decision: ConnectionDecision,
+    ) {
         let mut lock = self.outgoing.lock().await;
+        if let Some(state) = lock.get_mut(&transfer_id) {
+            state.retry.decision = Some(decision);
+        }
+    }
 
-        let state = lock
-            .get_mut(&transfer_id)
-            .ok_or(crate::Error::BadTransfer)?;
-
-        match state.xfer_sync {
-            // If it's new, then suppress transfer synchronization by deleting the state
-            sync::TransferState::New => {
-                self.storage.transfer_sync_clear(transfer_id).await;
+    /// Returns the connection retry states of the outgoing transfers which are
+    /// not connected to the peer
+    pub async fn outgoing_retry_states(&self) -> Vec<OutgoingRetryState> {
+        let lock = self.outgoing.lock().await;
+        lock.values()
+            .filter(|state| state.conn.is_none())
+            .filter(|state| !matches!(state.xfer_sync, sync::TransferState::Canceled))
+            .map(|state| OutgoingRetryState {
+                transfer_id: state.xfer.id(),
+                peer: state.xfer.peer(),
+                attempts: state.retry.attempts,
+                next_attempt: state.retry.next_attempt,
+                decision: state.retry.decision,
+            })
+            .collect()
+    }
 
-                let res = CloseResult {
-                    file_events: state.file_events.values().cloned().collect(),
-                    xfer_events: state.xfer_events.clone(),
+    /// Returns the live states of the active incoming and outgoing transfers
+    pub async fn snapshot(&self) -> (Vec<TransferSnapshot>, Vec<TransferSnapshot>) {
+        let mut incoming = Vec::new();
+        for state in self.incoming.lock().await.values() {
+            let mut files = Vec::with_capacity(state.file_events.len());
+            for (file_id, events) in &state.file_events {
+                let terminal = match state.file_sync.get(file_id) {
+                    Some(IncomingLocalFileState::Terminal(terminal)) => Some(*terminal),
+                    _ => None,
                 };
+                files.push(file_snapshot(file_id, events, terminal).await);
+            }
 
-                lock.remove(&transfer_id);
+            incoming.push(TransferSnapshot {
+                transfer_id: state.xfer.id(),
+                peer: state.xfer.peer(),
+                connected: state.conn.is_some(),
+                retry_attempts: 0,
+                files,
+            });
+        }
 
-                Ok(res)
+        let mut outgoing = Vec::new();
+        for state in self.outgoing.lock().await.values() {
+            let mut files = Vec::with_capacity(state.file_events.len());
+            for (file_id, events) in &state.file_events {
+                let terminal = match state.file_sync.get(file_id) {
+                    Some(OutgoingLocalFileState::Terminal(terminal)) => Some(*terminal),
+                    _ => None,
+                };
+                files.push(file_snapshot(file_id, events, terminal).await);
             }
-            sync::TransferState::Active => {
-                state.cancel_transfer(&self.logger, &self.storage).await;
 
-                Ok(CloseResult {
-                    file_events: state.file_events.values().cloned().collect(),
-                    xfer_events: state.xfer_events.clone(),
-                })
-            }
-            sync::TransferState::Canceled => Err(crate::Error::BadTransfer),
+            outgoing.push(TransferSnapshot {
+                transfer_id: state.xfer.id(),
+                peer: state.xfer.peer(),
+                connected: state.conn.is_some(),
+                retry_attempts: state.retry.attempts,
+                files,
+            });
         }
+
+        (incoming, outgoing)
     }
 
-    pub async fn outgoing_ensure_file_not_terminated(
-        &self,
-        transfer_id: Uuid,
-        file_id: &FileId,
-    ) -> crate::Result<()> {
+    /// Returns the token used to stop the connection job of the outgoing
+    /// transfer or `None` if the transfer is no longer tracked
+    pub async fn outgoing_job_stop(&self, transfer_id: Uuid) -> Option<CancellationToken> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id).map(|state| state.job_stop.clone())
+    }
+
+    pub async fn outgoing_retry_trigger(&self, transfer_id: Uuid) -> Option<Arc<Notify>> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id).map(|state| state.retry_now.clone())
+    }
+
+    /// Makes the not connected outgoing transfer retry the connection right
+    /// away instead of waiting for the scheduled retry
+    pub async fn outgoing_retry_now(&self, transfer_id: Uuid) -> crate::Result<()> {
         let lock = self.outgoing.lock().await;
+
         let state = lock.get(&transfer_id).ok_or(crate::Error::BadTransfer)?;
-        let state = state
-            .file_sync
-            .get(file_id)
-            .ok_or(crate::Error::BadFileId)?;
+        state.ensure_not_cancelled()?;
 
-        state.ensure_not_terminated()
+        if state.conn.is_some() {
+            return Err(crate::Error::BadTransferState(
+                "The transfer is already connected".into(),
+            ));
+        }
+
+        state.retry_now.notify_one();
+        Ok(())
     }
 
-    pub async fn outgoing_remove(&self, transfer_id: Uuid) -> Option<OutgoingState> {
-        debug!(self.logger, "Removing outgoing transfer: {transfer_id}");
+    /// Records the pause state sent by the receiver. Returns the events when
+    /// it differs from the one known already, the receiver repeats it on
+    /// every connection
+    pub async fn outgoing_peer_paused(
+        &self,
+        transfer_id: Uuid,
+        paused: bool,
+    ) -> Option<Arc<OutgoingTransferEventTx>> {
         let mut lock = self.outgoing.lock().await;
+        let state = lock.get_mut(&transfer_id)?;
 
-        let state = lock.remove(&transfer_id)?;
-        self.storage.transfer_sync_clear(transfer_id).await;
-        Some(state)
+        if std::mem::replace(&mut state.peer_paused, paused) == paused {
+            return None;
+        }
+        Some(state.xfer_events.clone())
     }
 
     pub async fn outgoing_event_tx(
@@ -753,6 +1666,15 @@ impl OutgoingState {
             .file_sync
             .iter()
             .filter_map(|(file_id, state)| match state {
+                OutgoingLocalFileState::Terminal(FileTerminalState::Rejected)
+                    if self.withdrawn.contains(file_id) =>
+                {
+                    info!(logger, "Withdrawing file: {file_id}",);
+
+                    Some(ClientReq::WithdrawFile {
+                        file: file_id.clone(),
+                    })
+                }
                 OutgoingLocalFileState::Terminal(FileTerminalState::Rejected) => {
                     info!(logger, "Rejecting file: {file_id}",);
 
@@ -760,6 +1682,15 @@ impl OutgoingState {
                         file: file_id.clone(),
                     })
                 }
+                OutgoingLocalFileState::Terminal(FileTerminalState::Failed)
+                    if self.expired.contains(file_id) =>
+                {
+                    info!(logger, "Expiring file: {file_id}",);
+
+                    Some(ClientReq::Expire {
+                        file: file_id.clone(),
+                    })
+                }
                 OutgoingLocalFileState::Terminal(FileTerminalState::Failed) => {
                     info!(logger, "Failing file: {file_id}",);
 
@@ -822,6 +1753,12 @@ impl OutgoingState {
     }
 
     async fn cancel_transfer(&mut self, logger: &Logger, storage: &Storage) {
+        self.finish_transfer(logger, storage, ClientReq::Close)
+            .await
+    }
+
+    // The `req` closes the connection, if there is one
+    async fn finish_transfer(&mut self, logger: &Logger, storage: &Storage, req: ClientReq) {
         storage
             .update_transfer_sync_states(
                 self.xfer.id(),
@@ -833,7 +1770,7 @@ impl OutgoingState {
         if let Some(conn) = self.conn.take() {
             debug!(logger, "Pushing outgoing  close request");
 
-            if let Err(e) = conn.send(ClientReq::Close) {
+            if let Err(e) = conn.send(req) {
                 warn!(logger, "Failed to send close request: {}", e);
             }
         }
@@ -841,6 +1778,113 @@ impl OutgoingState {
 }
 
 impl IncomingState {
+    /// Checks if all the files under the given root directory other than the
+    /// `except` one are already terminal
+    pub fn root_files_terminal(&self, root: &str, except: &FileId) -> bool {
+        self.xfer
+            .files()
+            .values()
+            .filter(|file| file.id() != except && file.subpath().root() == root)
+            .all(|file| {
+                matches!(
+                    self.file_sync.get(file.id()),
+                    Some(IncomingLocalFileState::Terminal(_))
+                )
+            })
+    }
+
+    /// Adds the files the sender added to the transfer the receiver did not
+    /// act on yet. The limits apply to the whole transfer. Returns the IDs of
+    /// the added files
+    async fn add_sender_files(
+        &mut self,
+        mut files: Vec<FileToRecv>,
+        entries: DirEntries,
+        config: &DropConfig,
+        storage: &Storage,
+        event_factory: &EventTxFactory,
+    ) -> crate::Result<Vec<FileId>> {
+        self.ensure_not_cancelled()?;
+
+        files.retain(|file| !self.xfer.files().contains_key(file.id()));
+        if files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if self
+            .file_sync
+            .values()
+            .any(|state| !matches!(state, IncomingLocalFileState::Idle))
+        {
+            return Err(crate::Error::BadTransferState(
+                "The transfer is already accepted".into(),
+            ));
+        }
+
+        let xfer = Arc::new(self.xfer.with_added_files(files, entries, config)?);
+        Ok(self.add_files(xfer, storage, event_factory).await)
+    }
+
+    /// Takes the transfer with the files added by the sender in place of the
+    /// current one. Returns the IDs of the added files
+    async fn add_files(
+        &mut self,
+        xfer: Arc<IncomingTransfer>,
+        storage: &Storage,
+        event_factory: &EventTxFactory,
+    ) -> Vec<FileId> {
+        let added: Vec<_> = xfer
+            .files()
+            .keys()
+            .filter(|file_id| !self.xfer.files().contains_key(*file_id))
+            .cloned()
+            .collect();
+
+        if added.is_empty() {
+            return added;
+        }
+
+        storage
+            .insert_transfer_files(
+                xfer.id(),
+                &xfer.storage_files(|file_id| added.contains(file_id)),
+            )
+            .await;
+
+        let old_entries = self.xfer.dir_entries();
+        let entries = DirEntries {
+            empty_dirs: xfer
+                .dir_entries()
+                .empty_dirs
+                .iter()
+                .filter(|dir| !old_entries.empty_dirs.contains(dir))
+                .cloned()
+                .collect(),
+            symlinks: xfer
+                .dir_entries()
+                .symlinks
+                .iter()
+                .filter(|link| !old_entries.symlinks.contains(link))
+                .cloned()
+                .collect(),
+        };
+        storage
+            .insert_incoming_dir_entries(xfer.id(), &Vec::from(&entries))
+            .await;
+
+        for file_id in &added {
+            self.file_sync
+                .insert(file_id.clone(), IncomingLocalFileState::Idle);
+            self.file_events.insert(
+                file_id.clone(),
+                Arc::new(event_factory.file(xfer.clone(), file_id.clone(), self.stats.clone())),
+            );
+        }
+
+        self.xfer = xfer;
+        added
+    }
+
     /// Returs `true` when the new download can be started and `false` in case
     /// the downaload is already happening
     pub fn validate_for_download(&self, file_id: &FileId) -> crate::Result<bool> {
@@ -858,11 +1902,58 @@ impl IncomingState {
         Ok(start)
     }
 
+    /// Creates the directories of all the files sharing the root directory
+    /// with the given file, once per root and destination. Returns the root
+    /// directory name and the path it was created at, `None` when the file is
+    /// not inside of a directory or the structure was already created
+    pub fn create_dir_skeleton(
+        &mut self,
+        file_id: &FileId,
+        dest_dir: &Path,
+    ) -> crate::Result<Option<(String, PathBuf)>> {
+        let subpath = self.xfer.files()[file_id].subpath();
+        if subpath.len() < 2
+            || !self
+                .dir_mappings
+                .mark_skeleton_created(dest_dir, subpath.root())
+        {
+            return Ok(None);
+        }
+
+        let mut dirs = BTreeSet::new();
+        for file in self.xfer.files().values() {
+            let file_subpath = file.subpath();
+            if file_subpath.len() < 2 || file_subpath.root() != subpath.root() {
+                continue;
+            }
+
+            let path = self
+                .dir_mappings
+                .compose_final_path(dest_dir, file_subpath)?;
+            if let Some(parent) = path.parent() {
+                dirs.insert(dest_dir.join(parent));
+            }
+        }
+
+        for dir in &dirs {
+            fs::create_dir_all(dir)?;
+        }
+
+        let root = self.dir_mappings.compose_final_path(dest_dir, subpath)?;
+        let root = root
+            .components()
+            .next()
+            .ok_or_else(|| crate::Error::BadPath("Missing root directory".into()))?;
+
+        Ok(Some((subpath.root().clone(), dest_dir.join(root))))
+    }
+
     pub async fn start_download(
         &mut self,
         storage: &Storage,
         file_id: &FileId,
         parent_dir: &Path,
+        dest: Option<Arc<DestinationFd>>,
         logger: &Logger,
     ) -> crate::Result<()> {
         let state = self.file_sync_mut(file_id)?;
@@ -870,6 +1961,7 @@ impl IncomingState {
         state.ensure_not_terminated()?;
         *state = IncomingLocalFileState::InFlight {
             path: parent_dir.to_path_buf(),
+            dest: dest.clone(),
         };
 
         storage
@@ -883,7 +1975,7 @@ impl IncomingState {
         let file = &self.xfer.files()[file_id];
 
         if let Some(conn) = &self.conn {
-            let task = FileXferTask::new(file.clone(), self.xfer.clone(), parent_dir.into());
+            let task = FileXferTask::new(file.clone(), self.xfer.clone(), parent_dir.into(), dest);
 
             debug!(logger, "Pushing download request: file_id {file_id}");
 
@@ -909,15 +2001,42 @@ impl IncomingState {
     }
 
     fn issue_pending_requests(&self, conn: &UnboundedSender<ServerReq>, logger: &Logger) {
+        // Before anything is started. The sender may still hold the state of
+        // the previous connection, so the resumed one is sent too
+        let req = if self.paused {
+            info!(logger, "Pausing transfer: {}", self.xfer.id());
+            ServerReq::Pause
+        } else {
+            ServerReq::Resume
+        };
+        if let Err(e) = conn.send(req) {
+            warn!(logger, "Failed to send pause request: {}", e);
+        }
+
+        for (file_id, rate) in &self.paced {
+            let req = ServerReq::Pace {
+                file: file_id.clone(),
+                rate: Some(*rate),
+            };
+            if let Err(e) = conn.send(req) {
+                warn!(logger, "Failed to send pace request: {}", e);
+            }
+        }
+
         let iter = self
             .file_sync
             .iter()
             .filter_map(|(file_id, state)| match state {
-                IncomingLocalFileState::InFlight { path } => {
+                IncomingLocalFileState::InFlight { path, dest } => {
                     info!(logger, "Resuming file: {file_id}",);
 
                     let xfile = &self.xfer.files()[file_id];
-                    let task = FileXferTask::new(xfile.clone(), self.xfer.clone(), path.into());
+                    let task = FileXferTask::new(
+                        xfile.clone(),
+                        self.xfer.clone(),
+                        path.into(),
+                        dest.clone(),
+                    );
                     Some(ServerReq::Download {
                         task: Box::new(task),
                     })
@@ -934,6 +2053,7 @@ impl IncomingState {
 
                     Some(ServerReq::Done {
                         file: file_id.clone(),
+                        final_path: None,
                     })
                 }
                 IncomingLocalFileState::Terminal(FileTerminalState::Failed) => {
@@ -1071,6 +2191,24 @@ impl DirMapping {
         Ok(mapped)
     }
 
+    /// Returns `true` if the empty directories under the given root were not
+    /// recreated yet and marks them as recreated
+    pub fn mark_entries_created(&mut self, dest_dir: &Path, root: &str) -> bool {
+        self.entries_created.insert(dest_dir.join(root))
+    }
+
+    /// Returns `true` if the symlinks under the given root were not recreated
+    /// yet and marks them as recreated
+    pub fn mark_links_created(&mut self, dest_dir: &Path, root: &str) -> bool {
+        self.links_created.insert(dest_dir.join(root))
+    }
+
+    /// Returns `true` if the directory structure under the given root was not
+    /// created yet and marks it as created
+    pub fn mark_skeleton_created(&mut self, dest_dir: &Path, root: &str) -> bool {
+        self.skeletons_created.insert(dest_dir.join(root))
+    }
+
     fn register_preexisting_final_path(
         &mut self,
         file_subpath: &FileSubPath,
@@ -1119,18 +2257,69 @@ impl OutgoingLocalFileState {
     }
 }
 
-pub(crate) async fn restore_transfers_state(state: &Arc<State>, logger: &Logger) {
+/// Restores the transfers from the storage. The storage states left
+/// unfinished by the previous run are reconciled: the files in progress are
+/// marked as paused and the transfers which can't be restored as failed
+pub(crate) async fn restore_transfers_state(
+    state: &Arc<State>,
+    logger: &Logger,
+) -> StartupReconciliation {
+    let mut summary = StartupReconciliation::default();
+
     let incoming = restore_incoming(
         &state.transfer_manager.event_factory,
+        &state.transfer_manager.totals,
         &state.storage,
         &state.config,
         logger,
+        &mut summary,
     )
     .await;
     *state.transfer_manager.incoming.lock().await = incoming;
 
-    let outgoing = restore_outgoing(state, logger).await;
+    let outgoing = restore_outgoing(state, logger, &mut summary).await;
     *state.transfer_manager.outgoing.lock().await = outgoing;
+
+    summary
+}
+
+/// The live state of the file reported in the instance state snapshot
+async fn file_snapshot<T: Transfer>(
+    file_id: &FileId,
+    events: &FileEventTx<T>,
+    terminal: Option<FileTerminalState>,
+) -> FileSnapshot {
+    let (status, transferred) = events.live_status().await;
+
+    let status = match terminal {
+        Some(FileTerminalState::Completed) => FileLiveStatus::Completed,
+        Some(FileTerminalState::Rejected) => FileLiveStatus::Rejected,
+        Some(FileTerminalState::Failed) => FileLiveStatus::Failed,
+        None => status,
+    };
+
+    FileSnapshot {
+        file_id: file_id.clone(),
+        status,
+        transferred,
+        timings: events.timer().timings(),
+    }
+}
+
+/// Reconciles the transfer restored from the storage
+async fn reconcile_restored(storage: &Storage, id: Uuid, summary: &mut StartupReconciliation) {
+    summary.paused_files += storage.pause_interrupted_paths(id).await;
+}
+
+/// Reconciles the transfer which failed to be restored, so it's not picked up
+/// again on the next startup
+async fn reconcile_dead(storage: &Storage, id: Uuid, summary: &mut StartupReconciliation) {
+    let status = drop_core::Status::Interrupted as u32;
+
+    summary.failed_files += storage.fail_unfinished_paths(id, status).await;
+    storage.insert_transfer_failed_state(id, status).await;
+    storage.transfer_sync_clear(id).await;
+    summary.failed_transfers += 1;
 }
 
 pub(crate) async fn resume(
@@ -1144,6 +2333,14 @@ pub(crate) async fn resume(
         let xfers = state.transfer_manager.outgoing.lock().await;
 
         for xstate in xfers.values() {
+            expiry::spawn(
+                state.clone(),
+                xstate.xfer.clone(),
+                logger.clone(),
+                guard.clone(),
+                stop.clone(),
+            );
+
             let trig = refresh_trigger.clone();
             ws::client::spawn(
                 trig,
@@ -1175,30 +2372,47 @@ pub(crate) async fn resume(
 
 async fn restore_incoming(
     factory: &EventTxFactory,
+    totals: &TransferTotals,
     storage: &Storage,
     config: &DropConfig,
     logger: &Logger,
+    summary: &mut StartupReconciliation,
 ) -> HashMap<Uuid, IncomingState> {
     let transfers = storage.incoming_transfers_to_resume().await;
 
     let mut xfers = HashMap::new();
     for transfer in transfers {
+        let id = transfer.uuid;
         let restore_transfer = async {
             let files = transfer
                 .files
                 .into_iter()
                 .map(|dbfile| {
                     FileToRecv::new(dbfile.file_id.into(), dbfile.subpath.into(), dbfile.size)
+                        .with_metadata(dbfile.metadata.into())
                 })
                 .collect();
 
-            let xfer = IncomingTransfer::new_with_uuid(
-                transfer.peer.parse().context("Failed to parse peer IP")?,
-                files,
-                transfer.uuid,
-                config,
-            )
-            .context("Failed to create transfer")?;
+            let (peer, peer_scope_id, peer_port) =
+                utils::parse_peer(&transfer.peer).context("Failed to parse peer IP")?;
+            let peer_port = match peer_port {
+                Some(port) => Some(port),
+                None => storage.peer_listen_port(&peer.to_string()).await,
+            };
+
+            let xfer = IncomingTransfer::new_with_uuid(peer, files, transfer.uuid, config)
+                .and_then(|xfer| xfer.with_dir_entries(transfer.dir_entries.into(), config))
+                .context("Failed to create transfer")?
+                .with_peer_scope_id(peer_scope_id)
+                .with_peer_port(peer_port)
+                .with_forwarding(
+                    storage
+                        .transfer_forwarding(transfer.uuid)
+                        .await
+                        .map(Into::into),
+                )
+                .with_label(storage.transfer_label(transfer.uuid).await)
+                .context("Failed to restore transfer label")?;
 
             let sync = storage
                 .transfer_sync_state(xfer.id())
@@ -1234,16 +2448,26 @@ async fn restore_incoming(
             let in_flights = storage.incoming_files_to_resume(xfer.id()).await;
 
             for file in in_flights {
+                // The descriptor of the destination does not outlive the
+                // process, the download is left idle for the app to start
+                // again
+                if utils::is_uri(&file.base_dir) {
+                    continue;
+                }
+
                 if let Some(state) = file_sync.get_mut(&file.file_id) {
                     if state.ensure_not_terminated().is_ok() {
                         *state = IncomingLocalFileState::InFlight {
                             path: file.base_dir.into(),
+                            dest: None,
                         };
                     }
                 }
             }
 
             let xfer = Arc::new(xfer);
+            let stats = totals.incoming_tracker();
+            let span = spans::transfer(xfer.id(), "incoming");
             let mut xstate = IncomingState {
                 xfer: xfer.clone(),
                 conn: None,
@@ -1256,7 +2480,7 @@ async fn restore_incoming(
                     .map(|file_id| {
                         (
                             file_id.clone(),
-                            Arc::new(factory.file(xfer.clone(), file_id.clone())),
+                            Arc::new(factory.file(xfer.clone(), file_id.clone(), stats.clone())),
                         )
                     })
                     .collect(),
@@ -1264,6 +2488,10 @@ async fn restore_incoming(
                     xfer,
                     matches!(sync.local_state, sync::TransferState::Canceled),
                 )),
+                stats,
+                span,
+                paused: false,
+                paced: HashMap::new(),
             };
 
             debug!(
@@ -1286,13 +2514,12 @@ async fn restore_incoming(
 
         match restore_transfer.await {
             Ok(xstate) => {
-                xfers.insert(xstate.xfer.id(), xstate);
+                reconcile_restored(storage, id, summary).await;
+                xfers.insert(id, xstate);
             }
             Err(err) => {
-                error!(
-                    logger,
-                    "Failed to restore transfer {}: {err:?}", transfer.uuid
-                );
+                error!(logger, "Failed to restore transfer {id}: {err:?}");
+                reconcile_dead(storage, id, summary).await;
             }
         }
     }
@@ -1300,11 +2527,16 @@ async fn restore_incoming(
     xfers
 }
 
-async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid, OutgoingState> {
+async fn restore_outgoing(
+    state: &Arc<State>,
+    logger: &Logger,
+    summary: &mut StartupReconciliation,
+) -> HashMap<Uuid, OutgoingState> {
     let transfers = state.storage.outgoing_transfers_to_resume().await;
 
     let mut xfers = HashMap::new();
     for transfer in transfers {
+        let id = transfer.uuid;
         let restore_transfer = || async move {
             let files = transfer
                 .files
@@ -1312,13 +2544,30 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                 .map(|dbfile| restore_outgoing_file(state, dbfile))
                 .collect::<Result<_, _>>()?;
 
-            let xfer = OutgoingTransfer::new_with_uuid(
-                transfer.peer.parse().context("Failed to parse peer IP")?,
-                files,
-                transfer.uuid,
-                &state.config,
-            )
-            .context("Failed to create transfer")?;
+            let (peer, peer_scope_id, peer_port) =
+                utils::parse_peer(&transfer.peer).context("Failed to parse peer IP")?;
+
+            let xfer = OutgoingTransfer::new_with_uuid(peer, files, transfer.uuid, &state.config)
+                .context("Failed to create transfer")?
+                .with_peer_scope_id(peer_scope_id)
+                .with_peer_port(peer_port)
+                .with_forwarding(
+                    state
+                        .storage
+                        .transfer_forwarding(transfer.uuid)
+                        .await
+                        .map(Into::into),
+                )
+                .with_expiry(
+                    state
+                        .storage
+                        .transfer_deadline(transfer.uuid)
+                        .await
+                        .map(utils::from_unix_millis),
+                )
+                .with_request_key(state.storage.transfer_request_key(transfer.uuid).await)
+                .with_label(state.storage.transfer_label(transfer.uuid).await)
+                .context("Failed to restore transfer label")?;
 
             let sync = state
                 .storage
@@ -1327,6 +2576,9 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                 .context("Missing sync state for transfer")?;
 
             let mut file_sync = HashMap::new();
+            let mut started = HashSet::new();
+            let mut expired = HashSet::new();
+            let mut withdrawn = HashSet::new();
             for file_id in xfer.files().keys() {
                 let state = state
                     .storage
@@ -1334,6 +2586,16 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                     .await
                     .context("Missing sync state for file")?;
 
+                if state.is_started {
+                    started.insert(file_id.clone());
+                }
+                if state.failed_status == Some(drop_core::Status::Expired as _) {
+                    expired.insert(file_id.clone());
+                }
+                if state.is_withdrawn {
+                    withdrawn.insert(file_id.clone());
+                }
+
                 let local = if state.is_rejected {
                     OutgoingLocalFileState::Terminal(FileTerminalState::Rejected)
                 } else if state.is_success {
@@ -1352,10 +2614,24 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                 file_sync.insert(file_id.clone(), local);
             }
 
+            // The finished files mean the receiver accepted the transfer. Otherwise
+            // the receiver decides when the withdrawal arrives. The files withdrawn
+            // or expired here do not count
+            let accepted = file_sync.iter().any(|(file_id, state)| {
+                matches!(state, OutgoingLocalFileState::Terminal(_))
+                    && !withdrawn.contains(file_id)
+                    && !expired.contains(file_id)
+            });
+
             let xfer = Arc::new(xfer);
+            let stats = state.transfer_manager.totals.outgoing_tracker();
+            let span = spans::transfer(xfer.id(), "outgoing");
             let xstate = OutgoingState {
                 xfer: xfer.clone(),
                 conn: None,
+                job_stop: CancellationToken::new(),
+                retry_now: Arc::new(Notify::new()),
+                retry: RetryInfo::default(),
                 xfer_sync: sync.local_state,
                 file_sync,
                 file_events: xfer
@@ -1364,12 +2640,11 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                     .map(|file_id| {
                         (
                             file_id.clone(),
-                            Arc::new(
-                                state
-                                    .transfer_manager
-                                    .event_factory
-                                    .file(xfer.clone(), file_id.clone()),
-                            ),
+                            Arc::new(state.transfer_manager.event_factory.file(
+                                xfer.clone(),
+                                file_id.clone(),
+                                stats.clone(),
+                            )),
                         )
                     })
                     .collect(),
@@ -1377,19 +2652,26 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                     xfer,
                     matches!(sync.local_state, sync::TransferState::Canceled),
                 )),
+                stats,
+                span,
+                accepted,
+                started,
+                expired,
+                withdrawn,
+                can_add_files: false,
+                peer_paused: false,
             };
             anyhow::Ok(xstate)
         };
 
         match restore_transfer().await {
             Ok(xstate) => {
-                xfers.insert(xstate.xfer.id(), xstate);
+                reconcile_restored(&state.storage, id, summary).await;
+                xfers.insert(id, xstate);
             }
             Err(err) => {
-                error!(
-                    logger,
-                    "Failed to restore transfer {}: {err}", transfer.uuid
-                );
+                error!(logger, "Failed to restore transfer {id}: {err}");
+                reconcile_dead(&state.storage, id, summary).await;
             }
         }
     }
@@ -1404,6 +2686,8 @@ fn restore_outgoing_file(state: &State, dbfile: OutgoingFileToRetry) -> anyhow::
     let uri = dbfile.uri;
     let size = dbfile.size as u64;
 
+    let app_metadata = dbfile.app_metadata;
+
     let file = match uri.scheme() {
         "file" => {
             let fullpath = uri
@@ -1425,7 +2709,7 @@ fn restore_outgoing_file(state: &State, dbfile: OutgoingFileToRetry) -> anyhow::
         unknown => anyhow::bail!("Unknon URI schema: {unknown}"),
     };
 
-    anyhow::Ok(file)
+    anyhow::Ok(file.with_app_metadata(app_metadata))
 }
 
 fn extract_directory_mapping(
@@ -1453,19 +2737,22 @@ fn extract_directory_mapping(
 fn ensure_resume_matches_existing_transfer<T: Transfer>(
     current: &T,
     existing: &T,
+    can_add_files: bool,
 ) -> anyhow::Result<()> {
-    // Check if the transfer matches
+    // Check if the transfer matches. The sender supporting it may have added
+    // the files since
     anyhow::ensure!(current.peer() == existing.peer(), "Peers do not match",);
     anyhow::ensure!(
-        current.files().len() == existing.files().len(),
+        current.files().len() == existing.files().len()
+            || can_add_files && current.files().len() > existing.files().len(),
         "File count does not match"
     );
 
     anyhow::ensure!(
-        current
+        existing
             .files()
             .iter()
-            .all(|(key, val)| existing.files().get(key).map_or(false, |v| {
+            .all(|(key, val)| current.files().get(key).map_or(false, |v| {
                 val.id() == v.id() && val.size() == v.size() && val.mime_type() == v.mime_type()
             })),
         "Files do not match"
@@ -1489,4 +2776,90 @@ mod tests {
         assert_eq!(path, Path::new("/home/xyz/foo/bar/a"));
         assert_eq!(name, "a(2)");
     }
+
+    #[tokio::test]
+    async fn startup_reconciliation() {
+        use drop_storage::types::{
+            DbTransferType, FileMetadata, IncomingPathStateEventData, TransferFiles,
+            TransferIncomingPath, TransferInfo, TransferStateEventData,
+        };
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger.clone(), ":memory:").unwrap();
+        let (events, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(events, drop_analytics::moose_mock());
+        let config = DropConfig::default();
+
+        let insert = |id: Uuid, peer: &str| {
+            let transfer = TransferInfo {
+                id,
+                peer: peer.to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".into(),
+                    relative_path: "file".to_string(),
+                    size: 1024,
+                    metadata: FileMetadata::default(),
+                }]),
+            };
+            let storage = &storage;
+            async move { storage.insert_transfer(&transfer).await }
+        };
+
+        let alive = Uuid::new_v4();
+        let dead = Uuid::new_v4();
+        insert(alive, "1.2.3.4").await;
+        insert(dead, "not a peer").await;
+        storage
+            .insert_incoming_path_started_state(alive, "id1", 512)
+            .await;
+
+        let restore = || async {
+            let mut summary = StartupReconciliation::default();
+            let xfers = restore_incoming(
+                &factory,
+                &TransferTotals::default(),
+                &storage,
+                &config,
+                &logger,
+                &mut summary,
+            )
+            .await;
+            (xfers, summary)
+        };
+
+        let (xfers, summary) = restore().await;
+        assert!(xfers.contains_key(&alive));
+        assert!(!xfers.contains_key(&dead));
+        assert_eq!(
+            summary,
+            StartupReconciliation {
+                paused_files: 1,
+                failed_transfers: 1,
+                failed_files: 1,
+            }
+        );
+
+        let failed = storage.transfer(dead).await.unwrap();
+        assert!(matches!(
+            failed.states.last().map(|state| &state.data),
+            Some(TransferStateEventData::Failed { status_code })
+                if *status_code == drop_core::Status::Interrupted as i64
+        ));
+
+        let paused = storage.transfer(alive).await.unwrap();
+        let DbTransferType::Incoming(paths) = &paused.transfer_type else {
+            panic!("Expected incoming transfer");
+        };
+        assert!(matches!(
+            paths[0].states.last().map(|state| &state.data),
+            Some(IncomingPathStateEventData::Paused {
+                bytes_received: 512
+            })
+        ));
+
+        // Nothing is left to reconcile on the next startup
+        let (xfers, summary) = restore().await;
+        assert!(xfers.contains_key(&alive));
+        assert!(summary.is_empty());
+    }
 }
@@ -0,0 +1,95 @@
+//! The preflight of the outgoing transfer, checking all the source files are
+//! readable before the request is sent. The unreadable files are failed up
+//! front instead of when the receiver asks for them
+
+use std::sync::Arc;
+
+use futures::{future, StreamExt};
+use slog::{warn, Logger};
+
+use crate::{service::State, transfer::Transfer, ws, Event, FileId, OutgoingTransfer};
+
+// The number of the files checked at once
+const PARALLELISM: usize = 8;
+
+pub(crate) async fn run(state: &State, logger: &Logger, xfer: &Arc<OutgoingTransfer>) {
+    let unreadable = unreadable_files(xfer).await;
+
+    state.emit_event(
+        logger,
+        Event::OutgoingPreflightFinished {
+            transfer_id: xfer.id(),
+            unreadable: unreadable
+                .iter()
+                .map(|(file_id, _)| file_id.clone())
+                .collect(),
+        },
+    );
+
+    for (file_id, err) in unreadable {
+        warn!(logger, "File {file_id} is not readable: {err}");
+
+        match state
+            .transfer_manager
+            .outgoing_failure_post(xfer.id(), &file_id, err.to_string())
+            .await
+        {
+            Ok(res) => {
+                res.file_events.failed(err).await;
+                ws::client::handle_finish_xfer_state(res.xfer_state, false).await;
+            }
+            Err(err) => warn!(
+                logger,
+                "Failed to fail the unreadable file {file_id}: {err}"
+            ),
+        }
+    }
+}
+
+async fn unreadable_files(xfer: &Arc<OutgoingTransfer>) -> Vec<(FileId, crate::Error)> {
+    futures::stream::iter(xfer.files().keys().cloned())
+        .map(|file_id| {
+            let xfer = xfer.clone();
+            tokio::task::spawn_blocking(move || {
+                let err = xfer.files()[&file_id].check_readable().err()?;
+                Some((file_id, err))
+            })
+        })
+        .buffer_unordered(PARALLELISM)
+        .filter_map(|res| future::ready(res.ok().flatten()))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use drop_config::DropConfig;
+
+    use super::*;
+    use crate::{file::FileSubPath, FileToSend};
+
+    #[tokio::test]
+    async fn unreadable() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(b"data").unwrap();
+
+        let file = |name: &str, path| {
+            FileToSend::new(FileSubPath::from(name), path, 4, FileId::from(name))
+        };
+        let xfer = OutgoingTransfer::new(
+            "127.0.0.1".parse().unwrap(),
+            vec![
+                file("readable", tmp.path().to_path_buf()),
+                file("missing", tmp.path().with_extension("missing")),
+            ],
+            &DropConfig::default(),
+        )
+        .unwrap();
+
+        let unreadable = unreadable_files(&Arc::new(xfer)).await;
+        assert_eq!(unreadable.len(), 1);
+        assert_eq!(unreadable[0].0, FileId::from("missing"));
+    }
+}
@@ -0,0 +1,313 @@
+//! Negotiation of the optional protocol features. The client lists the
+//! features it supports in the `x-drop-capabilities` header of the WebSocket
+//! upgrade request and the server answers with the ones both peers are going
+//! to use. The names unknown to the peer are ignored, so a new feature is
+//! added as a new capability and not as a new protocol version.
+//!
+//! The peers predating the header negotiate every feature with its own header.
+//! These are still sent and understood when the capabilities header is absent
+//!
+//! The headers themselves are not protected by the authentication, so each
+//! side sends the MAC of its capabilities in the `x-drop-capabilities-mac`
+//! header, bound to the `authorization` header of the same handshake. The
+//! peers which sent the MAC once must keep sending it, otherwise stripping it
+//! together with the capabilities would turn e.g. the chunk encryption off
+
+use std::{fmt, net::IpAddr};
+
+use hyper::{header::HeaderValue, HeaderMap};
+
+use super::v6;
+use crate::auth;
+
+pub const CAPABILITIES_HEADER: &str = "x-drop-capabilities";
+pub const CAPABILITIES_MAC_HEADER: &str = "x-drop-capabilities-mac";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// The chunks are sealed with XChaCha20-Poly1305
+    ChunkEncryption,
+    /// The chunks carry the CRC32 trailer
+    ChunkCrc,
+    /// The partial files are compared with BLAKE3 when resuming
+    ResumeChecksum,
+    /// The ranges of a file are uploaded at the same time and written at the
+    /// offsets from the CRC32 trailer, used together with `ChunkCrc` only
+    ParallelRanges,
+    /// The receiver paces or pauses the chunks of a single file
+    FilePacing,
+    /// The sender tells the receiver about the files expired by the transfer
+    /// deadline
+    FileExpiry,
+    /// The sender adds the files to the transfer not yet acted on by the
+    /// receiver
+    FileAddition,
+    /// The sender withdraws the single files the receiver did not start
+    FileWithdrawal,
+    /// The receiver pauses and resumes all the files of the transfer at once
+    TransferPause,
+}
+
+impl Capability {
+    pub const ALL: [Self; 9] = [
+        Self::ChunkEncryption,
+        Self::ChunkCrc,
+        Self::ResumeChecksum,
+        Self::ParallelRanges,
+        Self::FilePacing,
+        Self::FileExpiry,
+        Self::FileAddition,
+        Self::FileWithdrawal,
+        Self::TransferPause,
+    ];
+
+    /// The name in the `x-drop-capabilities` header. The algorithm is a part
+    /// of the name so that changing it is a new capability
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ChunkEncryption => "chunk-encryption-xchacha20poly1305",
+            Self::ChunkCrc => "chunk-crc32",
+            Self::ResumeChecksum => "resume-blake3",
+            Self::ParallelRanges => "parallel-ranges",
+            Self::FilePacing => "file-pacing",
+            Self::FileExpiry => "file-expiry",
+            Self::FileAddition => "file-addition",
+            Self::FileWithdrawal => "file-withdrawal",
+            Self::TransferPause => "transfer-pause",
+        }
+    }
+
+    // The header and the value of the older peers, the features added after
+    // the capabilities header have none
+    fn legacy_header(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Self::ChunkEncryption => Some((v6::CHUNK_ENCRYPTION_HEADER, v6::CHUNK_ENCRYPTION_ALG)),
+            Self::ChunkCrc => Some((v6::CHUNK_CRC_HEADER, v6::CHUNK_CRC_ALG)),
+            Self::ResumeChecksum => Some((v6::RESUME_CHECKSUM_HEADER, v6::RESUME_CHECKSUM_ALG)),
+            Self::ParallelRanges
+            | Self::FilePacing
+            | Self::FileExpiry
+            | Self::FileAddition
+            | Self::FileWithdrawal
+            | Self::TransferPause => None,
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// The set of the optional features
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub fn with(self, cap: Capability, enabled: bool) -> Self {
+        if enabled {
+            Self(self.0 | cap.bit())
+        } else {
+            Self(self.0 & !cap.bit())
+        }
+    }
+
+    pub fn contains(self, cap: Capability) -> bool {
+        self.0 & cap.bit() != 0
+    }
+
+    /// The features supported by both sides
+    pub fn negotiate(self, peer: Self) -> Self {
+        Self(self.0 & peer.0)
+    }
+
+    /// Parses the comma separated list of the capability names, skipping the
+    /// unknown ones
+    pub fn parse(value: &str) -> Self {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter_map(|name| Capability::ALL.into_iter().find(|cap| cap.name() == name))
+            .fold(Self::default(), |caps, cap| caps.with(cap, true))
+    }
+
+    /// Reads the capabilities of the peer from the request or the response
+    /// headers
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        if let Some(value) = headers.get(CAPABILITIES_HEADER) {
+            return value.to_str().map(Self::parse).unwrap_or_default();
+        }
+
+        Capability::ALL
+            .into_iter()
+            .fold(Self::default(), |caps, cap| {
+                let enabled = cap
+                    .legacy_header()
+                    .is_some_and(|(header, alg)| headers.get(header).is_some_and(|v| v == alg));
+                caps.with(cap, enabled)
+            })
+    }
+
+    /// Writes the capabilities together with the headers of the older peers
+    pub fn insert_headers(self, headers: &mut HeaderMap) {
+        if let Ok(value) = HeaderValue::from_str(&self.to_string()) {
+            headers.insert(CAPABILITIES_HEADER, value);
+        }
+
+        for (header, alg) in self.iter().filter_map(Capability::legacy_header) {
+            headers.insert(header, HeaderValue::from_static(alg));
+        }
+    }
+
+    /// Writes the MAC of the capabilities bound to the `authorization` header
+    /// value of the handshake. Skipped when the keys are not available
+    pub(crate) fn insert_mac(
+        self,
+        headers: &mut HeaderMap,
+        auth: &auth::Context,
+        peer: IpAddr,
+        from_client: bool,
+        authorization: &str,
+    ) {
+        let mac = auth
+            .capabilities_mac(peer, from_client, authorization, &self.to_string())
+            .and_then(|mac| HeaderValue::from_str(&mac).ok());
+
+        if let Some(mac) = mac {
+            headers.insert(CAPABILITIES_MAC_HEADER, mac);
+        }
+    }
+
+    /// Checks the MAC of the capabilities the peer sent in the headers. `None`
+    /// when the peer sent no MAC, as the peers predating it do
+    pub(crate) fn verify_mac(
+        headers: &HeaderMap,
+        auth: &auth::Context,
+        peer: IpAddr,
+        from_client: bool,
+        authorization: &str,
+    ) -> Option<bool> {
+        let mac = headers.get(CAPABILITIES_MAC_HEADER)?;
+
+        let caps = headers
+            .get(CAPABILITIES_HEADER)
+            .map_or(Some(""), |value| value.to_str().ok());
+        let verified = match (caps, mac.to_str()) {
+            (Some(caps), Ok(mac)) => {
+                auth.verify_capabilities_mac(peer, from_client, authorization, caps, mac)
+            }
+            _ => false,
+        };
+
+        Some(verified)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Capability> {
+        Capability::ALL
+            .into_iter()
+            .filter(move |cap| self.contains(*cap))
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, cap) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(cap.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let local = Capabilities::default()
+            .with(Capability::ChunkEncryption, true)
+            .with(Capability::ChunkCrc, true);
+
+        let mut headers = HeaderMap::new();
+        local.insert_headers(&mut headers);
+        assert_eq!(
+            headers[CAPABILITIES_HEADER],
+            "chunk-encryption-xchacha20poly1305, chunk-crc32"
+        );
+        assert_eq!(Capabilities::from_headers(&headers), local);
+
+        let peer = Capabilities::parse("chunk-crc32,compression-zstd, resume-blake3");
+        let negotiated = local.negotiate(peer);
+        assert!(negotiated.contains(Capability::ChunkCrc));
+        assert!(!negotiated.contains(Capability::ChunkEncryption));
+        assert!(!negotiated.contains(Capability::ResumeChecksum));
+    }
+
+    #[test]
+    fn legacy_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            v6::RESUME_CHECKSUM_HEADER,
+            HeaderValue::from_static(v6::RESUME_CHECKSUM_ALG),
+        );
+        headers.insert(v6::CHUNK_CRC_HEADER, HeaderValue::from_static("crc64"));
+        headers.insert("x-drop-parallel-ranges", HeaderValue::from_static("1"));
+
+        let caps = Capabilities::from_headers(&headers);
+        assert_eq!(
+            caps.iter().collect::<Vec<_>>(),
+            [Capability::ResumeChecksum]
+        );
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mac() {
+        let client_secret = drop_auth::SecretKey::from([1; 32]);
+        let server_secret = drop_auth::SecretKey::from([2; 32]);
+        let client_public = drop_auth::PublicKey::from(&client_secret);
+        let server_public = drop_auth::PublicKey::from(&server_secret);
+
+        let client = auth::Context::new(
+            move || Some(client_secret.clone()),
+            move |_| Some(server_public),
+        );
+        let server = auth::Context::new(
+            move || Some(server_secret.clone()),
+            move |_| Some(client_public),
+        );
+        let peer = IpAddr::from([127, 0, 0, 1]);
+
+        let caps = Capabilities::default().with(Capability::ChunkEncryption, true);
+        let mut headers = HeaderMap::new();
+        caps.insert_headers(&mut headers);
+        caps.insert_mac(&mut headers, &client, peer, true, "drop ticket");
+
+        assert_eq!(
+            Capabilities::verify_mac(&headers, &server, peer, true, "drop ticket"),
+            Some(true)
+        );
+        // Bound to the handshake and to the side
+        assert_eq!(
+            Capabilities::verify_mac(&headers, &server, peer, true, "drop other"),
+            Some(false)
+        );
+        assert_eq!(
+            Capabilities::verify_mac(&headers, &server, peer, false, "drop ticket"),
+            Some(false)
+        );
+
+        // Stripping the capabilities does not go unnoticed
+        headers.remove(CAPABILITIES_HEADER);
+        assert_eq!(
+            Capabilities::verify_mac(&headers, &server, peer, true, "drop ticket"),
+            Some(false)
+        );
+
+        headers.remove(CAPABILITIES_MAC_HEADER);
+        assert_eq!(
+            Capabilities::verify_mac(&headers, &server, peer, true, "drop ticket"),
+            None
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! The name and the platform the device declares about itself. Both peers send
+//! them in the headers of the WebSocket upgrade request and response, so that
+//! the apps can show the peer by its name instead of the address. The values
+//! are self-declared and only as trustworthy as the peer itself.
+//!
+//! The header values are the raw UTF-8 bytes, the control characters are
+//! dropped and the values are truncated to `MAX_LEN` bytes on both sides.
+
+use hyper::{header::HeaderValue, HeaderMap};
+
+pub const DEVICE_NAME_HEADER: &str = "x-drop-device-name";
+pub const PLATFORM_HEADER: &str = "x-drop-platform";
+
+const MAX_LEN: usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerDevice {
+    pub name: Option<String>,
+    pub platform: Option<String>,
+}
+
+impl PeerDevice {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        for (key, value) in [
+            (DEVICE_NAME_HEADER, &self.name),
+            (PLATFORM_HEADER, &self.platform),
+        ] {
+            let value = value
+                .as_deref()
+                .and_then(sanitize)
+                .and_then(|value| HeaderValue::from_bytes(value.as_bytes()).ok());
+
+            if let Some(value) = value {
+                headers.insert(key, value);
+            }
+        }
+    }
+
+    /// The device declared by the peer, `None` when it declared nothing
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |key| {
+            let value = std::str::from_utf8(headers.get(key)?.as_bytes()).ok()?;
+            sanitize(value)
+        };
+
+        let device = Self {
+            name: get(DEVICE_NAME_HEADER),
+            platform: get(PLATFORM_HEADER),
+        };
+
+        (device.name.is_some() || device.platform.is_some()).then_some(device)
+    }
+}
+
+impl From<&PeerDevice> for drop_storage::PeerDevice {
+    fn from(value: &PeerDevice) -> Self {
+        Self {
+            name: value.name.clone(),
+            platform: value.platform.clone(),
+        }
+    }
+}
+
+fn sanitize(value: &str) -> Option<String> {
+    let mut clean = String::new();
+    for c in value.trim().chars().filter(|c| !c.is_control()) {
+        if clean.len() + c.len_utf8() > MAX_LEN {
+            break;
+        }
+        clean.push(c);
+    }
+
+    let clean = clean.trim_end();
+    (!clean.is_empty()).then(|| clean.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exchange() {
+        let local = PeerDevice {
+            name: Some("Alice’s laptop\n".to_string()),
+            platform: Some("linux".to_string()),
+        };
+
+        let mut headers = HeaderMap::new();
+        local.insert_headers(&mut headers);
+        assert_eq!(
+            PeerDevice::from_headers(&headers),
+            Some(PeerDevice {
+                name: Some("Alice’s laptop".to_string()),
+                platform: Some("linux".to_string()),
+            })
+        );
+
+        let mut headers = HeaderMap::new();
+        PeerDevice::default().insert_headers(&mut headers);
+        assert!(headers.is_empty());
+        assert_eq!(PeerDevice::from_headers(&headers), None);
+
+        // Truncated on the character boundary
+        headers.insert(
+            DEVICE_NAME_HEADER,
+            HeaderValue::from_bytes("ż".repeat(40).as_bytes()).unwrap(),
+        );
+        let device = PeerDevice::from_headers(&headers).unwrap();
+        assert_eq!(device.name.unwrap(), "ż".repeat(32));
+        assert_eq!(device.platform, None);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod caps;
+pub mod device;
 pub mod v6;
 
 #[derive(Copy, Clone, strum::Display, strum::EnumString)]
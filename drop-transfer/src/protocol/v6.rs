@@ -14,7 +14,8 @@
 //! * server (receiver) ->   client (sender): `Progress (file)`
 //!
 //! This message indicate that the file is downloaded. Can be sent without
-//! `Start` in case the downloaded file is already there
+//! `Start` in case the downloaded file is already there. If the receiver
+//! reports the final paths, it carries the location the file was saved at
 //! * server (receiver) ->   client (sender): `Done (file)`
 //!
 //! There is also a posibility to delete file from the transfer (reject)
@@ -23,12 +24,131 @@
 //! * client (receiver) ->   server (sender): `Reject (file)`
 //! The operation cannot be undone and subsequest downloads of this file
 //! will result in error
+//!
+//! The sender can withdraw the transfer as long as the receiver did not act
+//! on any of its files. The receiver then drops the request instead of
+//! reporting it as canceled. Otherwise it is treated as a regular cancel
+//! * client (sender)   -> server (receiver): `Withdraw`
+//!
+//! The receiver can pause all the files of the transfer at once, e.g. to free
+//! the bandwidth for a while. The sender stops sending the chunks until
+//! resumed, the files stay started on both sides. Requires the
+//! `transfer-pause` capability. The receiver sends its state once after
+//! reconnecting, so the sender learns about the changes made in the meantime
+//! * server (receiver) ->   client (sender): `Pause`
+//! * server (receiver) ->   client (sender): `Resume`
+//!
+//! The receiver can also pace a single file, e.g. when its destination disk
+//! is slow or the battery saver kicks in. The sender holds the file's chunks
+//! back to the given rate in bytes per second, `0` pauses the file and no
+//! rate lifts the limit. Requires the `file-pacing` capability. The receiver
+//! repeats it after reconnecting
+//! * server (receiver) ->   client (sender): `Pace (file)`
+//!
+//! The sender can give the receiver a deadline to start the files. The files
+//! not started by then expire on both sides and cannot be downloaded anymore.
+//! Requires the `file-expiry` capability, the older receivers get an `Error`
+//! for each of the files instead
+//! * client (sender)   -> server (receiver): `Expire (file)`
+//!
+//! The sender can add the files to the transfer as long as the receiver did
+//! not act on any of its files. The message carries the added files and
+//! directory entries only, the receiver appends them to the request. The
+//! files already known are ignored, so it can be repeated. The sender
+//! reconnecting later sends the whole request with the added files, which the
+//! receiver accepts in place of the one it has. Requires the `file-addition`
+//! capability, the older receivers can get the added files only with the
+//! first request
+//! * client (sender)   -> server (receiver): `AddFiles`
+//!
+//! The sender can also withdraw a single file the receiver did not start. The
+//! receiver marks it as withdrawn, so it cannot be downloaded anymore, unlike
+//! the canceled file which is only stopped. The sender repeats it after
+//! reconnecting. Requires the `file-withdrawal` capability, the older
+//! receivers get a `Reject (file)` instead
+//! * client (sender)   -> server (receiver): `WithdrawFile (file)`
+//!
+//! # Capabilities
+//!
+//! The optional features are negotiated with the `x-drop-capabilities` header
+//! of the WebSocket upgrade request and the response, the comma separated list
+//! of the feature names. The client lists what it supports and the server
+//! answers with the features used on the connection, see `caps`. Both also
+//! send the per-feature headers below for the older peers
+//!
+//! # Chunk encryption
+//!
+//! When both peers enable it, the client requests it with the
+//! `chunk-encryption-xchacha20poly1305` capability, or the
+//! `x-drop-chunk-encryption` header of the WebSocket upgrade request, and the
+//! server confirms it the same way in the response. The `data` of every
+//! `Chunk` is then `nonce || ciphertext` sealed with XChaCha20-Poly1305. The
+//! key is derived from the peers' X25519 keys and the transfer ID, the file
+//! ID and the chunk offset are the associated data so the chunks cannot be
+//! reordered or moved between files.
+//!
+//! # Chunk CRC
+//!
+//! Negotiated the same way with the `chunk-crc32` capability or the
+//! `x-drop-chunk-crc` header. The `data` of every `Chunk` is followed by the
+//! chunk offset and the CRC32 of both, as little endian `u64` and `u32`. The
+//! trailer covers the encrypted data if the encryption is used as well. When
+//! the CRC does not match, the server asks for the given range of the file
+//! again while the rest of the file keeps streaming. The retransmitted chunks
+//! are placed by their offsets
+//! * server (receiver) ->   client (sender): `Retransmit (file)`
+//!
+//! # Resume checksum
+//!
+//! Negotiated the same way with the `resume-blake3` capability or the
+//! `x-drop-resume-checksum` header. The `ReqChsum` for a part of the file,
+//! asked when resuming the partially downloaded file, is then answered with
+//! the BLAKE3 checksum in place of the SHA-256. The checksum of the whole file
+//! stays SHA-256
+//!
+//! # Parallel ranges
+//!
+//! Negotiated with the `parallel-ranges` capability only, and used only
+//! together with the chunk CRC. The client may then split a big file into
+//! ranges and send their chunks interleaved on the connection. The server
+//! writes every chunk at the offset from its CRC trailer. A corrupted chunk
+//! cannot be placed, so the missing parts are asked for once the chunks stop
+//! coming
+//!
+//! # Clock skew
+//!
+//! Both the WebSocket upgrade request and the response carry the sender's
+//! wall-clock time in the `x-drop-time` header as unix milliseconds. Each peer
+//! estimates how far the other's clock is off and stores it with the
+//! transfer. The server ignores the network delay, the client uses the middle
+//! of the round trip. Peers not sending the header are not estimated
+//!
+//! # Previews
+//!
+//! The files of the `TransferRequest` may carry the base64 encoded `preview`,
+//! a small thumbnail generated by the sender for the images and videos. The
+//! previews longer than `MAX_PREVIEW_SIZE` bytes, and the ones past
+//! `MAX_TOTAL_PREVIEW_SIZE` bytes of all the previews of the request, are
+//! dropped by the receiver, the transfer goes on without them. Older peers
+//! ignore the field
+//!
+//! # Busy server
+//!
+//! The server with too many connections open answers the WebSocket upgrade
+//! request with `503 Service Unavailable` and the `Retry-After` header. The
+//! client keeps the transfer and retries later, as with any other connection
+//! failure
 
 use anyhow::Context;
+use base64::prelude::*;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    file::{File as _, FileSubPath},
+    file::{DirEntries, File as _, FileMetadata, FileSubPath, FileToSend, MAX_PREVIEW_SIZE},
     transfer::Transfer,
     FileId, OutgoingTransfer,
 };
@@ -38,12 +158,38 @@ pub struct File {
     pub path: FileSubPath,
     pub id: FileId,
     pub size: u64,
+    #[serde(flatten)]
+    pub metadata: FileMetadata,
+    // Base64 encoded thumbnail of the image or video shown to the receiver
+    // before accepting, at most `MAX_PREVIEW_SIZE` bytes once decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 pub struct TransferRequest {
     pub files: Vec<File>,
     pub id: uuid::Uuid,
+    // The empty directories and the symlinks, omitted when there are none
+    #[serde(flatten)]
+    pub entries: DirEntries,
+    // The sender left the hidden files out of the directories
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden_files_skipped: bool,
+    // The aggregate size of the files, lets the receiver check its limits
+    // before going through the file list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<u64>,
+    // The transfer ID given by the original sender when passing through a
+    // relay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_id: Option<uuid::Uuid>,
+    // Asks the receiver, acting as a relay, to forward the files to this peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_to: Option<String>,
+    // The opaque label given by the sender's app
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
@@ -72,6 +218,8 @@ pub struct Progress<T = FileId> {
 pub struct Done {
     pub file: FileId,
     pub bytes_transfered: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
@@ -96,6 +244,39 @@ pub struct Reject {
     pub file: FileId,
 }
 
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct Expire {
+    pub file: FileId,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct WithdrawFile {
+    pub file: FileId,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct AddFiles {
+    pub files: Vec<File>,
+    // The empty directories and the symlinks of the added directories
+    #[serde(flatten)]
+    pub entries: DirEntries,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct Retransmit {
+    pub file: FileId,
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct Pace {
+    pub file: FileId,
+    // Bytes per second, `None` lifts the limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 #[serde(tag = "type")]
 pub enum ServerMsg {
@@ -106,6 +287,10 @@ pub enum ServerMsg {
     Start(Start),
     Cancel(Cancel),
     Reject(Reject),
+    Retransmit(Retransmit),
+    Pause,
+    Resume,
+    Pace(Pace),
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
@@ -115,6 +300,10 @@ pub enum ClientMsg {
     Error(Error<FileId>),
     Cancel(Cancel),
     Reject(Reject),
+    Withdraw,
+    Expire(Expire),
+    AddFiles(AddFiles),
+    WithdrawFile(WithdrawFile),
 }
 
 pub struct Chunk<T = FileId> {
@@ -165,11 +354,13 @@ where
         let file = file.to_string();
 
         let len = file.len() as u32;
-        len.to_le_bytes()
-            .into_iter()
-            .chain(file.into_bytes())
-            .chain(data)
-            .collect()
+
+        // A single copy of the data into the exactly sized frame
+        let mut msg = Vec::with_capacity(std::mem::size_of::<u32>() + file.len() + data.len());
+        msg.extend_from_slice(&len.to_le_bytes());
+        msg.extend_from_slice(file.as_bytes());
+        msg.extend_from_slice(&data);
+        msg
     }
 }
 
@@ -182,19 +373,164 @@ where
     }
 }
 
+pub const CHUNK_ENCRYPTION_HEADER: &str = "x-drop-chunk-encryption";
+pub const CHUNK_ENCRYPTION_ALG: &str = "xchacha20poly1305";
+
+pub const CHUNK_CRC_HEADER: &str = "x-drop-chunk-crc";
+pub const CHUNK_CRC_ALG: &str = "crc32";
+
+pub const RESUME_CHECKSUM_HEADER: &str = "x-drop-resume-checksum";
+pub const RESUME_CHECKSUM_ALG: &str = "blake3";
+
+pub const CLOCK_HEADER: &str = "x-drop-time";
+
+/// The wall-clock time as sent in the `x-drop-time` header
+pub fn clock_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as _)
+}
+
+/// Parses the `x-drop-time` header value
+pub fn parse_clock(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// The port the client listens on, so that the server can dial it back when
+/// the client's port differs from its own
+pub const LISTEN_PORT_HEADER: &str = "x-drop-listen-port";
+
+/// Parses the `x-drop-listen-port` header value
+pub fn parse_listen_port(value: &[u8]) -> Option<u16> {
+    std::str::from_utf8(value)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|port| *port != 0)
+}
+
+const XNONCE_LEN: usize = 24;
+
+const CRC_TRAILER_LEN: usize = std::mem::size_of::<u64>() + std::mem::size_of::<u32>();
+
+/// Appends the chunk offset and the CRC32 of the data and the offset
+pub fn append_chunk_crc(data: &mut Vec<u8>, offset: u64) {
+    data.extend_from_slice(&offset.to_le_bytes());
+    let crc = crc32fast::hash(data);
+    data.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// Checks and strips the trailer added with [`append_chunk_crc`]. Returns the
+/// chunk offset along with the data
+pub fn strip_chunk_crc(mut data: Vec<u8>) -> crate::Result<(u64, Vec<u8>)> {
+    if data.len() < CRC_TRAILER_LEN {
+        return Err(crate::Error::ChunkCorrupted);
+    }
+
+    let offset_start = data.len() - CRC_TRAILER_LEN;
+    let crc_start = offset_start + std::mem::size_of::<u64>();
+
+    let crc = u32::from_le_bytes(data[crc_start..].try_into().expect("Invalid u32 size"));
+    if crc32fast::hash(&data[..crc_start]) != crc {
+        return Err(crate::Error::ChunkCorrupted);
+    }
+
+    let chunk_offset = u64::from_le_bytes(
+        data[offset_start..crc_start]
+            .try_into()
+            .expect("Invalid u64 size"),
+    );
+
+    data.truncate(offset_start);
+    Ok((chunk_offset, data))
+}
+
+/// Seals and opens the chunk data
+pub struct ChunkCipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl ChunkCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub fn seal(&self, file: &FileId, offset: u64, data: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = Self::associated_data(file, offset);
+
+        let sealed = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .expect("Chunk encryption should not fail");
+
+        nonce.into_iter().chain(sealed).collect()
+    }
+
+    pub fn open(&self, file: &FileId, offset: u64, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(data.len() >= XNONCE_LEN, "Encrypted chunk too short");
+
+        let (nonce, sealed) = data.split_at(XNONCE_LEN);
+        let aad = Self::associated_data(file, offset);
+
+        self.aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .ok()
+            .context("Failed to authenticate the chunk")
+    }
+
+    fn associated_data(file: &FileId, offset: u64) -> Vec<u8> {
+        let file = file.to_string();
+
+        (file.len() as u32)
+            .to_le_bytes()
+            .into_iter()
+            .chain(file.into_bytes())
+            .chain(offset.to_le_bytes())
+            .collect()
+    }
+}
+
+impl From<&FileToSend> for File {
+    fn from(value: &FileToSend) -> Self {
+        Self {
+            path: value.subpath().clone(),
+            id: value.id().clone(),
+            size: value.size(),
+            metadata: value.metadata().clone(),
+            preview: value
+                .preview()
+                .filter(|preview| preview.len() <= MAX_PREVIEW_SIZE)
+                .map(|preview| BASE64_STANDARD.encode(preview)),
+        }
+    }
+}
+
 impl From<&OutgoingTransfer> for TransferRequest {
     fn from(value: &OutgoingTransfer) -> Self {
         Self {
-            files: value
-                .files()
-                .values()
-                .map(|f| File {
-                    path: f.subpath().clone(),
-                    id: f.id().clone(),
-                    size: f.size(),
-                })
-                .collect(),
+            files: value.files().values().map(File::from).collect(),
             id: value.id(),
+            entries: value.dir_entries().clone(),
+            hidden_files_skipped: value.hidden_files_skipped(),
+            total_size: Some(value.total_size()),
+            origin_id: value.forwarding().map(|fwd| fwd.origin_id),
+            forward_to: value.forwarding().and_then(|fwd| fwd.forward_to.clone()),
+            label: value.label().map(ToString::to_string),
         }
     }
 }
@@ -235,6 +571,58 @@ mod tests {
         assert_eq!(data, FILE_CONTNET);
     }
 
+    #[test]
+    fn chunk_encryption() {
+        let file = FileId::from("ESDW8PFTBoD8UYaqxMSWp6FBCZN3SKnhyHFqlhrdMzU");
+        let other = FileId::from("Unk8pL5Kq1ZRqgCvVL6Vx7WJEVVEcZmfZ7jGGq1Sj9g");
+        let cipher = ChunkCipher::new(&[7; 32]);
+
+        let sealed = cipher.seal(&file, 1024, b"test file content");
+        assert_ne!(cipher.seal(&file, 1024, b"test file content"), sealed);
+
+        let opened = cipher.open(&file, 1024, &sealed).expect("Failed to open");
+        assert_eq!(opened, b"test file content");
+
+        // Reordered, moved to another file, tampered with or sealed with another key
+        assert!(cipher.open(&file, 0, &sealed).is_err());
+        assert!(cipher.open(&other, 1024, &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(cipher.open(&file, 1024, &tampered).is_err());
+
+        assert!(ChunkCipher::new(&[8; 32])
+            .open(&file, 1024, &sealed)
+            .is_err());
+        assert!(cipher.open(&file, 1024, &sealed[..10]).is_err());
+    }
+
+    #[test]
+    fn chunk_crc() {
+        let mut data = b"test file content".to_vec();
+        append_chunk_crc(&mut data, 1024);
+        assert_eq!(data.len(), 17 + 12);
+
+        assert_eq!(
+            strip_chunk_crc(data.clone()).unwrap(),
+            (1024, b"test file content".to_vec())
+        );
+
+        // Corrupted
+        let mut corrupted = data.clone();
+        corrupted[3] ^= 1;
+        assert!(strip_chunk_crc(corrupted).is_err());
+
+        let mut corrupted = data.clone();
+        corrupted[data.len() - 8] ^= 1;
+        assert!(strip_chunk_crc(corrupted).is_err());
+        assert!(strip_chunk_crc(data[..8].to_vec()).is_err());
+
+        let mut empty = Vec::new();
+        append_chunk_crc(&mut empty, 0);
+        assert_eq!(strip_chunk_crc(empty).unwrap(), (0, Vec::new()));
+    }
+
     fn test_json<T: Serialize + DeserializeOwned + Eq>(message: T, expected: &str) {
         let json_msg = serde_json::to_value(&message).expect("Failed to serialize");
         let json_exp: serde_json::Value =
@@ -254,14 +642,29 @@ mod tests {
                         path: "dir/a.txt".into(),
                         id: "ID1".into(),
                         size: 41,
+                        metadata: FileMetadata::default(),
+                        preview: None,
                     },
                     File {
                         path: "dir/b.txt".into(),
                         id: "ID2".into(),
                         size: 4141,
+                        metadata: FileMetadata {
+                            mime_type: Some("text/plain".to_string()),
+                            mtime: Some(1700000000000),
+                            permissions: Some(0o644),
+                            app_metadata: [("album".to_string(), "Summer".to_string())].into(),
+                        },
+                        preview: Some("iVBORw0K".to_string()),
                     },
                 ],
                 id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                entries: DirEntries::default(),
+                hidden_files_skipped: false,
+                total_size: None,
+                origin_id: None,
+                forward_to: None,
+                label: None,
             },
             r#"
             {
@@ -274,13 +677,66 @@ mod tests {
                 {
                   "path": "dir/b.txt",
                   "id": "ID2",
-                  "size": 4141
+                  "size": 4141,
+                  "mime_type": "text/plain",
+                  "mtime": 1700000000000,
+                  "permissions": 420,
+                  "app_metadata": {
+                    "album": "Summer"
+                  },
+                  "preview": "iVBORw0K"
                 }
               ],
               "id": "1b0397eb-66e9-4252-b7cf-71782698ee3d"
             }"#,
         );
 
+        test_json(
+            TransferRequest {
+                files: vec![File {
+                    path: "dir/a.txt".into(),
+                    id: "ID1".into(),
+                    size: 41,
+                    metadata: FileMetadata::default(),
+                    preview: None,
+                }],
+                id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                entries: DirEntries {
+                    empty_dirs: vec!["dir/empty".into()],
+                    symlinks: vec![crate::file::Symlink {
+                        path: "dir/link".into(),
+                        target: "a.txt".into(),
+                    }],
+                },
+                hidden_files_skipped: true,
+                total_size: Some(41),
+                origin_id: None,
+                forward_to: None,
+                label: Some("chat-42".to_string()),
+            },
+            r#"
+            {
+              "files": [
+                {
+                  "path": "dir/a.txt",
+                  "id": "ID1",
+                  "size": 41
+                }
+              ],
+              "id": "1b0397eb-66e9-4252-b7cf-71782698ee3d",
+              "empty_dirs": ["dir/empty"],
+              "symlinks": [
+                {
+                  "path": "dir/link",
+                  "target": "a.txt"
+                }
+              ],
+              "hidden_files_skipped": true,
+              "total_size": 41,
+              "label": "chat-42"
+            }"#,
+        );
+
         test_json(
             ClientMsg::ReportChsum(ReportChsum {
                 file: FileId::from("TESTID"),
@@ -351,6 +807,128 @@ mod tests {
             }
             "#,
         );
+
+        test_json(
+            ClientMsg::Withdraw,
+            r#"
+            {
+              "type": "Withdraw"
+            }
+            "#,
+        );
+
+        test_json(
+            ClientMsg::Expire(Expire {
+                file: FileId::from("TESTID"),
+            }),
+            r#"
+            {
+              "type": "Expire",
+              "file": "TESTID"
+            }
+            "#,
+        );
+
+        test_json(
+            ClientMsg::AddFiles(AddFiles {
+                files: vec![File {
+                    path: "dir/c.txt".into(),
+                    id: "ID3".into(),
+                    size: 41,
+                    metadata: FileMetadata::default(),
+                    preview: None,
+                }],
+                entries: DirEntries {
+                    empty_dirs: vec!["dir/empty".into()],
+                    symlinks: Vec::new(),
+                },
+            }),
+            r#"
+            {
+              "type": "AddFiles",
+              "files": [
+                {
+                  "path": "dir/c.txt",
+                  "id": "ID3",
+                  "size": 41
+                }
+              ],
+              "empty_dirs": ["dir/empty"]
+            }
+            "#,
+        );
+
+        test_json(
+            ClientMsg::WithdrawFile(WithdrawFile {
+                file: FileId::from("TESTID"),
+            }),
+            r#"
+            {
+              "type": "WithdrawFile",
+              "file": "TESTID"
+            }
+            "#,
+        );
+
+        test_json(
+            ServerMsg::Retransmit(Retransmit {
+                file: FileId::from("TESTID"),
+                offset: 41,
+                len: 1024,
+            }),
+            r#"
+            {
+              "type": "Retransmit",
+              "file": "TESTID",
+              "offset": 41,
+              "len": 1024
+            }"#,
+        );
+
+        test_json(
+            ServerMsg::Pause,
+            r#"
+            {
+              "type": "Pause"
+            }
+            "#,
+        );
+
+        test_json(
+            ServerMsg::Resume,
+            r#"
+            {
+              "type": "Resume"
+            }
+            "#,
+        );
+
+        test_json(
+            ServerMsg::Pace(Pace {
+                file: FileId::from("TESTID"),
+                rate: Some(65536),
+            }),
+            r#"
+            {
+              "type": "Pace",
+              "file": "TESTID",
+              "rate": 65536
+            }
+            "#,
+        );
+
+        test_json(
+            ServerMsg::Pace(Pace {
+                file: FileId::from("TESTID"),
+                rate: None,
+            }),
+            r#"
+            {
+              "type": "Pace",
+              "file": "TESTID"
+            }
+            "#,
+        );
     }
 
     #[test]
@@ -372,6 +950,7 @@ mod tests {
             ServerMsg::Done(Done {
                 file: FileId::from("TESTID"),
                 bytes_transfered: 41,
+                final_path: None,
             }),
             r#"
             {
@@ -381,6 +960,21 @@ mod tests {
             }"#,
         );
 
+        test_json(
+            ServerMsg::Done(Done {
+                file: FileId::from("TESTID"),
+                bytes_transfered: 41,
+                final_path: Some("/home/user/Downloads/a.txt".to_string()),
+            }),
+            r#"
+            {
+              "type": "Done",
+              "file": "TESTID",
+              "bytes_transfered": 41,
+              "final_path": "/home/user/Downloads/a.txt"
+            }"#,
+        );
+
         test_json(
             ServerMsg::Error(Error {
                 file: Some(FileId::from("TESTID")),
@@ -1,8 +1,12 @@
 use std::{
-    fs,
-    net::IpAddr,
-    path::{Component, Path},
-    sync::Arc,
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, Weak,
+    },
     time::{Duration, Instant, SystemTime},
 };
 
@@ -10,21 +14,80 @@ use drop_analytics::{InitEventData, Moose, TransferStateEventData};
 use drop_config::DropConfig;
 use drop_core::Status;
 use drop_storage::Storage;
-use slog::{debug, info, trace, Logger};
-use tokio::sync::{mpsc, Semaphore};
+use slog::{debug, info, trace, warn, Logger};
+use tokio::sync::{mpsc, watch, Semaphore};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
-    auth,
+    alias, auth, diagnostics,
     error::ResultExt,
+    event::RefusedOperation,
+    expiry,
+    file::{DirEntries, HashAlg},
+    forward, inbox,
     manager::{self},
+    preflight,
+    protocol::{
+        caps::{Capabilities, Capability},
+        v6::ChunkCipher,
+    },
+    share,
     tasks::{AliveGuard, AliveWaiter},
+    text,
     transfer::Transfer,
+    utils::{self, Hidden},
+    validate,
     ws::{self, EventTxFactory},
-    Error, Event, FileId, TransferManager,
+    DestinationFd, Error, Event, FileId, FileToSend, IncomingTransfer, TransferManager,
 };
 
+/// The app's decision about the outgoing connection attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDecision {
+    /// Connect to the peer
+    Allow,
+    /// Skip this attempt, the connection is retried later
+    Postpone,
+    /// Fail the transfer without connecting
+    Deny,
+}
+
+/// Consulted before each outgoing connection attempt with the peer's address
+/// and the transfer ID
+pub type ConnectionPolicy = dyn Fn(IpAddr, Uuid) -> ConnectionDecision + Send + Sync;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncomingDecision {
+    /// Register the transfer and report it as usual
+    Accept,
+    /// Close the connection, the peer sees the transfer as cancelled
+    Reject,
+    /// Drop the connection without telling the peer anything
+    Drop,
+}
+
+/// Snapshot of the running instance
+#[derive(Debug, Clone)]
+pub struct InstanceSnapshot {
+    /// The addresses the server is bound to
+    pub addrs: Vec<IpAddr>,
+    pub read_only: bool,
+    pub low_power: bool,
+    pub incoming: Vec<manager::TransferSnapshot>,
+    pub outgoing: Vec<manager::TransferSnapshot>,
+}
+
+// How often the in-flight files are checked while stopping gracefully
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How often the connections using the previous secret key are checked after
+// the key rotation
+const KEY_ROTATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consulted when a new transfer request arrives, before the transfer is
+/// registered and reported
+pub type IncomingFilter = dyn Fn(&IncomingTransfer) -> IncomingDecision + Send + Sync;
+
 pub(super) struct State {
     pub(super) event_tx: mpsc::UnboundedSender<(Event, SystemTime)>,
     pub(super) transfer_manager: TransferManager,
@@ -33,16 +96,230 @@ pub(super) struct State {
     pub(crate) config: Arc<DropConfig>,
     pub(crate) storage: Arc<Storage>,
     pub(crate) throttle: Arc<Semaphore>,
-    pub(crate) addr: IpAddr,
+    pub(crate) addrs: Vec<IpAddr>,
+    // The addresses the listeners are bound to, set once they are
+    pub(crate) listen_addrs: OnceLock<Vec<SocketAddr>>,
+    pub(crate) low_power: watch::Sender<bool>,
+    pub(crate) read_only: AtomicBool,
+    // Set by the graceful stop, new incoming transfers are dropped
+    pub(crate) draining: AtomicBool,
+    pub(crate) conn_policy: Option<Arc<ConnectionPolicy>>,
+    pub(crate) incoming_filter: Option<Arc<IncomingFilter>>,
+    // The room taken in the inbox spool, used with `inbox_quota_bytes`
+    pub(crate) inbox_usage: inbox::SpoolUsage,
+    // The shared directory files sent but not yet delivered
+    pub(crate) shared_files: share::Pending,
+    // Set when the instance acts as a relay
+    pub(crate) forwarder: Option<mpsc::UnboundedSender<forward::Request>>,
+    // Hashes the partially transferred files, set with `checksum_threads`
+    pub(crate) hash_pool: Option<Arc<rayon::ThreadPool>>,
+    // The connections shared with the peers, used with `shared_connections`
+    pub(crate) mux: ws::mux::Registry,
+    // The last outgoing connection handshake with each peer
+    pub(crate) handshakes: diagnostics::Log,
     #[cfg(unix)]
     pub fdresolv: Option<Arc<crate::file::FdResolver>>,
 }
 
 impl State {
-    pub fn emit_event(&self, event: crate::Event) {
-        self.event_tx
-            .send((event, SystemTime::now()))
-            .expect("Failed to emit Event");
+    /// Picks the local address to bind outgoing connections to. Prefers the
+    /// listen address of the same family as the peer's one
+    pub(crate) fn local_addr_for(&self, peer: IpAddr) -> IpAddr {
+        self.addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() == peer.is_ipv4())
+            .or_else(|| self.addrs.first())
+            .copied()
+            .expect("The service is always started with at least one address")
+    }
+
+    /// The port of the listener bound to the `local` address, advertised to
+    /// the peers connecting from it
+    pub(crate) fn listen_port_for(&self, local: IpAddr) -> Option<u16> {
+        let addrs = self.listen_addrs.get()?;
+        addrs
+            .iter()
+            .find(|addr| addr.ip() == local)
+            .or_else(|| addrs.first())
+            .map(SocketAddr::port)
+    }
+
+    pub(crate) fn is_low_power(&self) -> bool {
+        *self.low_power.borrow()
+    }
+
+    /// Checks whether the data flow can be started. In the read-only mode,
+    /// while stopping gracefully, for the new transfers and uploads in the
+    /// receive-only mode, or for the downloads in the send-only mode, the
+    /// refusal is reported and `false` is returned
+    pub(crate) fn allow_data_flow(
+        &self,
+        logger: &Logger,
+        transfer_id: Uuid,
+        file_id: Option<&FileId>,
+        operation: RefusedOperation,
+    ) -> bool {
+        let mode = if self.draining.load(Ordering::Relaxed) {
+            // Otherwise the new files would keep the instance from stopping
+            "Stopping"
+        } else if self.read_only.load(Ordering::Relaxed) {
+            "Read-only"
+        } else if matches!(
+            operation,
+            RefusedOperation::NewTransfer | RefusedOperation::Upload
+        ) && !self.config.transfer_mode.can_send()
+        {
+            "Receive-only"
+        } else if operation == RefusedOperation::Download
+            && !self.config.transfer_mode.can_receive()
+        {
+            "Send-only"
+        } else {
+            return true;
+        };
+
+        info!(
+            logger,
+            "{mode} mode, refusing {operation:?} of transfer {transfer_id}, file: {file_id:?}"
+        );
+
+        self.emit_event(
+            logger,
+            Event::OperationRefused {
+                transfer_id,
+                file_id: file_id.cloned(),
+                operation,
+            },
+        );
+
+        false
+    }
+
+    /// The ping interval of newly established connections
+    pub(crate) fn ping_interval(&self) -> Duration {
+        if self.is_low_power() {
+            drop_config::LOW_POWER_PING_INTERVAL
+        } else {
+            drop_config::PING_INTERVAL
+        }
+    }
+
+    /// Asks the connection policy whether to connect to the peer. Returns
+    /// `None` when there is no policy
+    pub(crate) fn connection_decision(&self, peer: IpAddr, id: Uuid) -> Option<ConnectionDecision> {
+        self.conn_policy.as_ref().map(|policy| policy(peer, id))
+    }
+
+    /// Asks the incoming filter whether to accept the transfer request.
+    /// Returns `None` when there is no filter
+    pub(crate) fn incoming_decision(&self, xfer: &IncomingTransfer) -> Option<IncomingDecision> {
+        // The peer retries the transfer once the instance is started again
+        if self.draining.load(Ordering::Relaxed) {
+            return Some(IncomingDecision::Drop);
+        }
+
+        self.incoming_filter.as_ref().map(|filter| filter(xfer))
+    }
+
+    /// Whether the transfer request would make the peer exceed its quota.
+    /// The transfers still in progress count as if they were received, so
+    /// the peer cannot get around the quota with many requests at once
+    pub(crate) async fn exceeds_peer_quota(&self, xfer: &IncomingTransfer) -> bool {
+        let Some(quota) = self.config.peer_quota_bytes else {
+            return false;
+        };
+
+        let pending = self
+            .transfer_manager
+            .incoming_pending_bytes(xfer.peer())
+            .await;
+        let usage = self.peer_usage(xfer.peer()).await;
+
+        usage
+            .saturating_add(pending)
+            .saturating_add(xfer.total_size())
+            > quota
+    }
+
+    /// Remembers the peers which authenticated their capabilities in the
+    /// handshake and refuses the unauthenticated capabilities of those, so
+    /// that the MAC cannot be stripped on the way together with e.g. the chunk
+    /// encryption. Returns `false` when the capabilities are refused
+    pub(crate) async fn check_capabilities_pin(&self, peer: IpAddr, authenticated: bool) -> bool {
+        let Some(key) = tokio::task::block_in_place(|| self.auth.peer_public_key(peer)) else {
+            return true;
+        };
+        let key = hex::encode(key.as_bytes());
+
+        if authenticated {
+            self.storage.pin_peer_capabilities(&key).await;
+            true
+        } else {
+            !self.storage.are_peer_capabilities_pinned(&key).await
+        }
+    }
+
+    /// The bytes received from the peer within the quota window
+    async fn peer_usage(&self, peer: IpAddr) -> u64 {
+        let since = SystemTime::now()
+            .checked_sub(self.config.peer_quota_window)
+            .and_then(|since| since.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs() as i64);
+
+        self.storage.peer_usage(&peer.to_string(), since).await
+    }
+
+    /// Passes the transfer asking to be forwarded to the relay coordinator.
+    /// Does nothing when the instance is not a relay
+    pub(crate) fn forward(&self, logger: &Logger, req: forward::Request) {
+        if let Some(forwarder) = &self.forwarder {
+            if forwarder.send(req).is_err() {
+                warn!(logger, "Forwarding coordinator is gone");
+            }
+        }
+    }
+
+    /// The optional protocol features offered to the peers
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+            .with(Capability::ChunkEncryption, self.config.chunk_encryption)
+            .with(Capability::ChunkCrc, self.config.chunk_crc)
+            .with(Capability::ResumeChecksum, self.hash_pool.is_some())
+            // Receiving the ranges needs nothing but the offsets in the chunks
+            .with(Capability::ParallelRanges, self.config.chunk_crc)
+            .with(Capability::FilePacing, true)
+            .with(Capability::FileExpiry, true)
+            .with(Capability::FileAddition, true)
+            .with(Capability::FileWithdrawal, true)
+            .with(Capability::TransferPause, true)
+    }
+
+    /// The name and the platform of this device declared to the peers
+    pub(crate) fn local_device(&self) -> crate::PeerDevice {
+        crate::PeerDevice {
+            name: self.config.device_name.clone(),
+            platform: self.config.platform.clone(),
+        }
+    }
+
+    /// The hash function checking if the partially transferred file can be
+    /// resumed, BLAKE3 when `negotiated` with the peer
+    pub(crate) fn resume_hash(&self, negotiated: bool) -> HashAlg {
+        match &self.hash_pool {
+            Some(pool) if negotiated => HashAlg::Blake3(pool.clone()),
+            _ => HashAlg::Sha256,
+        }
+    }
+
+    /// Emits the event. The send fails only when the consumer is gone, which
+    /// happens on shutdown, so the event is logged and dropped
+    pub fn emit_event(&self, logger: &Logger, event: crate::Event) {
+        if let Err(err) = self.event_tx.send((event, SystemTime::now())) {
+            warn!(
+                logger,
+                "Event consumer is gone, dropping event: {:?}", err.0 .0
+            );
+        }
     }
 }
 
@@ -53,12 +330,14 @@ pub struct Service {
     pub(super) logger: Logger,
 
     refresh_trigger: tokio::sync::watch::Sender<()>,
+    // The watchers of the shared directories
+    shares: HashMap<Uuid, CancellationToken>,
 }
 
 impl Service {
     #[allow(clippy::too_many_arguments)]
     pub async fn start(
-        addr: IpAddr,
+        addrs: Vec<IpAddr>,
         storage: Arc<Storage>,
         event_tx: mpsc::UnboundedSender<(Event, SystemTime)>,
         logger: Logger,
@@ -66,10 +345,43 @@ impl Service {
         moose: Arc<dyn Moose>,
         auth: Arc<auth::Context>,
         init_time: Instant,
+        conn_policy: Option<Arc<ConnectionPolicy>>,
+        incoming_filter: Option<Arc<IncomingFilter>>,
         #[cfg(unix)] fdresolv: Option<Arc<crate::FdResolver>>,
     ) -> Result<Self, Error> {
         let task = async {
+            if addrs.is_empty() {
+                return Err(Error::InvalidArgument);
+            }
+
+            let (forwarder, forward_rx) =
+                if config.forward_dir.is_some() || config.inbox_dir.is_some() {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    (Some(tx), Some(rx))
+                } else {
+                    (None, None)
+                };
+
+            let hash_pool = config
+                .checksum_threads
+                .map(|threads| {
+                    rayon::ThreadPoolBuilder::new()
+                        .num_threads(threads)
+                        .thread_name(|i| format!("drop-hash-{i}"))
+                        .build()
+                        .map(Arc::new)
+                        .map_err(|err| Error::Io(io::Error::new(io::ErrorKind::Other, err)))
+                })
+                .transpose()?;
+
+            let stop = CancellationToken::new();
+            let mux = ws::mux::Registry::new(stop.clone(), config.connection_keep_alive);
+            let inbox_usage = inbox::SpoolUsage::load(&config);
+
             let state = Arc::new(State {
+                low_power: watch::channel(config.low_power_mode).0,
+                read_only: AtomicBool::new(config.read_only),
+                draining: AtomicBool::new(false),
                 throttle: Arc::new(Semaphore::new(drop_config::MAX_UPLOADS_IN_FLIGHT)),
                 transfer_manager: TransferManager::new(
                     storage.clone(),
@@ -81,34 +393,76 @@ impl Service {
                 config,
                 auth: auth.clone(),
                 storage,
-                addr,
+                addrs,
+                listen_addrs: OnceLock::new(),
+                conn_policy,
+                incoming_filter,
+                inbox_usage,
+                shared_files: Default::default(),
+                forwarder,
+                hash_pool,
+                mux,
+                handshakes: Default::default(),
                 #[cfg(unix)]
                 fdresolv,
             });
 
             let waiter = AliveWaiter::new();
-            let stop = CancellationToken::new();
 
             let guard = waiter.guard();
 
             state.storage.cleanup_garbage_transfers().await;
 
-            manager::restore_transfers_state(&state, &logger).await;
+            let reconciled = manager::restore_transfers_state(&state, &logger).await;
+            if !reconciled.is_empty() {
+                info!(
+                    logger,
+                    "Reconciled the unfinished transfers: {reconciled:?}"
+                );
+                state.emit_event(&logger, Event::StartupReconciled(reconciled));
+            }
+
+            if let Some(max_age) = state.config.partial_gc_age {
+                remove_stale_partial_files(&state, &logger, max_age).await;
+            }
 
             let refresh_trigger = tokio::sync::watch::channel(()).0;
-            ws::server::spawn(
+            let listen_addrs = ws::server::spawn(
                 refresh_trigger.subscribe(),
                 state.clone(),
                 logger.clone(),
                 stop.clone(),
                 guard.clone(),
             )?;
+            let _ = state.listen_addrs.set(listen_addrs);
 
             manager::resume(&refresh_trigger.subscribe(), &state, &logger, &guard, &stop).await;
 
+            if let Some(forward_rx) = forward_rx {
+                forward::spawn(
+                    forward_rx,
+                    refresh_trigger.subscribe(),
+                    state.clone(),
+                    logger.clone(),
+                    guard.clone(),
+                    stop.clone(),
+                )
+                .await;
+            }
+
+            if state.is_low_power() {
+                spawn_low_power_throttle(
+                    state.clone(),
+                    logger.clone(),
+                    guard.clone(),
+                    stop.clone(),
+                );
+            }
+
             if let Some(interval) = state.config.auto_retry_interval {
                 spawn_auto_retry_loop(
                     refresh_trigger.clone(),
+                    state.low_power.subscribe(),
                     interval,
                     logger.clone(),
                     guard.clone(),
@@ -122,6 +476,7 @@ impl Service {
                 stop,
                 waiter,
                 logger,
+                shares: HashMap::new(),
             })
         };
 
@@ -140,56 +495,210 @@ impl Service {
         self.waiter.wait_for_all().await;
     }
 
+    /// Stops accepting new transfers and starting new files and waits up to
+    /// `timeout` for the files in flight to finish, so that their final
+    /// messages reach the peers. Then stops like `stop()`. The unfinished
+    /// transfers are kept in the storage and resumed on the next start
+    pub async fn stop_graceful(self, timeout: Duration) {
+        self.state.draining.store(true, Ordering::Relaxed);
+        info!(
+            self.logger,
+            "Draining in-flight files for up to {timeout:?}"
+        );
+
+        let drain = async {
+            loop {
+                let count = self.state.transfer_manager.in_flight_files().await;
+                if count == 0 {
+                    break;
+                }
+
+                trace!(self.logger, "Waiting for {count} in-flight files");
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        };
+
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            warn!(
+                self.logger,
+                "Files still in flight after {timeout:?}, stopping anyway"
+            );
+        }
+
+        self.stop().await;
+    }
+
+    /// The background tasks which are still running, for debugging task leaks
+    pub fn background_tasks(&self) -> Vec<crate::TaskInfo> {
+        self.waiter.tasks()
+    }
+
+    /// The number of the running background tasks per subsystem
+    pub fn background_task_counts(&self) -> BTreeMap<&'static str, usize> {
+        self.waiter.task_counts()
+    }
+
     pub fn storage(&self) -> &Storage {
         &self.state.storage
     }
 
-    pub fn network_refresh(&mut self) {
-        if self.refresh_trigger.send(()).is_ok() {
-            trace!(self.logger, "Refresh trigger sent");
+    /// The sockets the server listens on, with the port picked by the system
+    /// when the `port` is configured as `0`
+    pub fn listen_addrs(&self) -> &[SocketAddr] {
+        self.state.listen_addrs.get().map_or(&[], Vec::as_slice)
+    }
+
+    pub async fn outgoing_retry_states(&self) -> Vec<manager::OutgoingRetryState> {
+        self.state.transfer_manager.outgoing_retry_states().await
+    }
+
+    /// The live state of the instance and its active transfers
+    pub async fn snapshot(&self) -> InstanceSnapshot {
+        let (incoming, outgoing) = self.state.transfer_manager.snapshot().await;
+
+        InstanceSnapshot {
+            addrs: self.state.addrs.clone(),
+            read_only: self.state.read_only.load(Ordering::Relaxed),
+            low_power: self.state.is_low_power(),
+            incoming,
+            outgoing,
         }
     }
 
-    pub async fn send_request(&mut self, xfer: crate::OutgoingTransfer) {
-        let xfer = Arc::new(xfer);
+    /// Checks if the outgoing transfer is neither finished nor cancelled
+    pub async fn is_outgoing_alive(&self, transfer_id: Uuid) -> bool {
+        self.state
+            .transfer_manager
+            .is_outgoing_alive(transfer_id)
+            .await
+    }
+
+    /// Resolves the transfer ID given either as the UUID or as the short
+    /// transfer code
+    pub async fn resolve_transfer_id(&self, id: &str) -> Option<Uuid> {
+        if let Ok(id) = Uuid::try_parse(id) {
+            return Some(id);
+        }
 
-        self.state.moose.event_transfer_intent(xfer.info());
+        let code = alias::normalize(id)?;
+        self.state.storage.transfer_by_alias(&code).await
+    }
 
-        match self
-            .state
+    /// The pending outgoing transfer created with the same request key, see
+    /// [`OutgoingTransfer::with_request_key()`]
+    pub async fn pending_outgoing(&self, request_key: &str) -> Option<Uuid> {
+        self.state
             .transfer_manager
-            .insert_outgoing(xfer.clone())
+            .pending_outgoing(request_key)
             .await
-        {
-            Err(err) => {
-                self.state
-                    .moose
-                    .event_transfer_state(TransferStateEventData {
-                        transfer_id: xfer.id().to_string(),
-                        result: i32::from(&err),
-                        protocol_version: 0,
-                    });
-
-                self.state
-                    .emit_event(Event::OutgoingTransferFailed(xfer.clone(), err, true));
-
-                return;
-            }
-            Ok(tx) => tx.queued().await,
-        };
+    }
+
+    /// The live statistics of the active transfer, `None` when the transfer is
+    /// not known or already finished
+    pub async fn transfer_stats(&self, transfer_id: Uuid) -> Option<crate::TransferStats> {
+        self.state
+            .transfer_manager
+            .transfer_stats(transfer_id)
+            .await
+    }
+
+    /// The phases of the last connection handshake with the peer, `None`
+    /// when no transfer connected to it yet
+    pub fn connection_diagnostics(&self, peer: IpAddr) -> Option<crate::HandshakeDiagnostics> {
+        self.state.handshakes.get(peer)
+    }
+
+    /// Counters of all the transfers, exported as the metrics
+    pub async fn transfer_counters(&self) -> crate::TransferCounters {
+        self.state.transfer_manager.counters().await
+    }
+
+    /// Enables or disables the low-power mode. In the low-power mode the
+    /// connections are pinged less frequently, fewer files are uploaded at
+    /// once and the scheduled connection retries are postponed until the next
+    /// `network_refresh()` or until the mode is disabled
+    pub fn set_low_power(&self, enabled: bool) {
+        let changed = self.state.low_power.send_if_modified(|on| {
+            let changed = *on != enabled;
+            *on = enabled;
+            changed
+        });
+
+        if !changed {
+            return;
+        }
+
+        info!(self.logger, "Low-power mode: {enabled}");
+
+        if enabled {
+            spawn_low_power_throttle(
+                self.state.clone(),
+                self.logger.clone(),
+                self.waiter.guard(),
+                self.stop.clone(),
+            );
+        }
+    }
+
+    /// Enables or disables the read-only mode. In the read-only mode the
+    /// transfer requests are still received and the history is served, but
+    /// no new transfer, download or upload is started. The refused operations
+    /// are reported with the `OperationRefused` event. The data flows already
+    /// running are not affected
+    pub fn set_read_only(&self, enabled: bool) {
+        if self.state.read_only.swap(enabled, Ordering::Relaxed) != enabled {
+            info!(self.logger, "Read-only mode: {enabled}");
+        }
+    }
 
-        let subscriber = self.refresh_trigger.subscribe();
+    /// Replaces the local secret key without restarting. The new connections
+    /// authenticate and encrypt the chunks with the new key, while the open
+    /// ones keep the chunk encryption derived from the previous key until they
+    /// close. The transfers reconnecting meanwhile switch to the new key. The
+    /// `KeyRotationCompleted` event is emitted once no connection uses the
+    /// previous key
+    pub fn set_private_key(&self, secret: drop_auth::SecretKey) {
+        let in_use = self.state.auth.rotate_secret_key(secret);
+        info!(
+            self.logger,
+            "Private key rotated, {} connections keep the previous one",
+            in_use.len()
+        );
 
-        ws::client::spawn(
-            subscriber,
+        spawn_key_rotation_wait(
             self.state.clone(),
-            xfer,
+            in_use,
             self.logger.clone(),
             self.waiter.guard(),
             self.stop.clone(),
         );
     }
 
+    /// Kicks the scheduled connection retries and makes the listeners check
+    /// whether their addresses are still assigned to the host. Listeners of the
+    /// vanished addresses are bound again once the address reappears
+    pub fn network_refresh(&mut self) {
+        if self.refresh_trigger.send(()).is_ok() {
+            trace!(self.logger, "Refresh trigger sent");
+        }
+    }
+
+    /// Creates the outgoing transfer and starts connecting to the peer.
+    /// Returns [`crate::Error::OperationRefused`] when the transfer is
+    /// refused by the read-only or receive-only mode, the transfer is not
+    /// created then
+    pub async fn send_request(&mut self, xfer: crate::OutgoingTransfer) -> crate::Result<()> {
+        send_request(
+            &self.state,
+            &self.logger,
+            self.refresh_trigger.subscribe(),
+            self.waiter.guard(),
+            self.stop.clone(),
+            xfer,
+        )
+        .await
+    }
+
     pub async fn download(
         &mut self,
         uuid: Uuid,
@@ -203,28 +712,107 @@ impl Service {
             file_id,
         );
 
+        start_download(&self.state, &self.logger, uuid, file_id, parent_dir, None).await
+    }
+
+    /// Validates and schedules the downloads of many files of the transfer
+    /// at once. None of the files is scheduled if any of them fails the
+    /// validation. The files already being downloaded are skipped
+    pub async fn download_many(
+        &mut self,
+        uuid: Uuid,
+        files: &[(FileId, String)],
+    ) -> crate::Result<()> {
+        debug!(
+            self.logger,
+            "Client::download_many() called with Uuid: {uuid}, files: {}",
+            files.len()
+        );
+
         let mut lock = self.state.transfer_manager.incoming.lock().await;
+        let xfer_state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
 
-        let state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
-        let started = state.validate_for_download(file_id)?;
+        let mut to_start = Vec::new();
+        for (file_id, parent_dir) in files {
+            if to_start.iter().any(|(id, _)| *id == file_id) {
+                return Err(crate::Error::InvalidArgument);
+            }
 
-        if started {
-            validate_dest_path(parent_dir.as_ref())?;
-            state.file_events(file_id)?.pending(parent_dir).await;
+            if xfer_state.validate_for_download(file_id)? {
+                validate::check_destination(&self.state.config, parent_dir.as_ref())?;
+                to_start.push((file_id, parent_dir));
+            }
+        }
 
-            state
-                .start_download(
-                    &self.state.storage,
-                    file_id,
-                    parent_dir.as_ref(),
-                    &self.logger,
-                )
-                .await?;
+        if to_start.is_empty()
+            || !self
+                .state
+                .allow_data_flow(&self.logger, uuid, None, RefusedOperation::Download)
+        {
+            return Ok(());
+        }
+
+        // Nothing is created until all the files pass the validation
+        for (_, parent_dir) in &to_start {
+            let dest = validate::destination(&self.state.config, parent_dir.as_ref())?;
+            check_partial_dir_fs(&self.state, &self.logger, &dest);
+        }
+
+        self.state.emit_event(
+            &self.logger,
+            Event::IncomingDownloadsQueued {
+                transfer_id: uuid,
+                file_ids: to_start
+                    .iter()
+                    .map(|(file_id, _)| (*file_id).clone())
+                    .collect(),
+            },
+        );
+
+        for (file_id, parent_dir) in to_start {
+            schedule_download(
+                &self.state,
+                &self.logger,
+                xfer_state,
+                file_id,
+                parent_dir,
+                None,
+            )
+            .await?;
         }
 
         Ok(())
     }
 
+    /// Downloads the file in place into the pre-opened destination instead
+    /// of the directory. The download always starts from the beginning, also
+    /// when resumed after the reconnection
+    pub async fn download_to_fd(
+        &mut self,
+        uuid: Uuid,
+        file_id: &FileId,
+        dest: DestinationFd,
+    ) -> crate::Result<()> {
+        debug!(
+            self.logger,
+            "Client::download_to_fd() called with Uuid: {}, file: {:?}, content_uri: {}",
+            uuid,
+            file_id,
+            dest.content_uri(),
+        );
+
+        let content_uri = dest.content_uri().to_string();
+        start_download(
+            &self.state,
+            &self.logger,
+            uuid,
+            file_id,
+            &content_uri,
+            Some(Arc::new(dest)),
+        )
+        .await
+    }
+
     /// Reject a single file in a transfer. After rejection the file can no
     /// longer be transferred
     pub async fn reject(&self, transfer_id: Uuid, file: FileId) -> crate::Result<()> {
@@ -244,37 +832,183 @@ impl Service {
                 Err(err) => return Err(err),
             }
         }
+
+        reject_incoming(&self.state, &self.logger, transfer_id, &file).await
+    }
+
+    /// Withdraws the file of the outgoing transfer the receiver did not start
+    /// yet. Unlike the rejected file, it is reported as withdrawn on both
+    /// sides
+    pub async fn withdraw_file(&self, transfer_id: Uuid, file: FileId) -> crate::Result<()> {
+        let res = self
+            .state
+            .transfer_manager
+            .outgoing_withdraw_file(transfer_id, &file)
+            .await?;
+
+        res.file_events.withdrawn().await;
+        super::ws::client::handle_finish_xfer_state(res.xfer_state, false).await;
+        Ok(())
+    }
+
+    /// Deletes the partially downloaded data of the incoming file and forgets
+    /// where it was downloaded to, so the file is not resumed from it. The
+    /// file must not be in flight, e.g. rejected or part of a cancelled
+    /// transfer
+    pub async fn remove_partial_file(&self, transfer_id: Uuid, file: FileId) -> crate::Result<()> {
+        if self
+            .state
+            .transfer_manager
+            .is_incoming_file_in_flight(transfer_id, &file)
+            .await
         {
-            match self
-                .state
-                .transfer_manager
-                .incoming_rejection_post(transfer_id, &file)
-                .await
-            {
-                Ok(res) => {
-                    // Try to delete temporary files
-                    let tmp_bases = self
-                        .state
-                        .storage
-                        .fetch_base_dirs_for_file(transfer_id, file.as_ref())
-                        .await;
-
-                    super::ws::server::remove_temp_files(
-                        &self.logger,
-                        transfer_id,
-                        tmp_bases.into_iter().map(|base| (base, &file)),
-                    );
+            return Err(crate::Error::BadTransferState(
+                "File is being downloaded".into(),
+            ));
+        }
 
-                    res.file_events.rejected(false).await;
-                    super::ws::server::handle_finish_xfer_state(res.xfer_state, false).await;
-                    return Ok(());
-                }
-                Err(crate::Error::BadTransfer) => (),
-                Err(err) => return Err(err),
-            }
+        let tmp_bases = self
+            .state
+            .storage
+            .fetch_base_dirs_for_file(transfer_id, file.as_ref())
+            .await;
+        if tmp_bases.is_empty() {
+            return Err(crate::Error::BadFileId);
         }
 
-        Err(crate::Error::BadTransfer)
+        super::ws::server::remove_temp_files(
+            &self.logger,
+            &self.state.config,
+            transfer_id,
+            tmp_bases.into_iter().map(|base| (base, &file)),
+        );
+
+        self.state
+            .storage
+            .clear_incoming_file_in_flight(transfer_id, file.as_ref())
+            .await;
+
+        Ok(())
+    }
+
+    /// The bytes received from the peer within the quota window, counted
+    /// against `peer_quota_bytes`
+    pub async fn peer_quota_usage(&self, peer: IpAddr) -> u64 {
+        self.state.peer_usage(peer).await
+    }
+
+    /// The transfers kept in the inbox spool
+    pub async fn inbox(&self) -> crate::Result<Vec<inbox::InboxEntry>> {
+        inbox::list(&self.state).await
+    }
+
+    /// Moves the files of the downloaded inbox transfer into the destination
+    /// directory and removes the transfer from the inbox. Returns the paths
+    /// of the moved files and directories
+    pub async fn claim_inbox(
+        &self,
+        transfer_id: Uuid,
+        dest: &Path,
+    ) -> crate::Result<Vec<std::path::PathBuf>> {
+        inbox::claim(&self.state, &self.logger, transfer_id, dest).await
+    }
+
+    /// Sends the files of the downloaded inbox transfer to another peer. The
+    /// files are kept in the inbox. Returns the new transfer ID
+    pub async fn forward_inbox(
+        &mut self,
+        transfer_id: Uuid,
+        peer: IpAddr,
+        peer_scope_id: u32,
+        peer_port: Option<u16>,
+    ) -> crate::Result<Uuid> {
+        let xfer =
+            inbox::outgoing(&self.state, transfer_id, peer, peer_scope_id, peer_port).await?;
+        let id = xfer.id();
+
+        self.send_request(xfer).await?;
+        Ok(id)
+    }
+
+    /// Starts sharing the directory with the peer. Every new or modified
+    /// file is sent in a new outgoing transfer, the ones not sent yet right
+    /// away. Returns the share ID
+    pub fn share_directory(
+        &mut self,
+        dir: &Path,
+        peer: IpAddr,
+        peer_scope_id: u32,
+        peer_port: Option<u16>,
+    ) -> crate::Result<Uuid> {
+        let dir = crate::utils::make_path_absolute(dir)?;
+        let stop = self.stop.child_token();
+
+        share::spawn(
+            share::Share {
+                dir,
+                peer,
+                peer_scope_id,
+                peer_port,
+            },
+            self.refresh_trigger.subscribe(),
+            self.state.clone(),
+            self.logger.clone(),
+            self.waiter.guard(),
+            stop.clone(),
+        )?;
+
+        let id = Uuid::new_v4();
+        self.shares.insert(id, stop);
+        Ok(id)
+    }
+
+    /// Sends the short UTF-8 text message to the peer. The outcome is reported
+    /// with the `OutgoingTextSent` or `OutgoingTextFailed` event. Returns the
+    /// message ID
+    pub fn send_text(
+        &self,
+        peer: IpAddr,
+        peer_scope_id: u32,
+        peer_port: Option<u16>,
+        text: String,
+    ) -> crate::Result<Uuid> {
+        if text.is_empty() {
+            return Err(crate::Error::InvalidArgument);
+        }
+        if text.len() > text::MAX_TEXT_LEN {
+            return Err(crate::Error::TransferLimitsExceeded);
+        }
+        if !self.state.config.transfer_mode.can_send() {
+            info!(self.logger, "Receive-only mode, refusing the text message");
+            return Err(crate::Error::OperationRefused);
+        }
+
+        let id = Uuid::new_v4();
+        text::spawn(
+            text::Text {
+                id,
+                peer,
+                peer_scope_id,
+                peer_port,
+                text,
+            },
+            self.state.clone(),
+            self.logger.clone(),
+            self.waiter.guard(),
+            self.stop.clone(),
+        );
+
+        Ok(id)
+    }
+
+    /// Stops sharing the directory. The transfers already created are kept
+    pub fn stop_sharing(&mut self, share_id: Uuid) -> crate::Result<()> {
+        let stop = self
+            .shares
+            .remove(&share_id)
+            .ok_or(crate::Error::InvalidArgument)?;
+        stop.cancel();
+        Ok(())
     }
 
     /// Cancel all of the files in a transfer
@@ -326,28 +1060,292 @@ impl Service {
 
         Err(crate::Error::BadTransfer)
     }
+
+    /// Retry the connection of the outgoing transfer right away instead of
+    /// waiting for the scheduled retry
+    pub async fn retry_transfer(&self, transfer_id: Uuid) -> crate::Result<()> {
+        self.state
+            .transfer_manager
+            .outgoing_retry_now(transfer_id)
+            .await
+    }
+
+    /// Asks the sender to pause all the files of the incoming transfer until
+    /// resumed. The files keep their started state
+    pub async fn pause_transfer(&self, transfer_id: Uuid) -> crate::Result<()> {
+        self.set_transfer_paused(transfer_id, true).await
+    }
+
+    /// Resumes the incoming transfer paused with [`Self::pause_transfer`]
+    pub async fn resume_transfer(&self, transfer_id: Uuid) -> crate::Result<()> {
+        self.set_transfer_paused(transfer_id, false).await
+    }
+
+    async fn set_transfer_paused(&self, transfer_id: Uuid, paused: bool) -> crate::Result<()> {
+        let events = self
+            .state
+            .transfer_manager
+            .incoming_pause(transfer_id, paused)
+            .await?;
+
+        events.paused(paused).await;
+        Ok(())
+    }
+
+    /// Asks the sender to send the file of the incoming transfer at most at
+    /// `rate` bytes per second. `0` pauses the file and `None` lifts the
+    /// limit. The senders not supporting it keep sending at full speed
+    pub async fn pace_file(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+        rate: Option<u64>,
+    ) -> crate::Result<()> {
+        self.state
+            .transfer_manager
+            .incoming_pace(transfer_id, file_id, rate)
+            .await
+    }
+
+    /// Withdraw the outgoing transfer not yet accepted by the receiver
+    pub async fn withdraw(&mut self, transfer_id: Uuid) -> crate::Result<()> {
+        let res = self
+            .state
+            .transfer_manager
+            .outgoing_withdraw(transfer_id)
+            .await?;
+
+        futures::future::join_all(
+            res.file_events
+                .iter()
+                .map(|ev| ev.stop_silent(Status::Finalized)),
+        )
+        .await;
+
+        res.xfer_events.withdrawn(false).await;
+        Ok(())
+    }
+
+    /// Adds the files to the outgoing transfer not yet accepted by the
+    /// receiver. The files already in the transfer are skipped
+    pub async fn add_files(
+        &self,
+        transfer_id: Uuid,
+        files: Vec<FileToSend>,
+        entries: DirEntries,
+    ) -> crate::Result<()> {
+        let added = self
+            .state
+            .transfer_manager
+            .outgoing_add_files(transfer_id, files, entries, &self.state.config)
+            .await?;
+
+        if !added.files.is_empty() {
+            added.events.files_added(added.xfer, added.files).await;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn send_request(
+    state: &Arc<State>,
+    logger: &Logger,
+    refresh_trigger: watch::Receiver<()>,
+    guard: AliveGuard,
+    stop: CancellationToken,
+    xfer: crate::OutgoingTransfer,
+) -> crate::Result<()> {
+    if !state.allow_data_flow(logger, xfer.id(), None, RefusedOperation::NewTransfer) {
+        return Err(crate::Error::OperationRefused);
+    }
+
+    let xfer = Arc::new(xfer);
+
+    state.moose.event_transfer_intent(xfer.info());
+
+    match state.transfer_manager.insert_outgoing(xfer.clone()).await {
+        Err(err) => {
+            state.moose.event_transfer_state(TransferStateEventData {
+                transfer_id: xfer.id().to_string(),
+                result: i32::from(&err),
+                protocol_version: 0,
+            });
+
+            state.emit_event(
+                logger,
+                Event::OutgoingTransferFailed(xfer.clone(), err, true),
+            );
+
+            // Reported with the event
+            return Ok(());
+        }
+        Ok(tx) => {
+            alias::assign(state, logger, xfer.id()).await;
+            tx.queued().await
+        }
+    };
+
+    if state.config.transfer_preflight {
+        preflight::run(state, logger, &xfer).await;
+    }
+
+    expiry::spawn(
+        state.clone(),
+        xfer.clone(),
+        logger.clone(),
+        guard.clone(),
+        stop.clone(),
+    );
+    ws::client::spawn(
+        refresh_trigger,
+        state.clone(),
+        xfer,
+        logger.clone(),
+        guard,
+        stop,
+    );
+
+    Ok(())
+}
+
+pub(crate) async fn start_download(
+    state: &State,
+    logger: &Logger,
+    uuid: Uuid,
+    file_id: &FileId,
+    parent_dir: &str,
+    dest: Option<Arc<DestinationFd>>,
+) -> crate::Result<()> {
+    let mut lock = state.transfer_manager.incoming.lock().await;
+
+    let xfer_state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
+    let started = xfer_state.validate_for_download(file_id)?;
+
+    if started && !state.allow_data_flow(logger, uuid, Some(file_id), RefusedOperation::Download) {
+        // The file is left idle and can be downloaded later
+        return Ok(());
+    }
+
+    if started {
+        if dest.is_none() {
+            let dest = validate::destination(&state.config, parent_dir.as_ref())?;
+            check_partial_dir_fs(state, logger, &dest);
+        }
+
+        schedule_download(state, logger, xfer_state, file_id, parent_dir, dest).await?;
+    }
+
+    Ok(())
 }
 
-fn validate_dest_path(parent_dir: &Path) -> crate::Result<()> {
-    if parent_dir.components().any(|x| x == Component::ParentDir) {
-        return Err(crate::Error::BadPath(
-            "Path should not contain a reference to parrent directory".into(),
-        ));
+/// The partial files on another filesystem than the destination cannot be
+/// renamed into it once downloaded, they are copied instead
+fn check_partial_dir_fs(state: &State, logger: &Logger, dest: &Path) {
+    let Some(partial_dir) = &state.config.partial_dir else {
+        return;
+    };
+
+    if utils::same_filesystem(Path::new(partial_dir), dest) == Some(false) {
+        info!(
+            logger,
+            "Partial files directory is on another filesystem than {:?}, the downloaded files \
+             will be copied",
+            Hidden(dest)
+        );
     }
+}
 
-    if parent_dir.ancestors().any(Path::is_symlink) {
-        return Err(crate::Error::BadPath(
-            "Destination should not contain directory symlinks".into(),
-        ));
+/// Starts the download of the validated file
+async fn schedule_download(
+    state: &State,
+    logger: &Logger,
+    xfer_state: &mut manager::IncomingState,
+    file_id: &FileId,
+    parent_dir: &str,
+    dest: Option<Arc<DestinationFd>>,
+) -> crate::Result<()> {
+    if dest.is_none() && state.config.create_dir_skeleton {
+        match xfer_state.create_dir_skeleton(file_id, parent_dir.as_ref()) {
+            Ok(Some((root, path))) => state.emit_event(
+                logger,
+                Event::IncomingDirectoryPrepared {
+                    transfer_id: xfer_state.xfer.id(),
+                    root,
+                    path,
+                },
+            ),
+            Ok(None) => (),
+            // The directories are still created on demand by the download
+            Err(err) => warn!(
+                logger,
+                "Failed to create the directory structure of {file_id}: {err}"
+            ),
+        }
     }
 
-    fs::create_dir_all(parent_dir).map_err(|ioerr| crate::Error::BadPath(ioerr.to_string()))?;
+    xfer_state.file_events(file_id)?.pending(parent_dir).await;
+
+    xfer_state
+        .start_download(&state.storage, file_id, parent_dir.as_ref(), dest, logger)
+        .await
+}
+
+/// Rejects the file of the incoming transfer and removes its partial data
+pub(crate) async fn reject_incoming(
+    state: &State,
+    logger: &Logger,
+    transfer_id: Uuid,
+    file: &FileId,
+) -> crate::Result<()> {
+    let res = state
+        .transfer_manager
+        .incoming_rejection_post(transfer_id, file)
+        .await?;
+
+    // Try to delete temporary files
+    let tmp_bases = state
+        .storage
+        .fetch_base_dirs_for_file(transfer_id, file.as_ref())
+        .await;
+
+    ws::server::remove_temp_files(
+        logger,
+        &state.config,
+        transfer_id,
+        tmp_bases.into_iter().map(|base| (base, file)),
+    );
 
+    res.file_events.rejected(false).await;
+    ws::server::handle_finish_xfer_state(res.xfer_state, false).await;
     Ok(())
 }
 
+/// Garbage-collects the partial files left behind by the transfers which are
+/// not going to be resumed
+async fn remove_stale_partial_files(state: &State, logger: &Logger, max_age: Duration) {
+    let keep: HashSet<_> = state
+        .transfer_manager
+        .incoming
+        .lock()
+        .await
+        .keys()
+        .copied()
+        .collect();
+
+    let dirs = match &state.config.partial_dir {
+        Some(dir) => vec![dir.clone()],
+        None => state.storage.incoming_base_dirs().await,
+    };
+
+    let count = ws::server::remove_stale_temp_files(logger, &state.config, dirs, &keep, max_age);
+    if count > 0 {
+        info!(logger, "Removed {count} stale partial files");
+    }
+}
+
 fn spawn_auto_retry_loop(
     trigger: tokio::sync::watch::Sender<()>,
+    low_power: watch::Receiver<bool>,
     interval: Duration,
     logger: Logger,
     guard: AliveGuard,
@@ -359,12 +1357,19 @@ fn spawn_auto_retry_loop(
         interval.as_millis()
     );
 
+    let guard = guard.task("service", "Auto retry loop");
     tokio::spawn(async move {
         let _guard = guard;
 
         let task = async {
             loop {
                 tokio::time::sleep(interval).await;
+
+                if *low_power.borrow() {
+                    trace!(logger, "Low-power mode, skipping auto retry");
+                    continue;
+                }
+
                 let _ = trigger.send(());
             }
         };
@@ -379,3 +1384,82 @@ fn spawn_auto_retry_loop(
         }
     });
 }
+
+/// Emits the `KeyRotationCompleted` event once the connections close which
+/// encrypt the chunks with the ciphers derived from the previous key
+fn spawn_key_rotation_wait(
+    state: Arc<State>,
+    mut in_use: Vec<Weak<ChunkCipher>>,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) {
+    let guard = guard.task("service", "Key rotation");
+    tokio::spawn(async move {
+        let _guard = guard;
+
+        loop {
+            in_use.retain(|cipher| cipher.strong_count() > 0);
+
+            if in_use.is_empty() {
+                info!(logger, "Key rotation completed");
+                state.emit_event(&logger, Event::KeyRotationCompleted);
+                break;
+            }
+
+            trace!(
+                logger,
+                "Waiting for {} connections using the previous key",
+                in_use.len()
+            );
+
+            tokio::select! {
+                biased;
+
+                _ = stop.cancelled() => {
+                    // The transfers resumed after the restart connect anew
+                    debug!(logger, "Stopping key rotation wait");
+                    break;
+                },
+                _ = tokio::time::sleep(KEY_ROTATION_POLL_INTERVAL) => (),
+            }
+        }
+    });
+}
+
+/// Holds the upload permits above the low-power limit until the low-power mode
+/// is disabled
+fn spawn_low_power_throttle(
+    state: Arc<State>,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) {
+    let guard = guard.task("service", "Low-power upload throttle");
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut low_power = state.low_power.subscribe();
+
+        let task = async {
+            let count =
+                drop_config::MAX_UPLOADS_IN_FLIGHT - drop_config::LOW_POWER_UPLOADS_IN_FLIGHT;
+
+            let permits = tokio::select! {
+                permits = state.throttle.clone().acquire_many_owned(count as _) => permits,
+                _ = low_power.wait_for(|on| !*on) => return,
+            };
+
+            let _ = low_power.wait_for(|on| !*on).await;
+            drop(permits);
+        };
+
+        tokio::select! {
+            biased;
+
+            _ = stop.cancelled() => {
+                debug!(logger, "Stopping low-power throttle");
+            },
+            _ = task => (),
+        }
+    });
+}
@@ -0,0 +1,305 @@
+//! Live sharing of the directory with the peer, a one-way folder sync. The
+//! directory is watched and, once the changes settle, every new or modified
+//! file is sent in a new outgoing transfer. The versions delivered to the peer
+//! are persisted so sharing the same directory again sends only what changed
+//! in the meantime
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use drop_config::DropConfig;
+use drop_storage::SharedFile;
+use notify::{EventKind, RecursiveMode, Watcher};
+use slog::{debug, info, warn, Logger};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    file::{File, FileId, GatherCtx},
+    service::{self, State},
+    tasks::AliveGuard,
+    transfer::Transfer,
+    utils, FileToSend, OutgoingTransfer,
+};
+
+// The time without any change in the directory before the files are sent
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+pub(crate) struct Share {
+    pub(crate) dir: PathBuf,
+    pub(crate) peer: IpAddr,
+    pub(crate) peer_scope_id: u32,
+    pub(crate) peer_port: Option<u16>,
+}
+
+/// The versions of the shared files sent in the transfers still in flight. A
+/// version is persisted only once the peer has received the file, so the file
+/// lost with the transfer is sent again on the next sync
+#[derive(Default)]
+pub(crate) struct Pending(Mutex<HashMap<Uuid, Batch>>);
+
+struct Batch {
+    dir: String,
+    peer: String,
+    files: HashMap<FileId, SharedFile>,
+}
+
+impl Pending {
+    fn track(&self, transfer_id: Uuid, dir: &str, peer: &str, files: HashMap<FileId, SharedFile>) {
+        self.lock().insert(
+            transfer_id,
+            Batch {
+                dir: dir.to_string(),
+                peer: peer.to_string(),
+                files,
+            },
+        );
+    }
+
+    /// Drops the versions not delivered by the ended transfer
+    pub(crate) fn forget(&self, transfer_id: Uuid) {
+        self.lock().remove(&transfer_id);
+    }
+
+    fn in_flight(&self, dir: &str, peer: &str) -> Vec<SharedFile> {
+        self.lock()
+            .values()
+            .filter(|batch| batch.dir == dir && batch.peer == peer)
+            .flat_map(|batch| batch.files.values().cloned())
+            .collect()
+    }
+
+    fn take(&self, transfer_id: Uuid, file_id: &FileId) -> Option<(String, String, SharedFile)> {
+        let mut batches = self.lock();
+        let batch = batches.get_mut(&transfer_id)?;
+        let file = batch.files.remove(file_id)?;
+        let delivered = (batch.dir.clone(), batch.peer.clone(), file);
+
+        if batch.files.is_empty() {
+            batches.remove(&transfer_id);
+        }
+        Some(delivered)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Batch>> {
+        self.0.lock().expect("Shared files lock poisoned")
+    }
+}
+
+/// Advances the cursor of the shared directory by the file the peer has
+/// received
+pub(crate) async fn delivered(state: &State, transfer_id: Uuid, file_id: &FileId) {
+    if let Some((dir, peer, file)) = state.shared_files.take(transfer_id, file_id) {
+        state.storage.save_shared_files(&dir, &peer, &[file]).await;
+    }
+}
+
+impl Share {
+    fn peer_str(&self) -> String {
+        utils::format_peer(self.peer, self.peer_scope_id, self.peer_port)
+    }
+}
+
+pub(crate) fn spawn(
+    share: Share,
+    refresh_trigger: watch::Receiver<()>,
+    state: Arc<State>,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) -> crate::Result<()> {
+    if !share.dir.is_dir() {
+        return Err(crate::Error::BadPath("Not a directory".into()));
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                let _ = tx.send(());
+            }
+        }
+    })
+    .map_err(|err| crate::Error::BadPath(format!("Failed to watch the directory: {err}")))?;
+    watcher
+        .watch(&share.dir, RecursiveMode::Recursive)
+        .map_err(|err| crate::Error::BadPath(format!("Failed to watch the directory: {err}")))?;
+
+    let task_guard = guard.task("share", "Shared directory watcher");
+    tokio::spawn(async move {
+        let _guard = task_guard;
+        // Dropping the watcher stops watching
+        let _watcher = watcher;
+
+        let task = async {
+            loop {
+                match sync(&state, &logger, &share).await {
+                    Ok(Some(xfer)) => {
+                        let id = xfer.id();
+                        if let Err(err) = service::send_request(
+                            &state,
+                            &logger,
+                            refresh_trigger.clone(),
+                            guard.clone(),
+                            stop.clone(),
+                            xfer,
+                        )
+                        .await
+                        {
+                            state.shared_files.forget(id);
+                            warn!(logger, "Failed to send the shared directory changes: {err}");
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(err) => warn!(logger, "Failed to sync the shared directory: {err}"),
+                }
+
+                // Wait for the change and then for the changes to settle
+                if rx.recv().await.is_none() {
+                    return;
+                }
+                loop {
+                    match tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                        Ok(Some(())) => continue,
+                        Ok(None) => return,
+                        Err(_) => break,
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            biased;
+
+            _ = stop.cancelled() => {
+                debug!(logger, "Stopping shared directory watcher");
+            },
+            _ = task => (),
+        }
+    });
+
+    Ok(())
+}
+
+/// Creates the transfer of the files changed since the last sync, skipping the
+/// ones still in flight. `None` when there is nothing new to send
+async fn sync(
+    state: &Arc<State>,
+    logger: &Logger,
+    share: &Share,
+) -> crate::Result<Option<OutgoingTransfer>> {
+    let dir = share
+        .dir
+        .to_str()
+        .ok_or_else(|| crate::Error::BadPath("Shared directory is not valid UTF-8".into()))?;
+    let peer = share.peer_str();
+
+    let sent = state
+        .storage
+        .shared_files(dir, &peer)
+        .await
+        .into_iter()
+        .chain(state.shared_files.in_flight(dir, &peer))
+        .map(|file| (file.subpath.clone(), file))
+        .collect();
+
+    let config = state.config.clone();
+    let share_dir = share.dir.clone();
+    let (files, versions) =
+        tokio::task::spawn_blocking(move || changed_files(&config, &share_dir, &sent))
+            .await
+            .map_err(|_| crate::Error::Canceled)??;
+
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    let versions: HashMap<_, _> = files
+        .iter()
+        .map(|file| file.id().clone())
+        .zip(versions)
+        .collect();
+
+    let xfer = OutgoingTransfer::new(share.peer, files, &state.config)?
+        .with_hidden_files_skipped(state.config.skip_hidden_files)
+        .with_peer_scope_id(share.peer_scope_id)
+        .with_peer_port(share.peer_port);
+
+    info!(
+        logger,
+        "Sharing {} changed files of directory {:?} in transfer {}",
+        versions.len(),
+        utils::Hidden(&share.dir),
+        xfer.id()
+    );
+
+    state.shared_files.track(xfer.id(), dir, &peer, versions);
+    Ok(Some(xfer))
+}
+
+/// Gathers the files of the directory which differ from the versions already
+/// sent, together with their new versions
+fn changed_files(
+    config: &DropConfig,
+    dir: &Path,
+    sent: &HashMap<String, SharedFile>,
+) -> crate::Result<(Vec<FileToSend>, Vec<SharedFile>)> {
+    let files = GatherCtx::new(config).gather_from_path(dir)?.take();
+
+    Ok(files
+        .into_iter()
+        .filter_map(|file| {
+            let version = SharedFile {
+                subpath: file.subpath().to_string(),
+                size: file.size() as _,
+                mtime: file.metadata().mtime.unwrap_or_default(),
+            };
+
+            if sent.get(&version.subpath) == Some(&version) {
+                None
+            } else {
+                Some((file, version))
+            }
+        })
+        .unzip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn changed_since_last_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), b"a").unwrap();
+        fs::write(dir.path().join("b"), b"b").unwrap();
+
+        let config = DropConfig::default();
+        let (files, cursor) = changed_files(&config, dir.path(), &HashMap::new()).unwrap();
+        assert_eq!(files.len(), 2);
+
+        let mut sent: HashMap<_, _> = cursor
+            .into_iter()
+            .map(|file| (file.subpath.clone(), file))
+            .collect();
+        let (files, _) = changed_files(&config, dir.path(), &sent).unwrap();
+        assert!(files.is_empty());
+
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        sent.get_mut(&format!("{name}/b")).unwrap().mtime -= 1;
+        fs::write(dir.path().join("c"), b"c").unwrap();
+
+        let (_, cursor) = changed_files(&config, dir.path(), &sent).unwrap();
+        let mut changed: Vec<_> = cursor.into_iter().map(|file| file.subpath).collect();
+        changed.sort();
+        assert_eq!(changed, [format!("{name}/b"), format!("{name}/c")]);
+    }
+}
@@ -0,0 +1,47 @@
+//! The `tracing` spans of the transfers and their file tasks. The transfer
+//! span lives as long as the transfer is tracked by the manager, the file
+//! spans as long as the file is being uploaded or downloaded. Every span
+//! carries the `transfer` field, the file spans the `file` field as well, so
+//! the subscriber can attribute the logs and timings without walking the
+//! span tree.
+//!
+//! The spans go to the process-wide subscriber of the application, unless a
+//! dispatcher is set with [`set_dispatch()`]. The library itself never
+//! installs the global subscriber.
+
+use std::sync::OnceLock;
+
+use tracing::{Dispatch, Span};
+use uuid::Uuid;
+
+use crate::FileId;
+
+static DISPATCH: OnceLock<Dispatch> = OnceLock::new();
+
+/// Reports the transfer and file spans to the given dispatcher in place of
+/// the process-wide one. Only the first call takes effect
+pub fn set_dispatch(dispatch: Dispatch) {
+    let _ = DISPATCH.set(dispatch);
+}
+
+fn with_dispatch<T>(f: impl FnOnce() -> T) -> T {
+    match DISPATCH.get() {
+        Some(dispatch) => tracing::dispatcher::with_default(dispatch, f),
+        None => f(),
+    }
+}
+
+pub(crate) fn transfer(transfer_id: Uuid, direction: &'static str) -> Span {
+    with_dispatch(|| tracing::info_span!("transfer", transfer = %transfer_id, direction))
+}
+
+pub(crate) fn file(parent: &Span, transfer_id: Uuid, file_id: &FileId, task: &'static str) -> Span {
+    with_dispatch(
+        || tracing::info_span!(parent: parent, "file", transfer = %transfer_id, file = %file_id, task),
+    )
+}
+
+/// The span entered by the current task
+pub(crate) fn current() -> Span {
+    with_dispatch(Span::current)
+}
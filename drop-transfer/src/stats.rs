@@ -0,0 +1,203 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// The weight of the newest throughput sample
+const EWMA_ALPHA: f64 = 0.3;
+// The bytes are accumulated for at least this long before taking a sample
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+// The throughput is reported as zero when nothing was transferred for this long
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Live statistics of the transfer
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    /// Exponentially weighted moving average of the throughput in bytes per
+    /// second
+    pub throughput_bps: f64,
+    /// The bytes transferred since the transfer was created or restored
+    pub transferred_bytes: u64,
+    /// The received bytes dropped because of the corrupted chunks. Tracked by
+    /// the receiver only
+    pub retransmitted_bytes: u64,
+    /// Number of the connections made or attempted
+    pub connection_attempts: u32,
+    /// The round-trip time of the last WebSocket ping
+    pub rtt: Option<Duration>,
+}
+
+/// Counters of all the transfers since the service start
+#[derive(Debug, Clone, Default)]
+pub struct TransferCounters {
+    pub active_incoming: usize,
+    pub active_outgoing: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct StatsTracker {
+    inner: Mutex<StatsInner>,
+    // The total of all the transfers in the same direction
+    total: Arc<AtomicU64>,
+}
+
+/// The bytes transferred by all the transfers, summed up by their trackers
+#[derive(Default)]
+pub(crate) struct TransferTotals {
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+}
+
+impl TransferTotals {
+    pub(crate) fn incoming_tracker(&self) -> Arc<StatsTracker> {
+        Arc::new(StatsTracker {
+            inner: Default::default(),
+            total: self.received.clone(),
+        })
+    }
+
+    pub(crate) fn outgoing_tracker(&self) -> Arc<StatsTracker> {
+        Arc::new(StatsTracker {
+            inner: Default::default(),
+            total: self.sent.clone(),
+        })
+    }
+
+    pub(crate) fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+struct StatsInner {
+    stats: TransferStats,
+    // The start of the current sample and the bytes accumulated since then
+    sample: Option<(Instant, u64)>,
+    last_progress: Option<Instant>,
+    ping_sent: Option<Instant>,
+}
+
+impl StatsTracker {
+    pub(crate) fn transferred(&self, bytes: u64) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        let now = Instant::now();
+
+        inner.stats.transferred_bytes += bytes;
+        inner.last_progress = Some(now);
+
+        let (started, acc) = inner.sample.get_or_insert((now, 0));
+        *acc += bytes;
+        let (started, acc) = (*started, *acc);
+
+        let elapsed = now.duration_since(started);
+        if elapsed >= SAMPLE_INTERVAL {
+            let rate = acc as f64 / elapsed.as_secs_f64();
+            inner.sample = Some((now, 0));
+
+            inner.stats.throughput_bps = if inner.stats.throughput_bps == 0.0 {
+                rate
+            } else {
+                EWMA_ALPHA * rate + (1.0 - EWMA_ALPHA) * inner.stats.throughput_bps
+            };
+        }
+    }
+
+    pub(crate) fn retransmitted(&self, bytes: u64) {
+        self.inner
+            .lock()
+            .expect("Poisoned lock")
+            .stats
+            .retransmitted_bytes += bytes;
+    }
+
+    pub(crate) fn connection_attempt(&self) {
+        self.inner
+            .lock()
+            .expect("Poisoned lock")
+            .stats
+            .connection_attempts += 1;
+    }
+
+    pub(crate) fn ping_sent(&self) {
+        self.inner.lock().expect("Poisoned lock").ping_sent = Some(Instant::now());
+    }
+
+    pub(crate) fn pong_received(&self) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+        if let Some(sent) = inner.ping_sent.take() {
+            inner.stats.rtt = Some(sent.elapsed());
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> TransferStats {
+        let inner = self.inner.lock().expect("Poisoned lock");
+        let mut stats = inner.stats.clone();
+
+        let stalled = match inner.last_progress {
+            Some(last) => last.elapsed() >= STALL_TIMEOUT,
+            None => true,
+        };
+        if stalled {
+            stats.throughput_bps = 0.0;
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_stats() {
+        let tracker = StatsTracker::default();
+        assert_eq!(tracker.snapshot().throughput_bps, 0.0);
+
+        tracker.transferred(1000);
+        std::thread::sleep(SAMPLE_INTERVAL);
+        tracker.transferred(1000);
+
+        let stats = tracker.snapshot();
+        assert_eq!(stats.transferred_bytes, 2000);
+        assert!(stats.throughput_bps > 0.0 && stats.throughput_bps <= 4000.0);
+
+        tracker.retransmitted(10);
+        tracker.connection_attempt();
+        tracker.connection_attempt();
+        assert!(tracker.snapshot().rtt.is_none());
+
+        tracker.ping_sent();
+        tracker.pong_received();
+        // Unsolicited pong
+        tracker.pong_received();
+
+        let stats = tracker.snapshot();
+        assert_eq!(stats.retransmitted_bytes, 10);
+        assert_eq!(stats.connection_attempts, 2);
+        assert!(stats.rtt.is_some());
+    }
+
+    #[test]
+    fn transfer_totals() {
+        let totals = TransferTotals::default();
+
+        totals.incoming_tracker().transferred(100);
+        totals.incoming_tracker().transferred(20);
+        totals.outgoing_tracker().transferred(3);
+
+        assert_eq!(totals.received(), 120);
+        assert_eq!(totals.sent(), 3);
+    }
+}
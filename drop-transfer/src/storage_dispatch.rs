@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use drop_storage::Storage;
 use uuid::Uuid;
 
-use crate::{transfer::Transfer, FileId};
+use crate::{file::File as _, transfer::Transfer, FileId};
 
 pub struct StorageDispatch<'a> {
     storage: &'a drop_storage::Storage,
@@ -47,11 +47,21 @@ impl<'a> StorageDispatch<'a> {
                         download.id.as_ref(),
                         &download.final_path.to_string_lossy(),
                     )
-                    .await
+                    .await;
+
+                if let Some(file) = transfer.files().get(&download.id) {
+                    self.storage
+                        .insert_peer_usage(&transfer.peer().to_string(), file.size() as _)
+                        .await
+                }
             }
-            crate::Event::FileUploadSuccess(transfer, file_id) => {
+            crate::Event::FileUploadSuccess(transfer, file_id, final_path) => {
                 self.storage
-                    .insert_outgoing_path_completed_state(transfer.id(), file_id.as_ref())
+                    .insert_outgoing_path_completed_state(
+                        transfer.id(),
+                        file_id.as_ref(),
+                        final_path.as_deref(),
+                    )
                     .await
             }
             crate::Event::IncomingTransferCanceled(transfer, by_peer) => {
@@ -66,6 +76,18 @@ impl<'a> StorageDispatch<'a> {
                     .await;
                 self.clear_transfer(transfer.id());
             }
+            crate::Event::IncomingTransferWithdrawn(transfer, by_peer) => {
+                self.storage
+                    .insert_transfer_cancel_state(transfer.id(), *by_peer)
+                    .await;
+                self.clear_transfer(transfer.id());
+            }
+            crate::Event::OutgoingTransferWithdrawn(transfer, by_peer) => {
+                self.storage
+                    .insert_transfer_cancel_state(transfer.id(), *by_peer)
+                    .await;
+                self.clear_transfer(transfer.id());
+            }
             crate::Event::OutgoingTransferFailed(transfer, err, _) => {
                 self.storage
                     .insert_transfer_failed_state(transfer.id(), err.into())
@@ -82,7 +104,7 @@ impl<'a> StorageDispatch<'a> {
                     )
                     .await
             }
-            crate::Event::FileDownloadFailed(transfer, file_id, err) => {
+            crate::Event::FileDownloadFailed(transfer, file_id, err, _) => {
                 self.storage
                     .insert_incoming_path_failed_state(
                         transfer.id(),
@@ -126,6 +148,22 @@ impl<'a> StorageDispatch<'a> {
                     )
                     .await
             }
+            crate::Event::FileUploadWithdrawn {
+                transfer_id,
+                file_id,
+            } => {
+                self.storage
+                    .insert_outgoing_path_withdrawn_state(*transfer_id, file_id.as_ref())
+                    .await
+            }
+            crate::Event::FileDownloadWithdrawn {
+                transfer_id,
+                file_id,
+            } => {
+                self.storage
+                    .insert_incoming_path_withdrawn_state(*transfer_id, file_id.as_ref())
+                    .await
+            }
             crate::Event::FileUploadPaused {
                 transfer_id,
                 file_id,
@@ -154,9 +192,16 @@ impl<'a> StorageDispatch<'a> {
             // not stored in the database
             crate::Event::RequestReceived(_) => (),
             crate::Event::RequestQueued(_) => (),
+            // The added files are stored by the transfer manager
+            crate::Event::IncomingFilesAdded(..) => (),
+            crate::Event::OutgoingFilesAdded(..) => (),
             crate::Event::FileUploadThrottled { .. } => (),
 
             crate::Event::OutgoingTransferDeferred { .. } => (),
+            crate::Event::OutgoingTransferPostponed(_) => (),
+            crate::Event::OutgoingPeerKeyUnavailable(_) => (),
+            crate::Event::OutgoingConnectionAttemptFailed { .. } => (),
+            crate::Event::OutgoingTransferConnected { .. } => (),
 
             crate::Event::FinalizeChecksumStarted { .. } => (),
             crate::Event::FinalizeChecksumFinished { .. } => (),
@@ -167,6 +212,23 @@ impl<'a> StorageDispatch<'a> {
             crate::Event::VerifyChecksumProgress { .. } => (),
 
             crate::Event::FileDownloadPending { .. } => (),
+
+            // The storage is already reconciled
+            crate::Event::StartupReconciled(_) => (),
+            crate::Event::KeyRotationCompleted => (),
+            crate::Event::OperationRefused { .. } => (),
+            crate::Event::OutgoingConnectionDiagnostics { .. } => (),
+            crate::Event::IncomingDirectoryPrepared { .. } => (),
+            crate::Event::IncomingDownloadsQueued { .. } => (),
+            crate::Event::IncomingFileDuplicate { .. } => (),
+            crate::Event::OutgoingPreflightFinished { .. } => (),
+            crate::Event::IncomingTransferPaused { .. } => (),
+            crate::Event::OutgoingTransferPaused { .. } => (),
+            // The text messages are not persisted
+            crate::Event::IncomingTransferRejected { .. } => (),
+            crate::Event::IncomingText { .. } => (),
+            crate::Event::OutgoingTextSent { .. } => (),
+            crate::Event::OutgoingTextFailed { .. } => (),
         }
     }
 
@@ -1,21 +1,130 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
 use tokio::sync::mpsc;
 
 #[derive(Clone)]
-#[allow(unused)]
-pub struct AliveGuard(mpsc::Sender<()>);
+pub struct AliveGuard {
+    _alive: mpsc::Sender<()>,
+    registry: Arc<TaskRegistry>,
+    // Keeps the task registered until the guard and all of its clones are dropped
+    _task: Option<Arc<RegisteredTask>>,
+}
 
 pub struct AliveWaiter(AliveGuard, mpsc::Receiver<()>);
 
+/// Description of the background task holding the guard
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub subsystem: &'static str,
+    pub description: String,
+    pub started_at: Instant,
+}
+
+#[derive(Default)]
+struct TaskRegistry {
+    next_id: AtomicU64,
+    tasks: Mutex<HashMap<u64, TaskInfo>>,
+}
+
+struct RegisteredTask {
+    id: u64,
+    registry: Arc<TaskRegistry>,
+}
+
+impl AliveGuard {
+    /// Creates the guard of the new background task. The task is accounted
+    /// for under the given subsystem as long as the returned guard lives
+    pub fn task(&self, subsystem: &'static str, description: impl Into<String>) -> Self {
+        let id = self.registry.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.registry.tasks.lock().expect("Poisoned lock").insert(
+            id,
+            TaskInfo {
+                subsystem,
+                description: description.into(),
+                started_at: Instant::now(),
+            },
+        );
+
+        Self {
+            _alive: self._alive.clone(),
+            registry: self.registry.clone(),
+            _task: Some(Arc::new(RegisteredTask {
+                id,
+                registry: self.registry.clone(),
+            })),
+        }
+    }
+}
+
+impl Drop for RegisteredTask {
+    fn drop(&mut self) {
+        self.registry
+            .tasks
+            .lock()
+            .expect("Poisoned lock")
+            .remove(&self.id);
+    }
+}
+
 impl AliveWaiter {
     pub fn new() -> Self {
         let (send, recv) = mpsc::channel(1);
-        Self(AliveGuard(send), recv)
+        Self(
+            AliveGuard {
+                _alive: send,
+                registry: Default::default(),
+                _task: None,
+            },
+            recv,
+        )
     }
 
     pub fn guard(&self) -> AliveGuard {
         self.0.clone()
     }
 
+    /// The currently running background tasks, the oldest first
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let mut tasks: Vec<_> = self
+            .0
+            .registry
+            .tasks
+            .lock()
+            .expect("Poisoned lock")
+            .values()
+            .cloned()
+            .collect();
+
+        tasks.sort_by_key(|task| task.started_at);
+        tasks
+    }
+
+    /// The number of the currently running background tasks per subsystem
+    pub fn task_counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+
+        for task in self
+            .0
+            .registry
+            .tasks
+            .lock()
+            .expect("Poisoned lock")
+            .values()
+        {
+            *counts.entry(task.subsystem).or_default() += 1;
+        }
+
+        counts
+    }
+
     pub async fn wait_for_all(self) {
         // Drop the sender and wait for the receiver to get the notification about last
         // sender being dropped. Based on <https://tokio.rs/tokio/topics/shutdown>
@@ -26,3 +135,32 @@ impl AliveWaiter {
         let _ = recv.recv().await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_accounting() {
+        let waiter = AliveWaiter::new();
+        let guard = waiter.guard();
+        assert!(waiter.tasks().is_empty());
+
+        let server = guard.task("server", "Listener");
+        let client = guard.task("client", "Transfer");
+        let client_clone = client.clone();
+
+        assert_eq!(
+            waiter.task_counts(),
+            BTreeMap::from([("client", 1), ("server", 1)])
+        );
+        assert_eq!(waiter.tasks()[0].description, "Listener");
+
+        drop(client);
+        assert_eq!(waiter.tasks().len(), 2);
+
+        drop(client_clone);
+        drop(server);
+        assert!(waiter.tasks().is_empty());
+    }
+}
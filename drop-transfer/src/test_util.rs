@@ -0,0 +1,1388 @@
+//! In-process harness running two service instances against each other over
+//! the loopback interface. Used by the protocol matrix tests below and
+//! exposed with the `test-util` feature for the downstream crates.
+//!
+//! Protocol v6 is the only one spoken, the older versions were yanked, so the
+//! matrix covers the combinations of the optional features negotiated with
+//! the capabilities header instead.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use drop_auth::{PublicKey, SecretKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use drop_config::DropConfig;
+use drop_storage::Storage;
+use slog::Logger;
+use tempfile::TempDir;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    auth, file::GatherCtx, ConnectionPolicy, Event, File as _, IncomingFilter, OutgoingTransfer,
+    Service, StorageDispatch, Transfer as _,
+};
+
+const PRIV_KEY: [u8; SECRET_KEY_LENGTH] = [
+    0x15, 0xc6, 0xe3, 0x45, 0x08, 0xf8, 0x3e, 0x4d, 0x3a, 0x28, 0x9d, 0xd4, 0xa4, 0x05, 0x95, 0x8d,
+    0x8a, 0xa4, 0x68, 0x2d, 0x4a, 0xba, 0x4f, 0xf3, 0x2d, 0x8f, 0x72, 0x60, 0x4b, 0x69, 0x46, 0xc7,
+];
+const PUB_KEY: [u8; PUBLIC_KEY_LENGTH] = [
+    0x24, 0x0f, 0xcc, 0x7b, 0xbc, 0x11, 0x0c, 0x12, 0x7a, 0xed, 0xf9, 0x26, 0x8e, 0x9a, 0x24, 0xa4,
+    0x5a, 0x1b, 0x4c, 0xb1, 0x87, 0x4e, 0xff, 0x46, 0x5e, 0x56, 0x31, 0xb2, 0x33, 0x6b, 0xca, 0x6d,
+];
+
+// How long `Peer::wait_for()` waits for the matching event
+const EVENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The app callbacks consulted by the peer
+#[derive(Default)]
+pub struct Callbacks {
+    pub conn_policy: Option<Arc<ConnectionPolicy>>,
+    pub incoming_filter: Option<Arc<IncomingFilter>>,
+}
+
+/// The optional protocol features enabled on the peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    pub chunk_encryption: bool,
+    pub chunk_crc: bool,
+    pub resume_checksum: bool,
+}
+
+impl Features {
+    /// Every combination of the features
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..8u8).map(|bits| Self {
+            chunk_encryption: bits & 1 != 0,
+            chunk_crc: bits & 2 != 0,
+            resume_checksum: bits & 4 != 0,
+        })
+    }
+
+    /// Every pair of the sender and the receiver features
+    pub fn matrix() -> impl Iterator<Item = (Self, Self)> {
+        Self::all().flat_map(|sender| Self::all().map(move |receiver| (sender, receiver)))
+    }
+
+    pub fn apply(self, config: &mut DropConfig) {
+        config.chunk_encryption = self.chunk_encryption;
+        config.chunk_crc = self.chunk_crc;
+        config.checksum_threads = self.resume_checksum.then_some(2);
+    }
+}
+
+/// The service instance listening on the loopback address and the port picked
+/// by the system, with the in-memory storage and a temporary download
+/// directory
+pub struct Peer {
+    pub service: Service,
+    pub storage: Arc<Storage>,
+    pub config: Arc<DropConfig>,
+    pub addr: IpAddr,
+    pub port: u16,
+    events: mpsc::UnboundedReceiver<Event>,
+    dir: TempDir,
+    peer_keys_known: Arc<AtomicBool>,
+}
+
+impl Peer {
+    /// Starts the peer on `127.0.0.1`. All of the peers use the same key pair
+    pub async fn start(features: Features, logger: Logger) -> crate::Result<Self> {
+        Self::start_with(logger, |config, _| features.apply(config)).await
+    }
+
+    /// Starts the peer with the config adjusted by `configure`, which is
+    /// given the temporary directory of the peer
+    pub async fn start_with(
+        logger: Logger,
+        configure: impl FnOnce(&mut DropConfig, &Path),
+    ) -> crate::Result<Self> {
+        Self::launch(logger, configure, Callbacks::default()).await
+    }
+
+    /// Starts the peer with the default config consulting the app callbacks
+    pub async fn start_with_callbacks(logger: Logger, callbacks: Callbacks) -> crate::Result<Self> {
+        Self::launch(logger, |_, _| (), callbacks).await
+    }
+
+    async fn launch(
+        logger: Logger,
+        configure: impl FnOnce(&mut DropConfig, &Path),
+        callbacks: Callbacks,
+    ) -> crate::Result<Self> {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let dir = tempfile::tempdir()?;
+
+        // The system picks the port when the server binds, so the peers started
+        // in parallel never race for it
+        let mut config = DropConfig {
+            port: 0,
+            storage_path: ":memory:".to_string(),
+            ..Default::default()
+        };
+        configure(&mut config, dir.path());
+        let config = Arc::new(config);
+
+        let storage = Arc::new(Storage::new(logger.clone(), ":memory:")?);
+
+        let peer_keys_known = Arc::new(AtomicBool::new(true));
+        let auth = auth::Context::new(|| Some(SecretKey::from(PRIV_KEY)), {
+            let known = peer_keys_known.clone();
+            move |_| {
+                known
+                    .load(Ordering::Relaxed)
+                    .then(|| PublicKey::from(PUB_KEY))
+            }
+        });
+
+        // Record the events in the storage the way the apps do before passing
+        // them to the test
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        tokio::spawn({
+            let storage = storage.clone();
+            async move {
+                let mut dispatch = StorageDispatch::new(&storage);
+                while let Some((event, _)) = rx.recv().await {
+                    dispatch.handle_event(&event).await;
+                    if events_tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let service = Service::start(
+            vec![addr],
+            storage.clone(),
+            tx,
+            logger,
+            config.clone(),
+            drop_analytics::moose_mock(),
+            Arc::new(auth),
+            Instant::now(),
+            callbacks.conn_policy,
+            callbacks.incoming_filter,
+            #[cfg(unix)]
+            None,
+        )
+        .await?;
+        let port = service.listen_addrs()[0].port();
+
+        Ok(Self {
+            service,
+            storage,
+            config,
+            addr,
+            port,
+            events,
+            dir,
+            peer_keys_known,
+        })
+    }
+
+    /// Makes the public key callback provide the keys of the other peers or
+    /// not
+    pub fn set_peer_keys_known(&self, known: bool) {
+        self.peer_keys_known.store(known, Ordering::Relaxed);
+    }
+
+    /// The temporary directory the files are downloaded into
+    pub fn download_dir(&self) -> PathBuf {
+        self.dir.path().join("downloads")
+    }
+
+    /// Writes the file of the given size into the temporary directory, to be
+    /// sent to the other peer
+    pub fn create_file(&self, name: &str, size: usize) -> std::io::Result<PathBuf> {
+        let path = self.dir.path().join("src").join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    /// Sends the files to the other peer, returns the transfer ID
+    pub async fn send(&mut self, peer: &Peer, paths: &[PathBuf]) -> crate::Result<Uuid> {
+        let mut files = GatherCtx::new(&self.config);
+        for path in paths {
+            files.gather_from_path(path)?;
+        }
+
+        let xfer = OutgoingTransfer::new(peer.addr, files.take(), &self.config)?
+            .with_peer_port(Some(peer.port));
+        let xfid = xfer.id();
+
+        self.service.send_request(xfer).await?;
+        Ok(xfid)
+    }
+
+    /// Waits for the event the `filter` returns `Some` for, skipping the
+    /// others. Panics when it does not come in time
+    pub async fn wait_for<T>(&mut self, mut filter: impl FnMut(&Event) -> Option<T>) -> T {
+        let wait = async {
+            loop {
+                let event = self.events.recv().await.expect("Event channel closed");
+                if let Some(out) = filter(&event) {
+                    break out;
+                }
+            }
+        };
+
+        tokio::time::timeout(EVENT_TIMEOUT, wait)
+            .await
+            .expect("Timed out waiting for the event")
+    }
+
+    /// Accepts the next incoming transfer by downloading all of its files
+    /// into the download directory, returns the transfer ID
+    pub async fn accept_next(&mut self) -> crate::Result<Uuid> {
+        let xfer = self
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.clone()),
+                _ => None,
+            })
+            .await;
+
+        let dir = self.download_dir();
+        std::fs::create_dir_all(&dir)?;
+
+        for file in xfer.files().values() {
+            self.service
+                .download(xfer.id(), file.id(), &dir.to_string_lossy())
+                .await?;
+        }
+
+        Ok(xfer.id())
+    }
+
+    pub async fn stop(self) {
+        self.service.stop().await;
+    }
+
+    /// Stops the peer letting the files in flight finish first
+    pub async fn stop_graceful(self, timeout: Duration) {
+        self.service.stop_graceful(timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use drop_config::TransferMode;
+
+    use super::*;
+
+    async fn transfer_files(sender: Features, receiver: Features) {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(sender, logger.clone()).await.unwrap();
+        let mut bob = Peer::start(receiver, logger).await.unwrap();
+
+        let paths = vec![
+            alice.create_file("small", 1024).unwrap(),
+            alice.create_file("dir/big", 3 * 1024 * 1024 + 7).unwrap(),
+        ];
+        let xfid = alice.send(&bob, &paths).await.unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), xfid);
+
+        let mut pending: HashSet<_> = ["small", "big"].into_iter().collect();
+        while !pending.is_empty() {
+            let path = bob
+                .wait_for(|event| match event {
+                    Event::FileDownloadSuccess(_, info) => Some(info.final_path.0.clone()),
+                    Event::FileDownloadFailed(_, file, err, _) => {
+                        panic!("{sender:?} -> {receiver:?}: {file} failed: {err}")
+                    }
+                    _ => None,
+                })
+                .await;
+
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            assert!(pending.remove(name.as_str()), "Unexpected file {name}");
+
+            let src = paths.iter().find(|src| src.ends_with(&name)).unwrap();
+            assert_eq!(
+                std::fs::read(&path).unwrap(),
+                std::fs::read(src).unwrap(),
+                "{sender:?} -> {receiver:?}: {name} differs"
+            );
+        }
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn protocol_feature_matrix() {
+        for (sender, receiver) in Features::matrix() {
+            transfer_files(sender, receiver).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn read_only_refuses_new_transfers() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![alice.create_file("small", 1024).unwrap()];
+
+        alice.service.set_read_only(true);
+        let err = alice.send(&bob, &paths).await.unwrap_err();
+        assert!(matches!(err, crate::Error::OperationRefused), "{err:?}");
+        alice
+            .wait_for(|event| match event {
+                Event::OperationRefused {
+                    file_id: None,
+                    operation: crate::RefusedOperation::NewTransfer,
+                    ..
+                } => Some(()),
+                _ => None,
+            })
+            .await;
+
+        alice.service.set_read_only(false);
+        let xfid = alice.send(&bob, &paths).await.unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), xfid);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn allow_data_flow_follows_transfer_mode() {
+        use crate::RefusedOperation::{Download, NewTransfer, Upload};
+
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let transfer_id = Uuid::new_v4();
+
+        for (mode, allowed) in [
+            (
+                TransferMode::Both,
+                [NewTransfer, Upload, Download].as_slice(),
+            ),
+            (TransferMode::ReceiveOnly, &[Download]),
+            (TransferMode::SendOnly, &[NewTransfer, Upload]),
+        ] {
+            let mut peer = Peer::start_with(logger.clone(), |config, _| {
+                config.transfer_mode = mode;
+            })
+            .await
+            .unwrap();
+
+            for operation in [NewTransfer, Upload, Download] {
+                let allow = allowed.contains(&operation);
+                assert_eq!(
+                    peer.service
+                        .state
+                        .allow_data_flow(&logger, transfer_id, None, operation),
+                    allow,
+                    "{mode:?}: {operation:?}"
+                );
+
+                if !allow {
+                    let refused = peer
+                        .wait_for(|event| match event {
+                            Event::OperationRefused { operation, .. } => Some(*operation),
+                            _ => None,
+                        })
+                        .await;
+                    assert_eq!(refused, operation);
+                }
+            }
+
+            peer.stop().await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn receive_only_refuses_sending() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start_with(logger.clone(), |config, _| {
+            config.transfer_mode = TransferMode::ReceiveOnly;
+        })
+        .await
+        .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![alice.create_file("small", 1024).unwrap()];
+        let err = alice.send(&bob, &paths).await.unwrap_err();
+        assert!(matches!(err, crate::Error::OperationRefused), "{err:?}");
+
+        let err = alice
+            .service
+            .send_text(bob.addr, 0, Some(bob.port), "refused".into())
+            .unwrap_err();
+        assert!(matches!(err, crate::Error::OperationRefused), "{err:?}");
+
+        // Receiving is still allowed
+        let paths = vec![bob.create_file("small", 1024).unwrap()];
+        let xfid = bob.send(&alice, &paths).await.unwrap();
+        assert_eq!(alice.accept_next().await.unwrap(), xfid);
+        received_file(&mut alice).await;
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_only_refuses_receiving() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start_with(logger, |config, _| {
+            config.transfer_mode = TransferMode::SendOnly;
+        })
+        .await
+        .unwrap();
+
+        let paths = vec![alice.create_file("small", 1024).unwrap()];
+        let xfid = alice.send(&bob, &paths).await.unwrap();
+        let rejected = bob
+            .wait_for(|event| match event {
+                Event::IncomingTransferRejected {
+                    transfer_id,
+                    error: crate::Error::ReceiveDisabled,
+                    ..
+                } => Some(*transfer_id),
+                _ => None,
+            })
+            .await;
+        assert_eq!(rejected, xfid);
+
+        let id = alice
+            .service
+            .send_text(bob.addr, 0, Some(bob.port), "refused".into())
+            .unwrap();
+        let error = text_failure(&mut alice, id).await;
+        assert_eq!(error, crate::Error::Canceled.to_string());
+
+        while let Ok(event) = bob.events.try_recv() {
+            assert!(!matches!(event, Event::IncomingText { .. }));
+        }
+
+        // Sending is still allowed
+        let paths = vec![bob.create_file("small", 1024).unwrap()];
+        let xfid = bob.send(&alice, &paths).await.unwrap();
+        assert_eq!(alice.accept_next().await.unwrap(), xfid);
+        received_file(&mut alice).await;
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn missing_peer_key_is_retried() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![alice.create_file("small", 1024).unwrap()];
+
+        alice.set_peer_keys_known(false);
+        let xfid = alice.send(&bob, &paths).await.unwrap();
+        let unavailable = alice
+            .wait_for(|event| match event {
+                Event::OutgoingPeerKeyUnavailable(xfer) => Some(xfer.id()),
+                Event::OutgoingTransferFailed(..) => panic!("Transfer failed"),
+                _ => None,
+            })
+            .await;
+        assert_eq!(unavailable, xfid);
+
+        let deferred = alice
+            .wait_for(|event| match event {
+                Event::OutgoingTransferDeferred { error, .. } => {
+                    Some(matches!(error, crate::Error::PeerKeyUnavailable))
+                }
+                Event::OutgoingTransferFailed(..) => panic!("Transfer failed"),
+                _ => None,
+            })
+            .await;
+        assert!(deferred);
+
+        // The key is provided later and the transfer reconnects on request
+        alice.set_peer_keys_known(true);
+        alice.service.retry_transfer(xfid).await.unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), xfid);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_stops_connection_retries() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start_with(logger, |config, _| {
+            config.retry_policy.base_delay = Duration::from_millis(50);
+            config.retry_policy.jitter = false;
+        })
+        .await
+        .unwrap();
+
+        // The peer drops each connection, failing every attempt
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let peer = tokio::spawn({
+            let attempts = attempts.clone();
+            async move {
+                while let Ok((sock, _)) = listener.accept().await {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    drop(sock);
+                }
+            }
+        });
+        let mut files = GatherCtx::new(&alice.config);
+        files
+            .gather_from_path(alice.create_file("small", 1024).unwrap())
+            .unwrap();
+        let xfer = OutgoingTransfer::new(Ipv4Addr::LOCALHOST.into(), files.take(), &alice.config)
+            .unwrap()
+            .with_peer_port(Some(port));
+        let xfid = xfer.id();
+        alice.service.send_request(xfer).await.unwrap();
+
+        alice
+            .wait_for(|event| match event {
+                Event::OutgoingConnectionAttemptFailed { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+
+        // The retries are stopped by the time the cancellation returns
+        alice.service.cancel_all(xfid).await.unwrap();
+        assert!(!alice.service.is_outgoing_alive(xfid).await);
+        let canceled = alice
+            .wait_for(|event| match event {
+                Event::OutgoingTransferCanceled(xfer, _) => Some(xfer.id()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(canceled, xfid);
+
+        // Longer than the next few retries would take
+        let made = attempts.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), made);
+
+        peer.abort();
+        alice.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn incoming_transfer_dials_sender_port() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+        // Both listen on the ports picked by the system, not the config one
+        assert_ne!(alice.port, alice.config.port);
+
+        let paths = vec![alice.create_file("small", 1024).unwrap()];
+        alice.send(&bob, &paths).await.unwrap();
+
+        let peer_port = bob
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.peer_port()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(peer_port, Some(alice.port));
+        assert_eq!(
+            bob.storage.peer_listen_port(&alice.addr.to_string()).await,
+            Some(alice.port)
+        );
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn graceful_stop_drains_files() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![alice.create_file("big", 4 * 1024 * 1024).unwrap()];
+        let xfid = alice.send(&bob, &paths).await.unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), xfid);
+
+        // Keeps the file in flight for a while
+        let file = bob
+            .wait_for(|event| match event {
+                Event::FileDownloadStarted(_, file, _, _) => Some(file.clone()),
+                _ => None,
+            })
+            .await;
+        bob.service
+            .pace_file(xfid, &file, Some(2 * 1024 * 1024))
+            .await
+            .unwrap();
+        alice
+            .wait_for(|event| match event {
+                Event::FileUploadStarted(..) => Some(()),
+                _ => None,
+            })
+            .await;
+
+        alice.stop_graceful(Duration::from_secs(20)).await;
+
+        bob.wait_for(|event| match event {
+            Event::FileDownloadSuccess(..) => Some(()),
+            Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+            Event::FileDownloadPaused { .. } => panic!("Download interrupted"),
+            _ => None,
+        })
+        .await;
+
+        bob.stop().await;
+    }
+
+    /// Sends the files to `peer` through the `relay`
+    async fn send_via(
+        sender: &mut Peer,
+        relay: &Peer,
+        peer: &Peer,
+        paths: &[PathBuf],
+    ) -> crate::Result<Uuid> {
+        let mut files = GatherCtx::new(&sender.config);
+        for path in paths {
+            files.gather_from_path(path)?;
+        }
+
+        let xfer = OutgoingTransfer::new(relay.addr, files.take(), &sender.config)?
+            .with_peer_port(Some(relay.port))
+            .with_forward_to(format!("{}:{}", peer.addr, peer.port));
+        let xfid = xfer.id();
+
+        sender.service.send_request(xfer).await?;
+        Ok(xfid)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forwarded_transfer() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let relay = Peer::start_with(logger.clone(), |config, dir| {
+            config.forward_dir = Some(dir.join("forward").to_string_lossy().into_owned());
+            config.forward_peers = vec!["127.0.0.1".to_string()];
+        })
+        .await
+        .unwrap();
+        let mut carol = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![alice.create_file("forwarded", 64 * 1024).unwrap()];
+        let xfid = send_via(&mut alice, &relay, &carol, &paths).await.unwrap();
+
+        let xfer = carol
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.clone()),
+                _ => None,
+            })
+            .await;
+        assert_ne!(xfer.id(), xfid);
+        assert_eq!(xfer.origin_id(), xfid);
+        assert_eq!(xfer.forward_to(), None);
+
+        let dir = carol.download_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in xfer.files().values() {
+            carol
+                .service
+                .download(xfer.id(), file.id(), &dir.to_string_lossy())
+                .await
+                .unwrap();
+        }
+        carol
+            .wait_for(|event| match event {
+                Event::FileDownloadSuccess(..) => Some(()),
+                Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+                _ => None,
+            })
+            .await;
+
+        // The relay drops the stored files once the forwarded transfer ends
+        let stored =
+            PathBuf::from(relay.config.forward_dir.as_ref().unwrap()).join(xfid.to_string());
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        while stored.exists() {
+            assert!(
+                Instant::now() < deadline,
+                "Forwarded files were not removed"
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        alice.stop().await;
+        relay.stop().await;
+        carol.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forwarding_requires_allowed_peer() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut relay = Peer::start_with(logger.clone(), |config, dir| {
+            config.forward_dir = Some(dir.join("forward").to_string_lossy().into_owned());
+        })
+        .await
+        .unwrap();
+        let carol = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![alice.create_file("forwarded", 1024).unwrap()];
+        let xfid = send_via(&mut alice, &relay, &carol, &paths).await.unwrap();
+
+        // The relay refuses the request and the sender retries later
+        let refused = alice
+            .wait_for(|event| match event {
+                Event::OutgoingConnectionAttemptFailed { transfer, .. } => Some(transfer.id()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(refused, xfid);
+        while let Ok(event) = relay.events.try_recv() {
+            assert!(!matches!(event, Event::RequestReceived(..)), "{event:?}");
+        }
+
+        alice.stop().await;
+        relay.stop().await;
+        carol.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn download_many_is_atomic() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let paths = vec![
+            alice.create_file("first", 1024).unwrap(),
+            alice.create_file("second", 2048).unwrap(),
+        ];
+        let xfid = alice.send(&bob, &paths).await.unwrap();
+
+        let xfer = bob
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.clone()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(xfer.id(), xfid);
+
+        let dest = bob.download_dir();
+        let files: Vec<_> = xfer
+            .files()
+            .keys()
+            .map(|file_id| (file_id.clone(), dest.to_string_lossy().into_owned()))
+            .collect();
+        let file_ids: HashSet<_> = xfer.files().keys().cloned().collect();
+
+        // One unknown file fails the whole batch before anything is created
+        let mut invalid = files.clone();
+        invalid.push(("unknown".into(), dest.to_string_lossy().into_owned()));
+        let err = bob.service.download_many(xfid, &invalid).await.unwrap_err();
+        assert!(matches!(err, crate::Error::BadFileId), "{err:?}");
+        assert!(!dest.exists());
+
+        bob.service.download_many(xfid, &files).await.unwrap();
+        let queued = bob
+            .wait_for(|event| match event {
+                Event::IncomingDownloadsQueued {
+                    transfer_id,
+                    file_ids,
+                } if *transfer_id == xfid => Some(file_ids.clone()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(queued.into_iter().collect::<HashSet<_>>(), file_ids);
+
+        let mut downloaded = HashSet::new();
+        while downloaded.len() < file_ids.len() {
+            let file_id = bob
+                .wait_for(|event| match event {
+                    Event::FileDownloadSuccess(_, success) => Some(success.id.clone()),
+                    Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+                    _ => None,
+                })
+                .await;
+            downloaded.insert(file_id);
+        }
+        assert_eq!(downloaded, file_ids);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicate_files_are_skipped() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start_with(logger, |config, _| config.skip_duplicates = true)
+            .await
+            .unwrap();
+
+        let paths = vec![alice.create_file("shared", 4096).unwrap()];
+        let first = alice.send(&bob, &paths).await.unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), first);
+        let existing = bob
+            .wait_for(|event| match event {
+                Event::FileDownloadSuccess(xfer, success) if xfer.id() == first => {
+                    Some(success.final_path.0.to_path_buf())
+                }
+                Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+                _ => None,
+            })
+            .await;
+
+        let second = alice.send(&bob, &paths).await.unwrap();
+        let duplicate = bob
+            .wait_for(|event| match event {
+                Event::IncomingFileDuplicate {
+                    transfer_id,
+                    existing_path,
+                    ..
+                } if *transfer_id == second => Some(existing_path.clone()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(duplicate, existing);
+
+        bob.wait_for(|event| match event {
+            Event::FileDownloadRejected {
+                transfer_id,
+                by_peer: false,
+                ..
+            } if *transfer_id == second => Some(()),
+            _ => None,
+        })
+        .await;
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shared_directory_cursor_follows_delivery() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let path = alice.create_file("shared/a", 4096).unwrap();
+        let dir = path.parent().unwrap().to_path_buf();
+        alice
+            .service
+            .share_directory(&dir, bob.addr, 0, Some(bob.port))
+            .unwrap();
+
+        let xfer = bob
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.clone()),
+                _ => None,
+            })
+            .await;
+
+        // Nothing is persisted until the peer receives the file
+        let dir = dir.to_str().unwrap();
+        let peer = crate::utils::format_peer(bob.addr, 0, Some(bob.port));
+        assert!(alice.storage.shared_files(dir, &peer).await.is_empty());
+
+        let download_dir = bob.download_dir();
+        std::fs::create_dir_all(&download_dir).unwrap();
+        for file in xfer.files().values() {
+            bob.service
+                .download(xfer.id(), file.id(), &download_dir.to_string_lossy())
+                .await
+                .unwrap();
+        }
+
+        alice
+            .wait_for(|event| match event {
+                Event::FileUploadSuccess(upload, ..) if upload.id() == xfer.id() => Some(()),
+                _ => None,
+            })
+            .await;
+
+        let sent = alice.storage.shared_files(dir, &peer).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].size, 4096);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    async fn text_failure(peer: &mut Peer, id: Uuid) -> String {
+        peer.wait_for(|event| match event {
+            Event::OutgoingTextFailed { id: failed, error } if *failed == id => {
+                Some(error.to_string())
+            }
+            Event::OutgoingTextSent { id: sent } if *sent == id => panic!("Text delivered"),
+            _ => None,
+        })
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn text_messages_are_filtered() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start_with_callbacks(
+            logger.clone(),
+            Callbacks {
+                conn_policy: Some(Arc::new(|_, _| crate::ConnectionDecision::Deny)),
+                incoming_filter: Some(Arc::new(|_| crate::IncomingDecision::Reject)),
+            },
+        )
+        .await
+        .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        // Denied by the sender's connection policy
+        let id = alice
+            .service
+            .send_text(bob.addr, 0, Some(bob.port), "denied".into())
+            .unwrap();
+        let error = text_failure(&mut alice, id).await;
+        assert_eq!(error, crate::Error::ConnectionDenied.to_string());
+
+        // Rejected by the receiver's incoming filter
+        let id = bob
+            .service
+            .send_text(alice.addr, 0, Some(alice.port), "rejected".into())
+            .unwrap();
+        let error = text_failure(&mut bob, id).await;
+        assert_eq!(error, crate::Error::Canceled.to_string());
+
+        while let Ok(event) = alice.events.try_recv() {
+            assert!(!matches!(event, Event::IncomingText { .. }));
+        }
+        while let Ok(event) = bob.events.try_recv() {
+            assert!(!matches!(event, Event::IncomingText { .. }));
+        }
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peer_quota() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start_with(logger, |config, _| config.peer_quota_bytes = Some(10_000))
+            .await
+            .unwrap();
+
+        let first = alice
+            .send(&bob, &[alice.create_file("first", 6000).unwrap()])
+            .await
+            .unwrap();
+        bob.wait_for(|event| match event {
+            Event::RequestReceived(xfer) if xfer.id() == first => Some(()),
+            _ => None,
+        })
+        .await;
+
+        // The pending transfer counts already
+        let second = alice
+            .send(&bob, &[alice.create_file("second", 6000).unwrap()])
+            .await
+            .unwrap();
+        let from = bob
+            .wait_for(|event| match event {
+                Event::IncomingTransferRejected {
+                    transfer_id,
+                    peer,
+                    error: crate::Error::QuotaExceeded,
+                } if *transfer_id == second => Some(*peer),
+                Event::RequestReceived(xfer) if xfer.id() == second => panic!("Request received"),
+                _ => None,
+            })
+            .await;
+        assert_eq!(from, alice.addr);
+
+        let third = alice
+            .send(&bob, &[alice.create_file("third", 3000).unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), third);
+        bob.wait_for(|event| match event {
+            Event::FileDownloadSuccess(xfer, _) if xfer.id() == third => Some(()),
+            Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+            _ => None,
+        })
+        .await;
+        assert_eq!(bob.service.peer_quota_usage(alice.addr).await, 3000);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    /// Forwards the connections to the `target` port, flipping the byte at
+    /// `corrupt_at` of the data the first connection sends
+    async fn corrupting_proxy(target: u16, corrupt_at: usize) -> u16 {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut corrupt_at = Some(corrupt_at);
+            while let Ok((client, _)) = listener.accept().await {
+                let Ok(server) = TcpStream::connect((Ipv4Addr::LOCALHOST, target)).await else {
+                    continue;
+                };
+                let corrupt_at = corrupt_at.take();
+
+                tokio::spawn(async move {
+                    let (mut client_rx, mut client_tx) = client.into_split();
+                    let (mut server_rx, mut server_tx) = server.into_split();
+
+                    let upstream = async {
+                        let mut buf = vec![0; 64 * 1024];
+                        let mut pos = 0;
+                        loop {
+                            let n = client_rx.read(&mut buf).await?;
+                            if n == 0 {
+                                break;
+                            }
+                            if let Some(at) = corrupt_at.filter(|at| (pos..pos + n).contains(at)) {
+                                buf[at - pos] ^= 0xff;
+                            }
+                            pos += n;
+                            server_tx.write_all(&buf[..n]).await?;
+                        }
+                        server_tx.shutdown().await
+                    };
+                    let downstream = tokio::io::copy(&mut server_rx, &mut client_tx);
+
+                    let _ = tokio::join!(upstream, downstream);
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parallel_ranges_recover_lost_chunks() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let features = Features {
+            chunk_crc: true,
+            ..Default::default()
+        };
+
+        let mut alice = Peer::start_with(logger.clone(), |config, _| {
+            features.apply(config);
+            config.parallel_ranges = 4;
+        })
+        .await
+        .unwrap();
+        let mut bob = Peer::start(features, logger).await.unwrap();
+
+        let size = 64 * 1024 * 1024;
+        let path = alice.create_file("big", size).unwrap();
+        // Not downloaded, keeps the transfer and its stats alive
+        let pending = alice.create_file("pending", 1024).unwrap();
+
+        // Deep into the chunks of the second range
+        let proxy = corrupting_proxy(bob.port, size / 4 + 12345).await;
+
+        let mut files = GatherCtx::new(&alice.config);
+        files.gather_from_path(&path).unwrap();
+        files.gather_from_path(&pending).unwrap();
+        let xfer = OutgoingTransfer::new(bob.addr, files.take(), &alice.config)
+            .unwrap()
+            .with_peer_port(Some(proxy));
+        let xfid = xfer.id();
+        alice.service.send_request(xfer).await.unwrap();
+
+        let xfer = bob
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.clone()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(xfer.id(), xfid);
+        let file = xfer
+            .files()
+            .values()
+            .find(|file| file.subpath().name() == "big")
+            .unwrap()
+            .id()
+            .clone();
+        std::fs::create_dir_all(bob.download_dir()).unwrap();
+        bob.service
+            .download(xfid, &file, &bob.download_dir().to_string_lossy())
+            .await
+            .unwrap();
+
+        let received = bob
+            .wait_for(|event| match event {
+                Event::FileDownloadSuccess(_, info) => Some(info.final_path.0.clone()),
+                Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+                _ => None,
+            })
+            .await;
+        assert_eq!(
+            std::fs::read(received).unwrap(),
+            std::fs::read(&path).unwrap()
+        );
+
+        // Only the lost chunk was dropped and sent again, not the parts of the
+        // ranges streamed meanwhile
+        let stats = bob.service.transfer_stats(xfid).await.unwrap();
+        assert!(stats.retransmitted_bytes > 0);
+        assert!(stats.retransmitted_bytes <= drop_config::MAX_CHUNK_SIZE as u64);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn added_files() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start_with(logger, |config, _| config.transfer_file_limit = 2)
+            .await
+            .unwrap();
+
+        let first = alice.create_file("dir/first", 1024).unwrap();
+        let dir = first.parent().unwrap().to_path_buf();
+        let xfid = alice.send(&bob, std::slice::from_ref(&dir)).await.unwrap();
+        let received = bob
+            .wait_for(|event| match event {
+                Event::RequestReceived(xfer) => Some(xfer.id()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(received, xfid);
+
+        // The added file lands in the root directory of the request
+        let second = alice.create_file("dir/second", 2048).unwrap();
+        let mut files = GatherCtx::new(&alice.config);
+        files.gather_from_path(&dir).unwrap();
+        alice
+            .service
+            .add_files(xfid, files.take(), Default::default())
+            .await
+            .unwrap();
+        alice
+            .wait_for(|event| match event {
+                Event::OutgoingFilesAdded(..) => Some(()),
+                _ => None,
+            })
+            .await;
+
+        let (xfer, added) = bob
+            .wait_for(|event| match event {
+                Event::IncomingFilesAdded(xfer, files) => Some((xfer.clone(), files.clone())),
+                _ => None,
+            })
+            .await;
+        assert_eq!(added.len(), 1);
+        assert_eq!(xfer.files()[&added[0]].subpath().to_string(), "dir/second");
+
+        // Over the limit of the receiver, the files fail on the sender instead
+        // of staying queued
+        let mut files = GatherCtx::new(&alice.config);
+        files
+            .gather_from_path(alice.create_file("third", 16).unwrap())
+            .unwrap();
+        alice
+            .service
+            .add_files(xfid, files.take(), Default::default())
+            .await
+            .unwrap();
+
+        let refused = alice
+            .wait_for(|event| match event {
+                Event::OutgoingFilesAdded(_, files) => Some(files[0].clone()),
+                _ => None,
+            })
+            .await;
+        let failed = alice
+            .wait_for(|event| match event {
+                Event::FileUploadFailed(_, file_id, _) => Some(file_id.clone()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(failed, refused);
+
+        std::fs::create_dir_all(bob.download_dir()).unwrap();
+        bob.service
+            .download(xfid, &added[0], &bob.download_dir().to_string_lossy())
+            .await
+            .unwrap();
+        let received = bob
+            .wait_for(|event| match event {
+                Event::FileDownloadSuccess(_, info) => Some(info.final_path.0.clone()),
+                Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+                _ => None,
+            })
+            .await;
+        assert_eq!(
+            std::fs::read(received).unwrap(),
+            std::fs::read(second).unwrap()
+        );
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pending_outgoing_matches_request_key() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let mut alice = Peer::start(Features::default(), logger.clone())
+            .await
+            .unwrap();
+        let mut bob = Peer::start(Features::default(), logger).await.unwrap();
+
+        let dir = alice
+            .create_file("dir/first", 1024)
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        let mut files = GatherCtx::new(&alice.config);
+        files.gather_from_path(&dir).unwrap();
+        let xfer = OutgoingTransfer::new(bob.addr, files.take(), &alice.config)
+            .unwrap()
+            .with_peer_port(Some(bob.port))
+            .with_request_key(Some("first-key".into()));
+        let xfid = xfer.id();
+        alice.service.send_request(xfer).await.unwrap();
+        bob.wait_for(|event| match event {
+            Event::RequestReceived(..) => Some(()),
+            _ => None,
+        })
+        .await;
+
+        assert_eq!(
+            alice.service.pending_outgoing("first-key").await,
+            Some(xfid)
+        );
+        assert_eq!(alice.service.pending_outgoing("other-key").await, None);
+        // Kept with the transfer for the restarts
+        assert_eq!(
+            alice.storage.transfer_request_key(xfid).await.as_deref(),
+            Some("first-key")
+        );
+
+        // The file set is no longer the requested one
+        alice.create_file("dir/second", 1024).unwrap();
+        let mut files = GatherCtx::new(&alice.config);
+        files.gather_from_path(&dir).unwrap();
+        alice
+            .service
+            .add_files(xfid, files.take(), Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(alice.service.pending_outgoing("first-key").await, None);
+        assert_eq!(alice.storage.transfer_request_key(xfid).await, None);
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+
+    async fn received_file(peer: &mut Peer) -> Box<Path> {
+        peer.wait_for(|event| match event {
+            Event::FileDownloadSuccess(_, info) => Some(info.final_path.0.clone()),
+            Event::FileDownloadFailed(_, file, err, _) => panic!("{file} failed: {err}"),
+            _ => None,
+        })
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shared_connection_carries_both_directions() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let share = |config: &mut DropConfig, _: &Path| {
+            config.shared_connections = true;
+            config.connection_keep_alive = Some(Duration::from_secs(60));
+        };
+        let mut alice = Peer::start_with(logger.clone(), share).await.unwrap();
+        let mut bob = Peer::start_with(logger, share).await.unwrap();
+
+        let sent = alice.create_file("there", 300 * 1024).unwrap();
+        let xfid = alice.send(&bob, std::slice::from_ref(&sent)).await.unwrap();
+        assert_eq!(bob.accept_next().await.unwrap(), xfid);
+        let received = received_file(&mut bob).await;
+        assert_eq!(
+            std::fs::read(received).unwrap(),
+            std::fs::read(sent).unwrap()
+        );
+
+        // The transfer back goes over the connection alice opened
+        assert!(bob.service.state.mux.get(alice.addr).is_some());
+
+        let sent = bob.create_file("back", 300 * 1024).unwrap();
+        let xfid = bob.send(&alice, std::slice::from_ref(&sent)).await.unwrap();
+        assert_eq!(alice.accept_next().await.unwrap(), xfid);
+        let received = received_file(&mut alice).await;
+        assert_eq!(
+            std::fs::read(received).unwrap(),
+            std::fs::read(sent).unwrap()
+        );
+
+        assert!(alice.service.state.mux.get(bob.addr).is_some());
+
+        alice.stop().await;
+        bob.stop().await;
+    }
+}
@@ -0,0 +1,112 @@
+//! Short text messages sent to the peer, e.g. to share the clipboard between
+//! devices. The message is the body of the `POST /drop/<version>/text/<id>`
+//! request authenticated the same way as the transfers. The receiver only
+//! reports it with the event, nothing touches the filesystem. There are no
+//! retries, the sender learns right away whether the message was delivered.
+//! The connection policy and the incoming filter are consulted as for the
+//! transfers, with the message ID in place of the transfer ID
+
+use std::{io, net::IpAddr, sync::Arc};
+
+use hyper::{body::Bytes, Method, StatusCode};
+use slog::{debug, info, warn, Logger};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    check::{self, RequestError},
+    protocol,
+    service::{ConnectionDecision, State},
+    tasks::AliveGuard,
+    utils, Error, Event,
+};
+
+// The limit of the message size in bytes, checked by both sides
+pub(crate) const MAX_TEXT_LEN: usize = 64 * 1024;
+
+pub(crate) struct Text {
+    pub(crate) id: Uuid,
+    pub(crate) peer: IpAddr,
+    pub(crate) peer_scope_id: u32,
+    pub(crate) peer_port: Option<u16>,
+    pub(crate) text: String,
+}
+
+pub(crate) fn spawn(
+    text: Text,
+    state: Arc<State>,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) {
+    let guard = guard.task("text", format!("Text message {}", text.id));
+
+    tokio::spawn(async move {
+        let _guard = guard;
+
+        tokio::select! {
+            biased;
+
+            _ = stop.cancelled() => {
+                debug!(logger, "Stopping text message {}", text.id);
+            },
+            res = send(&state, &text, &logger) => {
+                let event = match res {
+                    Ok(()) => Event::OutgoingTextSent { id: text.id },
+                    Err(error) => {
+                        warn!(logger, "Failed to send text message {}: {error}", text.id);
+                        Event::OutgoingTextFailed { id: text.id, error }
+                    }
+                };
+                state.emit_event(&logger, event);
+            },
+        }
+    });
+}
+
+async fn send(state: &State, text: &Text, logger: &Logger) -> crate::Result<()> {
+    // Without the retries the postponed message is not sent either
+    match state.connection_decision(text.peer, text.id) {
+        Some(ConnectionDecision::Allow) | None => (),
+        Some(ConnectionDecision::Postpone | ConnectionDecision::Deny) => {
+            info!(
+                logger,
+                "Connection policy denied the text message {}", text.id
+            );
+            return Err(Error::ConnectionDenied);
+        }
+    }
+
+    let addr = utils::peer_socket_addr(
+        text.peer,
+        text.peer_scope_id,
+        text.peer_port.unwrap_or(state.config.port),
+    );
+    let path = format!("/drop/{}/text/{}", protocol::Version::V6, text.id);
+
+    let status = check::request(
+        state,
+        addr,
+        Method::POST,
+        &path,
+        Bytes::copy_from_slice(text.text.as_bytes()),
+        logger,
+    )
+    .await
+    .map_err(|err| match err {
+        RequestError::General(err) => {
+            Error::Io(io::Error::new(io::ErrorKind::Other, format!("{err:#}")))
+        }
+        RequestError::UnexpectedResponse(_) => Error::UnexpectedData,
+    })?;
+
+    match status {
+        StatusCode::OK => Ok(()),
+        StatusCode::UNAUTHORIZED => Err(Error::AuthenticationFailed),
+        StatusCode::TOO_MANY_REQUESTS => Err(Error::TooManyRequests),
+        StatusCode::FORBIDDEN => Err(Error::Canceled),
+        StatusCode::SERVICE_UNAVAILABLE => Err(Error::PeerBusy),
+        StatusCode::PAYLOAD_TOO_LARGE => Err(Error::TransferLimitsExceeded),
+        _ => Err(Error::UnexpectedData),
+    }
+}
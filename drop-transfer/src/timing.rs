@@ -0,0 +1,94 @@
+//! Lightweight timing of the chunk pipeline stages, enabled with the
+//! `pipeline_timing` config option. The time spent in each stage is summed up
+//! per file and reported in the diagnostics snapshot
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stage {
+    Read,
+    Encrypt,
+    Send,
+    Recv,
+    Decrypt,
+    Write,
+}
+
+const STAGES: usize = 6;
+
+/// The total time spent in each stage of the chunk pipeline of the file. The
+/// upload stages stay zero for the incoming files and the download stages for
+/// the outgoing ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StageTimings {
+    pub read: Duration,
+    pub encrypt: Duration,
+    pub send: Duration,
+    pub recv: Duration,
+    pub decrypt: Duration,
+    pub write: Duration,
+}
+
+#[derive(Default)]
+pub struct StageTimer {
+    nanos: [AtomicU64; STAGES],
+}
+
+impl StageTimer {
+    pub(crate) fn record(&self, stage: Stage, started: Instant) {
+        let elapsed = started.elapsed().as_nanos() as u64;
+        self.nanos[stage as usize].fetch_add(elapsed, Ordering::Relaxed);
+    }
+
+    /// The timings, `None` when nothing was measured
+    pub(crate) fn timings(&self) -> Option<StageTimings> {
+        let nanos: [u64; STAGES] =
+            std::array::from_fn(|stage| self.nanos[stage].load(Ordering::Relaxed));
+        if nanos.iter().all(|&n| n == 0) {
+            return None;
+        }
+
+        let stage = |stage: Stage| Duration::from_nanos(nanos[stage as usize]);
+        Some(StageTimings {
+            read: stage(Stage::Read),
+            encrypt: stage(Stage::Encrypt),
+            send: stage(Stage::Send),
+            recv: stage(Stage::Recv),
+            decrypt: stage(Stage::Decrypt),
+            write: stage(Stage::Write),
+        })
+    }
+}
+
+/// Records the stage when the timing is enabled
+pub(crate) fn record(timer: Option<&StageTimer>, stage: Stage, started: Instant) {
+    if let Some(timer) = timer {
+        timer.record(stage, started);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulates_per_stage() {
+        let timer = StageTimer::default();
+        assert_eq!(timer.timings(), None);
+
+        let started = Instant::now() - Duration::from_millis(10);
+        timer.record(Stage::Write, started);
+        timer.record(Stage::Write, started);
+        record(Some(&timer), Stage::Recv, started);
+        record(None, Stage::Read, started);
+
+        let timings = timer.timings().unwrap();
+        assert!(timings.write >= Duration::from_millis(20));
+        assert!(timings.recv >= Duration::from_millis(10));
+        assert_eq!(timings.read, Duration::ZERO);
+        assert_eq!(timings.send, Duration::ZERO);
+    }
+}
@@ -0,0 +1,203 @@
+//! TLS layer of the transport. The certificates are derived from the X25519
+//! identities (see [`drop_auth::cert`]) so no additional key distribution is
+//! needed. The client verifies that the server's certificate belongs to the
+//! peer's public key, while the client itself is still authenticated with the
+//! `authorization` HTTP header inside the TLS tunnel.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use drop_auth::cert::{self, CertKey};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientHello, ResolvesServerCert},
+    sign::{CertifiedKey, Signer, SigningKey},
+    Certificate, CertificateError, ClientConfig, ServerConfig, ServerName, SignatureAlgorithm,
+    SignatureScheme,
+};
+use tokio::{io::DuplexStream, net::TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_util::either::Either;
+
+use crate::auth;
+
+/// The plain stream towards the peer, either its own connection or the
+/// logical stream of the connection shared with the peer
+pub type PlainStream = Either<TcpStream, DuplexStream>;
+
+/// The stream towards the peer, either plain or TLS protected
+pub type PeerStream = Either<PlainStream, Box<tokio_rustls::client::TlsStream<TcpStream>>>;
+
+/// The address of the peer connected over TLS or the shared connection,
+/// passed to the request handlers as the request extension
+#[derive(Clone, Copy)]
+pub(crate) struct TlsPeer(pub SocketAddr);
+
+pub(crate) fn acceptor(auth: Arc<auth::Context>) -> crate::Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|_| crate::Error::InvalidArgument)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(IdentityCert { auth }));
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Wraps the plain connection with TLS if enabled
+pub(crate) async fn connect(
+    enabled: bool,
+    auth: &auth::Context,
+    stream: TcpStream,
+    peer: IpAddr,
+) -> io::Result<PeerStream> {
+    if !enabled {
+        return Ok(Either::Left(Either::Left(stream)));
+    }
+
+    let expected = tokio::task::block_in_place(|| auth.peer_public_key(peer))
+        .and_then(|pubkey| cert::peer_cert_key(&pubkey))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Missing peer's public key"))?;
+
+    let config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+        .with_custom_certificate_verifier(Arc::new(PeerKeyVerifier { expected }))
+        .with_no_client_auth();
+
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::IpAddress(peer), stream)
+        .await?;
+
+    Ok(Either::Right(Box::new(stream)))
+}
+
+/// Serves the certificate derived from the current private key
+struct IdentityCert {
+    auth: Arc<auth::Context>,
+}
+
+impl ResolvesServerCert for IdentityCert {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let secret = tokio::task::block_in_place(|| self.auth.secret_key())?;
+        let key = CertKey::new(&secret);
+
+        Some(Arc::new(CertifiedKey::new(
+            vec![Certificate(key.certificate())],
+            Arc::new(IdentityKey(Arc::new(key))),
+        )))
+    }
+}
+
+#[derive(Clone)]
+struct IdentityKey(Arc<CertKey>);
+
+impl SigningKey for IdentityKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        if offered.contains(&SignatureScheme::ED25519) {
+            Some(Box::new(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ED25519
+    }
+}
+
+impl Signer for IdentityKey {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        Ok(self.0.sign(message).to_vec())
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::ED25519
+    }
+}
+
+/// Accepts only the certificate derived from the peer's public key
+struct PeerKeyVerifier {
+    expected: [u8; 32],
+}
+
+impl ServerCertVerifier for PeerKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _: &[Certificate],
+        _: &ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let key = cert::certificate_key(&end_entity.0).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+
+        if key == self.expected {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use drop_auth::{PublicKey, SecretKey};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn context(secret: [u8; 32], peer: [u8; 32]) -> Arc<auth::Context> {
+        let peer = PublicKey::from(&SecretKey::from(peer));
+        Arc::new(auth::Context::new(
+            move || Some(SecretKey::from(secret)),
+            move |_| Some(peer),
+        ))
+    }
+
+    async fn handshake(server_secret: [u8; 32], expected_secret: [u8; 32]) -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let acceptor = acceptor(context(server_secret, [0; 32])).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = acceptor.accept(stream).await?;
+            stream.write_all(b"hello").await?;
+            stream.flush().await?;
+            io::Result::Ok(())
+        });
+
+        let client = context([0; 32], expected_secret);
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connect(true, &client, stream, addr.ip()).await?;
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        let _ = server.await;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peer_certificate_verification() {
+        handshake([1; 32], [1; 32]).await.unwrap();
+        assert!(handshake([1; 32], [2; 32]).await.is_err());
+    }
+}
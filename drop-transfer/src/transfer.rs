@@ -1,4 +1,4 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{collections::HashMap, net::IpAddr, time::SystemTime};
 
 use drop_analytics::{TransferDirection, TransferIntentEventData};
 use drop_config::DropConfig;
@@ -6,10 +6,13 @@ use drop_storage::TransferInfo as StorageInfo;
 use uuid::Uuid;
 
 use crate::{
-    file::{File, FileId, FileSource, FileSubPath, FileToRecv, FileToSend},
-    utils, Error,
+    file::{DirEntries, File, FileId, FileSource, FileSubPath, FileToRecv, FileToSend},
+    utils, Error, PeerDevice,
 };
 
+/// The longest transfer label accepted, in bytes
+pub const MAX_LABEL_LEN: usize = 4096;
+
 pub type IncomingTransfer = TransferData<FileToRecv>;
 pub type OutgoingTransfer = TransferData<FileToSend>;
 
@@ -22,6 +25,11 @@ pub trait Transfer {
 
     fn direction() -> TransferDirection;
 
+    /// The aggregate size of all the files in bytes
+    fn total_size(&self) -> u64 {
+        total_size(self.files().values())
+    }
+
     fn file_by_subpath(&self, file_subpath: &FileSubPath) -> Option<&Self::File> {
         self.files()
             .values()
@@ -66,13 +74,77 @@ pub trait Transfer {
     }
 }
 
+/// The store-and-forward routing of a transfer passing through a relay
+/// instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forwarding {
+    /// The transfer ID given by the original sender, kept on every hop
+    pub origin_id: Uuid,
+    /// The final peer the relay delivers the files to. Set only on the hop
+    /// from the original sender to the relay
+    pub forward_to: Option<String>,
+}
+
+impl From<drop_storage::TransferForwarding> for Forwarding {
+    fn from(value: drop_storage::TransferForwarding) -> Self {
+        let drop_storage::TransferForwarding {
+            origin_id,
+            forward_to,
+        } = value;
+
+        Self {
+            origin_id,
+            forward_to,
+        }
+    }
+}
+
+impl From<&Forwarding> for drop_storage::TransferForwarding {
+    fn from(value: &Forwarding) -> Self {
+        Self {
+            origin_id: value.origin_id,
+            forward_to: value.forward_to.clone(),
+        }
+    }
+}
+
+// The sizes come from the peer, summed up without overflowing
+fn total_size<'a, F: File + 'a>(files: impl IntoIterator<Item = &'a F>) -> u64 {
+    files
+        .into_iter()
+        .fold(0, |total, file| total.saturating_add(file.size()))
+}
+
 #[derive(Debug)]
 pub struct TransferData<F: File> {
     peer: IpAddr,
+    // IPv6 zone index of the peer, `0` if not present
+    peer_scope_id: u32,
+    // The port the peer listens on, if different from the configured one
+    peer_port: Option<u16>,
     uuid: Uuid,
 
     // all the files
     files: HashMap<FileId, F>,
+    // the empty directories and the symlinks of the directory transfers
+    entries: DirEntries,
+    // the number of the entries skipped by the exclude patterns, sender only
+    excluded: usize,
+    // the hidden files were left out of the directories
+    hidden_files_skipped: bool,
+    // the routing of the transfers passing through a relay
+    forwarding: Option<Forwarding>,
+    // the digest of the request the transfer was created from, sender only
+    request_key: Option<String>,
+    // the deadline for the receiver to start the files, sender only
+    expires_at: Option<SystemTime>,
+    // the opaque label given by the app creating the transfer
+    label: Option<String>,
+    // the name and the platform the peer declared in the handshake
+    peer_device: Option<PeerDevice>,
+    // the peer's names of the root directories keyed by the local ones,
+    // receiver only
+    root_names: HashMap<String, String>,
 }
 
 impl<F: File> TransferData<F> {
@@ -94,12 +166,265 @@ impl<F: File> TransferData<F> {
             return Err(Error::TransferLimitsExceeded);
         }
 
+        if let Some(limit) = config.transfer_size_limit_bytes {
+            if total_size(&files) > limit {
+                return Err(Error::TransferLimitsExceeded);
+            }
+        }
+
         let files = files
             .into_iter()
             .map(|file| (file.id().clone(), file))
             .collect();
 
-        Ok(Self { peer, uuid, files })
+        Ok(Self {
+            peer,
+            peer_scope_id: 0,
+            peer_port: None,
+            uuid,
+            files,
+            entries: DirEntries::default(),
+            excluded: 0,
+            hidden_files_skipped: false,
+            forwarding: None,
+            request_key: None,
+            expires_at: None,
+            label: None,
+            peer_device: None,
+            root_names: HashMap::new(),
+        })
+    }
+
+    /// Adds the empty directories and the symlinks to be recreated by the
+    /// receiver. The entries outside of the transfer directories are dropped
+    pub fn with_dir_entries(
+        mut self,
+        mut entries: DirEntries,
+        config: &DropConfig,
+    ) -> crate::Result<Self> {
+        entries.sanitize();
+
+        if self.files.len() + entries.len() > config.transfer_file_limit {
+            return Err(Error::TransferLimitsExceeded);
+        }
+
+        self.entries = entries;
+        Ok(self)
+    }
+
+    pub fn dir_entries(&self) -> &DirEntries {
+        &self.entries
+    }
+
+    /// Records the number of the directory entries skipped by the exclude
+    /// patterns when gathering the files
+    pub fn with_excluded(mut self, excluded: usize) -> Self {
+        self.excluded = excluded;
+        self
+    }
+
+    pub fn excluded(&self) -> usize {
+        self.excluded
+    }
+
+    /// Marks the hidden files as left out of the transfer directories. Such
+    /// files are dropped from the transfer so both peers agree on the file
+    /// list
+    pub fn with_hidden_files_skipped(mut self, skipped: bool) -> Self {
+        if skipped {
+            self.files
+                .retain(|_, file| !file.subpath().is_hidden_below_root());
+            self.entries
+                .empty_dirs
+                .retain(|dir| !dir.is_hidden_below_root());
+            self.entries
+                .symlinks
+                .retain(|link| !link.path.is_hidden_below_root());
+        }
+
+        self.hidden_files_skipped = skipped;
+        self
+    }
+
+    pub fn hidden_files_skipped(&self) -> bool {
+        self.hidden_files_skipped
+    }
+
+    /// Sets the IPv6 zone index used to reach link-local peers
+    pub fn with_peer_scope_id(mut self, scope_id: u32) -> Self {
+        self.peer_scope_id = scope_id;
+        self
+    }
+
+    pub fn peer_scope_id(&self) -> u32 {
+        self.peer_scope_id
+    }
+
+    /// Sets the port the peer is listening on. Without it the port from the
+    /// config is used
+    pub fn with_peer_port(mut self, port: Option<u16>) -> Self {
+        self.peer_port = port;
+        self
+    }
+
+    pub fn peer_port(&self) -> Option<u16> {
+        self.peer_port
+    }
+
+    /// Asks the peer, acting as a relay, to store the files and forward them
+    /// to the given final peer
+    pub fn with_forward_to(mut self, peer: String) -> Self {
+        self.forwarding = Some(Forwarding {
+            origin_id: self.uuid,
+            forward_to: Some(peer),
+        });
+        self
+    }
+
+    pub fn with_forwarding(mut self, forwarding: Option<Forwarding>) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    pub fn forwarding(&self) -> Option<&Forwarding> {
+        self.forwarding.as_ref()
+    }
+
+    /// The final peer the relay delivers the files to
+    pub fn forward_to(&self) -> Option<&str> {
+        self.forwarding.as_ref()?.forward_to.as_deref()
+    }
+
+    /// Records the digest of the peer and the file set the transfer was
+    /// requested with, used to find the pending transfers requested again
+    pub fn with_request_key(mut self, request_key: Option<String>) -> Self {
+        self.request_key = request_key;
+        self
+    }
+
+    pub fn request_key(&self) -> Option<&str> {
+        self.request_key.as_deref()
+    }
+
+    /// Sets the deadline for the receiver to start the files. The files not
+    /// started by then expire
+    pub fn with_expiry(mut self, expires_at: Option<SystemTime>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    /// Attaches the opaque label, e.g. of the conversation the transfer
+    /// belongs to. The label is sent to the receiver with the request
+    pub fn with_label(mut self, label: Option<String>) -> crate::Result<Self> {
+        if label
+            .as_ref()
+            .is_some_and(|label| label.len() > MAX_LABEL_LEN)
+        {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.label = label;
+        Ok(self)
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Attaches the device the peer declared in the handshake of the
+    /// connection carrying the transfer
+    pub fn with_peer_device(mut self, device: Option<PeerDevice>) -> Self {
+        self.peer_device = device;
+        self
+    }
+
+    pub fn peer_device(&self) -> Option<&PeerDevice> {
+        self.peer_device.as_ref()
+    }
+
+    /// Records how the root directory names of the request were mapped into
+    /// the local ones, so that the files added later land in the same roots
+    pub(crate) fn with_root_names(mut self, root_names: HashMap<String, String>) -> Self {
+        self.root_names = root_names;
+        self
+    }
+
+    pub(crate) fn root_names(&self) -> &HashMap<String, String> {
+        &self.root_names
+    }
+
+    /// The transfer ID given by the original sender. The same as the
+    /// transfer ID for the transfers not passing through a relay
+    pub fn origin_id(&self) -> Uuid {
+        self.forwarding
+            .as_ref()
+            .map_or(self.uuid, |fwd| fwd.origin_id)
+    }
+}
+
+impl<F: File + Clone> TransferData<F> {
+    /// The same transfer with the files and the directory entries added. The
+    /// files already in the transfer are skipped, the limits apply to the
+    /// whole transfer
+    pub(crate) fn with_added_files(
+        &self,
+        files: Vec<F>,
+        mut entries: DirEntries,
+        config: &DropConfig,
+    ) -> crate::Result<Self> {
+        entries.sanitize();
+
+        let mut all_files = self.files.clone();
+        for file in files {
+            if self.hidden_files_skipped && file.subpath().is_hidden_below_root() {
+                continue;
+            }
+            all_files.entry(file.id().clone()).or_insert(file);
+        }
+
+        let mut all_entries = self.entries.clone();
+        for dir in entries.empty_dirs {
+            if !all_entries.empty_dirs.contains(&dir) {
+                all_entries.empty_dirs.push(dir);
+            }
+        }
+        for link in entries.symlinks {
+            if !all_entries.symlinks.contains(&link) {
+                all_entries.symlinks.push(link);
+            }
+        }
+
+        if all_files.len() + all_entries.len() > config.transfer_file_limit {
+            return Err(Error::TransferLimitsExceeded);
+        }
+
+        if let Some(limit) = config.transfer_size_limit_bytes {
+            if total_size(all_files.values()) > limit {
+                return Err(Error::TransferLimitsExceeded);
+            }
+        }
+
+        Ok(Self {
+            peer: self.peer,
+            peer_scope_id: self.peer_scope_id,
+            peer_port: self.peer_port,
+            uuid: self.uuid,
+            files: all_files,
+            entries: all_entries,
+            excluded: self.excluded,
+            hidden_files_skipped: self.hidden_files_skipped,
+            forwarding: self.forwarding.clone(),
+            // The file set is no longer the requested one
+            request_key: None,
+            expires_at: self.expires_at,
+            label: self.label.clone(),
+            peer_device: self.peer_device.clone(),
+            root_names: self.root_names.clone(),
+        })
     }
 }
 
@@ -124,30 +449,76 @@ impl<F: File> Transfer for TransferData<F> {
 }
 
 impl IncomingTransfer {
+    /// The text message as seen by the incoming filter, the request with the
+    /// message ID and no files
+    pub(crate) fn text_request(peer: IpAddr, id: Uuid) -> Self {
+        Self {
+            peer,
+            peer_scope_id: 0,
+            peer_port: None,
+            uuid: id,
+            files: HashMap::new(),
+            entries: DirEntries::default(),
+            excluded: 0,
+            hidden_files_skipped: false,
+            forwarding: None,
+            request_key: None,
+            expires_at: None,
+            label: None,
+            peer_device: None,
+            root_names: HashMap::new(),
+        }
+    }
+
     pub(crate) fn storage_info(&self) -> StorageInfo {
+        StorageInfo {
+            id: self.id(),
+            // The listen port of the sender is kept with the peer, since it
+            // outlives the transfer
+            peer: utils::format_peer(self.peer, self.peer_scope_id, None),
+            files: self.storage_files(|_| true),
+        }
+    }
+
+    /// The files matching the filter as stored in the database
+    pub(crate) fn storage_files(
+        &self,
+        filter: impl Fn(&FileId) -> bool,
+    ) -> drop_storage::types::TransferFiles {
         let files = self
             .files
             .values()
+            .filter(|f| filter(f.id()))
             .map(|f| drop_storage::types::TransferIncomingPath {
                 file_id: f.id().to_string(),
                 relative_path: f.subpath().to_string(),
                 size: f.size() as _,
+                metadata: f.metadata().clone().into(),
             })
             .collect();
 
-        StorageInfo {
-            id: self.id(),
-            peer: self.peer().to_string(),
-            files: drop_storage::types::TransferFiles::Incoming(files),
-        }
+        drop_storage::types::TransferFiles::Incoming(files)
     }
 }
 
 impl OutgoingTransfer {
     pub(crate) fn storage_info(&self) -> StorageInfo {
+        StorageInfo {
+            id: self.id(),
+            peer: utils::format_peer(self.peer, self.peer_scope_id, self.peer_port),
+            files: self.storage_files(|_| true),
+        }
+    }
+
+    /// The files matching the filter as stored in the database
+    pub(crate) fn storage_files(
+        &self,
+        filter: impl Fn(&FileId) -> bool,
+    ) -> drop_storage::types::TransferFiles {
         let files = self
             .files
             .values()
+            .filter(|f| filter(f.id()))
             .filter_map(|f| {
                 let uri = match &f.source {
                     FileSource::Path(fullpath) => url::Url::from_file_path(&fullpath.0).ok()?,
@@ -160,15 +531,45 @@ impl OutgoingTransfer {
                     relative_path: f.subpath().to_string(),
                     uri,
                     size: f.size() as _,
+                    metadata: f.metadata().clone().into(),
                 })
             })
             .collect();
 
-        let files = drop_storage::types::TransferFiles::Outgoing(files);
-        StorageInfo {
-            id: self.id(),
-            peer: self.peer().to_string(),
-            files,
-        }
+        drop_storage::types::TransferFiles::Outgoing(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_limit() {
+        let config = DropConfig {
+            transfer_size_limit_bytes: Some(1024),
+            ..Default::default()
+        };
+        let peer = IpAddr::from([127, 0, 0, 1]);
+        let file = |id: &str, size| FileToRecv::new(id.into(), id.into(), size);
+
+        let xfer = IncomingTransfer::new(peer, vec![file("a", 512), file("b", 512)], &config)
+            .expect("Transfer within the limit");
+        assert_eq!(xfer.total_size(), 1024);
+
+        assert!(matches!(
+            IncomingTransfer::new(peer, vec![file("a", 512), file("b", 513)], &config),
+            Err(Error::TransferLimitsExceeded)
+        ));
+        // Does not wrap around
+        assert!(matches!(
+            IncomingTransfer::new(peer, vec![file("a", u64::MAX), file("b", 2)], &config),
+            Err(Error::TransferLimitsExceeded)
+        ));
+
+        assert!(matches!(
+            xfer.with_added_files(vec![file("c", 1)], DirEntries::default(), &config),
+            Err(Error::TransferLimitsExceeded)
+        ));
     }
 }
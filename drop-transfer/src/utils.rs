@@ -1,15 +1,18 @@
 use std::{
     fmt, io, iter,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr, SocketAddrV6},
     ops,
     path::{Path, PathBuf},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use drop_config::RetryPolicy;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::{
     net::{TcpSocket, TcpStream},
-    sync::watch,
+    sync::{watch, Notify},
 };
 
 #[derive(Deserialize, Serialize, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -18,30 +21,83 @@ pub struct Hidden<T>(pub T);
 
 pub struct RetryTrigger {
     chan: watch::Receiver<()>,
+    low_power: watch::Receiver<bool>,
+    policy: RetryPolicy,
     retry: u32,
     retries: u32,
+    // The delay of the next timed retry, with the jitter applied
+    delay: Duration,
+    manual: Option<Arc<Notify>>,
 }
 
 impl RetryTrigger {
-    pub fn new(chan: watch::Receiver<()>, retries: u32) -> Self {
-        Self {
+    pub fn new(
+        chan: watch::Receiver<()>,
+        low_power: watch::Receiver<bool>,
+        retries: u32,
+        policy: &RetryPolicy,
+    ) -> Self {
+        let mut trigger = Self {
             chan,
+            low_power,
+            policy: policy.clone(),
             retry: 0,
             retries,
+            delay: Duration::MAX,
+            manual: None,
+        };
+        trigger.reset_delay();
+        trigger
+    }
+
+    /// Retry also when the `notify` is notified, regardless of the delay
+    pub fn with_manual_trigger(mut self, notify: Arc<Notify>) -> Self {
+        self.manual = Some(notify);
+        self
+    }
+
+    /// The delay of the next retry. `Duration::MAX` means the retry happens
+    /// only on the refresh trigger. Timed retries are suspended in the
+    /// low-power mode
+    pub fn next_delay(&self) -> Duration {
+        if *self.low_power.borrow() {
+            return Duration::MAX;
         }
+
+        self.delay
     }
 
     pub async fn backoff(&mut self) {
-        let delay = if self.retry + 1 < self.retries {
-            drop_config::FIRST_RETRY_AFTER * (0x01 << self.retry)
-        } else {
-            Duration::MAX
+        let delay = self.next_delay();
+        let low_power = *self.low_power.borrow();
+        let manual = async {
+            match &self.manual {
+                Some(notify) => notify.notified().await,
+                None => std::future::pending().await,
+            }
         };
 
         self.retry = tokio::select! {
             _ = self.chan.changed() => 0,
+            _ = manual => 0,
+            _ = self.low_power.wait_for(|on| !*on), if low_power => 0,
             _ = tokio::time::sleep(delay) => self.retry + 1,
         };
+        self.reset_delay();
+    }
+
+    fn reset_delay(&mut self) {
+        if self.retry.saturating_add(1) >= self.retries {
+            self.delay = Duration::MAX;
+            return;
+        }
+
+        let delay = self.policy.delay(self.retry);
+        self.delay = if self.policy.jitter {
+            rand::thread_rng().gen_range(delay / 2..=delay)
+        } else {
+            delay
+        };
     }
 }
 
@@ -104,6 +160,9 @@ pub fn filepath_variants(location: &'_ Path) -> crate::Result<impl Iterator<Item
 /// Rules taken from: <https://stackoverflow.com/questions/1976007/what-characters-are-forbidden-in-windows-and-linux-directory-names>
 pub fn normalize_filename(filename: impl AsRef<str>) -> String {
     const REPLACEMENT_CHAR: &str = "_";
+    // Most of the filesystems limit the names to 255 bytes. Some space is left
+    // for the ` (N)` suffix added on name conflicts
+    const MAX_NAME_LEN: usize = 240;
 
     // This is unified across all platforms, because we don't have a control
     // over the filesystem mounted on user's device
@@ -116,15 +175,17 @@ pub fn normalize_filename(filename: impl AsRef<str>) -> String {
             "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
         ];
 
-        // file name cannot end with .
-        if name.ends_with('.') {
+        // file name cannot end with . or space
+        if name.ends_with(['.', ' ']) {
             // append the replacement char
             name.push_str(REPLACEMENT_CHAR);
         }
 
-        // check illegal names
+        // check illegal names, these are case insensitive and the trailing spaces
+        // are ignored
         if let Some(prefix) = name.split('.').next() {
-            if ILLEGAL.contains(&prefix) {
+            let prefix = prefix.trim_end_matches(' ').to_ascii_uppercase();
+            if ILLEGAL.contains(&prefix.as_str()) {
                 // prepend the replacement char
                 name.insert_str(0, REPLACEMENT_CHAR);
             }
@@ -143,7 +204,69 @@ pub fn normalize_filename(filename: impl AsRef<str>) -> String {
         .replace(ILLEGAL_CHARS, REPLACEMENT_CHAR)
         .replace(|c: char| c.is_ascii_control(), REPLACEMENT_CHAR);
 
-    check_illegal_filename(name)
+    truncate_filename(check_illegal_filename(name), MAX_NAME_LEN)
+}
+
+/// Shortens the name to at most `max_len` bytes, keeping the extension when
+/// it is reasonably short
+fn truncate_filename(name: String, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name;
+    }
+
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= max_len / 2 => name.split_at(dot),
+        _ => (name.as_str(), ""),
+    };
+
+    let mut end = max_len - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{ext}", &stem[..end])
+}
+
+/// Windows limits the paths to 260 characters unless they are prefixed with
+/// `\\?\`. Adds the prefix to the long absolute paths, shorter ones are
+/// returned as they are
+#[cfg(windows)]
+pub fn long_path(path: PathBuf) -> PathBuf {
+    // The directory paths are limited further to leave space for the 8.3 file
+    // name
+    const MAX_DIR_PATH: usize = 248;
+
+    if path.as_os_str().len() < MAX_DIR_PATH || !path.is_absolute() {
+        return path;
+    }
+
+    let full = match path.to_str() {
+        Some(full) if !full.starts_with(r"\\?\") => full.replace('/', "\\"),
+        _ => return path,
+    };
+
+    match full.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{unc}")),
+        None => PathBuf::from(format!(r"\\?\{full}")),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// Whether both paths are on the same filesystem, `None` when unknown
+#[cfg(unix)]
+pub fn same_filesystem(a: &Path, b: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(std::fs::metadata(a).ok()?.dev() == std::fs::metadata(b).ok()?.dev())
+}
+
+#[cfg(not(unix))]
+pub fn same_filesystem(_: &Path, _: &Path) -> Option<bool> {
+    None
 }
 
 pub fn make_path_absolute(path: impl AsRef<Path>) -> io::Result<PathBuf> {
@@ -171,12 +294,108 @@ pub async fn connect(local: SocketAddr, remote: SocketAddr) -> io::Result<TcpStr
     sock.connect(remote).await
 }
 
+/// Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses. Convert
+/// those back to plain IPv4 so the peer is identified the same way regardless
+/// of the socket it connected to
+pub fn canonical_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Whether the download destination is a URI, like the Android `content://`
+/// one, rather than a directory. The single letter schemes are the Windows
+/// drive letters
+pub fn is_uri(destination: &str) -> bool {
+    url::Url::parse(destination).is_ok_and(|url| url.scheme().len() > 1)
+}
+
+/// Returns the IPv6 zone index of the address or `0` if there is none
+pub fn scope_id(addr: &SocketAddr) -> u32 {
+    match addr {
+        SocketAddr::V6(v6) => v6.scope_id(),
+        SocketAddr::V4(_) => 0,
+    }
+}
+
+/// Creates the socket address of the peer, preserving the IPv6 zone index
+/// which is required to reach link-local addresses
+pub fn peer_socket_addr(ip: IpAddr, scope_id: u32, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V6(v6) => SocketAddrV6::new(v6, port, 0, scope_id).into(),
+        IpAddr::V4(_) => SocketAddr::new(ip, port),
+    }
+}
+
+/// Formats the peer address in the `<ip>%<zone index>` form used for
+/// persistence. The zone is omitted when not present. When the peer listens
+/// on a non-default port the socket address form is used instead
+pub fn format_peer(ip: IpAddr, scope_id: u32, port: Option<u16>) -> String {
+    match port {
+        Some(port) => peer_socket_addr(ip, scope_id, port).to_string(),
+        None => match ip {
+            IpAddr::V6(_) if scope_id != 0 => format!("{ip}%{scope_id}"),
+            _ => ip.to_string(),
+        },
+    }
+}
+
+/// Parses the peer address in the form created by [`format_peer`]
+pub fn parse_peer(peer: &str) -> Option<(IpAddr, u32, Option<u16>)> {
+    if let Ok(addr) = peer.parse::<SocketAddr>() {
+        return Some((addr.ip(), scope_id(&addr), Some(addr.port())));
+    }
+
+    match peer.split_once('%') {
+        Some((ip, scope_id)) => {
+            let ip: IpAddr = ip.parse().ok()?;
+            if ip.is_ipv4() {
+                return None;
+            }
+            Some((ip, scope_id.parse().ok()?, None))
+        }
+        None => Some((peer.parse().ok()?, 0, None)),
+    }
+}
+
+/// Splits the optional port off the peer address. Accepts `host:port` and
+/// `[ipv6]:port`. Bare IPv6 addresses are returned as they are
+pub fn split_peer_port(peer: &str) -> Option<(&str, Option<u16>)> {
+    if let Some(rest) = peer.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        let port = match port {
+            "" => None,
+            port => Some(port.strip_prefix(':')?.parse().ok()?),
+        };
+        return Some((host, port));
+    }
+
+    match peer.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Some((host, Some(port.parse().ok()?))),
+        _ => Some((peer, None)),
+    }
+}
+
 /// Returns the number of kilobytes rounded up, used for moose event size
 /// calculations
 pub fn to_kb(bytes: u64) -> i32 {
     (bytes as f64 / 1024.0).ceil() as i32
 }
 
+/// The milliseconds since the Unix epoch, as kept in the storage
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as _)
+}
+
+pub fn from_unix_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as _)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -205,7 +424,95 @@ mod tests {
             let special_name = "COM1.txt.png";
             let norm = normalize_filename(special_name);
             assert_eq!(norm, "_COM1.txt.png");
+
+            assert_eq!(normalize_filename("nul "), "_nul _");
+            assert_eq!(normalize_filename("Con.txt"), "_Con.txt");
         }
+
+        let long_name = format!("{}.txt", "a".repeat(300));
+        let norm = normalize_filename(long_name);
+        assert_eq!(norm, format!("{}.txt", "a".repeat(236)));
+
+        let long_utf8 = "😀".repeat(100);
+        let norm = normalize_filename(long_utf8);
+        assert_eq!(norm, "😀".repeat(60));
+    }
+
+    #[test]
+    fn peer_address_handling() {
+        let mapped: SocketAddr = "[::ffff:192.168.0.1]:49111".parse().unwrap();
+        assert_eq!(
+            canonical_socket_addr(mapped),
+            "192.168.0.1:49111".parse::<SocketAddr>().unwrap()
+        );
+
+        let scoped: SocketAddr = "[fe80::1%3]:49111".parse().unwrap();
+        assert_eq!(canonical_socket_addr(scoped), scoped);
+        assert_eq!(scope_id(&scoped), 3);
+        assert_eq!(peer_socket_addr(scoped.ip(), 3, 49111), scoped);
+
+        let peer = format_peer(scoped.ip(), 3, None);
+        assert_eq!(peer, "fe80::1%3");
+        assert_eq!(parse_peer(&peer), Some((scoped.ip(), 3, None)));
+
+        let peer = format_peer(scoped.ip(), 3, Some(50000));
+        assert_eq!(peer, "[fe80::1%3]:50000");
+        assert_eq!(parse_peer(&peer), Some((scoped.ip(), 3, Some(50000))));
+
+        assert_eq!(format_peer("fe80::1".parse().unwrap(), 0, None), "fe80::1");
+        assert_eq!(
+            parse_peer("192.168.0.1"),
+            Some(("192.168.0.1".parse().unwrap(), 0, None))
+        );
+        assert_eq!(
+            parse_peer("192.168.0.1:50000"),
+            Some(("192.168.0.1".parse().unwrap(), 0, Some(50000)))
+        );
+        assert_eq!(parse_peer("192.168.0.1%3"), None);
+        assert_eq!(parse_peer("fe80::1%eth0"), None);
+
+        assert_eq!(split_peer_port("host"), Some(("host", None)));
+        assert_eq!(split_peer_port("host:50000"), Some(("host", Some(50000))));
+        assert_eq!(
+            split_peer_port("fe80::1%eth0"),
+            Some(("fe80::1%eth0", None))
+        );
+        assert_eq!(
+            split_peer_port("[fe80::1%eth0]:50000"),
+            Some(("fe80::1%eth0", Some(50000)))
+        );
+        assert_eq!(split_peer_port("[::1]"), Some(("::1", None)));
+        assert_eq!(split_peer_port("host:port"), None);
+    }
+
+    #[tokio::test]
+    async fn manual_retry_trigger() {
+        let (_refresh_tx, refresh) = watch::channel(());
+        let (_low_power_tx, low_power) = watch::channel(false);
+        let policy = drop_config::RetryPolicy {
+            base_delay: Duration::from_secs(3600),
+            max_delay: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let notify = Arc::new(Notify::new());
+
+        let mut trigger =
+            RetryTrigger::new(refresh, low_power, 5, &policy).with_manual_trigger(notify.clone());
+        assert_eq!(trigger.next_delay(), Duration::from_secs(3600));
+
+        // The trigger notified before the backoff starts is not lost
+        notify.notify_one();
+        tokio::time::timeout(Duration::from_secs(5), trigger.backoff())
+            .await
+            .expect("Manual trigger should end the backoff");
+
+        // The backoff starts over
+        assert_eq!(trigger.next_delay(), Duration::from_secs(3600));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), trigger.backoff())
+                .await
+                .is_err()
+        );
     }
 
     #[test]
@@ -217,4 +524,14 @@ mod tests {
         assert_eq!(iter.next(), Some(PathBuf::from("file(2).ext")));
         assert_eq!(iter.next(), Some(PathBuf::from("file(3).ext")));
     }
+
+    #[test]
+    fn uri_destinations() {
+        assert!(is_uri("content://com.app/tree/primary%3ADownload"));
+        assert!(is_uri("vault://x"));
+        assert!(!is_uri("/home/user/Downloads"));
+        assert!(!is_uri("Downloads"));
+        assert!(!is_uri("C:\\Users\\user\\Downloads"));
+        assert!(!is_uri("C:/Users/user/Downloads"));
+    }
 }
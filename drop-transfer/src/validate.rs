@@ -0,0 +1,217 @@
+//! Validation of the paths received from the peer and of the download
+//! destinations given by the app
+
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use drop_config::DropConfig;
+
+use crate::{
+    file::{FileSubPath, Symlink},
+    Error,
+};
+
+/// Checks the path from the peer's manifest is a plain relative path, so that
+/// joined with the destination it cannot point outside of it
+pub(crate) fn peer_subpath(path: &FileSubPath) -> crate::Result<()> {
+    for name in path.iter() {
+        let mut components = Path::new(name).components();
+        let is_plain = matches!(components.next(), Some(Component::Normal(_)))
+            && components.next().is_none()
+            && !name.contains(['/', '\\', '\0']);
+
+        if !is_plain {
+            return Err(Error::BadPath(
+                "File subpath contains disallowed element".into(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the symlink from the peer's manifest is placed at a plain relative
+/// path and its target is relative and stays within the root directory
+pub(crate) fn peer_symlink(link: &Symlink) -> crate::Result<()> {
+    peer_subpath(&link.path)?;
+
+    if link.target.starts_with('/') || link.target.contains('\0') || !link.stays_inside() {
+        return Err(Error::BadPath(
+            "Symlink target points outside of the transfer".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Validates the download destination and creates it. Returns the canonical
+/// destination path
+pub(crate) fn destination(config: &DropConfig, parent_dir: &Path) -> crate::Result<PathBuf> {
+    check_destination(config, parent_dir)?;
+
+    fs::create_dir_all(parent_dir).map_err(|ioerr| Error::BadPath(ioerr.to_string()))?;
+    fs::canonicalize(parent_dir).map_err(|ioerr| Error::BadPath(ioerr.to_string()))
+}
+
+/// Validates the download destination without creating anything. The
+/// destination must not contain parent directory references nor symlinks
+/// and, when the allow list is configured, must be within one of the
+/// download roots
+pub(crate) fn check_destination(config: &DropConfig, parent_dir: &Path) -> crate::Result<()> {
+    if parent_dir.components().any(|x| x == Component::ParentDir) {
+        return Err(Error::BadPath(
+            "Path should not contain a reference to parrent directory".into(),
+        ));
+    }
+
+    if parent_dir.ancestors().any(Path::is_symlink) {
+        return Err(Error::BadPath(
+            "Destination should not contain directory symlinks".into(),
+        ));
+    }
+
+    let resolved =
+        canonicalize_nearest(parent_dir).map_err(|ioerr| Error::BadPath(ioerr.to_string()))?;
+    if !is_within_roots(&config.download_roots, &resolved) {
+        return Err(Error::BadPath(
+            "Destination is outside of the allowed download roots".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Canonicalizes the nearest existing ancestor of the path and appends the
+/// components which do not exist yet
+fn canonicalize_nearest(path: &Path) -> io::Result<PathBuf> {
+    let mut missing = Vec::new();
+    let mut existing = path;
+
+    loop {
+        let current = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+
+        match fs::canonicalize(current) {
+            Ok(canonical) => {
+                return Ok(missing
+                    .into_iter()
+                    .rev()
+                    .fold(canonical, |path, name| path.join(name)));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                missing.push(existing.file_name().ok_or(err)?);
+                existing = existing.parent().unwrap_or(Path::new(""));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// An empty allow list allows any destination. The roots which do not exist
+/// match nothing
+fn is_within_roots(roots: &[String], canonical: &Path) -> bool {
+    roots.is_empty()
+        || roots
+            .iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .any(|root| canonical.starts_with(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_subpaths() {
+        assert!(peer_subpath(&FileSubPath::from("dir/a.txt")).is_ok());
+        assert!(peer_subpath(&FileSubPath::from("a..b")).is_ok());
+
+        for path in [
+            "../../etc/passwd",
+            "dir/../../a.txt",
+            "./a.txt",
+            "dir//a.txt",
+            "dir\\..\\a.txt",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    peer_subpath(&FileSubPath::from(path)),
+                    Err(Error::BadPath(_))
+                ),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn download_roots() {
+        let root = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+
+        let mut config = DropConfig {
+            download_roots: vec![root.path().to_string_lossy().into_owned()],
+            ..Default::default()
+        };
+
+        let dest = root.path().join("sub");
+        assert_eq!(
+            destination(&config, &dest).unwrap(),
+            fs::canonicalize(&dest).unwrap()
+        );
+        assert!(matches!(
+            destination(&config, other.path()),
+            Err(Error::BadPath(_))
+        ));
+        assert!(matches!(
+            destination(&config, &root.path().join("sub/../..")),
+            Err(Error::BadPath(_))
+        ));
+
+        // The refused destination is not created
+        let outside = other.path().join("new/dir");
+        assert!(matches!(
+            destination(&config, &outside),
+            Err(Error::BadPath(_))
+        ));
+        assert!(!other.path().join("new").exists());
+
+        let nested = root.path().join("new/dir");
+        assert_eq!(
+            destination(&config, &nested).unwrap(),
+            fs::canonicalize(&nested).unwrap()
+        );
+
+        config.download_roots.clear();
+        assert!(destination(&config, other.path()).is_ok());
+    }
+
+    #[test]
+    fn peer_symlinks() {
+        let link = |path: &str, target: &str| Symlink {
+            path: FileSubPath::from(path),
+            target: target.to_string(),
+        };
+
+        assert!(peer_symlink(&link("dir/link", "a.txt")).is_ok());
+        assert!(peer_symlink(&link("dir/sub/link", "../a.txt")).is_ok());
+
+        for (path, target) in [
+            ("dir/link", "../a.txt"),
+            ("dir/link", "/etc/passwd"),
+            ("dir/link", "..\\a.txt"),
+            ("dir/link", "a\0b"),
+            ("../link", "a.txt"),
+        ] {
+            assert!(
+                matches!(peer_symlink(&link(path, target)), Err(Error::BadPath(_))),
+                "{path} -> {target}"
+            );
+        }
+    }
+}
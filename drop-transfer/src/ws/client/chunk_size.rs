@@ -0,0 +1,70 @@
+//! Adaptive sizing of the uploaded chunks, enabled with the
+//! `adaptive_chunk_size` config option. The chunk is sized to take about
+//! [`TARGET_CHUNK_TIME`] or the round-trip time to send, whichever is longer,
+//! so that the fast links are not held back by the per-chunk overhead and the
+//! slow ones still react quickly to the cancellation.
+
+use std::{sync::Arc, time::Duration};
+
+use drop_config::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+use crate::{file::ChunkSize, stats::StatsTracker};
+
+const TARGET_CHUNK_TIME: Duration = Duration::from_millis(100);
+
+pub(crate) struct ChunkSizer {
+    size: ChunkSize,
+    stats: Arc<StatsTracker>,
+}
+
+impl ChunkSizer {
+    pub(crate) fn new(size: ChunkSize, stats: Arc<StatsTracker>) -> Self {
+        Self { size, stats }
+    }
+
+    /// Resizes the next chunks after a chunk is sent. Nothing changes until
+    /// the first throughput sample is taken
+    pub(crate) fn adjust(&self) {
+        let stats = self.stats.snapshot();
+        if let Some(size) = target_size(stats.throughput_bps, stats.rtt) {
+            self.size.set(size);
+        }
+    }
+}
+
+fn target_size(throughput_bps: f64, rtt: Option<Duration>) -> Option<usize> {
+    if throughput_bps <= 0.0 {
+        return None;
+    }
+
+    let window = rtt.unwrap_or_default().max(TARGET_CHUNK_TIME);
+    let size = (throughput_bps * window.as_secs_f64()) as usize;
+
+    // Multiples of the minimal size keep the reads aligned
+    Some(size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE) / MIN_CHUNK_SIZE * MIN_CHUNK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizing() {
+        assert_eq!(target_size(0.0, None), None);
+
+        // 1 Mbps
+        assert_eq!(target_size(125_000.0, None), Some(MIN_CHUNK_SIZE));
+        // 1 Gbps
+        assert_eq!(target_size(125_000_000.0, None), Some(MAX_CHUNK_SIZE));
+        // 100 Mbps
+        assert_eq!(
+            target_size(12_500_000.0, None),
+            Some(1_250_000 / MIN_CHUNK_SIZE * MIN_CHUNK_SIZE)
+        );
+        // The long round trip makes the chunks bigger
+        assert_eq!(
+            target_size(12_500_000.0, Some(Duration::from_millis(300))),
+            Some(3_750_000 / MIN_CHUNK_SIZE * MIN_CHUNK_SIZE)
+        );
+    }
+}
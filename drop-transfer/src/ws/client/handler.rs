@@ -1,14 +1,20 @@
 use std::{sync::Arc, time::Duration};
 
-use tokio::{sync::mpsc::Sender, task::JoinSet};
+use tokio::{
+    sync::{mpsc::Sender, OwnedSemaphorePermit},
+    task::JoinSet,
+};
 use tokio_tungstenite::tungstenite::Message;
 
-use super::WebSocket;
-use crate::{ws, FileId, OutgoingTransfer};
+use super::{FilePermit, WebSocket};
+use crate::{timing::StageTimer, ws, FileId, OutgoingTransfer};
 
 #[derive(Debug)]
 pub struct MsgToSend {
     pub msg: Message,
+    // The share of the upload budget taken by the chunk, returned once the
+    // message is written to the socket
+    pub budget: Option<OwnedSemaphorePermit>,
 }
 
 #[async_trait::async_trait]
@@ -35,6 +41,19 @@ pub trait HandlerLoop {
         file_id: FileId,
         msg: String,
     ) -> anyhow::Result<()>;
+    async fn issue_expire(&mut self, ws: &mut WebSocket, file_id: FileId) -> anyhow::Result<()>;
+    async fn issue_withdraw_file(
+        &mut self,
+        ws: &mut WebSocket,
+        file_id: FileId,
+    ) -> anyhow::Result<()>;
+    async fn issue_withdraw(&mut self, ws: &mut WebSocket) -> anyhow::Result<()>;
+    async fn issue_add_files(
+        &mut self,
+        ws: &mut WebSocket,
+        xfer: Arc<OutgoingTransfer>,
+        files: Vec<FileId>,
+    ) -> anyhow::Result<()>;
 
     async fn on_close(&mut self);
     async fn on_text_msg(
@@ -48,10 +67,23 @@ pub trait HandlerLoop {
 
 #[async_trait::async_trait]
 pub trait Uploader: Send + 'static {
-    async fn chunk(&mut self, chunk: &[u8]) -> crate::Result<()>;
+    // Takes the ownership of the chunk so that it is not copied when sent as is
+    async fn chunk(&mut self, chunk: Vec<u8>) -> crate::Result<()>;
 
     // File stream offset
     fn offset(&self) -> u64;
+
+    // Another uploader of the same file starting at the `offset`, `None` when
+    // the peer does not accept the ranges of the file uploaded at the same time
+    fn fork(&self, offset: u64) -> Option<Self>
+    where
+        Self: Sized;
+
+    // Enables the timing of the chunk pipeline stages
+    fn set_timer(&mut self, timer: Arc<StageTimer>);
+
+    // The upload permit of the file, shared with the forks
+    fn set_permit(&mut self, permit: FilePermit);
 }
 
 impl<T> From<T> for MsgToSend
@@ -59,6 +91,9 @@ where
     T: Into<Message>,
 {
     fn from(value: T) -> Self {
-        Self { msg: value.into() }
+        Self {
+            msg: value.into(),
+            budget: None,
+        }
     }
 }
@@ -1,63 +1,113 @@
+mod chunk_size;
 mod handler;
+mod ranges;
+mod relay;
 mod socket;
 mod throttle;
 mod v6;
 
 use std::{
+    future::Future,
     io,
-    net::{IpAddr, SocketAddr},
+    net::SocketAddr,
     ops::ControlFlow,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use hyper::{Request, Response, StatusCode};
+use futures::{SinkExt, StreamExt};
+use hyper::{http::HeaderValue, Request, Response, StatusCode};
 use slog::{debug, error, info, warn, Logger};
+use throttle::FilePermit;
+pub(crate) use throttle::UploadBudget;
 use tokio::{
-    net::TcpStream,
-    sync::mpsc::{self, UnboundedReceiver},
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        OwnedSemaphorePermit,
+    },
     task::{AbortHandle, JoinSet},
 };
 use tokio_tungstenite::{
     tungstenite::{self, client::IntoClientRequest, protocol::Role, Message},
     WebSocketStream,
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{either::Either, sync::CancellationToken};
+use tracing::Instrument;
 
 use self::{
+    chunk_size::ChunkSizer,
     handler::{HandlerInit, HandlerLoop, Uploader},
     socket::{WebSocket, WsStream},
 };
 use super::OutgoingFileEventTx;
 use crate::{
-    auth,
-    file::FileId,
+    diagnostics,
+    file::{ChunkSize, File as _, FileId, FileToSend},
+    forward,
     manager::{FileTerminalState, FinishTransferState, OutgoingConnected},
-    protocol,
-    service::State,
+    protocol::{
+        self,
+        caps::{Capabilities, Capability},
+        device::PeerDevice,
+    },
+    service::{ConnectionDecision, State},
+    share, spans,
     tasks::AliveGuard,
+    timing::StageTimer,
+    tls::{self, PeerStream},
     transfer::Transfer,
     utils,
-    ws::{client::handler::MsgToSend, Pinger},
-    OutgoingTransfer,
+    ws::{client::handler::MsgToSend, mux, Pinger},
+    HandshakePhase, OutgoingTransfer,
 };
 
 pub enum ClientReq {
-    Reject { file: FileId },
-    Fail { file: FileId, msg: String },
+    Reject {
+        file: FileId,
+    },
+    Fail {
+        file: FileId,
+        msg: String,
+    },
+    /// The receiver did not start the file before the transfer deadline
+    Expire {
+        file: FileId,
+    },
+    /// The sender withdrew the file before the receiver started it
+    WithdrawFile {
+        file: FileId,
+    },
+    /// Tells the receiver about the files added to the transfer, `xfer`
+    /// carries all of them
+    AddFiles {
+        xfer: Arc<OutgoingTransfer>,
+        files: Vec<FileId>,
+    },
     Close,
+    /// Closes the connection after telling the receiver the transfer is
+    /// withdrawn
+    Withdraw,
 }
 
 struct RunContext<'a> {
     logger: &'a slog::Logger,
     state: &'a Arc<State>,
-    xfer: &'a Arc<OutgoingTransfer>,
+    xfer: Arc<OutgoingTransfer>,
+    caps: Capabilities,
 }
 
 enum WsConnection {
     Recoverable(crate::Error),
     Unrecoverable(crate::Error),
-    Connected(WsStream, protocol::Version),
+    Connected {
+        stream: Box<WsStream>,
+        version: protocol::Version,
+        relayed: bool,
+        caps: Capabilities,
+        clock_skew: Option<i64>,
+        device: Option<PeerDevice>,
+    },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -77,30 +127,118 @@ pub(crate) fn spawn(
     stop: CancellationToken,
 ) {
     let id = xfer.id();
+    let guard = guard.task("client", format!("Outgoing transfer {id}"));
 
     tokio::spawn(async move {
-        let mut backoff =
-            utils::RetryTrigger::new(refresh_trigger, state.config.connection_retries);
+        let job_stop = state.transfer_manager.outgoing_job_stop(id).await;
+        let retry_now = state.transfer_manager.outgoing_retry_trigger(id).await;
+        let (job_stop, retry_now) = if let (Some(token), Some(retry_now)) = (job_stop, retry_now) {
+            (token, retry_now)
+        } else {
+            debug!(
+                logger,
+                "Outgoing transfer {id} is gone, not starting client job"
+            );
+            return;
+        };
+
+        let mut backoff = utils::RetryTrigger::new(
+            refresh_trigger,
+            state.low_power.subscribe(),
+            state.config.connection_retries,
+            &state.config.retry_policy,
+        )
+        .with_manual_trigger(retry_now);
 
         let task = async {
             loop {
-                let cf = connect_to_peer(&state, &xfer, &logger, &guard).await;
-                if cf.is_break() {
-                    debug!(logger, "connection status is irrecoverable");
-                    break;
+                let decision = state.connection_decision(xfer.peer(), id);
+                if let Some(decision) = decision {
+                    state
+                        .transfer_manager
+                        .outgoing_connection_decision(id, decision)
+                        .await;
                 }
 
+                let postponed = match decision.unwrap_or(ConnectionDecision::Allow) {
+                    ConnectionDecision::Allow => {
+                        let cf = connect_to_peer(&state, &xfer, &logger, &guard).await;
+                        if cf.is_break() {
+                            debug!(logger, "connection status is irrecoverable");
+                            break;
+                        }
+                        false
+                    }
+                    ConnectionDecision::Postpone => {
+                        debug!(logger, "Connection policy postponed the connection of {id}");
+                        true
+                    }
+                    ConnectionDecision::Deny => {
+                        info!(logger, "Connection policy denied the connection of {id}");
+
+                        if let Some(state) = state.transfer_manager.outgoing_remove(id).await {
+                            state
+                                .xfer_events
+                                .failed(crate::Error::ConnectionDenied, false)
+                                .await
+                        }
+                        break;
+                    }
+                };
+
+                if state.is_low_power() {
+                    debug!(logger, "Low-power mode, postponing retries of {id}");
+                }
+
+                if postponed || state.is_low_power() {
+                    if let Some(tx) = state.transfer_manager.outgoing_event_tx(id).await {
+                        tx.postponed().await;
+                    }
+                }
+
+                let delay = backoff.next_delay();
+                if postponed {
+                    state
+                        .transfer_manager
+                        .outgoing_retry_postponed(id, delay)
+                        .await;
+                } else if let Some(attempt) = state
+                    .transfer_manager
+                    .outgoing_retry_scheduled(id, delay)
+                    .await
+                {
+                    if let Some(tx) = state.transfer_manager.outgoing_event_tx(id).await {
+                        let next_retry_in = Some(delay).filter(|delay| *delay != Duration::MAX);
+                        tx.connection_attempt_failed(attempt, next_retry_in).await;
+                    }
+                }
                 backoff.backoff().await;
             }
         };
 
-        tokio::select! {
+        let span = state
+            .transfer_manager
+            .transfer_span(id)
+            .await
+            .unwrap_or_else(tracing::Span::none);
+
+        let finished = tokio::select! {
             biased;
 
             _ = stop.cancelled() => {
                 debug!(logger, "stop client job for: {}", id);
+                false
+            },
+            _ = job_stop.cancelled() => {
+                debug!(logger, "Outgoing transfer {id} cancelled, stopping client job");
+                true
             },
-            _ = task => ()
+            _ = task.instrument(span) => !stop.is_cancelled(),
+        };
+
+        if finished {
+            forward::finished(&state, &logger, &xfer);
+            state.shared_files.forget(id);
         }
     });
 }
@@ -113,12 +251,35 @@ async fn connect_to_peer(
 ) -> ControlFlow<()> {
     debug!(logger, "Outgoing transfer job started for {}", xfer.id(),);
 
-    let (socket, ver) = match establish_ws_conn(state, xfer, logger).await {
-        WsConnection::Connected(sock, ver) => (sock, ver),
+    let (socket, ver, relayed, caps) = match establish_ws_conn(state, xfer, logger).await {
+        WsConnection::Connected {
+            stream,
+            version,
+            relayed,
+            caps,
+            clock_skew,
+            device,
+        } => {
+            if let Some(skew) = clock_skew {
+                debug!(logger, "Peer clock skew: {skew} ms");
+                state.storage.update_peer_clock_skew(xfer.id(), skew).await;
+            }
+            if let Some(device) = &device {
+                state
+                    .storage
+                    .save_peer_device(&xfer.peer().to_string(), &device.into())
+                    .await;
+            }
+
+            (*stream, version, relayed, caps)
+        }
         WsConnection::Recoverable(error) => {
             info!(logger, "Transfer deferred {}: {error}", xfer.id());
 
             if let Some(tx) = state.transfer_manager.outgoing_event_tx(xfer.id()).await {
+                if let crate::Error::PeerKeyUnavailable = error {
+                    tx.peer_key_unavailable().await;
+                }
                 tx.deferred(error).await;
             }
             return ControlFlow::Continue(());
@@ -136,19 +297,28 @@ async fn connect_to_peer(
 
     if let Some(tx) = state.transfer_manager.outgoing_event_tx(xfer.id()).await {
         tx.connected(ver.into()).await;
+
+        // The connectivity is only interesting when there is an alternative
+        if state.config.relay.is_some() {
+            tx.connectivity(relayed).await;
+        }
     }
-    info!(logger, "Client connected, using version: {ver}");
+    info!(
+        logger,
+        "Client connected, using version: {ver}, relayed: {relayed}, capabilities: [{caps}]"
+    );
 
     let ctx = RunContext {
         logger,
         state,
-        xfer,
+        xfer: xfer.clone(),
+        caps,
     };
 
     use protocol::Version;
     let control = match ver {
         Version::V6 => {
-            ctx.run(socket, v6::HandlerInit::new(state, logger, alive))
+            ctx.run(socket, v6::HandlerInit::new(state, caps, logger, alive))
                 .await
         }
     };
@@ -163,20 +333,148 @@ async fn establish_ws_conn(
     xfer: &OutgoingTransfer,
     logger: &Logger,
 ) -> WsConnection {
-    let remote = SocketAddr::new(xfer.peer(), drop_config::PORT);
-    let local = SocketAddr::new(state.addr, 0);
+    let mut recorder = diagnostics::Recorder::new(xfer.peer());
+    let conn = handshake(state, xfer, logger, &mut recorder).await;
 
-    let mut socket = match utils::connect(local, remote).await {
-        Ok(sock) => sock,
-        Err(err) => {
-            debug!(logger, "Failed to connect: {:?}", err,);
-            return WsConnection::Recoverable(crate::Error::Io(err));
+    let error = match &conn {
+        WsConnection::Connected { .. } => None,
+        WsConnection::Recoverable(err) | WsConnection::Unrecoverable(err) => Some(err.to_string()),
+    };
+    let failed = error.is_some();
+
+    let diagnostics = recorder.finish(error);
+    state.handshakes.record(diagnostics.clone());
+
+    if failed {
+        if let Some(tx) = state.transfer_manager.outgoing_event_tx(xfer.id()).await {
+            tx.connection_diagnostics(diagnostics).await;
         }
+    }
+
+    conn
+}
+
+async fn handshake(
+    state: &State,
+    xfer: &OutgoingTransfer,
+    logger: &Logger,
+    recorder: &mut diagnostics::Recorder,
+) -> WsConnection {
+    let port = xfer.peer_port().unwrap_or(state.config.port);
+    let remote = utils::peer_socket_addr(xfer.peer(), xfer.peer_scope_id(), port);
+
+    // Both the TLS and the request authentication need the key, there is no
+    // point in connecting without it. The app may provide the key later, e.g.
+    // once the peer is discovered
+    if tokio::task::block_in_place(|| state.auth.peer_public_key(xfer.peer())).is_none() {
+        return WsConnection::Recoverable(crate::Error::PeerKeyUnavailable);
+    }
+
+    // The connection shared with the peer carries the transfer in place of a
+    // new one
+    let shared = if state.config.shared_connections {
+        state.mux.get(remote.ip())
+    } else {
+        None
+    };
+    let shared = match shared {
+        Some(session) => match session.open().await {
+            Ok(stream) => Some((Either::Left(Either::Right(stream)), session.relayed())),
+            Err(err) => {
+                debug!(logger, "Failed to open the stream to {remote}: {err}");
+                None
+            }
+        },
+        None => None,
+    };
+
+    let (mut socket, relayed) = if let Some(shared) = shared {
+        debug!(logger, "Using the connection shared with {remote}");
+        shared
+    } else {
+        let attempts = state
+            .transfer_manager
+            .outgoing_retry_attempts(xfer.id())
+            .await;
+        let relay = state
+            .config
+            .relay
+            .as_ref()
+            .filter(|relay| attempts >= relay.after_retries);
+
+        let conn = if let Some(relay) = relay {
+            debug!(logger, "Connecting to {remote} through the relay");
+            relay::connect(state, relay, remote).await
+        } else {
+            let local = SocketAddr::new(state.local_addr_for(xfer.peer()), 0);
+            utils::connect(local, remote).await
+        };
+
+        let conn = match conn {
+            Ok(sock) => {
+                if state.config.tls {
+                    recorder.enter(HandshakePhase::Tls);
+                }
+                tls::connect(state.config.tls, &state.auth, sock, remote.ip()).await
+            }
+            Err(err) => Err(err),
+        };
+
+        let mut socket = match conn {
+            Ok(sock) => sock,
+            Err(err) => {
+                debug!(logger, "Failed to connect: {:?}", err,);
+                return WsConnection::Recoverable(crate::Error::Io(err));
+            }
+        };
+
+        if state.config.shared_connections {
+            match make_request(&mut socket, remote, mux::RESOURCE, state, recorder, logger).await {
+                Ok(_) => {
+                    debug!(logger, "Sharing the connection with {remote}");
+                    recorder.enter(HandshakePhase::Upgrade);
+
+                    let conn = WebSocketStream::from_raw_socket(socket, Role::Client, None).await;
+                    let (sink, stream) = conn.split();
+                    let session = state.mux.start(
+                        remote,
+                        sink.with(|buf: Vec<u8>| {
+                            futures::future::ready(Ok::<_, tungstenite::Error>(Message::Binary(
+                                buf,
+                            )))
+                        }),
+                        stream.filter_map(|msg| {
+                            futures::future::ready(match msg {
+                                Ok(Message::Binary(buf)) => Some(Ok(buf)),
+                                Ok(_) => None,
+                                Err(err) => Some(Err(err)),
+                            })
+                        }),
+                        true,
+                        relay.is_some(),
+                        logger,
+                    );
+
+                    recorder.enter(HandshakePhase::Connect);
+                    socket = match session.open().await {
+                        Ok(stream) => Either::Left(Either::Right(stream)),
+                        Err(err) => return WsConnection::Recoverable(crate::Error::Io(err)),
+                    };
+                }
+                Err(err) => {
+                    if let Some(conn) = request_failed(err, mux::RESOURCE, logger) {
+                        return conn;
+                    }
+                }
+            }
+        }
+
+        (socket, relay.is_some())
     };
 
     let mut versions_to_try = [protocol::Version::V6].into_iter();
 
-    let ver = loop {
+    let (ver, accepted) = loop {
         let ver = if let Some(ver) = versions_to_try.next() {
             ver
         } else {
@@ -186,56 +484,160 @@ async fn establish_ws_conn(
             )));
         };
 
-        match make_request(&mut socket, xfer.peer(), ver, state.auth.as_ref(), logger).await {
-            Ok(_) => break ver,
-            Err(RequestError::General(err)) => {
-                info!(logger, "Error while making the HTTP request: {err:?}");
-                return WsConnection::Recoverable(crate::Error::ConnectionClosedByPeer);
-            }
-            Err(RequestError::UnexpectedResponse(status)) => {
-                match status {
-                    StatusCode::UNAUTHORIZED => {
-                        return WsConnection::Unrecoverable(crate::Error::AuthenticationFailed)
-                    }
-                    StatusCode::TOO_MANY_REQUESTS => {
-                        warn!(logger, "The response triggered DoS protection mechanism");
-                        return WsConnection::Recoverable(crate::Error::TooManyRequests);
-                    }
-                    StatusCode::NOT_FOUND => (), // Server doesn't support
-                    status => debug!(
-                        logger,
-                        "Failed to connect to version {ver}, status: {status}"
-                    ),
+        match make_request(
+            &mut socket,
+            remote,
+            &ver.to_string(),
+            state,
+            recorder,
+            logger,
+        )
+        .await
+        {
+            Ok(accepted) => break (ver, accepted),
+            Err(err) => {
+                if let Some(conn) = request_failed(err, &ver.to_string(), logger) {
+                    return conn;
                 }
             }
         }
     };
 
+    if !state
+        .check_capabilities_pin(remote.ip(), accepted.caps_authenticated)
+        .await
+    {
+        warn!(
+            logger,
+            "The capabilities of {remote} were not authenticated although they were before"
+        );
+        return WsConnection::Unrecoverable(crate::Error::AuthenticationFailed);
+    }
+
+    recorder.enter(HandshakePhase::Upgrade);
     let client = WebSocketStream::from_raw_socket(socket, Role::Client, None).await;
-    WsConnection::Connected(client, ver)
+    WsConnection::Connected {
+        stream: Box::new(client),
+        version: ver,
+        relayed,
+        caps: accepted.caps,
+        clock_skew: accepted.clock_skew,
+        device: accepted.device,
+    }
+}
+
+// Returns the outcome of the connection when the request failed for a reason
+// other than the server not serving the resource
+fn request_failed(err: RequestError, resource: &str, logger: &Logger) -> Option<WsConnection> {
+    match err {
+        RequestError::General(err) => {
+            info!(logger, "Error while making the HTTP request: {err:?}");
+            Some(WsConnection::Recoverable(
+                crate::Error::ConnectionClosedByPeer,
+            ))
+        }
+        RequestError::UnexpectedResponse(status) => match status {
+            StatusCode::UNAUTHORIZED => Some(WsConnection::Unrecoverable(
+                crate::Error::AuthenticationFailed,
+            )),
+            StatusCode::TOO_MANY_REQUESTS => {
+                warn!(logger, "The response triggered DoS protection mechanism");
+                Some(WsConnection::Recoverable(crate::Error::TooManyRequests))
+            }
+            StatusCode::SERVICE_UNAVAILABLE => {
+                info!(logger, "Peer is busy, retrying later");
+                Some(WsConnection::Recoverable(crate::Error::PeerBusy))
+            }
+            StatusCode::NOT_FOUND => None, // Server doesn't support
+            status => {
+                debug!(logger, "Failed to request {resource}, status: {status}");
+                None
+            }
+        },
+    }
+}
+
+// What the server accepted the request with
+struct Accepted {
+    caps: Capabilities,
+    // The estimated skew of the server's clock
+    clock_skew: Option<i64>,
+    device: Option<PeerDevice>,
+    // Whether the server sent the MAC of its capabilities
+    caps_authenticated: bool,
 }
 
 async fn make_request(
-    socket: &mut TcpStream,
-    ip: IpAddr,
-    version: protocol::Version,
-    auth: &auth::Context,
+    socket: &mut PeerStream,
+    addr: SocketAddr,
+    resource: &str,
+    state: &State,
+    recorder: &mut diagnostics::Recorder,
     logger: &slog::Logger,
-) -> Result<(), RequestError> {
-    let addr = SocketAddr::new(ip, drop_config::PORT);
-
-    let url = format!("ws://{addr}/drop/{version}",);
+) -> Result<Accepted, RequestError> {
+    recorder.enter(HandshakePhase::Version);
+
+    let auth = state.auth.as_ref();
+    let caps = state.capabilities();
+    let device = state.local_device();
+    let listen_port = state.listen_port_for(state.local_addr_for(addr.ip()));
+
+    let ip = addr.ip();
+    // The zone index is not a part of the host in the URL
+    let scheme = if matches!(socket, Either::Right(_)) {
+        "wss"
+    } else {
+        "ws"
+    };
+    let url = format!(
+        "{scheme}://{}/drop/{resource}",
+        SocketAddr::new(ip, addr.port())
+    );
 
     debug!(logger, "Making HTTP request: {url}");
 
-    let mut req = url.as_str().into_client_request().context("Invalid URL")?;
+    let request = || {
+        let mut req = url.as_str().into_client_request().context("Invalid URL")?;
+        caps.insert_headers(req.headers_mut());
+        device.insert_headers(req.headers_mut());
+        req.headers_mut().insert(
+            protocol::v6::CLOCK_HEADER,
+            HeaderValue::from(protocol::v6::clock_now()),
+        );
+        if let Some(port) = listen_port {
+            req.headers_mut()
+                .insert(protocol::v6::LISTEN_PORT_HEADER, HeaderValue::from(port));
+        }
+        anyhow::Ok(req)
+    };
+
+    let accepted = |resp: &Response<_>, sent: i64, rtt: Duration, caps_authenticated: bool| {
+        // The server time is assumed to be taken in the middle of the round trip
+        let clock_skew = resp
+            .headers()
+            .get(protocol::v6::CLOCK_HEADER)
+            .and_then(|value| protocol::v6::parse_clock(value.as_bytes()))
+            .map(|time| time - sent - rtt.as_millis() as i64 / 2);
+
+        Accepted {
+            caps: caps.negotiate(Capabilities::from_headers(resp.headers())),
+            clock_skew,
+            device: PeerDevice::from_headers(resp.headers()),
+            caps_authenticated,
+        }
+    };
+
+    let mut req = request()?;
 
     let nonce = drop_auth::Nonce::generate_as_client();
 
-    let (key, value) = auth::create_www_authentication_header(&nonce);
-    req.headers_mut().insert(key, value);
+    for (key, value) in auth.create_www_authentication_headers(&nonce) {
+        req.headers_mut().append(key, value);
+    }
 
+    let (sent, started) = (protocol::v6::clock_now(), Instant::now());
     let resp = send_request_and_wait_for_respnse(socket, req).await?;
+    let rtt = started.elapsed();
 
     let authorize = || {
         // Validate the server response
@@ -248,25 +650,49 @@ async fn make_request(
             authorize()?;
 
             debug!(logger, "Connected to {url} without authorization");
-            Ok(())
+            Ok(accepted(&resp, sent, rtt, false))
         }
         StatusCode::UNAUTHORIZED => {
+            recorder.enter(HandshakePhase::Auth);
             authorize()?;
 
             debug!(logger, "Creating 'authorization' header");
 
             debug!(logger, "Extracting peers ({ip}) public key");
             let (key, value) = auth.create_clients_auth_header(&resp, ip, true)?;
+            let authorization = value.to_str().context("Invalid 'authorization' header")?;
+            let authorization = authorization.to_owned();
 
             debug!(logger, "Building 'authorization' request");
-            let mut req = url.as_str().into_client_request().context("Invalid URL")?;
+            let mut req = request()?;
+            caps.insert_mac(req.headers_mut(), auth, ip, true, &authorization);
             req.headers_mut().insert(key, value);
 
             debug!(logger, "Re-sending request with the 'authorization' header");
+            let (sent, started) = (protocol::v6::clock_now(), Instant::now());
             let resp = send_request_and_wait_for_respnse(socket, req).await?;
+            let rtt = started.elapsed();
 
             match resp.status() {
-                status if status.is_success() || status.is_informational() => Ok(()),
+                status if status.is_success() || status.is_informational() => {
+                    let caps_authenticated = match Capabilities::verify_mac(
+                        resp.headers(),
+                        auth,
+                        ip,
+                        false,
+                        &authorization,
+                    ) {
+                        Some(true) => true,
+                        Some(false) => {
+                            return Err(
+                                anyhow::anyhow!("Invalid MAC of the server's capabilities").into()
+                            )
+                        }
+                        None => false,
+                    };
+
+                    Ok(accepted(&resp, sent, rtt, caps_authenticated))
+                }
                 status => Err(RequestError::UnexpectedResponse(status)),
             }
         }
@@ -275,7 +701,7 @@ async fn make_request(
 }
 
 async fn send_request_and_wait_for_respnse(
-    socket: &mut TcpStream,
+    socket: &mut PeerStream,
     req: Request<()>,
 ) -> anyhow::Result<Response<Option<Vec<u8>>>> {
     let resp = match tokio_tungstenite::client_async(req, &mut *socket).await {
@@ -297,16 +723,20 @@ impl RunContext<'_> {
         match self
             .state
             .transfer_manager
-            .outgoing_connected(self.xfer.id(), tx)
+            .outgoing_connected(
+                self.xfer.id(),
+                tx,
+                self.caps.contains(Capability::FileAddition),
+            )
             .await
         {
-            Ok(OutgoingConnected::Continue) => (),
+            Ok(OutgoingConnected::Continue { xfer }) => self.xfer = xfer,
             Ok(OutgoingConnected::JustCancelled { events }) => events.cancel(false).await,
             Err(crate::Error::BadTransfer) => return Ok(None),
             Err(err) => return Err(err),
         }
 
-        handler.start(socket, self.xfer).await?;
+        handler.start(socket, &self.xfer).await?;
 
         Ok(Some(rx))
     }
@@ -371,11 +801,16 @@ impl RunContext<'_> {
                     },
                     // Message to send down the wire
                     msg = upload_rx.recv() => {
-                        let MsgToSend { msg } = msg.expect("Handler channel should always be open");
+                        let MsgToSend { msg, budget } = msg.context("Upload channel closed")?;
                         socket.send(msg).await.context("Socket sending upload msg")?;
+                        drop(budget);
                     },
                     _ = ping.tick() => {
                         socket.send(Message::Ping(Vec::new())).await.context("Failed to send PING")?;
+
+                        if let Some(stats) = self.state.transfer_manager.outgoing_stats(self.xfer.id()).await {
+                            stats.ping_sent();
+                        }
                     }
                 }
             }
@@ -447,6 +882,15 @@ impl RunContext<'_> {
             }
             Message::Pong(_) => {
                 debug!(self.logger, "PONG");
+
+                if let Some(stats) = self
+                    .state
+                    .transfer_manager
+                    .outgoing_stats(self.xfer.id())
+                    .await
+                {
+                    stats.pong_received();
+                }
             }
             _ => warn!(self.logger, "Client received invalid WS message type"),
         }
@@ -467,7 +911,22 @@ impl RunContext<'_> {
             ClientReq::Fail { file, msg } => {
                 handler.issue_failure(socket, file, msg).await?;
             }
-            ClientReq::Close => {
+            ClientReq::Expire { file } => {
+                handler.issue_expire(socket, file).await?;
+            }
+            ClientReq::WithdrawFile { file } => {
+                handler.issue_withdraw_file(socket, file).await?;
+            }
+            ClientReq::AddFiles { xfer, files } => {
+                handler.issue_add_files(socket, xfer.clone(), files).await?;
+                self.xfer = xfer;
+            }
+            req @ (ClientReq::Close | ClientReq::Withdraw) => {
+                if let ClientReq::Withdraw = req {
+                    debug!(self.logger, "Withdrawing the transfer");
+                    handler.issue_withdraw(socket).await?;
+                }
+
                 debug!(self.logger, "Stopping client connection gracefuly");
                 socket.close().await?;
                 handler.on_close().await;
@@ -499,36 +958,151 @@ async fn start_upload(
         .outgoing_file_events(xfer.id(), &file_id)
         .await?;
 
+    let timer = state.config.pipeline_timing.then(|| events.timer().clone());
+    if let Some(timer) = &timer {
+        uploader.set_timer(timer.clone());
+    }
+
+    let chunk_size = ChunkSize::new(state.config.chunk_size);
+    let sizer = state
+        .config
+        .adaptive_chunk_size
+        .then(|| ChunkSizer::new(chunk_size.clone(), events.stats().clone()));
+
     let offset = uploader.offset();
 
     let permit = throttle::init(&logger, &state, &events, offset)
         .await
         .context("Failed to acquire upload permit")?;
 
-    let upload_job = async move {
+    let job = spawn_upload(
+        jobs,
+        state,
+        guard,
+        logger,
+        uploader,
+        xfer,
+        file_id,
+        permit.acquire(),
+        chunk_size,
+        sizer,
+        timer,
+    );
+
+    Ok((job, events))
+}
+
+/// Sends the `len` bytes of the file from the uploader's offset again, next
+/// to the upload in progress
+fn spawn_retransmission(
+    jobs: &mut JoinSet<()>,
+    guard: AliveGuard,
+    logger: slog::Logger,
+    mut uploader: impl Uploader,
+    xfer: Arc<OutgoingTransfer>,
+    file_id: FileId,
+    len: u64,
+) {
+    let offset = uploader.offset();
+
+    let guard = guard.task(
+        "client",
+        format!("Retransmission of file {file_id} of transfer {}", xfer.id()),
+    );
+    // Called from the transfer job, the transfer span is the current one
+    let span = spans::file(&spans::current(), xfer.id(), &file_id, "retransmission");
+    let job = async move {
         let _guard = guard;
         let xfile = &xfer.files()[&file_id];
 
-        let send_file = async {
-            let _permit = permit.acquire().await.ok_or(crate::Error::Canceled)?;
+        let send_range = async {
+            let mut iofile = xfile.open(offset)?;
+            let mut left = len;
 
-            let mut iofile = match xfile.open(offset) {
-                Ok(f) => f,
-                Err(err) => {
-                    error!(
-                        logger,
-                        "Failed at service::download() while opening a file: {}", err
-                    );
-                    return Err(err);
-                }
-            };
+            while left > 0 {
+                let chunk = match iofile.read_chunk()? {
+                    Some(chunk) => chunk,
+                    None => break,
+                };
 
-            loop {
-                match iofile.read_chunk()? {
-                    Some(chunk) => uploader.chunk(chunk).await?,
-                    None => return Ok(()),
-                }
+                let chunk = &chunk[..chunk.len().min(left as usize)];
+                left -= chunk.len() as u64;
+                uploader.chunk(chunk.to_vec()).await?;
             }
+
+            crate::Result::Ok(())
+        };
+
+        if let Err(err) = send_range.await {
+            // The receiver asks again or fails the file
+            warn!(logger, "Failed to retransmit {file_id}: {err}");
+        }
+    };
+
+    jobs.spawn(job.instrument(span));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_upload(
+    jobs: &mut JoinSet<()>,
+    state: Arc<State>,
+    guard: AliveGuard,
+    logger: slog::Logger,
+    mut uploader: impl Uploader,
+    xfer: Arc<OutgoingTransfer>,
+    file_id: FileId,
+    permit: impl Future<Output = Option<OwnedSemaphorePermit>> + Send + 'static,
+    chunk_size: ChunkSize,
+    sizer: Option<ChunkSizer>,
+    timer: Option<Arc<StageTimer>>,
+) -> AbortHandle {
+    let offset = uploader.offset();
+
+    let guard = guard.task(
+        "client",
+        format!("Upload of file {file_id} of transfer {}", xfer.id()),
+    );
+    let span = spans::file(&spans::current(), xfer.id(), &file_id, "upload");
+    let upload_job = async move {
+        let _guard = guard;
+        let xfile = &xfer.files()[&file_id];
+
+        let send_file = async {
+            let permit = permit.await.ok_or(crate::Error::Canceled)?;
+            uploader.set_permit(FilePermit::new(state.throttle.clone(), permit));
+
+            // Falls back to the single range when the peer does not take them
+            let mut ranges = ranges::split(offset, xfile.size(), state.config.parallel_ranges);
+            let forks: Vec<_> = ranges
+                .iter()
+                .skip(1)
+                .map_while(|range| uploader.fork(range.start))
+                .collect();
+            ranges.truncate(forks.len() + 1);
+
+            let last = forks.len();
+            let uploads = std::iter::once(uploader)
+                .chain(forks)
+                .zip(ranges)
+                .enumerate()
+                .map(|(i, (uploader, range))| {
+                    // The last range is read to the end of the file to catch
+                    // the changes of its size
+                    let end = (i < last).then_some(range.end);
+                    upload_range(
+                        &logger,
+                        xfile,
+                        uploader,
+                        range.start,
+                        end,
+                        chunk_size.clone(),
+                        sizer.as_ref(),
+                        timer.clone(),
+                    )
+                });
+
+            futures::future::try_join_all(uploads).await?;
+            Ok(())
         };
 
         match send_file.await {
@@ -557,13 +1131,58 @@ async fn start_upload(
         };
     };
 
-    Ok((jobs.spawn(upload_job), events))
+    jobs.spawn(upload_job.instrument(span))
+}
+
+/// Uploads the part of the file from the `start` until the `end` or the end
+/// of the file
+#[allow(clippy::too_many_arguments)]
+async fn upload_range(
+    logger: &slog::Logger,
+    xfile: &FileToSend,
+    mut uploader: impl Uploader,
+    start: u64,
+    end: Option<u64>,
+    chunk_size: ChunkSize,
+    sizer: Option<&ChunkSizer>,
+    timer: Option<Arc<StageTimer>>,
+) -> crate::Result<()> {
+    let mut chunks = match xfile.open(start) {
+        Ok(f) => f.read_ahead(chunk_size, timer),
+        Err(err) => {
+            error!(
+                logger,
+                "Failed at service::download() while opening a file: {}", err
+            );
+            return Err(err);
+        }
+    };
+
+    let mut pos = start;
+    while end.map_or(true, |end| pos < end) {
+        let Some(mut chunk) = chunks.next_chunk().await? else {
+            break;
+        };
+
+        if let Some(end) = end {
+            chunk.truncate(chunk.len().min((end - pos) as usize));
+        }
+        pos += chunk.len() as u64;
+        uploader.chunk(chunk).await?;
+
+        if let Some(sizer) = sizer {
+            sizer.adjust();
+        }
+    }
+
+    Ok(())
 }
 
 async fn on_upload_finished(
     state: &State,
     xfer: &OutgoingTransfer,
     file_id: &FileId,
+    final_path: Option<String>,
     logger: &slog::Logger,
 ) {
     match state
@@ -573,7 +1192,8 @@ async fn on_upload_finished(
     {
         Err(err) => warn!(logger, "Failed to accept file as done: {err}"),
         Ok(Some(res)) => {
-            res.file_events.success().await;
+            share::delivered(state, xfer.id(), file_id).await;
+            res.file_events.success(final_path).await;
             handle_finish_xfer_state(res.xfer_state, true).await;
         }
         Ok(None) => (),
@@ -0,0 +1,56 @@
+//! Splitting of a big file into the ranges uploaded at the same time, enabled
+//! with the `parallel_ranges` config option. Each range has its own reader on
+//! the blocking thread pool, while the upload task of the file seals and
+//! sends the chunks of all the ranges as they come. The chunks of all the
+//! ranges are interleaved on the single connection of the transfer, so it
+//! speeds up only the uploads held back by reading the file, e.g. from the
+//! slow or network storage, and not the links limited by the latency.
+
+use std::ops::Range;
+
+use drop_config::MIN_CHUNK_SIZE;
+
+// The smaller files are not worth splitting
+const MIN_RANGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Splits the `offset..size` part of the file into at most `count` ranges of
+/// about the same size, aligned to the minimal chunk size
+pub(crate) fn split(offset: u64, size: u64, count: usize) -> Vec<Range<u64>> {
+    let len = size.saturating_sub(offset);
+    let count = (count as u64).min(len / MIN_RANGE_SIZE).max(1);
+
+    let step = len / count / MIN_CHUNK_SIZE as u64 * MIN_CHUNK_SIZE as u64;
+    (0..count)
+        .map(|i| {
+            let start = offset + i * step;
+            let end = if i + 1 == count { size } else { start + step };
+            start..end
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitting() {
+        // Too small to split
+        assert_eq!(split(0, 1024, 4), vec![0..1024]);
+        assert_eq!(split(0, MIN_RANGE_SIZE, 1), vec![0..MIN_RANGE_SIZE]);
+        assert_eq!(split(10, 10, 4), vec![10..10]);
+
+        let size = 4 * MIN_RANGE_SIZE + 7;
+        let ranges = split(0, size, 4);
+        assert_eq!(ranges.len(), 4);
+        assert_eq!(ranges[1].start, MIN_RANGE_SIZE);
+        assert_eq!(ranges[3], 3 * MIN_RANGE_SIZE..size);
+
+        // Fewer ranges than asked for, the rest of the resumed file is small
+        let ranges = split(size - 2 * MIN_RANGE_SIZE, size, 4);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].start, size - 2 * MIN_RANGE_SIZE);
+        assert_eq!(ranges[1].end, size);
+        assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+    }
+}
@@ -0,0 +1,125 @@
+//! Relay transport used when the peer cannot be reached directly. The relay
+//! is a plain TCP forwarder accepting the HTTP `CONNECT` request, authorized
+//! with the bearer token. Once the relay accepts the request the stream is
+//! used exactly like a direct connection to the peer.
+
+use std::{io, net::SocketAddr};
+
+use drop_config::RelayConfig;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{service::State, utils};
+
+const MAX_RESPONSE_HEAD_LEN: usize = 4096;
+
+pub(super) async fn connect(
+    state: &State,
+    relay: &RelayConfig,
+    peer: SocketAddr,
+) -> io::Result<TcpStream> {
+    let relay_addr = tokio::net::lookup_host(&relay.addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Relay address did not resolve"))?;
+
+    let local = SocketAddr::new(state.local_addr_for(relay_addr.ip()), 0);
+    let mut socket = utils::connect(local, relay_addr).await?;
+
+    handshake(&mut socket, &relay.token, peer).await?;
+    Ok(socket)
+}
+
+async fn handshake(socket: &mut TcpStream, token: &str, peer: SocketAddr) -> io::Result<()> {
+    // The zone index is meaningless to the relay
+    let peer = SocketAddr::new(peer.ip(), peer.port());
+
+    let req = format!(
+        "CONNECT {peer} HTTP/1.1\r\nHost: {peer}\r\nProxy-Authorization: Bearer {token}\r\n\r\n"
+    );
+    socket.write_all(req.as_bytes()).await?;
+
+    // Read byte by byte so that nothing past the response head is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Relay response is too long",
+            ));
+        }
+
+        head.push(socket.read_u8().await?);
+    }
+
+    match parse_status(&head) {
+        Some(200) => Ok(()),
+        Some(status) => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Relay refused the connection with status {status}"),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid relay response",
+        )),
+    }
+}
+
+fn parse_status(head: &[u8]) -> Option<u16> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut parts = head.lines().next()?.split_whitespace();
+
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn relay_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let relay = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 1024];
+            let n = sock.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).to_string();
+
+            sock.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+            req
+        });
+
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        handshake(&mut sock, "secret", "[fe80::1%3]:49111".parse().unwrap())
+            .await
+            .unwrap();
+
+        let req = relay.await.unwrap();
+        assert!(req.starts_with("CONNECT [fe80::1]:49111 HTTP/1.1\r\n"));
+        assert!(req.contains("Proxy-Authorization: Bearer secret\r\n"));
+
+        // The data after the response head is left for the tunneled protocol
+        let mut buf = [0; 5];
+        sock.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        assert_eq!(
+            parse_status(b"HTTP/1.1 407 Unauthorized\r\n\r\n"),
+            Some(407)
+        );
+        assert_eq!(parse_status(b"SSH-2.0\r\n\r\n"), None);
+    }
+}
@@ -4,10 +4,11 @@ use std::{
 };
 
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 
-pub type WsStream = WebSocketStream<TcpStream>;
+use crate::tls::PeerStream;
+
+pub type WsStream = WebSocketStream<PeerStream>;
 
 pub struct WebSocket {
     stream: WsStream,
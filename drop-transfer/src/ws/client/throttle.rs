@@ -1,12 +1,28 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc};
 
 use slog::{error, info};
-use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 
 use crate::{service::State, ws::OutgoingFileEventTx};
 
 pub struct PermitInit(PermitInitRepr);
 
+/// The memory budget of the chunks queued for sending, shared by the uploads
+/// over the same connection
+#[derive(Clone)]
+pub(crate) struct UploadBudget {
+    bytes: Arc<Semaphore>,
+    limit: u32,
+}
+
+/// The upload permit of the file, shared by its ranges. Given back while the
+/// file waits for the receiver so that the other files are sent meanwhile
+#[derive(Clone)]
+pub struct FilePermit {
+    throttle: Arc<Semaphore>,
+    held: Arc<Mutex<Option<OwnedSemaphorePermit>>>,
+}
+
 enum PermitInitRepr {
     Acquired(OwnedSemaphorePermit),
     WillWait {
@@ -49,6 +65,49 @@ pub(crate) async fn init(
     Some(PermitInit(repr))
 }
 
+impl UploadBudget {
+    pub(crate) fn new(limit: usize) -> Self {
+        let limit = limit.clamp(1, u32::MAX as usize) as u32;
+
+        Self {
+            bytes: Arc::new(Semaphore::new(limit as _)),
+            limit,
+        }
+    }
+
+    /// Waits for the share of the budget for the chunk of `len` bytes. The
+    /// chunk bigger than the whole budget waits for all of it. Returns `None`
+    /// once the budget is closed
+    pub(crate) async fn reserve(&self, len: usize) -> Option<OwnedSemaphorePermit> {
+        let permits = len.min(self.limit as usize) as u32;
+        self.bytes.clone().acquire_many_owned(permits).await.ok()
+    }
+}
+
+impl FilePermit {
+    pub(crate) fn new(throttle: Arc<Semaphore>, permit: OwnedSemaphorePermit) -> Self {
+        Self {
+            throttle,
+            held: Arc::new(Mutex::new(Some(permit))),
+        }
+    }
+
+    /// Runs the `wait` without the permit and takes it back afterwards, after
+    /// the files started meanwhile if there is no free one. Returns `None`
+    /// once the throttle is closed
+    pub(crate) async fn released_while<T>(&self, wait: impl Future<Output = T>) -> Option<T> {
+        self.held.lock().await.take();
+        let out = wait.await;
+
+        // The ranges of the file take the single permit
+        let mut held = self.held.lock().await;
+        if held.is_none() {
+            *held = Some(self.throttle.clone().acquire_owned().await.ok()?);
+        }
+        Some(out)
+    }
+}
+
 impl PermitInit {
     pub async fn acquire(self) -> Option<OwnedSemaphorePermit> {
         match self.0 {
@@ -74,3 +133,70 @@ impl PermitInit {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn permit_released_while_waiting() {
+        let throttle = Arc::new(Semaphore::new(1));
+        let permit = throttle.clone().try_acquire_owned().unwrap();
+        let file = FilePermit::new(throttle.clone(), permit);
+
+        let (resume_tx, resume_rx) = oneshot::channel();
+        let paused = tokio::spawn({
+            let file = file.clone();
+            async move { file.released_while(resume_rx).await }
+        });
+
+        // Another file takes the permit while this one waits
+        let other = throttle.clone().acquire_owned().await.unwrap();
+        resume_tx.send(()).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!paused.is_finished());
+
+        // And gives it back once done
+        drop(other);
+        paused.await.unwrap().unwrap().unwrap();
+        assert_eq!(throttle.available_permits(), 0);
+
+        drop(file);
+        assert_eq!(throttle.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn budget_waits_for_queued_chunks() {
+        let budget = UploadBudget::new(100);
+
+        let first = budget.reserve(60).await.unwrap();
+        let second = budget.reserve(40).await.unwrap();
+
+        // Used up until the queued chunks are written
+        let third = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.reserve(10).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!third.is_finished());
+
+        drop(second);
+        let third = third.await.unwrap().unwrap();
+        drop((first, third));
+
+        // The chunk bigger than the whole budget waits for all of it
+        let partial = budget.reserve(1).await.unwrap();
+        let oversized = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.reserve(1000).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!oversized.is_finished());
+
+        drop(partial);
+        let oversized = oversized.await.unwrap().unwrap();
+        assert_eq!(oversized.num_permits(), 100);
+    }
+}
@@ -1,28 +1,48 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    sync::Arc,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use drop_core::Status;
 use slog::{debug, error, info, warn};
 use tokio::{
-    sync::mpsc::Sender,
+    sync::{mpsc::Sender, watch},
     task::{AbortHandle, JoinSet},
 };
 use tokio_tungstenite::tungstenite::Message;
 
 use super::{
     handler::{self, MsgToSend},
-    WebSocket,
+    FilePermit, UploadBudget, WebSocket,
 };
 use crate::{
-    manager::FileTerminalState, protocol::v6 as prot, service::State, tasks::AliveGuard,
-    transfer::Transfer, ws::events::FileEventTx, FileId, OutgoingTransfer,
+    event::RefusedOperation,
+    file::{DirEntries, File as _, HashAlg},
+    manager::FileTerminalState,
+    protocol::{
+        caps::{Capabilities, Capability},
+        v6 as prot,
+    },
+    service::State,
+    tasks::AliveGuard,
+    timing::{self, Stage, StageTimer},
+    transfer::Transfer,
+    ws::events::FileEventTx,
+    FileId, OutgoingTransfer,
 };
 
 pub struct HandlerInit<'a> {
     state: &'a Arc<State>,
+    encrypt: bool,
+    crc: bool,
+    parallel: bool,
+    expiry: bool,
+    withdrawal: bool,
+    resume_hash: HashAlg,
+    cipher: Option<Arc<prot::ChunkCipher>>,
     logger: &'a slog::Logger,
     alive: &'a AliveGuard,
 }
@@ -32,8 +52,23 @@ pub struct HandlerLoop<'a> {
     logger: &'a slog::Logger,
     alive: &'a AliveGuard,
     upload_tx: Sender<MsgToSend>,
+    // Per connection, so that the stalled socket holds back only the uploads
+    // queued on it
+    budget: UploadBudget,
     tasks: HashMap<FileId, FileTask>,
     xfer: Arc<OutgoingTransfer>,
+    cipher: Option<Arc<prot::ChunkCipher>>,
+    crc: bool,
+    parallel: bool,
+    // The receiver understands the `Expire` message
+    expiry: bool,
+    // The receiver understands the `WithdrawFile` message
+    withdrawal: bool,
+    resume_hash: HashAlg,
+    // The receiver paused the whole transfer
+    paused: watch::Sender<bool>,
+    // The rates the receiver paces the files at, in bytes per second
+    paces: HashMap<FileId, watch::Sender<Option<u64>>>,
 }
 
 struct FileTask {
@@ -43,18 +78,48 @@ struct FileTask {
 
 struct Uploader {
     sink: Sender<MsgToSend>,
+    budget: UploadBudget,
     file_id: FileId,
     offset: u64,
+    // The offset of the next chunk
+    pos: u64,
+    cipher: Option<Arc<prot::ChunkCipher>>,
+    crc: bool,
+    // The peer accepts the ranges of the file uploaded at the same time
+    parallel: bool,
+    timer: Option<Arc<StageTimer>>,
+    // Given back while the file waits for the receiver
+    permit: Option<FilePermit>,
+    paused: watch::Receiver<bool>,
+    pace: Pacer,
+}
+
+// Keeps the file at the rate the receiver paced it at. The ranges of the file
+// share it, so that together they stay at the rate
+#[derive(Clone)]
+struct Pacer {
+    rate: watch::Receiver<Option<u64>>,
+    // When the file may send the next chunk
+    next: Arc<Mutex<Instant>>,
 }
 
 impl<'a> HandlerInit<'a> {
     pub(crate) fn new(
         state: &'a Arc<State>,
+        caps: Capabilities,
         logger: &'a slog::Logger,
         alive: &'a AliveGuard,
     ) -> Self {
         Self {
             state,
+            encrypt: caps.contains(Capability::ChunkEncryption),
+            crc: caps.contains(Capability::ChunkCrc),
+            parallel: caps.contains(Capability::ChunkCrc)
+                && caps.contains(Capability::ParallelRanges),
+            expiry: caps.contains(Capability::FileExpiry),
+            withdrawal: caps.contains(Capability::FileWithdrawal),
+            resume_hash: state.resume_hash(caps.contains(Capability::ResumeChecksum)),
+            cipher: None,
             logger,
             alive,
         }
@@ -71,6 +136,19 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
         socket: &mut WebSocket,
         xfer: &OutgoingTransfer,
     ) -> crate::Result<()> {
+        if self.encrypt {
+            let cipher = self
+                .state
+                .auth
+                .chunk_cipher(xfer.peer(), xfer.id())
+                .ok_or_else(|| {
+                    error!(self.logger, "Missing keys for the chunk encryption");
+                    crate::Error::AuthenticationFailed
+                })?;
+
+            self.cipher = Some(cipher);
+        }
+
         let req = prot::TransferRequest::from(xfer);
         socket.send(Message::from(&req)).await?;
         Ok(())
@@ -79,6 +157,13 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
     fn upgrade(self, upload_tx: Sender<MsgToSend>, xfer: Arc<OutgoingTransfer>) -> Self::Loop {
         let Self {
             state,
+            encrypt: _,
+            crc,
+            parallel,
+            expiry,
+            withdrawal,
+            resume_hash,
+            cipher,
             logger,
             alive,
         } = self;
@@ -88,13 +173,22 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             alive,
             logger,
             upload_tx,
+            budget: UploadBudget::new(state.config.upload_buffer_bytes),
             xfer,
             tasks: HashMap::new(),
+            cipher,
+            crc,
+            parallel,
+            expiry,
+            withdrawal,
+            resume_hash,
+            paused: watch::channel(false).0,
+            paces: HashMap::new(),
         }
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        tokio::time::interval(drop_config::PING_INTERVAL)
+        tokio::time::interval(self.state.ping_interval())
     }
 }
 
@@ -149,8 +243,8 @@ impl HandlerLoop<'_> {
         }
     }
 
-    async fn on_done(&mut self, file_id: FileId) {
-        super::on_upload_finished(self.state, &self.xfer, &file_id, self.logger).await;
+    async fn on_done(&mut self, file_id: FileId, final_path: Option<String>) {
+        super::on_upload_finished(self.state, &self.xfer, &file_id, final_path, self.logger).await;
         self.stop_task(&file_id, Status::FileFinished).await;
     }
 
@@ -159,7 +253,11 @@ impl HandlerLoop<'_> {
         let msg_tx = self.upload_tx.clone();
         let xfer = self.xfer.clone();
         let logger = self.logger.clone();
-        let alive = self.alive.clone();
+        let resume_hash = self.resume_hash.clone();
+        let alive = self.alive.task(
+            "client",
+            format!("Checksum of file {file_id} of transfer {}", xfer.id()),
+        );
 
         let task = async move {
             let _guard = alive;
@@ -170,13 +268,14 @@ impl HandlerLoop<'_> {
                     .outgoing_ensure_file_not_terminated(xfer.id(), &file_id)
                     .await?;
 
-                let checksum = xfer.files()[&file_id]
-                    .checksum::<_, futures::future::Ready<()>>(
-                        limit,
-                        None::<fn(u64) -> futures::future::Ready<()>>,
-                        None,
-                    )
-                    .await?;
+                let xfile = &xfer.files()[&file_id];
+                // Only the checks of the partially downloaded file use the
+                // negotiated hash
+                let checksum = if limit < xfile.size() {
+                    xfile.checksum(&resume_hash, limit).await?
+                } else {
+                    xfile.full_checksum(&state.storage).await?
+                };
 
                 crate::Result::Ok(prot::ReportChsum {
                     file: file_id.clone(),
@@ -224,17 +323,44 @@ impl HandlerLoop<'_> {
         file_id: FileId,
         offset: u64,
     ) -> anyhow::Result<()> {
+        if !self.state.allow_data_flow(
+            self.logger,
+            self.xfer.id(),
+            Some(&file_id),
+            RefusedOperation::Upload,
+        ) {
+            // The receiver pauses the download and requests it again on the
+            // next connection
+            let msg = prot::ClientMsg::Cancel(prot::Cancel { file: file_id });
+            socket
+                .send(Message::from(&msg))
+                .await
+                .context("Failed to refuse the upload")?;
+
+            return Ok(());
+        }
+
         let start = async {
             self.state
                 .transfer_manager
-                .outgoing_ensure_file_not_terminated(self.xfer.id(), &file_id)
+                .outgoing_file_started(self.xfer.id(), &file_id)
                 .await?;
 
+            let pace = self.pace(&file_id);
             let start = || {
                 let uploader = Uploader {
                     sink: self.upload_tx.clone(),
+                    budget: self.budget.clone(),
                     file_id: file_id.clone(),
                     offset,
+                    pos: offset,
+                    cipher: self.cipher.clone(),
+                    crc: self.crc,
+                    parallel: self.parallel,
+                    timer: None,
+                    permit: None,
+                    paused: self.paused.subscribe(),
+                    pace: Pacer::new(pace.clone()),
                 };
                 let state = self.state.clone();
                 let alive = self.alive.clone();
@@ -286,6 +412,82 @@ impl HandlerLoop<'_> {
         Ok(())
     }
 
+    fn on_retransmit(&mut self, jobs: &mut JoinSet<()>, file_id: FileId, offset: u64, len: u64) {
+        if !self.tasks.contains_key(&file_id) {
+            warn!(self.logger, "Retransmission of unknown upload: {file_id}");
+            return;
+        }
+
+        info!(
+            self.logger,
+            "Retransmitting {len} bytes of file {file_id} from offset {offset}"
+        );
+
+        let uploader = Uploader {
+            sink: self.upload_tx.clone(),
+            budget: self.budget.clone(),
+            file_id: file_id.clone(),
+            offset,
+            pos: offset,
+            cipher: self.cipher.clone(),
+            crc: self.crc,
+            parallel: false,
+            timer: None,
+            permit: None,
+            paused: self.paused.subscribe(),
+            pace: Pacer::new(self.pace(&file_id)),
+        };
+
+        super::spawn_retransmission(
+            jobs,
+            self.alive.clone(),
+            self.logger.clone(),
+            uploader,
+            self.xfer.clone(),
+            file_id,
+            len,
+        );
+    }
+
+    async fn on_pause(&self, paused: bool) {
+        self.paused.send_replace(paused);
+
+        // The state is repeated on every connection
+        if let Some(events) = self
+            .state
+            .transfer_manager
+            .outgoing_peer_paused(self.xfer.id(), paused)
+            .await
+        {
+            info!(
+                self.logger,
+                "Receiver {} the transfer",
+                if paused { "paused" } else { "resumed" }
+            );
+            events.paused(paused).await;
+        }
+    }
+
+    fn pace(&mut self, file_id: &FileId) -> watch::Receiver<Option<u64>> {
+        self.paces
+            .entry(file_id.clone())
+            .or_insert_with(|| watch::channel(None).0)
+            .subscribe()
+    }
+
+    fn on_pace(&mut self, file_id: FileId, rate: Option<u64>) {
+        match rate {
+            Some(0) => info!(self.logger, "Receiver paused file {file_id}"),
+            Some(rate) => info!(self.logger, "Receiver paced file {file_id} at {rate} B/s"),
+            None => info!(self.logger, "Receiver lifted the pace of file {file_id}"),
+        }
+
+        self.paces
+            .entry(file_id)
+            .or_insert_with(|| watch::channel(None).0)
+            .send_replace(rate);
+    }
+
     async fn on_error(&mut self, file_id: Option<FileId>, msg: String) {
         error!(
             self.logger,
@@ -331,6 +533,84 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
         Ok(())
     }
 
+    async fn issue_expire(
+        &mut self,
+        socket: &mut WebSocket,
+        file_id: FileId,
+    ) -> anyhow::Result<()> {
+        let msg = if self.expiry {
+            prot::ClientMsg::Expire(prot::Expire { file: file_id })
+        } else {
+            prot::ClientMsg::Error(prot::Error {
+                file: Some(file_id),
+                msg: crate::Error::Expired.to_string(),
+            })
+        };
+        socket.send(Message::from(&msg)).await?;
+
+        Ok(())
+    }
+
+    async fn issue_withdraw_file(
+        &mut self,
+        socket: &mut WebSocket,
+        file_id: FileId,
+    ) -> anyhow::Result<()> {
+        let file = file_id.clone();
+        let msg = if self.withdrawal {
+            prot::ClientMsg::WithdrawFile(prot::WithdrawFile { file })
+        } else {
+            prot::ClientMsg::Reject(prot::Reject { file })
+        };
+        socket.send(Message::from(&msg)).await?;
+
+        self.stop_task(&file_id, Status::FileRejected).await;
+
+        Ok(())
+    }
+
+    async fn issue_withdraw(&mut self, socket: &mut WebSocket) -> anyhow::Result<()> {
+        socket
+            .send(Message::from(&prot::ClientMsg::Withdraw))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn issue_add_files(
+        &mut self,
+        socket: &mut WebSocket,
+        xfer: Arc<OutgoingTransfer>,
+        files: Vec<FileId>,
+    ) -> anyhow::Result<()> {
+        let (old, new) = (self.xfer.dir_entries(), xfer.dir_entries());
+        let msg = prot::ClientMsg::AddFiles(prot::AddFiles {
+            files: files
+                .iter()
+                .filter_map(|file_id| xfer.files().get(file_id))
+                .map(prot::File::from)
+                .collect(),
+            entries: DirEntries {
+                empty_dirs: new
+                    .empty_dirs
+                    .iter()
+                    .filter(|dir| !old.empty_dirs.contains(dir))
+                    .cloned()
+                    .collect(),
+                symlinks: new
+                    .symlinks
+                    .iter()
+                    .filter(|link| !old.symlinks.contains(link))
+                    .cloned()
+                    .collect(),
+            },
+        });
+        socket.send(Message::from(&msg)).await?;
+
+        self.xfer = xfer;
+        Ok(())
+    }
+
     async fn on_close(&mut self) {
         debug!(self.logger, "ClientHandler::on_close()");
         self.on_stop().await;
@@ -345,6 +625,17 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
         let msg: prot::ServerMsg =
             serde_json::from_str(&text).context("Failed to deserialize server message")?;
 
+        // The checksums are requested on every connection, before the receiver
+        // acts on the files
+        if let prot::ServerMsg::Start(_) | prot::ServerMsg::Done(_) | prot::ServerMsg::Reject(_) =
+            msg
+        {
+            self.state
+                .transfer_manager
+                .outgoing_accepted(self.xfer.id())
+                .await;
+        }
+
         match msg {
             prot::ServerMsg::Progress(prot::Progress {
                 file,
@@ -353,7 +644,8 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
             prot::ServerMsg::Done(prot::Done {
                 file,
                 bytes_transfered: _,
-            }) => self.on_done(file).await,
+                final_path,
+            }) => self.on_done(file, final_path).await,
             prot::ServerMsg::Error(prot::Error { file, msg }) => self.on_error(file, msg).await,
             prot::ServerMsg::ReqChsum(prot::ReqChsum { file, limit }) => {
                 self.on_checksum(jobs, file, limit)
@@ -363,6 +655,12 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
             }
             prot::ServerMsg::Cancel(prot::Cancel { file }) => self.on_cancel(file).await,
             prot::ServerMsg::Reject(prot::Reject { file }) => self.on_reject(file).await,
+            prot::ServerMsg::Retransmit(prot::Retransmit { file, offset, len }) => {
+                self.on_retransmit(jobs, file, offset, len)
+            }
+            prot::ServerMsg::Pause => self.on_pause(true).await,
+            prot::ServerMsg::Resume => self.on_pause(false).await,
+            prot::ServerMsg::Pace(prot::Pace { file, rate }) => self.on_pace(file, rate),
         }
         Ok(())
     }
@@ -392,25 +690,186 @@ impl Drop for HandlerLoop<'_> {
     }
 }
 
+impl Uploader {
+    // Waits for the receiver to resume the transfer and the file, giving the
+    // upload permit back meanwhile
+    async fn wait_resumed(&mut self) -> crate::Result<()> {
+        if !*self.paused.borrow() && *self.pace.rate.borrow() != Some(0) {
+            return Ok(());
+        }
+
+        let paused = &mut self.paused;
+        let rate = &mut self.pace.rate;
+        let resumed = async {
+            loop {
+                paused.wait_for(|paused| !paused).await?;
+                rate.wait_for(|rate| *rate != Some(0)).await?;
+                // The transfer could be paused again meanwhile
+                if !*paused.borrow() {
+                    return Ok::<_, watch::error::RecvError>(());
+                }
+            }
+        };
+
+        released_while(self.permit.as_ref(), resumed)
+            .await?
+            .map_err(|_| crate::Error::Canceled)
+    }
+
+    // Holds the next chunk back to keep the file at the paced rate, giving the
+    // upload permit back meanwhile. The new rate applies right away
+    async fn hold_back(&mut self, len: u64) -> crate::Result<()> {
+        let Some(delay) = self.pace.delay(len) else {
+            return Ok(());
+        };
+
+        let pace = &mut self.pace;
+        let wait = async {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                _ = pace.rate.changed() => pace.reset(),
+            }
+        };
+
+        released_while(self.permit.as_ref(), wait).await
+    }
+}
+
+impl Pacer {
+    fn new(rate: watch::Receiver<Option<u64>>) -> Self {
+        Self {
+            rate,
+            next: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    // Books the time the `len` bytes take at the paced rate and returns how
+    // long to wait for it, `None` when the file is not paced
+    fn delay(&mut self, len: u64) -> Option<Duration> {
+        let rate = (*self.rate.borrow_and_update())?;
+
+        let now = Instant::now();
+        let mut next = self.next.lock().expect("Poisoned lock");
+        *next = (*next).max(now) + Duration::from_secs_f64(len as f64 / rate.max(1) as f64);
+        Some(*next - now)
+    }
+
+    // Drops the time booked at the previous rate
+    fn reset(&self) {
+        *self.next.lock().expect("Poisoned lock") = Instant::now();
+    }
+}
+
+// Runs the wait without the upload permit, so that the other files upload
+// meanwhile
+async fn released_while<T>(
+    permit: Option<&FilePermit>,
+    wait: impl Future<Output = T>,
+) -> crate::Result<T> {
+    match permit {
+        Some(permit) => permit
+            .released_while(wait)
+            .await
+            .ok_or(crate::Error::Canceled),
+        None => Ok(wait.await),
+    }
+}
+
 #[async_trait::async_trait]
 impl handler::Uploader for Uploader {
-    async fn chunk(&mut self, chunk: &[u8]) -> Result<(), crate::Error> {
+    async fn chunk(&mut self, chunk: Vec<u8>) -> Result<(), crate::Error> {
+        self.wait_resumed().await?;
+
+        let budget = self
+            .budget
+            .reserve(chunk.len())
+            .await
+            .ok_or(crate::Error::Canceled)?;
+
+        let started = Instant::now();
+        let len = chunk.len() as u64;
+        let mut data = match &self.cipher {
+            Some(cipher) => cipher.seal(&self.file_id, self.pos, &chunk),
+            None => chunk,
+        };
+        if self.crc {
+            prot::append_chunk_crc(&mut data, self.pos);
+        }
+        self.pos += len;
+        timing::record(self.timer.as_deref(), Stage::Encrypt, started);
+
         let msg = prot::Chunk {
             file: self.file_id.clone(),
-            data: chunk.to_vec(),
+            data,
         };
 
+        let started = Instant::now();
         self.sink
             .send(MsgToSend {
                 msg: Message::from(msg),
+                budget: Some(budget),
             })
             .await
             .map_err(|_| crate::Error::Canceled)?;
+        timing::record(self.timer.as_deref(), Stage::Send, started);
 
-        Ok(())
+        self.hold_back(len).await
     }
 
     fn offset(&self) -> u64 {
         self.offset
     }
+
+    fn fork(&self, offset: u64) -> Option<Self> {
+        self.parallel.then(|| Self {
+            sink: self.sink.clone(),
+            budget: self.budget.clone(),
+            file_id: self.file_id.clone(),
+            offset,
+            pos: offset,
+            cipher: self.cipher.clone(),
+            crc: self.crc,
+            parallel: false,
+            timer: self.timer.clone(),
+            permit: self.permit.clone(),
+            paused: self.paused.clone(),
+            // The ranges book the time of their chunks with the same pacer
+            pace: self.pace.clone(),
+        })
+    }
+
+    fn set_timer(&mut self, timer: Arc<StageTimer>) {
+        self.timer = Some(timer);
+    }
+
+    fn set_permit(&mut self, permit: FilePermit) {
+        self.permit = Some(permit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_share_the_pace() {
+        let (rate, rx) = watch::channel(Some(1000));
+        let mut first = Pacer::new(rx);
+        let mut second = first.clone();
+
+        // Each range waits for the chunks booked before its own
+        let delay = first.delay(500).unwrap();
+        assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500));
+        let delay = second.delay(500).unwrap();
+        assert!(delay > Duration::from_millis(950) && delay <= Duration::from_millis(1000));
+
+        // The new rate does not wait for the time booked at the previous one
+        rate.send_replace(Some(10_000));
+        first.reset();
+        let delay = second.delay(500).unwrap();
+        assert!(delay <= Duration::from_millis(50));
+
+        rate.send_replace(None);
+        assert_eq!(first.delay(500), None);
+    }
 }
@@ -9,7 +9,8 @@ use drop_core::Status;
 use tokio::sync::{mpsc::UnboundedSender, Mutex};
 
 use crate::{
-    file::FileInfo, utils, Event, File, FileId, IncomingTransfer, OutgoingTransfer, Transfer,
+    file::FileInfo, stats::StatsTracker, timing::StageTimer, utils, Event, File, FileId,
+    HandshakeDiagnostics, IncomingTransfer, OutgoingTransfer, Transfer,
 };
 
 struct FileEventTxInner {
@@ -34,6 +35,8 @@ pub struct FileEventTx<T: Transfer> {
     inner: Mutex<FileEventTxInner>,
     xfer: Arc<T>,
     file_id: FileId,
+    stats: Arc<StatsTracker>,
+    timer: Arc<StageTimer>,
 }
 
 pub struct EventTxFactory {
@@ -43,7 +46,8 @@ pub struct EventTxFactory {
 
 pub struct TransferEventTx<T: Transfer> {
     inner: Mutex<TransferEventTxInner>,
-    pub xfer: Arc<T>,
+    // Replaced when the files are added to the transfer
+    xfer: std::sync::Mutex<Arc<T>>,
 }
 
 pub type IncomingTransferEventTx = TransferEventTx<IncomingTransfer>;
@@ -77,7 +81,12 @@ impl EventTxFactory {
         Self { events, moose }
     }
 
-    pub fn file<T: Transfer>(&self, xfer: Arc<T>, file_id: FileId) -> FileEventTx<T> {
+    pub(crate) fn file<T: Transfer>(
+        &self,
+        xfer: Arc<T>,
+        file_id: FileId,
+        stats: Arc<StatsTracker>,
+    ) -> FileEventTx<T> {
         FileEventTx {
             inner: Mutex::new(FileEventTxInner {
                 tx: self.events.clone(),
@@ -87,6 +96,8 @@ impl EventTxFactory {
             }),
             xfer,
             file_id,
+            stats,
+            timer: Default::default(),
         }
     }
 
@@ -101,7 +112,7 @@ impl EventTxFactory {
                     TransferState::Ongoing
                 },
             }),
-            xfer,
+            xfer: std::sync::Mutex::new(xfer),
         }
     }
 }
@@ -111,6 +122,39 @@ impl<T: Transfer> FileEventTx<T> {
         self.xfer.files()[&self.file_id].info()
     }
 
+    /// The live status of the file, without the terminal states which are
+    /// tracked by the manager, and the number of transferred bytes
+    pub(crate) async fn live_status(&self) -> (crate::manager::FileLiveStatus, u64) {
+        use crate::manager::FileLiveStatus;
+
+        let lock = self.inner.lock().await;
+        let status = match lock.state {
+            FileState::Idle | FileState::Terminal => FileLiveStatus::Idle,
+            FileState::Throttled | FileState::Preflight => FileLiveStatus::Pending,
+            FileState::InFlight { .. } => FileLiveStatus::InFlight,
+        };
+
+        (status, lock.transferred)
+    }
+
+    /// The accumulated time of the chunk pipeline stages of the file
+    pub(crate) fn timer(&self) -> &Arc<StageTimer> {
+        &self.timer
+    }
+
+    /// The live statistics of the whole transfer
+    pub(crate) fn stats(&self) -> &Arc<StatsTracker> {
+        &self.stats
+    }
+
+    /// Whether the file data is being transferred right now
+    pub async fn is_in_flight(&self) -> bool {
+        matches!(
+            self.inner.lock().await.state,
+            FileState::Preflight | FileState::InFlight { .. }
+        )
+    }
+
     async fn emit_in_flight(&self, event: Event) {
         let mut lock = self.inner.lock().await;
 
@@ -123,6 +167,8 @@ impl<T: Transfer> FileEventTx<T> {
         match event {
             Event::FileUploadProgress(_, _, progress)
             | Event::FileDownloadProgress(_, _, progress) => {
+                self.stats
+                    .transferred(progress.saturating_sub(lock.transferred));
                 lock.transferred = progress;
             }
             _ => {}
@@ -321,6 +367,11 @@ impl FileEventTx<IncomingTransfer> {
         .await
     }
 
+    /// Records the received bytes dropped because of the corrupted chunks
+    pub fn retransmitted(&self, bytes: u64) {
+        self.stats.retransmitted(bytes);
+    }
+
     pub async fn progress(&self, transfered: u64) {
         self.emit_in_flight(crate::Event::FileDownloadProgress(
             self.xfer.clone(),
@@ -345,10 +396,15 @@ impl FileEventTx<IncomingTransfer> {
         lock.state = FileState::Preflight;
     }
 
-    pub async fn failed(&self, err: crate::Error) {
+    pub async fn failed(&self, err: crate::Error, partial_path: Option<PathBuf>) {
         let status = i32::from(&err);
         self.terminate(
-            crate::Event::FileDownloadFailed(self.xfer.clone(), self.file_id.clone(), err),
+            crate::Event::FileDownloadFailed(
+                self.xfer.clone(),
+                self.file_id.clone(),
+                err,
+                partial_path,
+            ),
             Err(status),
         )
         .await
@@ -366,13 +422,27 @@ impl FileEventTx<IncomingTransfer> {
         .await
     }
 
+    pub async fn withdrawn(&self) {
+        self.terminate(
+            crate::Event::FileDownloadWithdrawn {
+                transfer_id: self.xfer.id(),
+                file_id: self.file_id.clone(),
+            },
+            Err(Status::FileRejected as _),
+        )
+        .await
+    }
+
     pub async fn success(&self, final_path: impl Into<PathBuf>) {
+        let path_sanitized = self.xfer.files()[&self.file_id].is_path_sanitized();
+
         self.terminate(
             crate::Event::FileDownloadSuccess(
                 self.xfer.clone(),
                 crate::event::DownloadSuccess {
                     id: self.file_id.clone(),
                     final_path: crate::utils::Hidden(final_path.into().into_boxed_path()),
+                    path_sanitized,
                 },
             ),
             Ok(()),
@@ -450,9 +520,9 @@ impl FileEventTx<OutgoingTransfer> {
         .await
     }
 
-    pub async fn success(&self) {
+    pub async fn success(&self, final_path: Option<String>) {
         self.terminate(
-            crate::Event::FileUploadSuccess(self.xfer.clone(), self.file_id.clone()),
+            crate::Event::FileUploadSuccess(self.xfer.clone(), self.file_id.clone(), final_path),
             Ok(()),
         )
         .await
@@ -480,9 +550,24 @@ impl FileEventTx<OutgoingTransfer> {
         )
         .await
     }
+
+    pub async fn withdrawn(&self) {
+        self.terminate(
+            crate::Event::FileUploadWithdrawn {
+                transfer_id: self.xfer.id(),
+                file_id: self.file_id.clone(),
+            },
+            Err(Status::FileRejected as _),
+        )
+        .await
+    }
 }
 
 impl<T: Transfer> TransferEventTx<T> {
+    pub fn xfer(&self) -> Arc<T> {
+        self.xfer.lock().expect("Poisoned lock").clone()
+    }
+
     async fn emit_ongoing(&self, event: Event) {
         let lock = self.inner.lock().await;
 
@@ -508,8 +593,7 @@ impl<T: Transfer> TransferEventTx<T> {
 
 impl TransferEventTx<OutgoingTransfer> {
     pub async fn queued(&self) {
-        self.emit_ongoing(Event::RequestQueued(self.xfer.clone()))
-            .await;
+        self.emit_ongoing(Event::RequestQueued(self.xfer())).await;
     }
 
     pub async fn failed(&self, err: crate::Error, by_peer: bool) {
@@ -523,25 +607,49 @@ impl TransferEventTx<OutgoingTransfer> {
 
         lock.moose.event_transfer_state(TransferStateEventData {
             protocol_version: 0,
-            transfer_id: self.xfer.id().to_string(),
+            transfer_id: self.xfer().id().to_string(),
             result: i32::from(&err),
         });
 
-        lock.tx.emit(Event::OutgoingTransferFailed(
-            self.xfer.clone(),
-            err,
-            by_peer,
-        ));
+        lock.tx
+            .emit(Event::OutgoingTransferFailed(self.xfer(), err, by_peer));
     }
 
     pub async fn deferred(&self, err: crate::Error) {
         self.emit_ongoing(Event::OutgoingTransferDeferred {
-            transfer: self.xfer.clone(),
+            transfer: self.xfer(),
             error: err,
         })
         .await;
     }
 
+    pub async fn postponed(&self) {
+        self.emit_ongoing(Event::OutgoingTransferPostponed(self.xfer()))
+            .await;
+    }
+
+    pub async fn peer_key_unavailable(&self) {
+        self.emit_ongoing(Event::OutgoingPeerKeyUnavailable(self.xfer()))
+            .await;
+    }
+
+    pub async fn connection_attempt_failed(&self, attempt: u32, next_retry_in: Option<Duration>) {
+        self.emit_ongoing(Event::OutgoingConnectionAttemptFailed {
+            transfer: self.xfer(),
+            attempt,
+            next_retry_in,
+        })
+        .await;
+    }
+
+    pub async fn connection_diagnostics(&self, diagnostics: HandshakeDiagnostics) {
+        self.emit_ongoing(Event::OutgoingConnectionDiagnostics {
+            transfer: self.xfer(),
+            diagnostics,
+        })
+        .await;
+    }
+
     pub async fn connected(&self, protocol_version: i32) {
         let lock = self.inner.lock().await;
 
@@ -551,15 +659,43 @@ impl TransferEventTx<OutgoingTransfer> {
 
         lock.moose.event_transfer_state(TransferStateEventData {
             protocol_version,
-            transfer_id: self.xfer.id().to_string(),
+            transfer_id: self.xfer().id().to_string(),
             result: MOOSE_STATUS_SUCCESS,
         });
     }
 
+    pub async fn connectivity(&self, relayed: bool) {
+        self.emit_ongoing(Event::OutgoingTransferConnected {
+            transfer: self.xfer(),
+            relayed,
+        })
+        .await;
+    }
+
     pub async fn cancel(&self, by_peer: bool) {
-        self.stop(Event::OutgoingTransferCanceled(self.xfer.clone(), by_peer))
+        self.stop(Event::OutgoingTransferCanceled(self.xfer(), by_peer))
+            .await;
+    }
+
+    pub async fn withdrawn(&self, by_peer: bool) {
+        self.stop(Event::OutgoingTransferWithdrawn(self.xfer(), by_peer))
+            .await;
+    }
+
+    /// Reports the files added to the transfer, `xfer` carries all of them
+    pub async fn files_added(&self, xfer: Arc<OutgoingTransfer>, files: Vec<FileId>) {
+        *self.xfer.lock().expect("Poisoned lock") = xfer.clone();
+        self.emit_ongoing(Event::OutgoingFilesAdded(xfer, files))
             .await;
     }
+
+    pub async fn paused(&self, paused: bool) {
+        self.emit_ongoing(Event::OutgoingTransferPaused {
+            transfer_id: self.xfer().id(),
+            paused,
+        })
+        .await;
+    }
 }
 
 impl TransferEventTx<IncomingTransfer> {
@@ -569,17 +705,37 @@ impl TransferEventTx<IncomingTransfer> {
             .await
             .moose
             .event_transfer_intent_received(drop_analytics::TransferIntentReceivedEventData {
-                transfer_id: self.xfer.id().to_string(),
+                transfer_id: self.xfer().id().to_string(),
             });
 
-        self.emit_ongoing(Event::RequestReceived(self.xfer.clone()))
-            .await;
+        self.emit_ongoing(Event::RequestReceived(self.xfer())).await;
     }
 
     pub async fn cancel(&self, by_peer: bool) {
-        self.stop(Event::IncomingTransferCanceled(self.xfer.clone(), by_peer))
+        self.stop(Event::IncomingTransferCanceled(self.xfer(), by_peer))
             .await;
     }
+
+    pub async fn withdrawn(&self, by_peer: bool) {
+        self.stop(Event::IncomingTransferWithdrawn(self.xfer(), by_peer))
+            .await;
+    }
+
+    /// Reports the files added to the transfer by the sender, `xfer` carries
+    /// all of them
+    pub async fn files_added(&self, xfer: Arc<IncomingTransfer>, files: Vec<FileId>) {
+        *self.xfer.lock().expect("Poisoned lock") = xfer.clone();
+        self.emit_ongoing(Event::IncomingFilesAdded(xfer, files))
+            .await;
+    }
+
+    pub async fn paused(&self, paused: bool) {
+        self.emit_ongoing(Event::IncomingTransferPaused {
+            transfer_id: self.xfer().id(),
+            paused,
+        })
+        .await;
+    }
 }
 
 impl<T: Transfer> Drop for FileEventTx<T> {
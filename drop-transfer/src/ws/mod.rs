@@ -1,5 +1,6 @@
 pub mod client;
 mod events;
+pub(crate) mod mux;
 pub mod server;
 mod utils;
 
@@ -0,0 +1,926 @@
+//! Shares one connection between the transfers in both directions between
+//! the same pair of peers. The peer dialing the other one for its transfer
+//! upgrades the authenticated `/drop/mux` request into a WebSocket carrying
+//! the logical streams, each used exactly like a direct connection. The
+//! transfers of the other peer towards the dialer open their streams over it
+//! instead of dialing back. The peers not supporting it answer with 404 and
+//! each transfer gets its own connection.
+//!
+//! Each binary WebSocket message carries one frame: the big-endian stream ID,
+//! the frame kind and the payload. The dialer picks the odd stream IDs, the
+//! other peer the even ones. Each side sends at most `STREAM_WINDOW` bytes
+//! over a stream before the peer credits them back, so that a slow stream
+//! does not buffer without limit nor stall the others. The connection is
+//! closed once none of its streams is left for the keep-alive period, so
+//! that the next transfers reuse it without connecting and authenticating
+//! again. Meanwhile the connection is pinged so that the NATs and the
+//! firewalls along the way do not drop it.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use slog::{debug, warn, Logger};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::{mpsc, oneshot, Semaphore},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+/// The path segment of the request upgrading to the shared connection
+pub(crate) const RESOURCE: &str = "mux";
+
+const HEADER_LEN: usize = 5;
+// The in-memory buffer of each logical stream
+const STREAM_BUFFER_LEN: usize = 64 * 1024;
+// The frames read from the logical streams waiting for the connection
+const OUTBOUND_FRAMES: usize = 16;
+// The bytes sent over a stream and not yet credited back by the peer
+const STREAM_WINDOW: usize = 4 * STREAM_BUFFER_LEN;
+// How long the connection is kept when no stream is opened over it
+const UNUSED_TIMEOUT: Duration = Duration::from_secs(30);
+// How long the connection may stay silent before it's pinged
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+type Incoming = (SocketAddr, DuplexStream);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    // Opens the stream with the ID picked by the sender
+    Open,
+    Data,
+    // The sender is not going to write into the stream anymore
+    Fin,
+    // The receiver took in the given number of bytes, the sender may send as
+    // many again
+    Credit,
+    // Keeps the silent connection open, ignored by the receiver
+    Ping,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    stream: u32,
+    kind: FrameKind,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn control(stream: u32, kind: FrameKind) -> Self {
+        Self {
+            stream,
+            kind,
+            payload: Vec::new(),
+        }
+    }
+
+    fn credit(stream: u32, bytes: usize) -> Self {
+        Self {
+            stream,
+            kind: FrameKind::Credit,
+            payload: (bytes as u32).to_be_bytes().to_vec(),
+        }
+    }
+
+    fn credited_bytes(&self) -> Option<usize> {
+        let bytes = u32::from_be_bytes(self.payload.as_slice().try_into().ok()?);
+        Some(bytes as usize)
+    }
+
+    fn encode(self) -> Vec<u8> {
+        let kind = match self.kind {
+            FrameKind::Open => 0,
+            FrameKind::Data => 1,
+            FrameKind::Fin => 2,
+            FrameKind::Credit => 3,
+            FrameKind::Ping => 4,
+        };
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.stream.to_be_bytes());
+        buf.push(kind);
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    fn decode(mut buf: Vec<u8>) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        let stream = u32::from_be_bytes(buf[..4].try_into().ok()?);
+        let kind = match buf[4] {
+            0 => FrameKind::Open,
+            1 => FrameKind::Data,
+            2 => FrameKind::Fin,
+            3 => FrameKind::Credit,
+            4 => FrameKind::Ping,
+            _ => return None,
+        };
+
+        Some(Self {
+            stream,
+            kind,
+            payload: buf.split_off(HEADER_LEN),
+        })
+    }
+}
+
+/// Opens the logical streams over the connection shared with the peer
+#[derive(Clone)]
+pub(crate) struct Session {
+    open_tx: mpsc::UnboundedSender<oneshot::Sender<DuplexStream>>,
+    relayed: bool,
+    // Closes the connection once its streams finish
+    retire: CancellationToken,
+}
+
+impl Session {
+    pub(crate) async fn open(&self) -> io::Result<DuplexStream> {
+        let closed = || io::Error::new(io::ErrorKind::NotConnected, "Shared connection closed");
+
+        let (tx, rx) = oneshot::channel();
+        self.open_tx.send(tx).map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())
+    }
+
+    /// Whether the connection goes through the relay. Only known to the
+    /// dialer
+    pub(crate) fn relayed(&self) -> bool {
+        self.relayed
+    }
+
+    fn is_closed(&self) -> bool {
+        self.open_tx.is_closed()
+    }
+}
+
+/// The connections shared with the peers, by their IP addresses
+pub(crate) struct Registry {
+    sessions: Mutex<HashMap<IpAddr, Session>>,
+    // The streams opened by the peers, served like the incoming connections
+    incoming_tx: mpsc::UnboundedSender<Incoming>,
+    incoming_rx: Mutex<Option<mpsc::UnboundedReceiver<Incoming>>>,
+    // How long the connections without any streams are kept
+    keep_alive: Duration,
+    ping_interval: Duration,
+    stop: CancellationToken,
+}
+
+impl Registry {
+    pub(crate) fn new(stop: CancellationToken, keep_alive: Option<Duration>) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+        Self {
+            sessions: Mutex::default(),
+            incoming_tx,
+            incoming_rx: Mutex::new(Some(incoming_rx)),
+            keep_alive: keep_alive.unwrap_or_default(),
+            ping_interval: PING_INTERVAL,
+            stop,
+        }
+    }
+
+    /// The open connection shared with the peer, if any
+    pub(crate) fn get(&self, peer: IpAddr) -> Option<Session> {
+        let mut sessions = self.sessions.lock().expect("Poisoned lock");
+        match sessions.get(&peer) {
+            Some(session) if session.is_closed() => {
+                sessions.remove(&peer);
+                None
+            }
+            session => session.cloned(),
+        }
+    }
+
+    /// Takes the receiver of the streams opened by the peers, there is only
+    /// one
+    pub(crate) fn take_incoming(&self) -> Option<mpsc::UnboundedReceiver<Incoming>> {
+        self.incoming_rx.lock().expect("Poisoned lock").take()
+    }
+
+    /// Starts sharing the connection with the peer. It replaces the previous
+    /// one, which is closed once its streams finish
+    pub(crate) fn start<Si, St, E>(
+        &self,
+        peer: SocketAddr,
+        sink: Si,
+        stream: St,
+        dialer: bool,
+        relayed: bool,
+        logger: &Logger,
+    ) -> Session
+    where
+        Si: Sink<Vec<u8>, Error = E> + Send + Unpin + 'static,
+        St: Stream<Item = Result<Vec<u8>, E>> + Send + Unpin + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        let (open_tx, open_rx) = mpsc::unbounded_channel();
+        let retire = CancellationToken::new();
+        let session = Session {
+            open_tx,
+            relayed,
+            retire: retire.clone(),
+        };
+        let replaced = self
+            .sessions
+            .lock()
+            .expect("Poisoned lock")
+            .insert(peer.ip(), session.clone());
+        if let Some(replaced) = replaced {
+            replaced.retire.cancel();
+        }
+
+        let (frames_tx, frames_rx) = mpsc::channel(OUTBOUND_FRAMES);
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let conn = Connection {
+            peer,
+            out_tx,
+            next_id: if dialer { 1 } else { 2 },
+            streams: HashMap::new(),
+            used: false,
+            idle_since: Some(Instant::now()),
+            keep_alive: self.keep_alive,
+            retire,
+            frames_tx,
+            incoming: self.incoming_tx.clone(),
+        };
+
+        // The frames are written by their own task, so that reading the
+        // peer's frames never waits for the writes. Otherwise both peers
+        // blocked on writing would never read what unblocks the other one
+        let mut writer = tokio::spawn(write_frames(sink, out_rx, self.ping_interval));
+
+        let stop = self.stop.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let res = tokio::select! {
+                _ = stop.cancelled() => {
+                    writer.abort();
+                    return;
+                },
+                res = conn.run(stream, open_rx, frames_rx) => match res {
+                    // The writer closes the connection once the queued frames
+                    // are sent
+                    Ok(()) => writer.await.unwrap_or(Ok(())),
+                    Err(err) => {
+                        writer.abort();
+                        Err(err)
+                    }
+                },
+                res = &mut writer => res.unwrap_or(Ok(())),
+            };
+
+            match res {
+                Ok(()) => debug!(logger, "Connection shared with {peer} closed"),
+                Err(err) => warn!(logger, "Connection shared with {peer} failed: {err}"),
+            }
+        });
+
+        session
+    }
+}
+
+struct StreamState {
+    // `None` once the peer finished writing
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    // The bytes received and not yet credited back to the peer
+    inbound_pending: usize,
+    // The bytes the peer is ready to take in
+    outbound_credit: Arc<Semaphore>,
+    // The bytes sent and not yet credited back by the peer
+    outbound_pending: usize,
+    // Set once the local side finished writing
+    outbound_done: bool,
+}
+
+struct Connection {
+    peer: SocketAddr,
+    // The frames for the writer task
+    out_tx: mpsc::UnboundedSender<Frame>,
+    next_id: u32,
+    streams: HashMap<u32, StreamState>,
+    // Set once the first stream is opened, the connection is closed when the
+    // last one finishes and no new one is opened for the keep-alive period
+    used: bool,
+    // When the last stream finished, `None` while any is open
+    idle_since: Option<Instant>,
+    keep_alive: Duration,
+    retire: CancellationToken,
+    frames_tx: mpsc::Sender<Frame>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+}
+
+/// Writes the frames into the connection, pinging it once it's silent for the
+/// ping interval. Closes the connection once all of the frames are sent
+async fn write_frames<Si, E>(
+    mut sink: Si,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+    ping_interval: Duration,
+) -> io::Result<()>
+where
+    Si: Sink<Vec<u8>, Error = E> + Unpin,
+    E: fmt::Display,
+{
+    loop {
+        let frame = match tokio::time::timeout(ping_interval, frames.recv()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(_) => Frame::control(0, FrameKind::Ping),
+        };
+
+        sink.send(frame.encode())
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    }
+
+    let _ = sink.close().await;
+    Ok(())
+}
+
+impl Connection {
+    async fn run<St, E>(
+        mut self,
+        mut stream: St,
+        mut open_rx: mpsc::UnboundedReceiver<oneshot::Sender<DuplexStream>>,
+        mut frames_rx: mpsc::Receiver<Frame>,
+    ) -> io::Result<()>
+    where
+        St: Stream<Item = Result<Vec<u8>, E>> + Unpin,
+        E: fmt::Display,
+    {
+        loop {
+            let retire = self.retire.clone();
+            let timeout = if retire.is_cancelled() {
+                Duration::ZERO
+            } else if self.used {
+                self.keep_alive
+            } else {
+                self.keep_alive.max(UNUSED_TIMEOUT)
+            };
+            let deadline = self.idle_since.map(|since| since + timeout);
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                return Ok(());
+            }
+
+            tokio::select! {
+                Some(reply) = open_rx.recv() => {
+                    let id = self.next_id;
+                    self.next_id += 2;
+
+                    self.send(Frame::control(id, FrameKind::Open))?;
+                    // The stream is closed right away if the caller is gone
+                    let _ = reply.send(self.add_stream(id));
+                },
+                frame = stream.next() => {
+                    let buf = match frame {
+                        Some(Ok(buf)) => buf,
+                        Some(Err(err)) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+                        None => return Ok(()),
+                    };
+
+                    let frame = Frame::decode(buf).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Invalid frame")
+                    })?;
+                    self.on_frame(frame)?;
+                },
+                Some(frame) = frames_rx.recv() => {
+                    self.on_local_frame(&frame);
+                    self.send(frame)?;
+                },
+                _ = retire.cancelled(), if !retire.is_cancelled() => (),
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => (),
+            }
+        }
+    }
+
+    // Queues the frame for the writer task
+    fn send(&self, frame: Frame) -> io::Result<()> {
+        self.out_tx
+            .send(frame)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Connection writer is gone"))
+    }
+
+    // Accounts for the frame of the local side before it's sent
+    fn on_local_frame(&mut self, frame: &Frame) {
+        let Some(state) = self.streams.get_mut(&frame.stream) else {
+            return;
+        };
+
+        match frame.kind {
+            FrameKind::Open | FrameKind::Ping => (),
+            FrameKind::Data => state.outbound_pending += frame.payload.len(),
+            FrameKind::Fin => {
+                state.outbound_done = true;
+                self.remove_if_finished(frame.stream);
+            }
+            FrameKind::Credit => {
+                let bytes = frame.credited_bytes().unwrap_or_default();
+                state.inbound_pending = state.inbound_pending.saturating_sub(bytes);
+            }
+        }
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        match frame.kind {
+            FrameKind::Open => {
+                // The local side picks the IDs of the same parity as its next
+                // one
+                if frame.stream % 2 == self.next_id % 2 {
+                    return Err(invalid("Stream ID of the wrong parity"));
+                }
+                if self.streams.contains_key(&frame.stream) {
+                    return Err(invalid("Stream opened twice"));
+                }
+
+                let stream = self.add_stream(frame.stream);
+                // Dropping the stream closes it if the server is gone
+                let _ = self.incoming.send((self.peer, stream));
+            }
+            FrameKind::Data => {
+                let len = frame.payload.len();
+                let Some(state) = self.streams.get_mut(&frame.stream) else {
+                    // The data of the stream already gone is dropped but
+                    // credited back, so that the peer is not held back
+                    return self.send(Frame::credit(frame.stream, len));
+                };
+
+                let delivered = match &state.inbound {
+                    Some(inbound) => inbound.send(frame.payload).is_ok(),
+                    None => false,
+                };
+                if delivered {
+                    state.inbound_pending += len;
+                    if state.inbound_pending > STREAM_WINDOW {
+                        return Err(invalid("Stream window exceeded"));
+                    }
+                } else {
+                    // The local side dropped the stream, it's removed once the
+                    // peer finishes it too
+                    self.send(Frame::credit(frame.stream, len))?;
+                }
+            }
+            FrameKind::Fin => {
+                if let Some(state) = self.streams.get_mut(&frame.stream) {
+                    state.inbound = None;
+                }
+                self.remove_if_finished(frame.stream);
+            }
+            FrameKind::Credit => {
+                let bytes = frame
+                    .credited_bytes()
+                    .ok_or_else(|| invalid("Invalid credit"))?;
+                if let Some(state) = self.streams.get_mut(&frame.stream) {
+                    if bytes > state.outbound_pending {
+                        return Err(invalid("Credited more than sent"));
+                    }
+
+                    state.outbound_pending -= bytes;
+                    state.outbound_credit.add_permits(bytes);
+                }
+            }
+            FrameKind::Ping => (),
+        }
+
+        Ok(())
+    }
+
+    fn remove_if_finished(&mut self, id: u32) {
+        if let Some(StreamState {
+            inbound: None,
+            outbound_done: true,
+            ..
+        }) = self.streams.get(&id)
+        {
+            self.streams.remove(&id);
+            if self.streams.is_empty() {
+                self.idle_since = Some(Instant::now());
+            }
+        }
+    }
+
+    fn add_stream(&mut self, id: u32) -> DuplexStream {
+        let (local, remote) = tokio::io::duplex(STREAM_BUFFER_LEN);
+        let (mut reader, mut writer) = tokio::io::split(local);
+
+        let outbound_credit = Arc::new(Semaphore::new(STREAM_WINDOW));
+        let credit = outbound_credit.clone();
+        let frames_tx = self.frames_tx.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; STREAM_BUFFER_LEN];
+            while let Ok(n @ 1..) = reader.read(&mut buf).await {
+                // Waits for the peer to take in the data sent before
+                let permit = tokio::select! {
+                    permit = credit.acquire_many(n as u32) => permit,
+                    _ = frames_tx.closed() => return,
+                };
+                match permit {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => return,
+                }
+
+                let frame = Frame {
+                    stream: id,
+                    kind: FrameKind::Data,
+                    payload: buf[..n].to_vec(),
+                };
+                if frames_tx.send(frame).await.is_err() {
+                    return;
+                }
+            }
+
+            let _ = frames_tx.send(Frame::control(id, FrameKind::Fin)).await;
+        });
+
+        // The received data is not held back so that a slow stream does not
+        // stall the others, the window bounds how much of it is queued
+        let (inbound_tx, mut inbound_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let frames_tx = self.frames_tx.clone();
+        tokio::spawn(async move {
+            while let Some(payload) = inbound_rx.recv().await {
+                if writer.write_all(&payload).await.is_err() {
+                    // The local side dropped the stream. The data queued for
+                    // it is credited back, the connection drops the rest
+                    inbound_rx.close();
+                    let mut dropped = payload.len();
+                    while let Ok(payload) = inbound_rx.try_recv() {
+                        dropped += payload.len();
+                    }
+                    let _ = frames_tx.send(Frame::credit(id, dropped)).await;
+                    return;
+                }
+                if frames_tx
+                    .send(Frame::credit(id, payload.len()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+
+            let _ = writer.shutdown().await;
+        });
+
+        self.streams.insert(
+            id,
+            StreamState {
+                inbound: Some(inbound_tx),
+                inbound_pending: 0,
+                outbound_credit,
+                outbound_pending: 0,
+                outbound_done: false,
+            },
+        );
+        self.used = true;
+        self.idle_since = None;
+
+        remote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc::unbounded;
+
+    use super::*;
+
+    #[test]
+    fn frame_encoding() {
+        let frame = Frame {
+            stream: 258,
+            kind: FrameKind::Data,
+            payload: b"hello".to_vec(),
+        };
+
+        let buf = frame.encode();
+        assert_eq!(&buf[..HEADER_LEN], &[0, 0, 1, 2, 1]);
+        assert_eq!(
+            Frame::decode(buf),
+            Some(Frame {
+                stream: 258,
+                kind: FrameKind::Data,
+                payload: b"hello".to_vec(),
+            })
+        );
+
+        assert_eq!(Frame::decode(vec![0, 0, 0, 1]), None);
+        assert_eq!(Frame::decode(vec![0, 0, 0, 1, 7]), None);
+    }
+
+    #[tokio::test]
+    async fn streams_in_both_directions() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let (a_tx, a_rx) = unbounded();
+        let (b_tx, b_rx) = unbounded();
+
+        let a = Registry::new(CancellationToken::new(), None);
+        let b = Registry::new(CancellationToken::new(), None);
+        let mut a_incoming = a.take_incoming().unwrap();
+        let mut b_incoming = b.take_incoming().unwrap();
+
+        let to_b = a.start(
+            "10.0.0.2:49111".parse().unwrap(),
+            a_tx,
+            b_rx.map(Ok),
+            true,
+            false,
+            &logger,
+        );
+        b.start(
+            "10.0.0.1:49111".parse().unwrap(),
+            b_tx,
+            a_rx.map(Ok),
+            false,
+            false,
+            &logger,
+        );
+
+        let mut stream = to_b.open().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+
+        let (peer, mut served) = b_incoming.recv().await.unwrap();
+        assert_eq!(peer, "10.0.0.1:49111".parse().unwrap());
+
+        let mut buf = [0; 4];
+        served.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The other peer opens its own stream over the same connection
+        let to_a = b.get("10.0.0.1".parse().unwrap()).unwrap();
+        let mut back = to_a.open().await.unwrap();
+        back.write_all(b"pong").await.unwrap();
+        back.shutdown().await.unwrap();
+
+        let (_, mut served_back) = a_incoming.recv().await.unwrap();
+        let mut buf = Vec::new();
+        served_back.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+
+        served.write_all(b"done").await.unwrap();
+        served.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"done");
+    }
+
+    #[tokio::test]
+    async fn keeps_idle_connection() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let (a_tx, a_rx) = unbounded();
+        let (b_tx, b_rx) = unbounded();
+
+        let keep_alive = Some(Duration::from_secs(60));
+        let a = Registry::new(CancellationToken::new(), keep_alive);
+        let b = Registry::new(CancellationToken::new(), keep_alive);
+        let mut b_incoming = b.take_incoming().unwrap();
+
+        let to_b = a.start(
+            "10.0.0.2:49111".parse().unwrap(),
+            a_tx,
+            b_rx.map(Ok),
+            true,
+            false,
+            &logger,
+        );
+        b.start(
+            "10.0.0.1:49111".parse().unwrap(),
+            b_tx,
+            a_rx.map(Ok),
+            false,
+            false,
+            &logger,
+        );
+
+        // Each transfer finishes its stream before the next one starts
+        for msg in [b"first", b"again"] {
+            let mut stream = to_b.open().await.unwrap();
+            stream.write_all(msg).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let (_, mut served) = b_incoming.recv().await.unwrap();
+            let mut buf = Vec::new();
+            served.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(&buf, msg);
+            drop(served);
+
+            stream.read_to_end(&mut Vec::new()).await.unwrap();
+        }
+
+        assert!(a.get("10.0.0.2".parse().unwrap()).is_some());
+    }
+
+    // Connects the dialing `a` to `b`
+    fn connect(a: &Registry, b: &Registry, logger: &Logger) -> Session {
+        let (a_tx, a_rx) = unbounded();
+        let (b_tx, b_rx) = unbounded();
+
+        b.start(
+            "10.0.0.1:49111".parse().unwrap(),
+            b_tx,
+            a_rx.map(Ok),
+            false,
+            false,
+            logger,
+        );
+        a.start(
+            "10.0.0.2:49111".parse().unwrap(),
+            a_tx,
+            b_rx.map(Ok),
+            true,
+            false,
+            logger,
+        )
+    }
+
+    #[tokio::test]
+    async fn slow_stream_is_held_back() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let a = Registry::new(CancellationToken::new(), None);
+        let b = Registry::new(CancellationToken::new(), None);
+        let mut b_incoming = b.take_incoming().unwrap();
+        let to_b = connect(&a, &b, &logger);
+
+        // The peer does not read the stream, the writes stop once the window
+        // and the buffers are used up
+        let mut slow = to_b.open().await.unwrap();
+        let (_, mut slow_served) = b_incoming.recv().await.unwrap();
+        let data = vec![7; 4 * STREAM_WINDOW];
+        let written = tokio::time::timeout(Duration::from_millis(500), slow.write_all(&data)).await;
+        assert!(written.is_err());
+
+        // The other streams go on
+        let mut fast = to_b.open().await.unwrap();
+        fast.write_all(b"fast").await.unwrap();
+        fast.shutdown().await.unwrap();
+
+        let (_, mut served) = b_incoming.recv().await.unwrap();
+        let mut buf = Vec::new();
+        served.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"fast");
+
+        // Reading the slow stream lets the rest through
+        let writer = tokio::spawn(async move {
+            slow.write_all(&data).await.unwrap();
+            slow.shutdown().await.unwrap();
+            slow
+        });
+        let mut buf = Vec::new();
+        slow_served.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.len() >= 4 * STREAM_WINDOW);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_while_writes_are_blocked() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let a = Registry::new(CancellationToken::new(), None);
+        let mut a_incoming = a.take_incoming().unwrap();
+
+        // The peer does not read anything, so the first frame written blocks
+        // the connection
+        let (a_tx, _a_rx) = futures::channel::mpsc::channel::<Vec<u8>>(0);
+        let (peer_tx, peer_rx) = unbounded::<Vec<u8>>();
+        a.start(
+            "10.0.0.2:49111".parse().unwrap(),
+            a_tx,
+            peer_rx.map(Ok),
+            true,
+            false,
+            &logger,
+        );
+
+        // Receiving the data makes the connection write the credits, the
+        // frames of the streams are still read meanwhile
+        for id in [2, 4] {
+            peer_tx
+                .unbounded_send(Frame::control(id, FrameKind::Open).encode())
+                .unwrap();
+            let frame = Frame {
+                stream: id,
+                kind: FrameKind::Data,
+                payload: b"data".to_vec(),
+            };
+            peer_tx.unbounded_send(frame.encode()).unwrap();
+
+            let (_, mut served) = tokio::time::timeout(Duration::from_secs(5), a_incoming.recv())
+                .await
+                .expect("Connection stopped reading")
+                .unwrap();
+            let mut buf = [0; 4];
+            served.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"data");
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_is_credited_back() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let a = Registry::new(CancellationToken::new(), None);
+        let b = Registry::new(CancellationToken::new(), None);
+        let mut b_incoming = b.take_incoming().unwrap();
+        let to_b = connect(&a, &b, &logger);
+
+        // The peer stops serving the stream, the data written to it past the
+        // window does not hold back the writer
+        let mut stream = to_b.open().await.unwrap();
+        let (_, served) = b_incoming.recv().await.unwrap();
+        drop(served);
+
+        let data = vec![7; 4 * STREAM_WINDOW];
+        tokio::time::timeout(Duration::from_secs(5), stream.write_all(&data))
+            .await
+            .expect("Dropped stream was not credited back")
+            .unwrap();
+        stream.shutdown().await.unwrap();
+
+        // The connection goes on
+        let mut other = to_b.open().await.unwrap();
+        other.write_all(b"other").await.unwrap();
+        other.shutdown().await.unwrap();
+
+        let (_, mut served) = b_incoming.recv().await.unwrap();
+        let mut buf = Vec::new();
+        served.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"other");
+    }
+
+    #[tokio::test]
+    async fn replaced_connection_is_closed() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let a = Registry::new(CancellationToken::new(), Some(Duration::from_secs(60)));
+        let peer: SocketAddr = "10.0.0.2:49111".parse().unwrap();
+
+        let (old_tx, mut old_rx) = unbounded::<Vec<u8>>();
+        let (_old_peer_tx, old_peer_rx) = unbounded::<Vec<u8>>();
+        a.start(peer, old_tx, old_peer_rx.map(Ok), true, false, &logger);
+
+        let (new_tx, _new_rx) = unbounded::<Vec<u8>>();
+        let (_new_peer_tx, new_peer_rx) = unbounded::<Vec<u8>>();
+        a.start(peer, new_tx, new_peer_rx.map(Ok), true, false, &logger);
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), old_rx.next()).await;
+        assert_eq!(closed, Ok(None));
+        assert!(a.get(peer.ip()).is_some());
+    }
+
+    #[tokio::test]
+    async fn pings_silent_connection() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut a = Registry::new(CancellationToken::new(), Some(Duration::from_secs(60)));
+        a.ping_interval = Duration::from_millis(50);
+
+        let (a_tx, mut a_rx) = unbounded::<Vec<u8>>();
+        let (_peer_tx, peer_rx) = unbounded::<Vec<u8>>();
+        a.start(
+            "10.0.0.2:49111".parse().unwrap(),
+            a_tx,
+            peer_rx.map(Ok),
+            true,
+            false,
+            &logger,
+        );
+
+        for _ in 0..2 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), a_rx.next())
+                .await
+                .unwrap()
+                .and_then(Frame::decode);
+            assert_eq!(frame, Some(Frame::control(0, FrameKind::Ping)));
+        }
+    }
+
+    #[tokio::test]
+    async fn open_with_wrong_parity_fails() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let a = Registry::new(CancellationToken::new(), Some(Duration::from_secs(60)));
+
+        let (a_tx, mut a_rx) = unbounded::<Vec<u8>>();
+        let (peer_tx, peer_rx) = unbounded::<Vec<u8>>();
+        a.start(
+            "10.0.0.2:49111".parse().unwrap(),
+            a_tx,
+            peer_rx.map(Ok),
+            true,
+            false,
+            &logger,
+        );
+
+        // The dialer picks the odd IDs itself
+        peer_tx
+            .unbounded_send(Frame::control(1, FrameKind::Open).encode())
+            .unwrap();
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), a_rx.next()).await;
+        assert_eq!(closed, Ok(None));
+    }
+}
@@ -1,13 +1,14 @@
 use std::net::SocketAddr;
 
-use hyper::http::HeaderValue;
+use hyper::{http::HeaderValue, HeaderMap};
 use slog::warn;
 
 use crate::auth;
 
 #[derive(Debug)]
 pub struct WWWAuthenticate {
-    header_value: Option<String>,
+    // One challenge per scheme supported by the client
+    header_values: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -16,8 +17,10 @@ pub struct Authorization {
 }
 
 impl WWWAuthenticate {
-    pub fn new(header_value: Option<String>) -> Self {
-        Self { header_value }
+    pub fn new(headers: HeaderMap) -> Self {
+        Self {
+            header_values: crate::auth::www_authenticate_values(&headers),
+        }
     }
 
     pub fn authorize(
@@ -26,8 +29,8 @@ impl WWWAuthenticate {
         peer: SocketAddr,
         logger: &slog::Logger,
     ) -> Authorization {
-        let Self { header_value } = self;
-        let header = create_authorization_header(auth, header_value, peer, logger);
+        let Self { header_values } = self;
+        let header = create_authorization_header(auth, &header_values, peer, logger);
         Authorization { header }
     }
 }
@@ -44,13 +47,15 @@ impl Authorization {
 
 fn create_authorization_header(
     auth: &auth::Context,
-    www_auth_header: Option<String>,
+    www_auth_headers: &[String],
     peer: SocketAddr,
     logger: &slog::Logger,
 ) -> Option<(&'static str, HeaderValue)> {
-    let www_auth = www_auth_header?;
+    if www_auth_headers.is_empty() {
+        return None;
+    }
 
-    match auth.create_servers_auth_header(peer.ip(), &www_auth) {
+    match auth.create_servers_auth_header(peer.ip(), www_auth_headers) {
         Ok(header) => Some(header),
         Err(err) => {
             warn!(logger, "Failed to create authentication ticket: {err:?}");
@@ -1,10 +1,12 @@
-use std::{fs, future::Future, path::PathBuf, sync::Arc, time::Duration};
+use std::{fs, future::Future, ops::Range, path::PathBuf, sync::Arc, time::Duration};
 
 use tokio::{sync::mpsc::Sender, task::JoinSet};
+use uuid::Uuid;
 use warp::ws::Message;
 
 use super::{socket::WebSocket, TmpFileState};
 use crate::{
+    file::HashAlg,
     transfer::IncomingTransfer,
     utils::Hidden,
     ws::{self},
@@ -53,7 +55,19 @@ pub trait HandlerLoop {
         file: FileId,
         msg: String,
     ) -> anyhow::Result<()>;
-    async fn issue_done(&mut self, ws: &mut WebSocket, file: FileId) -> anyhow::Result<()>;
+    async fn issue_done(
+        &mut self,
+        ws: &mut WebSocket,
+        file: FileId,
+        final_path: Option<PathBuf>,
+    ) -> anyhow::Result<()>;
+    async fn issue_pause(&mut self, ws: &mut WebSocket, paused: bool) -> anyhow::Result<()>;
+    async fn issue_pace(
+        &mut self,
+        ws: &mut WebSocket,
+        file: FileId,
+        rate: Option<u64>,
+    ) -> anyhow::Result<()>;
 
     async fn on_close(&mut self);
     async fn on_text_msg(&mut self, ws: &mut WebSocket, text: &str) -> anyhow::Result<()>;
@@ -64,7 +78,11 @@ pub trait HandlerLoop {
 }
 
 pub trait Request {
-    fn parse(self) -> anyhow::Result<IncomingTransfer>;
+    fn id(&self) -> Uuid;
+    // The limits of the `known` transfers were checked when these were first
+    // received, the files added since are checked when taken into the
+    // transfer
+    fn parse(self, known: bool) -> anyhow::Result<IncomingTransfer>;
 }
 
 pub enum DownloadInit {
@@ -72,16 +90,30 @@ pub enum DownloadInit {
 }
 #[async_trait::async_trait]
 pub trait Downloader {
+    // The hash function of the partially downloaded file checks
+    fn resume_hash(&self) -> HashAlg;
     async fn init(
         &mut self,
         task: &super::FileXferTask,
         tmp_file: Option<TmpFileState>,
     ) -> crate::Result<DownloadInit>;
     async fn open(&mut self, tmp_location: &Hidden<PathBuf>) -> crate::Result<fs::File>;
+    // Turns the received chunk data into the file content and its offset. The
+    // chunks without the offset in them are at the given stream offset
+    fn open_chunk(&self, stream_pos: u64, data: Vec<u8>) -> crate::Result<(u64, Vec<u8>)>;
+    // The chunks come out of order, from the ranges of the file uploaded at
+    // the same time
+    fn parallel(&self) -> bool;
+    // The receiver asked the sender to hold the file back, no chunks come
+    fn held(&self) -> bool;
+    // Resolves once the file is held back or let go, with the new state
+    async fn held_changed(&mut self) -> bool;
+    // Asks the peer to send the given range of the file again
+    async fn retransmit(&mut self, range: Range<u64>) -> crate::Result<()>;
     async fn progress(&mut self, bytes: u64) -> crate::Result<()>;
     async fn validate<F, Fut>(
         &mut self,
-        location: &Hidden<PathBuf>,
+        file: fs::File,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> crate::Result<()>
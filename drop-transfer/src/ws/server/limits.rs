@@ -0,0 +1,93 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use drop_config::DropConfig;
+
+/// Limits the simultaneous incoming connections, each of which carries one
+/// transfer. The peers over the limits are told to retry later
+pub struct ConnectionLimits {
+    max_peers: Option<usize>,
+    max_per_peer: Option<usize>,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// The connection counted into the limits until dropped
+pub struct ConnectionSlot {
+    peer: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &DropConfig) -> Self {
+        Self {
+            max_peers: config.max_peers,
+            max_per_peer: config.max_transfers_per_peer,
+            active: Arc::default(),
+        }
+    }
+
+    /// `None` when the connection would exceed the limits
+    pub fn acquire(&self, peer: IpAddr) -> Option<ConnectionSlot> {
+        let mut active = self.active.lock().expect("Poisoned lock");
+
+        match active.get(&peer) {
+            Some(&count) if self.max_per_peer.is_some_and(|max| count >= max) => return None,
+            None if self.max_peers.is_some_and(|max| active.len() >= max) => return None,
+            _ => (),
+        }
+
+        *active.entry(peer).or_default() += 1;
+
+        Some(ConnectionSlot {
+            peer,
+            active: self.active.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().expect("Poisoned lock");
+
+        if let Some(count) = active.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_limits() {
+        let limits = ConnectionLimits::new(&DropConfig {
+            max_peers: Some(2),
+            max_transfers_per_peer: Some(2),
+            ..Default::default()
+        });
+
+        let a: IpAddr = "1.1.1.1".parse().unwrap();
+        let b: IpAddr = "2.2.2.2".parse().unwrap();
+        let c: IpAddr = "3.3.3.3".parse().unwrap();
+
+        let a1 = limits.acquire(a).unwrap();
+        let _a2 = limits.acquire(a).unwrap();
+        assert!(limits.acquire(a).is_none());
+
+        let b1 = limits.acquire(b).unwrap();
+        assert!(limits.acquire(c).is_none());
+
+        drop(a1);
+        assert!(limits.acquire(a).is_some());
+
+        drop(b1);
+        assert!(limits.acquire(c).is_some());
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    convert::Infallible,
+    future::{self, Future},
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    time::Duration,
+};
+
+use hyper::service::Service;
+use slog::{debug, info, warn, Logger};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::TcpListener,
+    sync::{mpsc, watch},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+use crate::{tasks::AliveGuard, tls::TlsPeer};
+
+// How long the in-flight requests of the server listening on the vanished
+// address are given to finish before it is bound again
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(super) type Serving = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Binds the server on the address. The returned future serves until the
+/// token is cancelled
+pub(super) type Bind = Box<dyn Fn(SocketAddr, CancellationToken) -> io::Result<Serving> + Send>;
+
+enum Wake {
+    Stopped,
+    Refresh,
+    RefreshGone,
+}
+
+/// Keeps serving on the address until the `stop` is cancelled. On each network
+/// refresh checks whether the address is still assigned to the host. Once the
+/// vanished address reappears the server is bound again, as the old socket
+/// might not receive the connections anymore
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn supervise(
+    addr: SocketAddr,
+    serving: Serving,
+    mut shutdown: CancellationToken,
+    bind: Bind,
+    mut refresh: watch::Receiver<()>,
+    stop: CancellationToken,
+    logger: Logger,
+    alive: AliveGuard,
+) {
+    let _guard = alive;
+    let mut serving = Some(serving);
+    let mut vanished = false;
+
+    loop {
+        let wake = {
+            let server = async {
+                match &mut serving {
+                    Some(serving) => serving.await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = server => Wake::Stopped,
+                res = refresh.changed() => if res.is_ok() { Wake::Refresh } else { Wake::RefreshGone },
+            }
+        };
+
+        match wake {
+            Wake::Stopped => {
+                serving = None;
+
+                if stop.is_cancelled() {
+                    break;
+                }
+
+                warn!(
+                    logger,
+                    "Server on {addr} stopped, binding it again on the next network refresh"
+                );
+                vanished = true;
+            }
+            Wake::RefreshGone => {
+                if let Some(serving) = serving {
+                    serving.await;
+                }
+                break;
+            }
+            Wake::Refresh => {
+                if !is_assigned(addr.ip()) {
+                    if !vanished {
+                        info!(logger, "Listen address {} vanished", addr.ip());
+                    }
+                    vanished = true;
+                    continue;
+                }
+
+                if !vanished {
+                    continue;
+                }
+
+                shutdown.cancel();
+                if let Some(serving) = serving.take() {
+                    // The graceful shutdown releases the socket right away, the connections
+                    // over the vanished address are dropped if they do not finish in time
+                    let _ = tokio::time::timeout(DRAIN_TIMEOUT, serving).await;
+                }
+
+                shutdown = stop.child_token();
+                match bind(addr, shutdown.clone()) {
+                    Ok(server) => {
+                        info!(logger, "Server is bound again to {addr}");
+                        serving = Some(server);
+                        vanished = false;
+                    }
+                    Err(err) => {
+                        warn!(logger, "Failed to bind the server to {addr} again: {err}");
+                    }
+                }
+            }
+        }
+    }
+
+    debug!(logger, "Server on {addr} stopped");
+}
+
+// The socket can be bound only to the addresses assigned to the host
+fn is_assigned(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+pub(super) fn bind_tls(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+pub(super) async fn serve_tls<S>(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    service: S,
+    logger: Logger,
+    stop: CancellationToken,
+) where
+    S: Service<
+            hyper::Request<hyper::Body>,
+            Response = hyper::Response<hyper::Body>,
+            Error = Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = stop.cancelled() => break,
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(logger, "Failed to accept connection: {err}");
+                    continue;
+                }
+            },
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let logger = logger.clone();
+        let stop = stop.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!(logger, "TLS handshake with {peer} failed: {err}");
+                    return;
+                }
+            };
+
+            serve_connection(stream, peer, service, logger, stop).await;
+        });
+    }
+}
+
+/// Serves the streams the peers open over the connections shared with them
+/// like the incoming connections
+pub(super) async fn serve_shared<S>(
+    mut incoming: mpsc::UnboundedReceiver<(SocketAddr, DuplexStream)>,
+    service: S,
+    logger: Logger,
+    stop: CancellationToken,
+) where
+    S: Service<
+            hyper::Request<hyper::Body>,
+            Response = hyper::Response<hyper::Body>,
+            Error = Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (peer, stream) = tokio::select! {
+            _ = stop.cancelled() => break,
+            conn = incoming.recv() => match conn {
+                Some(conn) => conn,
+                None => break,
+            },
+        };
+
+        tokio::spawn(serve_connection(
+            stream,
+            peer,
+            service.clone(),
+            logger.clone(),
+            stop.clone(),
+        ));
+    }
+}
+
+async fn serve_connection<I, S>(
+    stream: I,
+    peer: SocketAddr,
+    mut service: S,
+    logger: Logger,
+    stop: CancellationToken,
+) where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    S: Service<
+            hyper::Request<hyper::Body>,
+            Response = hyper::Response<hyper::Body>,
+            Error = Infallible,
+        > + Send
+        + 'static,
+    S::Future: Send,
+{
+    let service = hyper::service::service_fn(move |mut req| {
+        req.extensions_mut().insert(TlsPeer(peer));
+        service.call(req)
+    });
+
+    let conn = hyper::server::conn::Http::new()
+        .serve_connection(stream, service)
+        .with_upgrades();
+
+    tokio::select! {
+        _ = stop.cancelled() => (),
+        res = conn => if let Err(err) = res {
+            debug!(logger, "Connection with {peer} failed: {err}");
+        },
+    }
+}
@@ -1,24 +1,31 @@
 mod auth;
 mod handler;
+mod limits;
+mod listener;
+mod retransmit;
 mod socket;
 mod v6;
 
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
     fs,
     future::Future,
-    io::{self, Write},
-    net::SocketAddr,
+    io::{self, Seek, Write},
+    net::{IpAddr, SocketAddr},
     ops::ControlFlow,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use drop_auth::Nonce;
+use drop_config::DropConfig;
+use futures::{SinkExt, StreamExt};
 use handler::{Downloader, HandlerInit, HandlerLoop};
-use hyper::StatusCode;
+use hyper::{service::Service, HeaderMap, StatusCode};
 use slog::{debug, error, info, warn, Logger};
 use tokio::{
     sync::{
@@ -27,22 +34,40 @@ use tokio::{
     },
     task::{AbortHandle, JoinSet},
 };
+use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
-use warp::{ws::Message, Filter};
+use tracing::Instrument;
+use warp::{ws::Message, Filter, Reply};
 
-use self::socket::{WebSocket, WsStream};
+use self::{
+    limits::ConnectionLimits,
+    retransmit::{RangeFronts, RetransmitWindow},
+    socket::{WebSocket, WsStream},
+};
 use super::{events::FileEventTx, IncomingFileEventTx};
 use crate::{
-    check,
-    file::{self, FileSubPath, FileToRecv},
+    alias, check,
+    event::RefusedOperation,
+    file::{self, DestinationFd, FileSubPath, FileToRecv},
+    forward,
     manager::{FinishTransferState, IncomingRegistered},
-    protocol,
+    protocol::{
+        self,
+        caps::{Capabilities, Capability},
+        device::PeerDevice,
+    },
     quarantine::PathExt,
-    service::State,
+    service::{IncomingDecision, State},
+    spans,
     tasks::AliveGuard,
+    text,
+    timing::{self, Stage},
+    tls::{self, TlsPeer},
     transfer::{IncomingTransfer, Transfer},
-    utils::Hidden,
+    utils::{self, Hidden},
+    validate,
     ws::{
+        mux,
         server::handler::{MsgToSend, Request},
         Pinger,
     },
@@ -53,13 +78,40 @@ const MAX_FILENAME_LENGTH: usize = 255;
 const MAX_FILE_SUFFIX_LEN: usize = 5; // Assume that the suffix will fit into 5 characters e.g.
                                       // `<filename>(999).<ext>`
 const REPORT_PROGRESS_THRESHOLD: u64 = 1024 * 64;
+// The file fails when more of its chunks are corrupted
+const MAX_CHUNK_RETRANSMISSIONS: u32 = 16;
+// The file fails when more parts of it are waiting for the retransmission
+const RETRANSMIT_WINDOW: usize = 8;
+// The missing parts are requested again when nothing arrives for this long
+// after the end of the stream
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub enum ServerReq {
-    Download { task: Box<FileXferTask> },
-    Start { file: FileId, offset: u64 },
-    Reject { file: FileId },
-    Done { file: FileId },
-    Fail { file: FileId, msg: String },
+    Download {
+        task: Box<FileXferTask>,
+    },
+    Start {
+        file: FileId,
+        offset: u64,
+    },
+    Reject {
+        file: FileId,
+    },
+    Done {
+        file: FileId,
+        // `None` if not known at this point
+        final_path: Option<PathBuf>,
+    },
+    Fail {
+        file: FileId,
+        msg: String,
+    },
+    Pause,
+    Resume,
+    Pace {
+        file: FileId,
+        rate: Option<u64>,
+    },
     Close,
 }
 
@@ -67,6 +119,8 @@ pub struct FileXferTask {
     pub file: FileToRecv,
     pub xfer: Arc<IncomingTransfer>,
     pub base_dir: Hidden<PathBuf>,
+    // Written in place instead of the temporary file in the base directory
+    pub dest: Option<Arc<DestinationFd>>,
 }
 
 pub struct FileStreamCtx<'a> {
@@ -110,16 +164,33 @@ impl warp::reject::Reject for ToManyReqs {}
 struct BadRequest;
 impl warp::reject::Reject for BadRequest {}
 
+#[derive(Debug)]
+struct Busy;
+impl warp::reject::Reject for Busy {}
+
+#[derive(Debug)]
+struct Refused;
+impl warp::reject::Reject for Refused {}
+
+/// Binds the server to all of the listen addresses, returns the bound sockets
+/// with the port picked by the system if the configured one is `0`
 pub(crate) fn spawn(
     refresh_trigger: tokio::sync::watch::Receiver<()>,
     state: Arc<State>,
     logger: Logger,
     stop: CancellationToken,
     alive: AliveGuard,
-) -> crate::Result<()> {
-    let addr = SocketAddr::new(state.addr, drop_config::PORT);
-
+) -> crate::Result<Vec<SocketAddr>> {
+    let addrs = state.addrs.clone();
+    let port = state.config.port;
+    let acceptor = if state.config.tls {
+        Some(tls::acceptor(state.auth.clone())?)
+    } else {
+        None
+    };
     let nonce_store = Arc::new(Mutex::new(HashMap::new()));
+    let network_refresh = refresh_trigger.clone();
+    let shared_incoming = state.mux.take_incoming();
 
     let service = {
         let rate_limiter = Arc::new(governor::RateLimiter::dashmap(governor::Quota::per_second(
@@ -128,8 +199,13 @@ pub(crate) fn spawn(
                 .map_err(|_| crate::Error::InvalidArgument)?,
         )));
 
+        // Connections served over TLS carry the peer address in the request extension
         let remote = warp::filters::addr::remote()
-            .map(move |peer: Option<SocketAddr>| peer.expect("Transport should use IP addresses"));
+            .and(warp::ext::optional::<TlsPeer>())
+            .map(move |peer: Option<SocketAddr>, tls: Option<TlsPeer>| {
+                let peer = tls.map(|TlsPeer(peer)| peer).or(peer);
+                utils::canonical_socket_addr(peer.expect("Transport should use IP addresses"))
+            });
 
         let ddos = remote
             .and_then(move |peer: SocketAddr| {
@@ -155,10 +231,7 @@ pub(crate) fn spawn(
             .and(warp::filters::header::optional(
                 drop_auth::http::Authorization::KEY,
             ))
-            .and(
-                warp::filters::header::optional(drop_auth::http::WWWAuthenticate::KEY)
-                    .map(auth::WWWAuthenticate::new),
-            );
+            .and(warp::filters::header::headers_cloned().map(auth::WWWAuthenticate::new));
 
         let ws_route = {
             let logger = logger.clone();
@@ -166,55 +239,291 @@ pub(crate) fn spawn(
             let alive = alive.clone();
             let stop = stop.clone();
             let state = state.clone();
+            let limits = Arc::new(ConnectionLimits::new(&state.config));
+
+            base.and(warp::ws())
+                .and(warp::filters::header::headers_cloned())
+                .and(warp::filters::header::optional::<String>(
+                    protocol::v6::CLOCK_HEADER,
+                ))
+                .and_then(
+                    move |peer: SocketAddr,
+                          version: protocol::Version,
+                          auth_header: Option<String>,
+                          www_auth: auth::WWWAuthenticate,
+                          ws: warp::ws::Ws,
+                          headers: HeaderMap,
+                          peer_clock: Option<String>| {
+                        let state = Arc::clone(&state);
+                        let alive = alive.clone();
+                        let stop = stop.clone();
+                        let logger = logger.clone();
+                        let nonces = nonces.clone();
+                        let refresh_trigger = refresh_trigger.clone();
+                        let limits = limits.clone();
+
+                        async move {
+                            let client_authorization = auth_header.clone().unwrap_or_default();
+                            let authorization = process_authentication(
+                                &state.auth,
+                                &nonces,
+                                peer,
+                                auth_header,
+                                www_auth,
+                                &logger,
+                            )
+                            .await?;
+
+                            let caps_authenticated = match Capabilities::verify_mac(
+                                &headers,
+                                &state.auth,
+                                peer.ip(),
+                                true,
+                                &client_authorization,
+                            ) {
+                                Some(true) => true,
+                                Some(false) => {
+                                    warn!(logger, "Invalid MAC of the capabilities of {peer}");
+                                    return Err(warp::reject::custom(Unauthorized));
+                                }
+                                None => false,
+                            };
+                            if !state
+                                .check_capabilities_pin(peer.ip(), caps_authenticated)
+                                .await
+                            {
+                                warn!(
+                                    logger,
+                                    "The capabilities of {peer} were not authenticated although \
+                                     they were before"
+                                );
+                                return Err(warp::reject::custom(Unauthorized));
+                            }
+
+                            let Some(slot) = limits.acquire(peer.ip()) else {
+                                info!(
+                                    logger,
+                                    "Too many connections, telling {peer} to retry later"
+                                );
+                                return Err(warp::reject::custom(Busy));
+                            };
+
+                            let caps = state
+                                .capabilities()
+                                .negotiate(Capabilities::from_headers(&headers));
+
+                            let now = protocol::v6::clock_now();
+                            let clock_skew = peer_clock
+                                .and_then(|time| protocol::v6::parse_clock(time.as_bytes()))
+                                .map(|time| time - now);
+
+                            let device = PeerDevice::from_headers(&headers);
+                            if let Some(device) = &device {
+                                state
+                                    .storage
+                                    .save_peer_device(&peer.ip().to_string(), &device.into())
+                                    .await;
+                            }
+                            let listen_port = headers
+                                .get(protocol::v6::LISTEN_PORT_HEADER)
+                                .and_then(|port| protocol::v6::parse_listen_port(port.as_bytes()));
+                            if let Some(port) = listen_port {
+                                state
+                                    .storage
+                                    .save_peer_listen_port(&peer.ip().to_string(), port)
+                                    .await;
+                            }
+                            let local_device = state.local_device();
+                            let auth = state.auth.clone();
+
+                            let reply = ws.on_upgrade(move |socket| async move {
+                                let _slot = slot;
+                                info!(
+                                    logger,
+                                    "Client requested protocol version: {version}, capabilities: \
+                                     [{caps}]"
+                                );
+                                websocket_start(
+                                    socket,
+                                    state,
+                                    alive,
+                                    stop,
+                                    version,
+                                    peer,
+                                    caps,
+                                    clock_skew,
+                                    device,
+                                    listen_port,
+                                    logger,
+                                    refresh_trigger,
+                                )
+                                .await;
+                            });
+
+                            let mut reply = authorization.insert(reply).into_response();
+                            caps.insert_headers(reply.headers_mut());
+                            caps.insert_mac(
+                                reply.headers_mut(),
+                                &auth,
+                                peer.ip(),
+                                false,
+                                &client_authorization,
+                            );
+                            local_device.insert_headers(reply.headers_mut());
+                            let reply =
+                                warp::reply::with_header(reply, protocol::v6::CLOCK_HEADER, now);
+
+                            Ok::<_, warp::Rejection>(reply)
+                        }
+                    },
+                )
+        };
 
-            base.and(warp::ws()).and_then(
-                move |peer: SocketAddr,
-                      version: protocol::Version,
-                      auth_header: Option<String>,
-                      www_auth: auth::WWWAuthenticate,
-                      ws: warp::ws::Ws| {
-                    let state = Arc::clone(&state);
-                    let alive = alive.clone();
-                    let stop = stop.clone();
-                    let logger = logger.clone();
-                    let nonces = nonces.clone();
-                    let refresh_trigger = refresh_trigger.clone();
-
-                    async move {
-                        let authorization = process_authentication(
-                            &state.auth,
-                            &nonces,
-                            peer,
-                            auth_header,
-                            www_auth,
-                            &logger,
-                        )
-                        .await?;
+        let mux_route = {
+            let nonces = nonce_store.clone();
+            let logger = logger.clone();
+            let state = state.clone();
 
-                        let reply = ws.on_upgrade(move |socket| async move {
-                            info!(logger, "Client requested protocol version: {}", version);
-                            websocket_start(
-                                socket,
-                                state,
-                                alive,
-                                stop,
-                                version,
+            remote
+                .and(warp::path("drop"))
+                .and(warp::path(mux::RESOURCE))
+                .and(warp::path::end())
+                .and(warp::filters::header::optional(
+                    drop_auth::http::Authorization::KEY,
+                ))
+                .and(warp::filters::header::headers_cloned().map(auth::WWWAuthenticate::new))
+                .and(warp::ws())
+                .and_then(
+                    move |peer: SocketAddr,
+                          auth_header: Option<String>,
+                          www_auth: auth::WWWAuthenticate,
+                          ws: warp::ws::Ws| {
+                        let state = Arc::clone(&state);
+                        let nonces = nonces.clone();
+                        let logger = logger.clone();
+
+                        async move {
+                            if !state.config.shared_connections {
+                                return Err(warp::reject::not_found());
+                            }
+
+                            let authorization = process_authentication(
+                                &state.auth,
+                                &nonces,
                                 peer,
-                                logger,
-                                refresh_trigger,
+                                auth_header,
+                                www_auth,
+                                &logger,
                             )
-                            .await;
-                        });
+                            .await?;
+
+                            let reply = ws.on_upgrade(move |socket| async move {
+                                info!(logger, "Sharing the connection with {peer}");
+
+                                let (sink, stream) = socket.split();
+                                state.mux.start(
+                                    peer,
+                                    sink.with(|buf: Vec<u8>| {
+                                        futures::future::ready(Ok::<_, warp::Error>(
+                                            Message::binary(buf),
+                                        ))
+                                    }),
+                                    stream.filter_map(|msg| {
+                                        futures::future::ready(match msg {
+                                            Ok(msg) if msg.is_binary() => {
+                                                Some(Ok(msg.into_bytes()))
+                                            }
+                                            Ok(_) => None,
+                                            Err(err) => Some(Err(err)),
+                                        })
+                                    }),
+                                    false,
+                                    false,
+                                    &logger,
+                                );
+                            });
+
+                            Ok::<_, warp::Rejection>(authorization.insert(reply))
+                        }
+                    },
+                )
+        };
 
-                        Ok::<_, warp::Rejection>(authorization.insert(reply))
-                    }
-                },
-            )
+        let text_route = {
+            let nonces = nonce_store.clone();
+            let logger = logger.clone();
+            let state = state.clone();
+
+            base.and(warp::path!("text" / String))
+                .and(warp::post())
+                .and(warp::body::content_length_limit(text::MAX_TEXT_LEN as _))
+                .and(warp::body::bytes())
+                .and_then(
+                    move |peer: SocketAddr,
+                          _version,
+                          auth_header,
+                          www_auth,
+                          id: String,
+                          body: hyper::body::Bytes| {
+                        let state = Arc::clone(&state);
+                        let nonces = nonces.clone();
+                        let logger = logger.clone();
+
+                        async move {
+                            let authorization = process_authentication(
+                                &state.auth,
+                                &nonces,
+                                peer,
+                                auth_header,
+                                www_auth,
+                                &logger,
+                            )
+                            .await?;
+
+                            let id = id.parse().map_err(|_| warp::reject::custom(BadRequest))?;
+                            let text = String::from_utf8(body.to_vec())
+                                .map_err(|_| warp::reject::custom(BadRequest))?;
+
+                            if !state.config.transfer_mode.can_receive() {
+                                info!(logger, "Send-only mode, refusing text message {id}");
+                                return Err(warp::reject::custom(Refused));
+                            }
+
+                            let request = IncomingTransfer::text_request(peer.ip(), id);
+                            match state.incoming_decision(&request) {
+                                Some(IncomingDecision::Accept) | None => (),
+                                Some(IncomingDecision::Reject) => {
+                                    info!(logger, "Incoming text message {id} rejected");
+                                    return Err(warp::reject::custom(Refused));
+                                }
+                                // The sender does not retry, it is only told
+                                // to try again later
+                                Some(IncomingDecision::Drop) => {
+                                    info!(logger, "Incoming text message {id} dropped");
+                                    return Err(warp::reject::custom(Busy));
+                                }
+                            }
+
+                            info!(logger, "Received text message {id} from {}", peer.ip());
+                            state.emit_event(
+                                &logger,
+                                crate::Event::IncomingText {
+                                    id,
+                                    peer: peer.ip(),
+                                    text,
+                                },
+                            );
+
+                            Ok::<_, warp::Rejection>(authorization.insert(StatusCode::OK))
+                        }
+                    },
+                )
         };
 
         let check_route = {
             let nonces = nonce_store.clone();
             let logger = logger.clone();
+            let state = state.clone();
 
             base.and(warp::path!("check" / String))
                 .and(warp::get())
@@ -246,17 +555,60 @@ pub(crate) fn spawn(
                 })
         };
 
-        ddos.and(ws_route.or(check_route)).recover(move |err| {
-            let nonces = Arc::clone(&nonce_store);
-            async move { handle_rejection(&nonces, err).await }
-        })
+        let auth = state.auth.clone();
+        ddos.and(ws_route.or(mux_route).or(check_route).or(text_route))
+            .recover(move |err| {
+                let nonces = Arc::clone(&nonce_store);
+                let auth = auth.clone();
+                async move { handle_rejection(&auth, &nonces, err).await }
+            })
     };
 
-    let future =
-        match warp::serve(service).try_bind_with_graceful_shutdown(addr, stop.cancelled_owned()) {
+    if let Some(incoming) = shared_incoming {
+        let alive = alive.task("server", "Shared connections");
+        let serve = listener::serve_shared(
+            incoming,
+            warp::service(service.clone()),
+            logger.clone(),
+            stop.clone(),
+        );
+
+        tokio::spawn(async move {
+            let _alive = alive;
+            serve.await
+        });
+    }
+
+    if let Some(acceptor) = acceptor {
+        return spawn_tls(
+            acceptor,
+            addrs,
+            port,
+            warp::service(service),
+            network_refresh,
+            logger,
+            stop,
+            alive,
+        );
+    }
+
+    let bind = move |addr, shutdown: CancellationToken| {
+        warp::serve(service.clone())
+            .try_bind_with_graceful_shutdown(addr, shutdown.cancelled_owned())
+            .map(|(socket, future)| (socket, Box::pin(future) as listener::Serving))
+    };
+
+    // Bind all of the sockets first so that nothing is served in case any of the
+    // addresses is unavailable
+    let mut futures = Vec::with_capacity(addrs.len());
+    for ip in addrs {
+        let addr = SocketAddr::new(ip, port);
+        let shutdown = stop.child_token();
+
+        match bind(addr, shutdown.clone()) {
             Ok((socket, future)) => {
                 debug!(logger, "WS server is bound to: {socket}");
-                future
+                futures.push((socket, future, shutdown));
             }
             Err(err) => {
                 // Check if this is IO error about address already in use
@@ -277,15 +629,121 @@ pub(crate) fn spawn(
 
                 return Err(err.into());
             }
+        }
+    }
+
+    let bound = futures.iter().map(|(socket, _, _)| *socket).collect();
+
+    let bind = Arc::new(bind);
+    for (socket, future, shutdown) in futures {
+        let alive = alive.task("server", format!("WS server on {socket}"));
+        let bind = bind.clone();
+        let rebind: listener::Bind = Box::new(move |addr, shutdown| {
+            bind(addr, shutdown)
+                .map(|(_, future)| future)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        });
+
+        tokio::spawn(listener::supervise(
+            socket,
+            future,
+            shutdown,
+            rebind,
+            network_refresh.clone(),
+            stop.clone(),
+            logger.clone(),
+            alive,
+        ));
+    }
+
+    Ok(bound)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_tls<S>(
+    acceptor: TlsAcceptor,
+    addrs: Vec<IpAddr>,
+    port: u16,
+    service: S,
+    network_refresh: tokio::sync::watch::Receiver<()>,
+    logger: Logger,
+    stop: CancellationToken,
+    alive: AliveGuard,
+) -> crate::Result<Vec<SocketAddr>>
+where
+    S: Service<
+            hyper::Request<hyper::Body>,
+            Response = hyper::Response<hyper::Body>,
+            Error = Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    // Bind all of the sockets first so that nothing is served in case any of the
+    // addresses is unavailable
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for ip in addrs {
+        let addr = SocketAddr::new(ip, port);
+
+        let listener = listener::bind_tls(addr).map_err(|err| {
+            if err.kind() == io::ErrorKind::AddrInUse {
+                error!(
+                    logger,
+                    "Found that the address {addr} is already used, while trying to bind the WS \
+                     server: {err}",
+                );
+                Error::AddrInUse
+            } else {
+                Error::Io(err)
+            }
+        })?;
+        let socket = listener.local_addr()?;
+
+        debug!(logger, "WSS server is bound to: {socket}");
+        listeners.push((socket, listener));
+    }
+
+    let bound = listeners.iter().map(|(socket, _)| *socket).collect();
+
+    for (socket, tcp) in listeners {
+        let alive = alive.task("server", format!("WSS server on {socket}"));
+        let shutdown = stop.child_token();
+
+        let serve = {
+            let acceptor = acceptor.clone();
+            let service = service.clone();
+            let logger = logger.clone();
+
+            move |tcp, shutdown| -> listener::Serving {
+                Box::pin(listener::serve_tls(
+                    tcp,
+                    acceptor.clone(),
+                    service.clone(),
+                    logger.clone(),
+                    shutdown,
+                ))
+            }
         };
 
-    tokio::spawn(async move {
-        let _guard = alive;
-        future.await;
-        debug!(logger, "WS server stopped");
-    });
+        let future = serve(tcp, shutdown.clone());
+        let rebind: listener::Bind = Box::new(move |addr, shutdown| {
+            listener::bind_tls(addr).map(|tcp| serve(tcp, shutdown))
+        });
+
+        tokio::spawn(listener::supervise(
+            socket,
+            future,
+            shutdown,
+            rebind,
+            network_refresh.clone(),
+            stop.clone(),
+            logger.clone(),
+            alive,
+        ));
+    }
 
-    Ok(())
+    Ok(bound)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -296,22 +754,31 @@ async fn websocket_start(
     stop: CancellationToken,
     version: protocol::Version,
     peer: SocketAddr,
+    caps: Capabilities,
+    clock_skew: Option<i64>,
+    device: Option<PeerDevice>,
+    listen_port: Option<u16>,
     logger: Logger,
     refresh_trigger: tokio::sync::watch::Receiver<()>,
 ) {
+    let alive = alive.task("server", format!("Connection from {peer}"));
     let ctx = RunContext {
         logger: &logger,
         state: state.clone(),
         stop: &stop,
         alive: &alive,
         refresh_trigger: &refresh_trigger,
+        clock_skew,
+        device,
+        listen_port,
+        can_add_files: caps.contains(Capability::FileAddition),
     };
 
     match version {
         protocol::Version::V6 => {
             ctx.run(
                 socket,
-                v6::HandlerInit::new(peer.ip(), state, &logger, &alive),
+                v6::HandlerInit::new(peer, state, caps, &logger, &alive),
             )
             .await
         }
@@ -346,6 +813,7 @@ async fn process_authentication(
 }
 
 async fn handle_rejection(
+    auth: &crate::auth::Context,
     nonces: &Mutex<HashMap<SocketAddr, Nonce>>,
     err: warp::Rejection,
 ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
@@ -355,15 +823,16 @@ async fn handle_rejection(
     }) = err.find()
     {
         let nonce = Nonce::generate_as_server();
-        let (header_key, header_val) = crate::auth::create_www_authentication_header(&nonce);
+
+        // One challenge per scheme, the client picks the one to answer
+        let mut reply = StatusCode::UNAUTHORIZED.into_response();
+        for (key, value) in auth.create_www_authentication_headers(&nonce) {
+            reply.headers_mut().append(key, value);
+        }
 
         nonces.lock().await.insert(*peer, nonce);
 
-        let reply = authorization.insert(warp::reply::with_header(
-            StatusCode::UNAUTHORIZED,
-            header_key,
-            header_val,
-        ));
+        let reply = authorization.insert(reply);
 
         Ok(reply)
     } else if let Some(Unauthorized) = err.find() {
@@ -372,6 +841,14 @@ async fn handle_rejection(
         Ok(Box::new(StatusCode::TOO_MANY_REQUESTS))
     } else if let Some(BadRequest) = err.find() {
         Ok(Box::new(StatusCode::BAD_REQUEST))
+    } else if let Some(Refused) = err.find() {
+        Ok(Box::new(StatusCode::FORBIDDEN))
+    } else if let Some(Busy) = err.find() {
+        Ok(Box::new(warp::reply::with_header(
+            StatusCode::SERVICE_UNAVAILABLE,
+            hyper::header::RETRY_AFTER,
+            drop_config::FIRST_RETRY_AFTER.as_secs(),
+        )))
     } else {
         Err(err)
     }
@@ -383,6 +860,14 @@ struct RunContext<'a> {
     refresh_trigger: &'a tokio::sync::watch::Receiver<()>,
     stop: &'a CancellationToken,
     alive: &'a AliveGuard,
+    // The client's wall clock minus ours, when the client reported it
+    clock_skew: Option<i64>,
+    // The device the client declared in the handshake
+    device: Option<PeerDevice>,
+    // The port the client listens on, dialed by the checks of the transfer
+    listen_port: Option<u16>,
+    // The client may add the files to the transfer
+    can_add_files: bool,
 }
 
 impl RunContext<'_> {
@@ -410,10 +895,17 @@ impl RunContext<'_> {
             },
         };
 
-        let xfer = match xfer.parse() {
+        let known = self
+            .state
+            .transfer_manager
+            .is_incoming_known(xfer.id())
+            .await;
+
+        let xfer = match xfer.parse(known) {
             Ok(xfer) => {
                 debug!(self.logger, "RunContext::run() called with {:?}", xfer);
-                xfer
+                xfer.with_peer_device(self.device.clone())
+                    .with_peer_port(self.listen_port)
             }
             Err(err) => {
                 if let Err(err) = handler.on_error(&mut socket, err).await {
@@ -427,6 +919,84 @@ impl RunContext<'_> {
             }
         };
 
+        if !known {
+            if !self.state.config.transfer_mode.can_receive() {
+                info!(
+                    self.logger,
+                    "Send-only mode, rejecting incoming transfer {}",
+                    xfer.id()
+                );
+
+                let err = crate::Error::ReceiveDisabled.into();
+                if let Err(err) = handler.on_error(&mut socket, err).await {
+                    warn!(
+                        self.logger,
+                        "Failed to report the disabled receiving: {err}"
+                    );
+                }
+                self.state.emit_event(
+                    self.logger,
+                    crate::Event::IncomingTransferRejected {
+                        transfer_id: xfer.id(),
+                        peer: xfer.peer(),
+                        error: crate::Error::ReceiveDisabled,
+                    },
+                );
+                return;
+            }
+
+            if self.state.exceeds_peer_quota(&xfer).await {
+                info!(
+                    self.logger,
+                    "Incoming transfer {} exceeds the peer quota",
+                    xfer.id()
+                );
+
+                let err = crate::Error::QuotaExceeded.into();
+                if let Err(err) = handler.on_error(&mut socket, err).await {
+                    warn!(self.logger, "Failed to report the exceeded quota: {err}");
+                }
+                self.state.emit_event(
+                    self.logger,
+                    crate::Event::IncomingTransferRejected {
+                        transfer_id: xfer.id(),
+                        peer: xfer.peer(),
+                        error: crate::Error::QuotaExceeded,
+                    },
+                );
+                return;
+            }
+
+            match self.state.incoming_decision(&xfer) {
+                Some(IncomingDecision::Accept) | None => (),
+                Some(IncomingDecision::Reject) => {
+                    info!(self.logger, "Incoming transfer {} rejected", xfer.id());
+
+                    if let Err(err) = socket.send(Message::close()).await {
+                        warn!(self.logger, "Failed to close rejected transfer: {err}");
+                    }
+                    return;
+                }
+                Some(IncomingDecision::Drop) => {
+                    info!(self.logger, "Incoming transfer {} dropped", xfer.id());
+                    return;
+                }
+            }
+
+            if !self.state.inbox_usage.reserve(&self.state.config, &xfer) {
+                info!(
+                    self.logger,
+                    "Incoming transfer {} does not fit into the inbox",
+                    xfer.id()
+                );
+
+                if let Err(err) = socket.send(Message::close()).await {
+                    warn!(self.logger, "Failed to close rejected transfer: {err}");
+                }
+                return;
+            }
+        }
+
         let xfer = Arc::new(xfer);
         let xfer_id = xfer.id();
 
@@ -505,11 +1075,15 @@ impl RunContext<'_> {
                     },
                     // Message to send down the wire
                     msg = send_rx.recv() => {
-                        let MsgToSend { msg } = msg.expect("Handler channel should always be open");
+                        let MsgToSend { msg } = msg.context("Handler channel closed")?;
                         socket.send(msg).await?;
                     },
                     _ = ping.tick() => {
                         socket.send(Message::ping(Vec::new())).await.context("Failed to send PING message")?;
+
+                        if let Some(stats) = self.state.transfer_manager.incoming_stats(xfer.id()).await {
+                            stats.ping_sent();
+                        }
                     }
                 };
             }
@@ -542,11 +1116,17 @@ impl RunContext<'_> {
         let registered = self
             .state
             .transfer_manager
-            .register_incoming(xfer.clone(), req_send)
+            .register_incoming(
+                xfer.clone(),
+                req_send,
+                self.can_add_files,
+                &self.state.config,
+            )
             .await?;
 
         match registered {
             IncomingRegistered::IsNew { events } => {
+                alias::assign(&self.state, self.logger, xfer.id()).await;
                 events.received().await;
 
                 check::spawn(
@@ -557,11 +1137,27 @@ impl RunContext<'_> {
                     self.alive.clone(),
                     self.stop.clone(),
                 );
+
+                if forward::is_stored(&self.state.config, xfer) {
+                    self.state
+                        .forward(self.logger, forward::Request::Received(xfer.clone()));
+                }
             }
             IncomingRegistered::Continue => (),
+            IncomingRegistered::FilesAdded(added) => {
+                added.events.files_added(added.xfer, added.files).await
+            }
             IncomingRegistered::JustCancelled { events } => events.cancel(false).await,
         }
 
+        if let Some(skew) = self.clock_skew {
+            debug!(self.logger, "Peer clock skew: {skew} ms");
+            self.state
+                .storage
+                .update_peer_clock_skew(xfer.id(), skew)
+                .await;
+        }
+
         Ok(())
     }
 
@@ -585,12 +1181,17 @@ impl RunContext<'_> {
             if let Some(state) = self.state.transfer_manager.incoming_remove(xfer.id()).await {
                 state.xfer_events.cancel(true).await
             }
+            forward::ended(&self.state, self.logger, xfer);
 
             return Ok(ControlFlow::Break(()));
         } else if msg.is_ping() {
             debug!(self.logger, "PING");
         } else if msg.is_pong() {
             debug!(self.logger, "PONG");
+
+            if let Some(stats) = self.state.transfer_manager.incoming_stats(xfer.id()).await {
+                stats.pong_received();
+            }
         } else {
             warn!(self.logger, "Server received invalid WS message type");
         }
@@ -609,6 +1210,16 @@ impl RunContext<'_> {
     ) -> anyhow::Result<ControlFlow<()>> {
         match req.context("API channel broken")? {
             ServerReq::Download { task } => {
+                // Covers the files resumed on the restored transfers too
+                if !self.state.allow_data_flow(
+                    self.logger,
+                    task.xfer.id(),
+                    Some(task.file.id()),
+                    RefusedOperation::Download,
+                ) {
+                    return Ok(ControlFlow::Continue(()));
+                }
+
                 let ctx = FileStreamCtx {
                     jobs,
                     guard: self.alive.clone(),
@@ -622,8 +1233,13 @@ impl RunContext<'_> {
             }
             ServerReq::Start { file, offset } => handler.issue_start(socket, file, offset).await?,
             ServerReq::Reject { file } => handler.issue_reject(socket, file).await?,
-            ServerReq::Done { file } => handler.issue_done(socket, file).await?,
+            ServerReq::Done { file, final_path } => {
+                handler.issue_done(socket, file, final_path).await?
+            }
             ServerReq::Fail { file, msg } => handler.issue_failure(socket, file, msg).await?,
+            ServerReq::Pause => handler.issue_pause(socket, true).await?,
+            ServerReq::Resume => handler.issue_pause(socket, false).await?,
+            ServerReq::Pace { file, rate } => handler.issue_pace(socket, file, rate).await?,
 
             ServerReq::Close => {
                 debug!(self.logger, "Stoppping server connection gracefuly");
@@ -632,6 +1248,7 @@ impl RunContext<'_> {
                 socket.drain().await.context("Failed to drain the socket")?;
 
                 self.state.transfer_manager.incoming_remove(xfer.id()).await;
+                forward::ended(&self.state, self.logger, xfer);
                 return Ok(ControlFlow::Break(()));
             }
         }
@@ -641,11 +1258,24 @@ impl RunContext<'_> {
 }
 
 impl FileXferTask {
-    pub fn new(file: FileToRecv, xfer: Arc<IncomingTransfer>, base_dir: PathBuf) -> Self {
+    pub fn new(
+        file: FileToRecv,
+        xfer: Arc<IncomingTransfer>,
+        base_dir: PathBuf,
+        dest: Option<Arc<DestinationFd>>,
+    ) -> Self {
         Self {
             file,
             xfer,
             base_dir: Hidden(base_dir),
+            dest,
+        }
+    }
+
+    fn open_written(&self, location: &Hidden<PathBuf>) -> io::Result<fs::File> {
+        match &self.dest {
+            Some(dest) => dest.open(),
+            None => fs::File::open(&location.0),
         }
     }
 
@@ -663,7 +1293,12 @@ impl FileXferTask {
         emit_checksum_events: bool,
         checksum_events_granularity: u64,
     ) -> crate::Result<PathBuf> {
-        let mut out_file = match downloader.open(tmp_loc).await {
+        let out_file = match &self.dest {
+            // The descriptor cannot be resumed, it's always written from the start
+            Some(dest) => dest.create().map_err(Into::into),
+            None => downloader.open(tmp_loc).await,
+        };
+        let mut out_file = match out_file {
             Ok(out_file) => out_file,
             Err(err) => {
                 error!(
@@ -675,42 +1310,176 @@ impl FileXferTask {
             }
         };
 
+        let mut window = RetransmitWindow::new(offset, self.file.size());
+        let timer = state
+            .config
+            .pipeline_timing
+            .then(|| events.timer().as_ref());
+
+        let parallel = downloader.parallel();
+
         let consume_file_chunks = async {
-            let mut bytes_received = offset;
-            let mut last_progress = bytes_received;
+            let mut file_pos = offset;
+            let mut last_progress = offset;
+            let mut retransmissions = 0;
+            // The corrupted chunk of the parallel ranges cannot be placed. The
+            // missing parts the ranges did not stream since are asked for at
+            // the deadline
+            let mut fronts = RangeFronts::default();
+            let mut retransmit_at: Option<Instant> = None;
 
             // Announce initial state of the transfer
-            downloader.progress(bytes_received).await?;
-            events.progress(bytes_received).await;
+            downloader.progress(offset).await?;
+            events.progress(offset).await;
+
+            while !window.is_complete() {
+                let started = Instant::now();
+                let chunk = if let Some(deadline) = &mut retransmit_at {
+                    match recv_until(stream, downloader, deadline).await? {
+                        Some(chunk) => chunk,
+                        None => {
+                            let since = *deadline - RETRANSMIT_TIMEOUT;
+                            let ranges = fronts.stalled(&window, since);
+                            if ranges.is_empty() {
+                                // Nothing was lost, the ranges are still streaming
+                                retransmit_at = None;
+                                continue;
+                            }
+                            if retransmissions >= MAX_CHUNK_RETRANSMISSIONS {
+                                return Err(crate::Error::ChunkCorrupted);
+                            }
+
+                            warn!(
+                                logger,
+                                "Requesting the lost chunks of {}: {ranges:?}",
+                                self.file.id()
+                            );
+                            for range in ranges {
+                                downloader.retransmit(range).await?;
+                            }
+                            retransmissions += 1;
+                            retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+                            continue;
+                        }
+                    }
+                } else if window.stream_done() && !parallel {
+                    // Only the retransmitted chunks are left
+                    let mut deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+                    match recv_until(stream, downloader, &mut deadline).await? {
+                        Some(chunk) => chunk,
+                        None if retransmissions < MAX_CHUNK_RETRANSMISSIONS => {
+                            warn!(
+                                logger,
+                                "Retransmission of {} timed out, requesting again",
+                                self.file.id()
+                            );
+
+                            for range in window.holes() {
+                                downloader.retransmit(range).await?;
+                            }
+                            retransmissions += 1;
+                            continue;
+                        }
+                        None => return Err(crate::Error::ChunkCorrupted),
+                    }
+                } else {
+                    stream.recv().await.ok_or(crate::Error::Canceled)?
+                };
+                let received_size = chunk.len() as u64;
+                timing::record(timer, Stage::Recv, started);
+
+                let started = Instant::now();
+                let opened = downloader.open_chunk(window.stream_pos(), chunk);
+                let (chunk_offset, chunk) = match opened {
+                    Ok(chunk) => {
+                        timing::record(timer, Stage::Decrypt, started);
+                        chunk
+                    }
+                    Err(crate::Error::ChunkCorrupted)
+                        if retransmissions < MAX_CHUNK_RETRANSMISSIONS =>
+                    {
+                        events.retransmitted(received_size);
+                        retransmissions += 1;
+
+                        if parallel {
+                            warn!(
+                                logger,
+                                "Corrupted chunk of {}, waiting for the rest of the ranges",
+                                self.file.id()
+                            );
+                            retransmit_at.get_or_insert(Instant::now() + RETRANSMIT_TIMEOUT);
+                            continue;
+                        }
 
-            while bytes_received < self.file.size() {
-                let chunk = stream.recv().await.ok_or(crate::Error::Canceled)?;
+                        // The data is not longer than the received message, the
+                        // overlap with the next chunk is dropped as a duplicate
+                        let ranges = match window.corrupted(received_size) {
+                            Some(range) => vec![range],
+                            // It was one of the retransmitted chunks
+                            None => window.holes(),
+                        };
+
+                        if window.holes().len() > RETRANSMIT_WINDOW {
+                            return Err(crate::Error::ChunkCorrupted);
+                        }
+
+                        for range in ranges {
+                            warn!(
+                                logger,
+                                "Corrupted chunk of {} at {range:?}, retransmitting",
+                                self.file.id()
+                            );
+                            downloader.retransmit(range).await?;
+                        }
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
 
-                let chunk_size = chunk.len();
-                if chunk_size as u64 + bytes_received > self.file.size() {
+                if chunk_offset + chunk.len() as u64 > self.file.size() {
                     return Err(crate::Error::MismatchedSize);
                 }
+                if parallel {
+                    fronts.received(chunk_offset, chunk.len() as u64, Instant::now());
+                }
+
+                let fresh = window.received(chunk_offset, chunk.len() as u64);
+                if fresh.is_empty() {
+                    events.retransmitted(received_size);
+                    continue;
+                }
 
-                out_file.write_all(&chunk)?;
+                let started = Instant::now();
+                for range in fresh {
+                    if range.start != file_pos {
+                        out_file.seek(io::SeekFrom::Start(range.start))?;
+                    }
 
-                bytes_received += chunk_size as u64;
+                    let start = (range.start - chunk_offset) as usize;
+                    let end = (range.end - chunk_offset) as usize;
+                    out_file.write_all(&chunk[start..end])?;
+                    file_pos = range.end;
+                }
+                timing::record(timer, Stage::Write, started);
 
-                if last_progress + REPORT_PROGRESS_THRESHOLD <= bytes_received {
+                let written = window.written();
+                if last_progress + REPORT_PROGRESS_THRESHOLD <= written {
                     // send progress to the caller
-                    downloader.progress(bytes_received).await?;
-                    events.progress(bytes_received).await;
+                    downloader.progress(written).await?;
+                    events.progress(written).await;
 
-                    last_progress = bytes_received;
+                    last_progress = written;
                 }
             }
 
+            if state.config.verify_writes {
+                out_file.sync_all()?;
+                evict_cached_pages(&out_file)?;
+            }
+
             // Close the file handle
             drop(out_file);
 
-            if bytes_received > self.file.size() {
-                return Err(crate::Error::UnexpectedData);
-            }
-
             if emit_checksum_events {
                 events.finalize_checksum_start(self.file.size()).await;
                 let progress_cb = {
@@ -721,7 +1490,7 @@ impl FileXferTask {
 
                 downloader
                     .validate(
-                        tmp_loc,
+                        self.open_written(tmp_loc)?,
                         Some(progress_cb),
                         Some(checksum_events_granularity),
                     )
@@ -731,7 +1500,7 @@ impl FileXferTask {
             } else {
                 downloader
                     .validate::<_, futures::future::Ready<()>>(
-                        tmp_loc,
+                        self.open_written(tmp_loc)?,
                         None::<fn(u64) -> futures::future::Ready<()>>,
                         None,
                     )
@@ -741,10 +1510,33 @@ impl FileXferTask {
             Ok(())
         };
 
-        match consume_file_chunks.await {
-            Err(err @ crate::Error::Canceled) => return Err(err), // Do not remove temp file
-            // when cancelled. We might
-            // resume
+        let result = consume_file_chunks.await;
+
+        if let Some(dest) = &self.dest {
+            // The app owns the destination and removes it on failure
+            result?;
+            return Ok(PathBuf::from(dest.content_uri().as_str()));
+        }
+
+        match result {
+            Err(err @ crate::Error::Canceled) => {
+                // Do not remove temp file when cancelled. We might resume, but only from the
+                // part with no holes in it
+                if !window.is_complete() {
+                    if let Err(ioerr) = fs::OpenOptions::new()
+                        .write(true)
+                        .open(&tmp_loc.0)
+                        .and_then(|file| file.set_len(window.contiguous()))
+                    {
+                        warn!(
+                            logger,
+                            "Could not truncate temporary file {tmp_loc:?}: {ioerr}"
+                        );
+                    }
+                }
+
+                return Err(err);
+            }
             Err(err) => {
                 if let Err(ioerr) = fs::remove_file(&tmp_loc.0) {
                     error!(
@@ -780,6 +1572,54 @@ impl FileXferTask {
             }
         };
 
+        if state.config.verify_writes {
+            let dst = Hidden(dst);
+
+            // The rename is made durable together with the data
+            #[cfg(unix)]
+            if let Some(parent) = dst.0.parent() {
+                if let Err(err) = fs::File::open(parent).and_then(|dir| dir.sync_all()) {
+                    warn!(logger, "Failed to sync the destination directory: {err}");
+                }
+            }
+
+            let file = fs::File::open(&dst.0).and_then(|file| {
+                evict_cached_pages(&file)?;
+                Ok(file)
+            });
+            let verified = match file {
+                Ok(file) => {
+                    downloader
+                        .validate::<_, futures::future::Ready<()>>(
+                            file,
+                            None::<fn(u64) -> futures::future::Ready<()>>,
+                            None,
+                        )
+                        .await
+                }
+                Err(err) => Err(err.into()),
+            };
+
+            if let Err(err) = verified {
+                error!(
+                    logger,
+                    "Verification of the written file {} failed: {err}",
+                    self.file.id()
+                );
+
+                if let Err(ioerr) = fs::remove_file(&dst.0) {
+                    error!(
+                        logger,
+                        "Could not remove the unverified file {dst:?}: {ioerr}"
+                    );
+                }
+
+                return Err(err);
+            }
+
+            return Ok(dst.0);
+        }
+
         Ok(dst)
     }
 
@@ -796,7 +1636,83 @@ impl FileXferTask {
 
         drop(lock);
 
-        Ok(self.base_dir.join(mapping))
+        Ok(utils::long_path(self.base_dir.join(mapping)))
+    }
+
+    /// Recreates the empty directories of the file's root directory, once per
+    /// transfer and destination. The symlinks are recreated after the last
+    /// regular file of the root directory, so that no file is written through
+    /// them
+    async fn create_dir_entries(&self, state: &State, logger: &Logger) {
+        let subpath = self.file.subpath();
+        if subpath.len() < 2 {
+            return;
+        }
+
+        let entries = self.xfer.dir_entries().with_root(subpath.root());
+        if entries.is_empty() {
+            return;
+        }
+
+        let mut lock = state.transfer_manager.incoming.lock().await;
+        let xstate = if let Some(xstate) = lock.get_mut(&self.xfer.id()) {
+            xstate
+        } else {
+            return;
+        };
+
+        let create_dirs = state.config.transfer_empty_dirs
+            && xstate
+                .dir_mappings
+                .mark_entries_created(&self.base_dir, subpath.root());
+        let create_links = state.config.transfer_symlinks
+            && xstate.root_files_terminal(subpath.root(), self.file.id())
+            && xstate
+                .dir_mappings
+                .mark_links_created(&self.base_dir, subpath.root());
+
+        let mut compose = |path| {
+            xstate
+                .dir_mappings
+                .compose_final_path(&self.base_dir, path)
+                .map(|mapping| utils::long_path(self.base_dir.join(mapping)))
+        };
+
+        if create_dirs {
+            for dir in &entries.empty_dirs {
+                if let Err(err) = compose(dir).and_then(|path| Ok(fs::create_dir_all(path)?)) {
+                    warn!(logger, "Failed to create empty directory {dir:?}: {err}");
+                }
+            }
+        }
+
+        if create_links {
+            let root_dir = compose(subpath).map(|path| {
+                let depth = subpath.len() - 1;
+                path.ancestors().nth(depth).map(Path::to_path_buf)
+            });
+
+            for link in &entries.symlinks {
+                let create = compose(&link.path).and_then(|path| {
+                    let Ok(Some(root_dir)) = &root_dir else {
+                        return Err(crate::Error::BadPath("Missing root directory".into()));
+                    };
+                    if let Some(parent) = path.parent() {
+                        if file::crosses_symlink(root_dir, parent)? {
+                            return Err(crate::Error::BadPath(
+                                "Symlink is placed under another symlink".into(),
+                            ));
+                        }
+                        fs::create_dir_all(parent)?;
+                    }
+                    Ok(link.create(root_dir, &path)?)
+                });
+
+                if let Err(err) = create {
+                    warn!(logger, "Failed to create symlink {:?}: {err}", link.path);
+                }
+            }
+        }
     }
 
     async fn place_file_into_dest(
@@ -807,11 +1723,32 @@ impl FileXferTask {
     ) -> crate::Result<PathBuf> {
         let abs_path = self.prepare_abs_path(state).await?;
         if let Some(parent) = abs_path.parent() {
+            // The files added to the transfer later must not be written through
+            // the recreated symlinks
+            if file::crosses_symlink(&self.base_dir, parent)? {
+                return Err(crate::Error::BadPath(
+                    "The file path goes through a symlink".into(),
+                ));
+            }
             std::fs::create_dir_all(parent)?;
         }
 
         let dst = move_tmp_to_dst(tmp_location, Hidden(&abs_path), logger)?;
 
+        if state.config.transfer_empty_dirs || state.config.transfer_symlinks {
+            self.create_dir_entries(state, logger).await;
+        }
+
+        if state.config.preserve_metadata {
+            if let Err(err) = self.file.apply_metadata(&dst) {
+                warn!(
+                    logger,
+                    "Failed to apply metadata to the downloaded file {}: {err}",
+                    self.file.id()
+                );
+            }
+        }
+
         Ok(dst)
     }
 
@@ -820,6 +1757,7 @@ impl FileXferTask {
         logger: &Logger,
         events: &FileEventTx<IncomingTransfer>,
         tmp_location: &Hidden<PathBuf>,
+        resume_hash: file::HashAlg,
         emit_checksum_events: bool,
         checksum_events_granularity: u64,
     ) -> Option<TmpFileState> {
@@ -842,9 +1780,18 @@ impl FileXferTask {
             None
         };
 
+        // The temporary file of the full size is compared with the checksum of
+        // the whole file, which is always SHA-256
+        let hash = if tmp_size.is_some_and(|size| size < self.file.size()) {
+            resume_hash
+        } else {
+            file::HashAlg::Sha256
+        };
+
         // Check if we can resume the temporary file
         let tmp_file_state = match TmpFileState::load(
             &tmp_location.0,
+            &hash,
             cb,
             Some(checksum_events_granularity),
         )
@@ -883,6 +1830,13 @@ impl FileXferTask {
         logger: Logger,
         guard: AliveGuard,
     ) {
+        let tmp_location = Hidden(temp_file_path(
+            &state.config,
+            &self.base_dir,
+            self.xfer.id(),
+            self.file.id(),
+        ));
+
         let task = async {
             validate_subpath_for_download(self.file.subpath())?;
             validate_file_id_for_download(self.file.id())?;
@@ -898,20 +1852,26 @@ impl FileXferTask {
 
             events.preflight().await;
 
-            let tmp_location: Hidden<PathBuf> = Hidden(
-                self.base_dir
-                    .join(temp_file_name(self.xfer.id(), self.file.id())),
-            );
+            if self.dest.is_none() {
+                if let Some(dir) = tmp_location.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+            }
 
-            let tmp_file_state = self
-                .handle_tmp_file(
+            let resume_hash = downloader.resume_hash();
+            let tmp_file_state = if self.dest.is_some() {
+                None
+            } else {
+                self.handle_tmp_file(
                     &logger,
                     &events,
                     &tmp_location,
+                    resume_hash,
                     emit_checksum_events,
                     checksum_events_granularity,
                 )
-                .await;
+                .await
+            };
 
             let init_res = downloader.init(&self, tmp_file_state).await?;
 
@@ -954,6 +1914,14 @@ impl FileXferTask {
         // Since the outter task can be aborted, let's move it to a separate task
         // so that it's never interrupted.
         let error_logger = logger.clone();
+        let guard = guard.task(
+            "server",
+            format!(
+                "Finalizing file {} of transfer {}",
+                self.file.id(),
+                self.xfer.id()
+            ),
+        );
         if let Err(e) = tokio::spawn(async move {
             let _guard = guard;
 
@@ -967,7 +1935,11 @@ impl FileXferTask {
 
                     let finish_res = state
                         .transfer_manager
-                        .incoming_finish_post(self.xfer.id(), self.file.id(), Ok(()))
+                        .incoming_finish_post(
+                            self.xfer.id(),
+                            self.file.id(),
+                            Ok(dst_location.clone()),
+                        )
                         .await;
 
                     events.success(dst_location).await;
@@ -985,13 +1957,21 @@ impl FileXferTask {
                         .incoming_finish_post(self.xfer.id(), self.file.id(), Err(err.to_string()))
                         .await;
 
-                    events.failed(err).await;
+                    let partial_path =
+                        Some(tmp_location.0).filter(|path| self.dest.is_none() && path.is_file());
+                    events.failed(err, partial_path).await;
                     finish_res
                 }
             };
 
             match finish_res {
-                Ok(xfer_state) => handle_finish_xfer_state(xfer_state, false).await,
+                Ok(xfer_state) => {
+                    if matches!(xfer_state, FinishTransferState::Canceled { .. }) {
+                        forward::ended(&state, &logger, &self.xfer);
+                    }
+
+                    handle_finish_xfer_state(xfer_state, false).await
+                }
                 Err(err) => warn!(logger, "Failed to post finish: {err}"),
             }
         })
@@ -1006,6 +1986,7 @@ impl TmpFileState {
     // Blocking operation
     async fn load<F, Fut>(
         path: &Path,
+        hash: &file::HashAlg,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> io::Result<Self>
@@ -1017,11 +1998,55 @@ impl TmpFileState {
 
         let meta = file.metadata()?;
 
-        let csum = file::checksum(file, progress_cb, event_granularity).await?;
+        let csum = file::checksum_with(hash, file, progress_cb, event_granularity).await?;
         Ok(TmpFileState { meta, csum })
     }
 }
 
+// Waits for the next chunk until the deadline, `None` once it passes. The time
+// the file is held back on the sender does not count, letting it go puts the
+// deadline off by the full timeout
+async fn recv_until(
+    stream: &mut UnboundedReceiver<Vec<u8>>,
+    downloader: &mut impl Downloader,
+    deadline: &mut Instant,
+) -> crate::Result<Option<Vec<u8>>> {
+    loop {
+        let held = downloader.held();
+        let timeout = tokio::time::sleep_until(tokio::time::Instant::from_std(*deadline));
+
+        tokio::select! {
+            chunk = stream.recv() => return chunk.map(Some).ok_or(crate::Error::Canceled),
+            _ = timeout, if !held => return Ok(None),
+            held = downloader.held_changed() => {
+                if !held {
+                    *deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+                }
+            }
+        }
+    }
+}
+
+// Drops the file's pages from the cache, so that reading the file back comes
+// from the storage medium where the OS allows it. The file must be synced
+fn evict_cached_pages(file: &fs::File) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: the descriptor stays open for the duration of the call
+        let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = file;
+
+    Ok(())
+}
+
 fn move_tmp_to_dst(
     tmp_location: &Hidden<PathBuf>,
     absolute_path: Hidden<&Path>,
@@ -1056,7 +2081,19 @@ fn move_tmp_to_dst(
         }
     };
 
-    if let Err(err) = fs::rename(&tmp_location.0, &dst_location) {
+    let moved = match fs::rename(&tmp_location.0, &dst_location) {
+        // The `partial_dir` on another filesystem than the destination
+        Err(err) if crosses_devices(&err) => {
+            debug!(
+                logger,
+                "Temporary file is on another filesystem, copying it into the destination"
+            );
+            copy_tmp_to_dst(&tmp_location.0, &dst_location, logger)
+        }
+        res => res,
+    };
+
+    if let Err(err) = moved {
         if let Err(err) = fs::remove_file(&dst_location) {
             warn!(
                 logger,
@@ -1073,6 +2110,40 @@ fn move_tmp_to_dst(
     Ok(dst_location)
 }
 
+/// Tells whether the rename failed because the paths are on different
+/// filesystems
+fn crosses_devices(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CROSS_DEVICE: i32 = libc::EXDEV;
+    #[cfg(windows)]
+    const CROSS_DEVICE: i32 = 17; // ERROR_NOT_SAME_DEVICE
+
+    err.raw_os_error() == Some(CROSS_DEVICE)
+}
+
+/// Copies the temporary file next to the destination first so that the file
+/// appears at the destination whole, with the rename
+fn copy_tmp_to_dst(tmp_location: &Path, dst_location: &Path, logger: &Logger) -> io::Result<()> {
+    let dir = dst_location.parent().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "Destination has no parent directory")
+    })?;
+    let name = tmp_location
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Temporary file has no name"))?;
+    let copy = dir.join(name);
+
+    let res = fs::copy(tmp_location, &copy).and_then(|_| fs::rename(&copy, dst_location));
+    if res.is_err() {
+        let _ = fs::remove_file(&copy);
+        return res;
+    }
+
+    if let Err(err) = fs::remove_file(tmp_location) {
+        warn!(logger, "Failed to remove copied temporary file: {err}");
+    }
+    Ok(())
+}
+
 impl<'a> FileStreamCtx<'a> {
     async fn start(
         self,
@@ -1084,6 +2155,12 @@ impl<'a> FileStreamCtx<'a> {
             .transfer_manager
             .incoming_file_events(self.task.xfer.id(), self.task.file.id())
             .await?;
+        let transfer_span = self
+            .state
+            .transfer_manager
+            .transfer_span(self.task.xfer.id())
+            .await
+            .unwrap_or_else(tracing::Span::none);
 
         let job = {
             let events = events.clone();
@@ -1097,12 +2174,24 @@ impl<'a> FileStreamCtx<'a> {
                 task,
             } = self;
 
-            jobs.spawn(async move {
-                let _guard = guard.clone();
+            let guard = guard.task(
+                "server",
+                format!(
+                    "Download of file {} of transfer {}",
+                    task.file.id(),
+                    task.xfer.id()
+                ),
+            );
+            let span = spans::file(&transfer_span, task.xfer.id(), task.file.id(), "download");
+            jobs.spawn(
+                async move {
+                    let _guard = guard.clone();
 
-                task.run(state, events, downloader, stream, req_send, logger, guard)
-                    .await;
-            })
+                    task.run(state, events, downloader, stream, req_send, logger, guard)
+                        .await;
+                }
+                .instrument(span),
+            )
         };
 
         Ok((job, events))
@@ -1111,6 +2200,7 @@ impl<'a> FileStreamCtx<'a> {
 
 pub fn remove_temp_files<P, I>(
     logger: &Logger,
+    config: &DropConfig,
     transfer_id: uuid::Uuid,
     iter: impl IntoIterator<Item = (P, I)>,
 ) where
@@ -1119,7 +2209,7 @@ pub fn remove_temp_files<P, I>(
 {
     for (base, file_id) in iter.into_iter() {
         let file_id = file_id.borrow();
-        let location = base.into().join(temp_file_name(transfer_id, file_id));
+        let location = temp_file_path(config, &base.into(), transfer_id, file_id);
         let location = Hidden(location);
 
         debug!(logger, "Removing temporary file: {location:?}");
@@ -1136,27 +2226,115 @@ pub fn remove_temp_files<P, I>(
     }
 }
 
-fn temp_file_name(transfer_id: uuid::Uuid, file_id: &FileId) -> String {
-    format!("{}-{file_id}.dropdl-part", transfer_id.as_simple(),)
+/// Removes the partial files in the given directories which are older than
+/// `max_age` and do not belong to any of the `keep` transfers. Returns the
+/// number of the removed files
+pub fn remove_stale_temp_files<P>(
+    logger: &Logger,
+    config: &DropConfig,
+    dirs: impl IntoIterator<Item = P>,
+    keep: &HashSet<uuid::Uuid>,
+    max_age: Duration,
+) -> usize
+where
+    P: Into<PathBuf>,
+{
+    let suffix = format!(".{}", config.partial_file_extension);
+    let mut count = 0;
+
+    for dir in dirs {
+        let dir = Hidden(dir.into());
+        let entries = match std::fs::read_dir(&*dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                warn!(logger, "Failed to scan {dir:?} for partial files: {err}");
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(transfer_id) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(&suffix))
+                .and_then(|name| name.split_once('-'))
+                .and_then(|(id, _)| uuid::Uuid::try_parse(id).ok())
+            else {
+                continue;
+            };
+
+            if keep.contains(&transfer_id) {
+                continue;
+            }
+
+            let is_stale = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|mtime| mtime.elapsed().ok())
+                .is_some_and(|age| age > max_age);
+            if !is_stale {
+                continue;
+            }
+
+            let location = Hidden(entry.path());
+            match std::fs::remove_file(&*location) {
+                Ok(()) => {
+                    debug!(logger, "Removed stale partial file: {location:?}");
+                    count += 1;
+                }
+                Err(err) => warn!(
+                    logger,
+                    "Failed to remove stale partial file {location:?}: {err}"
+                ),
+            }
+        }
+    }
+
+    count
+}
+
+fn temp_file_path(
+    config: &DropConfig,
+    base_dir: &Path,
+    transfer_id: uuid::Uuid,
+    file_id: &FileId,
+) -> PathBuf {
+    let dir = config.partial_dir.as_deref().map_or(base_dir, Path::new);
+
+    dir.join(format!(
+        "{}-{file_id}.{}",
+        transfer_id.as_simple(),
+        config.partial_file_extension
+    ))
+}
+
+/// Returns the first of the temporary files of the file left on the disk
+pub fn find_temp_file<P>(
+    config: &DropConfig,
+    transfer_id: uuid::Uuid,
+    file_id: &FileId,
+    bases: impl IntoIterator<Item = P>,
+) -> Option<PathBuf>
+where
+    P: Into<PathBuf>,
+{
+    bases
+        .into_iter()
+        .map(|base| temp_file_path(config, &base.into(), transfer_id, file_id))
+        .find(|path| path.is_file())
 }
 
 /// Check file and dir names are shorter then MAX and contain illegal values
 fn validate_subpath_for_download(subpath: &FileSubPath) -> crate::Result<()> {
-    const DISALLOWED: &[&str] = &[".."];
-
     for name in subpath.iter() {
         if name.len() + MAX_FILE_SUFFIX_LEN > MAX_FILENAME_LENGTH {
             return Err(Error::FilenameTooLong);
         }
-
-        if DISALLOWED.contains(&name.as_str()) {
-            return Err(Error::BadPath(
-                "File subpath contains disallowed element".into(),
-            ));
-        }
     }
 
-    Ok(())
+    validate::peer_subpath(subpath)
 }
 
 /// Check file ID for illegal characters so that the temp file is not created in
@@ -1185,8 +2363,31 @@ pub async fn handle_finish_xfer_state(state: FinishTransferState<IncomingTransfe
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
+    use drop_config::DropConfig;
+
     use crate::{file::FileSubPath, FileId};
 
+    #[test]
+    fn read_back_after_eviction() {
+        use std::io::{Read, Write};
+
+        let mut tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        tmp.write_all(b"written").unwrap();
+        tmp.as_file().sync_all().unwrap();
+        super::evict_cached_pages(tmp.as_file()).unwrap();
+
+        let mut read = String::new();
+        std::fs::File::open(tmp.path())
+            .and_then(|mut file| {
+                super::evict_cached_pages(&file)?;
+                file.read_to_string(&mut read)
+            })
+            .unwrap();
+        assert_eq!(read, "written");
+    }
+
     #[test]
     fn validate_subpath() {
         let sp = FileSubPath::from_path("abc/dfg/hjk.txt").unwrap();
@@ -1237,4 +2438,91 @@ mod tests {
             Err(crate::Error::BadFileId)
         ));
     }
+
+    #[test]
+    fn temp_file_location() {
+        let id = uuid::Uuid::nil();
+        let file = FileId::from("file");
+        let mut config = DropConfig::default();
+
+        assert_eq!(
+            super::temp_file_path(&config, Path::new("/dst"), id, &file),
+            Path::new("/dst/00000000000000000000000000000000-file.dropdl-part")
+        );
+
+        config.partial_dir = Some("/staging".into());
+        config.partial_file_extension = "part".into();
+        assert_eq!(
+            super::temp_file_path(&config, Path::new("/dst"), id, &file),
+            Path::new("/staging/00000000000000000000000000000000-file.part")
+        );
+    }
+
+    #[test]
+    fn copy_across_filesystems() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let staging = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+
+        let tmp = staging.path().join("xfer-file.part");
+        let dst = dest.path().join("file.txt");
+        std::fs::write(&tmp, b"data").unwrap();
+        // Touched by `move_tmp_to_dst()` before moving
+        std::fs::write(&dst, b"").unwrap();
+
+        super::copy_tmp_to_dst(&tmp, &dst, &logger).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), b"data");
+        assert!(!tmp.exists());
+        assert_eq!(std::fs::read_dir(dest.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn stale_temp_files() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let config = DropConfig::default();
+        let dir = tempfile::tempdir().unwrap();
+
+        let kept = uuid::Uuid::from_u128(1);
+        let orphaned = uuid::Uuid::from_u128(2);
+        let file = FileId::from("file");
+
+        let kept_path = super::temp_file_path(&config, dir.path(), kept, &file);
+        let orphaned_path = super::temp_file_path(&config, dir.path(), orphaned, &file);
+        let other_path = dir.path().join("other.txt");
+        for path in [&kept_path, &orphaned_path, &other_path] {
+            std::fs::write(path, b"data").unwrap();
+        }
+
+        let keep = [kept].into_iter().collect();
+        let dirs = [dir.path()];
+
+        // Too young to be removed
+        let count = super::remove_stale_temp_files(
+            &logger,
+            &config,
+            dirs,
+            &keep,
+            std::time::Duration::from_secs(3600),
+        );
+        assert_eq!(count, 0);
+
+        let count =
+            super::remove_stale_temp_files(&logger, &config, dirs, &keep, Default::default());
+        assert_eq!(count, 1);
+        assert!(kept_path.exists());
+        assert!(!orphaned_path.exists());
+        assert!(other_path.exists());
+    }
+
+    #[test]
+    fn detect_cross_device_rename() {
+        #[cfg(unix)]
+        let err = std::io::Error::from_raw_os_error(libc::EXDEV);
+        #[cfg(windows)]
+        let err = std::io::Error::from_raw_os_error(17);
+        assert!(super::crosses_devices(&err));
+
+        let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(!super::crosses_devices(&err));
+    }
 }
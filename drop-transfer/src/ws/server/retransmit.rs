@@ -0,0 +1,219 @@
+use std::{ops::Range, time::Instant};
+
+/// Tracks the parts of the file which are not written yet, so the corrupted
+/// chunks can be requested again while the rest of the file keeps streaming
+pub struct RetransmitWindow {
+    // Sorted and disjoint
+    missing: Vec<Range<u64>>,
+    // The end of the furthest chunk of the stream
+    stream_pos: u64,
+    size: u64,
+}
+
+impl RetransmitWindow {
+    pub fn new(offset: u64, size: u64) -> Self {
+        let mut missing = Vec::new();
+        if offset < size {
+            missing.push(offset..size);
+        }
+
+        Self {
+            missing,
+            stream_pos: offset,
+            size,
+        }
+    }
+
+    pub fn stream_pos(&self) -> u64 {
+        self.stream_pos
+    }
+
+    /// All the chunks of the stream were received, intact or not
+    pub fn stream_done(&self) -> bool {
+        self.stream_pos >= self.size
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    pub fn written(&self) -> u64 {
+        self.size - self.missing.iter().map(|r| r.end - r.start).sum::<u64>()
+    }
+
+    /// The end of the written part of the file with no holes in it
+    pub fn contiguous(&self) -> u64 {
+        self.missing.first().map_or(self.size, |r| r.start)
+    }
+
+    /// The missing parts behind the stream which need to be retransmitted
+    pub fn holes(&self) -> Vec<Range<u64>> {
+        self.missing
+            .iter()
+            .filter(|r| r.start < self.stream_pos)
+            .map(|r| r.start..r.end.min(self.stream_pos))
+            .collect()
+    }
+
+    /// All the parts not written yet, the ones the stream did not reach
+    /// included
+    pub fn missing(&self) -> Vec<Range<u64>> {
+        self.missing.clone()
+    }
+
+    /// Marks the intact chunk as received. Returns the parts of it which were
+    /// not written yet
+    pub fn received(&mut self, offset: u64, len: u64) -> Vec<Range<u64>> {
+        let chunk = offset..offset + len;
+        self.stream_pos = self.stream_pos.max(chunk.end);
+
+        let mut fresh = Vec::new();
+        let mut missing = Vec::with_capacity(self.missing.len() + 1);
+
+        for range in self.missing.drain(..) {
+            let start = range.start.max(chunk.start);
+            let end = range.end.min(chunk.end);
+
+            if start >= end {
+                missing.push(range);
+                continue;
+            }
+
+            fresh.push(start..end);
+            if range.start < start {
+                missing.push(range.start..start);
+            }
+            if end < range.end {
+                missing.push(end..range.end);
+            }
+        }
+
+        self.missing = missing;
+        fresh
+    }
+
+    /// Skips the corrupted chunk of the stream at most `len` bytes long.
+    /// Returns the range to be requested again
+    pub fn corrupted(&mut self, len: u64) -> Option<Range<u64>> {
+        if self.stream_done() {
+            return None;
+        }
+
+        let range = self.stream_pos..(self.stream_pos + len).min(self.size);
+        self.stream_pos = range.end;
+        Some(range)
+    }
+}
+
+/// Tracks the streams of the ranges of the file uploaded at the same time, to
+/// tell the parts still being streamed from the ones lost with the corrupted
+/// chunks
+#[derive(Default)]
+pub struct RangeFronts {
+    // The end of the latest chunk of each range and when it came
+    fronts: Vec<(u64, Instant)>,
+}
+
+impl RangeFronts {
+    /// Moves the front of the range the intact chunk continues. The chunk
+    /// continuing none of them starts a range, follows the lost chunks or is
+    /// retransmitted
+    pub fn received(&mut self, offset: u64, len: u64, now: Instant) {
+        let end = offset + len;
+        match self.fronts.iter_mut().find(|(pos, _)| *pos == offset) {
+            Some(front) => *front = (end, now),
+            None => self.fronts.push((end, now)),
+        }
+    }
+
+    /// The missing parts of the window which need to be retransmitted. The
+    /// ones starting at the front of a range which moved after `since` are
+    /// still being streamed
+    pub fn stalled(&mut self, window: &RetransmitWindow, since: Instant) -> Vec<Range<u64>> {
+        let missing = window.missing();
+
+        // The fronts followed by the written parts are done
+        self.fronts
+            .retain(|(pos, _)| missing.iter().any(|range| range.start == *pos));
+
+        missing
+            .into_iter()
+            .filter(|range| {
+                !self
+                    .fronts
+                    .iter()
+                    .any(|(pos, at)| *pos == range.start && *at > since)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn retransmit_window() {
+        let mut window = RetransmitWindow::new(10, 100);
+        assert_eq!(window.contiguous(), 10);
+
+        assert_eq!(window.received(10, 20), vec![10..30]);
+        assert_eq!(window.corrupted(25), Some(30..55));
+        assert_eq!(window.received(55, 20), vec![55..75]);
+        assert_eq!(window.holes(), vec![30..55]);
+        assert_eq!(window.missing(), vec![30..55, 75..100]);
+        assert_eq!(window.contiguous(), 30);
+        assert_eq!(window.written(), 50);
+
+        // Retransmission overlapping the written parts
+        assert_eq!(window.received(25, 40), vec![30..55]);
+        assert!(window.holes().is_empty());
+        assert!(window.received(30, 10).is_empty());
+
+        assert_eq!(window.corrupted(50), Some(75..100));
+        assert!(window.stream_done());
+        assert_eq!(window.corrupted(10), None);
+        assert!(!window.is_complete());
+
+        assert_eq!(window.received(75, 25), vec![75..100]);
+        assert!(window.is_complete());
+        assert_eq!(window.contiguous(), 100);
+    }
+
+    #[test]
+    fn range_fronts() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(1);
+
+        let mut window = RetransmitWindow::new(0, 100);
+        let mut fronts = RangeFronts::default();
+        let receive = |window: &mut RetransmitWindow, fronts: &mut RangeFronts, offset, len, at| {
+            window.received(offset, len);
+            fronts.received(offset, len, at);
+        };
+
+        // Two ranges, 0..50 and 50..100
+        receive(&mut window, &mut fronts, 0, 10, start);
+        receive(&mut window, &mut fronts, 50, 10, start);
+        // The chunk at 10 is lost, the first range goes on after it
+        receive(&mut window, &mut fronts, 20, 10, later);
+        receive(&mut window, &mut fronts, 60, 10, later);
+        assert_eq!(window.missing(), vec![10..20, 30..50, 70..100]);
+
+        // The ranges streamed since are not asked for
+        assert_eq!(fronts.stalled(&window, start), vec![10..20]);
+        // Neither moved since, both are asked for in full
+        assert_eq!(
+            fronts.stalled(&window, later),
+            vec![10..20, 30..50, 70..100]
+        );
+
+        // The first range is done
+        receive(&mut window, &mut fronts, 30, 20, later);
+        receive(&mut window, &mut fronts, 10, 10, later);
+        assert_eq!(fronts.stalled(&window, start), Vec::<Range<u64>>::new());
+        assert_eq!(fronts.stalled(&window, later), vec![70..100]);
+    }
+}
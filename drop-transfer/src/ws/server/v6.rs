@@ -1,22 +1,29 @@
 use std::{
     cmp::Ordering,
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     fs,
     future::Future,
-    net::IpAddr,
+    io::{self, Seek},
+    net::{IpAddr, SocketAddr},
+    ops::Range,
     path::PathBuf,
     sync::Arc,
 };
 
 use anyhow::Context;
 use async_cell::sync::AsyncCell;
+use base64::prelude::*;
 use drop_config::DropConfig;
 use drop_core::Status;
 use slog::{debug, error, info, warn};
 use tokio::{
-    sync::mpsc::{self, Sender, UnboundedSender},
+    sync::{
+        mpsc::{self, Sender, UnboundedSender},
+        watch,
+    },
     task::{AbortHandle, JoinSet},
 };
+use uuid::Uuid;
 use warp::ws::Message;
 
 use super::{
@@ -25,20 +32,33 @@ use super::{
     TmpFileState,
 };
 use crate::{
-    file::{self, FileToRecv},
+    file::{
+        self, DirEntries, FileMetadata, FileSubPath, FileToRecv, HashAlg, MAX_PREVIEW_SIZE,
+        MAX_TOTAL_PREVIEW_SIZE,
+    },
     manager::FileTerminalState,
-    protocol::v6 as prot,
-    service::State,
+    protocol::{
+        caps::{Capabilities, Capability},
+        v6 as prot,
+    },
+    service::{self, State},
     tasks::AliveGuard,
-    transfer::{IncomingTransfer, Transfer},
+    transfer::{Forwarding, IncomingTransfer, Transfer},
     utils::{self, Hidden},
+    validate,
     ws::events::FileEventTx,
     File, FileId,
 };
 
 pub struct HandlerInit<'a> {
-    peer: IpAddr,
+    peer: SocketAddr,
     state: Arc<State>,
+    encrypt: bool,
+    crc: bool,
+    parallel: bool,
+    pacing: bool,
+    transfer_pause: bool,
+    resume_hash: HashAlg,
     logger: &'a slog::Logger,
     alive: &'a AliveGuard,
 }
@@ -50,6 +70,28 @@ pub struct HandlerLoop<'a> {
     xfer: Arc<IncomingTransfer>,
     jobs: HashMap<FileId, FileTask>,
     checksums: HashMap<FileId, Arc<AsyncCell<[u8; 32]>>>,
+    cipher: Option<Arc<prot::ChunkCipher>>,
+    crc: bool,
+    parallel: bool,
+    // The sender understands the `Pace` messages
+    pacing: bool,
+    // The sender understands the `Pause` and `Resume` messages
+    transfer_pause: bool,
+    // The pause state last sent on this connection
+    paused: Option<bool>,
+    held: watch::Sender<Held>,
+    resume_hash: HashAlg,
+    // The root directory names of the request, the added files are mapped
+    // with them
+    roots: RootMapping,
+}
+
+// What the sender was asked to hold back on this connection, the whole
+// transfer or the single files
+#[derive(Default)]
+struct Held {
+    transfer: bool,
+    files: HashSet<FileId>,
 }
 
 struct Downloader {
@@ -59,6 +101,11 @@ struct Downloader {
     csum_rx: mpsc::Receiver<prot::ReportChsum>,
     full_csum: Arc<AsyncCell<[u8; 32]>>,
     offset: u64,
+    cipher: Option<Arc<prot::ChunkCipher>>,
+    crc: bool,
+    parallel: bool,
+    held: watch::Receiver<Held>,
+    resume_hash: HashAlg,
 }
 
 struct FileTask {
@@ -70,14 +117,22 @@ struct FileTask {
 
 impl<'a> HandlerInit<'a> {
     pub(crate) fn new(
-        peer: IpAddr,
+        peer: SocketAddr,
         state: Arc<State>,
+        caps: Capabilities,
         logger: &'a slog::Logger,
         alive: &'a AliveGuard,
     ) -> Self {
         Self {
             peer,
+            resume_hash: state.resume_hash(caps.contains(Capability::ResumeChecksum)),
             state,
+            encrypt: caps.contains(Capability::ChunkEncryption),
+            crc: caps.contains(Capability::ChunkCrc),
+            parallel: caps.contains(Capability::ChunkCrc)
+                && caps.contains(Capability::ParallelRanges),
+            pacing: caps.contains(Capability::FilePacing),
+            transfer_pause: caps.contains(Capability::TransferPause),
             logger,
             alive,
         }
@@ -86,7 +141,7 @@ impl<'a> HandlerInit<'a> {
 
 #[async_trait::async_trait]
 impl<'a> handler::HandlerInit for HandlerInit<'a> {
-    type Request = (prot::TransferRequest, IpAddr, Arc<DropConfig>);
+    type Request = (prot::TransferRequest, SocketAddr, Arc<DropConfig>);
     type Loop = HandlerLoop<'a>;
     type Pinger = tokio::time::Interval;
 
@@ -151,13 +206,25 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
                 }
             }
 
-            Ok((to_fetch, checksum_map))
+            let cipher = if self.encrypt {
+                let cipher = self
+                    .state
+                    .auth
+                    .chunk_cipher(self.peer.ip(), xfer.id())
+                    .context("Missing keys for the chunk encryption")?;
+
+                Some(cipher)
+            } else {
+                None
+            };
+
+            Ok((to_fetch, checksum_map, cipher))
         };
 
-        let (to_fetch, checksums) = match task.await {
+        let (to_fetch, checksums, cipher) = match task.await {
             Ok(res) => res,
             Err(err) => {
-                error!(self.logger, "Failed to prepare checksum info: {err}");
+                error!(self.logger, "Failed to prepare the transfer: {err}");
 
                 if let Err(e) = self.on_error(ws, err).await {
                     warn!(self.logger, "Failed to send error message: {e}");
@@ -169,6 +236,12 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
         let Self {
             peer: _,
             state,
+            encrypt: _,
+            crc,
+            parallel,
+            pacing,
+            transfer_pause,
+            resume_hash,
             logger,
             alive,
         } = self;
@@ -178,7 +251,10 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             let msg_tx = msg_tx.clone();
             let logger = logger.clone();
             let xfer = xfer.clone();
-            let guard = alive.clone();
+            let guard = alive.task(
+                "server",
+                format!("Checksum requests of transfer {}", xfer.id()),
+            );
 
             async move {
                 let _guard = guard;
@@ -198,6 +274,10 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
 
         jobs.spawn(req_file_checksums);
 
+        let roots = RootMapping {
+            used_mappings: xfer.root_names().clone(),
+        };
+
         Some(HandlerLoop {
             state,
             msg_tx,
@@ -205,11 +285,20 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             jobs: HashMap::new(),
             logger,
             checksums,
+            cipher,
+            crc,
+            parallel,
+            pacing,
+            transfer_pause,
+            paused: None,
+            held: watch::channel(Held::default()).0,
+            resume_hash,
+            roots,
         })
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        tokio::time::interval(drop_config::PING_INTERVAL)
+        tokio::time::interval(self.state.ping_interval())
     }
 }
 
@@ -251,6 +340,21 @@ impl HandlerLoop<'_> {
         }
     }
 
+    async fn on_withdraw(&mut self) {
+        info!(self.logger, "On transfer withdrawal");
+
+        match self
+            .state
+            .transfer_manager
+            .incoming_withdraw(self.xfer.id())
+            .await
+        {
+            Ok(events) => events.withdrawn(true).await,
+            // The sender closes the connection anyway, so the transfer gets cancelled
+            Err(err) => warn!(self.logger, "Failed to withdraw the transfer: {err}"),
+        }
+    }
+
     async fn on_reject(&mut self, file_id: FileId) {
         info!(self.logger, "On reject file {file_id}");
 
@@ -277,6 +381,7 @@ impl HandlerLoop<'_> {
 
                 super::remove_temp_files(
                     self.logger,
+                    &self.state.config,
                     self.xfer.id(),
                     tmp_bases.into_iter().map(|base| (base, &file_id)),
                 );
@@ -288,6 +393,30 @@ impl HandlerLoop<'_> {
         }
     }
 
+    async fn on_withdraw_file(&mut self, file_id: FileId) {
+        info!(self.logger, "On withdraw file {file_id}");
+
+        let result = self
+            .state
+            .transfer_manager
+            .incoming_terminal_recv(self.xfer.id(), &file_id, FileTerminalState::Rejected)
+            .await;
+
+        // The sender does not send the file anymore
+        self.stop_task(&file_id, Status::FileRejected).await;
+
+        match result {
+            Err(err) => {
+                error!(self.logger, "Failed to handle file withdrawal: {err}");
+            }
+            Ok(Some(res)) => {
+                res.file_events.withdrawn().await;
+                super::handle_finish_xfer_state(res.xfer_state, true).await;
+            }
+            Ok(None) => (),
+        }
+    }
+
     async fn stop_task(&mut self, file_id: &FileId, status: Status) {
         if let Some(FileTask {
             job: task,
@@ -316,29 +445,128 @@ impl HandlerLoop<'_> {
         );
 
         if let Some(file_id) = file_id {
-            match self
+            self.fail_file(
+                file_id,
+                crate::Error::BadTransferState(format!("Sender reported an error: {msg}")),
+                true,
+            )
+            .await;
+        }
+    }
+
+    async fn on_expire(&mut self, file_id: FileId) {
+        info!(self.logger, "File {file_id} expired");
+        self.fail_file(file_id, crate::Error::Expired, true).await;
+    }
+
+    /// Fails a single file, leaving the rest of the transfer intact
+    async fn fail_file(&mut self, file_id: FileId, err: crate::Error, by_peer: bool) {
+        match self
+            .state
+            .transfer_manager
+            .incoming_terminal_recv(self.xfer.id(), &file_id, FileTerminalState::Failed)
+            .await
+        {
+            Err(err) => {
+                warn!(self.logger, "Failed to accept failure: {err}");
+            }
+            Ok(Some(res)) => {
+                let tmp_bases = self
+                    .state
+                    .storage
+                    .fetch_base_dirs_for_file(self.xfer.id(), file_id.as_ref())
+                    .await;
+                let partial_path =
+                    super::find_temp_file(&self.state.config, self.xfer.id(), &file_id, tmp_bases);
+
+                res.file_events.failed(err, partial_path).await;
+                super::handle_finish_xfer_state(res.xfer_state, by_peer).await;
+            }
+            Ok(None) => (),
+        }
+
+        self.stop_task(&file_id, Status::BadTransferState).await;
+    }
+
+    async fn on_add_files(
+        &mut self,
+        socket: &mut WebSocket,
+        prot::AddFiles { files, entries }: prot::AddFiles,
+    ) -> anyhow::Result<()> {
+        let file_ids: Vec<_> = files.iter().map(|file| file.id.clone()).collect();
+
+        // The roots known from the request map the same way, the new ones must
+        // not take their names
+        let mut roots = self.roots.clone();
+        let mapped = map_files(files, &mut roots)
+            .and_then(|files| Ok((files, map_entries(entries, &mut roots)?)));
+
+        let res = match mapped {
+            Ok((files, entries)) => self
                 .state
                 .transfer_manager
-                .incoming_terminal_recv(self.xfer.id(), &file_id, FileTerminalState::Failed)
+                .incoming_add_files(self.xfer.id(), files, entries, &self.state.config)
                 .await
-            {
-                Err(err) => {
-                    warn!(self.logger, "Failed to accept failure: {err}");
+                .map_err(anyhow::Error::from),
+            Err(err) => Err(err.context("Invalid files added by the sender")),
+        };
+
+        match res {
+            Ok(added) => {
+                info!(
+                    self.logger,
+                    "Sender added {} files to {}",
+                    added.files.len(),
+                    self.xfer.id()
+                );
+
+                self.roots = roots;
+                self.xfer = added.xfer.clone();
+
+                // The full checksums of the added files, the same as of the
+                // ones requested at the connection start
+                for xfile in added
+                    .files
+                    .iter()
+                    .filter_map(|id| added.xfer.files().get(id))
+                {
+                    self.checksums
+                        .insert(xfile.id().clone(), AsyncCell::shared());
+
+                    let msg = prot::ServerMsg::ReqChsum(prot::ReqChsum {
+                        file: xfile.id().clone(),
+                        limit: xfile.size(),
+                    });
+                    socket.send(Message::from(&msg)).await?;
                 }
-                Ok(Some(res)) => {
-                    res.file_events
-                        .failed(crate::Error::BadTransferState(format!(
-                            "Sender reported an error: {msg}"
-                        )))
-                        .await;
-
-                    super::handle_finish_xfer_state(res.xfer_state, true).await;
+
+                if !added.files.is_empty() {
+                    added.events.files_added(added.xfer, added.files).await;
                 }
-                Ok(None) => (),
             }
+            Err(err) => {
+                warn!(
+                    self.logger,
+                    "Failed to add the files to {}: {err:?}",
+                    self.xfer.id()
+                );
 
-            self.stop_task(&file_id, Status::BadTransferState).await;
+                // The sender already queued the files, they fail there so that
+                // both sides agree on the transfer
+                for file_id in file_ids
+                    .into_iter()
+                    .filter(|file_id| !self.xfer.files().contains_key(file_id))
+                {
+                    let msg = prot::ServerMsg::Error(prot::Error {
+                        file: Some(file_id),
+                        msg: err.to_string(),
+                    });
+                    socket.send(Message::from(&msg)).await?;
+                }
+            }
         }
+
+        Ok(())
     }
 
     async fn on_checksum(&mut self, report: prot::ReportChsum) {
@@ -349,19 +577,34 @@ impl HandlerLoop<'_> {
 
         // Full checksum requsted at the begining of the transfer
         if report.limit == xfile.size() {
-            self.checksums
-                .get(&report.file)
-                .expect("Missing file")
-                .or_set(report.checksum);
+            if let Some(cell) = self.checksums.get(&report.file) {
+                cell.or_set(report.checksum);
+            } else {
+                error!(
+                    self.logger,
+                    "Missing checksum cell for file {}, failing it", report.file
+                );
 
-            let storage = self.state.storage.clone();
-            let transfer_id = self.xfer.id();
-            let file_id = report.file.clone();
+                self.fail_file(
+                    report.file,
+                    crate::Error::BadTransferState("Missing file checksum cell".into()),
+                    false,
+                )
+                .await;
+                return;
+            }
+
+            let state = self.state.clone();
+            let logger = self.logger.clone();
+            let xfer = self.xfer.clone();
 
             tokio::spawn(async move {
-                storage
-                    .save_checksum(transfer_id, file_id.as_ref(), &report.checksum)
+                state
+                    .storage
+                    .save_checksum(xfer.id(), report.file.as_ref(), &report.checksum)
                     .await;
+
+                check_duplicate(&state, &logger, &xfer, &report.file, &report.checksum).await;
             });
         // Requests made by the download task
         } else if let Some(job) = self.jobs.get_mut(&report.file) {
@@ -415,6 +658,11 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
             csum_rx,
             full_csum: full_csum_cell,
             offset: 0,
+            cipher: self.cipher.clone(),
+            crc: self.crc,
+            parallel: self.parallel,
+            held: self.held.subscribe(),
+            resume_hash: self.resume_hash.clone(),
         };
 
         let file_id = ctx.task.file.id().clone();
@@ -454,6 +702,7 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
 
         super::remove_temp_files(
             self.logger,
+            &self.state.config,
             self.xfer.id(),
             tmp_bases.into_iter().map(|base| (base, &file_id)),
         );
@@ -476,12 +725,92 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
         Ok(())
     }
 
-    async fn issue_done(&mut self, socket: &mut WebSocket, file_id: FileId) -> anyhow::Result<()> {
+    async fn issue_pause(&mut self, socket: &mut WebSocket, paused: bool) -> anyhow::Result<()> {
+        if !self.transfer_pause {
+            if paused {
+                warn!(
+                    self.logger,
+                    "The sender does not support pausing the transfer, the files keep going"
+                );
+            }
+            return Ok(());
+        }
+        if self.paused.replace(paused) == Some(paused) {
+            return Ok(());
+        }
+
+        let msg = if paused {
+            prot::ServerMsg::Pause
+        } else {
+            prot::ServerMsg::Resume
+        };
+        socket.send(Message::from(&msg)).await?;
+        self.held.send_modify(|held| held.transfer = paused);
+
+        Ok(())
+    }
+
+    async fn issue_pace(
+        &mut self,
+        socket: &mut WebSocket,
+        file_id: FileId,
+        rate: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if !self.pacing {
+            warn!(
+                self.logger,
+                "The sender does not support pacing, file {file_id} is sent at full speed"
+            );
+            return Ok(());
+        }
+
+        let msg = prot::ServerMsg::Pace(prot::Pace {
+            file: file_id.clone(),
+            rate,
+        });
+        socket.send(Message::from(&msg)).await?;
+        self.held.send_modify(|held| {
+            if rate == Some(0) {
+                held.files.insert(file_id);
+            } else {
+                held.files.remove(&file_id);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn issue_done(
+        &mut self,
+        socket: &mut WebSocket,
+        file_id: FileId,
+        final_path: Option<PathBuf>,
+    ) -> anyhow::Result<()> {
         let file = self.xfer.files().get(&file_id).context("Invalid file")?;
 
+        let final_path = if self.state.config.report_final_paths {
+            match final_path {
+                Some(path) => Some(path.to_string_lossy().into_owned()),
+                // The file was finished before, the path is in the database
+                None => {
+                    let subpath = file.subpath().to_string();
+                    self.state
+                        .storage
+                        .finished_incoming_files(self.xfer.id())
+                        .await
+                        .into_iter()
+                        .find(|finished| finished.subpath == subpath)
+                        .map(|finished| finished.final_path)
+                }
+            }
+        } else {
+            None
+        };
+
         let msg = prot::ServerMsg::Done(prot::Done {
             file: file_id,
             bytes_transfered: file.size(),
+            final_path,
         });
         socket.send(Message::from(&msg)).await?;
         Ok(())
@@ -511,7 +840,7 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
         futures::future::join_all(tasks).await;
     }
 
-    async fn on_text_msg(&mut self, _: &mut WebSocket, text: &str) -> anyhow::Result<()> {
+    async fn on_text_msg(&mut self, ws: &mut WebSocket, text: &str) -> anyhow::Result<()> {
         let msg: prot::ClientMsg =
             serde_json::from_str(text).context("Failed to deserialize json")?;
 
@@ -520,6 +849,12 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
             prot::ClientMsg::Cancel(prot::Cancel { file }) => self.on_cancel(file).await,
             prot::ClientMsg::ReportChsum(report) => self.on_checksum(report).await,
             prot::ClientMsg::Reject(prot::Reject { file }) => self.on_reject(file).await,
+            prot::ClientMsg::Withdraw => self.on_withdraw().await,
+            prot::ClientMsg::Expire(prot::Expire { file }) => self.on_expire(file).await,
+            prot::ClientMsg::WithdrawFile(prot::WithdrawFile { file }) => {
+                self.on_withdraw_file(file).await
+            }
+            prot::ClientMsg::AddFiles(add) => self.on_add_files(ws, add).await?,
         }
         Ok(())
     }
@@ -544,6 +879,7 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
 
         super::remove_temp_files(
             self.logger,
+            &self.state.config,
             self.xfer.id(),
             files
                 .into_iter()
@@ -565,6 +901,68 @@ impl Drop for HandlerLoop<'_> {
     }
 }
 
+/// Reports the file already downloaded by another transfer and, with the
+/// `skip_duplicates` config option, rejects it so it's not downloaded again
+async fn check_duplicate(
+    state: &State,
+    logger: &slog::Logger,
+    xfer: &IncomingTransfer,
+    file_id: &FileId,
+    checksum: &[u8; 32],
+) {
+    let Some(size) = xfer.files().get(file_id).map(|file| file.size()) else {
+        return;
+    };
+
+    let mut existing = None;
+    for path in state
+        .storage
+        .completed_paths_with_checksum(checksum, size, xfer.id())
+        .await
+    {
+        let path = PathBuf::from(path);
+        if tokio::fs::metadata(&path)
+            .await
+            .is_ok_and(|meta| meta.is_file() && meta.len() == size)
+        {
+            existing = Some(path);
+            break;
+        }
+    }
+
+    let Some(existing_path) = existing else {
+        return;
+    };
+
+    info!(
+        logger,
+        "File {file_id} of transfer {} was already downloaded to {:?}",
+        xfer.id(),
+        Hidden(&existing_path)
+    );
+
+    state.emit_event(
+        logger,
+        crate::Event::IncomingFileDuplicate {
+            transfer_id: xfer.id(),
+            file_id: file_id.clone(),
+            existing_path,
+        },
+    );
+
+    if state.config.skip_duplicates {
+        if let Err(err) = service::reject_incoming(state, logger, xfer.id(), file_id).await {
+            warn!(logger, "Failed to skip the duplicate file {file_id}: {err}");
+        }
+    }
+}
+
+impl Held {
+    fn holds(&self, file_id: &FileId) -> bool {
+        self.transfer || self.files.contains(file_id)
+    }
+}
+
 impl Downloader {
     async fn send(&mut self, msg: impl Into<Message>) -> crate::Result<()> {
         self.msg_tx
@@ -588,6 +986,10 @@ impl Downloader {
 
 #[async_trait::async_trait]
 impl handler::Downloader for Downloader {
+    fn resume_hash(&self) -> HashAlg {
+        self.resume_hash.clone()
+    }
+
     async fn init(
         &mut self,
         task: &super::FileXferTask,
@@ -648,12 +1050,69 @@ impl handler::Downloader for Downloader {
         let file = if self.offset == 0 {
             fs::File::create(&path.0)?
         } else {
-            fs::File::options().append(true).open(&path.0)?
+            let mut file = fs::File::options().write(true).open(&path.0)?;
+            file.seek(io::SeekFrom::Start(self.offset))?;
+            file
         };
 
         Ok(file)
     }
 
+    fn open_chunk(&self, stream_pos: u64, data: Vec<u8>) -> crate::Result<(u64, Vec<u8>)> {
+        let (offset, data) = if self.crc {
+            prot::strip_chunk_crc(data)?
+        } else {
+            (stream_pos, data)
+        };
+
+        match &self.cipher {
+            Some(cipher) => cipher
+                .open(&self.file_id, offset, &data)
+                .map(|data| (offset, data))
+                .map_err(|err| {
+                    warn!(
+                        self.logger,
+                        "Failed to decrypt chunk of {}: {err}", self.file_id
+                    );
+                    crate::Error::ChunkDecryption
+                }),
+            None => Ok((offset, data)),
+        }
+    }
+
+    fn parallel(&self) -> bool {
+        self.parallel
+    }
+
+    fn held(&self) -> bool {
+        self.held.borrow().holds(&self.file_id)
+    }
+
+    async fn held_changed(&mut self) -> bool {
+        let file_id = &self.file_id;
+        let was = self.held.borrow_and_update().holds(file_id);
+
+        let held = self
+            .held
+            .wait_for(|held| held.holds(file_id) != was)
+            .await
+            .map(|held| held.holds(file_id));
+        match held {
+            Ok(held) => held,
+            // The connection is gone, so are the chunks
+            Err(_) => std::future::pending().await,
+        }
+    }
+
+    async fn retransmit(&mut self, range: Range<u64>) -> crate::Result<()> {
+        self.send(&prot::ServerMsg::Retransmit(prot::Retransmit {
+            file: self.file_id.clone(),
+            offset: range.start,
+            len: range.end - range.start,
+        }))
+        .await
+    }
+
     async fn progress(&mut self, bytes: u64) -> crate::Result<()> {
         self.send(&prot::ServerMsg::Progress(prot::Progress {
             file: self.file_id.clone(),
@@ -664,7 +1123,7 @@ impl handler::Downloader for Downloader {
 
     async fn validate<F, Fut>(
         &mut self,
-        path: &Hidden<PathBuf>,
+        file: fs::File,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> crate::Result<()>
@@ -672,7 +1131,6 @@ impl handler::Downloader for Downloader {
         F: FnMut(u64) -> Fut + Send + Sync,
         Fut: Future<Output = ()> + Send,
     {
-        let file = std::fs::File::open(&path.0)?;
         let csum = file::checksum(file, progress_cb, event_granularity).await?;
 
         if self.full_csum.get().await != csum {
@@ -683,28 +1141,116 @@ impl handler::Downloader for Downloader {
     }
 }
 
-impl handler::Request for (prot::TransferRequest, IpAddr, Arc<DropConfig>) {
-    fn parse(self) -> anyhow::Result<IncomingTransfer> {
-        let (prot::TransferRequest { files, id }, peer, config) = self;
+impl handler::Request for (prot::TransferRequest, SocketAddr, Arc<DropConfig>) {
+    fn id(&self) -> Uuid {
+        self.0.id
+    }
+
+    fn parse(self, known: bool) -> anyhow::Result<IncomingTransfer> {
+        let (
+            prot::TransferRequest {
+                files,
+                id,
+                entries,
+                hidden_files_skipped,
+                total_size,
+                origin_id,
+                forward_to,
+                label,
+            },
+            peer,
+            config,
+        ) = self;
+
+        if forward_to.is_some() {
+            if config.forward_dir.is_none() {
+                anyhow::bail!("Transfer forwarding is not enabled");
+            }
+            if !is_forward_peer(&config, peer.ip()) {
+                anyhow::bail!("The peer is not allowed to forward transfers");
+            }
+            // The sender asking for the forwarding is the origin of the transfer
+            if origin_id != Some(id) {
+                anyhow::bail!("Origin ID of the forwarded transfer does not match its ID");
+            }
+        }
+        if origin_id.is_some_and(|origin_id| origin_id.is_nil()) {
+            anyhow::bail!("Invalid origin ID");
+        }
+
+        let files_size = files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.size))
+            .context("The size of the files overflows")?;
+        if total_size.is_some_and(|total_size| total_size != files_size) {
+            anyhow::bail!("Declared transfer size does not match the size of the files");
+        }
+
+        let config = if known {
+            Arc::new(DropConfig {
+                transfer_file_limit: usize::MAX,
+                transfer_size_limit_bytes: None,
+                ..DropConfig::clone(&config)
+            })
+        } else {
+            config
+        };
 
-        IncomingTransfer::new_with_uuid(peer, map_files(files)?, id, &config)
-            .context("Failed to crate transfer")
+        // Checked also for the older peers not declaring the size
+        if let Some(limit) = config.transfer_size_limit_bytes {
+            if files_size > limit {
+                return Err(crate::Error::TransferLimitsExceeded).context(format!(
+                    "Transfer size of {files_size} bytes exceeds the limit of {limit}"
+                ));
+            }
+        }
+
+        let mut roots = RootMapping::default();
+        let files = map_files(files, &mut roots)?;
+        let entries = map_entries(entries, &mut roots)?;
+
+        let xfer = IncomingTransfer::new_with_uuid(peer.ip(), files, id, &config)
+            .context("Failed to crate transfer")?
+            .with_dir_entries(entries, &config)
+            .context("Failed to crate transfer")?
+            .with_root_names(roots.used_mappings)
+            .with_hidden_files_skipped(hidden_files_skipped)
+            .with_peer_scope_id(utils::scope_id(&peer))
+            .with_forwarding(origin_id.map(|origin_id| Forwarding {
+                origin_id,
+                forward_to,
+            }))
+            .with_label(label)
+            .context("Invalid transfer label")?;
+
+        Ok(xfer)
     }
 }
 
-fn map_files(files: Vec<prot::File>) -> anyhow::Result<Vec<FileToRecv>> {
-    let mut out = Vec::with_capacity(files.len());
+/// Checks whether the peer is on the `forward_peers` list of the relay
+fn is_forward_peer(config: &DropConfig, peer: IpAddr) -> bool {
+    config
+        .forward_peers
+        .iter()
+        .any(|addr| addr.parse::<IpAddr>().is_ok_and(|addr| addr == peer))
+}
 
-    let mut used_mappings = HashMap::new();
+/// Maps the root directory names received from the peer into the unique and
+/// normalized ones
+#[derive(Default, Clone)]
+struct RootMapping {
+    used_mappings: HashMap<String, String>,
+}
 
-    for prot::File { mut path, id, size } in files {
+impl RootMapping {
+    fn map(&mut self, path: &mut FileSubPath) -> anyhow::Result<()> {
         let uroot = path.root();
         let nroot = utils::normalize_filename(uroot);
 
         for nvariant in utils::filepath_variants(nroot.as_ref())?
             .filter_map(|p| p.into_os_string().into_string().ok())
         {
-            let nroot = match used_mappings.entry(nvariant) {
+            let nroot = match self.used_mappings.entry(nvariant) {
                 Entry::Occupied(occ) => {
                     if occ.get() == uroot {
                         // Good we known the root
@@ -726,19 +1272,132 @@ fn map_files(files: Vec<prot::File>) -> anyhow::Result<Vec<FileToRecv>> {
             *piter.next().context("Subpath should always contain root")? = nroot;
             piter.for_each(|s| *s = utils::normalize_filename(&*s));
 
-            out.push(FileToRecv::new(id, path, size));
             break;
         }
+
+        Ok(())
     }
+}
 
-    Ok(out)
+fn map_files(files: Vec<prot::File>, roots: &mut RootMapping) -> anyhow::Result<Vec<FileToRecv>> {
+    let mut previews_size = 0;
+
+    files
+        .into_iter()
+        .map(
+            |prot::File {
+                 mut path,
+                 id,
+                 size,
+                 metadata,
+                 preview,
+             }| {
+                validate::peer_subpath(&path)?;
+                let sanitized = path
+                    .iter()
+                    .any(|name| utils::normalize_filename(name) != *name);
+                roots.map(&mut path)?;
+                FileMetadata::check_app_metadata(&metadata.app_metadata)
+                    .context("App metadata of the file is too large")?;
+
+                let mut file = FileToRecv::new(id, path, size)
+                    .with_metadata(metadata)
+                    .with_path_sanitized(sanitized);
+                // The invalid and oversized previews are not worth failing the transfer
+                if let Some(preview) = preview
+                    .and_then(|preview| BASE64_STANDARD.decode(preview).ok())
+                    .filter(|preview| preview.len() <= MAX_PREVIEW_SIZE)
+                    .filter(|preview| previews_size + preview.len() <= MAX_TOTAL_PREVIEW_SIZE)
+                {
+                    previews_size += preview.len();
+                    file = file.with_preview(preview);
+                }
+                Ok(file)
+            },
+        )
+        .collect()
+}
+
+fn map_entries(mut entries: DirEntries, roots: &mut RootMapping) -> anyhow::Result<DirEntries> {
+    for dir in &mut entries.empty_dirs {
+        validate::peer_subpath(dir)?;
+        roots.map(dir)?;
+    }
+
+    for link in &mut entries.symlinks {
+        validate::peer_symlink(link)?;
+        roots.map(&mut link.path)?;
+    }
+
+    Ok(entries)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, Instant};
+
     use super::*;
     use crate::file::FileSubPath;
 
+    fn downloader(held: watch::Receiver<Held>) -> Downloader {
+        Downloader {
+            logger: slog::Logger::root(slog::Discard, slog::o!()),
+            file_id: FileId::from("id"),
+            msg_tx: mpsc::channel(1).0,
+            csum_rx: mpsc::channel(1).1,
+            full_csum: Arc::new(AsyncCell::new()),
+            offset: 0,
+            cipher: None,
+            crc: true,
+            parallel: true,
+            held,
+            resume_hash: HashAlg::Sha256,
+        }
+    }
+
+    #[tokio::test]
+    async fn held_file_puts_off_the_retransmit_deadline() {
+        let (chunks_tx, mut chunks) = mpsc::unbounded_channel();
+        let (held, held_rx) = watch::channel(Held {
+            files: HashSet::from([FileId::from("id")]),
+            ..Default::default()
+        });
+        let mut downloader = downloader(held_rx);
+
+        // Let go long after the deadline
+        let mut deadline = Instant::now() + Duration::from_millis(50);
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            held.send_modify(|held| {
+                held.files.clear();
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            chunks_tx.send(vec![1]).unwrap();
+            (held, chunks_tx)
+        });
+
+        let chunk = super::super::recv_until(&mut chunks, &mut downloader, &mut deadline)
+            .await
+            .unwrap();
+        assert_eq!(chunk, Some(vec![1]));
+        let (held, _chunks_tx) = sender.await.unwrap();
+
+        // Not held, the deadline passes
+        let mut deadline = Instant::now() + Duration::from_millis(50);
+        let chunk = super::super::recv_until(&mut chunks, &mut downloader, &mut deadline)
+            .await
+            .unwrap();
+        assert_eq!(chunk, None);
+
+        // The pause of the whole transfer holds the file too
+        held.send_modify(|held| held.transfer = true);
+        let mut deadline = Instant::now() + Duration::from_millis(50);
+        let wait = super::super::recv_until(&mut chunks, &mut downloader, &mut deadline);
+        assert!(tokio::time::timeout(Duration::from_millis(300), wait)
+            .await
+            .is_err());
+    }
+
     #[test]
     fn file_mapping() {
         // all good
@@ -747,23 +1406,30 @@ mod tests {
                 path: FileSubPath::from("a/b"),
                 id: FileId::from("id1"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
             prot::File {
                 path: FileSubPath::from("b"),
                 id: FileId::from("id2"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
             prot::File {
                 path: FileSubPath::from("c"),
                 id: FileId::from("id3"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
         ];
-        let output = map_files(input).unwrap();
+        let output = map_files(input, &mut RootMapping::default()).unwrap();
 
         assert_eq!(*output[0].subpath(), FileSubPath::from("a/b"));
         assert_eq!(*output[1].subpath(), FileSubPath::from("b"));
         assert_eq!(*output[2].subpath(), FileSubPath::from("c"));
+        assert!(output.iter().all(|file| !file.is_path_sanitized()));
 
         // Same root name
         let input = vec![
@@ -771,14 +1437,18 @@ mod tests {
                 path: FileSubPath::from("a/b"),
                 id: FileId::from("id1"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
             prot::File {
                 path: FileSubPath::from("a/c"),
                 id: FileId::from("id2"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
         ];
-        let output = map_files(input).unwrap();
+        let output = map_files(input, &mut RootMapping::default()).unwrap();
 
         assert_eq!(*output[0].subpath(), FileSubPath::from("a/b"));
         assert_eq!(*output[1].subpath(), FileSubPath::from("a/c"));
@@ -789,28 +1459,75 @@ mod tests {
                 path: FileSubPath::from("</a"),
                 id: FileId::from("id1"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
             prot::File {
                 path: FileSubPath::from("</b"),
                 id: FileId::from("id2"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
             prot::File {
                 path: FileSubPath::from(">/c"),
                 id: FileId::from("id3"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
             prot::File {
                 path: FileSubPath::from(">/d"),
                 id: FileId::from("id4"),
                 size: 0,
+                metadata: Default::default(),
+                preview: None,
             },
         ];
-        let output = map_files(input).unwrap();
+        let output = map_files(input, &mut RootMapping::default()).unwrap();
 
         assert_eq!(*output[0].subpath(), FileSubPath::from("_/a"));
         assert_eq!(*output[1].subpath(), FileSubPath::from("_/b"));
         assert_eq!(*output[2].subpath(), FileSubPath::from("_(1)/c"));
         assert_eq!(*output[3].subpath(), FileSubPath::from("_(1)/d"));
+        assert!(output.iter().all(|file| file.is_path_sanitized()));
+    }
+
+    #[test]
+    fn preview_mapping() {
+        let file = |id: &str, preview: Option<String>| prot::File {
+            path: FileSubPath::from(id),
+            id: FileId::from(id),
+            size: 0,
+            metadata: Default::default(),
+            preview,
+        };
+
+        let input = vec![
+            file("a", Some(BASE64_STANDARD.encode(b"thumbnail"))),
+            file("b", Some("not base64!".to_string())),
+            file(
+                "c",
+                Some(BASE64_STANDARD.encode(vec![0; MAX_PREVIEW_SIZE + 1])),
+            ),
+            file("d", None),
+        ];
+        let output = map_files(input, &mut RootMapping::default()).unwrap();
+
+        assert_eq!(output[0].preview(), Some(&b"thumbnail"[..]));
+        assert!(output[1..].iter().all(|file| file.preview().is_none()));
+
+        // The previews past the total limit are dropped
+        let count = MAX_TOTAL_PREVIEW_SIZE / MAX_PREVIEW_SIZE;
+        let input = (0..=count)
+            .map(|i| {
+                let preview = BASE64_STANDARD.encode(vec![0; MAX_PREVIEW_SIZE]);
+                file(&i.to_string(), Some(preview))
+            })
+            .collect();
+        let output = map_files(input, &mut RootMapping::default()).unwrap();
+
+        assert!(output[..count].iter().all(|file| file.preview().is_some()));
+        assert!(output[count].preview().is_none());
     }
 }
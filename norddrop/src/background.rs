@@ -0,0 +1,166 @@
+//! The hooks for the background transfers on the mobile platforms. Before the
+//! process is suspended the app persists the state from
+//! `prepare_background()`, e.g. with its background task API. After the
+//! relaunch `restore_background()` takes it back and resumes the transfers the
+//! peers still wait for. The transfers themselves are restored from the
+//! storage on start, the state carries what the storage does not, like the
+//! downloads the app started. The downloads continue from the temporary files
+//! and the uploads from the offsets the receivers ask for.
+
+use std::{
+    collections::BTreeSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use drop_transfer::{FileLiveStatus, Service};
+use serde::{Deserialize, Serialize};
+use slog::{debug, info, warn, Logger};
+use uuid::Uuid;
+
+// Bumped when the state changes in a way the older versions cannot read
+const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BackgroundState {
+    version: u32,
+    // When the state was taken, in milliseconds since the Unix epoch
+    taken_at: i64,
+    #[serde(default)]
+    downloads: Vec<Download>,
+    #[serde(default)]
+    uploads: Vec<Upload>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Download {
+    pub(crate) transfer_id: Uuid,
+    pub(crate) file_id: String,
+    // The download directory or the URI of the destination
+    pub(crate) destination: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    transfer_id: Uuid,
+    file_id: String,
+}
+
+/// Gathers the files in flight and flushes what the storage keeps in memory
+pub(crate) async fn prepare(service: &Service, logger: &Logger) -> BackgroundState {
+    let snapshot = service.snapshot().await;
+    let storage = service.storage();
+
+    let mut downloads = Vec::new();
+    for xfer in snapshot.incoming {
+        let destinations = storage.incoming_files_to_resume(xfer.transfer_id).await;
+
+        for file in xfer.files.into_iter().filter(|file| in_flight(file.status)) {
+            let Some(dest) = destinations
+                .iter()
+                .find(|dest| dest.file_id == file.file_id.as_ref())
+            else {
+                continue;
+            };
+
+            downloads.push(Download {
+                transfer_id: xfer.transfer_id,
+                file_id: file.file_id.to_string(),
+                destination: dest.base_dir.clone(),
+            });
+        }
+    }
+
+    let mut uploads = Vec::new();
+    for xfer in snapshot.outgoing {
+        uploads.extend(
+            xfer.files
+                .into_iter()
+                .filter(|file| in_flight(file.status))
+                .map(|file| Upload {
+                    transfer_id: xfer.transfer_id,
+                    file_id: file.file_id.to_string(),
+                }),
+        );
+    }
+
+    storage.flush_journal().await;
+
+    info!(
+        logger,
+        "Prepared for background, {} downloads and {} uploads in flight",
+        downloads.len(),
+        uploads.len()
+    );
+
+    BackgroundState {
+        version: STATE_VERSION,
+        taken_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as _),
+        downloads,
+        uploads,
+    }
+}
+
+impl BackgroundState {
+    pub(crate) fn parse(state: &str) -> Option<Self> {
+        let state: Self = serde_json::from_str(state).ok()?;
+        (state.version <= STATE_VERSION).then_some(state)
+    }
+}
+
+/// Resumes the downloads to the download directories and reconnects the
+/// transfers right away. Returns the IDs of the resumed transfers together
+/// with the downloads to the URIs, like the content ones, which need the
+/// destinations opened by the app again
+pub(crate) async fn restore(
+    service: &mut Service,
+    logger: &Logger,
+    state: BackgroundState,
+) -> (BTreeSet<Uuid>, Vec<Download>) {
+    let mut resumed = BTreeSet::new();
+    let mut by_uri = Vec::new();
+
+    for download in state.downloads {
+        if drop_transfer::utils::is_uri(&download.destination) {
+            by_uri.push(download);
+            continue;
+        }
+
+        debug!(logger, "Resuming download of {}", download.file_id);
+
+        // The downloads restored from the storage are already in flight and
+        // skipped
+        let files = [(download.file_id.into(), download.destination)];
+        match service.download_many(download.transfer_id, &files).await {
+            Ok(()) => {
+                resumed.insert(download.transfer_id);
+            }
+            Err(err) => warn!(
+                logger,
+                "Not resuming a download of {}: {err}", download.transfer_id
+            ),
+        }
+    }
+
+    for upload in state.uploads {
+        if !service.is_outgoing_alive(upload.transfer_id).await {
+            continue;
+        }
+
+        debug!(logger, "Resuming upload of {}", upload.file_id);
+
+        // Fails when the transfer is already connected, which is fine
+        let _ = service.retry_transfer(upload.transfer_id).await;
+        resumed.insert(upload.transfer_id);
+    }
+
+    // The incoming transfers reconnect right away too
+    service.network_refresh();
+
+    (resumed, by_uri)
+}
+
+fn in_flight(status: FileLiveStatus) -> bool {
+    matches!(status, FileLiveStatus::Pending | FileLiveStatus::InFlight)
+}
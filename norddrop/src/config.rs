@@ -10,7 +10,61 @@ pub struct Config {
     pub checksum_events_size_threshold: Option<u64>,
     pub checksum_events_granularity: Option<u64>,
     pub connection_retries: Option<u32>,
+    pub retry_base_delay_ms: Option<u32>,
+    pub retry_max_delay_ms: Option<u32>,
+    pub retry_backoff: Option<crate::RetryBackoff>,
+    pub retry_jitter: Option<bool>,
     pub auto_retry_interval_ms: Option<u32>,
+    pub prefer_ipv6: Option<bool>,
+    pub port: Option<u16>,
+    pub low_power_mode: Option<bool>,
+    pub relay_addr: Option<String>,
+    pub relay_token: Option<String>,
+    pub relay_after_retries: Option<u32>,
+    pub tls: Option<bool>,
+    pub chunk_encryption: Option<bool>,
+    pub duplicate_transfer_window_ms: Option<u32>,
+    pub preserve_metadata: Option<bool>,
+    pub transfer_empty_dirs: Option<bool>,
+    pub transfer_symlinks: Option<bool>,
+    pub report_final_paths: Option<bool>,
+    pub skip_hidden_files: Option<bool>,
+    pub transfer_size_limit_bytes: Option<u64>,
+    pub verify_writes: Option<bool>,
+    pub chunk_crc: Option<bool>,
+    pub metrics_addr: Option<String>,
+    pub read_only: Option<bool>,
+    pub peer_key_ttl_ms: Option<u32>,
+    pub create_dir_skeleton: Option<bool>,
+    pub partial_dir: Option<String>,
+    pub partial_file_extension: Option<String>,
+    pub forward_dir: Option<String>,
+    pub forward_peers: Option<Vec<String>>,
+    pub partial_gc_age_ms: Option<u64>,
+    pub download_roots: Option<Vec<String>>,
+    pub inbox_dir: Option<String>,
+    pub inbox_quota_bytes: Option<u64>,
+    pub pipeline_timing: Option<bool>,
+    pub idempotent_transfers: Option<bool>,
+    pub short_transfer_ids: Option<bool>,
+    pub skip_duplicates: Option<bool>,
+    pub transfer_preflight: Option<bool>,
+    pub event_files_limit: Option<u32>,
+    pub checksum_threads: Option<u32>,
+    pub peer_quota_bytes: Option<u64>,
+    pub peer_quota_window_ms: Option<u64>,
+    pub max_peers: Option<u32>,
+    pub max_transfers_per_peer: Option<u32>,
+    pub chunk_size: Option<u32>,
+    pub adaptive_chunk_size: Option<bool>,
+    pub upload_buffer_bytes: Option<u64>,
+    pub parallel_ranges: Option<u32>,
+    pub shared_connections: Option<bool>,
+    pub connection_keep_alive_ms: Option<u64>,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub transfer_mode: Option<crate::TransferMode>,
+    pub event_signing_key: Option<String>,
 }
 
 impl Config {
@@ -21,6 +75,14 @@ impl Config {
     const fn default_checksum_granularity() -> u32 {
         256 * 1024
     }
+
+    const fn default_relay_after_retries() -> u32 {
+        3
+    }
+
+    const fn default_duplicate_transfer_window_ms() -> u32 {
+        2000
+    }
 }
 
 impl From<Config> for drop_config::Config {
@@ -34,7 +96,61 @@ impl From<Config> for drop_config::Config {
             checksum_events_size_threshold,
             checksum_events_granularity,
             connection_retries,
+            retry_base_delay_ms,
+            retry_max_delay_ms,
+            retry_backoff,
+            retry_jitter,
             auto_retry_interval_ms,
+            prefer_ipv6,
+            port,
+            low_power_mode,
+            relay_addr,
+            relay_token,
+            relay_after_retries,
+            tls,
+            chunk_encryption,
+            duplicate_transfer_window_ms,
+            preserve_metadata,
+            transfer_empty_dirs,
+            transfer_symlinks,
+            report_final_paths,
+            skip_hidden_files,
+            transfer_size_limit_bytes,
+            verify_writes,
+            chunk_crc,
+            metrics_addr,
+            read_only,
+            peer_key_ttl_ms,
+            create_dir_skeleton,
+            partial_dir,
+            partial_file_extension,
+            forward_dir,
+            forward_peers,
+            partial_gc_age_ms,
+            download_roots,
+            inbox_dir,
+            inbox_quota_bytes,
+            pipeline_timing,
+            idempotent_transfers,
+            short_transfer_ids,
+            skip_duplicates,
+            transfer_preflight,
+            event_files_limit,
+            checksum_threads,
+            peer_quota_bytes,
+            peer_quota_window_ms,
+            max_peers,
+            max_transfers_per_peer,
+            chunk_size,
+            adaptive_chunk_size,
+            upload_buffer_bytes,
+            parallel_ranges,
+            shared_connections,
+            connection_keep_alive_ms,
+            device_name,
+            platform,
+            transfer_mode,
+            event_signing_key,
         } = val;
 
         drop_config::Config {
@@ -47,8 +163,84 @@ impl From<Config> for drop_config::Config {
                     .unwrap_or(Config::default_checksum_granularity() as _),
                 connection_retries: connection_retries
                     .unwrap_or(Config::default_connection_retries()),
+                retry_policy: drop_config::RetryPolicy {
+                    base_delay: retry_base_delay_ms.map_or(drop_config::FIRST_RETRY_AFTER, |ms| {
+                        Duration::from_millis(ms as _)
+                    }),
+                    max_delay: retry_max_delay_ms.map_or(drop_config::MAX_RETRY_DELAY, |ms| {
+                        Duration::from_millis(ms as _)
+                    }),
+                    backoff: retry_backoff.unwrap_or(drop_config::RetryBackoff::Exponential),
+                    jitter: retry_jitter.unwrap_or(false),
+                },
                 auto_retry_interval: auto_retry_interval_ms
                     .map(|ms| Duration::from_millis(ms as _)),
+                prefer_ipv6: prefer_ipv6.unwrap_or(false),
+                port: port.unwrap_or(drop_config::DEFAULT_PORT),
+                low_power_mode: low_power_mode.unwrap_or(false),
+                relay: relay_addr.map(|addr| drop_config::RelayConfig {
+                    addr,
+                    token: relay_token.unwrap_or_default(),
+                    after_retries: relay_after_retries
+                        .unwrap_or(Config::default_relay_after_retries()),
+                }),
+                tls: tls.unwrap_or(false),
+                chunk_encryption: chunk_encryption.unwrap_or(false),
+                duplicate_transfer_window: Duration::from_millis(
+                    duplicate_transfer_window_ms
+                        .unwrap_or(Config::default_duplicate_transfer_window_ms())
+                        as _,
+                ),
+                preserve_metadata: preserve_metadata.unwrap_or(false),
+                transfer_empty_dirs: transfer_empty_dirs.unwrap_or(false),
+                transfer_symlinks: transfer_symlinks.unwrap_or(false),
+                report_final_paths: report_final_paths.unwrap_or(false),
+                skip_hidden_files: skip_hidden_files.unwrap_or(false),
+                transfer_size_limit_bytes,
+                verify_writes: verify_writes.unwrap_or(false),
+                chunk_crc: chunk_crc.unwrap_or(false),
+                metrics_addr,
+                read_only: read_only.unwrap_or(false),
+                peer_key_ttl: peer_key_ttl_ms.map(|ms| Duration::from_millis(ms as _)),
+                create_dir_skeleton: create_dir_skeleton.unwrap_or(false),
+                partial_dir,
+                partial_file_extension: partial_file_extension
+                    .unwrap_or_else(|| drop_config::DEFAULT_PARTIAL_FILE_EXTENSION.to_string()),
+                forward_dir,
+                forward_peers: forward_peers.unwrap_or_default(),
+                partial_gc_age: partial_gc_age_ms.map(Duration::from_millis),
+                download_roots: download_roots.unwrap_or_default(),
+                inbox_dir,
+                inbox_quota_bytes,
+                pipeline_timing: pipeline_timing.unwrap_or(false),
+                idempotent_transfers: idempotent_transfers.unwrap_or(false),
+                short_transfer_ids: short_transfer_ids.unwrap_or(false),
+                skip_duplicates: skip_duplicates.unwrap_or(false),
+                transfer_preflight: transfer_preflight.unwrap_or(false),
+                event_files_limit: event_files_limit.map(|limit| limit as _),
+                checksum_threads: checksum_threads.map(|threads| threads as _),
+                peer_quota_bytes,
+                peer_quota_window: peer_quota_window_ms.map_or(
+                    drop_config::DEFAULT_PEER_QUOTA_WINDOW,
+                    Duration::from_millis,
+                ),
+                max_peers: max_peers.map(|max| max as _),
+                max_transfers_per_peer: max_transfers_per_peer.map(|max| max as _),
+                chunk_size: chunk_size.map_or(drop_config::DEFAULT_CHUNK_SIZE, |size| {
+                    (size as usize).clamp(drop_config::MIN_CHUNK_SIZE, drop_config::MAX_CHUNK_SIZE)
+                }),
+                adaptive_chunk_size: adaptive_chunk_size.unwrap_or(false),
+                upload_buffer_bytes: upload_buffer_bytes
+                    .map_or(drop_config::DEFAULT_UPLOAD_BUFFER_BYTES, |bytes| bytes as _),
+                parallel_ranges: parallel_ranges.map_or(1, |ranges| {
+                    (ranges as usize).clamp(1, drop_config::MAX_PARALLEL_RANGES)
+                }),
+                shared_connections: shared_connections.unwrap_or(false),
+                connection_keep_alive: connection_keep_alive_ms.map(Duration::from_millis),
+                device_name,
+                platform,
+                transfer_mode: transfer_mode.unwrap_or(drop_config::TransferMode::Both),
+                event_signing_key,
             },
             moose: drop_config::MooseConfig {
                 event_path: moose_event_path,
@@ -0,0 +1,190 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Notify;
+
+use crate::{Event, EventKind};
+
+/// The number of the events waiting for the callback above which the
+/// progress events are dropped
+pub(crate) const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// The processed events waiting for the host app's callback. It decouples the
+/// callback from the processing, so a slow or stalled callback never blocks
+/// the service. The progress events arriving while the queue is full are
+/// dropped and counted with the `EventsDropped` event put in their place, the
+/// other events are always queued. The dropped events stay in the journal and
+/// `EventsDropped` carries the sequence number of the last of them
+#[derive(Default)]
+pub(crate) struct DeliveryQueue {
+    inner: Mutex<Inner>,
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct Inner {
+    events: VecDeque<Event>,
+    closed: bool,
+}
+
+impl DeliveryQueue {
+    pub(crate) fn push(&self, event: Event) {
+        let mut inner = self.inner.lock().expect("Poisoned lock");
+
+        if inner.events.len() >= EVENT_QUEUE_CAPACITY && event.kind.is_progress() {
+            match inner.events.back_mut() {
+                Some(Event {
+                    seq,
+                    timestamp,
+                    kind: EventKind::EventsDropped { count },
+                    ..
+                }) => {
+                    *count += 1;
+                    *seq = event.seq;
+                    *timestamp = event.timestamp;
+                }
+                _ => inner.events.push_back(Event {
+                    seq: event.seq,
+                    timestamp: event.timestamp,
+                    ..EventKind::EventsDropped { count: 1 }.into()
+                }),
+            }
+        } else {
+            inner.events.push_back(event);
+        }
+
+        drop(inner);
+        self.ready.notify_one();
+    }
+
+    /// Lets the consumer finish once the queued events are delivered
+    pub(crate) fn close(&self) {
+        self.inner.lock().expect("Poisoned lock").closed = true;
+        self.ready.notify_one();
+    }
+
+    /// The next event together with the number of the events left, `None`
+    /// once the queue is closed and empty
+    pub(crate) async fn pop(&self) -> Option<(Event, usize)> {
+        loop {
+            {
+                let mut inner = self.inner.lock().expect("Poisoned lock");
+                if let Some(event) = inner.events.pop_front() {
+                    return Some((event, inner.events.len()));
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// How late the event is delivered
+pub(crate) fn lag(event: &Event) -> Duration {
+    let emitted = UNIX_EPOCH + Duration::from_millis(event.timestamp.max(0) as _);
+    SystemTime::now()
+        .duration_since(emitted)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(seq: u64, kind: EventKind) -> Event {
+        Event { seq, ..kind.into() }
+    }
+
+    fn progress(seq: u64) -> Event {
+        event(
+            seq,
+            EventKind::FileProgress {
+                transfer_id: "t".to_string(),
+                file_id: "f".to_string(),
+                transferred: seq,
+            },
+        )
+    }
+
+    fn text_sent(seq: u64) -> Event {
+        event(
+            seq,
+            EventKind::TextSent {
+                message_id: seq.to_string(),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn delivers_in_order_until_closed() {
+        let queue = DeliveryQueue::default();
+        queue.push(text_sent(1));
+        queue.push(progress(2));
+        queue.close();
+
+        let (e, pending) = queue.pop().await.unwrap();
+        assert_eq!((e.seq, pending), (1, 1));
+        let (e, pending) = queue.pop().await.unwrap();
+        assert_eq!((e.seq, pending), (2, 0));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_next_event() {
+        let queue = std::sync::Arc::new(DeliveryQueue::default());
+        let consumer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await.map(|(e, _)| e.seq) }
+        });
+
+        tokio::task::yield_now().await;
+        queue.push(text_sent(7));
+        assert_eq!(consumer.await.unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn drops_progress_when_full() {
+        let queue = DeliveryQueue::default();
+        for seq in 1..=EVENT_QUEUE_CAPACITY as u64 {
+            queue.push(text_sent(seq));
+        }
+
+        let next = EVENT_QUEUE_CAPACITY as u64 + 1;
+        queue.push(progress(next));
+        queue.push(progress(next + 1));
+        // Never dropped
+        queue.push(text_sent(next + 2));
+        queue.push(progress(next + 3));
+        queue.close();
+
+        let mut events = Vec::new();
+        while let Some((e, _)) = queue.pop().await {
+            events.push(e);
+        }
+        assert_eq!(events.len(), EVENT_QUEUE_CAPACITY + 3);
+
+        let tail = &events[EVENT_QUEUE_CAPACITY..];
+        assert!(matches!(
+            tail[0].kind,
+            EventKind::EventsDropped { count: 2 }
+        ));
+        assert_eq!(tail[0].seq, next + 1);
+        assert!(matches!(tail[1].kind, EventKind::TextSent { .. }));
+        assert_eq!(tail[1].seq, next + 2);
+        assert!(matches!(
+            tail[2].kind,
+            EventKind::EventsDropped { count: 1 }
+        ));
+        assert_eq!(tail[2].seq, next + 3);
+
+        // The sequence numbers keep growing, so the dropped events can be
+        // fetched from the journal after the last delivered one
+        assert!(events.windows(2).all(|w| w[0].seq < w[1].seq));
+    }
+}
@@ -1,26 +1,52 @@
 use std::{
-    net::{IpAddr, ToSocketAddrs},
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::Path,
     sync::Arc,
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
 };
 
 use drop_analytics::DeveloperExceptionEventData;
 use drop_auth::{PublicKey, SecretKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
 use drop_config::{Config, DropConfig, MooseConfig};
 use drop_storage::types::Transfer as TransferInfo;
-use drop_transfer::{auth, utils::Hidden, Event, FileToSend, OutgoingTransfer, Service, Transfer};
+use drop_transfer::{
+    auth,
+    file::{
+        DirEntries, Exclude, FileMetadata, MAX_APP_METADATA_SIZE, MAX_PREVIEW_SIZE,
+        MAX_TOTAL_PREVIEW_SIZE,
+    },
+    utils::Hidden,
+    ConnectionDecision, Event, File as _, FileToSend, IncomingDecision, IncomingTransfer,
+    OutgoingTransfer, Service, Transfer,
+};
+use sha2::{Digest, Sha256};
 use slog::{debug, error, trace, warn, Logger};
 use tokio::{
     sync::{mpsc, Mutex},
     task::JoinHandle,
 };
 
-use crate::{event, KeyStore, TransferDescriptor};
+#[cfg(unix)]
+use crate::lifecycle::OpenFiles;
+use crate::{
+    background::{self, BackgroundState},
+    delivery::{self, DeliveryQueue},
+    event, KeyStore, TransferDescriptor, TransferOptions,
+};
 
 pub type Result<T = ()> = std::result::Result<T, crate::LibdropError>;
 
+// Produces the preview of the file given its URI and MIME type
+type PreviewProvider = dyn Fn(&str, &str) -> Option<Vec<u8>> + Send + Sync;
+
 const SQLITE_TIMESTAMP_MIN: i64 = -210866760000;
 const SQLITE_TIMESTAMP_MAX: i64 = 253402300799;
+// The event delivery delay after which the app is considered a slow consumer
+const SLOW_CONSUMER_LAG: Duration = Duration::from_secs(1);
+// The transfers whose labels are kept in memory, the labels of the others are
+// looked up again
+const MAX_CACHED_LABELS: usize = 256;
 
 pub(super) struct NordDropFFI {
     rt: tokio::runtime::Runtime,
@@ -28,25 +54,258 @@ pub(super) struct NordDropFFI {
     instance: Arc<Mutex<Option<ServiceData>>>,
     event_dispatcher: EventDispatcher,
     keys: Arc<auth::Context>,
+    key_store: Arc<dyn KeyStore>,
     config: DropConfig,
+    conn_policy: Option<Arc<drop_transfer::ConnectionPolicy>>,
+    incoming_filter: Option<Arc<drop_transfer::IncomingFilter>>,
+    preview_provider: Option<Arc<PreviewProvider>>,
+    recent_transfers: HashMap<TransferKey, (Instant, uuid::Uuid)>,
     #[cfg(unix)]
     fdresolv: Option<Arc<drop_transfer::file::FdResolver>>,
+    #[cfg(unix)]
+    content_resolver: Option<Arc<dyn crate::ContentResolver>>,
+    #[cfg(unix)]
+    open_files: Option<Arc<OpenFiles>>,
+}
+
+type FileKey = (
+    String,
+    Option<String>,
+    Option<Vec<String>>,
+    Option<BTreeMap<String, String>>,
+);
+
+/// Identifies the transfers with the same peer and files, used to detect
+/// duplicate `new_transfer()` calls. The file descriptors themselves are not
+/// part of it since the app opens a new one on each call.
+#[derive(PartialEq, Eq, Hash, serde::Serialize)]
+struct TransferKey {
+    peer: SocketAddr,
+    peer_port: Option<u16>,
+    // The path or file name, content URI, exclude patterns and app metadata
+    files: Vec<FileKey>,
+}
+
+impl TransferKey {
+    fn new(peer: SocketAddr, peer_port: Option<u16>, descriptors: &[TransferDescriptor]) -> Self {
+        let files = descriptors
+            .iter()
+            .map(|desc| {
+                let metadata = desc
+                    .app_metadata()
+                    .map(|metadata| metadata.clone().into_iter().collect());
+
+                match desc {
+                    TransferDescriptor::Path { path, exclude, .. } => {
+                        (path.clone(), None, exclude.clone(), metadata)
+                    }
+                    TransferDescriptor::Fd {
+                        filename,
+                        content_uri,
+                        ..
+                    } => (filename.clone(), Some(content_uri.clone()), None, metadata),
+                    TransferDescriptor::ContentUri { content_uri, .. } => (
+                        content_uri.clone(),
+                        Some(content_uri.clone()),
+                        None,
+                        metadata,
+                    ),
+                }
+            })
+            .collect();
+
+        Self {
+            peer,
+            peer_port,
+            files,
+        }
+    }
+
+    /// The digest kept by the transfer created from this key. Unlike the
+    /// `Hash` one it is stable across the restarts and does not collide
+    fn digest(&self) -> Option<String> {
+        let key = serde_json::to_vec(self).ok()?;
+        Some(format!("{:x}", Sha256::digest(key)))
+    }
 }
 
-struct ServiceData {
-    service: drop_transfer::Service,
+pub(crate) struct ServiceData {
+    pub(crate) service: drop_transfer::Service,
     event_task: JoinHandle<()>,
+    event_backlog: Arc<std::sync::Mutex<EventBacklog>>,
+    session: Arc<std::sync::Mutex<drop_session::Session>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Arc<crate::metrics::MetricsCollector>,
+    #[cfg(feature = "metrics")]
+    metrics_task: Option<JoinHandle<()>>,
+}
+
+/// Tracks how far the event callback lags behind the emitted events
+#[derive(Default)]
+struct EventBacklog {
+    pending: usize,
+    lag: Duration,
+    max_lag: Duration,
+    dropped: u64,
+    slow: bool,
+}
+
+impl EventBacklog {
+    fn record(&mut self, logger: &Logger, pending: usize, lag: Duration) {
+        self.pending = pending;
+        self.lag = lag;
+        self.max_lag = self.max_lag.max(lag);
+
+        let slow = lag >= SLOW_CONSUMER_LAG;
+        if slow && !self.slow {
+            warn!(
+                logger,
+                "Event consumer is slow, events are delivered {}ms late, {pending} pending",
+                lag.as_millis()
+            );
+        } else if !slow && self.slow {
+            debug!(logger, "Event consumer caught up");
+        }
+        self.slow = slow;
+    }
+
+    fn stats(&self) -> crate::EventQueueStats {
+        crate::EventQueueStats {
+            pending_events: self.pending as _,
+            lag_ms: self.lag.as_millis() as _,
+            max_lag_ms: self.max_lag.as_millis() as _,
+            dropped_events: self.dropped,
+            slow_consumer: self.slow,
+        }
+    }
 }
 
 #[derive(Clone)]
 struct EventDispatcher {
     cb: Arc<dyn Fn(crate::Event) + Send + Sync>,
+    signing_key: Option<Arc<[u8]>>,
+    logger: Logger,
 }
 
 impl EventDispatcher {
     fn dispatch(&self, e: impl Into<crate::Event>) {
-        (self.cb)(e.into());
+        let mut event = e.into();
+        self.sign(&mut event);
+        (self.cb)(event);
+    }
+
+    fn sign(&self, event: &mut crate::Event) {
+        if let Some(key) = &self.signing_key {
+            if let Err(err) = event.sign(key) {
+                warn!(self.logger, "Failed to serialize event for signing: {err}");
+            }
+        }
+    }
+
+    async fn dispatch_journaled(
+        &self,
+        storage: &drop_storage::Storage,
+        logger: &Logger,
+        e: impl Into<crate::Event>,
+    ) {
+        let event = journal_event(storage, logger, e).await;
+        self.dispatch(event);
+    }
+}
+
+/// The labels of the transfers the events relate to, looked up in the storage
+/// once per transfer. Up to `MAX_CACHED_LABELS` of the transfers are kept, the
+/// least recently looked up ones are evicted first
+#[derive(Default)]
+struct TransferLabels {
+    labels: HashMap<uuid::Uuid, Option<String>>,
+    // The cached transfers, the least recently looked up first
+    order: VecDeque<uuid::Uuid>,
+}
+
+impl TransferLabels {
+    async fn lookup(&mut self, storage: &drop_storage::Storage, event: &Event) -> Option<String> {
+        let transfer_id = event.transfer_id()?;
+
+        let label = match self.labels.get(&transfer_id) {
+            Some(label) => {
+                let label = label.clone();
+                self.unlink(&transfer_id);
+                self.order.push_back(transfer_id);
+                label
+            }
+            None => {
+                let label = storage.transfer_label(transfer_id).await;
+                if self.order.len() >= MAX_CACHED_LABELS {
+                    if let Some(evicted) = self.order.pop_front() {
+                        self.labels.remove(&evicted);
+                    }
+                }
+                self.labels.insert(transfer_id, label.clone());
+                self.order.push_back(transfer_id);
+                label
+            }
+        };
+
+        // No more transfer events follow, except the late file ones, which
+        // look the label up again
+        if matches!(
+            event,
+            Event::IncomingTransferCanceled(..)
+                | Event::OutgoingTransferCanceled(..)
+                | Event::IncomingTransferWithdrawn(..)
+                | Event::OutgoingTransferWithdrawn(..)
+                | Event::OutgoingTransferFailed(..)
+                | Event::IncomingTransferRejected { .. }
+        ) {
+            self.forget(&transfer_id);
+        }
+
+        label
+    }
+
+    fn forget(&mut self, transfer_id: &uuid::Uuid) {
+        if self.labels.remove(transfer_id).is_some() {
+            self.unlink(transfer_id);
+        }
+    }
+
+    // The events mostly relate to the recent transfers, searched from the back
+    fn unlink(&mut self, transfer_id: &uuid::Uuid) {
+        if let Some(pos) = self.order.iter().rposition(|id| id == transfer_id) {
+            self.order.remove(pos);
+        }
+    }
+}
+
+/// Persists the event in the journal and assigns it the sequence number. In
+/// case of a DB failure the event is still returned but with `seq` equal to 0.
+async fn journal_event(
+    storage: &drop_storage::Storage,
+    logger: &Logger,
+    e: impl Into<crate::Event>,
+) -> crate::Event {
+    let mut event = e.into();
+
+    match event.journal_payload() {
+        Ok(payload) => {
+            // The progress is written in batches, the next events supersede it
+            let seq = if event.kind.is_progress() {
+                storage.queue_journal_event(event.timestamp, &payload).await
+            } else {
+                storage
+                    .insert_journal_event(event.timestamp, &payload)
+                    .await
+            };
+
+            if let Some(seq) = seq {
+                event.seq = seq as _;
+            }
+        }
+        Err(err) => warn!(logger, "Failed to serialize event for the journal: {err}"),
     }
+
+    event
 }
 
 impl NordDropFFI {
@@ -57,37 +316,61 @@ impl NordDropFFI {
     ) -> Result<Self> {
         trace!(logger, "norddrop_new()");
 
+        crate::spans::install();
+
         Ok(NordDropFFI {
             instance: Arc::default(),
             logger: logger.clone(),
             rt: tokio::runtime::Runtime::new().map_err(|_| crate::LibdropError::Unknown)?,
             event_dispatcher: EventDispatcher {
                 cb: Arc::new(event_cb) as _,
+                signing_key: None,
+                logger: logger.clone(),
             },
             config: DropConfig::default(),
-            keys: Arc::new(create_key_context(logger, key_store)),
+            keys: Arc::new(create_key_context(logger, key_store.clone())),
+            key_store,
+            conn_policy: None,
+            incoming_filter: None,
+            preview_provider: None,
+            recent_transfers: HashMap::new(),
             #[cfg(unix)]
             fdresolv: None,
+            #[cfg(unix)]
+            content_resolver: None,
+            #[cfg(unix)]
+            open_files: None,
         })
     }
 
-    pub(super) fn start(&mut self, listen_addr: &str, config: Config) -> Result<()> {
+    pub(super) fn start(&mut self, listen_addrs: &[String], config: Config) -> Result<()> {
         let init_time = std::time::Instant::now();
         trace!(
             self.logger,
-            "norddrop_start() listen address: {:?}",
-            listen_addr,
+            "norddrop_start() listen addresses: {:?}",
+            listen_addrs,
         );
 
         // Check preconditions first
         validate_config(&self.logger, &config)?;
-        let addr: IpAddr = match listen_addr.parse() {
-            Ok(addr) => addr,
-            Err(err) => {
-                error!(self.logger, "Failed to parse IP address: {err}");
-                return Err(crate::LibdropError::BadInput);
+        if listen_addrs.is_empty() {
+            error!(self.logger, "At least one listen address is required");
+            return Err(crate::LibdropError::BadInput);
+        }
+
+        let mut addrs: Vec<IpAddr> = Vec::with_capacity(listen_addrs.len());
+        for listen_addr in listen_addrs {
+            match listen_addr.parse() {
+                Ok(addr) if addrs.contains(&addr) => {
+                    warn!(self.logger, "Duplicate listen address: {addr}");
+                }
+                Ok(addr) => addrs.push(addr),
+                Err(err) => {
+                    error!(self.logger, "Failed to parse IP address: {err}");
+                    return Err(crate::LibdropError::BadInput);
+                }
             }
-        };
+        }
 
         let mut instance = self.instance.blocking_lock();
         if instance.is_some() {
@@ -96,10 +379,48 @@ impl NordDropFFI {
 
         // All good, let's proceed
 
+        self.keys.set_key_cache_ttl(config.drop.peer_key_ttl);
+        self.event_dispatcher.signing_key = config
+            .drop
+            .event_signing_key
+            .as_deref()
+            .map(|key| Arc::from(key.as_bytes()));
+
+        #[cfg(feature = "metrics")]
+        let metrics_task = match config.drop.metrics_addr.as_deref() {
+            Some(addr) => {
+                let addr = addr.parse().map_err(|_| crate::LibdropError::BadInput)?;
+
+                let _rt = self.rt.enter();
+                let server =
+                    crate::metrics::serve(addr, self.instance.clone(), self.logger.clone())
+                        .map_err(|_| crate::LibdropError::AddrInUse)?;
+                Some(self.rt.spawn(server))
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "metrics"))]
+        if config.drop.metrics_addr.is_some() {
+            warn!(
+                self.logger,
+                "The library is built without the metrics support, not serving the metrics"
+            );
+        }
+
         let moose = initialize_moose(&self.logger, config.moose)?;
 
+        let storage_path =
+            drop_storage::path::resolve(&config.drop.storage_path).map_err(|err| {
+                error!(
+                    self.logger,
+                    "Failed to resolve the storage path \"{}\": {err}", config.drop.storage_path
+                );
+                crate::LibdropError::DbError
+            })?;
+        debug!(self.logger, "Using the storage at \"{storage_path}\"");
+
         let storage = Arc::new(open_database(
-            &config.drop.storage_path,
+            &storage_path,
             &self.event_dispatcher,
             &self.logger,
             &moose,
@@ -110,25 +431,108 @@ impl NordDropFFI {
         let ed = self.event_dispatcher.clone();
         let event_logger = self.logger.clone();
         let event_storage = storage.clone();
+        let event_files_limit = config.drop.event_files_limit;
         let (tx, mut rx) = mpsc::unbounded_channel::<(Event, SystemTime)>();
+        let event_backlog = Arc::new(std::sync::Mutex::new(EventBacklog::default()));
+        let backlog = event_backlog.clone();
+        let session = Arc::new(std::sync::Mutex::new(drop_session::Session::default()));
+        let event_session = session.clone();
+        #[cfg(feature = "metrics")]
+        let metrics = Arc::new(crate::metrics::MetricsCollector::default());
+        #[cfg(feature = "metrics")]
+        let collector = metrics.clone();
+
+        #[cfg(unix)]
+        let content_resolver = self.content_resolver.clone();
+        #[cfg(unix)]
+        let open_files = self.open_files.clone();
+
+        // The callback is called from its own task, the processing of the
+        // events does not wait for it
+        let delivery = Arc::new(DeliveryQueue::default());
+        let deliver_events = {
+            let delivery = delivery.clone();
+            let event_logger = event_logger.clone();
+
+            async move {
+                while let Some((e, pending)) = delivery.pop().await {
+                    let lag = delivery::lag(&e);
+                    {
+                        let mut backlog = backlog.lock().expect("Poisoned lock");
+                        backlog.record(&event_logger, pending, lag);
+                        if let crate::EventKind::EventsDropped { count } = &e.kind {
+                            backlog.dropped += count;
+                            warn!(
+                                event_logger,
+                                "Event consumer is stalled, dropped {count} events"
+                            );
+                        }
+                    }
+
+                    // Android team reported problems with the event ordering.
+                    // The events where dispatched in different order than where emitted.
+                    // To fix that we need to process the events sequentially.
+                    // Also the callback may block the executor - we need to be resistant to that.
+                    tokio::task::block_in_place(|| ed.dispatch(e));
+
+                    if pending == 0 {
+                        backlog.lock().expect("Poisoned lock").record(
+                            &event_logger,
+                            0,
+                            Duration::ZERO,
+                        );
+                    }
+                }
+            }
+        };
 
         let event_task = self.rt.spawn(async move {
+            let deliver_task = tokio::spawn(deliver_events);
+            let flush_task = tokio::spawn({
+                let storage = event_storage.clone();
+                async move { storage.flush_journal_periodically().await }
+            });
             let mut dispatch = drop_transfer::StorageDispatch::new(&event_storage);
+            let mut labels = TransferLabels::default();
 
             while let Some(e) = rx.recv().await {
                 debug!(event_logger, "emitting event: {:#?}", e);
 
                 dispatch.handle_event(&e.0).await;
-                // Android team reported problems with the event ordering.
-                // The events where dispatched in different order than where emitted.
-                // To fix that we need to process the events sequentially.
-                // Also the callback may block the executor - we need to be resistant to that.
-                tokio::task::block_in_place(|| ed.dispatch(e));
+                event_session
+                    .lock()
+                    .expect("Poisoned lock")
+                    .handle_event(&e.0);
+                #[cfg(feature = "metrics")]
+                collector.record_event(&e.0);
+                #[cfg(unix)]
+                if let Some(resolver) = &content_resolver {
+                    for uri in finished_content_uris(&e.0) {
+                        tokio::task::block_in_place(|| resolver.on_persist(uri, false));
+                    }
+                }
+                #[cfg(unix)]
+                if let Some(open_files) = &open_files {
+                    tokio::task::block_in_place(|| open_files.handle_event(&e.0));
+                }
+                let label = labels.lookup(&event_storage, &e.0).await;
+                let mut e = crate::Event::from(e);
+                e.label = label;
+                if let Some(limit) = event_files_limit {
+                    e.kind.omit_files(limit);
+                }
+                let e = journal_event(&event_storage, &event_logger, e).await;
+                delivery.push(e);
             }
+
+            flush_task.abort();
+            event_storage.flush_journal().await;
+            delivery.close();
+            let _ = deliver_task.await;
         });
 
         match self.rt.block_on(Service::start(
-            addr,
+            addrs,
             storage,
             tx,
             self.logger.clone(),
@@ -136,12 +540,20 @@ impl NordDropFFI {
             moose,
             self.keys.clone(),
             init_time,
+            self.conn_policy.clone(),
+            self.incoming_filter.clone(),
             #[cfg(unix)]
             self.fdresolv.clone(),
         )) {
             Ok(service) => instance.replace(ServiceData {
                 service,
                 event_task,
+                event_backlog,
+                session,
+                #[cfg(feature = "metrics")]
+                metrics,
+                #[cfg(feature = "metrics")]
+                metrics_task,
             }),
             Err(err) => {
                 error!(self.logger, "Failed to start the service: {}", err);
@@ -162,15 +574,31 @@ impl NordDropFFI {
 
     pub(super) fn stop(&mut self) -> Result<()> {
         trace!(self.logger, "norddrop_stop()");
+        self.stop_instance(None)
+    }
+
+    pub(super) fn stop_graceful(&mut self, timeout_ms: u64) -> Result<()> {
+        trace!(self.logger, "norddrop_stop_graceful() : {timeout_ms}");
+        self.stop_instance(Some(Duration::from_millis(timeout_ms)))
+    }
 
+    fn stop_instance(&mut self, drain_timeout: Option<Duration>) -> Result<()> {
         let instance = self
             .instance
             .blocking_lock()
             .take()
             .ok_or(crate::LibdropError::NotStarted)?;
 
+        #[cfg(feature = "metrics")]
+        if let Some(task) = &instance.metrics_task {
+            task.abort();
+        }
+
         self.rt.block_on(async {
-            instance.service.stop().await;
+            match drain_timeout {
+                Some(timeout) => instance.service.stop_graceful(timeout).await,
+                None => instance.service.stop().await,
+            }
             let _ = instance.event_task.await;
         });
 
@@ -224,11 +652,16 @@ impl NordDropFFI {
         Ok(())
     }
 
-    pub(super) fn transfers_since(&mut self, since_timestamp_s: i64) -> Result<Vec<TransferInfo>> {
+    pub(super) fn transfers_since(
+        &mut self,
+        since_timestamp_s: i64,
+        label: Option<&str>,
+    ) -> Result<Vec<TransferInfo>> {
         trace!(
             self.logger,
-            "norddrop_get_transfers_since() since_timestamp: {:?}",
-            since_timestamp_s
+            "norddrop_get_transfers_since() since_timestamp: {:?}, label: {:?}",
+            since_timestamp_s,
+            label
         );
 
         if !(SQLITE_TIMESTAMP_MIN..=SQLITE_TIMESTAMP_MAX).contains(&since_timestamp_s) {
@@ -247,10 +680,75 @@ impl NordDropFFI {
             .service
             .storage();
 
-        let result = self.rt.block_on(storage.transfers_since(since_timestamp_s));
+        let result = match label {
+            Some(label) => self
+                .rt
+                .block_on(storage.transfers_with_label(since_timestamp_s, label)),
+            None => self.rt.block_on(storage.transfers_since(since_timestamp_s)),
+        };
         Ok(result)
     }
 
+    pub(super) fn events_since(&self, seq: u64) -> Result<Vec<crate::Event>> {
+        trace!(self.logger, "norddrop_get_events_since() seq: {seq}");
+
+        let seq = i64::try_from(seq).map_err(|_| crate::LibdropError::BadInput)?;
+
+        let instance = self.instance.blocking_lock();
+        let storage = instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service
+            .storage();
+
+        let events = self
+            .rt
+            .block_on(storage.journal_events_since(seq))
+            .into_iter()
+            .filter_map(|ev| {
+                match crate::Event::from_journal(ev.seq as _, ev.timestamp, &ev.payload) {
+                    Ok(mut event) => {
+                        self.event_dispatcher.sign(&mut event);
+                        Some(event)
+                    }
+                    Err(err) => {
+                        warn!(
+                            self.logger,
+                            "Failed to decode journal event {}: {err}", ev.seq
+                        );
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Ok(events)
+    }
+
+    pub(super) fn set_transfer_note(
+        &self,
+        transfer_id: uuid::Uuid,
+        note: Option<&str>,
+    ) -> Result<()> {
+        trace!(
+            self.logger,
+            "set_transfer_note() transfer_id: {transfer_id}, note: {note:?}",
+        );
+
+        let mut instance = self.instance.blocking_lock();
+        let storage = instance
+            .as_mut()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service
+            .storage();
+
+        let res = self
+            .rt
+            .block_on(storage.set_transfer_note(transfer_id, note));
+
+        res.ok_or(crate::LibdropError::BadInput)
+    }
+
     pub(super) fn remove_transfer_file(
         &self,
         transfer_id: uuid::Uuid,
@@ -275,29 +773,100 @@ impl NordDropFFI {
         res.ok_or(crate::LibdropError::BadInput)
     }
 
+    /// Creates the outgoing transfer. With `forward_to` the `peer` acts as a
+    /// relay delivering the files to the final peer
     pub(super) fn new_transfer(
         &mut self,
         peer: &str,
+        forward_to: Option<&str>,
         descriptors: &[TransferDescriptor],
+        options: &TransferOptions,
     ) -> Result<uuid::Uuid> {
-        trace!(self.logger, "norddrop_new_transfer() to peer {peer:?}",);
+        trace!(
+            self.logger,
+            "norddrop_new_transfer() to peer {peer:?}, forward to: {forward_to:?}",
+        );
 
-        let peer = (peer, drop_config::PORT)
-            .to_socket_addrs()
-            .map_err(|err| {
-                error!(self.logger, "Failed to perform lookup of address: {err}");
-                crate::LibdropError::BadInput
-            })?
-            .next()
-            .ok_or(crate::LibdropError::BadInput)?;
+        let (peer, peer_port) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        // The zone index is meaningful only on the local host
+        let forward_to = forward_to
+            .map(|final_peer| {
+                let (addr, port) = resolve_peer(&self.logger, final_peer, &self.config)?;
+                Ok::<_, crate::LibdropError>(drop_transfer::utils::format_peer(addr.ip(), 0, port))
+            })
+            .transpose()?;
+        // The forwarded transfers are never deduplicated
+        let forwarded = forward_to.is_some();
+
+        let window = self.config.duplicate_transfer_window;
+        let key = TransferKey::new(peer, peer_port, descriptors);
+        self.recent_transfers
+            .retain(|_, (created, _)| created.elapsed() < window);
+
+        let existing = self.recent_transfers.get(&key).filter(|_| !forwarded);
+        if let Some(&(_, xfid)) = existing {
+            let instance = self.instance.blocking_lock();
+            let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+            if self.rt.block_on(instance.service.is_outgoing_alive(xfid)) {
+                warn!(
+                    self.logger,
+                    "Transfer {xfid} with the same peer and files was just created, not creating \
+                     a duplicate"
+                );
+                return Ok(xfid);
+            }
+        }
+
+        let request_key = key.digest();
+        let pending_key = request_key
+            .as_deref()
+            .filter(|_| self.config.idempotent_transfers && !forwarded);
+        if let Some(pending_key) = pending_key {
+            let instance = self.instance.blocking_lock();
+            let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+            if let Some(xfid) = self
+                .rt
+                .block_on(instance.service.pending_outgoing(pending_key))
+            {
+                warn!(
+                    self.logger,
+                    "Transfer {xfid} with the same peer and files is still pending, not creating \
+                     a duplicate"
+                );
+                return Ok(xfid);
+            }
+        }
 
         let xfer = {
-            let files = self.prepare_transfer_files(descriptors)?;
-            OutgoingTransfer::new(peer.ip(), files, &self.config).map_err(|e| {
-                error!(self.logger, "Could not create transfer: {e}");
-                crate::LibdropError::TransferCreate
-            })?
+            let (files, entries, excluded) = self.prepare_transfer_files(descriptors)?;
+            let files = self.attach_previews(files);
+            OutgoingTransfer::new(peer.ip(), files, &self.config)
+                .and_then(|xfer| xfer.with_dir_entries(entries, &self.config))
+                .map_err(|e| {
+                    error!(self.logger, "Could not create transfer: {e}");
+                    crate::LibdropError::TransferCreate
+                })?
+                .with_excluded(excluded)
+                .with_hidden_files_skipped(self.config.skip_hidden_files)
+                .with_peer_scope_id(drop_transfer::utils::scope_id(&peer))
+                .with_peer_port(peer_port)
+        };
+        let xfer = match forward_to {
+            Some(final_peer) => xfer.with_forward_to(final_peer),
+            None => xfer.with_request_key(request_key),
         };
+        let xfer = xfer.with_expiry(
+            options
+                .expire_after_ms
+                .map(|ms| SystemTime::now() + Duration::from_millis(ms)),
+        );
+        let xfer = xfer.with_label(options.label.clone()).map_err(|e| {
+            error!(self.logger, "Invalid transfer label: {e}");
+            crate::LibdropError::BadInput
+        })?;
 
         debug!(
             self.logger,
@@ -306,114 +875,989 @@ impl NordDropFFI {
         );
 
         let xfid = xfer.id();
+        #[cfg(unix)]
+        let content_uris: Vec<_> = xfer
+            .files()
+            .values()
+            .filter_map(FileToSend::content_uri)
+            .map(ToString::to_string)
+            .collect();
 
         let mut instance = self.instance.blocking_lock();
         let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
 
-        self.rt.block_on(instance.service.send_request(xfer));
-
-        Ok(xfid)
-    }
-
-    pub(super) fn network_refresh(&mut self) -> Result<()> {
-        trace!(self.logger, "norddrop_network_refresh()");
+        self.rt
+            .block_on(instance.service.send_request(xfer))
+            .map_err(|err| {
+                warn!(self.logger, "Transfer {xfid} not created: {err}");
+                refusal_error(&err)
+            })?;
 
-        let mut instance = self.instance.blocking_lock();
-        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+        #[cfg(unix)]
+        if let Some(resolver) = &self.content_resolver {
+            // The transfer might have failed to be stored
+            if self.rt.block_on(instance.service.is_outgoing_alive(xfid)) {
+                for uri in content_uris {
+                    resolver.on_persist(uri, true);
+                }
+            }
+        }
 
-        instance.service.network_refresh();
+        if !window.is_zero() && !forwarded {
+            self.recent_transfers.insert(key, (Instant::now(), xfid));
+        }
 
-        Ok(())
+        Ok(xfid)
     }
 
-    pub(super) fn download(
-        &mut self,
+    /// Adds the files to the outgoing transfer not yet accepted by the peer
+    pub(super) fn add_files(
+        &self,
         xfid: uuid::Uuid,
-        file_id: String,
-        dst: String,
+        descriptors: &[TransferDescriptor],
     ) -> Result<()> {
-        let logger = self.logger.clone();
-        let ed = self.event_dispatcher.clone();
+        trace!(self.logger, "norddrop_add_files() to transfer {xfid}");
 
-        trace!(
-            logger,
-            "norddrop_download() for transfer {:?}, file {:?}, to {:?}",
-            xfid,
-            file_id,
-            dst
-        );
+        let (files, entries, _) = self.prepare_transfer_files(descriptors)?;
+        let files = self.attach_previews(files);
 
-        let mut inst = self.instance.clone().blocking_lock_owned();
-        if inst.is_none() {
-            return Err(crate::LibdropError::NotStarted);
-        }
+        #[cfg(unix)]
+        let content_uris: Vec<_> = files
+            .iter()
+            .filter_map(FileToSend::content_uri)
+            .map(ToString::to_string)
+            .collect();
 
-        self.rt.spawn(async move {
-            let inst = inst.as_mut().expect("Instance not initialized");
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
 
-            if let Err(e) = inst
-                .service
-                .download(xfid, &file_id.clone().into(), &dst)
-                .await
-            {
-                error!(
-                    logger,
-                    "Failed to download a file with xfid: {}, file: {:?}, dst: {:?}, error: {:?}",
-                    xfid,
-                    Hidden(&file_id),
-                    Hidden(&dst),
-                    e
-                );
+        self.rt
+            .block_on(instance.service.add_files(xfid, files, entries))
+            .map_err(|err| {
+                warn!(self.logger, "Failed to add files to transfer {xfid}: {err}");
+                crate::LibdropError::BadInput
+            })?;
 
-                ed.dispatch(event::EventKind::FileFailed {
-                    transfer_id: xfid.to_string(),
-                    file_id,
-                    status: From::from(&e),
-                });
+        #[cfg(unix)]
+        if let Some(resolver) = &self.content_resolver {
+            for uri in content_uris {
+                resolver.on_persist(uri, true);
             }
-        });
+        }
 
         Ok(())
     }
 
-    pub(super) fn cancel_transfer(&mut self, xfid: uuid::Uuid) -> Result<()> {
-        let logger = self.logger.clone();
-        let ed = self.event_dispatcher.clone();
+    pub(super) fn retry_states(&self) -> Result<Vec<crate::RetryState>> {
+        trace!(self.logger, "norddrop_get_retry_states()");
 
-        trace!(logger, "norddrop_cancel_transfer() for {:?}", xfid);
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
 
-        let mut inst = self.instance.clone().blocking_lock_owned();
-        if inst.is_none() {
-            return Err(crate::LibdropError::NotStarted);
-        }
+        let states = self
+            .rt
+            .block_on(instance.service.outgoing_retry_states())
+            .into_iter()
+            .map(From::from)
+            .collect();
 
-        self.rt.spawn(async move {
-            let inst = inst.as_mut().expect("Instance not initialized");
+        Ok(states)
+    }
 
-            if let Err(e) = inst.service.cancel_all(xfid).await {
-                error!(
-                    logger,
-                    "Failed to cancel a transfer with xfid: {:?}, error: {:?}", xfid, e
-                );
+    pub(super) fn state(&self) -> Result<crate::InstanceState> {
+        trace!(self.logger, "norddrop_get_state()");
 
-                ed.dispatch(crate::EventKind::TransferFailed {
-                    transfer_id: xfid.to_string(),
-                    status: From::from(&e),
-                });
-            }
-        });
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
 
-        Ok(())
+        let snapshot = self.rt.block_on(instance.service.snapshot());
+        Ok(snapshot.into())
     }
 
-    pub(super) fn reject_file(&self, xfid: uuid::Uuid, file: String) -> Result<()> {
-        trace!(
-            self.logger,
-            "norddrop_reject_file() for transfer {xfid}, file {file}",
-        );
+    pub(super) fn transfer_manifest(&self, xfid: uuid::Uuid) -> Result<crate::TransferManifest> {
+        trace!(self.logger, "norddrop_get_transfer_manifest() for {xfid}");
 
-        let logger = self.logger.clone();
-        let evdisp = self.event_dispatcher.clone();
+        let instance = self.instance.blocking_lock();
+        let storage = instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service
+            .storage();
+
+        let transfer = self.rt.block_on(storage.transfer(xfid)).ok_or_else(|| {
+            warn!(self.logger, "Transfer {xfid} is not known");
+            crate::LibdropError::BadInput
+        })?;
+
+        Ok(transfer.into())
+    }
+
+    pub(super) fn transfer_report(&self, xfid: uuid::Uuid) -> Result<String> {
+        trace!(self.logger, "norddrop_get_transfer_report() for {xfid}");
+
+        let instance = self.instance.blocking_lock();
+        let storage = instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service
+            .storage();
+
+        let transfer = self.rt.block_on(storage.transfer(xfid)).ok_or_else(|| {
+            warn!(self.logger, "Transfer {xfid} is not known");
+            crate::LibdropError::BadInput
+        })?;
+
+        let local_key = self.keys.public_key().ok_or_else(|| {
+            error!(
+                self.logger,
+                "Failed to get the local key to sign the report"
+            );
+            crate::LibdropError::Unknown
+        })?;
+        let checksums = self.rt.block_on(storage.fetch_checksums(xfid));
+        let peer_key = self
+            .key_store
+            .on_pubkey(transfer.peer_id.clone())
+            .and_then(|key| <[u8; PUBLIC_KEY_LENGTH]>::try_from(key).ok());
+
+        let report = crate::report::TransferReport::new(
+            transfer,
+            checksums,
+            crate::report::hex(local_key.as_bytes()),
+            peer_key.map(|key| crate::report::hex(&key)),
+        )
+        .ok_or_else(|| {
+            warn!(self.logger, "Transfer {xfid} is not finished");
+            crate::LibdropError::BadInput
+        })?;
+
+        let report = serde_json::to_string(&report).map_err(|err| {
+            error!(self.logger, "Failed to serialize the report: {err}");
+            crate::LibdropError::Unknown
+        })?;
+        let signature = self.keys.sign_report(report.as_bytes()).ok_or_else(|| {
+            error!(
+                self.logger,
+                "Failed to get the local key to sign the report"
+            );
+            crate::LibdropError::Unknown
+        })?;
+
+        serde_json::to_string(&crate::report::SignedReport { report, signature }).map_err(|err| {
+            error!(self.logger, "Failed to serialize the report: {err}");
+            crate::LibdropError::Unknown
+        })
+    }
+
+    pub(super) fn inbox(&self) -> Result<Vec<crate::InboxEntry>> {
+        trace!(self.logger, "norddrop_get_inbox()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let entries = self.rt.block_on(instance.service.inbox()).map_err(|err| {
+            warn!(self.logger, "Failed to list the inbox: {err}");
+            crate::LibdropError::BadInput
+        })?;
+
+        Ok(entries.into_iter().map(From::from).collect())
+    }
+
+    /// Parses the transfer ID given either as the UUID or as the short
+    /// transfer code
+    pub(super) fn transfer_id(&self, id: &str) -> Result<uuid::Uuid> {
+        if let Ok(id) = id.parse() {
+            return Ok(id);
+        }
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance
+            .as_ref()
+            .ok_or(crate::LibdropError::InvalidString)?;
+
+        self.rt
+            .block_on(instance.service.resolve_transfer_id(id))
+            .ok_or(crate::LibdropError::InvalidString)
+    }
+
+    pub(super) fn download_many(
+        &self,
+        xfid: uuid::Uuid,
+        files: Vec<crate::FileDownload>,
+    ) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_download_many() for transfer {xfid}, {} files",
+            files.len()
+        );
+
+        let files: Vec<_> = files
+            .into_iter()
+            .map(|file| (file.file_id.into(), file.destination))
+            .collect();
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        self.rt
+            .block_on(instance.service.download_many(xfid, &files))
+            .map_err(|err| {
+                warn!(self.logger, "Failed to download files of {xfid}: {err}");
+                crate::LibdropError::BadInput
+            })
+    }
+
+    pub(super) fn claim_inbox(&self, xfid: uuid::Uuid, dst: &str) -> Result<Vec<String>> {
+        trace!(
+            self.logger,
+            "norddrop_claim_inbox() for transfer {xfid}, dst {:?}",
+            Hidden(dst)
+        );
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let paths = self
+            .rt
+            .block_on(instance.service.claim_inbox(xfid, Path::new(dst)))
+            .map_err(|err| {
+                warn!(self.logger, "Failed to claim inbox transfer {xfid}: {err}");
+                crate::LibdropError::BadInput
+            })?;
+
+        Ok(paths
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    pub(super) fn forward_inbox(&mut self, xfid: uuid::Uuid, peer: &str) -> Result<uuid::Uuid> {
+        trace!(
+            self.logger,
+            "norddrop_forward_inbox() for transfer {xfid} to peer {peer:?}"
+        );
+
+        let (peer, peer_port) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        self.rt
+            .block_on(instance.service.forward_inbox(
+                xfid,
+                peer.ip(),
+                drop_transfer::utils::scope_id(&peer),
+                peer_port,
+            ))
+            .map_err(|err| {
+                warn!(
+                    self.logger,
+                    "Failed to forward inbox transfer {xfid}: {err}"
+                );
+                refusal_error(&err)
+            })
+    }
+
+    pub(super) fn share_directory(&mut self, peer: &str, path: &str) -> Result<uuid::Uuid> {
+        trace!(
+            self.logger,
+            "norddrop_share_directory() {:?} with peer {peer:?}",
+            Hidden(path)
+        );
+
+        let (peer, peer_port) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        let _guard = self.rt.enter();
+        instance
+            .service
+            .share_directory(
+                Path::new(path),
+                peer.ip(),
+                drop_transfer::utils::scope_id(&peer),
+                peer_port,
+            )
+            .map_err(|err| {
+                warn!(self.logger, "Failed to share directory: {err}");
+                crate::LibdropError::BadInput
+            })
+    }
+
+    pub(super) fn stop_sharing(&mut self, share_id: uuid::Uuid) -> Result<()> {
+        trace!(self.logger, "norddrop_stop_sharing() {share_id}");
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        instance.service.stop_sharing(share_id).map_err(|err| {
+            warn!(self.logger, "Failed to stop sharing {share_id}: {err}");
+            crate::LibdropError::BadInput
+        })
+    }
+
+    pub(super) fn send_text(&self, peer: &str, text: &str) -> Result<uuid::Uuid> {
+        trace!(
+            self.logger,
+            "norddrop_send_text() {} bytes to peer {peer:?}",
+            text.len()
+        );
+
+        let (peer, peer_port) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let _guard = self.rt.enter();
+        instance
+            .service
+            .send_text(
+                peer.ip(),
+                drop_transfer::utils::scope_id(&peer),
+                peer_port,
+                text.to_owned(),
+            )
+            .map_err(|err| {
+                warn!(self.logger, "Failed to send text: {err}");
+                match err {
+                    drop_transfer::Error::OperationRefused => crate::LibdropError::OperationRefused,
+                    _ => crate::LibdropError::BadInput,
+                }
+            })
+    }
+
+    pub(super) fn peer_quota_usage(&self, peer: &str) -> Result<u64> {
+        trace!(self.logger, "norddrop_peer_quota_usage() : {peer:?}");
+
+        let (peer, _) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        Ok(self
+            .rt
+            .block_on(instance.service.peer_quota_usage(peer.ip())))
+    }
+
+    pub(super) fn connection_diagnostics(&self, peer: &str) -> Result<crate::HandshakeDiagnostics> {
+        trace!(
+            self.logger,
+            "norddrop_get_connection_diagnostics() : {peer:?}"
+        );
+
+        let (peer, _) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let diagnostics = instance
+            .service
+            .connection_diagnostics(peer.ip())
+            .ok_or(crate::LibdropError::BadInput)?;
+
+        Ok(diagnostics.into())
+    }
+
+    pub(super) fn peer_device(&self, peer: &str) -> Result<Option<crate::PeerDevice>> {
+        trace!(self.logger, "norddrop_get_peer_device() : {peer:?}");
+
+        let (peer, _) = resolve_peer(&self.logger, peer, &self.config)?;
+
+        let instance = self.instance.blocking_lock();
+        let storage = instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service
+            .storage();
+
+        let device = self
+            .rt
+            .block_on(storage.peer_device(&peer.ip().to_string()));
+        Ok(device.map(From::from))
+    }
+
+    pub(super) fn sessions(&self, clear_finished: bool) -> Result<Vec<crate::TransferSession>> {
+        trace!(self.logger, "norddrop_get_sessions() : {clear_finished}");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let mut session = instance.session.lock().expect("Poisoned lock");
+        let sessions = session.transfers().map(From::from).collect();
+        if clear_finished {
+            session.clear_finished();
+        }
+
+        Ok(sessions)
+    }
+
+    pub(super) fn transfer_stats(&self, xfid: uuid::Uuid) -> Result<crate::TransferStats> {
+        trace!(self.logger, "norddrop_get_transfer_stats() for {xfid}");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let stats = self
+            .rt
+            .block_on(instance.service.transfer_stats(xfid))
+            .ok_or(crate::LibdropError::BadInput)?;
+
+        Ok(stats.into())
+    }
+
+    pub(super) fn metrics(&self) -> Result<crate::Metrics> {
+        trace!(self.logger, "norddrop_get_metrics()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        #[cfg(feature = "metrics")]
+        {
+            Ok(self
+                .rt
+                .block_on(instance.metrics.snapshot(&instance.service)))
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = instance;
+            error!(
+                self.logger,
+                "The library is built without the metrics support"
+            );
+            Err(crate::LibdropError::Unknown)
+        }
+    }
+
+    pub(super) fn event_queue_stats(&self) -> Result<crate::EventQueueStats> {
+        trace!(self.logger, "norddrop_get_event_queue_stats()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let stats = instance
+            .event_backlog
+            .lock()
+            .expect("Poisoned lock")
+            .stats();
+
+        Ok(stats)
+    }
+
+    pub(super) fn background_task_counts(&self) -> Result<Vec<crate::BackgroundTaskCount>> {
+        trace!(self.logger, "norddrop_get_background_task_counts()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let counts = instance
+            .service
+            .background_task_counts()
+            .into_iter()
+            .map(|(subsystem, count)| crate::BackgroundTaskCount {
+                subsystem: subsystem.to_string(),
+                count: count as _,
+            })
+            .collect();
+
+        Ok(counts)
+    }
+
+    pub(super) fn background_tasks(&self) -> Result<Vec<crate::BackgroundTask>> {
+        trace!(self.logger, "norddrop_get_background_tasks()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let tasks = instance
+            .service
+            .background_tasks()
+            .into_iter()
+            .map(From::from)
+            .collect();
+
+        Ok(tasks)
+    }
+
+    pub(super) fn network_refresh(&mut self) -> Result<()> {
+        trace!(self.logger, "norddrop_network_refresh()");
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        instance.service.network_refresh();
+
+        Ok(())
+    }
+
+    pub(super) fn prepare_background(&self) -> Result<String> {
+        trace!(self.logger, "norddrop_prepare_background()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let state = self
+            .rt
+            .block_on(background::prepare(&instance.service, &self.logger));
+
+        serde_json::to_string(&state).map_err(|err| {
+            error!(
+                self.logger,
+                "Failed to serialize the background state: {err}"
+            );
+            crate::LibdropError::Unknown
+        })
+    }
+
+    pub(super) fn restore_background(&mut self, state: &str) -> Result<Vec<String>> {
+        trace!(self.logger, "norddrop_restore_background()");
+
+        let state = BackgroundState::parse(state).ok_or_else(|| {
+            error!(self.logger, "Invalid background state");
+            crate::LibdropError::BadInput
+        })?;
+
+        let (mut resumed, by_uri) = {
+            let mut instance = self.instance.blocking_lock();
+            let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+            self.rt.block_on(background::restore(
+                &mut instance.service,
+                &self.logger,
+                state,
+            ))
+        };
+
+        resumed.extend(
+            by_uri
+                .into_iter()
+                .filter_map(|download| self.resume_download_to_uri(download)),
+        );
+
+        Ok(resumed.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    #[cfg(unix)]
+    fn resume_download_to_uri(&mut self, download: background::Download) -> Option<uuid::Uuid> {
+        if self.open_files.is_none() {
+            debug!(
+                self.logger,
+                "Download of {} to the URI left to the app", download.transfer_id
+            );
+            return None;
+        }
+
+        let uri = url::Url::parse(&download.destination).ok()?;
+        match self.download_to_uri(download.transfer_id, download.file_id, uri) {
+            Ok(()) => Some(download.transfer_id),
+            Err(err) => {
+                warn!(
+                    self.logger,
+                    "Not resuming a download of {}: {err}", download.transfer_id
+                );
+                None
+            }
+        }
+    }
+
+    // The destination can't be opened again without the file lifecycle
+    // callback, the app starts the download itself
+    #[cfg(not(unix))]
+    fn resume_download_to_uri(&mut self, download: background::Download) -> Option<uuid::Uuid> {
+        debug!(
+            self.logger,
+            "Download of {} to the URI left to the app", download.transfer_id
+        );
+        None
+    }
+
+    pub(super) fn set_low_power_mode(&mut self, enabled: bool) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_set_low_power_mode() enabled: {enabled}"
+        );
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let _guard = self.rt.enter();
+        instance.service.set_low_power(enabled);
+
+        Ok(())
+    }
+
+    pub(super) fn invalidate_peer_key(&self, peer: &str) -> Result<()> {
+        trace!(self.logger, "norddrop_invalidate_peer_key() peer: {peer}");
+
+        let ip: IpAddr = peer.parse().map_err(|_| crate::LibdropError::BadInput)?;
+        if !self.keys.invalidate_peer_key(ip) {
+            debug!(self.logger, "The key of {ip} was not cached");
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn set_private_key(&self, privkey: Vec<u8>) -> Result<()> {
+        trace!(self.logger, "norddrop_set_private_key()");
+
+        let privkey: [u8; SECRET_KEY_LENGTH] = privkey
+            .try_into()
+            .map_err(|_| crate::LibdropError::BadInput)?;
+        let secret = SecretKey::from(privkey);
+
+        let instance = self.instance.blocking_lock();
+        match instance.as_ref() {
+            Some(instance) => {
+                let _guard = self.rt.enter();
+                instance.service.set_private_key(secret);
+            }
+            None => {
+                // No connection is open, the key is used from the next start
+                self.keys.set_secret_key(secret);
+                self.event_dispatcher
+                    .dispatch(crate::EventKind::KeyRotationCompleted);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn set_read_only_mode(&mut self, enabled: bool) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_set_read_only_mode() enabled: {enabled}"
+        );
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        instance.service.set_read_only(enabled);
+
+        Ok(())
+    }
+
+    pub(super) fn download(
+        &mut self,
+        xfid: uuid::Uuid,
+        file_id: String,
+        dst: String,
+    ) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_download() for transfer {:?}, file {:?}, to {:?}",
+            xfid,
+            file_id,
+            dst
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            if let Err(e) = inst
+                .service
+                .download(xfid, &file_id.clone().into(), &dst)
+                .await
+            {
+                error!(
+                    logger,
+                    "Failed to download a file with xfid: {}, file: {:?}, dst: {:?}, error: {:?}",
+                    xfid,
+                    Hidden(&file_id),
+                    Hidden(&dst),
+                    e
+                );
+
+                ed.dispatch_journaled(
+                    inst.service.storage(),
+                    &logger,
+                    event::EventKind::FileFailed {
+                        transfer_id: xfid.to_string(),
+                        file_id,
+                        status: From::from(&e),
+                        partial_path: None,
+                    },
+                )
+                .await;
+            }
+        });
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(super) fn download_to_uri(
+        &mut self,
+        xfid: uuid::Uuid,
+        file_id: String,
+        content_uri: url::Url,
+    ) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_download_to_uri() for transfer {:?}, file {:?}, to {:?}",
+            xfid,
+            file_id,
+            content_uri
+        );
+
+        let open_files = self.open_files.clone().ok_or_else(|| {
+            error!(
+                self.logger,
+                "Destination URI provided but the file lifecycle callback is not set up"
+            );
+            crate::LibdropError::BadInput
+        })?;
+
+        let fd = open_files
+            .open_write(xfid, &file_id, &content_uri)
+            .ok_or_else(|| {
+                error!(self.logger, "Could not open {:?} for writing", content_uri);
+                crate::LibdropError::BadInput
+            })?;
+
+        let res = self.download_to_fd(xfid, file_id.clone(), content_uri, fd);
+        if res.is_err() {
+            open_files.close_download(xfid, &file_id.into(), false);
+        }
+        res
+    }
+
+    #[cfg(unix)]
+    pub(super) fn download_to_fd(
+        &mut self,
+        xfid: uuid::Uuid,
+        file_id: String,
+        content_uri: url::Url,
+        fd: std::os::fd::RawFd,
+    ) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_download_to_fd() for transfer {:?}, file {:?}, to {:?}, fd {fd}",
+            xfid,
+            file_id,
+            content_uri
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        let dest = drop_transfer::DestinationFd::new(content_uri, fd).map_err(|err| {
+            error!(logger, "Failed to duplicate the destination fd {fd}: {err}");
+            crate::LibdropError::BadInput
+        })?;
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            if let Err(e) = inst
+                .service
+                .download_to_fd(xfid, &file_id.clone().into(), dest)
+                .await
+            {
+                error!(
+                    logger,
+                    "Failed to download a file with xfid: {}, file: {:?} into fd, error: {:?}",
+                    xfid,
+                    Hidden(&file_id),
+                    e
+                );
+
+                ed.dispatch_journaled(
+                    inst.service.storage(),
+                    &logger,
+                    event::EventKind::FileFailed {
+                        transfer_id: xfid.to_string(),
+                        file_id,
+                        status: From::from(&e),
+                        partial_path: None,
+                    },
+                )
+                .await;
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn cancel_transfer(&mut self, xfid: uuid::Uuid) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(logger, "norddrop_cancel_transfer() for {:?}", xfid);
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        // Wait for the cancellation so that the retries of a not yet connected transfer
+        // are stopped by the time the call returns
+        self.rt.block_on(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            if let Err(e) = inst.service.cancel_all(xfid).await {
+                error!(
+                    logger,
+                    "Failed to cancel a transfer with xfid: {:?}, error: {:?}", xfid, e
+                );
+
+                ed.dispatch_journaled(
+                    inst.service.storage(),
+                    &logger,
+                    crate::EventKind::TransferFailed {
+                        transfer_id: xfid.to_string(),
+                        status: From::from(&e),
+                    },
+                )
+                .await;
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn retry_transfer(&self, xfid: uuid::Uuid) -> Result<()> {
+        trace!(self.logger, "norddrop_retry_transfer() for {xfid}");
+
+        let inst = self.instance.blocking_lock();
+        let inst = inst.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let res = self.rt.block_on(inst.service.retry_transfer(xfid));
+
+        res.map_err(|err| {
+            warn!(self.logger, "Failed to retry transfer {xfid}: {err}");
+            crate::LibdropError::BadInput
+        })
+    }
+
+    pub(super) fn remove_partial_file(&self, xfid: uuid::Uuid, file: String) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_remove_partial_file() for transfer {xfid}, file {file}",
+        );
+
+        let inst = self.instance.blocking_lock();
+        let inst = inst.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let res = self
+            .rt
+            .block_on(inst.service.remove_partial_file(xfid, file.clone().into()));
+
+        res.map_err(|err| {
+            warn!(
+                self.logger,
+                "Failed to remove partial file of transfer {xfid}, file {file}: {err}"
+            );
+            crate::LibdropError::BadInput
+        })
+    }
+
+    pub(super) fn withdraw_transfer(&self, xfid: uuid::Uuid) -> Result<()> {
+        trace!(self.logger, "norddrop_withdraw_transfer() for {xfid}");
+
+        let mut inst = self.instance.blocking_lock();
+        let inst = inst.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        let res = self.rt.block_on(inst.service.withdraw(xfid));
+
+        res.map_err(|err| {
+            warn!(self.logger, "Failed to withdraw transfer {xfid}: {err}");
+            crate::LibdropError::BadInput
+        })
+    }
+
+    pub(super) fn withdraw_file(&self, xfid: uuid::Uuid, file: String) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_withdraw_file() for transfer {xfid}, file {file}",
+        );
+
+        let inst = self.instance.blocking_lock();
+        let inst = inst.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let res = self
+            .rt
+            .block_on(inst.service.withdraw_file(xfid, file.clone().into()));
+
+        res.map_err(|err| {
+            warn!(
+                self.logger,
+                "Failed to withdraw file {file} of transfer {xfid}: {err}"
+            );
+            crate::LibdropError::BadInput
+        })
+    }
+
+    pub(super) fn set_transfer_paused(&self, xfid: uuid::Uuid, paused: bool) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_{}_transfer() for {xfid}",
+            if paused { "pause" } else { "resume" }
+        );
+
+        let inst = self.instance.blocking_lock();
+        let inst = inst.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        let res = if paused {
+            self.rt.block_on(inst.service.pause_transfer(xfid))
+        } else {
+            self.rt.block_on(inst.service.resume_transfer(xfid))
+        };
+
+        res.map_err(|err| {
+            warn!(
+                self.logger,
+                "Failed to pause or resume transfer {xfid}: {err}"
+            );
+            crate::LibdropError::BadInput
+        })
+    }
+
+    pub(super) fn pace_file(
+        &self,
+        xfid: uuid::Uuid,
+        file: String,
+        bytes_per_second: Option<u64>,
+    ) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_pace_file() for {xfid}, file {file:?}, rate {bytes_per_second:?}"
+        );
+
+        let inst = self.instance.blocking_lock();
+        let inst = inst.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        self.rt
+            .block_on(
+                inst.service
+                    .pace_file(xfid, &file.clone().into(), bytes_per_second),
+            )
+            .map_err(|err| {
+                warn!(self.logger, "Failed to pace file {file:?} of {xfid}: {err}");
+                crate::LibdropError::BadInput
+            })
+    }
+
+    pub(super) fn reject_file(&self, xfid: uuid::Uuid, file: String) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_reject_file() for transfer {xfid}, file {file}",
+        );
+
+        let logger = self.logger.clone();
+        let evdisp = self.event_dispatcher.clone();
 
         let inst = self.instance.clone().blocking_lock_owned();
         if inst.is_none() {
@@ -429,11 +1873,18 @@ impl NordDropFFI {
                     "Failed to reject a file with xfid: {xfid}, file: {file}, error: {err:?}"
                 );
 
-                evdisp.dispatch(crate::EventKind::FileFailed {
-                    transfer_id: xfid.to_string(),
-                    file_id: file,
-                    status: From::from(&err),
-                });
+                evdisp
+                    .dispatch_journaled(
+                        inst.service.storage(),
+                        &logger,
+                        crate::EventKind::FileFailed {
+                            transfer_id: xfid.to_string(),
+                            file_id: file,
+                            status: From::from(&err),
+                            partial_path: None,
+                        },
+                    )
+                    .await;
             }
         });
 
@@ -461,10 +1912,193 @@ impl NordDropFFI {
         Ok(())
     }
 
+    #[cfg(unix)]
+    pub(super) fn set_content_resolver(
+        &mut self,
+        resolver: Arc<dyn crate::ContentResolver>,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_content_resolver()");
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set content resolver. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        self.content_resolver = Some(resolver);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(super) fn set_file_lifecycle(
+        &mut self,
+        lifecycle: Arc<dyn crate::FileLifecycle>,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_file_lifecycle()");
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set file lifecycle callback. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        self.open_files = Some(Arc::new(OpenFiles::new(lifecycle)));
+        Ok(())
+    }
+
+    pub(super) fn set_connection_policy(
+        &mut self,
+        policy: impl Fn(IpAddr, uuid::Uuid) -> ConnectionDecision + Send + Sync + 'static,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_connection_policy()");
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set connection policy. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        let logger = self.logger.clone();
+        let policy = move |peer: IpAddr, id: uuid::Uuid| {
+            // The callback may block the executor
+            let decision = tokio::task::block_in_place(|| policy(peer, id));
+            debug!(logger, "Connection policy decision for {id}: {decision:?}");
+            decision
+        };
+
+        self.conn_policy = Some(Arc::new(policy));
+        Ok(())
+    }
+
+    pub(super) fn set_span_observer(
+        &mut self,
+        observer: impl Fn(crate::SpanTiming) + Send + Sync + 'static,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_span_observer()");
+
+        crate::spans::set_observer(observer);
+        Ok(())
+    }
+
+    pub(super) fn set_incoming_filter(
+        &mut self,
+        filter: impl Fn(&IncomingTransfer) -> IncomingDecision + Send + Sync + 'static,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_incoming_filter()");
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set incoming filter. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        let logger = self.logger.clone();
+        let filter = move |xfer: &IncomingTransfer| {
+            // The callback may block the executor
+            let decision = tokio::task::block_in_place(|| filter(xfer));
+            debug!(
+                logger,
+                "Incoming filter decision for {}: {decision:?}",
+                xfer.id()
+            );
+            decision
+        };
+
+        self.incoming_filter = Some(Arc::new(filter));
+        Ok(())
+    }
+
+    pub(super) fn set_preview_provider(
+        &mut self,
+        provider: impl Fn(&str, &str) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_preview_provider()");
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set preview provider. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        self.preview_provider = Some(Arc::new(provider));
+        Ok(())
+    }
+
+    /// Asks the preview provider for the thumbnails of the images and videos,
+    /// until the previews reach `MAX_TOTAL_PREVIEW_SIZE` together
+    fn attach_previews(&self, files: Vec<FileToSend>) -> Vec<FileToSend> {
+        let Some(provider) = &self.preview_provider else {
+            return files;
+        };
+        let mut total = 0;
+
+        files
+            .into_iter()
+            .map(|file| {
+                let mime = file.mime_type();
+                if !mime.starts_with("image/") && !mime.starts_with("video/") {
+                    return file;
+                }
+                let Some(uri) = file.uri() else {
+                    return file;
+                };
+                if total >= MAX_TOTAL_PREVIEW_SIZE {
+                    return file;
+                }
+
+                match provider(uri.as_str(), mime) {
+                    Some(preview) if preview.len() > MAX_PREVIEW_SIZE => {
+                        warn!(
+                            self.logger,
+                            "Preview of file {} is too big: {} bytes",
+                            file.id(),
+                            preview.len()
+                        );
+                        file
+                    }
+                    Some(preview) if total + preview.len() > MAX_TOTAL_PREVIEW_SIZE => {
+                        warn!(
+                            self.logger,
+                            "Previews of the transfer are too big, file {} is sent without one",
+                            file.id(),
+                        );
+                        total = MAX_TOTAL_PREVIEW_SIZE;
+                        file
+                    }
+                    Some(preview) => {
+                        total += preview.len();
+                        file.with_preview(preview)
+                    }
+                    None => file,
+                }
+            })
+            .collect()
+    }
+
     fn prepare_transfer_files(
         &self,
         descriptors: &[TransferDescriptor],
-    ) -> Result<Vec<FileToSend>> {
+    ) -> Result<(Vec<FileToSend>, DirEntries, usize)> {
         let mut gather = drop_transfer::file::GatherCtx::new(&self.config);
 
         #[cfg(unix)]
@@ -473,17 +2107,69 @@ impl NordDropFFI {
         }
 
         for desc in descriptors {
+            let app_metadata: BTreeMap<_, _> = desc
+                .app_metadata()
+                .map(|metadata| metadata.clone().into_iter().collect())
+                .unwrap_or_default();
+            FileMetadata::check_app_metadata(&app_metadata).map_err(|_| {
+                error!(
+                    self.logger,
+                    "The file metadata exceeds {MAX_APP_METADATA_SIZE} bytes"
+                );
+                crate::LibdropError::BadInput
+            })?;
+            gather.set_app_metadata(app_metadata);
+
             match desc {
                 #[cfg(windows)]
-                TransferDescriptor::Fd { .. } => {
+                TransferDescriptor::Fd { .. } | TransferDescriptor::ContentUri { .. } => {
                     error!(self.logger, "FD transfers are not supported on Windows");
                     return Err(crate::LibdropError::TransferCreate);
                 }
                 #[cfg(unix)]
+                TransferDescriptor::ContentUri { content_uri, .. } => {
+                    let resolver = self.content_resolver.as_ref().ok_or_else(|| {
+                        error!(
+                            self.logger,
+                            "Content URI provided but the content resolver is not set up"
+                        );
+                        crate::LibdropError::TransferCreate
+                    })?;
+
+                    let filename =
+                        resolver
+                            .on_display_name(content_uri.clone())
+                            .ok_or_else(|| {
+                                error!(
+                                    self.logger,
+                                    "Could not resolve the display name of {:?}",
+                                    Hidden(content_uri)
+                                );
+                                crate::LibdropError::TransferCreate
+                            })?;
+
+                    let uri = content_uri
+                        .parse()
+                        .map_err(|_| crate::LibdropError::InvalidString)?;
+
+                    gather
+                        .gather_from_content_uri(&filename, uri, None)
+                        .map_err(|err| {
+                            error!(
+                                self.logger,
+                                "Could not open file {:?} ({:?}) for transfer: {err}",
+                                Hidden(&filename),
+                                Hidden(content_uri)
+                            );
+                            crate::LibdropError::TransferCreate
+                        })?;
+                }
+                #[cfg(unix)]
                 TransferDescriptor::Fd {
                     filename,
                     content_uri,
                     fd,
+                    ..
                 } => {
                     let uri = content_uri
                         .parse()
@@ -501,20 +2187,68 @@ impl NordDropFFI {
                             crate::LibdropError::TransferCreate
                         })?;
                 }
-                TransferDescriptor::Path { path } => {
-                    gather.gather_from_path(path).map_err(|e| {
-                        error!(
-                            self.logger,
-                            "Could not open file {:?} for transfer: {e}",
-                            Hidden(path)
-                        );
-                        crate::LibdropError::TransferCreate
-                    })?;
+                TransferDescriptor::Path { path, exclude, .. } => {
+                    let exclude =
+                        Exclude::new(exclude.as_deref().unwrap_or_default()).map_err(|e| {
+                            error!(self.logger, "Invalid exclude patterns: {e}");
+                            crate::LibdropError::TransferCreate
+                        })?;
+
+                    gather
+                        .gather_from_path_excluding(path, &exclude)
+                        .map_err(|e| {
+                            error!(
+                                self.logger,
+                                "Could not open file {:?} for transfer: {e}",
+                                Hidden(path)
+                            );
+                            crate::LibdropError::TransferCreate
+                        })?;
                 }
             }
         }
 
-        Ok(gather.take())
+        Ok((gather.take(), gather.take_entries(), gather.excluded()))
+    }
+}
+
+/// Resolves the peer address. The system resolver handles IPv6 zone IDs
+/// (`fe80::1%eth0`) and hostnames resolving to several addresses, out of
+/// which the preferred family is picked. The peer may specify the port it
+/// listens on (`host:port`, `[ipv6]:port`), which is returned alongside.
+fn resolve_peer(
+    logger: &Logger,
+    peer: &str,
+    config: &DropConfig,
+) -> Result<(SocketAddr, Option<u16>)> {
+    let (host, port) = drop_transfer::utils::split_peer_port(peer).ok_or_else(|| {
+        error!(logger, "Invalid peer port");
+        crate::LibdropError::BadInput
+    })?;
+
+    let addrs: Vec<_> = (host, port.unwrap_or(config.port))
+        .to_socket_addrs()
+        .map_err(|err| {
+            error!(logger, "Failed to perform lookup of address: {err}");
+            crate::LibdropError::BadInput
+        })?
+        .map(drop_transfer::utils::canonical_socket_addr)
+        .collect();
+
+    let addr = addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() == config.prefer_ipv6)
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or(crate::LibdropError::BadInput)?;
+
+    Ok((addr, port))
+}
+
+fn refusal_error(err: &drop_transfer::Error) -> crate::LibdropError {
+    match err {
+        drop_transfer::Error::OperationRefused => crate::LibdropError::OperationRefused,
+        _ => crate::LibdropError::TransferCreate,
     }
 }
 
@@ -622,6 +2356,23 @@ fn open_database(
     }
 }
 
+/// The content URIs of the outgoing transfer finished by the event
+#[cfg(unix)]
+fn finished_content_uris(event: &Event) -> Vec<String> {
+    let xfer = match event {
+        Event::OutgoingTransferCanceled(xfer, _)
+        | Event::OutgoingTransferWithdrawn(xfer, _)
+        | Event::OutgoingTransferFailed(xfer, _, _) => xfer,
+        _ => return Vec::new(),
+    };
+
+    xfer.files()
+        .values()
+        .filter_map(FileToSend::content_uri)
+        .map(ToString::to_string)
+        .collect()
+}
+
 #[cfg(unix)]
 fn crate_fd_callback(
     logger: slog::Logger,
@@ -652,6 +2403,70 @@ fn validate_config(logger: &slog::Logger, config: &Config) -> Result<()> {
         return Err(crate::LibdropError::BadInput);
     }
 
+    if let Some(relay) = &config.drop.relay {
+        if !matches!(
+            drop_transfer::utils::split_peer_port(&relay.addr),
+            Some((_, Some(_)))
+        ) {
+            error!(logger, "Relay address must contain the port");
+            return Err(crate::LibdropError::BadInput);
+        }
+    }
+
+    if let Some(addr) = &config.drop.metrics_addr {
+        match addr.parse::<SocketAddr>() {
+            // The endpoint is not authenticated, it must not be reachable by
+            // the other hosts
+            Ok(addr) if addr.ip().is_loopback() => (),
+            Ok(_) => {
+                error!(logger, "Metrics address must be a loopback one: {addr}");
+                return Err(crate::LibdropError::BadInput);
+            }
+            Err(_) => {
+                error!(logger, "Invalid metrics address: {addr}");
+                return Err(crate::LibdropError::BadInput);
+            }
+        }
+    }
+
+    if let (Some(inbox_dir), Some(forward_dir)) = (&config.drop.inbox_dir, &config.drop.forward_dir)
+    {
+        let (inbox_dir, forward_dir) = (Path::new(inbox_dir), Path::new(forward_dir));
+        if inbox_dir.starts_with(forward_dir) || forward_dir.starts_with(inbox_dir) {
+            error!(
+                logger,
+                "The inbox and the forwarding directories must not overlap"
+            );
+            return Err(crate::LibdropError::BadInput);
+        }
+    }
+
+    if let Some(addr) = config
+        .drop
+        .forward_peers
+        .iter()
+        .find(|addr| addr.parse::<IpAddr>().is_err())
+    {
+        error!(logger, "Invalid forward peer address: {addr}");
+        return Err(crate::LibdropError::BadInput);
+    }
+
+    if let Some(root) = config
+        .drop
+        .download_roots
+        .iter()
+        .find(|root| !Path::new(root).is_absolute())
+    {
+        error!(logger, "Download root must be absolute: {:?}", Hidden(root));
+        return Err(crate::LibdropError::BadInput);
+    }
+
+    let extension = &config.drop.partial_file_extension;
+    if extension.is_empty() || extension.contains(['/', '\\']) {
+        error!(logger, "Invalid partial file extension: {extension:?}");
+        return Err(crate::LibdropError::BadInput);
+    }
+
     Ok(())
 }
 
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use drop_storage::types as db;
 
 pub enum TransferStateKind {
@@ -31,6 +33,9 @@ pub enum IncomingPathStateKind {
     Paused {
         bytes_received: u64,
     },
+    Withdrawn {
+        by_peer: bool,
+    },
 }
 
 pub struct IncomingPathState {
@@ -38,11 +43,19 @@ pub struct IncomingPathState {
     pub kind: IncomingPathStateKind,
 }
 
+pub struct FileMetadata {
+    pub mime_type: Option<String>,
+    pub mtime: Option<i64>,
+    pub permissions: Option<u32>,
+    pub app_metadata: HashMap<String, String>,
+}
+
 pub struct IncomingPath {
     pub file_id: String,
     pub relative_path: String,
     pub bytes: u64,
     pub bytes_received: u64,
+    pub metadata: FileMetadata,
     pub states: Vec<IncomingPathState>,
 }
 
@@ -54,7 +67,9 @@ pub enum OutgoingPathStateKind {
         status: crate::StatusCode,
         bytes_sent: u64,
     },
-    Completed,
+    Completed {
+        final_path: Option<String>,
+    },
     Rejected {
         by_peer: bool,
         bytes_sent: u64,
@@ -62,6 +77,9 @@ pub enum OutgoingPathStateKind {
     Paused {
         bytes_sent: u64,
     },
+    Withdrawn {
+        by_peer: bool,
+    },
 }
 
 pub struct OutgoingPathState {
@@ -80,6 +98,7 @@ pub struct OutgoingPath {
     pub bytes: u64,
     pub bytes_sent: u64,
     pub source: OutgoingFileSource,
+    pub metadata: FileMetadata,
     pub states: Vec<OutgoingPathState>,
 }
 
@@ -94,6 +113,16 @@ pub struct TransferInfo {
     pub peer: String,
     pub states: Vec<TransferState>,
     pub kind: TransferKind,
+    pub total_bytes: u64,
+    pub transferred_bytes: u64,
+    pub duration_ms: Option<u64>,
+    pub average_bps: Option<u64>,
+    pub peer_clock_skew_ms: Option<i64>,
+    pub note: Option<String>,
+    pub origin_id: Option<String>,
+    pub forward_to: Option<String>,
+    pub alias: Option<String>,
+    pub label: Option<String>,
 }
 
 impl From<db::TransferStateEventData> for TransferStateKind {
@@ -124,6 +153,16 @@ impl From<db::Transfer> for TransferInfo {
             peer: info.peer_id,
             states: info.states.into_iter().map(TransferState::from).collect(),
             kind: info.transfer_type.into(),
+            total_bytes: info.totals.total_bytes as _,
+            transferred_bytes: info.totals.transferred_bytes as _,
+            duration_ms: info.totals.duration_ms.map(|ms| ms as _),
+            average_bps: info.totals.average_bps.map(|bps| bps as _),
+            peer_clock_skew_ms: info.peer_clock_skew_ms,
+            note: info.note,
+            origin_id: info.origin_id.map(|id| id.to_string()),
+            forward_to: info.forward_to,
+            alias: info.alias,
+            label: info.label,
         }
     }
 }
@@ -161,6 +200,9 @@ impl From<db::IncomingPathStateEventData> for IncomingPathStateKind {
                     bytes_received: bytes_received as _,
                 }
             }
+            db::IncomingPathStateEventData::Withdrawn { by_peer } => {
+                IncomingPathStateKind::Withdrawn { by_peer }
+            }
         }
     }
 }
@@ -174,6 +216,17 @@ impl From<db::IncomingPathStateEvent> for IncomingPathState {
     }
 }
 
+impl From<db::FileMetadata> for FileMetadata {
+    fn from(value: db::FileMetadata) -> Self {
+        FileMetadata {
+            mime_type: value.mime_type,
+            mtime: value.mtime,
+            permissions: value.permissions,
+            app_metadata: value.app_metadata.into_iter().collect(),
+        }
+    }
+}
+
 impl From<db::IncomingPath> for IncomingPath {
     fn from(path: db::IncomingPath) -> Self {
         IncomingPath {
@@ -181,6 +234,7 @@ impl From<db::IncomingPath> for IncomingPath {
             relative_path: path.relative_path,
             bytes: path.bytes as _,
             bytes_received: path.bytes_received as _,
+            metadata: path.metadata.into(),
             states: path
                 .states
                 .into_iter()
@@ -205,7 +259,9 @@ impl From<db::OutgoingPathStateEventData> for OutgoingPathStateKind {
                 status: crate::StatusCode::from(status_code as u32),
                 bytes_sent: bytes_sent as _,
             },
-            db::OutgoingPathStateEventData::Completed => OutgoingPathStateKind::Completed,
+            db::OutgoingPathStateEventData::Completed { final_path } => {
+                OutgoingPathStateKind::Completed { final_path }
+            }
             db::OutgoingPathStateEventData::Rejected {
                 by_peer,
                 bytes_sent,
@@ -218,6 +274,9 @@ impl From<db::OutgoingPathStateEventData> for OutgoingPathStateKind {
                     bytes_sent: bytes_sent as _,
                 }
             }
+            db::OutgoingPathStateEventData::Withdrawn { by_peer } => {
+                OutgoingPathStateKind::Withdrawn { by_peer }
+            }
         }
     }
 }
@@ -249,6 +308,7 @@ impl From<db::OutgoingPath> for OutgoingPath {
                         .unwrap_or_default(),
                 },
             },
+            metadata: path.metadata.into(),
             states: path
                 .states
                 .into_iter()
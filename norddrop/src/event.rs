@@ -1,13 +1,20 @@
-use std::time::SystemTime;
+use std::{collections::HashMap, io::ErrorKind, time::SystemTime};
 
 use drop_transfer::{File, Transfer};
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize)]
 pub struct ReceivedFile {
     pub id: String,
     pub path: String,
     pub size: u64,
+    pub preview: Option<Vec<u8>>,
+    // Missing in the journal entries written by the older versions
+    #[serde(default, alias = "metadata")]
+    pub app_metadata: HashMap<String, String>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct QueuedFile {
     pub id: String,
     pub path: String,
@@ -15,26 +22,123 @@ pub struct QueuedFile {
     pub base_dir: Option<String>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Status {
     pub status: crate::StatusCode,
     pub os_error_code: Option<i32>,
+    // Missing in the journal entries written by the older versions
+    #[serde(default)]
+    pub category: crate::ErrorCategory,
+    #[serde(default)]
+    pub retryable: bool,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
 }
 
 pub struct Event {
+    pub seq: u64,
     pub timestamp: i64,
     pub kind: EventKind,
+    pub label: Option<String>,
+    pub payload: Option<String>,
+    pub signature: Option<String>,
 }
 
+/// The journal payload, the event kind with the label of its transfer
+#[derive(Serialize, Deserialize)]
+struct JournalRecord<K> {
+    #[serde(flatten)]
+    kind: K,
+    // Missing in the journal entries written by the older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+/// The signed payload, the journal one with the sequence number and the
+/// timestamp the receivers deduplicate the events with
+#[derive(Serialize)]
+struct SignedRecord<'a> {
+    seq: u64,
+    timestamp: i64,
+    #[serde(flatten)]
+    record: JournalRecord<&'a EventKind>,
+}
+
+impl Event {
+    /// Attaches the serialized event signed with the `key`
+    pub(crate) fn sign(&mut self, key: &[u8]) -> serde_json::Result<()> {
+        let payload = serde_json::to_string(&SignedRecord {
+            seq: self.seq,
+            timestamp: self.timestamp,
+            record: JournalRecord {
+                kind: &self.kind,
+                label: self.label.clone(),
+            },
+        })?;
+
+        self.signature = Some(drop_auth::create_payload_signature(key, payload.as_bytes()));
+        self.payload = Some(payload);
+        Ok(())
+    }
+
+    pub(crate) fn journal_payload(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&JournalRecord {
+            kind: &self.kind,
+            label: self.label.clone(),
+        })
+    }
+
+    pub(crate) fn from_journal(
+        seq: u64,
+        timestamp: i64,
+        payload: &str,
+    ) -> serde_json::Result<Self> {
+        let record: JournalRecord<EventKind> = serde_json::from_str(payload)?;
+
+        Ok(Self {
+            seq,
+            timestamp,
+            kind: record.kind,
+            label: record.label,
+            payload: None,
+            signature: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum EventKind {
     RequestReceived {
         peer: String,
         transfer_id: String,
         files: Vec<ReceivedFile>,
+        total_size: u64,
+        file_count: u64,
+        files_omitted: bool,
+        device_name: Option<String>,
+        platform: Option<String>,
     },
     RequestQueued {
         peer: String,
         transfer_id: String,
         files: Vec<QueuedFile>,
+        excluded: u64,
+        total_size: u64,
+        file_count: u64,
+        files_omitted: bool,
+    },
+    FilesQueued {
+        transfer_id: String,
+        files: Vec<QueuedFile>,
+    },
+    FilesReceived {
+        transfer_id: String,
+        files: Vec<ReceivedFile>,
     },
 
     FileStarted {
@@ -51,6 +155,8 @@ pub enum EventKind {
         transfer_id: String,
         file_id: String,
         final_path: String,
+        #[serde(default)]
+        path_sanitized: bool,
     },
     FileUploaded {
         transfer_id: String,
@@ -60,12 +166,19 @@ pub enum EventKind {
         transfer_id: String,
         file_id: String,
         status: Status,
+        #[serde(default)]
+        partial_path: Option<String>,
     },
     FileRejected {
         transfer_id: String,
         file_id: String,
         by_peer: bool,
     },
+    FileWithdrawn {
+        transfer_id: String,
+        file_id: String,
+        by_peer: bool,
+    },
     FilePaused {
         transfer_id: String,
         file_id: String,
@@ -85,6 +198,10 @@ pub enum EventKind {
         transfer_id: String,
         by_peer: bool,
     },
+    TransferWithdrawn {
+        transfer_id: String,
+        by_peer: bool,
+    },
     TransferFailed {
         transfer_id: String,
         status: Status,
@@ -94,6 +211,28 @@ pub enum EventKind {
         peer: String,
         status: Status,
     },
+    TransferPostponed {
+        transfer_id: String,
+        peer: String,
+    },
+    PeerKeyUnavailable {
+        transfer_id: String,
+        peer: String,
+    },
+    ConnectionAttemptFailed {
+        transfer_id: String,
+        attempt: u32,
+        next_retry_in_ms: Option<u64>,
+    },
+    TransferConnected {
+        transfer_id: String,
+        peer: String,
+        relayed: bool,
+    },
+    ConnectionDiagnostics {
+        transfer_id: String,
+        diagnostics: crate::HandshakeDiagnostics,
+    },
 
     FinalizeChecksumStarted {
         transfer_id: String,
@@ -128,22 +267,206 @@ pub enum EventKind {
     RuntimeError {
         status: crate::StatusCode,
     },
+
+    StartupReconciled {
+        paused_files: u64,
+        failed_transfers: u64,
+        failed_files: u64,
+    },
+    KeyRotationCompleted,
+    OperationRefused {
+        transfer_id: String,
+        file_id: Option<String>,
+        operation: crate::RefusedOperation,
+    },
+    DirectoryPrepared {
+        transfer_id: String,
+        root: String,
+        path: String,
+    },
+    DownloadsQueued {
+        transfer_id: String,
+        file_ids: Vec<String>,
+    },
+    DuplicateFileDetected {
+        transfer_id: String,
+        file_id: String,
+        existing_path: String,
+    },
+    PreflightFinished {
+        transfer_id: String,
+        unreadable_files: Vec<String>,
+    },
+    TransferPaused {
+        transfer_id: String,
+        by_peer: bool,
+    },
+    TransferResumed {
+        transfer_id: String,
+        by_peer: bool,
+    },
+    TextReceived {
+        message_id: String,
+        peer: String,
+        text: String,
+    },
+    TextSent {
+        message_id: String,
+    },
+    TextFailed {
+        message_id: String,
+        status: crate::StatusCode,
+    },
+    RequestRejected {
+        peer: String,
+        transfer_id: String,
+        status: crate::StatusCode,
+    },
+    EventsDropped {
+        count: u64,
+    },
+}
+
+impl EventKind {
+    /// The frequent events reporting the progress of the file
+    pub(crate) fn is_progress(&self) -> bool {
+        matches!(
+            self,
+            Self::FileProgress { .. }
+                | Self::FinalizeChecksumProgress { .. }
+                | Self::VerifyChecksumProgress { .. }
+        )
+    }
+
+    /// Drops the file list of the transfer request with more files than the
+    /// limit, to be fetched with `get_transfer_manifest()` instead
+    pub(crate) fn omit_files(&mut self, limit: usize) {
+        match self {
+            Self::RequestReceived {
+                files,
+                files_omitted,
+                ..
+            } if files.len() > limit => {
+                *files = Vec::new();
+                *files_omitted = true;
+            }
+            Self::RequestQueued {
+                files,
+                files_omitted,
+                ..
+            } if files.len() > limit => {
+                *files = Vec::new();
+                *files_omitted = true;
+            }
+            _ => (),
+        }
+    }
 }
 
 impl From<&drop_transfer::Error> for Status {
     fn from(value: &drop_transfer::Error) -> Self {
+        let status = crate::StatusCode::from(value);
+        let os_error_code = value.os_err_code();
+
+        let mut params = HashMap::new();
+        if let Some(errno) = os_error_code {
+            params.insert("errno".to_string(), errno.to_string());
+        }
+        match value {
+            drop_transfer::Error::BadPath(reason)
+            | drop_transfer::Error::BadTransferState(reason) => {
+                params.insert("reason".to_string(), reason.clone());
+            }
+            _ => (),
+        }
+
         Self {
-            status: value.into(),
-            os_error_code: value.os_err_code(),
+            status,
+            os_error_code,
+            category: value.category(),
+            retryable: value.is_retryable(),
+            message: value.to_string(),
+            key: message_key(value, status).to_string(),
+            params,
         }
     }
 }
 
+#[cfg(unix)]
+fn is_disk_full(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOSPC)
+}
+
+#[cfg(windows)]
+fn is_disk_full(err: &std::io::Error) -> bool {
+    use winapi::shared::winerror::{ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL};
+
+    matches!(err.raw_os_error(), Some(code) if code == ERROR_DISK_FULL as i32 || code == ERROR_HANDLE_DISK_FULL as i32)
+}
+
+// The stable identifiers of the failures for the apps to look the localized
+// messages up with. They must not change once released, new ones may be added
+fn message_key(err: &drop_transfer::Error, status: crate::StatusCode) -> &'static str {
+    use crate::StatusCode::*;
+
+    if let drop_transfer::Error::Io(io) = err {
+        match io.kind() {
+            ErrorKind::NotFound => return "file_not_found",
+            ErrorKind::AlreadyExists => return "file_exists",
+            _ => (),
+        }
+        if is_disk_full(io) {
+            return "disk_full";
+        }
+    }
+    if let drop_transfer::Error::WsServer(_) | drop_transfer::Error::WsClient(_) = err {
+        return "connection_failed";
+    }
+
+    match status {
+        Finalized => "canceled",
+        BadPath => "bad_path",
+        BadFile => "bad_file",
+        BadTransfer => "transfer_not_found",
+        BadTransferState => "bad_transfer_state",
+        BadFileId => "file_not_found_in_transfer",
+        IoError => "io_error",
+        TransferLimitsExceeded => "transfer_limits_exceeded",
+        MismatchedSize => "file_size_changed",
+        InvalidArgument => "invalid_argument",
+        AddrInUse => "address_in_use",
+        FileModified => "file_modified",
+        FilenameTooLong => "filename_too_long",
+        AuthenticationFailed => "authentication_failed",
+        StorageError => "storage_error",
+        DbLost => "database_lost",
+        FileChecksumMismatch => "checksum_mismatch",
+        FileRejected => "file_rejected",
+        FileFailed => "file_failed",
+        FileFinished => "file_finished",
+        EmptyTransfer => "empty_transfer",
+        ConnectionClosedByPeer => "connection_closed_by_peer",
+        TooManyRequests => "too_many_requests",
+        PermissionDenied => "permission_denied",
+        ConnectionDenied => "connection_denied",
+        Interrupted => "interrupted",
+        PeerKeyUnavailable => "peer_key_unavailable",
+        QuotaExceeded => "quota_exceeded",
+        PeerBusy => "peer_busy",
+        Expired => "expired",
+        ReceiveDisabled => "receive_disabled",
+    }
+}
+
 impl From<EventKind> for Event {
     fn from(kind: EventKind) -> Self {
         Self {
+            seq: 0,
             timestamp: current_timestamp(),
             kind,
+            label: None,
+            payload: None,
+            signature: None,
         }
     }
 }
@@ -157,8 +480,12 @@ impl From<(drop_transfer::Event, SystemTime)> for Event {
             .as_millis() as i64;
 
         Self {
+            seq: 0,
             timestamp,
             kind: e.into(),
+            label: None,
+            payload: None,
+            signature: None,
         }
     }
 }
@@ -172,11 +499,36 @@ impl From<drop_transfer::Event> for EventKind {
                 peer: tx.peer().to_string(),
                 transfer_id: tx.id().to_string(),
                 files: tx.files().values().map(From::from).collect(),
+                total_size: tx.total_size(),
+                file_count: tx.files().len() as _,
+                files_omitted: false,
+                device_name: tx.peer_device().and_then(|device| device.name.clone()),
+                platform: tx.peer_device().and_then(|device| device.platform.clone()),
             },
             RequestQueued(tx) => Self::RequestQueued {
                 peer: tx.peer().to_string(),
                 transfer_id: tx.id().to_string(),
                 files: tx.files().values().map(From::from).collect(),
+                excluded: tx.excluded() as _,
+                total_size: tx.total_size(),
+                file_count: tx.files().len() as _,
+                files_omitted: false,
+            },
+            OutgoingFilesAdded(tx, fids) => Self::FilesQueued {
+                transfer_id: tx.id().to_string(),
+                files: fids
+                    .iter()
+                    .filter_map(|fid| tx.files().get(fid))
+                    .map(From::from)
+                    .collect(),
+            },
+            IncomingFilesAdded(tx, fids) => Self::FilesReceived {
+                transfer_id: tx.id().to_string(),
+                files: fids
+                    .iter()
+                    .filter_map(|fid| tx.files().get(fid))
+                    .map(From::from)
+                    .collect(),
             },
             FileUploadStarted(tx, fid, transferred) => Self::FileStarted {
                 transfer_id: tx.id().to_string(),
@@ -198,7 +550,7 @@ impl From<drop_transfer::Event> for EventKind {
                 file_id: fid.to_string(),
                 transferred: progress,
             },
-            FileUploadSuccess(tx, fid) => Self::FileUploaded {
+            FileUploadSuccess(tx, fid, _) => Self::FileUploaded {
                 transfer_id: tx.id().to_string(),
                 file_id: fid.to_string(),
             },
@@ -206,16 +558,19 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: tx.id().to_string(),
                 file_id: info.id.to_string(),
                 final_path: info.final_path.0.to_string_lossy().to_string(),
+                path_sanitized: info.path_sanitized,
             },
             FileUploadFailed(tx, fid, status) => Self::FileFailed {
                 transfer_id: tx.id().to_string(),
                 file_id: fid.to_string(),
                 status: From::from(&status),
+                partial_path: None,
             },
-            FileDownloadFailed(tx, fid, status) => Self::FileFailed {
+            FileDownloadFailed(tx, fid, status, partial_path) => Self::FileFailed {
                 transfer_id: tx.id().to_string(),
                 file_id: fid.to_string(),
                 status: From::from(&status),
+                partial_path: partial_path.map(|path| path.to_string_lossy().to_string()),
             },
             IncomingTransferCanceled(tx, by_peer) => Self::TransferFinalized {
                 transfer_id: tx.id().to_string(),
@@ -225,6 +580,14 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: tx.id().to_string(),
                 by_peer,
             },
+            IncomingTransferWithdrawn(tx, by_peer) => Self::TransferWithdrawn {
+                transfer_id: tx.id().to_string(),
+                by_peer,
+            },
+            OutgoingTransferWithdrawn(tx, by_peer) => Self::TransferWithdrawn {
+                transfer_id: tx.id().to_string(),
+                by_peer,
+            },
             OutgoingTransferFailed(tx, status, _) => Self::TransferFailed {
                 transfer_id: tx.id().to_string(),
                 status: From::from(&status),
@@ -247,6 +610,22 @@ impl From<drop_transfer::Event> for EventKind {
                 file_id: file_id.to_string(),
                 by_peer,
             },
+            FileUploadWithdrawn {
+                transfer_id,
+                file_id,
+            } => Self::FileWithdrawn {
+                transfer_id: transfer_id.to_string(),
+                file_id: file_id.to_string(),
+                by_peer: false,
+            },
+            FileDownloadWithdrawn {
+                transfer_id,
+                file_id,
+            } => Self::FileWithdrawn {
+                transfer_id: transfer_id.to_string(),
+                file_id: file_id.to_string(),
+                by_peer: true,
+            },
             FileUploadPaused {
                 transfer_id,
                 file_id,
@@ -324,11 +703,137 @@ impl From<drop_transfer::Event> for EventKind {
                 bytes_checksummed: progress,
             },
 
+            StartupReconciled(summary) => Self::StartupReconciled {
+                paused_files: summary.paused_files as _,
+                failed_transfers: summary.failed_transfers as _,
+                failed_files: summary.failed_files as _,
+            },
+            KeyRotationCompleted => Self::KeyRotationCompleted,
+            OperationRefused {
+                transfer_id,
+                file_id,
+                operation,
+            } => Self::OperationRefused {
+                transfer_id: transfer_id.to_string(),
+                file_id: file_id.map(|id| id.to_string()),
+                operation,
+            },
+            IncomingDirectoryPrepared {
+                transfer_id,
+                root,
+                path,
+            } => Self::DirectoryPrepared {
+                transfer_id: transfer_id.to_string(),
+                root,
+                path: path.to_string_lossy().to_string(),
+            },
+            IncomingDownloadsQueued {
+                transfer_id,
+                file_ids,
+            } => Self::DownloadsQueued {
+                transfer_id: transfer_id.to_string(),
+                file_ids: file_ids.into_iter().map(|id| id.to_string()).collect(),
+            },
+            IncomingFileDuplicate {
+                transfer_id,
+                file_id,
+                existing_path,
+            } => Self::DuplicateFileDetected {
+                transfer_id: transfer_id.to_string(),
+                file_id: file_id.to_string(),
+                existing_path: existing_path.to_string_lossy().to_string(),
+            },
+            OutgoingPreflightFinished {
+                transfer_id,
+                unreadable,
+            } => Self::PreflightFinished {
+                transfer_id: transfer_id.to_string(),
+                unreadable_files: unreadable.into_iter().map(|id| id.to_string()).collect(),
+            },
+            IncomingTransferPaused {
+                transfer_id,
+                paused: true,
+            } => Self::TransferPaused {
+                transfer_id: transfer_id.to_string(),
+                by_peer: false,
+            },
+            IncomingTransferPaused {
+                transfer_id,
+                paused: false,
+            } => Self::TransferResumed {
+                transfer_id: transfer_id.to_string(),
+                by_peer: false,
+            },
+            OutgoingTransferPaused {
+                transfer_id,
+                paused: true,
+            } => Self::TransferPaused {
+                transfer_id: transfer_id.to_string(),
+                by_peer: true,
+            },
+            OutgoingTransferPaused {
+                transfer_id,
+                paused: false,
+            } => Self::TransferResumed {
+                transfer_id: transfer_id.to_string(),
+                by_peer: true,
+            },
+            IncomingText { id, peer, text } => Self::TextReceived {
+                message_id: id.to_string(),
+                peer: peer.to_string(),
+                text,
+            },
+            OutgoingTextSent { id } => Self::TextSent {
+                message_id: id.to_string(),
+            },
+            OutgoingTextFailed { id, error } => Self::TextFailed {
+                message_id: id.to_string(),
+                status: From::from(&error),
+            },
+            IncomingTransferRejected {
+                transfer_id,
+                peer,
+                error,
+            } => Self::RequestRejected {
+                peer: peer.to_string(),
+                transfer_id: transfer_id.to_string(),
+                status: From::from(&error),
+            },
+
             OutgoingTransferDeferred { transfer, error } => Self::TransferDeferred {
                 transfer_id: transfer.id().to_string(),
                 peer: transfer.peer().to_string(),
                 status: Status::from(&error),
             },
+            OutgoingTransferPostponed(tx) => Self::TransferPostponed {
+                transfer_id: tx.id().to_string(),
+                peer: tx.peer().to_string(),
+            },
+            OutgoingPeerKeyUnavailable(tx) => Self::PeerKeyUnavailable {
+                transfer_id: tx.id().to_string(),
+                peer: tx.peer().to_string(),
+            },
+            OutgoingConnectionAttemptFailed {
+                transfer,
+                attempt,
+                next_retry_in,
+            } => Self::ConnectionAttemptFailed {
+                transfer_id: transfer.id().to_string(),
+                attempt,
+                next_retry_in_ms: next_retry_in.map(|delay| delay.as_millis() as _),
+            },
+            OutgoingConnectionDiagnostics {
+                transfer,
+                diagnostics,
+            } => Self::ConnectionDiagnostics {
+                transfer_id: transfer.id().to_string(),
+                diagnostics: diagnostics.into(),
+            },
+            OutgoingTransferConnected { transfer, relayed } => Self::TransferConnected {
+                transfer_id: transfer.id().to_string(),
+                peer: transfer.peer().to_string(),
+                relayed,
+            },
             FileDownloadPending {
                 transfer_id,
                 file_id,
@@ -366,6 +871,13 @@ impl From<&drop_transfer::FileToRecv> for ReceivedFile {
             id: value.id().to_string(),
             path: value.subpath().to_string(),
             size: value.size(),
+            preview: value.preview().map(ToOwned::to_owned),
+            app_metadata: value
+                .metadata()
+                .app_metadata
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
         }
     }
 }
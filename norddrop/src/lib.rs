@@ -1,17 +1,30 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+mod background;
 mod config;
+mod delivery;
 pub mod device;
 mod dump;
 mod event;
+#[cfg(unix)]
+mod lifecycle;
 mod log;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod poll;
+mod report;
+mod spans;
 mod types;
 mod uni;
 
 uniffi::include_scaffolding!("norddrop");
 
 pub use config::*;
-pub use drop_core::Status as StatusCode;
+pub use drop_config::{RetryBackoff, TransferMode};
+pub use drop_core::{ErrorCategory, Status as StatusCode};
+pub use drop_transfer::{
+    ConnectionDecision, FileLiveStatus, HandshakePhase, IncomingDecision, RefusedOperation,
+};
 pub use dump::*;
 pub use event::*;
 pub use types::*;
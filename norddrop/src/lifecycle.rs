@@ -0,0 +1,192 @@
+//! Tells the host app when the files it provides the descriptors of are no
+//! longer used, with the callback set by `set_file_lifecycle()`. The library
+//! never closes those descriptors, so the app learns when it can close them
+//! and commit or discard the content of its virtual files.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use drop_transfer::{Event, FileId, OutgoingTransfer, Transfer as _};
+use uuid::Uuid;
+
+// The outgoing transfers with the files sent from the descriptors, together
+// with the files already closed
+type Uploads = HashMap<Uuid, (Arc<OutgoingTransfer>, HashSet<FileId>)>;
+
+pub(crate) struct OpenFiles {
+    lifecycle: Arc<dyn crate::FileLifecycle>,
+    uploads: Mutex<Uploads>,
+    // The content URIs of the destinations opened with `on_open_write()`
+    downloads: Mutex<HashMap<(Uuid, FileId), String>>,
+}
+
+impl OpenFiles {
+    pub(crate) fn new(lifecycle: Arc<dyn crate::FileLifecycle>) -> Self {
+        Self {
+            lifecycle,
+            uploads: Mutex::default(),
+            downloads: Mutex::default(),
+        }
+    }
+
+    /// Opens the destination of the file with the host app, returns the
+    /// descriptor
+    pub(crate) fn open_write(
+        &self,
+        transfer_id: Uuid,
+        file_id: &str,
+        content_uri: &url::Url,
+    ) -> Option<i32> {
+        let fd = self.lifecycle.on_open_write(
+            transfer_id.to_string(),
+            file_id.to_string(),
+            content_uri.to_string(),
+        )?;
+
+        let replaced = self.downloads.lock().expect("Poisoned lock").insert(
+            (transfer_id, file_id.to_string().into()),
+            content_uri.to_string(),
+        );
+        // The previous destination of the file is not going to be written
+        if let Some(uri) = replaced {
+            self.lifecycle.on_close(uri, false);
+        }
+
+        Some(fd)
+    }
+
+    /// Closes the files finished by the event
+    pub(crate) fn handle_event(&self, event: &Event) {
+        match event {
+            Event::RequestQueued(xfer) | Event::FileUploadStarted(xfer, ..) => {
+                if xfer
+                    .files()
+                    .values()
+                    .any(|file| file.content_uri().is_some())
+                {
+                    self.uploads
+                        .lock()
+                        .expect("Poisoned lock")
+                        .entry(xfer.id())
+                        .or_insert_with(|| (xfer.clone(), HashSet::new()));
+                }
+            }
+            Event::OutgoingFilesAdded(xfer, _) => {
+                if xfer
+                    .files()
+                    .values()
+                    .any(|file| file.content_uri().is_some())
+                {
+                    // The transfer with the added files replaces the previous one
+                    self.uploads
+                        .lock()
+                        .expect("Poisoned lock")
+                        .entry(xfer.id())
+                        .or_insert_with(|| (xfer.clone(), HashSet::new()))
+                        .0 = xfer.clone();
+                }
+            }
+            Event::FileUploadSuccess(xfer, file_id, _) => {
+                self.close_upload(xfer.id(), file_id, true)
+            }
+            Event::FileUploadFailed(xfer, file_id, _) => {
+                self.close_upload(xfer.id(), file_id, false)
+            }
+            Event::FileUploadRejected {
+                transfer_id,
+                file_id,
+                ..
+            }
+            | Event::FileUploadWithdrawn {
+                transfer_id,
+                file_id,
+            } => self.close_upload(*transfer_id, file_id, false),
+            Event::OutgoingTransferCanceled(xfer, _)
+            | Event::OutgoingTransferWithdrawn(xfer, _)
+            | Event::OutgoingTransferFailed(xfer, ..) => {
+                let entry = self
+                    .uploads
+                    .lock()
+                    .expect("Poisoned lock")
+                    .remove(&xfer.id());
+                if let Some((xfer, closed)) = entry {
+                    for (file_id, file) in xfer.files() {
+                        if !closed.contains(file_id) {
+                            self.close_source(file, false);
+                        }
+                    }
+                }
+            }
+            Event::FileDownloadSuccess(xfer, info) => {
+                self.close_download(xfer.id(), &info.id, true)
+            }
+            Event::FileDownloadFailed(xfer, file_id, ..) => {
+                self.close_download(xfer.id(), file_id, false)
+            }
+            Event::FileDownloadRejected {
+                transfer_id,
+                file_id,
+                ..
+            }
+            | Event::FileDownloadWithdrawn {
+                transfer_id,
+                file_id,
+            } => self.close_download(*transfer_id, file_id, false),
+            Event::IncomingTransferCanceled(xfer, _)
+            | Event::IncomingTransferWithdrawn(xfer, _) => {
+                let uris: Vec<_> = {
+                    let mut downloads = self.downloads.lock().expect("Poisoned lock");
+                    let keys: Vec<_> = downloads
+                        .keys()
+                        .filter(|(id, _)| *id == xfer.id())
+                        .cloned()
+                        .collect();
+                    keys.into_iter()
+                        .filter_map(|key| downloads.remove(&key))
+                        .collect()
+                };
+
+                for uri in uris {
+                    self.lifecycle.on_close(uri, false);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn close_upload(&self, transfer_id: Uuid, file_id: &FileId, success: bool) {
+        let mut uploads = self.uploads.lock().expect("Poisoned lock");
+        let Some((xfer, closed)) = uploads.get_mut(&transfer_id) else {
+            return;
+        };
+        if !closed.insert(file_id.clone()) {
+            return;
+        }
+
+        let xfer = xfer.clone();
+        drop(uploads);
+        if let Some(file) = xfer.files().get(file_id) {
+            self.close_source(file, success);
+        }
+    }
+
+    fn close_source(&self, file: &drop_transfer::FileToSend, success: bool) {
+        if let (Some(uri), true) = (file.content_uri(), file.fd_opened()) {
+            self.lifecycle.on_close(uri.to_string(), success);
+        }
+    }
+
+    pub(crate) fn close_download(&self, transfer_id: Uuid, file_id: &FileId, success: bool) {
+        let uri = self
+            .downloads
+            .lock()
+            .expect("Poisoned lock")
+            .remove(&(transfer_id, file_id.clone()));
+
+        if let Some(uri) = uri {
+            self.lifecycle.on_close(uri, success);
+        }
+    }
+}
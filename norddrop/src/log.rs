@@ -76,6 +76,13 @@ impl Drain for Log {
         let mut serializer = KeyValueSerializer::new(record);
         let _ = kv.serialize(record, &mut serializer);
 
+        if let Some((transfer_id, file_id)) = crate::spans::current() {
+            serializer.kv.insert("transfer_id", transfer_id);
+            if let Some(file_id) = file_id {
+                serializer.kv.insert("file_id", file_id);
+            }
+        }
+
         self.0.on_log(record.level().into(), serializer.msg());
         Ok(())
     }
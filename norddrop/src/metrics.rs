@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, fmt::Write, net::SocketAddr, sync::Arc};
+
+use drop_transfer::{Event, Service};
+use slog::{error, info, Logger};
+use tokio::sync::Mutex;
+use warp::Filter;
+
+use crate::device::ServiceData;
+
+/// Collects the metrics which are not tracked by the service itself
+#[derive(Default)]
+pub(crate) struct MetricsCollector {
+    // Failure count by the status code
+    failures: std::sync::Mutex<BTreeMap<u32, u64>>,
+}
+
+impl MetricsCollector {
+    pub(crate) fn record_event(&self, event: &Event) {
+        let err = match event {
+            Event::FileUploadFailed(_, _, err)
+            | Event::FileDownloadFailed(_, _, err, _)
+            | Event::OutgoingTransferFailed(_, err, _) => err,
+            _ => return,
+        };
+
+        let status = drop_core::Status::from(err) as u32;
+        *self
+            .failures
+            .lock()
+            .expect("Poisoned lock")
+            .entry(status)
+            .or_default() += 1;
+    }
+
+    pub(crate) async fn snapshot(&self, service: &Service) -> crate::Metrics {
+        let counters = service.transfer_counters().await;
+        let db_size_bytes = service.storage().db_size().await;
+
+        let failures = self
+            .failures
+            .lock()
+            .expect("Poisoned lock")
+            .iter()
+            .map(|(&status, &count)| crate::FailureCount {
+                status: status.into(),
+                count,
+            })
+            .collect();
+
+        crate::Metrics {
+            active_incoming_transfers: counters.active_incoming as _,
+            active_outgoing_transfers: counters.active_outgoing as _,
+            bytes_sent: counters.bytes_sent,
+            bytes_received: counters.bytes_received,
+            failures,
+            db_size_bytes,
+        }
+    }
+}
+
+/// Renders the metrics in the Prometheus text exposition format
+pub(crate) fn render(metrics: &crate::Metrics) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP libdrop_active_transfers Number of the active transfers"
+    );
+    let _ = writeln!(out, "# TYPE libdrop_active_transfers gauge");
+    let _ = writeln!(
+        out,
+        "libdrop_active_transfers{{direction=\"incoming\"}} {}",
+        metrics.active_incoming_transfers
+    );
+    let _ = writeln!(
+        out,
+        "libdrop_active_transfers{{direction=\"outgoing\"}} {}",
+        metrics.active_outgoing_transfers
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP libdrop_transferred_bytes_total Bytes transferred since the start"
+    );
+    let _ = writeln!(out, "# TYPE libdrop_transferred_bytes_total counter");
+    let _ = writeln!(
+        out,
+        "libdrop_transferred_bytes_total{{direction=\"sent\"}} {}",
+        metrics.bytes_sent
+    );
+    let _ = writeln!(
+        out,
+        "libdrop_transferred_bytes_total{{direction=\"received\"}} {}",
+        metrics.bytes_received
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP libdrop_failures_total Failed files and transfers by the status code"
+    );
+    let _ = writeln!(out, "# TYPE libdrop_failures_total counter");
+    for failure in &metrics.failures {
+        let _ = writeln!(
+            out,
+            "libdrop_failures_total{{status=\"{:?}\",code=\"{}\"}} {}",
+            failure.status, failure.status as u32, failure.count
+        );
+    }
+
+    if let Some(size) = metrics.db_size_bytes {
+        let _ = writeln!(
+            out,
+            "# HELP libdrop_db_size_bytes Size of the transfer database"
+        );
+        let _ = writeln!(out, "# TYPE libdrop_db_size_bytes gauge");
+        let _ = writeln!(out, "libdrop_db_size_bytes {size}");
+    }
+
+    out
+}
+
+/// Binds the HTTP endpoint serving the metrics of the running instance at
+/// `/metrics`. The returned future runs the server
+pub(crate) fn serve(
+    addr: SocketAddr,
+    instance: Arc<Mutex<Option<ServiceData>>>,
+    logger: Logger,
+) -> Result<impl std::future::Future<Output = ()>, warp::Error> {
+    let route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .then(move || {
+            let instance = instance.clone();
+
+            async move {
+                let text = match instance.lock().await.as_ref() {
+                    Some(instance) => render(&instance.metrics.snapshot(&instance.service).await),
+                    None => String::new(),
+                };
+
+                warp::reply::with_header(text, "Content-Type", "text/plain; version=0.0.4")
+            }
+        });
+
+    let (addr, server) = warp::serve(route).try_bind_ephemeral(addr).map_err(|err| {
+        error!(logger, "Failed to bind the metrics endpoint: {err}");
+        err
+    })?;
+    info!(logger, "Serving metrics at http://{addr}/metrics");
+
+    Ok(server)
+}
@@ -0,0 +1,45 @@
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::Event;
+
+/// Events waiting for the `next_event()` call of the instance created without
+/// the event callback
+#[derive(Default)]
+pub(crate) struct EventQueue {
+    events: Mutex<VecDeque<Event>>,
+    ready: Condvar,
+}
+
+impl EventQueue {
+    pub(crate) fn push(&self, event: Event) {
+        self.events.lock().expect("Poisoned lock").push_back(event);
+        self.ready.notify_one();
+    }
+
+    /// Waits up to `timeout` for the next event. Returns `None` on timeout
+    pub(crate) fn next(&self, timeout: Duration) -> Option<Event> {
+        let deadline = Instant::now() + timeout;
+        let mut events = self.events.lock().expect("Poisoned lock");
+
+        loop {
+            if let Some(event) = events.pop_front() {
+                return Some(event);
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return None;
+            }
+
+            events = self
+                .ready
+                .wait_timeout(events, left)
+                .expect("Poisoned lock")
+                .0;
+        }
+    }
+}
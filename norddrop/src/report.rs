@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use drop_storage::types::{
+    DbTransferType, FileChecksum, IncomingPathStateEventData as In,
+    OutgoingPathStateEventData as Out, Transfer, TransferStateEventData,
+};
+use serde::Serialize;
+
+const REPORT_VERSION: u32 = 1;
+
+/// The report serialized into the JSON string together with the signature
+/// computed over that string, so it can be verified without re-serializing
+#[derive(Serialize)]
+pub struct SignedReport {
+    pub report: String,
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+pub struct TransferReport {
+    version: u32,
+    transfer_id: String,
+    outgoing: bool,
+    peer: String,
+    // `finished` once all the files reached their final state, `cancelled`
+    // or `failed` if the transfer ended before that
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_public_key: Option<String>,
+    local_public_key: String,
+    // Timestamps are in milliseconds since the UNIX epoch
+    created_at: i64,
+    finished_at: i64,
+    generated_at: i64,
+    total_bytes: u64,
+    transferred_bytes: u64,
+    files: Vec<ReportFile>,
+}
+
+#[derive(Serialize)]
+struct ReportFile {
+    id: String,
+    path: String,
+    size: u64,
+    transferred_bytes: u64,
+    state: &'static str,
+    // The SHA-256 of the file reported by the sender, known for the incoming
+    // files only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    final_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<i64>,
+}
+
+impl TransferReport {
+    /// Builds the report of the finished transfer, `None` if the transfer is
+    /// still ongoing. The files left unfinished by the cancelled or failed
+    /// transfer are reported in the state of the transfer
+    pub fn new(
+        transfer: Transfer,
+        checksums: Vec<FileChecksum>,
+        local_public_key: String,
+        peer_public_key: Option<String>,
+    ) -> Option<Self> {
+        let mut checksums: HashMap<_, _> = checksums
+            .into_iter()
+            .filter_map(|csum| Some((csum.file_id, csum.checksum?)))
+            .collect();
+
+        // The transfer states are terminal, the last one is how it ended
+        let ended = transfer.states.last().map(|state| match state.data {
+            TransferStateEventData::Cancel { .. } => "cancelled",
+            TransferStateEventData::Failed { .. } => "failed",
+        });
+
+        let (outgoing, files) = match transfer.transfer_type {
+            DbTransferType::Incoming(paths) => {
+                let files = paths
+                    .into_iter()
+                    .map(|path| {
+                        let last = path.states.last();
+                        let (state, final_path) = match last.map(|state| &state.data) {
+                            Some(In::Completed { final_path }) => {
+                                ("completed", Some(final_path.clone()))
+                            }
+                            Some(In::Failed { .. }) => ("failed", None),
+                            Some(In::Rejected { .. }) => ("rejected", None),
+                            Some(In::Withdrawn { .. }) => ("withdrawn", None),
+                            _ => (ended?, None),
+                        };
+
+                        Some(ReportFile {
+                            sha256: checksums.remove(&path.file_id).as_deref().map(hex),
+                            id: path.file_id,
+                            path: path.relative_path,
+                            size: path.bytes as _,
+                            transferred_bytes: path.bytes_received as _,
+                            state,
+                            final_path,
+                            finished_at: last
+                                .map(|state| state.created_at.and_utc().timestamp_millis()),
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+
+                (false, files)
+            }
+            DbTransferType::Outgoing(paths) => {
+                let files = paths
+                    .into_iter()
+                    .map(|path| {
+                        let last = path.states.last();
+                        let (state, final_path) = match last.map(|state| &state.data) {
+                            Some(Out::Completed { final_path }) => {
+                                ("completed", final_path.clone())
+                            }
+                            Some(Out::Failed { .. }) => ("failed", None),
+                            Some(Out::Rejected { .. }) => ("rejected", None),
+                            Some(Out::Withdrawn { .. }) => ("withdrawn", None),
+                            _ => (ended?, None),
+                        };
+
+                        Some(ReportFile {
+                            id: path.file_id,
+                            path: path.relative_path,
+                            size: path.bytes as _,
+                            transferred_bytes: path.bytes_sent as _,
+                            state,
+                            sha256: None,
+                            final_path,
+                            finished_at: last
+                                .map(|state| state.created_at.and_utc().timestamp_millis()),
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+
+                (true, files)
+            }
+        };
+
+        let created_at = transfer.created_at.and_utc().timestamp_millis();
+        let finished_at = transfer
+            .states
+            .iter()
+            .map(|state| state.created_at.and_utc().timestamp_millis())
+            .chain(files.iter().filter_map(|file| file.finished_at))
+            .max()
+            .unwrap_or(created_at);
+
+        let generated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as i64)
+            .unwrap_or_default();
+
+        Some(Self {
+            version: REPORT_VERSION,
+            transfer_id: transfer.id.to_string(),
+            outgoing,
+            peer: transfer.peer_id,
+            state: ended.unwrap_or("finished"),
+            peer_public_key,
+            local_public_key,
+            created_at,
+            finished_at,
+            generated_at,
+            total_bytes: transfer.totals.total_bytes as _,
+            transferred_bytes: transfer.totals.transferred_bytes as _,
+            files,
+        })
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use drop_storage::types::{
+        IncomingPath, IncomingPathStateEvent, OutgoingPath, OutgoingPathStateEvent,
+        TransferStateEvent, TransferTotals,
+    };
+
+    use super::*;
+
+    const TRANSFER_ID: uuid::Uuid = uuid::Uuid::from_u128(1);
+
+    fn transfer(states: Vec<TransferStateEventData>, transfer_type: DbTransferType) -> Transfer {
+        Transfer {
+            id: TRANSFER_ID,
+            created_at: Default::default(),
+            peer_id: "172.20.0.5".into(),
+            states: states
+                .into_iter()
+                .map(|data| TransferStateEvent {
+                    transfer_id: TRANSFER_ID,
+                    created_at: Default::default(),
+                    data,
+                })
+                .collect(),
+            transfer_type,
+            totals: TransferTotals::default(),
+            peer_clock_skew_ms: None,
+            note: None,
+            origin_id: None,
+            forward_to: None,
+            alias: None,
+            label: None,
+        }
+    }
+
+    fn incoming(file_id: &str, states: Vec<In>) -> IncomingPath {
+        IncomingPath {
+            id: 0,
+            created_at: Default::default(),
+            transfer_id: TRANSFER_ID,
+            relative_path: format!("dir/{file_id}"),
+            file_id: file_id.into(),
+            bytes: 10,
+            bytes_received: 0,
+            metadata: Default::default(),
+            states: states
+                .into_iter()
+                .map(|data| IncomingPathStateEvent {
+                    path_id: 0,
+                    created_at: Default::default(),
+                    data,
+                })
+                .collect(),
+        }
+    }
+
+    fn outgoing(file_id: &str, states: Vec<Out>) -> OutgoingPath {
+        OutgoingPath {
+            id: 0,
+            created_at: Default::default(),
+            transfer_id: TRANSFER_ID,
+            base_path: None,
+            content_uri: None,
+            relative_path: format!("dir/{file_id}"),
+            file_id: file_id.into(),
+            bytes: 10,
+            bytes_sent: 0,
+            metadata: Default::default(),
+            states: states
+                .into_iter()
+                .map(|data| OutgoingPathStateEvent {
+                    path_id: 0,
+                    created_at: Default::default(),
+                    data,
+                })
+                .collect(),
+        }
+    }
+
+    fn states(report: &TransferReport) -> Vec<&'static str> {
+        report.files.iter().map(|file| file.state).collect()
+    }
+
+    #[test]
+    fn finished_transfer() {
+        let paths = vec![
+            incoming(
+                "a",
+                vec![In::Completed {
+                    final_path: "/tmp/dir/a".into(),
+                }],
+            ),
+            incoming(
+                "b",
+                vec![In::Rejected {
+                    by_peer: true,
+                    bytes_received: 0,
+                }],
+            ),
+        ];
+        let checksums = vec![
+            FileChecksum {
+                file_id: "a".into(),
+                checksum: Some(vec![0xab, 0x01]),
+            },
+            FileChecksum {
+                file_id: "b".into(),
+                checksum: None,
+            },
+        ];
+
+        let report = TransferReport::new(
+            transfer(vec![], DbTransferType::Incoming(paths)),
+            checksums,
+            "local".into(),
+            Some("peer".into()),
+        )
+        .unwrap();
+
+        assert_eq!(report.state, "finished");
+        assert!(!report.outgoing);
+        assert_eq!(states(&report), ["completed", "rejected"]);
+        assert_eq!(report.files[0].sha256.as_deref(), Some("ab01"));
+        assert_eq!(report.files[0].final_path.as_deref(), Some("/tmp/dir/a"));
+        assert_eq!(report.files[1].sha256, None);
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["peer_public_key"], "peer");
+        assert_eq!(json["local_public_key"], "local");
+        assert_eq!(json["files"][0]["path"], "dir/a");
+    }
+
+    #[test]
+    fn ongoing_transfer() {
+        let paths = vec![
+            outgoing("a", vec![Out::Completed { final_path: None }]),
+            outgoing("b", vec![Out::Started { bytes_sent: 0 }]),
+        ];
+
+        assert!(TransferReport::new(
+            transfer(vec![], DbTransferType::Outgoing(paths)),
+            vec![],
+            "local".into(),
+            None,
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn unfinished_files_take_the_transfer_state() {
+        for (state, expected) in [
+            (
+                TransferStateEventData::Cancel { by_peer: true },
+                "cancelled",
+            ),
+            (TransferStateEventData::Failed { status_code: 1 }, "failed"),
+        ] {
+            let paths = vec![
+                outgoing("a", vec![Out::Completed { final_path: None }]),
+                outgoing("b", vec![Out::Started { bytes_sent: 0 }]),
+                outgoing("c", vec![]),
+            ];
+
+            let report = TransferReport::new(
+                transfer(vec![state], DbTransferType::Outgoing(paths)),
+                vec![],
+                "local".into(),
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(report.state, expected);
+            assert!(report.outgoing);
+            assert_eq!(states(&report), ["completed", expected, expected]);
+        }
+    }
+}
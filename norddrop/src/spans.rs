@@ -0,0 +1,164 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    subscriber::Interest,
+    Dispatch, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer, Registry,
+};
+
+type Observer = Arc<dyn Fn(crate::SpanTiming) + Send + Sync>;
+
+static DISPATCH: OnceLock<Dispatch> = OnceLock::new();
+static OBSERVER: Mutex<Option<Observer>> = Mutex::new(None);
+
+// The subscriber tracking the transfer and file spans. It receives only the
+// spans of libdrop, the process-wide subscriber is left to the application
+fn dispatch() -> &'static Dispatch {
+    DISPATCH.get_or_init(|| Dispatch::new(Registry::default().with(TransferLayer)))
+}
+
+/// Makes libdrop report its spans to the subscriber tracking them
+pub(crate) fn install() {
+    drop_transfer::spans::set_dispatch(dispatch().clone());
+}
+
+/// Sets the callback receiving the timings of the closed spans
+pub(crate) fn set_observer(observer: impl Fn(crate::SpanTiming) + Send + Sync + 'static) {
+    *OBSERVER.lock().expect("Poisoned lock") = Some(Arc::new(observer));
+}
+
+/// The transfer and the file of the current span, if any
+pub(crate) fn current() -> Option<(String, Option<String>)> {
+    let registry = dispatch().downcast_ref::<Registry>()?;
+    let current = registry.current_span();
+    let span = registry.span(current.id()?)?;
+    let ext = span.extensions();
+    let data = ext.get::<SpanData>()?;
+
+    Some((data.transfer.clone()?, data.file.clone()))
+}
+
+fn is_libdrop(meta: &Metadata<'_>) -> bool {
+    meta.target().starts_with("drop_transfer")
+}
+
+struct SpanData {
+    transfer: Option<String>,
+    file: Option<String>,
+    task: Option<String>,
+    opened: Instant,
+    entered: Option<Instant>,
+    busy: Duration,
+}
+
+impl Visit for SpanData {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = Some(format!("{value:?}"));
+
+        match field.name() {
+            "transfer" => self.transfer = value,
+            "file" => self.file = value,
+            "task" => self.task = value,
+            _ => (),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        // Unlike `record_debug()` does not quote the value
+        self.record_debug(field, &format_args!("{value}"))
+    }
+}
+
+struct TransferLayer;
+
+impl<S> Layer<S> for TransferLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, meta: &'static Metadata<'static>) -> Interest {
+        if is_libdrop(meta) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        is_libdrop(meta)
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut data = SpanData {
+            transfer: None,
+            file: None,
+            task: None,
+            opened: Instant::now(),
+            entered: None,
+            busy: Duration::ZERO,
+        };
+        attrs.record(&mut data);
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(data);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                if let Some(entered) = data.entered.take() {
+                    data.busy += entered.elapsed();
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = if let Some(span) = ctx.span(&id) {
+            span
+        } else {
+            return;
+        };
+
+        let data = if let Some(data) = span.extensions_mut().remove::<SpanData>() {
+            data
+        } else {
+            return;
+        };
+
+        let transfer_id = if let Some(transfer_id) = data.transfer {
+            transfer_id
+        } else {
+            return;
+        };
+
+        let observer = OBSERVER.lock().expect("Poisoned lock").clone();
+        if let Some(observer) = observer {
+            observer(crate::SpanTiming {
+                name: data.task.unwrap_or_else(|| span.name().to_string()),
+                transfer_id,
+                file_id: data.file,
+                duration_ms: data.opened.elapsed().as_millis() as _,
+                busy_ms: data.busy.as_millis() as _,
+            });
+        }
+    }
+}
@@ -1,18 +1,512 @@
-use std::fmt;
+use std::{collections::HashMap, fmt, time::UNIX_EPOCH};
 
 use slog::Level;
 
 pub enum TransferDescriptor {
     Path {
         path: String,
+        exclude: Option<Vec<String>>,
+        app_metadata: Option<HashMap<String, String>>,
     },
     Fd {
         filename: String,
         content_uri: String,
         fd: Option<i32>,
+        app_metadata: Option<HashMap<String, String>>,
+    },
+    ContentUri {
+        content_uri: String,
+        app_metadata: Option<HashMap<String, String>>,
     },
 }
 
+impl TransferDescriptor {
+    pub(crate) fn app_metadata(&self) -> Option<&HashMap<String, String>> {
+        match self {
+            Self::Path { app_metadata, .. }
+            | Self::Fd { app_metadata, .. }
+            | Self::ContentUri { app_metadata, .. } => app_metadata.as_ref(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TransferOptions {
+    pub expire_after_ms: Option<u64>,
+    pub label: Option<String>,
+}
+
+pub struct RetryState {
+    pub transfer_id: String,
+    pub peer: String,
+    pub attempts: u32,
+    pub next_attempt_at: Option<i64>,
+    pub decision: Option<drop_transfer::ConnectionDecision>,
+}
+
+impl From<drop_transfer::OutgoingRetryState> for RetryState {
+    fn from(value: drop_transfer::OutgoingRetryState) -> Self {
+        Self {
+            transfer_id: value.transfer_id.to_string(),
+            peer: value.peer.to_string(),
+            attempts: value.attempts,
+            next_attempt_at: value.next_attempt.map(|time| {
+                time.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as i64
+            }),
+            decision: value.decision,
+        }
+    }
+}
+
+pub struct ActiveFile {
+    pub file_id: String,
+    pub status: drop_transfer::FileLiveStatus,
+    pub transferred_bytes: u64,
+    pub timings: Option<StageTimings>,
+}
+
+impl From<drop_transfer::FileSnapshot> for ActiveFile {
+    fn from(value: drop_transfer::FileSnapshot) -> Self {
+        Self {
+            file_id: value.file_id.to_string(),
+            status: value.status,
+            transferred_bytes: value.transferred,
+            timings: value.timings.map(From::from),
+        }
+    }
+}
+
+pub struct StageTimings {
+    pub read_us: u64,
+    pub encrypt_us: u64,
+    pub send_us: u64,
+    pub recv_us: u64,
+    pub decrypt_us: u64,
+    pub write_us: u64,
+}
+
+impl From<drop_transfer::StageTimings> for StageTimings {
+    fn from(value: drop_transfer::StageTimings) -> Self {
+        Self {
+            read_us: value.read.as_micros() as _,
+            encrypt_us: value.encrypt.as_micros() as _,
+            send_us: value.send.as_micros() as _,
+            recv_us: value.recv.as_micros() as _,
+            decrypt_us: value.decrypt.as_micros() as _,
+            write_us: value.write.as_micros() as _,
+        }
+    }
+}
+
+pub struct ActiveTransfer {
+    pub transfer_id: String,
+    pub peer: String,
+    pub connected: bool,
+    pub retry_attempts: u32,
+    pub files: Vec<ActiveFile>,
+}
+
+impl From<drop_transfer::TransferSnapshot> for ActiveTransfer {
+    fn from(value: drop_transfer::TransferSnapshot) -> Self {
+        Self {
+            transfer_id: value.transfer_id.to_string(),
+            peer: value.peer.to_string(),
+            connected: value.connected,
+            retry_attempts: value.retry_attempts,
+            files: value.files.into_iter().map(From::from).collect(),
+        }
+    }
+}
+
+pub struct InstanceState {
+    pub listen_addrs: Vec<String>,
+    pub read_only: bool,
+    pub low_power: bool,
+    pub incoming: Vec<ActiveTransfer>,
+    pub outgoing: Vec<ActiveTransfer>,
+}
+
+impl From<drop_transfer::InstanceSnapshot> for InstanceState {
+    fn from(value: drop_transfer::InstanceSnapshot) -> Self {
+        Self {
+            listen_addrs: value.addrs.iter().map(ToString::to_string).collect(),
+            read_only: value.read_only,
+            low_power: value.low_power,
+            incoming: value.incoming.into_iter().map(From::from).collect(),
+            outgoing: value.outgoing.into_iter().map(From::from).collect(),
+        }
+    }
+}
+
+pub enum SessionTransferState {
+    Pending,
+    Active,
+    Finished,
+    Canceled,
+    Failed,
+}
+
+pub enum SessionFileState {
+    Pending,
+    Queued,
+    InProgress,
+    Paused,
+    Completed,
+    Failed,
+    Rejected,
+    Withdrawn,
+}
+
+pub struct SessionFile {
+    pub file_id: String,
+    pub size: u64,
+    pub transferred_bytes: u64,
+    pub state: SessionFileState,
+    pub by_peer: bool,
+}
+
+impl From<&drop_session::FileSession> for SessionFile {
+    fn from(value: &drop_session::FileSession) -> Self {
+        use drop_session::FileState;
+
+        let (state, by_peer) = match value.state {
+            FileState::Pending => (SessionFileState::Pending, false),
+            FileState::Queued => (SessionFileState::Queued, false),
+            FileState::InProgress => (SessionFileState::InProgress, false),
+            FileState::Paused => (SessionFileState::Paused, false),
+            FileState::Completed => (SessionFileState::Completed, false),
+            FileState::Failed => (SessionFileState::Failed, false),
+            FileState::Rejected { by_peer } => (SessionFileState::Rejected, by_peer),
+            FileState::Withdrawn { by_peer } => (SessionFileState::Withdrawn, by_peer),
+        };
+
+        Self {
+            file_id: value.id.to_string(),
+            size: value.size,
+            transferred_bytes: value.transferred,
+            state,
+            by_peer,
+        }
+    }
+}
+
+pub struct TransferSession {
+    pub transfer_id: String,
+    pub peer: String,
+    pub outgoing: bool,
+    pub state: SessionTransferState,
+    pub by_peer: bool,
+    pub transferred_bytes: u64,
+    pub total_bytes: u64,
+    pub files: Vec<SessionFile>,
+}
+
+impl From<&drop_session::TransferSession> for TransferSession {
+    fn from(value: &drop_session::TransferSession) -> Self {
+        use drop_session::TransferState;
+
+        let (state, by_peer) = match value.state {
+            TransferState::Pending => (SessionTransferState::Pending, false),
+            TransferState::Active => (SessionTransferState::Active, false),
+            TransferState::Finished => (SessionTransferState::Finished, false),
+            TransferState::Canceled { by_peer } => (SessionTransferState::Canceled, by_peer),
+            TransferState::Failed => (SessionTransferState::Failed, false),
+        };
+
+        let mut files: Vec<SessionFile> = value.files.values().map(From::from).collect();
+        files.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+
+        Self {
+            transfer_id: value.id.to_string(),
+            peer: value.peer.to_string(),
+            outgoing: value.direction == drop_session::Direction::Outgoing,
+            state,
+            by_peer,
+            transferred_bytes: value.transferred(),
+            total_bytes: value.total_size(),
+            files,
+        }
+    }
+}
+
+pub struct EventQueueStats {
+    pub pending_events: u64,
+    pub lag_ms: u64,
+    pub max_lag_ms: u64,
+    pub dropped_events: u64,
+    pub slow_consumer: bool,
+}
+
+pub struct TransferStats {
+    pub throughput_bps: f64,
+    pub transferred_bytes: u64,
+    pub retransmitted_bytes: u64,
+    pub connection_attempts: u32,
+    pub rtt_ms: Option<u64>,
+}
+
+impl From<drop_transfer::TransferStats> for TransferStats {
+    fn from(value: drop_transfer::TransferStats) -> Self {
+        Self {
+            throughput_bps: value.throughput_bps,
+            transferred_bytes: value.transferred_bytes,
+            retransmitted_bytes: value.retransmitted_bytes,
+            connection_attempts: value.connection_attempts,
+            rtt_ms: value.rtt.map(|rtt| rtt.as_millis() as _),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HandshakePhaseTiming {
+    pub phase: crate::HandshakePhase,
+    pub duration_ms: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HandshakeDiagnostics {
+    pub peer: String,
+    pub started_at: i64,
+    pub phases: Vec<HandshakePhaseTiming>,
+    pub failed_phase: Option<crate::HandshakePhase>,
+    pub failure_reason: Option<String>,
+}
+
+impl From<drop_transfer::HandshakeDiagnostics> for HandshakeDiagnostics {
+    fn from(value: drop_transfer::HandshakeDiagnostics) -> Self {
+        let (failed_phase, failure_reason) = value.failure.unzip();
+
+        Self {
+            peer: value.peer.to_string(),
+            started_at: value
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_millis() as _),
+            phases: value
+                .phases
+                .into_iter()
+                .map(|timing| HandshakePhaseTiming {
+                    phase: timing.phase,
+                    duration_ms: timing.duration.as_millis() as _,
+                })
+                .collect(),
+            failed_phase,
+            failure_reason,
+        }
+    }
+}
+
+pub struct PeerDevice {
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+}
+
+impl From<drop_storage::PeerDevice> for PeerDevice {
+    fn from(value: drop_storage::PeerDevice) -> Self {
+        Self {
+            device_name: value.name,
+            platform: value.platform,
+        }
+    }
+}
+
+pub struct FileDownload {
+    pub file_id: String,
+    pub destination: String,
+}
+
+pub struct InboxEntry {
+    pub transfer_id: String,
+    pub origin_id: String,
+    pub size_bytes: u64,
+    pub in_progress: bool,
+}
+
+impl From<drop_transfer::InboxEntry> for InboxEntry {
+    fn from(value: drop_transfer::InboxEntry) -> Self {
+        Self {
+            transfer_id: value.transfer_id.to_string(),
+            origin_id: value.origin_id.to_string(),
+            size_bytes: value.size,
+            in_progress: value.in_progress,
+        }
+    }
+}
+
+pub struct ManifestFile {
+    pub id: String,
+    pub path: String,
+    pub size: u64,
+    pub base_dir: Option<String>,
+    pub state: SessionFileState,
+    pub transferred_bytes: u64,
+}
+
+pub struct TransferManifest {
+    pub transfer_id: String,
+    pub peer: String,
+    pub outgoing: bool,
+    pub total_size: u64,
+    pub files: Vec<ManifestFile>,
+}
+
+impl From<drop_storage::types::Transfer> for TransferManifest {
+    fn from(value: drop_storage::types::Transfer) -> Self {
+        use drop_storage::types::{
+            DbTransferType, IncomingPathStateEventData as In, OutgoingPathStateEventData as Out,
+        };
+
+        let (outgoing, files) =
+            match value.transfer_type {
+                DbTransferType::Incoming(paths) => (
+                    false,
+                    paths
+                        .into_iter()
+                        .map(|path| {
+                            // The download directory of the last request
+                            let base_dir = path.states.iter().rev().find_map(|state| match &state
+                                .data
+                            {
+                                In::Pending { base_dir } => Some(base_dir.clone()),
+                                _ => None,
+                            });
+                            let state = match path.states.last().map(|state| &state.data) {
+                                None => SessionFileState::Pending,
+                                Some(In::Pending { .. }) => SessionFileState::Queued,
+                                Some(In::Started { .. }) => SessionFileState::InProgress,
+                                Some(In::Paused { .. }) => SessionFileState::Paused,
+                                Some(In::Completed { .. }) => SessionFileState::Completed,
+                                Some(In::Failed { .. }) => SessionFileState::Failed,
+                                Some(In::Rejected { .. }) => SessionFileState::Rejected,
+                                Some(In::Withdrawn { .. }) => SessionFileState::Withdrawn,
+                            };
+
+                            ManifestFile {
+                                id: path.file_id,
+                                path: path.relative_path,
+                                size: path.bytes as _,
+                                base_dir,
+                                state,
+                                transferred_bytes: path.bytes_received as _,
+                            }
+                        })
+                        .collect(),
+                ),
+                DbTransferType::Outgoing(paths) => (
+                    true,
+                    paths
+                        .into_iter()
+                        .map(|path| {
+                            let state = match path.states.last().map(|state| &state.data) {
+                                None => SessionFileState::Pending,
+                                Some(Out::Started { .. }) => SessionFileState::InProgress,
+                                Some(Out::Paused { .. }) => SessionFileState::Paused,
+                                Some(Out::Completed { .. }) => SessionFileState::Completed,
+                                Some(Out::Failed { .. }) => SessionFileState::Failed,
+                                Some(Out::Rejected { .. }) => SessionFileState::Rejected,
+                                Some(Out::Withdrawn { .. }) => SessionFileState::Withdrawn,
+                            };
+
+                            ManifestFile {
+                                id: path.file_id,
+                                path: path.relative_path,
+                                size: path.bytes as _,
+                                base_dir: path
+                                    .base_path
+                                    .map(|base| base.to_string_lossy().into_owned()),
+                                state,
+                                transferred_bytes: path.bytes_sent as _,
+                            }
+                        })
+                        .collect(),
+                ),
+            };
+
+        Self {
+            transfer_id: value.id.to_string(),
+            peer: value.peer_id,
+            outgoing,
+            total_size: value.totals.total_bytes as _,
+            files,
+        }
+    }
+}
+
+pub struct SpanTiming {
+    pub name: String,
+    pub transfer_id: String,
+    pub file_id: Option<String>,
+    pub duration_ms: u64,
+    pub busy_ms: u64,
+}
+
+pub struct FailureCount {
+    pub status: crate::StatusCode,
+    pub count: u64,
+}
+
+pub struct Metrics {
+    pub active_incoming_transfers: u64,
+    pub active_outgoing_transfers: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub failures: Vec<FailureCount>,
+    pub db_size_bytes: Option<u64>,
+}
+
+pub struct StatusInfo {
+    pub category: crate::ErrorCategory,
+    pub retryable: bool,
+}
+
+impl From<crate::StatusCode> for StatusInfo {
+    fn from(status: crate::StatusCode) -> Self {
+        Self {
+            category: status.category(),
+            retryable: status.is_retryable(),
+        }
+    }
+}
+
+pub struct LocalAddress {
+    pub interface: String,
+    pub address: String,
+    pub mesh: bool,
+}
+
+impl From<drop_transfer::interfaces::LocalAddr> for LocalAddress {
+    fn from(value: drop_transfer::interfaces::LocalAddr) -> Self {
+        Self {
+            interface: value.interface,
+            address: value.ip.to_string(),
+            mesh: value.mesh,
+        }
+    }
+}
+
+pub struct BackgroundTaskCount {
+    pub subsystem: String,
+    pub count: u64,
+}
+
+pub struct BackgroundTask {
+    pub subsystem: String,
+    pub description: String,
+    pub running_ms: u64,
+}
+
+impl From<drop_transfer::TaskInfo> for BackgroundTask {
+    fn from(value: drop_transfer::TaskInfo) -> Self {
+        Self {
+            subsystem: value.subsystem.to_string(),
+            description: value.description,
+            running_ms: value.started_at.elapsed().as_millis() as _,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum LibdropError {
     /// Operation resulted to unknown error.
@@ -45,6 +539,9 @@ pub enum LibdropError {
 
     /// Database error
     DbError = 11,
+
+    /// The operation was refused by the read-only or receive-only mode
+    OperationRefused = 12,
 }
 
 impl fmt::Display for LibdropError {
@@ -1,6 +1,13 @@
-use std::sync::Mutex;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::{device::NordDropFFI, Event, TransferDescriptor, TransferInfo};
+use drop_transfer::Transfer;
+
+use crate::{
+    device::NordDropFFI, poll::EventQueue, Event, TransferDescriptor, TransferInfo, TransferOptions,
+};
 
 pub type Result<T> = std::result::Result<T, crate::LibdropError>;
 
@@ -22,8 +29,46 @@ pub trait FdResolver: Send + Sync {
     fn on_fd(&self, content_uri: String) -> Option<i32>;
 }
 
+pub trait FileLifecycle: Send + Sync {
+    fn on_open_write(
+        &self,
+        transfer_id: String,
+        file_id: String,
+        content_uri: String,
+    ) -> Option<i32>;
+    fn on_close(&self, content_uri: String, success: bool);
+}
+
+pub trait ContentResolver: Send + Sync {
+    fn on_display_name(&self, content_uri: String) -> Option<String>;
+    fn on_persist(&self, content_uri: String, keep: bool);
+}
+
+pub trait ConnectionPolicy: Send + Sync {
+    fn on_connect(&self, peer: String, transfer_id: String) -> crate::ConnectionDecision;
+}
+
+pub trait IncomingFilter: Send + Sync {
+    fn on_request(
+        &self,
+        peer: String,
+        transfer_id: String,
+        files: Vec<crate::ReceivedFile>,
+    ) -> crate::IncomingDecision;
+}
+
+pub trait SpanObserver: Send + Sync {
+    fn on_span_closed(&self, span: crate::SpanTiming);
+}
+
+pub trait PreviewProvider: Send + Sync {
+    fn on_preview(&self, uri: String, mime_type: String) -> Option<Vec<u8>>;
+}
+
 pub struct NordDrop {
     dev: Mutex<NordDropFFI>,
+    // Present when the instance is created without the event callback
+    events: Option<Arc<EventQueue>>,
 }
 
 impl NordDrop {
@@ -42,9 +87,34 @@ impl NordDrop {
 
         Ok(Self {
             dev: Mutex::new(dev),
+            events: None,
         })
     }
 
+    pub fn new_polling(key_store: Box<dyn KeyStore>, logger: Box<dyn Logger>) -> Result<Self> {
+        let logger = super::log::create(logger);
+        let events = Arc::new(EventQueue::default());
+
+        let dev = NordDropFFI::new(
+            {
+                let events = events.clone();
+                move |ev| events.push(ev)
+            },
+            key_store.into(),
+            logger,
+        )?;
+
+        Ok(Self {
+            dev: Mutex::new(dev),
+            events: Some(events),
+        })
+    }
+
+    pub fn next_event(&self, timeout_ms: u32) -> Result<Option<Event>> {
+        let events = self.events.as_ref().ok_or(crate::LibdropError::BadInput)?;
+        Ok(events.next(Duration::from_millis(timeout_ms as _)))
+    }
+
     #[cfg(not(unix))]
     pub fn set_fd_resolver(&self, resolver: Box<dyn FdResolver>) -> Result<()> {
         Err(crate::LibdropError::Unknown)
@@ -60,17 +130,88 @@ impl NordDrop {
         Ok(())
     }
 
-    pub fn start(&self, addr: &str, config: crate::Config) -> Result<()> {
+    #[cfg(not(unix))]
+    pub fn set_content_resolver(&self, resolver: Box<dyn ContentResolver>) -> Result<()> {
+        Err(crate::LibdropError::Unknown)
+    }
+
+    #[cfg(unix)]
+    pub fn set_content_resolver(&self, resolver: Box<dyn ContentResolver>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_content_resolver(resolver.into())
+    }
+
+    #[cfg(not(unix))]
+    pub fn set_file_lifecycle(&self, lifecycle: Box<dyn FileLifecycle>) -> Result<()> {
+        Err(crate::LibdropError::Unknown)
+    }
+
+    #[cfg(unix)]
+    pub fn set_file_lifecycle(&self, lifecycle: Box<dyn FileLifecycle>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_file_lifecycle(lifecycle.into())
+    }
+
+    pub fn set_connection_policy(&self, policy: Box<dyn ConnectionPolicy>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_connection_policy(move |peer, id| {
+                policy.on_connect(peer.to_string(), id.to_string())
+            })
+    }
+
+    pub fn set_incoming_filter(&self, filter: Box<dyn IncomingFilter>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_incoming_filter(move |xfer| {
+                filter.on_request(
+                    xfer.peer().to_string(),
+                    xfer.id().to_string(),
+                    xfer.files().values().map(From::from).collect(),
+                )
+            })
+    }
+
+    pub fn set_span_observer(&self, observer: Box<dyn SpanObserver>) -> Result<()> {
         self.dev
             .lock()
             .expect("Poisoned lock")
-            .start(addr, config.into())
+            .set_span_observer(move |span| observer.on_span_closed(span))
+    }
+
+    pub fn set_preview_provider(&self, provider: Box<dyn PreviewProvider>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_preview_provider(move |uri, mime| {
+                provider.on_preview(uri.to_string(), mime.to_string())
+            })
+    }
+
+    pub fn start(&self, addrs: &[String], config: crate::Config) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .start(addrs, config.into())
     }
 
     pub fn stop(&self) -> Result<()> {
         self.dev.lock().expect("Poisoned lock").stop()
     }
 
+    pub fn stop_graceful(&self, timeout_ms: u64) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .stop_graceful(timeout_ms)
+    }
+
     pub fn purge_transfers(&self, transfer_ids: &[String]) -> Result<()> {
         self.dev
             .lock()
@@ -94,64 +235,370 @@ impl NordDrop {
             .dev
             .lock()
             .expect("Poisoned lock")
-            .transfers_since(since / 1000)?;
+            .transfers_since(since / 1000, None)?;
 
         let xfers = infos.into_iter().map(TransferInfo::from).collect();
         Ok(xfers)
     }
 
-    pub fn new_transfer(&self, peer: &str, descriptors: &[TransferDescriptor]) -> Result<String> {
-        let transfer_id = self
+    pub fn transfers_with_label(&self, since: i64, label: &str) -> Result<Vec<TransferInfo>> {
+        let infos = self
             .dev
             .lock()
             .expect("Poisoned lock")
-            .new_transfer(peer, descriptors)?;
+            .transfers_since(since / 1000, Some(label))?;
+
+        let xfers = infos.into_iter().map(TransferInfo::from).collect();
+        Ok(xfers)
+    }
+
+    pub fn get_events_since(&self, seq: u64) -> Result<Vec<Event>> {
+        self.dev.lock().expect("Poisoned lock").events_since(seq)
+    }
+
+    pub fn new_transfer(&self, peer: &str, descriptors: &[TransferDescriptor]) -> Result<String> {
+        self.new_transfer_with_options(peer, descriptors, &TransferOptions::default())
+    }
+
+    pub fn new_transfer_with_options(
+        &self,
+        peer: &str,
+        descriptors: &[TransferDescriptor],
+        options: &TransferOptions,
+    ) -> Result<String> {
+        let transfer_id = self.dev.lock().expect("Poisoned lock").new_transfer(
+            peer,
+            None,
+            descriptors,
+            options,
+        )?;
 
         Ok(transfer_id.to_string())
     }
 
+    pub fn new_transfer_via(
+        &self,
+        relay: &str,
+        peer: &str,
+        descriptors: &[TransferDescriptor],
+    ) -> Result<String> {
+        let transfer_id = self.dev.lock().expect("Poisoned lock").new_transfer(
+            relay,
+            Some(peer),
+            descriptors,
+            &TransferOptions::default(),
+        )?;
+
+        Ok(transfer_id.to_string())
+    }
+
+    pub fn add_files(&self, transfer_id: &str, descriptors: &[TransferDescriptor]) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.add_files(transfer_id, descriptors)
+    }
+
     pub fn finalize_transfer(&self, transfer_id: &str) -> Result<()> {
-        self.dev.lock().expect("Poisoned lock").cancel_transfer(
-            transfer_id
-                .parse()
-                .map_err(|_| crate::LibdropError::InvalidString)?,
-        )
+        let mut dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.cancel_transfer(transfer_id)
+    }
+
+    pub fn retry_transfer(&self, transfer_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.retry_transfer(transfer_id)
+    }
+
+    pub fn withdraw_transfer(&self, transfer_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.withdraw_transfer(transfer_id)
+    }
+
+    pub fn withdraw_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.withdraw_file(transfer_id, file_id.to_string())
+    }
+
+    pub fn pause_transfer(&self, transfer_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.set_transfer_paused(transfer_id, true)
+    }
+
+    pub fn resume_transfer(&self, transfer_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.set_transfer_paused(transfer_id, false)
+    }
+
+    pub fn pace_file(
+        &self,
+        transfer_id: &str,
+        file_id: &str,
+        bytes_per_second: Option<u64>,
+    ) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.pace_file(transfer_id, file_id.to_string(), bytes_per_second)
     }
 
     pub fn remove_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.remove_transfer_file(transfer_id, file_id)
+    }
+
+    pub fn remove_partial_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.remove_partial_file(transfer_id, file_id.to_string())
+    }
+
+    pub fn set_transfer_note(&self, transfer_id: &str, note: Option<String>) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.set_transfer_note(transfer_id, note.as_deref())
+    }
+
+    pub fn download_file(&self, transfer_id: &str, file_id: &str, destination: &str) -> Result<()> {
+        let mut dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.download(transfer_id, file_id.to_string(), destination.to_string())
+    }
+
+    pub fn download_many(&self, transfer_id: &str, files: Vec<crate::FileDownload>) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.download_many(transfer_id, files)
+    }
+
+    #[cfg(not(unix))]
+    pub fn download_file_to_fd(
+        &self,
+        transfer_id: &str,
+        file_id: &str,
+        content_uri: &str,
+        fd: i32,
+    ) -> Result<()> {
+        Err(crate::LibdropError::Unknown)
+    }
+
+    #[cfg(unix)]
+    pub fn download_file_to_fd(
+        &self,
+        transfer_id: &str,
+        file_id: &str,
+        content_uri: &str,
+        fd: i32,
+    ) -> Result<()> {
+        let content_uri: url::Url = content_uri
+            .parse()
+            .map_err(|_| crate::LibdropError::InvalidString)?;
+        if content_uri.scheme() != "content" {
+            return Err(crate::LibdropError::BadInput);
+        }
+
+        let mut dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.download_to_fd(transfer_id, file_id.to_string(), content_uri, fd)
+    }
+
+    #[cfg(not(unix))]
+    pub fn download_file_to_uri(
+        &self,
+        transfer_id: &str,
+        file_id: &str,
+        content_uri: &str,
+    ) -> Result<()> {
+        Err(crate::LibdropError::Unknown)
+    }
+
+    #[cfg(unix)]
+    pub fn download_file_to_uri(
+        &self,
+        transfer_id: &str,
+        file_id: &str,
+        content_uri: &str,
+    ) -> Result<()> {
+        let content_uri: url::Url = content_uri
+            .parse()
+            .map_err(|_| crate::LibdropError::InvalidString)?;
+
+        let mut dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.download_to_uri(transfer_id, file_id.to_string(), content_uri)
+    }
+
+    pub fn reject_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.reject_file(transfer_id, file_id.to_string())
+    }
+
+    pub fn get_retry_states(&self) -> Result<Vec<crate::RetryState>> {
+        self.dev.lock().expect("Poisoned lock").retry_states()
+    }
+
+    pub fn get_state(&self) -> Result<crate::InstanceState> {
+        self.dev.lock().expect("Poisoned lock").state()
+    }
+
+    pub fn get_transfer_manifest(&self, transfer_id: &str) -> Result<crate::TransferManifest> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.transfer_manifest(transfer_id)
+    }
+
+    pub fn get_transfer_report(&self, transfer_id: &str) -> Result<String> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.transfer_report(transfer_id)
+    }
+
+    pub fn get_inbox(&self) -> Result<Vec<crate::InboxEntry>> {
+        self.dev.lock().expect("Poisoned lock").inbox()
+    }
+
+    pub fn claim_inbox(&self, transfer_id: &str, dst: &str) -> Result<Vec<String>> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.claim_inbox(transfer_id, dst)
+    }
+
+    pub fn forward_inbox(&self, transfer_id: &str, peer: &str) -> Result<String> {
+        let mut dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        let transfer_id = dev.forward_inbox(transfer_id, peer)?;
+
+        Ok(transfer_id.to_string())
+    }
+
+    pub fn share_directory(&self, peer: &str, path: &str) -> Result<String> {
+        let share_id = self
+            .dev
+            .lock()
+            .expect("Poisoned lock")
+            .share_directory(peer, path)?;
+
+        Ok(share_id.to_string())
+    }
+
+    pub fn stop_sharing(&self, share_id: &str) -> Result<()> {
+        let share_id = share_id
+            .parse()
+            .map_err(|_| crate::LibdropError::InvalidString)?;
+
         self.dev
             .lock()
             .expect("Poisoned lock")
-            .remove_transfer_file(
-                transfer_id
-                    .parse()
-                    .map_err(|_| crate::LibdropError::InvalidString)?,
-                file_id,
-            )
+            .stop_sharing(share_id)
     }
 
-    pub fn download_file(&self, transfer_id: &str, file_id: &str, destination: &str) -> Result<()> {
-        self.dev.lock().expect("Poisoned lock").download(
-            transfer_id
-                .parse()
-                .map_err(|_| crate::LibdropError::InvalidString)?,
-            file_id.to_string(),
-            destination.to_string(),
-        )
+    pub fn peer_quota_usage(&self, peer: &str) -> Result<u64> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .peer_quota_usage(peer)
     }
 
-    pub fn reject_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
-        self.dev.lock().expect("Poisoned lock").reject_file(
-            transfer_id
-                .parse()
-                .map_err(|_| crate::LibdropError::InvalidString)?,
-            file_id.to_string(),
-        )
+    pub fn get_connection_diagnostics(&self, peer: &str) -> Result<crate::HandshakeDiagnostics> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .connection_diagnostics(peer)
+    }
+
+    pub fn get_peer_device(&self, peer: &str) -> Result<Option<crate::PeerDevice>> {
+        self.dev.lock().expect("Poisoned lock").peer_device(peer)
+    }
+
+    pub fn send_text(&self, peer: &str, text: &str) -> Result<String> {
+        let message_id = self
+            .dev
+            .lock()
+            .expect("Poisoned lock")
+            .send_text(peer, text)?;
+
+        Ok(message_id.to_string())
+    }
+
+    pub fn get_sessions(&self, clear_finished: bool) -> Result<Vec<crate::TransferSession>> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .sessions(clear_finished)
+    }
+
+    pub fn get_transfer_stats(&self, transfer_id: &str) -> Result<crate::TransferStats> {
+        let dev = self.dev.lock().expect("Poisoned lock");
+        let transfer_id = dev.transfer_id(transfer_id)?;
+        dev.transfer_stats(transfer_id)
+    }
+
+    pub fn get_metrics(&self) -> Result<crate::Metrics> {
+        self.dev.lock().expect("Poisoned lock").metrics()
+    }
+
+    pub fn get_event_queue_stats(&self) -> Result<crate::EventQueueStats> {
+        self.dev.lock().expect("Poisoned lock").event_queue_stats()
+    }
+
+    pub fn get_background_task_counts(&self) -> Result<Vec<crate::BackgroundTaskCount>> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .background_task_counts()
+    }
+
+    pub fn get_background_tasks(&self) -> Result<Vec<crate::BackgroundTask>> {
+        self.dev.lock().expect("Poisoned lock").background_tasks()
     }
 
     pub fn network_refresh(&self) -> Result<()> {
         self.dev.lock().expect("Poisoned lock").network_refresh()
     }
+
+    pub fn prepare_background(&self) -> Result<String> {
+        self.dev.lock().expect("Poisoned lock").prepare_background()
+    }
+
+    pub fn restore_background(&self, state: &str) -> Result<Vec<String>> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .restore_background(state)
+    }
+
+    pub fn set_low_power_mode(&self, enabled: bool) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_low_power_mode(enabled)
+    }
+
+    pub fn invalidate_peer_key(&self, peer: &str) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .invalidate_peer_key(peer)
+    }
+
+    pub fn set_private_key(&self, privkey: Vec<u8>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_private_key(privkey)
+    }
+
+    pub fn set_read_only_mode(&self, enabled: bool) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_read_only_mode(enabled)
+    }
 }
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -159,6 +606,24 @@ extern "C" {
     fn fortify_source();
 }
 
+pub fn local_addresses(mesh_only: bool) -> Result<Vec<crate::LocalAddress>> {
+    let addrs = drop_transfer::interfaces::local_addrs(mesh_only)
+        .map_err(|_| crate::LibdropError::Unknown)?;
+
+    Ok(addrs.into_iter().map(From::from).collect())
+}
+
+pub fn pick_listen_address(mesh_only: bool) -> Result<Option<String>> {
+    let addr = drop_transfer::interfaces::pick_listen_addr(mesh_only)
+        .map_err(|_| crate::LibdropError::Unknown)?;
+
+    Ok(addr.map(|addr| addr.to_string()))
+}
+
+pub fn describe_status(status: crate::StatusCode) -> crate::StatusInfo {
+    status.into()
+}
+
 pub fn version() -> String {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     unsafe {
@@ -207,6 +207,11 @@ def compare_json_to_outgoing_path_state(
         )
     elif actual.kind.is_completed():
         compare_value(expected, "OutgoingPathState", "state", "completed")
+        if actual.kind.final_path is not None:
+            keys.append("final_path")
+            compare_value(
+                expected, "OutgoingPathState", "final_path", actual.kind.final_path
+            )
     elif actual.kind.is_rejected():
         compare_value(expected, "OutgoingPathState", "state", "rejected")
         keys.append("bytes_sent")
@@ -273,10 +278,30 @@ def compare_json_to_transfer_state(expected: dict, actual: norddrop.TransferStat
 
 
 def compare_json_to_transfer_info(expected: dict, actual: norddrop.TransferInfo):
-    keys = ["id", "created_at", "peer_id", "type", "states", "paths"]
+    keys = [
+        "id",
+        "created_at",
+        "peer_id",
+        "type",
+        "states",
+        "paths",
+        "total_bytes",
+        "transferred_bytes",
+        "duration_ms",
+        "average_bps",
+        "peer_clock_skew_ms",
+        "note",
+    ]
 
     compare_value(expected, "TransferInfo", "id", actual.id)
     compare_value(expected, "TransferInfo", "created_at", actual.created_at)
+    compare_value(expected, "TransferInfo", "total_bytes", actual.total_bytes)
+    compare_value(
+        expected, "TransferInfo", "transferred_bytes", actual.transferred_bytes
+    )
+    compare_value(expected, "TransferInfo", "duration_ms", actual.duration_ms)
+    compare_value(expected, "TransferInfo", "average_bps", actual.average_bps)
+    compare_value(expected, "TransferInfo", "note", actual.note)
 
     if "peer_id" in expected:
         eval = expected["peer_id"]
@@ -442,6 +467,35 @@ class NewTransfer(Action):
         return f"NewTransfer({peer_resolver.resolve(self._peer)}, {self._paths})"
 
 
+class SendText(Action):
+    def __init__(self, peer: str, text: str):
+        self._peer: str = peer
+        self._text: str = text
+
+    async def run(self, drop: ffi.Drop):
+        drop.send_text(peer_resolver.resolve(self._peer), self._text)
+
+    def __str__(self):
+        return f"SendText({peer_resolver.resolve(self._peer)}, {self._text!r})"
+
+
+class SendTextFails(Action):
+    def __init__(self, peer: str, text: str):
+        self._peer: str = peer
+        self._text: str = text
+
+    async def run(self, drop: ffi.Drop):
+        try:
+            drop.send_text(peer_resolver.resolve(self._peer), self._text)
+        except:
+            return
+
+        raise Exception("SendTextFails did not fail")
+
+    def __str__(self):
+        return f"SendTextFails({peer_resolver.resolve(self._peer)}, {self._text!r})"
+
+
 # New transfer just with files preopened. Used to test Android. Android can't share directories
 # so this is limited to accept a single file
 class NewTransferWithFD(Action):
@@ -493,6 +547,43 @@ class CancelTransferRequest(Action):
         return f"CancelTransferRequest({uuid_strings})"
 
 
+class RetryTransfer(Action):
+    def __init__(self, uuid_slot: int):
+        self._uuid_slot = uuid_slot
+
+    async def run(self, drop: ffi.Drop):
+        with UUIDS_LOCK:
+            drop.retry_transfer(UUIDS[self._uuid_slot])
+
+    def __str__(self):
+        return f"RetryTransfer({print_uuid(self._uuid_slot)})"
+
+
+class WithdrawTransfer(Action):
+    def __init__(self, uuid_slot: int):
+        self._uuid_slot = uuid_slot
+
+    async def run(self, drop: ffi.Drop):
+        with UUIDS_LOCK:
+            drop.withdraw_transfer(UUIDS[self._uuid_slot])
+
+    def __str__(self):
+        return f"WithdrawTransfer({print_uuid(self._uuid_slot)})"
+
+
+class WithdrawTransferFile(Action):
+    def __init__(self, uuid_slot: int, fid):
+        self._uuid_slot = uuid_slot
+        self._fid = fid
+
+    async def run(self, drop: ffi.Drop):
+        with UUIDS_LOCK:
+            drop.withdraw_transfer_file(UUIDS[self._uuid_slot], self._fid)
+
+    def __str__(self):
+        return f"WithdrawTransferFile({print_uuid(self._uuid_slot)}, {self._fid})"
+
+
 class RejectTransferFile(Action):
     def __init__(self, uuid_slot: int, fid):
         self._uuid_slot = uuid_slot
@@ -804,6 +895,17 @@ class Stop(Action):
         return "Stop"
 
 
+class StopGraceful(Action):
+    def __init__(self, timeout_ms: int):
+        self._timeout_ms = timeout_ms
+
+    async def run(self, drop: ffi.Drop):
+        drop.stop_graceful(self._timeout_ms)
+
+    def __str__(self):
+        return f"StopGraceful({self._timeout_ms})"
+
+
 class ModifyFile(Action):
     def __init__(self, file_glob: str):
         self._file = file_glob
@@ -967,6 +1069,36 @@ class PurgeTransfers(Action):
         return f"PurgeTransfers({self.uuid_indices})"
 
 
+class AssertPeerDevice(Action):
+    def __init__(
+        self,
+        peer: str,
+        device_name: typing.Optional[str],
+        platform: typing.Optional[str],
+    ):
+        self._peer = peer
+        self._device_name = device_name
+        self._platform = platform
+
+    async def run(self, drop: ffi.Drop):
+        device = drop.get_peer_device(peer_resolver.resolve(self._peer))
+
+        if self._device_name is None and self._platform is None:
+            if device is not None:
+                raise Exception(f"Expected no device, got {device}")
+            return
+
+        if device is None:
+            raise Exception("Expected the device, got none")
+        if (device.device_name, device.platform) != (self._device_name, self._platform):
+            raise Exception(
+                f"Expected device ({self._device_name}, {self._platform}), got ({device.device_name}, {device.platform})"
+            )
+
+    def __str__(self):
+        return f"AssertPeerDevice({self._peer}, {self._device_name}, {self._platform})"
+
+
 class Start(Action):
     def __init__(
         self,
@@ -975,12 +1107,18 @@ class Start(Action):
         checksum_events_size_threshold=2**32,  # don't emit events for existing tests
         checksum_events_granularity=None,
         auto_retry_interval_ms=None,
+        device_name=None,
+        platform=None,
+        transfer_mode=None,
     ):
         self._addr = addr
         self._dbpath = dbpath
         self._checksum_events_size_threshold = checksum_events_size_threshold
         self._checksum_events_granularity = checksum_events_granularity
         self._auto_retry_interval_ms = auto_retry_interval_ms
+        self._device_name = device_name
+        self._platform = platform
+        self._transfer_mode = transfer_mode
 
     async def run(self, drop: ffi.Drop):
         drop.start(
@@ -989,6 +1127,9 @@ class Start(Action):
             self._checksum_events_size_threshold,
             self._checksum_events_granularity,
             self._auto_retry_interval_ms,
+            self._device_name,
+            self._platform,
+            self._transfer_mode,
         )
 
     def __str__(self):
@@ -38,6 +38,11 @@ def is_equal(s, t):
     return not t
 
 
+def equal_if_set(lhs, rhs) -> bool:
+    # The unset values are not compared
+    return lhs is None or rhs is None or lhs == rhs
+
+
 class Event:
     def __init__(self):
         raise Exception("Base Event class should not be initialized")
@@ -45,11 +50,18 @@ class Event:
 
 class Queued(Event):
     def __init__(
-        self, uuid_slot: int, peer: str, files: typing.List[norddrop.QueuedFile]
+        self,
+        uuid_slot: int,
+        peer: str,
+        files: typing.List[norddrop.QueuedFile],
+        excluded: int = 0,
+        total_size: typing.Optional[int] = None,
     ):
         self._uuid_slot = uuid_slot
         self._peer: str = peer
         self._files: typing.List[norddrop.QueuedFile] = files
+        self._excluded: int = excluded
+        self._total_size: typing.Optional[int] = total_size
 
     def __eq__(self, rhs) -> bool:
         if not isinstance(rhs, Queued):
@@ -59,20 +71,31 @@ class Queued(Event):
             self._uuid_slot == rhs._uuid_slot
             and self._peer == rhs._peer
             and is_equal(self._files, rhs._files)
+            and self._excluded == rhs._excluded
+            and equal_if_set(self._total_size, rhs._total_size)
         )
 
     def __str__(self):
         files = ", ".join(str(p) for p in self._files)
-        return f"Queued(peer={self._peer}, uuid={print_uuid(self._uuid_slot)}, files=[{files}])"
+        return f"Queued(peer={self._peer}, uuid={print_uuid(self._uuid_slot)}, files=[{files}], excluded={self._excluded})"
 
 
 class Receive(Event):
     def __init__(
-        self, uuid_slot: int, peer: str, files: typing.List[norddrop.ReceivedFile]
+        self,
+        uuid_slot: int,
+        peer: str,
+        files: typing.List[norddrop.ReceivedFile],
+        total_size: typing.Optional[int] = None,
+        device_name: typing.Optional[str] = None,
+        platform: typing.Optional[str] = None,
     ):
         self._uuid_slot: int = uuid_slot
         self._peer: str = peer
         self._files: typing.List[norddrop.ReceivedFile] = files
+        self._total_size: typing.Optional[int] = total_size
+        self._device_name: typing.Optional[str] = device_name
+        self._platform: typing.Optional[str] = platform
 
     def __eq__(self, rhs) -> bool:
         if not isinstance(rhs, Receive):
@@ -82,6 +105,9 @@ class Receive(Event):
             self._uuid_slot == rhs._uuid_slot
             and self._peer == rhs._peer
             and is_equal(self._files, rhs._files)
+            and equal_if_set(self._total_size, rhs._total_size)
+            and equal_if_set(self._device_name, rhs._device_name)
+            and equal_if_set(self._platform, rhs._platform)
         )
 
     def __str__(self):
@@ -208,6 +234,25 @@ class FinishTransferCanceled(Event):
         return f"FinishTransferCanceled(transfer={print_uuid(self._uuid_slot)}, by_peer={self._by_peer})"
 
 
+class TransferWithdrawn(Event):
+    def __init__(self, uuid_slot: int, by_peer: bool):
+        self._uuid_slot = uuid_slot
+        self._by_peer = by_peer
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferWithdrawn):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._by_peer != rhs._by_peer:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferWithdrawn(transfer={print_uuid(self._uuid_slot)}, by_peer={self._by_peer})"
+
+
 class FinishFileUploaded(Event):
     def __init__(self, uuid_slot: int, file: str):
         self._uuid_slot = uuid_slot
@@ -271,6 +316,28 @@ class FinishFileRejected(Event):
         return f"FinishFileRejected(transfer={print_uuid(self._uuid_slot)}, file={self._file}, by_peer={self._by_peer})"
 
 
+class FinishFileWithdrawn(Event):
+    def __init__(self, uuid_slot: int, file: str, by_peer: bool):
+        self._uuid_slot = uuid_slot
+        self._file = file
+        self._by_peer = by_peer
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, FinishFileWithdrawn):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._file != rhs._file:
+            return False
+        if self._by_peer != rhs._by_peer:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"FinishFileWithdrawn(transfer={print_uuid(self._uuid_slot)}, file={self._file}, by_peer={self._by_peer})"
+
+
 class Paused(Event):
     def __init__(self, uuid_slot: int, file: str):
         self._uuid_slot = uuid_slot
@@ -502,6 +569,151 @@ class RuntimeError(Event):
         return f"RuntimeError(status={self._status})"
 
 
+class StartupReconciled(Event):
+    def __init__(self, paused_files: int, failed_transfers: int, failed_files: int):
+        self._paused_files = paused_files
+        self._failed_transfers = failed_transfers
+        self._failed_files = failed_files
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, StartupReconciled):
+            return False
+        return (
+            self._paused_files == rhs._paused_files
+            and self._failed_transfers == rhs._failed_transfers
+            and self._failed_files == rhs._failed_files
+        )
+
+    def __str__(self):
+        return f"StartupReconciled(paused_files={self._paused_files}, failed_transfers={self._failed_transfers}, failed_files={self._failed_files})"
+
+
+class KeyRotationCompleted(Event):
+    def __init__(self):
+        pass
+
+    def __eq__(self, rhs):
+        return isinstance(rhs, KeyRotationCompleted)
+
+    def __str__(self):
+        return "KeyRotationCompleted()"
+
+
+class OperationRefused(Event):
+    def __init__(self, uuid_slot: int, file: typing.Optional[str], operation):
+        self._uuid_slot = uuid_slot
+        self._file = file
+        self._operation = operation
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, OperationRefused):
+            return False
+        return (
+            self._uuid_slot == rhs._uuid_slot
+            and self._file == rhs._file
+            and self._operation == rhs._operation
+        )
+
+    def __str__(self):
+        return f"OperationRefused(transfer={print_uuid(self._uuid_slot)}, file={self._file}, operation={self._operation})"
+
+
+class DirectoryPrepared(Event):
+    def __init__(self, uuid_slot: int, root: str, path: str):
+        self._uuid_slot = uuid_slot
+        self._root = root
+        self._path = path
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, DirectoryPrepared):
+            return False
+        return (
+            self._uuid_slot == rhs._uuid_slot
+            and self._root == rhs._root
+            and self._path == rhs._path
+        )
+
+    def __str__(self):
+        return f"DirectoryPrepared(transfer={print_uuid(self._uuid_slot)}, root={self._root}, path={self._path})"
+
+
+class DownloadsQueued(Event):
+    def __init__(self, uuid_slot: int, files: typing.List[str]):
+        self._uuid_slot = uuid_slot
+        self._files = sorted(files)
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, DownloadsQueued):
+            return False
+        return self._uuid_slot == rhs._uuid_slot and self._files == rhs._files
+
+    def __str__(self):
+        return f"DownloadsQueued(transfer={print_uuid(self._uuid_slot)}, files={self._files})"
+
+
+class FilesQueued(Event):
+    def __init__(self, uuid_slot: int, files: typing.List[norddrop.QueuedFile]):
+        self._uuid_slot = uuid_slot
+        self._files: typing.List[norddrop.QueuedFile] = files
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, FilesQueued):
+            return False
+        return self._uuid_slot == rhs._uuid_slot and is_equal(self._files, rhs._files)
+
+    def __str__(self):
+        files = ", ".join(str(p) for p in self._files)
+        return f"FilesQueued(transfer={print_uuid(self._uuid_slot)}, files=[{files}])"
+
+
+class FilesReceived(Event):
+    def __init__(self, uuid_slot: int, files: typing.List[norddrop.ReceivedFile]):
+        self._uuid_slot = uuid_slot
+        self._files: typing.List[norddrop.ReceivedFile] = files
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, FilesReceived):
+            return False
+        return self._uuid_slot == rhs._uuid_slot and is_equal(self._files, rhs._files)
+
+    def __str__(self):
+        files = ", ".join(str(p) for p in self._files)
+        return f"FilesReceived(transfer={print_uuid(self._uuid_slot)}, files=[{files}])"
+
+
+class DuplicateFileDetected(Event):
+    def __init__(self, uuid_slot: int, file: str, existing_path: str):
+        self._uuid_slot = uuid_slot
+        self._file = file
+        self._existing_path = existing_path
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, DuplicateFileDetected):
+            return False
+        return (
+            self._uuid_slot == rhs._uuid_slot
+            and self._file == rhs._file
+            and self._existing_path == rhs._existing_path
+        )
+
+    def __str__(self):
+        return f"DuplicateFileDetected(transfer={print_uuid(self._uuid_slot)}, file={self._file}, existing_path={self._existing_path})"
+
+
+class PreflightFinished(Event):
+    def __init__(self, uuid_slot: int, files: typing.List[str]):
+        self._uuid_slot = uuid_slot
+        self._files = sorted(files)
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, PreflightFinished):
+            return False
+        return self._uuid_slot == rhs._uuid_slot and self._files == rhs._files
+
+    def __str__(self):
+        return f"PreflightFinished(transfer={print_uuid(self._uuid_slot)}, unreadable={self._files})"
+
+
 class TransferDeferred(Event):
     def __init__(
         self,
@@ -534,3 +746,201 @@ class TransferDeferred(Event):
 
     def __str__(self):
         return f"TransferDeferred(transfer={print_uuid(self._uuid_slot)}, peer={self._peer}, status={self._status}, os_err={self._os_err})"
+
+
+class TransferPostponed(Event):
+    def __init__(self, uuid_slot: int, peer: str):
+        self._uuid_slot = uuid_slot
+        self._peer = peer
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferPostponed):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._peer != rhs._peer:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferPostponed(transfer={print_uuid(self._uuid_slot)}, peer={self._peer})"
+
+
+class PeerKeyUnavailable(Event):
+    def __init__(self, uuid_slot: int, peer: str):
+        self._uuid_slot = uuid_slot
+        self._peer = peer
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, PeerKeyUnavailable):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._peer != rhs._peer:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"PeerKeyUnavailable(transfer={print_uuid(self._uuid_slot)}, peer={self._peer})"
+
+
+class ConnectionAttemptFailed(Event):
+    def __init__(
+        self, uuid_slot: int, attempt: int, next_retry_in_ms: typing.Optional[int]
+    ):
+        self._uuid_slot = uuid_slot
+        self._attempt = attempt
+        self._next_retry_in_ms = next_retry_in_ms
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, ConnectionAttemptFailed):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._attempt != rhs._attempt:
+            return False
+        # The delay is randomized with the jitter, only its presence is compared
+        if (self._next_retry_in_ms is None) != (rhs._next_retry_in_ms is None):
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"ConnectionAttemptFailed(transfer={print_uuid(self._uuid_slot)}, attempt={self._attempt}, next_retry_in_ms={self._next_retry_in_ms})"
+
+
+class ConnectionDiagnostics(Event):
+    def __init__(self, uuid_slot: int, failed_phase):
+        self._uuid_slot = uuid_slot
+        self._failed_phase = failed_phase
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, ConnectionDiagnostics):
+            return False
+        # The timing of the phases varies, only the failed phase is compared
+        return (
+            self._uuid_slot == rhs._uuid_slot
+            and self._failed_phase == rhs._failed_phase
+        )
+
+    def __str__(self):
+        return f"ConnectionDiagnostics(transfer={print_uuid(self._uuid_slot)}, failed_phase={self._failed_phase})"
+
+
+class TransferConnected(Event):
+    def __init__(self, uuid_slot: int, peer: str, relayed: bool):
+        self._uuid_slot = uuid_slot
+        self._peer = peer
+        self._relayed = relayed
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferConnected):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._peer != rhs._peer:
+            return False
+        if self._relayed != rhs._relayed:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferConnected(transfer={print_uuid(self._uuid_slot)}, peer={self._peer}, relayed={self._relayed})"
+
+
+class TransferPaused(Event):
+    def __init__(self, uuid_slot: int, by_peer: bool):
+        self._uuid_slot = uuid_slot
+        self._by_peer = by_peer
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferPaused):
+            return False
+        return self._uuid_slot == rhs._uuid_slot and self._by_peer == rhs._by_peer
+
+    def __str__(self):
+        return f"TransferPaused(transfer={print_uuid(self._uuid_slot)}, by_peer={self._by_peer})"
+
+
+class TransferResumed(Event):
+    def __init__(self, uuid_slot: int, by_peer: bool):
+        self._uuid_slot = uuid_slot
+        self._by_peer = by_peer
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferResumed):
+            return False
+        return self._uuid_slot == rhs._uuid_slot and self._by_peer == rhs._by_peer
+
+    def __str__(self):
+        return f"TransferResumed(transfer={print_uuid(self._uuid_slot)}, by_peer={self._by_peer})"
+
+
+class TextReceived(Event):
+    def __init__(self, peer: str, text: str):
+        self._peer = peer
+        self._text = text
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TextReceived):
+            return False
+        return self._peer == rhs._peer and self._text == rhs._text
+
+    def __str__(self):
+        return f"TextReceived(peer={self._peer}, text={self._text!r})"
+
+
+class TextSent(Event):
+    def __eq__(self, rhs):
+        return isinstance(rhs, TextSent)
+
+    def __str__(self):
+        return "TextSent()"
+
+
+class TextFailed(Event):
+    def __init__(self, status: int):
+        self._status = status
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TextFailed):
+            return False
+        return self._status == rhs._status
+
+    def __str__(self):
+        return f"TextFailed(status={self._status})"
+
+
+class RequestRejected(Event):
+    def __init__(self, uuid_slot: int, peer: str, status: int):
+        self._uuid_slot = uuid_slot
+        self._peer = peer
+        self._status = status
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, RequestRejected):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._peer != rhs._peer:
+            return False
+        return self._status == rhs._status
+
+    def __str__(self):
+        return f"RequestRejected(transfer={print_uuid(self._uuid_slot)}, peer={self._peer}, status={self._status})"
+
+
+class EventsDropped(Event):
+    def __init__(self, count: int):
+        self._count = count
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, EventsDropped):
+            return False
+        return self._count == rhs._count
+
+    def __str__(self):
+        return f"EventsDropped(count={self._count})"
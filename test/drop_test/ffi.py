@@ -77,7 +77,16 @@ class EventQueue(norddrop.EventCallback):
         for _ in range(0, duration):
             with self._lock:
                 self._events = [
-                    ev for ev in self._events if not isinstance(ev, event.Throttled)
+                    ev
+                    for ev in self._events
+                    if not isinstance(
+                        ev,
+                        (
+                            event.Throttled,
+                            event.ConnectionAttemptFailed,
+                            event.ConnectionDiagnostics,
+                        ),
+                    )
                 ]
 
                 if ignore_progress:
@@ -129,6 +138,24 @@ class EventQueue(norddrop.EventCallback):
                     ):
                         continue
 
+                    # Depends on when the previous run was killed
+                    if isinstance(e, event.StartupReconciled) and not isinstance(
+                        target_event, event.StartupReconciled
+                    ):
+                        continue
+
+                    # Depends on the timing of the connection retries
+                    if isinstance(e, event.ConnectionAttemptFailed) and not isinstance(
+                        target_event, event.ConnectionAttemptFailed
+                    ):
+                        continue
+
+                    # Accompanies the connection failures
+                    if isinstance(e, event.ConnectionDiagnostics) and not isinstance(
+                        target_event, event.ConnectionDiagnostics
+                    ):
+                        continue
+
                     if e == target_event:
                         return
 
@@ -179,6 +206,26 @@ class EventQueue(norddrop.EventCallback):
                     ):
                         continue
 
+                    # Depends on when the previous run was killed
+                    if isinstance(e, event.StartupReconciled) and not any(
+                        isinstance(te, event.StartupReconciled) for te in target_events
+                    ):
+                        continue
+
+                    # Depends on the timing of the connection retries
+                    if isinstance(e, event.ConnectionAttemptFailed) and not any(
+                        isinstance(te, event.ConnectionAttemptFailed)
+                        for te in target_events
+                    ):
+                        continue
+
+                    # Accompanies the connection failures
+                    if isinstance(e, event.ConnectionDiagnostics) and not any(
+                        isinstance(te, event.ConnectionDiagnostics)
+                        for te in target_events
+                    ):
+                        continue
+
                     found = False
                     for te in target_events:
                         if te == e:
@@ -280,25 +327,110 @@ class Drop:
     def new_transfer(self, peer: str, paths: typing.List[str]) -> str:
         descriptors = []
         for descriptor in paths:
-            descriptors.append(norddrop.TransferDescriptor.PATH(descriptor))
+            descriptors.append(
+                norddrop.TransferDescriptor.PATH(
+                    path=descriptor, exclude=None, app_metadata=None
+                )
+            )
 
         return self._instance.new_transfer(peer, descriptors)
 
+    def add_files(self, uuid: str, paths: typing.List[str]):
+        descriptors = []
+        for descriptor in paths:
+            descriptors.append(
+                norddrop.TransferDescriptor.PATH(
+                    path=descriptor, exclude=None, app_metadata=None
+                )
+            )
+
+        self._instance.add_files(uuid, descriptors)
+
+    def new_transfer_via(self, relay: str, peer: str, paths: typing.List[str]) -> str:
+        descriptors = []
+        for descriptor in paths:
+            descriptors.append(
+                norddrop.TransferDescriptor.PATH(
+                    path=descriptor, exclude=None, app_metadata=None
+                )
+            )
+
+        return self._instance.new_transfer_via(relay, peer, descriptors)
+
     def new_transfer_with_fd(self, peer: str, path: str, uri: str) -> str:
         descriptors = [
-            norddrop.TransferDescriptor.FD(filename=path, content_uri=uri, fd=None)
+            norddrop.TransferDescriptor.FD(
+                filename=path, content_uri=uri, fd=None, app_metadata=None
+            )
         ]
         return self._instance.new_transfer(peer, descriptors)
 
     def download(self, uuid: str, fid: str, dst: str):
         self._instance.download_file(uuid, fid, dst)
 
+    def download_many(self, uuid: str, files: typing.List[typing.Tuple[str, str]]):
+        self._instance.download_many(
+            uuid,
+            [norddrop.FileDownload(file_id=fid, destination=dst) for fid, dst in files],
+        )
+
     def cancel_transfer_request(self, uuid: str):
         self._instance.finalize_transfer(uuid)
 
+    def retry_transfer(self, uuid: str):
+        self._instance.retry_transfer(uuid)
+
+    def withdraw_transfer(self, uuid: str):
+        self._instance.withdraw_transfer(uuid)
+
+    def withdraw_transfer_file(self, uuid: str, fid: str):
+        self._instance.withdraw_file(uuid, fid)
+
+    def pause_transfer(self, uuid: str):
+        self._instance.pause_transfer(uuid)
+
+    def resume_transfer(self, uuid: str):
+        self._instance.resume_transfer(uuid)
+
     def reject_transfer_file(self, uuid: str, fid: str):
         self._instance.reject_file(uuid, fid)
 
+    def remove_partial_file(self, uuid: str, fid: str):
+        self._instance.remove_partial_file(uuid, fid)
+
+    def get_transfer_manifest(self, uuid: str):
+        return self._instance.get_transfer_manifest(uuid)
+
+    def get_transfer_report(self, uuid: str) -> str:
+        return self._instance.get_transfer_report(uuid)
+
+    def get_inbox(self):
+        return self._instance.get_inbox()
+
+    def claim_inbox(self, uuid: str, dst: str) -> typing.List[str]:
+        return self._instance.claim_inbox(uuid, dst)
+
+    def forward_inbox(self, uuid: str, peer: str) -> str:
+        return self._instance.forward_inbox(uuid, peer)
+
+    def share_directory(self, peer: str, path: str) -> str:
+        return self._instance.share_directory(peer, path)
+
+    def get_connection_diagnostics(self, peer: str):
+        return self._instance.get_connection_diagnostics(peer)
+
+    def get_peer_device(self, peer: str):
+        return self._instance.get_peer_device(peer)
+
+    def set_private_key(self, privkey: bytes):
+        self._instance.set_private_key(privkey)
+
+    def send_text(self, peer: str, text: str) -> str:
+        return self._instance.send_text(peer, text)
+
+    def stop_sharing(self, share_id: str):
+        self._instance.stop_sharing(share_id)
+
     def get_transfers_since(
         self, since_timestamp: int
     ) -> typing.List[norddrop.TransferInfo]:
@@ -323,6 +455,9 @@ class Drop:
         checksum_events_size_threshold=None,
         checksum_events_granularity=None,
         auto_retry_interval_ms=None,
+        device_name=None,
+        platform=None,
+        transfer_mode=None,
     ):
         cfg = norddrop.Config(
             dir_depth_limit=5,
@@ -333,14 +468,72 @@ class Drop:
             checksum_events_size_threshold=checksum_events_size_threshold,
             checksum_events_granularity=checksum_events_granularity,
             connection_retries=1,
+            retry_base_delay_ms=None,
+            retry_max_delay_ms=None,
+            retry_backoff=None,
+            retry_jitter=None,
             auto_retry_interval_ms=auto_retry_interval_ms,
+            prefer_ipv6=None,
+            port=None,
+            low_power_mode=None,
+            relay_addr=None,
+            relay_token=None,
+            relay_after_retries=None,
+            tls=None,
+            chunk_encryption=None,
+            # The scenarios send the same files on purpose
+            duplicate_transfer_window_ms=0,
+            preserve_metadata=None,
+            transfer_empty_dirs=None,
+            transfer_symlinks=None,
+            report_final_paths=None,
+            skip_hidden_files=None,
+            transfer_size_limit_bytes=None,
+            verify_writes=None,
+            chunk_crc=None,
+            metrics_addr=None,
+            read_only=None,
+            peer_key_ttl_ms=None,
+            create_dir_skeleton=None,
+            partial_dir=None,
+            partial_file_extension=None,
+            forward_dir=None,
+            forward_peers=None,
+            partial_gc_age_ms=None,
+            download_roots=None,
+            inbox_dir=None,
+            inbox_quota_bytes=None,
+            pipeline_timing=None,
+            idempotent_transfers=None,
+            short_transfer_ids=None,
+            skip_duplicates=None,
+            transfer_preflight=None,
+            event_files_limit=None,
+            checksum_threads=None,
+            peer_quota_bytes=None,
+            peer_quota_window_ms=None,
+            max_peers=None,
+            max_transfers_per_peer=None,
+            chunk_size=None,
+            adaptive_chunk_size=None,
+            upload_buffer_bytes=None,
+            parallel_ranges=None,
+            shared_connections=None,
+            connection_keep_alive_ms=None,
+            device_name=device_name,
+            platform=platform,
+            transfer_mode=transfer_mode,
+            event_signing_key=None,
         )
 
-        self._instance.start(addr, cfg)
+        self._instance.start([addr], cfg)
 
     def stop(self):
         self._instance.stop()
 
+    def stop_graceful(self, timeout_ms: int):
+        self._instance.stop_graceful(timeout_ms)
+
     @property
     def version(self) -> str:
         return norddrop.version()
@@ -373,7 +566,9 @@ def new_event(ev: norddrop.Event) -> event.Event:
     ev = ev.kind
 
     transfer_slot: int = 0
-    if ev.is_request_received():
+    # The refused new transfer was never returned to the app
+    refused_unknown = ev.is_operation_refused() and ev.transfer_id not in event.UUIDS
+    if ev.is_request_received() or ev.is_request_rejected() or refused_unknown:
         with event.UUIDS_LOCK:
             transfer_slot = len(event.UUIDS)
             event.UUIDS.append(ev.transfer_id)
@@ -386,9 +581,23 @@ def new_event(ev: norddrop.Event) -> event.Event:
         ev.peer = peer_resolver.reverse_lookup(ev.peer)
 
     if ev.is_request_received():
-        return event.Receive(transfer_slot, ev.peer, ev.files)
+        return event.Receive(
+            transfer_slot,
+            ev.peer,
+            ev.files,
+            ev.total_size,
+            ev.device_name,
+            ev.platform,
+        )
     elif ev.is_request_queued():
-        return event.Queued(transfer_slot, ev.peer, ev.files)
+        return event.Queued(
+            transfer_slot, ev.peer, ev.files, ev.excluded, ev.total_size
+        )
+
+    elif ev.is_files_queued():
+        return event.FilesQueued(transfer_slot, ev.files)
+    elif ev.is_files_received():
+        return event.FilesReceived(transfer_slot, ev.files)
 
     elif ev.is_file_started():
         return event.Start(transfer_slot, ev.file_id, ev.transferred)
@@ -407,6 +616,8 @@ def new_event(ev: norddrop.Event) -> event.Event:
         )
     elif ev.is_file_rejected():
         return event.FinishFileRejected(transfer_slot, ev.file_id, ev.by_peer)
+    elif ev.is_file_withdrawn():
+        return event.FinishFileWithdrawn(transfer_slot, ev.file_id, ev.by_peer)
     elif ev.is_file_paused():
         return event.Paused(transfer_slot, ev.file_id)
     elif ev.is_file_throttled():
@@ -416,6 +627,8 @@ def new_event(ev: norddrop.Event) -> event.Event:
 
     elif ev.is_transfer_finalized():
         return event.FinishTransferCanceled(transfer_slot, ev.by_peer)
+    elif ev.is_transfer_withdrawn():
+        return event.TransferWithdrawn(transfer_slot, ev.by_peer)
     elif ev.is_transfer_failed():
         return event.FinishFailedTransfer(
             transfer_slot, ev.status.status, ev.status.os_error_code
@@ -424,6 +637,18 @@ def new_event(ev: norddrop.Event) -> event.Event:
         return event.TransferDeferred(
             transfer_slot, ev.peer, ev.status.status, ev.status.os_error_code
         )
+    elif ev.is_transfer_postponed():
+        return event.TransferPostponed(transfer_slot, ev.peer)
+    elif ev.is_peer_key_unavailable():
+        return event.PeerKeyUnavailable(transfer_slot, ev.peer)
+    elif ev.is_connection_attempt_failed():
+        return event.ConnectionAttemptFailed(
+            transfer_slot, ev.attempt, ev.next_retry_in_ms
+        )
+    elif ev.is_connection_diagnostics():
+        return event.ConnectionDiagnostics(transfer_slot, ev.diagnostics.failed_phase)
+    elif ev.is_transfer_connected():
+        return event.TransferConnected(transfer_slot, ev.peer, ev.relayed)
 
     elif ev.is_finalize_checksum_progress():
         return event.FinalizeChecksumProgress(
@@ -445,6 +670,38 @@ def new_event(ev: norddrop.Event) -> event.Event:
 
     elif ev.is_runtime_error():
         return event.RuntimeError(ev.status)
+    elif ev.is_startup_reconciled():
+        return event.StartupReconciled(
+            ev.paused_files, ev.failed_transfers, ev.failed_files
+        )
+    elif ev.is_key_rotation_completed():
+        return event.KeyRotationCompleted()
+    elif ev.is_operation_refused():
+        return event.OperationRefused(transfer_slot, ev.file_id, ev.operation)
+    elif ev.is_directory_prepared():
+        return event.DirectoryPrepared(transfer_slot, ev.root, ev.path)
+    elif ev.is_downloads_queued():
+        return event.DownloadsQueued(transfer_slot, ev.file_ids)
+    elif ev.is_duplicate_file_detected():
+        return event.DuplicateFileDetected(
+            transfer_slot, ev.file_id, ev.existing_path
+        )
+    elif ev.is_preflight_finished():
+        return event.PreflightFinished(transfer_slot, ev.unreadable_files)
+    elif ev.is_transfer_paused():
+        return event.TransferPaused(transfer_slot, ev.by_peer)
+    elif ev.is_transfer_resumed():
+        return event.TransferResumed(transfer_slot, ev.by_peer)
+    elif ev.is_text_received():
+        return event.TextReceived(ev.peer, ev.text)
+    elif ev.is_text_sent():
+        return event.TextSent()
+    elif ev.is_text_failed():
+        return event.TextFailed(ev.status)
+    elif ev.is_request_rejected():
+        return event.RequestRejected(transfer_slot, ev.peer, ev.status)
+    elif ev.is_events_dropped():
+        return event.EventsDropped(ev.count)
 
     else:
         raise Exception("Unknown event type")
@@ -1057,6 +1057,146 @@ scenarios = [
         },
         tags=["offline", "cancel"],
     ),
+    Scenario(
+        "scenario4-13",
+        "Send a request with one file, withdraw the transfer from the sender side before it is accepted, expect the withdrawal be synced",
+        {
+            "DROP_PEER_REN": ActionList(
+                [
+                    action.Start("DROP_PEER_REN"),
+                    # Wait for another peer to appear
+                    action.WaitForAnotherPeer("DROP_PEER_STIMPY"),
+                    action.NewTransfer("DROP_PEER_STIMPY", ["/tmp/testfile-big"]),
+                    action.Wait(
+                        event.Queued(
+                            0,
+                            "DROP_PEER_STIMPY",
+                            [
+                                norddrop.QueuedFile(
+                                    FILES["testfile-big"].id,
+                                    "testfile-big",
+                                    10485760,
+                                    "/tmp",
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Sleep(2),
+                    action.WithdrawTransfer(0),
+                    action.Wait(event.TransferWithdrawn(0, False)),
+                    action.NoEvent(),
+                    action.Stop(),
+                ]
+            ),
+            "DROP_PEER_STIMPY": ActionList(
+                [
+                    action.Start("DROP_PEER_STIMPY"),
+                    action.Wait(
+                        event.Receive(
+                            0,
+                            "DROP_PEER_REN",
+                            [
+                                norddrop.ReceivedFile(
+                                    FILES["testfile-big"].id, "testfile-big", 10485760
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Wait(
+                        event.TransferWithdrawn(0, True),
+                    ),
+                    action.NoEvent(),
+                    action.Stop(),
+                ]
+            ),
+        },
+        tags=["cancel"],
+    ),
+    Scenario(
+        "scenario4-14",
+        "Send a request with two files, withdraw one of them from the sender side before it is started, expect the withdrawal be synced",
+        {
+            "DROP_PEER_REN": ActionList(
+                [
+                    action.Start("DROP_PEER_REN"),
+                    # Wait for another peer to appear
+                    action.WaitForAnotherPeer("DROP_PEER_STIMPY"),
+                    action.NewTransfer(
+                        "DROP_PEER_STIMPY",
+                        ["/tmp/testfile-small", "/tmp/testfile-big"],
+                    ),
+                    action.Wait(
+                        event.Queued(
+                            0,
+                            "DROP_PEER_STIMPY",
+                            [
+                                norddrop.QueuedFile(
+                                    FILES["testfile-small"].id,
+                                    "testfile-small",
+                                    1048576,
+                                    "/tmp",
+                                ),
+                                norddrop.QueuedFile(
+                                    FILES["testfile-big"].id,
+                                    "testfile-big",
+                                    10485760,
+                                    "/tmp",
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Sleep(2),
+                    action.WithdrawTransferFile(0, FILES["testfile-big"].id),
+                    action.Wait(
+                        event.FinishFileWithdrawn(0, FILES["testfile-big"].id, False)
+                    ),
+                    action.Wait(event.Start(0, FILES["testfile-small"].id)),
+                    action.Wait(event.FinishFileUploaded(0, FILES["testfile-small"].id)),
+                    action.ExpectCancel([0], True),
+                    action.Stop(),
+                ]
+            ),
+            "DROP_PEER_STIMPY": ActionList(
+                [
+                    action.Start("DROP_PEER_STIMPY"),
+                    action.Wait(
+                        event.Receive(
+                            0,
+                            "DROP_PEER_REN",
+                            [
+                                norddrop.ReceivedFile(
+                                    FILES["testfile-small"].id, "testfile-small", 1048576
+                                ),
+                                norddrop.ReceivedFile(
+                                    FILES["testfile-big"].id, "testfile-big", 10485760
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Wait(
+                        event.FinishFileWithdrawn(0, FILES["testfile-big"].id, True)
+                    ),
+                    action.Download(0, FILES["testfile-small"].id, "/tmp/received/4-14"),
+                    action.Wait(
+                        event.Pending(
+                            0, FILES["testfile-small"].id, "/tmp/received/4-14"
+                        )
+                    ),
+                    action.Wait(event.Start(0, FILES["testfile-small"].id)),
+                    action.Wait(
+                        event.FinishFileDownloaded(
+                            0,
+                            FILES["testfile-small"].id,
+                            "/tmp/received/4-14/testfile-small",
+                        )
+                    ),
+                    action.ExpectCancel([0], False),
+                    action.Stop(),
+                ]
+            ),
+        },
+        tags=["cancel"],
+    ),
     Scenario(
         "scenario5-1",
         "Try to send file to an offline peer, expect silence",
@@ -14149,4 +14289,242 @@ scenarios = [
             ),
         },
     ),
+    Scenario(
+        "scenario54",
+        "Send a request from the peer declaring its device, expect the receiver to report it and forget it once the transfer is purged",
+        {
+            "DROP_PEER_REN": ActionList(
+                [
+                    action.Start(
+                        "DROP_PEER_REN", device_name="Ren's laptop", platform="linux"
+                    ),
+                    action.WaitForAnotherPeer("DROP_PEER_STIMPY"),
+                    action.NewTransfer("DROP_PEER_STIMPY", ["/tmp/testfile-small"]),
+                    action.Wait(
+                        event.Queued(
+                            0,
+                            "DROP_PEER_STIMPY",
+                            [
+                                norddrop.QueuedFile(
+                                    FILES["testfile-small"].id,
+                                    "testfile-small",
+                                    1048576,
+                                    "/tmp",
+                                ),
+                            ],
+                        )
+                    ),
+                    action.ExpectCancel([0], True),
+                    action.Stop(),
+                ]
+            ),
+            "DROP_PEER_STIMPY": ActionList(
+                [
+                    action.Start("DROP_PEER_STIMPY"),
+                    action.Wait(
+                        event.Receive(
+                            0,
+                            "DROP_PEER_REN",
+                            [
+                                norddrop.ReceivedFile(
+                                    FILES["testfile-small"].id, "testfile-small", 1048576
+                                ),
+                            ],
+                            device_name="Ren's laptop",
+                            platform="linux",
+                        )
+                    ),
+                    action.AssertPeerDevice("DROP_PEER_REN", "Ren's laptop", "linux"),
+                    action.CancelTransferRequest([0]),
+                    action.ExpectCancel([0], False),
+                    action.PurgeTransfers([0]),
+                    action.AssertPeerDevice("DROP_PEER_REN", None, None),
+                    action.Stop(),
+                ]
+            ),
+        },
+        tags=["basic"],
+    ),
+    Scenario(
+        "scenario55",
+        "Start the peer in the receive-only mode, expect it to refuse sending the transfer and the text message but still receive the transfer",
+        {
+            "DROP_PEER_REN": ActionList(
+                [
+                    action.Start(
+                        "DROP_PEER_REN",
+                        transfer_mode=norddrop.TransferMode.RECEIVE_ONLY,
+                    ),
+                    action.NewTransferFails("DROP_PEER_STIMPY", "/tmp/testfile-small"),
+                    action.Wait(
+                        event.OperationRefused(
+                            0, None, norddrop.RefusedOperation.NEW_TRANSFER
+                        )
+                    ),
+                    action.SendTextFails("DROP_PEER_STIMPY", "hello"),
+                    action.Wait(
+                        event.Receive(
+                            1,
+                            "DROP_PEER_STIMPY",
+                            [
+                                norddrop.ReceivedFile(
+                                    FILES["testfile-small"].id, "testfile-small", 1048576
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Download(
+                        1,
+                        FILES["testfile-small"].id,
+                        "/tmp/received",
+                    ),
+                    action.Wait(
+                        event.Pending(1, FILES["testfile-small"].id, "/tmp/received")
+                    ),
+                    action.Wait(event.Start(1, FILES["testfile-small"].id)),
+                    action.Wait(
+                        event.FinishFileDownloaded(
+                            1,
+                            FILES["testfile-small"].id,
+                            "/tmp/received/testfile-small",
+                        )
+                    ),
+                    action.ExpectCancel([1], False),
+                    action.Stop(),
+                ]
+            ),
+            "DROP_PEER_STIMPY": ActionList(
+                [
+                    action.Start("DROP_PEER_STIMPY"),
+                    action.WaitForAnotherPeer("DROP_PEER_REN"),
+                    action.NewTransfer("DROP_PEER_REN", ["/tmp/testfile-small"]),
+                    action.Wait(
+                        event.Queued(
+                            0,
+                            "DROP_PEER_REN",
+                            [
+                                norddrop.QueuedFile(
+                                    FILES["testfile-small"].id,
+                                    "testfile-small",
+                                    1048576,
+                                    "/tmp",
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Wait(event.Start(0, FILES["testfile-small"].id)),
+                    action.Wait(
+                        event.FinishFileUploaded(
+                            0,
+                            FILES["testfile-small"].id,
+                        )
+                    ),
+                    action.ExpectCancel([0], True),
+                    action.Stop(),
+                ]
+            ),
+        },
+        tags=["basic"],
+    ),
+    Scenario(
+        "scenario56",
+        "Start the peer in the send-only mode, expect it to reject the incoming transfer and the text message but still send the transfer",
+        {
+            "DROP_PEER_REN": ActionList(
+                [
+                    action.Start(
+                        "DROP_PEER_REN", transfer_mode=norddrop.TransferMode.SEND_ONLY
+                    ),
+                    action.WaitForAnotherPeer("DROP_PEER_STIMPY"),
+                    action.NewTransfer("DROP_PEER_STIMPY", ["/tmp/testfile-small"]),
+                    action.Wait(
+                        event.Queued(
+                            0,
+                            "DROP_PEER_STIMPY",
+                            [
+                                norddrop.QueuedFile(
+                                    FILES["testfile-small"].id,
+                                    "testfile-small",
+                                    1048576,
+                                    "/tmp",
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Wait(event.Start(0, FILES["testfile-small"].id)),
+                    action.Wait(
+                        event.FinishFileUploaded(
+                            0,
+                            FILES["testfile-small"].id,
+                        )
+                    ),
+                    action.ExpectCancel([0], True),
+                    action.Wait(
+                        event.RequestRejected(
+                            1,
+                            "DROP_PEER_STIMPY",
+                            norddrop.StatusCode.RECEIVE_DISABLED,
+                        )
+                    ),
+                    action.Stop(),
+                ]
+            ),
+            "DROP_PEER_STIMPY": ActionList(
+                [
+                    action.Start("DROP_PEER_STIMPY"),
+                    action.Wait(
+                        event.Receive(
+                            0,
+                            "DROP_PEER_REN",
+                            [
+                                norddrop.ReceivedFile(
+                                    FILES["testfile-small"].id, "testfile-small", 1048576
+                                ),
+                            ],
+                        )
+                    ),
+                    action.Download(
+                        0,
+                        FILES["testfile-small"].id,
+                        "/tmp/received",
+                    ),
+                    action.Wait(
+                        event.Pending(0, FILES["testfile-small"].id, "/tmp/received")
+                    ),
+                    action.Wait(event.Start(0, FILES["testfile-small"].id)),
+                    action.Wait(
+                        event.FinishFileDownloaded(
+                            0,
+                            FILES["testfile-small"].id,
+                            "/tmp/received/testfile-small",
+                        )
+                    ),
+                    action.ExpectCancel([0], False),
+                    action.SendText("DROP_PEER_REN", "hello"),
+                    action.Wait(event.TextFailed(norddrop.StatusCode.FINALIZED)),
+                    action.NewTransfer("DROP_PEER_REN", ["/tmp/testfile-small"]),
+                    action.Wait(
+                        event.Queued(
+                            1,
+                            "DROP_PEER_REN",
+                            [
+                                norddrop.QueuedFile(
+                                    FILES["testfile-small"].id,
+                                    "testfile-small",
+                                    1048576,
+                                    "/tmp",
+                                ),
+                            ],
+                        )
+                    ),
+                    # The peer keeps rejecting the reconnecting transfer
+                    action.WaitForAnotherPeer("DROP_PEER_REN", PeerState.Offline),
+                    action.CancelTransferRequest([1]),
+                    action.ExpectCancel([1], False),
+                    action.Stop(),
+                ]
+            ),
+        },
+        tags=["basic"],
+    ),
 ]